@@ -5,16 +5,48 @@ export declare class FlashThing {
   openDirectory(path: string): Promise<void>
   openArchive(path: string): Promise<void>
   openJson(json: string): Promise<void>
-  openStockDirectory(path: string): Promise<void>
-  openStockArchive(path: string): Promise<void>
+  /** `profile` names the layout of the dump, e.g. `superbird-dump`; defaults to `superbird` */
+  openStockDirectory(path: string, profile?: string): Promise<void>
+  /** `profile` names the layout of the dump, e.g. `superbird-dump`; defaults to `superbird` */
+  openStockArchive(path: string, profile?: string): Promise<void>
   /** Method to get total number of steps */
   getNumSteps(): number
-  /** Method to flash with progress callback */
+  /** Method to get the steps of the loaded package in the order they run, with their ids */
+  getSteps(): Array<StepInfo>
+  /** Method to get the report for the most recent flash, or nothing while a flash is running */
+  getReport(): FlashReport | null
+  /** Method to get the loaded package as a shell script of the equivalent `update` tool commands, or nothing while a flash is running */
+  exportScript(): string | null
+  /**
+   * Method to flash with progress callback
+   *
+   * Rejects with `[AWAITING_USER_INPUT]` and the message at a `wait` for user input; call it again to carry on
+   */
   flash(): Promise<void>
   /** Utility method to unbrick a device */
   unbrick(): Promise<void>
-  /** Set up host for flashing (this currently only does anything on Linux) */
-  hostSetup(): void
+  /** Set up host for flashing (installs udev rules on Linux, checks for common access problems on macOS) */
+  hostSetup(group?: string): HostSetupStatus
+  /** Read a package directory or zip archive without a device, e.g. to show it before flashing */
+  inspectPackage(path: string): Promise<PackageInfo>
+  /** Get the udev rules `hostSetup` installs on Linux, for installing them without polkit */
+  hostSetupRules(group?: string): string
+  /** Get what this build of flashthing supports, to check before relying on it */
+  capabilities(): Capabilities
+}
+
+export interface ApplyDeltaValue {
+  name: string
+  delta: DataOrFile
+}
+
+export interface AssertValue {
+  variable?: string
+  bulkcmd?: string
+  equals?: string
+  contains?: string
+  matches?: string
+  message?: string
 }
 
 export interface Bl2BootValue {
@@ -22,9 +54,32 @@ export interface Bl2BootValue {
   bootloader: DataOrFile
 }
 
+export interface Capabilities {
+  /** version of the flashthing crate */
+  version: string
+  /** oldest `metadataVersion` a package can have */
+  minMetadataVersion: number
+  /** newest `metadataVersion` a package can have */
+  maxMetadataVersion: number
+  /** cargo features flashthing was built with, e.g. `adb` */
+  features: Array<string>
+  /** blobs built into flashthing, e.g. the BL2 sent to devices that are not given one */
+  resources: Array<EmbeddedResource>
+  /** devices there is a built-in profile for */
+  profiles: Array<ProfileInfo>
+}
+
+export declare const enum Confinement {
+  Flatpak = 'Flatpak',
+  Snap = 'Snap'
+}
+
 export type DataOrFile =
   | { type: 'Data' }
+  | { type: 'Hex', hex: string }
+  | { type: 'Base64', base64: string }
   | { type: 'File', file: MetaFile }
+  | { type: 'Concat', concat: Array<MetaFile> }
 
 export declare const enum DeviceMode {
   Normal = 'Normal',
@@ -33,16 +88,57 @@ export declare const enum DeviceMode {
   NotFound = 'NotFound'
 }
 
+export declare const enum ActionKind {
+  /** the device is booted normally and has to be restarted with buttons 1 & 4 held */
+  HoldButtons = 'HoldButtons',
+  /** no device was found, so it has to be plugged in with buttons 1 & 4 held */
+  Replug = 'Replug'
+}
+
+export interface EmbeddedResource {
+  /** `bl2`, `bootloader`, `unbrick`, or `stockMeta:<profile>` for a built-in stock meta.json */
+  name: string
+  /** size in bytes */
+  size: number
+  /** SHA-256 digest as hex */
+  sha256: string
+  /** version the blob declares, e.g. the stock meta.json's `version` */
+  version?: string
+}
+
+export interface EnvChange {
+  name: string
+  /** value the device had before, if the variable was set */
+  before?: string
+  after: string
+}
+
+export declare const enum EventDelivery {
+  /** call the callback from the flashing thread, which waits for it */
+  Blocking = 'Blocking',
+  /** queue events so a slow callback never holds up flashing, keeping only the latest progress */
+  LatestWins = 'LatestWins'
+}
+
 export type FlashEvent =
   | { type: 'Log', data: LogMessage }
   | { type: 'FindingDevice' }
   | { type: 'DeviceMode', mode: DeviceMode }
+  | { type: 'ActionRequired', kind: ActionKind, instructions: string }
   | { type: 'Connecting' }
   | { type: 'Connected' }
   | { type: 'Bl2Boot' }
   | { type: 'Resetting' }
-  | { type: 'StepChanged', step: number, data: FlashStep }
+  | { type: 'StepChanged', step: number, id: string, data: FlashStep }
+  | { type: 'StepCompleted', step: number, id: string }
+  | { type: 'StepFailed', step: number, id: string, error: string, code: string }
   | { type: 'FlashInfo', data: FlashProgress }
+  | { type: 'ThermalPause', temperature?: number }
+  | { type: 'Timeout', step: number, id: string, reason: string }
+  | { type: 'TransferStalled', endpoint: number, stalledForMs: number }
+  | { type: 'BootVerified', elapsedMs: number }
+  | { type: 'BootLoopDetected', cycles: number }
+  | { type: 'BootTimeout', timeoutMs: number, mode: DeviceMode }
 
 export interface FlashProgress {
   /** percent complete */
@@ -57,30 +153,137 @@ export interface FlashProgress {
   avgChunkTime: number
   /** average rate in kib/s */
   avgRate: number
+  /** id of the step making progress */
+  stepId?: string
+}
+
+export interface FlashReport {
+  package: string
+  version: string
+  deviceSerial?: string
+  /** rom version the last identify step read from the device */
+  deviceIdentity?: string
+  /** unix timestamp in seconds */
+  startedAt?: number
+  /** unix timestamp in seconds */
+  finishedAt?: number
+  stepsCompleted: number
+  stepsTotal: number
+  /** steps that failed and were skipped with `onError: "continue"` */
+  stepsFailed: number
+  /** steps left out by starting part way through or skipping them */
+  stepsSkipped: number
+  success: boolean
+  error?: string
+  /** stable code of the error, e.g. `WRONG_MODE`, if the flash failed */
+  errorCode?: string
+  /** bytes written to the emmc this session */
+  bytesWritten: number
+  /** bytes compare-before-write avoided rewriting this session */
+  bytesSkipped: number
+  /** bytes written to this device across all recorded sessions */
+  cumulativeBytesWritten?: number
+  /** partitions restored from the rollback bundle after a step failed */
+  rolledBack: Array<string>
+  warnings: Array<string>
+  /** outcome of every step that ran, in order */
+  steps: Array<StepResult>
+  /** outcome of every repeat step that ran, in order */
+  repeats: Array<RepeatResult>
+  /** step to start at to carry on after the flash was cancelled */
+  resumeAt?: string
+  /** byte of the resumeAt step's data to carry on from, when a streaming write was cancelled part way */
+  resumeOffset?: number
+  /** whether the device booted after flashing, if that was checked */
+  bootVerified?: boolean
+  /** times the device dropped off the bus and came back while checking it booted, if it was in a boot loop */
+  bootLoopCycles?: number
+  /** outcome of every command postBootCheck steps ran on the booted device, in order */
+  postBootChecks: Array<PostBootCheckResult>
+  /** bl2, bootloader and unbrick image the device profile sends, by size and digest */
+  resources: Array<EmbeddedResource>
+  /** env variables writeEnv steps changed, if `diffEnv` was set */
+  envChanges: Array<EnvChange>
 }
 
 export type FlashStep =
   | { type: 'Identify', variable?: string }
   | { type: 'Bulkcmd', value: string }
-  | { type: 'BulkcmdStat', value: string, variable?: string }
+  | { type: 'BulkcmdStat', value: string, variable?: string, capture?: string }
   | { type: 'Run', value: RunValue }
   | { type: 'WriteSimpleMemory', value: WriteSimpleMemoryValue }
   | { type: 'WriteLargeMemory', value: WriteLargeMemoryValue }
-  | { type: 'ReadSimpleMemory', value: ReadMemoryValue, variable?: string }
-  | { type: 'ReadLargeMemory', value: ReadMemoryValue, variable?: string }
+  | { type: 'ReadSimpleMemory', value: ReadMemoryValue, variable?: string, output?: StepOutput }
+  | { type: 'ReadLargeMemory', value: ReadMemoryValue, variable?: string, output?: StepOutput }
   | { type: 'GetBootAmlc', variable?: string }
   | { type: 'WriteAmlcData', value: WriteAmlcDataValue }
   | { type: 'Bl2Boot', value: Bl2BootValue }
   | { type: 'ValidatePartitionSize', value: ValidatePartitionSizeValue, variable?: string }
   | { type: 'RestorePartition', value: RestorePartitionValue }
+  | { type: 'ApplyDelta', value: ApplyDeltaValue }
   | { type: 'WriteBootPartition', value: WriteBootPartitionValue }
   | { type: 'WriteUserArea', value: WriteUserAreaValue }
   | { type: 'WriteEnv', value: StringOrFile }
+  | { type: 'WriteBootScript', value: WriteBootScriptValue }
   | { type: 'Log', value: string }
   | { type: 'Wait', value: WaitValue }
+  | { type: 'Reset', value: ResetValue }
+  | { type: 'Reconnect', value: ReconnectValue }
+  | { type: 'Repeat', value: RepeatValue }
+  | { type: 'Assert', value: AssertValue }
+  | { type: 'Provision', value: ProvisionValue }
+  | { type: 'PostBootCheck', value: PostBootCheckValue }
 
 export interface FlashThingOptions {
   logLevelDirective?: string
+  /** how much to log when no `logLevelDirective` is given (defaults to `RUST_LOG`, or normal) */
+  verbosity?: Verbosity
+  /** libusb log level, logged to stderr */
+  usbLogLevel?: UsbLogLevel
+  /** minimum milliseconds between two progress events, 0 sends every update (defaults to 100) */
+  progressIntervalMs?: number
+  /** how events are handed to the callback (defaults to blocking) */
+  eventDelivery?: EventDelivery
+  /** environment variables packages may use as `${env_NAME}` */
+  envVariables?: Array<string>
+  /** file of `name=value` lines with the values `provision` steps write to the next device */
+  provisionCounter?: string
+  /** per-device values for the package's variables, used instead of the package's `overrides.json` */
+  overridesPath?: string
+  /** directory read steps write their `output` files to (defaults to the current directory) */
+  outputDir?: string
+  /** directory to keep the report, checkpoint, read step outputs and rollback backups of each flash under */
+  artifactsDir?: string
+  /** move data to the device 64 KiB at a time, for hosts like Raspberry Pis whose large bulk writes stall */
+  limitedBulk?: boolean
+  /** split bulk writes into USB transfers of at most this many bytes, 0 to send them whole; defaults to 256 KiB on Windows */
+  maxUrbSize?: number
+  /** cancel and retry a usb transfer that goes this many milliseconds without finishing, 0 to wait on it for good; defaults to 30 seconds */
+  transferWatchdogMs?: number
+  /** wait this many milliseconds for the device to be put in usb mode, sending `ActionRequired` events, instead of failing straight away */
+  waitForDeviceMs?: number
+  /** once flashing succeeds, wait this many milliseconds for the device to boot, sending `BootVerified` or `BootTimeout` */
+  verifyBootMs?: number
+  /** record the env variables writeEnv steps change, and what they held, in the report's `envChanges` */
+  diffEnv?: boolean
+  /** write a zip of each flash's trace level logs, report, package metadata, host details and usb descriptors here, for a bug report */
+  bugReportPath?: string
+}
+
+export interface HostSetupStatus {
+  /** sandbox the process runs in, if any */
+  confinement?: Confinement
+  /** whether the host was changed, e.g. udev rules were installed */
+  changed: boolean
+  /** problems that have to be fixed by hand */
+  findings: Array<string>
+}
+
+export interface IterationFailure {
+  iteration: number
+  stepId: string
+  error: string
+  errorCode: string
 }
 
 export interface LogMessage {
@@ -97,6 +300,96 @@ export interface LogMessage {
 export interface MetaFile {
   filePath: string
   encoding?: string
+  offset?: number
+  length?: number
+  template?: boolean
+}
+
+export interface PackageCompatibility {
+  /** whether nothing stands in the way of flashing the package */
+  compatible: boolean
+  metadataVersion: number
+  /** whether the package asks for special partitions like `reserved` */
+  specialPartitions: boolean
+  /** partitions the steps name that the Car Thing does not have */
+  unknownPartitions: Array<string>
+  /** files the steps refer to that the package does not have */
+  missingFiles: Array<string>
+}
+
+export interface PackageFile {
+  filePath: string
+  /** size in bytes, missing if the package does not have the file */
+  size?: number
+}
+
+export interface PackageInfo {
+  name: string
+  version: string
+  description: string
+  /** steps in the order they run */
+  steps: Array<PackageStep>
+  /** files the steps refer to, sorted by path */
+  files: Array<PackageFile>
+  /** total size of the files the steps refer to */
+  totalBytes: number
+  compatibility: PackageCompatibility
+}
+
+export interface PackageStep {
+  index: number
+  id: string
+  /** the step's `type` as written in meta.json */
+  kind: string
+  /** partition the step works on, if it names one */
+  partition?: string
+  /** files the step reads */
+  files: Array<string>
+}
+
+export interface Patch {
+  offset: number
+  hex: string
+}
+
+export interface PostBootCheckResult {
+  command: string
+  /** what the command printed, trimmed */
+  output: string
+  passed: boolean
+}
+
+export interface PostBootCheckValue {
+  timeout?: number
+  checks: Array<PostBootCommand>
+}
+
+export interface PostBootCommand {
+  command: string
+  equals?: string
+  contains?: string
+  matches?: string
+  message?: string
+}
+
+export interface ProfileInfo {
+  name: string
+  /** USB vendor ID in USB (burn) mode */
+  vendorId: number
+  /** USB product ID in USB (burn) mode */
+  productId: number
+}
+
+export interface ProvisionDataValue {
+  partition: string
+  offsetInPartition?: number
+  text: string
+}
+
+export interface ProvisionValue {
+  variables: Array<string>
+  env?: Record<string, string>
+  data?: ProvisionDataValue
 }
 
 export interface ReadMemoryValue {
@@ -104,9 +397,40 @@ export interface ReadMemoryValue {
   length: number
 }
 
+export interface ReconnectValue {
+  timeout?: number
+}
+
+export interface RepeatResult {
+  id: string
+  iterations: number
+  /** iterations in which a step failed */
+  failed: number
+  failures: Array<IterationFailure>
+}
+
+export interface RepeatValue {
+  count: number
+  steps: Array<StepInfo>
+}
+
+export declare const enum ResetMode {
+  Soft = 'Soft',
+  Burn = 'Burn'
+}
+
+export interface ResetValue {
+  mode: ResetMode
+}
+
 export interface RestorePartitionValue {
   name: string
   data: DataOrFile
+  compareBeforeWrite?: boolean
+  confirmSpecialPartition?: boolean
+  trimPadding?: boolean
+  eraseTrimmed?: boolean
+  patches?: Array<Patch>
 }
 
 export interface RunValue {
@@ -114,10 +438,45 @@ export interface RunValue {
   keepPower?: boolean
 }
 
+export declare const enum ScriptArch {
+  Arm = 'Arm',
+  Arm64 = 'Arm64'
+}
+
+export interface StepInfo {
+  index: number
+  id: string
+  data: FlashStep
+}
+
+export interface StepOutput {
+  filePath: string
+}
+
+export interface StepResult {
+  index: number
+  id: string
+  completed: boolean
+  /** times the step was run, including retries */
+  attempts: number
+  /** error of the last attempt, if the step failed */
+  error?: string
+  /** stable code of the last attempt's error, if the step failed */
+  errorCode?: string
+}
+
 export type StringOrFile =
   | { type: 'String', string: string }
   | { type: 'File', file: MetaFile }
 
+export declare const enum UsbLogLevel {
+  None = 'None',
+  Error = 'Error',
+  Warning = 'Warning',
+  Info = 'Info',
+  Debug = 'Debug'
+}
+
 export interface ValidatePartitionSizeValue {
   name: string
 }
@@ -126,6 +485,17 @@ export type WaitValue =
   | { type: 'UserInput', message: string }
   | { type: 'Time', time: number }
 
+export declare const enum Verbosity {
+  /** only warnings and errors */
+  Quiet = 'Quiet',
+  /** what each step is doing, without per-chunk transfer logs */
+  Normal = 'Normal',
+  /** debug logs too, still without per-chunk transfer logs */
+  Verbose = 'Verbose',
+  /** everything, including a log for every chunk transferred */
+  Trace = 'Trace'
+}
+
 export interface WriteAmlcDataValue {
   seq: number
   amlcOffset: number
@@ -135,13 +505,27 @@ export interface WriteAmlcDataValue {
 export interface WriteBootPartitionValue {
   hwpart: number
   data: DataOrFile
+  patches?: Array<Patch>
+}
+
+export interface WriteBootScriptValue {
+  script: StringOrFile
+  partition: string
+  offsetInPartition?: number
+  name?: string
+  arch?: ScriptArch
 }
 
 export interface WriteLargeMemoryValue {
-  address: number
+  address?: number
+  partition?: string
+  offsetInPartition?: number
   data: DataOrFile
   blockLength: number
   appendZeros?: boolean
+  compareBeforeWrite?: boolean
+  allowCrossPartition?: boolean
+  patches?: Array<Patch>
 }
 
 export interface WriteSimpleMemoryValue {
@@ -152,4 +536,6 @@ export interface WriteSimpleMemoryValue {
 export interface WriteUserAreaValue {
   lba: number
   data: DataOrFile
+  compareBeforeWrite?: boolean
+  patches?: Array<Patch>
 }
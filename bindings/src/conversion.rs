@@ -1,4 +1,6 @@
 /// ! NAPI-rs really needs a better way to handle this
+use std::collections::HashMap;
+
 use napi_derive::napi;
 
 use crate::monitoring::LogMessage;
@@ -18,6 +20,8 @@ pub struct FlashProgress {
   pub avg_chunk_time: f64,
   /// average rate in kib/s
   pub avg_rate: f64,
+  /// id of the step making progress
+  pub step_id: Option<String>,
 }
 
 impl From<flashthing::FlashProgress> for FlashProgress {
@@ -29,6 +33,465 @@ impl From<flashthing::FlashProgress> for FlashProgress {
       rate: progress.rate,
       avg_chunk_time: progress.avg_chunk_time,
       avg_rate: progress.avg_rate,
+      step_id: progress.step_id,
+    }
+  }
+}
+
+// FlashReport representation for JavaScript
+#[napi(object)]
+pub struct FlashReport {
+  pub package: String,
+  pub version: String,
+  pub device_serial: Option<String>,
+  /// rom version the last identify step read from the device
+  pub device_identity: Option<String>,
+  /// unix timestamp in seconds
+  pub started_at: Option<f64>,
+  /// unix timestamp in seconds
+  pub finished_at: Option<f64>,
+  pub steps_completed: u32,
+  pub steps_total: u32,
+  /// steps that failed and were skipped with `onError: "continue"`
+  pub steps_failed: u32,
+  /// steps left out by starting part way through or skipping them
+  pub steps_skipped: u32,
+  pub success: bool,
+  pub error: Option<String>,
+  /// stable code of the error, e.g. `WRONG_MODE`, if the flash failed
+  pub error_code: Option<String>,
+  /// bytes written to the emmc this session
+  pub bytes_written: f64,
+  /// bytes compare-before-write avoided rewriting this session
+  pub bytes_skipped: f64,
+  /// bytes written to this device across all recorded sessions
+  pub cumulative_bytes_written: Option<f64>,
+  /// partitions restored from the rollback bundle after a step failed
+  pub rolled_back: Vec<String>,
+  pub warnings: Vec<String>,
+  /// outcome of every step that ran, in order
+  pub steps: Vec<StepResult>,
+  /// outcome of every repeat step that ran, in order
+  pub repeats: Vec<RepeatResult>,
+  /// step to start at to carry on after the flash was cancelled
+  pub resume_at: Option<String>,
+  /// byte of the resume_at step's data to carry on from, when a streaming write was cancelled part way
+  pub resume_offset: Option<f64>,
+  /// whether the device booted after flashing, if that was checked
+  pub boot_verified: Option<bool>,
+  /// times the device dropped off the bus and came back while checking it booted, if it was in a boot loop
+  pub boot_loop_cycles: Option<u32>,
+  /// outcome of every command postBootCheck steps ran on the booted device, in order
+  pub post_boot_checks: Vec<PostBootCheckResult>,
+  /// bl2, bootloader and unbrick image the device profile sends, by size and digest
+  pub resources: Vec<EmbeddedResource>,
+  /// env variables writeEnv steps changed, if `diffEnv` was set
+  pub env_changes: Vec<EnvChange>,
+}
+
+impl From<&flashthing::FlashReport> for FlashReport {
+  fn from(report: &flashthing::FlashReport) -> Self {
+    Self {
+      package: report.package.clone(),
+      version: report.version.clone(),
+      device_serial: report.device_serial.clone(),
+      device_identity: report.device_identity.clone(),
+      started_at: report.started_at.map(|t| t as f64),
+      finished_at: report.finished_at.map(|t| t as f64),
+      steps_completed: report.steps_completed as u32,
+      steps_total: report.steps_total as u32,
+      steps_failed: report.steps_failed as u32,
+      steps_skipped: report.steps_skipped as u32,
+      success: report.success,
+      error: report.error.clone(),
+      error_code: report.error_code.clone(),
+      bytes_written: report.bytes_written as f64,
+      bytes_skipped: report.bytes_skipped as f64,
+      cumulative_bytes_written: report.cumulative_bytes_written.map(|b| b as f64),
+      rolled_back: report.rolled_back.clone(),
+      warnings: report.warnings.clone(),
+      steps: report.steps.iter().map(Into::into).collect(),
+      repeats: report.repeats.iter().map(Into::into).collect(),
+      resume_at: report.resume_at.clone(),
+      resume_offset: report.resume_offset.map(|b| b as f64),
+      boot_verified: report
+        .boot
+        .map(|boot| matches!(boot, flashthing::BootOutcome::Verified { .. })),
+      boot_loop_cycles: match report.boot {
+        Some(flashthing::BootOutcome::BootLoop { cycles }) => Some(cycles),
+        _ => None,
+      },
+      post_boot_checks: report.post_boot_checks.iter().map(Into::into).collect(),
+      resources: report.resources.iter().map(Into::into).collect(),
+      env_changes: report.env_changes.iter().map(Into::into).collect(),
+    }
+  }
+}
+
+// RepeatResult representation for JavaScript
+#[napi(object)]
+pub struct RepeatResult {
+  pub id: String,
+  pub iterations: u32,
+  /// iterations in which a step failed
+  pub failed: u32,
+  pub failures: Vec<IterationFailure>,
+}
+
+impl From<&flashthing::RepeatResult> for RepeatResult {
+  fn from(result: &flashthing::RepeatResult) -> Self {
+    Self {
+      id: result.id.clone(),
+      iterations: result.iterations,
+      failed: result.failed,
+      failures: result
+        .failures
+        .iter()
+        .map(|failure| IterationFailure {
+          iteration: failure.iteration,
+          step_id: failure.step_id.clone(),
+          error: failure.error.clone(),
+          error_code: failure.error_code.clone(),
+        })
+        .collect(),
+    }
+  }
+}
+
+#[napi(object)]
+pub struct IterationFailure {
+  pub iteration: u32,
+  pub step_id: String,
+  pub error: String,
+  pub error_code: String,
+}
+
+#[napi(object)]
+pub struct PostBootCheckResult {
+  pub command: String,
+  /// what the command printed, trimmed
+  pub output: String,
+  pub passed: bool,
+}
+
+impl From<&flashthing::PostBootCheckResult> for PostBootCheckResult {
+  fn from(result: &flashthing::PostBootCheckResult) -> Self {
+    Self {
+      command: result.command.clone(),
+      output: result.output.clone(),
+      passed: result.passed,
+    }
+  }
+}
+
+#[napi(object)]
+pub struct EnvChange {
+  pub name: String,
+  /// value the device had before, if the variable was set
+  pub before: Option<String>,
+  pub after: String,
+}
+
+impl From<&flashthing::EnvChange> for EnvChange {
+  fn from(change: &flashthing::EnvChange) -> Self {
+    Self {
+      name: change.name.clone(),
+      before: change.before.clone(),
+      after: change.after.clone(),
+    }
+  }
+}
+
+// StepResult representation for JavaScript
+#[napi(object)]
+pub struct StepResult {
+  pub index: u32,
+  pub id: String,
+  pub completed: bool,
+  /// times the step was run, including retries
+  pub attempts: u32,
+  /// error of the last attempt, if the step failed
+  pub error: Option<String>,
+  /// stable code of the last attempt's error, if the step failed
+  pub error_code: Option<String>,
+}
+
+impl From<&flashthing::StepResult> for StepResult {
+  fn from(result: &flashthing::StepResult) -> Self {
+    Self {
+      index: result.index as u32,
+      id: result.id.clone(),
+      completed: result.completed,
+      attempts: result.attempts,
+      error: result.error.clone(),
+      error_code: result.error_code.clone(),
+    }
+  }
+}
+
+// a step of the loaded flash config
+#[napi(object)]
+pub struct StepInfo {
+  pub index: u32,
+  pub id: String,
+  pub data: FlashStep,
+}
+
+impl From<(usize, flashthing::config::Step)> for StepInfo {
+  fn from((index, step): (usize, flashthing::config::Step)) -> Self {
+    Self {
+      index: index as u32,
+      id: step.id.unwrap_or_default(),
+      data: step.step.into(),
+    }
+  }
+}
+
+// Package representation for JavaScript, read without a device
+#[napi(object)]
+pub struct PackageInfo {
+  pub name: String,
+  pub version: String,
+  pub description: String,
+  /// steps in the order they run
+  pub steps: Vec<PackageStep>,
+  /// files the steps refer to, sorted by path
+  pub files: Vec<PackageFile>,
+  /// total size of the files the steps refer to
+  pub total_bytes: f64,
+  pub compatibility: PackageCompatibility,
+}
+
+#[napi(object)]
+pub struct PackageStep {
+  pub index: u32,
+  pub id: String,
+  /// the step's `type` as written in meta.json
+  pub kind: String,
+  /// partition the step works on, if it names one
+  pub partition: Option<String>,
+  /// files the step reads
+  pub files: Vec<String>,
+}
+
+#[napi(object)]
+pub struct PackageFile {
+  pub file_path: String,
+  /// size in bytes, missing if the package does not have the file
+  pub size: Option<f64>,
+}
+
+#[napi(object)]
+pub struct PackageCompatibility {
+  /// whether nothing stands in the way of flashing the package
+  pub compatible: bool,
+  pub metadata_version: u32,
+  /// whether the package asks for special partitions like `reserved`
+  pub special_partitions: bool,
+  /// partitions the steps name that the Car Thing does not have
+  pub unknown_partitions: Vec<String>,
+  /// files the steps refer to that the package does not have
+  pub missing_files: Vec<String>,
+}
+
+impl From<&flashthing::Package> for PackageInfo {
+  fn from(package: &flashthing::Package) -> Self {
+    let compatibility = package.compatibility();
+    Self {
+      name: package.name().to_string(),
+      version: package.version().to_string(),
+      description: package.description().to_string(),
+      steps: package
+        .steps()
+        .into_iter()
+        .map(|step| PackageStep {
+          index: step.number as u32,
+          id: step.id,
+          kind: step.kind.to_string(),
+          partition: step.partition,
+          files: step.files,
+        })
+        .collect(),
+      files: package
+        .files()
+        .iter()
+        .map(|file| PackageFile {
+          file_path: file.file_path.clone(),
+          size: file.size.map(|size| size as f64),
+        })
+        .collect(),
+      total_bytes: package.total_bytes() as f64,
+      compatibility: PackageCompatibility {
+        compatible: compatibility.is_compatible(),
+        metadata_version: compatibility.metadata_version as u32,
+        special_partitions: compatibility.special_partitions,
+        unknown_partitions: compatibility.unknown_partitions,
+        missing_files: compatibility.missing_files,
+      },
+    }
+  }
+}
+
+#[napi(string_enum)]
+pub enum Confinement {
+  Flatpak,
+  Snap,
+}
+
+// HostSetupStatus representation for JavaScript
+#[napi(object)]
+pub struct HostSetupStatus {
+  /// sandbox the process runs in, if any
+  pub confinement: Option<Confinement>,
+  /// whether the host was changed, e.g. udev rules were installed
+  pub changed: bool,
+  /// problems that have to be fixed by hand
+  pub findings: Vec<String>,
+}
+
+impl From<flashthing::HostSetupStatus> for HostSetupStatus {
+  fn from(status: flashthing::HostSetupStatus) -> Self {
+    Self {
+      confinement: status.confinement.map(|confinement| match confinement {
+        flashthing::Confinement::Flatpak => Confinement::Flatpak,
+        flashthing::Confinement::Snap => Confinement::Snap,
+      }),
+      changed: status.changed,
+      findings: status.findings,
+    }
+  }
+}
+
+// Capabilities representation for JavaScript
+#[napi(object)]
+pub struct Capabilities {
+  /// version of the flashthing crate
+  pub version: String,
+  /// oldest `metadataVersion` a package can have
+  pub min_metadata_version: u32,
+  /// newest `metadataVersion` a package can have
+  pub max_metadata_version: u32,
+  /// cargo features flashthing was built with, e.g. `adb`
+  pub features: Vec<String>,
+  /// blobs built into flashthing, e.g. the BL2 sent to devices that are not given one
+  pub resources: Vec<EmbeddedResource>,
+  /// devices there is a built-in profile for
+  pub profiles: Vec<ProfileInfo>,
+}
+
+#[napi(object)]
+pub struct EmbeddedResource {
+  /// `bl2`, `bootloader`, `unbrick`, or `stockMeta:<profile>` for a built-in stock meta.json
+  pub name: String,
+  /// size in bytes
+  pub size: f64,
+  /// SHA-256 digest as hex
+  pub sha256: String,
+  /// version the blob declares, e.g. the stock meta.json's `version`
+  pub version: Option<String>,
+}
+
+impl From<&flashthing::EmbeddedResource> for EmbeddedResource {
+  fn from(resource: &flashthing::EmbeddedResource) -> Self {
+    Self {
+      name: resource.name.clone(),
+      size: resource.size as f64,
+      sha256: resource.sha256.clone(),
+      version: resource.version.clone(),
+    }
+  }
+}
+
+#[napi(object)]
+pub struct ProfileInfo {
+  pub name: String,
+  /// USB vendor ID in USB (burn) mode
+  pub vendor_id: u32,
+  /// USB product ID in USB (burn) mode
+  pub product_id: u32,
+}
+
+impl From<flashthing::Capabilities> for Capabilities {
+  fn from(capabilities: flashthing::Capabilities) -> Self {
+    Self {
+      version: capabilities.version,
+      min_metadata_version: capabilities.min_metadata_version as u32,
+      max_metadata_version: capabilities.max_metadata_version as u32,
+      features: capabilities.features,
+      resources: capabilities.resources.iter().map(Into::into).collect(),
+      profiles: capabilities
+        .profiles
+        .into_iter()
+        .map(|profile| ProfileInfo {
+          name: profile.name,
+          vendor_id: profile.vendor_id.into(),
+          product_id: profile.product_id.into(),
+        })
+        .collect(),
+    }
+  }
+}
+
+#[napi(string_enum)]
+#[derive(Debug, Clone, Copy)]
+pub enum UsbLogLevel {
+  None,
+  Error,
+  Warning,
+  Info,
+  Debug,
+}
+
+impl From<UsbLogLevel> for flashthing::UsbLogLevel {
+  fn from(level: UsbLogLevel) -> Self {
+    match level {
+      UsbLogLevel::None => Self::None,
+      UsbLogLevel::Error => Self::Error,
+      UsbLogLevel::Warning => Self::Warning,
+      UsbLogLevel::Info => Self::Info,
+      UsbLogLevel::Debug => Self::Debug,
+    }
+  }
+}
+
+#[napi(string_enum)]
+#[derive(Debug, Clone, Copy)]
+pub enum Verbosity {
+  /// only warnings and errors
+  Quiet,
+  /// what each step is doing, without per-chunk transfer logs
+  Normal,
+  /// debug logs too, still without per-chunk transfer logs
+  Verbose,
+  /// everything, including a log for every chunk transferred
+  Trace,
+}
+
+impl From<Verbosity> for flashthing::Verbosity {
+  fn from(verbosity: Verbosity) -> Self {
+    match verbosity {
+      Verbosity::Quiet => Self::Quiet,
+      Verbosity::Normal => Self::Normal,
+      Verbosity::Verbose => Self::Verbose,
+      Verbosity::Trace => Self::Trace,
+    }
+  }
+}
+
+#[napi(string_enum)]
+#[derive(Debug, Clone, Copy)]
+pub enum EventDelivery {
+  /// call the callback from the flashing thread, which waits for it
+  Blocking,
+  /// queue events so a slow callback never holds up flashing, keeping only the latest progress
+  LatestWins,
+}
+
+impl From<EventDelivery> for flashthing::EventDelivery {
+  fn from(delivery: EventDelivery) -> Self {
+    match delivery {
+      EventDelivery::Blocking => Self::Blocking,
+      EventDelivery::LatestWins => Self::LatestWins,
     }
   }
 }
@@ -52,6 +515,23 @@ impl From<flashthing::DeviceMode> for DeviceMode {
   }
 }
 
+#[napi(string_enum)]
+pub enum ActionKind {
+  /// the device is booted normally and has to be restarted with buttons 1 & 4 held
+  HoldButtons,
+  /// no device was found, so it has to be plugged in with buttons 1 & 4 held
+  Replug,
+}
+
+impl From<flashthing::ActionKind> for ActionKind {
+  fn from(kind: flashthing::ActionKind) -> Self {
+    match kind {
+      flashthing::ActionKind::HoldButtons => Self::HoldButtons,
+      flashthing::ActionKind::Replug => Self::Replug,
+    }
+  }
+}
+
 #[napi]
 pub enum FlashEvent {
   /// log message
@@ -60,6 +540,8 @@ pub enum FlashEvent {
   FindingDevice,
   /// found device in mode
   DeviceMode { mode: DeviceMode },
+  /// the user has to put the device in usb mode; instructions are ready to show
+  ActionRequired { kind: ActionKind, instructions: String },
   /// connecting to device
   Connecting,
   /// connected to device
@@ -69,9 +551,30 @@ pub enum FlashEvent {
   /// resetting
   Resetting,
   /// moved to step; this means previous step is over
-  StepChanged { step: i32, data: FlashStep },
+  StepChanged { step: i32, id: String, data: FlashStep },
+  /// step finished successfully
+  StepCompleted { step: i32, id: String },
+  /// step failed after any retries; flashing carries on if the step allows it
+  StepFailed {
+    step: i32,
+    id: String,
+    error: String,
+    code: String,
+  },
   /// percent complete with current step (for long-running steps)
   FlashInfo { data: FlashProgress },
+  /// writing paused to let the device cool down; temperature is in °C, if known
+  ThermalPause { temperature: Option<f64> },
+  /// step was aborted for running past its timeout or the flash deadline
+  Timeout { step: i32, id: String, reason: String },
+  /// a usb transfer hung and was cancelled to be retried; stalledForMs is how long it went without finishing
+  TransferStalled { endpoint: u32, stalled_for_ms: u32 },
+  /// the device booted normally after flashing, elapsedMs after it was reset
+  BootVerified { elapsed_ms: u32 },
+  /// the device dropped off the bus and came back cycles times after flashing, so its firmware crashes as it boots
+  BootLoopDetected { cycles: u32 },
+  /// the device did not boot normally within timeoutMs of flashing; mode is where it was last seen
+  BootTimeout { timeout_ms: u32, mode: DeviceMode },
 }
 
 impl From<flashthing::Event> for FlashEvent {
@@ -81,17 +584,55 @@ impl From<flashthing::Event> for FlashEvent {
       flashthing::Event::DeviceMode(device_mode) => Self::DeviceMode {
         mode: device_mode.into(),
       },
+      flashthing::Event::ActionRequired { kind, instructions } => Self::ActionRequired {
+        kind: kind.into(),
+        instructions,
+      },
       flashthing::Event::Connecting => Self::Connecting,
       flashthing::Event::Connected => Self::Connected,
       flashthing::Event::Bl2Boot => Self::Bl2Boot,
       flashthing::Event::Resetting => Self::Resetting,
       flashthing::Event::Step(step_number, step_data) => Self::StepChanged {
         step: step_number as i32,
-        data: step_data.into(),
+        id: step_data.id.unwrap_or_default(),
+        data: step_data.step.into(),
+      },
+      flashthing::Event::StepCompleted { step, id } => Self::StepCompleted { step: step as i32, id },
+      flashthing::Event::StepFailed { step, id, error, code } => Self::StepFailed {
+        step: step as i32,
+        id,
+        error,
+        code: code.to_string(),
       },
       flashthing::Event::FlashProgress(flash_progress) => Self::FlashInfo {
         data: flash_progress.into(),
       },
+      flashthing::Event::ThermalPause { temperature } => Self::ThermalPause { temperature },
+      flashthing::Event::Timeout { step, id, reason } => Self::Timeout {
+        step: step as i32,
+        id,
+        reason,
+      },
+      flashthing::Event::TransferStalled { endpoint, stalled_for } => Self::TransferStalled {
+        endpoint: endpoint.into(),
+        stalled_for_ms: stalled_for.as_millis() as u32,
+      },
+      flashthing::Event::BootVerified { elapsed } => Self::BootVerified {
+        elapsed_ms: elapsed.as_millis() as u32,
+      },
+      flashthing::Event::BootLoopDetected { cycles } => Self::BootLoopDetected { cycles },
+      flashthing::Event::BootTimeout { timeout, mode } => Self::BootTimeout {
+        timeout_ms: timeout.as_millis() as u32,
+        mode: mode.into(),
+      },
+      flashthing::Event::Log { level, target, message } => Self::Log {
+        data: LogMessage {
+          level: level.as_str().to_string(),
+          target,
+          message,
+          timestamp: chrono::Utc::now().to_rfc3339(),
+        },
+      },
     }
   }
 }
@@ -100,6 +641,9 @@ impl From<flashthing::Event> for FlashEvent {
 pub struct MetaFile {
   pub file_path: String,
   pub encoding: Option<String>,
+  pub offset: Option<f64>,
+  pub length: Option<f64>,
+  pub template: Option<bool>,
 }
 
 impl From<flashthing::config::MetaFile> for MetaFile {
@@ -107,6 +651,9 @@ impl From<flashthing::config::MetaFile> for MetaFile {
     Self {
       file_path: meta.file_path,
       encoding: meta.encoding,
+      offset: meta.offset.map(|offset| offset.get() as f64),
+      length: meta.length.map(|length| length.get() as f64),
+      template: meta.template,
     }
   }
 }
@@ -114,14 +661,22 @@ impl From<flashthing::config::MetaFile> for MetaFile {
 #[napi]
 pub enum DataOrFile {
   Data,
+  Hex { hex: String },
+  Base64 { base64: String },
   File { file: MetaFile },
+  Concat { concat: Vec<MetaFile> },
 }
 
 impl From<flashthing::config::DataOrFile> for DataOrFile {
   fn from(data_or_file: flashthing::config::DataOrFile) -> Self {
     match data_or_file {
       flashthing::config::DataOrFile::Data(_) => Self::Data,
+      flashthing::config::DataOrFile::Hex { hex } => Self::Hex { hex },
+      flashthing::config::DataOrFile::Base64 { base64 } => Self::Base64 { base64 },
       flashthing::config::DataOrFile::File(file) => Self::File { file: file.into() },
+      flashthing::config::DataOrFile::Concat { concat } => Self::Concat {
+        concat: concat.into_iter().map(Into::into).collect(),
+      },
     }
   }
 }
@@ -152,6 +707,7 @@ pub enum FlashStep {
   BulkcmdStat {
     value: String,
     variable: Option<String>,
+    capture: Option<String>,
   },
   Run {
     value: RunValue,
@@ -165,10 +721,12 @@ pub enum FlashStep {
   ReadSimpleMemory {
     value: ReadMemoryValue,
     variable: Option<String>,
+    output: Option<StepOutput>,
   },
   ReadLargeMemory {
     value: ReadMemoryValue,
     variable: Option<String>,
+    output: Option<StepOutput>,
   },
   GetBootAmlc {
     variable: Option<String>,
@@ -186,6 +744,9 @@ pub enum FlashStep {
   RestorePartition {
     value: RestorePartitionValue,
   },
+  ApplyDelta {
+    value: ApplyDeltaValue,
+  },
   WriteBootPartition {
     value: WriteBootPartitionValue,
   },
@@ -195,12 +756,33 @@ pub enum FlashStep {
   WriteEnv {
     value: StringOrFile,
   },
+  WriteBootScript {
+    value: WriteBootScriptValue,
+  },
   Log {
     value: String,
   },
   Wait {
     value: WaitValue,
   },
+  Reset {
+    value: ResetValue,
+  },
+  Reconnect {
+    value: ReconnectValue,
+  },
+  Repeat {
+    value: RepeatValue,
+  },
+  Assert {
+    value: AssertValue,
+  },
+  Provision {
+    value: ProvisionValue,
+  },
+  PostBootCheck {
+    value: PostBootCheckValue,
+  },
 }
 
 impl From<flashthing::config::FlashStep> for FlashStep {
@@ -208,17 +790,35 @@ impl From<flashthing::config::FlashStep> for FlashStep {
     match step {
       flashthing::config::FlashStep::Identify { variable } => Self::Identify { variable },
       flashthing::config::FlashStep::Bulkcmd { value } => Self::Bulkcmd { value },
-      flashthing::config::FlashStep::BulkcmdStat { value, variable } => Self::BulkcmdStat { value, variable },
+      flashthing::config::FlashStep::BulkcmdStat {
+        value,
+        variable,
+        capture,
+      } => Self::BulkcmdStat {
+        value,
+        variable,
+        capture,
+      },
       flashthing::config::FlashStep::Run { value } => Self::Run { value: value.into() },
       flashthing::config::FlashStep::WriteSimpleMemory { value } => Self::WriteSimpleMemory { value: value.into() },
       flashthing::config::FlashStep::WriteLargeMemory { value } => Self::WriteLargeMemory { value: value.into() },
-      flashthing::config::FlashStep::ReadSimpleMemory { value, variable } => Self::ReadSimpleMemory {
+      flashthing::config::FlashStep::ReadSimpleMemory {
+        value,
+        variable,
+        output,
+      } => Self::ReadSimpleMemory {
         value: value.into(),
         variable,
+        output: output.map(Into::into),
       },
-      flashthing::config::FlashStep::ReadLargeMemory { value, variable } => Self::ReadLargeMemory {
+      flashthing::config::FlashStep::ReadLargeMemory {
+        value,
+        variable,
+        output,
+      } => Self::ReadLargeMemory {
         value: value.into(),
         variable,
+        output: output.map(Into::into),
       },
       flashthing::config::FlashStep::GetBootAMLC { variable } => Self::GetBootAmlc { variable },
       flashthing::config::FlashStep::WriteAMLCData { value } => Self::WriteAmlcData { value: value.into() },
@@ -228,11 +828,19 @@ impl From<flashthing::config::FlashStep> for FlashStep {
         variable,
       },
       flashthing::config::FlashStep::RestorePartition { value } => Self::RestorePartition { value: value.into() },
+      flashthing::config::FlashStep::ApplyDelta { value } => Self::ApplyDelta { value: value.into() },
       flashthing::config::FlashStep::WriteBootPartition { value } => Self::WriteBootPartition { value: value.into() },
       flashthing::config::FlashStep::WriteUserArea { value } => Self::WriteUserArea { value: value.into() },
       flashthing::config::FlashStep::WriteEnv { value } => Self::WriteEnv { value: value.into() },
+      flashthing::config::FlashStep::WriteBootScript { value } => Self::WriteBootScript { value: value.into() },
       flashthing::config::FlashStep::Log { value } => Self::Log { value },
       flashthing::config::FlashStep::Wait { value } => Self::Wait { value: value.into() },
+      flashthing::config::FlashStep::Reset { value } => Self::Reset { value: value.into() },
+      flashthing::config::FlashStep::Reconnect { value } => Self::Reconnect { value: value.into() },
+      flashthing::config::FlashStep::Repeat { value } => Self::Repeat { value: value.into() },
+      flashthing::config::FlashStep::Assert { value } => Self::Assert { value: value.into() },
+      flashthing::config::FlashStep::Provision { value } => Self::Provision { value: value.into() },
+      flashthing::config::FlashStep::PostBootCheck { value } => Self::PostBootCheck { value: value.into() },
     }
   }
 }
@@ -246,7 +854,7 @@ pub struct RunValue {
 impl From<flashthing::config::RunValue> for RunValue {
   fn from(value: flashthing::config::RunValue) -> Self {
     Self {
-      address: value.address,
+      address: value.address.get(),
       keep_power: value.keep_power,
     }
   }
@@ -261,7 +869,7 @@ pub struct WriteSimpleMemoryValue {
 impl From<flashthing::config::WriteSimpleMemoryValue> for WriteSimpleMemoryValue {
   fn from(value: flashthing::config::WriteSimpleMemoryValue) -> Self {
     Self {
-      address: value.address,
+      address: value.address.get(),
       data: value.data.into(),
     }
   }
@@ -269,19 +877,42 @@ impl From<flashthing::config::WriteSimpleMemoryValue> for WriteSimpleMemoryValue
 
 #[napi(object)]
 pub struct WriteLargeMemoryValue {
-  pub address: u32,
+  pub address: Option<u32>,
+  pub partition: Option<String>,
+  pub offset_in_partition: Option<u32>,
   pub data: DataOrFile,
   pub block_length: u32,
   pub append_zeros: Option<bool>,
+  pub compare_before_write: Option<bool>,
+  pub allow_cross_partition: Option<bool>,
+  pub patches: Option<Vec<Patch>>,
 }
 
 impl From<flashthing::config::WriteLargeMemoryValue> for WriteLargeMemoryValue {
   fn from(value: flashthing::config::WriteLargeMemoryValue) -> Self {
     Self {
-      address: value.address,
+      address: value.address.map(|address| address.get()),
+      partition: value.partition,
+      offset_in_partition: value.offset_in_partition.map(|offset| offset.get()),
       data: value.data.into(),
       block_length: value.block_length as u32,
       append_zeros: value.append_zeros,
+      compare_before_write: value.compare_before_write,
+      allow_cross_partition: value.allow_cross_partition,
+      patches: patches(value.patches),
+    }
+  }
+}
+
+#[napi(object)]
+pub struct StepOutput {
+  pub file_path: String,
+}
+
+impl From<flashthing::config::StepOutput> for StepOutput {
+  fn from(output: flashthing::config::StepOutput) -> Self {
+    Self {
+      file_path: output.file_path,
     }
   }
 }
@@ -295,8 +926,8 @@ pub struct ReadMemoryValue {
 impl From<flashthing::config::ReadMemoryValue> for ReadMemoryValue {
   fn from(value: flashthing::config::ReadMemoryValue) -> Self {
     Self {
-      address: value.address,
-      length: value.length as u32,
+      address: value.address.get(),
+      length: value.length.get(),
     }
   }
 }
@@ -348,6 +979,11 @@ impl From<flashthing::config::ValidatePartitionSizeValue> for ValidatePartitionS
 pub struct RestorePartitionValue {
   pub name: String,
   pub data: DataOrFile,
+  pub compare_before_write: Option<bool>,
+  pub confirm_special_partition: Option<bool>,
+  pub trim_padding: Option<bool>,
+  pub erase_trimmed: Option<bool>,
+  pub patches: Option<Vec<Patch>>,
 }
 
 impl From<flashthing::config::RestorePartitionValue> for RestorePartitionValue {
@@ -355,6 +991,26 @@ impl From<flashthing::config::RestorePartitionValue> for RestorePartitionValue {
     Self {
       name: value.name,
       data: value.data.into(),
+      compare_before_write: value.compare_before_write,
+      confirm_special_partition: value.confirm_special_partition,
+      trim_padding: value.trim_padding,
+      erase_trimmed: value.erase_trimmed,
+      patches: patches(value.patches),
+    }
+  }
+}
+
+#[napi(object)]
+pub struct ApplyDeltaValue {
+  pub name: String,
+  pub delta: DataOrFile,
+}
+
+impl From<flashthing::config::ApplyDeltaValue> for ApplyDeltaValue {
+  fn from(value: flashthing::config::ApplyDeltaValue) -> Self {
+    Self {
+      name: value.name,
+      delta: value.delta.into(),
     }
   }
 }
@@ -363,6 +1019,7 @@ impl From<flashthing::config::RestorePartitionValue> for RestorePartitionValue {
 pub struct WriteBootPartitionValue {
   pub hwpart: u8,
   pub data: DataOrFile,
+  pub patches: Option<Vec<Patch>>,
 }
 
 impl From<flashthing::config::WriteBootPartitionValue> for WriteBootPartitionValue {
@@ -370,6 +1027,7 @@ impl From<flashthing::config::WriteBootPartitionValue> for WriteBootPartitionVal
     Self {
       hwpart: value.hwpart,
       data: value.data.into(),
+      patches: patches(value.patches),
     }
   }
 }
@@ -378,6 +1036,8 @@ impl From<flashthing::config::WriteBootPartitionValue> for WriteBootPartitionVal
 pub struct WriteUserAreaValue {
   pub lba: u32,
   pub data: DataOrFile,
+  pub compare_before_write: Option<bool>,
+  pub patches: Option<Vec<Patch>>,
 }
 
 impl From<flashthing::config::WriteUserAreaValue> for WriteUserAreaValue {
@@ -385,6 +1045,56 @@ impl From<flashthing::config::WriteUserAreaValue> for WriteUserAreaValue {
     Self {
       lba: value.lba,
       data: value.data.into(),
+      compare_before_write: value.compare_before_write,
+      patches: patches(value.patches),
+    }
+  }
+}
+
+#[napi(object)]
+pub struct Patch {
+  pub offset: f64,
+  pub hex: String,
+}
+
+fn patches(patches: Option<Vec<flashthing::config::Patch>>) -> Option<Vec<Patch>> {
+  patches.map(|patches| {
+    patches
+      .into_iter()
+      .map(|patch| Patch {
+        offset: patch.offset.get() as f64,
+        hex: patch.hex,
+      })
+      .collect()
+  })
+}
+
+#[napi(string_enum)]
+pub enum ScriptArch {
+  Arm,
+  Arm64,
+}
+
+#[napi(object)]
+pub struct WriteBootScriptValue {
+  pub script: StringOrFile,
+  pub partition: String,
+  pub offset_in_partition: Option<u32>,
+  pub name: Option<String>,
+  pub arch: Option<ScriptArch>,
+}
+
+impl From<flashthing::config::WriteBootScriptValue> for WriteBootScriptValue {
+  fn from(value: flashthing::config::WriteBootScriptValue) -> Self {
+    Self {
+      script: value.script.into(),
+      partition: value.partition,
+      offset_in_partition: value.offset_in_partition.map(|offset| offset.get()),
+      name: value.name,
+      arch: value.arch.map(|arch| match arch {
+        flashthing::config::ScriptArch::Arm => ScriptArch::Arm,
+        flashthing::config::ScriptArch::Arm64 => ScriptArch::Arm64,
+      }),
     }
   }
 }
@@ -403,3 +1113,149 @@ impl From<flashthing::config::WaitValue> for WaitValue {
     }
   }
 }
+
+#[napi(string_enum)]
+pub enum ResetMode {
+  Soft,
+  Burn,
+}
+
+#[napi(object)]
+pub struct ResetValue {
+  pub mode: ResetMode,
+}
+
+impl From<flashthing::config::ResetValue> for ResetValue {
+  fn from(value: flashthing::config::ResetValue) -> Self {
+    Self {
+      mode: match value.mode {
+        flashthing::config::ResetMode::Soft => ResetMode::Soft,
+        flashthing::config::ResetMode::Burn => ResetMode::Burn,
+      },
+    }
+  }
+}
+
+#[napi(object)]
+pub struct ReconnectValue {
+  pub timeout: Option<u32>,
+}
+
+impl From<flashthing::config::ReconnectValue> for ReconnectValue {
+  fn from(value: flashthing::config::ReconnectValue) -> Self {
+    Self {
+      timeout: value.timeout.map(|timeout| timeout as u32),
+    }
+  }
+}
+
+#[napi(object)]
+pub struct RepeatValue {
+  pub count: u32,
+  pub steps: Vec<StepInfo>,
+}
+
+impl From<flashthing::config::RepeatValue> for RepeatValue {
+  fn from(value: flashthing::config::RepeatValue) -> Self {
+    Self {
+      count: value.count,
+      steps: value
+        .steps
+        .into_iter()
+        .enumerate()
+        .map(|(i, step)| (i + 1, step).into())
+        .collect(),
+    }
+  }
+}
+
+#[napi(object)]
+pub struct AssertValue {
+  pub variable: Option<String>,
+  pub bulkcmd: Option<String>,
+  pub equals: Option<String>,
+  pub contains: Option<String>,
+  pub matches: Option<String>,
+  pub message: Option<String>,
+}
+
+impl From<flashthing::config::AssertValue> for AssertValue {
+  fn from(value: flashthing::config::AssertValue) -> Self {
+    Self {
+      variable: value.variable,
+      bulkcmd: value.bulkcmd,
+      equals: value.equals,
+      contains: value.contains,
+      matches: value.matches,
+      message: value.message,
+    }
+  }
+}
+
+#[napi(object)]
+pub struct PostBootCheckValue {
+  pub timeout: Option<u32>,
+  pub checks: Vec<PostBootCommand>,
+}
+
+impl From<flashthing::config::PostBootCheckValue> for PostBootCheckValue {
+  fn from(value: flashthing::config::PostBootCheckValue) -> Self {
+    Self {
+      timeout: value.timeout.map(|timeout| timeout as u32),
+      checks: value
+        .checks
+        .into_iter()
+        .map(|check| PostBootCommand {
+          command: check.command,
+          equals: check.equals,
+          contains: check.contains,
+          matches: check.matches,
+          message: check.message,
+        })
+        .collect(),
+    }
+  }
+}
+
+#[napi(object)]
+pub struct PostBootCommand {
+  pub command: String,
+  pub equals: Option<String>,
+  pub contains: Option<String>,
+  pub matches: Option<String>,
+  pub message: Option<String>,
+}
+
+#[napi(object)]
+pub struct ProvisionValue {
+  pub variables: Vec<String>,
+  pub env: Option<HashMap<String, String>>,
+  pub data: Option<ProvisionDataValue>,
+}
+
+impl From<flashthing::config::ProvisionValue> for ProvisionValue {
+  fn from(value: flashthing::config::ProvisionValue) -> Self {
+    Self {
+      variables: value.variables,
+      env: value.env.map(|env| env.into_iter().collect()),
+      data: value.data.map(Into::into),
+    }
+  }
+}
+
+#[napi(object)]
+pub struct ProvisionDataValue {
+  pub partition: String,
+  pub offset_in_partition: Option<u32>,
+  pub text: String,
+}
+
+impl From<flashthing::config::ProvisionDataValue> for ProvisionDataValue {
+  fn from(value: flashthing::config::ProvisionDataValue) -> Self {
+    Self {
+      partition: value.partition,
+      offset_in_partition: value.offset_in_partition.map(|offset| offset.get()),
+      text: value.text,
+    }
+  }
+}
@@ -1,9 +1,14 @@
-#![allow(clippy::missing_safety_doc)]
-
 mod conversion;
 mod monitoring;
 
-use std::{path::PathBuf, sync::Arc};
+use std::{
+  path::PathBuf,
+  sync::{
+    Arc, Mutex,
+    atomic::{AtomicUsize, Ordering},
+  },
+  time::Duration,
+};
 
 use conversion::*;
 use monitoring::init_logger;
@@ -17,14 +22,60 @@ type FlasherCallbackHandler = Arc<dyn Fn(flashthing::Event) + Send + Sync>;
 #[derive(Debug, Clone, Default)]
 pub struct FlashThingOptions {
   pub log_level_directive: Option<String>,
+  /// how much to log when no `logLevelDirective` is given (defaults to `RUST_LOG`, or normal)
+  pub verbosity: Option<Verbosity>,
+  /// libusb log level, logged to stderr
+  pub usb_log_level: Option<UsbLogLevel>,
+  /// minimum milliseconds between two progress events, 0 sends every update (defaults to 100)
+  pub progress_interval_ms: Option<u32>,
+  /// how events are handed to the callback (defaults to blocking)
+  pub event_delivery: Option<EventDelivery>,
+  /// environment variables packages may use as `${env_NAME}`
+  pub env_variables: Option<Vec<String>>,
+  /// file of `name=value` lines with the values `provision` steps write to the next device
+  pub provision_counter: Option<String>,
+  /// per-device values for the package's variables, used instead of the package's `overrides.json`
+  pub overrides_path: Option<String>,
+  /// directory read steps write their `output` files to (defaults to the current directory)
+  pub output_dir: Option<String>,
+  /// directory to keep the report, checkpoint, read step outputs and rollback backups of each flash under
+  pub artifacts_dir: Option<String>,
+  /// move data to the device 64 KiB at a time, for hosts like Raspberry Pis whose large bulk writes stall
+  pub limited_bulk: Option<bool>,
+  /// split bulk writes into USB transfers of at most this many bytes, 0 to send them whole; defaults to 256 KiB on Windows
+  pub max_urb_size: Option<u32>,
+  /// cancel and retry a usb transfer that goes this many milliseconds without finishing, 0 to wait on it for good; defaults to 30 seconds
+  pub transfer_watchdog_ms: Option<u32>,
+  /// wait this many milliseconds for the device to be put in usb mode, sending `ActionRequired` events, instead of failing straight away
+  pub wait_for_device_ms: Option<u32>,
+  /// once flashing succeeds, wait this many milliseconds for the device to boot, sending `BootVerified` or `BootTimeout`
+  pub verify_boot_ms: Option<u32>,
+  /// record the env variables writeEnv steps change, and what they held, in the report's `envChanges`
+  pub diff_env: Option<bool>,
+  /// write a zip of each flash's trace level logs, report, package metadata, host details and usb descriptors here, for a bug report
+  pub bug_report_path: Option<String>,
 }
 
 // The main FlashThing class
 #[napi]
 pub struct FlashThing {
   callback: FlasherCallbackHandler,
-  flasher: Option<flashthing::Flasher>,
-  num_steps: usize,
+  progress_interval: Option<Duration>,
+  event_delivery: Option<EventDelivery>,
+  env_variables: Vec<String>,
+  provision_counter: Option<String>,
+  overrides_path: Option<String>,
+  output_dir: Option<String>,
+  artifacts_dir: Option<String>,
+  usb_quirks: flashthing::UsbQuirks,
+  wait_for_device: Option<Duration>,
+  verify_boot: Option<Duration>,
+  diff_env: bool,
+  bug_report_path: Option<String>,
+  /// Held by whichever flash is running, so flashes run one at a time
+  flasher: Arc<Mutex<Option<flashthing::Flasher>>>,
+  num_steps: AtomicUsize,
+  steps: Mutex<Vec<flashthing::config::Step>>,
 }
 
 #[napi]
@@ -35,121 +86,270 @@ impl FlashThing {
   )]
   pub fn new(callback: Function<FlashEvent, Unknown<'static>>, options: Option<FlashThingOptions>) -> Result<Self> {
     let (tsfn, callback) = create_callback(callback)?;
-    init_logger(tsfn, options.unwrap_or_default().log_level_directive);
+    let options = options.unwrap_or_default();
+    let directive = options.log_level_directive.or_else(|| {
+      let verbosity = options.verbosity.map(flashthing::Verbosity::from)?;
+      Some(verbosity.directives().to_string())
+    });
+    init_logger(tsfn, directive);
+
+    if let Some(level) = options.usb_log_level {
+      flashthing::AmlogicSoC::set_usb_log_level(level.into())
+        .map_err(|e| Error::from_reason(format!("Failed to set usb log level: {}", e)))?;
+    }
 
     Ok(Self {
       callback,
+      progress_interval: options.progress_interval_ms.map(|ms| Duration::from_millis(ms.into())),
+      event_delivery: options.event_delivery,
+      env_variables: options.env_variables.unwrap_or_default(),
+      provision_counter: options.provision_counter,
+      overrides_path: options.overrides_path,
+      output_dir: options.output_dir,
+      artifacts_dir: options.artifacts_dir,
+      usb_quirks: flashthing::UsbQuirks {
+        limited_bulk: options.limited_bulk.unwrap_or(false),
+        max_urb_size: match options.max_urb_size {
+          Some(0) => None,
+          Some(bytes) => Some(bytes as usize),
+          None => flashthing::UsbQuirks::new().max_urb_size,
+        },
+        transfer_watchdog: match options.transfer_watchdog_ms {
+          Some(0) => None,
+          Some(ms) => Some(Duration::from_millis(ms.into())),
+          None => flashthing::UsbQuirks::new().transfer_watchdog,
+        },
+      },
+      wait_for_device: options.wait_for_device_ms.map(|ms| Duration::from_millis(ms.into())),
+      verify_boot: options.verify_boot_ms.map(|ms| Duration::from_millis(ms.into())),
+      diff_env: options.diff_env.unwrap_or(false),
+      bug_report_path: options.bug_report_path,
 
-      flasher: None,
-      num_steps: 0,
+      flasher: Arc::default(),
+      num_steps: AtomicUsize::new(0),
+      steps: Mutex::default(),
     })
   }
 
   #[napi]
-  pub async unsafe fn open_directory(&mut self, path: String) -> Result<()> {
-    let path_buf = PathBuf::from(path);
-    match flashthing::Flasher::from_directory(path_buf, Some(self.callback.clone())) {
-      Ok(flasher) => {
-        self.num_steps = flasher.num_steps();
-        self.flasher = Some(flasher);
-        Ok(())
-      }
-      Err(e) => Err(Error::from_reason(format!("Failed to create flasher: {}", e))),
-    }
+  pub async fn open_directory(&self, path: String) -> Result<()> {
+    self
+      .open(move |builder| builder.from_directory(PathBuf::from(path)))
+      .await
   }
 
   #[napi]
-  pub async unsafe fn open_archive(&mut self, path: String) -> Result<()> {
-    let path_buf = PathBuf::from(path);
-    match flashthing::Flasher::from_archive(path_buf, Some(self.callback.clone())) {
-      Ok(flasher) => {
-        self.num_steps = flasher.num_steps();
-        self.flasher = Some(flasher);
-        Ok(())
-      }
-      Err(e) => Err(Error::from_reason(format!("Failed to create flasher: {}", e))),
-    }
+  pub async fn open_archive(&self, path: String) -> Result<()> {
+    self
+      .open(move |builder| builder.from_archive(PathBuf::from(path)))
+      .await
   }
 
   #[napi]
-  pub async unsafe fn open_json(&mut self, json: String) -> Result<()> {
-    match flashthing::Flasher::from_json(json, Some(self.callback.clone())) {
-      Ok(flasher) => {
-        self.num_steps = flasher.num_steps();
-        self.flasher = Some(flasher);
-        Ok(())
-      }
-      Err(e) => Err(Error::from_reason(format!("Failed to create flasher: {}", e))),
-    }
+  pub async fn open_json(&self, json: String) -> Result<()> {
+    self.open(move |builder| builder.from_json(json)).await
   }
 
+  /// `profile` names the layout of the dump, e.g. `superbird-dump`; defaults to `superbird`
   #[napi]
-  pub async unsafe fn open_stock_directory(&mut self, path: String) -> Result<()> {
-    let path_buf = PathBuf::from(path);
-    match flashthing::Flasher::from_stock_directory(path_buf, Some(self.callback.clone())) {
-      Ok(flasher) => {
-        self.num_steps = flasher.num_steps();
-        self.flasher = Some(flasher);
-        Ok(())
-      }
-      Err(e) => Err(Error::from_reason(format!("Failed to create flasher: {}", e))),
-    }
+  pub async fn open_stock_directory(&self, path: String, profile: Option<String>) -> Result<()> {
+    self
+      .open(move |builder| match profile {
+        Some(profile) => builder.from_stock_with(profile.parse()?, PathBuf::from(path)),
+        None => builder.from_stock_directory(PathBuf::from(path)),
+      })
+      .await
   }
 
+  /// `profile` names the layout of the dump, e.g. `superbird-dump`; defaults to `superbird`
   #[napi]
-  pub async unsafe fn open_stock_archive(&mut self, path: String) -> Result<()> {
-    let path_buf = PathBuf::from(path);
-    match flashthing::Flasher::from_stock_archive(path_buf, Some(self.callback.clone())) {
-      Ok(flasher) => {
-        self.num_steps = flasher.num_steps();
-        self.flasher = Some(flasher);
-        Ok(())
-      }
-      Err(e) => Err(Error::from_reason(format!("Failed to create flasher: {}", e))),
-    }
+  pub async fn open_stock_archive(&self, path: String, profile: Option<String>) -> Result<()> {
+    self
+      .open(move |builder| match profile {
+        Some(profile) => builder.from_stock_with(profile.parse()?, PathBuf::from(path)),
+        None => builder.from_stock_archive(PathBuf::from(path)),
+      })
+      .await
   }
 
   /// Method to get total number of steps
   #[napi]
   pub fn get_num_steps(&self) -> u32 {
-    self.num_steps as u32
+    self.num_steps.load(Ordering::Relaxed) as u32
+  }
+
+  /// Method to get the steps of the loaded package in the order they run, with their ids
+  #[napi]
+  pub fn get_steps(&self) -> Result<Vec<StepInfo>> {
+    let steps = lock(&self.steps)?.clone();
+    Ok(
+      steps
+        .into_iter()
+        .enumerate()
+        .map(|(i, step)| (i + 1, step).into())
+        .collect(),
+    )
+  }
+
+  /// Method to get the report for the most recent flash, or nothing while a flash is running
+  #[napi]
+  pub fn get_report(&self) -> Option<FlashReport> {
+    let flasher = self.flasher.try_lock().ok()?;
+    flasher.as_ref().map(|flasher| flasher.report().into())
+  }
+
+  /// Method to get the loaded package as a shell script of the equivalent `update` tool commands, or nothing while a flash is running
+  #[napi]
+  pub fn export_script(&self) -> Option<String> {
+    let flasher = self.flasher.try_lock().ok()?;
+    flasher.as_ref().map(|flasher| flasher.export_script())
   }
 
   ///  Method to flash with progress callback
+  ///
+  /// Rejects with `[AWAITING_USER_INPUT]` and the message at a `wait` for user input; call it again to carry on
   #[napi]
-  pub async unsafe fn flash(&mut self) -> Result<()> {
-    let Some(flasher) = &mut self.flasher else {
-      return Err(Error::from_reason("Flasher is not initialized".to_string()));
-    };
-
-    match flasher.flash() {
-      Ok(_) => Ok(()),
-      Err(e) => Err(Error::from_reason(format!("Flashing failed: {}", e))),
-    }
+  pub async fn flash(&self) -> Result<()> {
+    let flasher = self.flasher.clone();
+    run_blocking(move || {
+      let mut flasher = lock(&flasher)?;
+      let Some(flasher) = flasher.as_mut() else {
+        return Err(Error::from_reason("Flasher is not initialized".to_string()));
+      };
+
+      match flasher.flash() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(flash_error("Flashing failed", e)),
+      }
+    })
+    .await
   }
 
   /// Utility method to unbrick a device
   #[napi]
-  pub async unsafe fn unbrick(&mut self) -> Result<()> {
-    match flashthing::AmlogicSoC::init(Some(self.callback.clone())) {
+  pub async fn unbrick(&self) -> Result<()> {
+    let callback = self.callback.clone();
+    run_blocking(move || match flashthing::AmlogicSoC::init(Some(callback)) {
       Ok(aml) => match aml.unbrick() {
         Ok(()) => Ok(()),
-        Err(e) => Err(Error::from_reason(format!("Failed to unbrick: {}", e))),
+        Err(e) => Err(flash_error("Failed to unbrick", e)),
       },
-      Err(e) => Err(Error::from_reason(format!("Failed to initialize device: {}", e))),
+      Err(e) => Err(flash_error("Failed to initialize device", e)),
+    })
+    .await
+  }
+
+  /// Set up host for flashing (installs udev rules on Linux, checks for common access problems on macOS)
+  #[napi]
+  pub fn host_setup(&self, group: Option<String>) -> Result<HostSetupStatus> {
+    let profile = flashthing::DeviceProfile::default();
+    match flashthing::AmlogicSoC::host_setup_with_group(&profile, group.as_deref()) {
+      Ok(status) => Ok(status.into()),
+      Err(e) => Err(flash_error("Failed to set up host", e)),
     }
   }
 
-  /// Set up host for flashing (this currently only does anything on Linux)
+  /// Read a package directory or zip archive without a device, e.g. to show it before flashing
+  #[napi]
+  pub async fn inspect_package(&self, path: String) -> Result<PackageInfo> {
+    run_blocking(move || {
+      let package = flashthing::Package::open(path.as_ref()).map_err(|e| flash_error("Failed to open package", e))?;
+      Ok((&package).into())
+    })
+    .await
+  }
+
+  /// Get the udev rules `hostSetup` installs on Linux, for installing them without polkit
+  #[napi]
+  pub fn host_setup_rules(&self, group: Option<String>) -> Result<String> {
+    flashthing::AmlogicSoC::host_setup_rules_with_group(group.as_deref())
+      .map_err(|e| flash_error("Failed to get udev rules", e))
+  }
+
+  /// Get what this build of flashthing supports, to check before relying on it
   #[napi]
-  pub fn host_setup(&self) -> Result<()> {
-    match flashthing::AmlogicSoC::host_setup() {
-      Ok(()) => Ok(()),
-      Err(e) => Err(Error::from_reason(format!("Failed to set up host: {}", e))),
+  pub fn capabilities(&self) -> Capabilities {
+    flashthing::Capabilities::current().into()
+  }
+}
+
+impl FlashThing {
+  /// Load a package off the JS thread, then replace the current flasher with it
+  async fn open<F>(&self, open: F) -> Result<()>
+  where
+    F: FnOnce(flashthing::FlasherBuilder) -> flashthing::Result<flashthing::Flasher> + Send + 'static,
+  {
+    let mut builder = flashthing::Flasher::builder()
+      .callback(Some(self.callback.clone()))
+      .env_variables(self.env_variables.clone())
+      .usb_quirks(self.usb_quirks);
+    if let Some(interval) = self.progress_interval {
+      builder = builder.progress_interval(interval);
+    }
+    if let Some(timeout) = self.wait_for_device {
+      builder = builder.wait_for_device(timeout);
+    }
+    if let Some(timeout) = self.verify_boot {
+      builder = builder.verify_boot(timeout);
+    }
+    if self.diff_env {
+      builder = builder.diff_env();
     }
+    if let Some(delivery) = self.event_delivery {
+      builder = builder.event_delivery(delivery.into());
+    }
+    if let Some(counter) = &self.provision_counter {
+      builder = builder.provisioner(flashthing::Provisioner::Counter(counter.into()));
+    }
+    if let Some(dir) = &self.output_dir {
+      builder = builder.output_dir(dir.into());
+    }
+    if let Some(dir) = &self.artifacts_dir {
+      builder = builder.artifacts_dir(dir.into());
+    }
+    if let Some(path) = &self.overrides_path {
+      let overrides =
+        flashthing::Overrides::load(path.as_ref()).map_err(|e| flash_error("Failed to load overrides", e))?;
+      builder = builder.overrides(overrides);
+    }
+
+    let mut flasher =
+      run_blocking(move || open(builder).map_err(|e| flash_error("Failed to create flasher", e))).await?;
+    if let Some(path) = &self.bug_report_path {
+      flasher.enable_session_capture(path);
+    }
+
+    let num_steps = flasher.num_steps();
+    let steps = flasher.steps().to_vec();
+    *lock(&self.flasher)? = Some(flasher);
+    self.num_steps.store(num_steps, Ordering::Relaxed);
+    *lock(&self.steps)? = steps;
+    Ok(())
   }
 }
 
+/// Run blocking USB and file work on the runtime's blocking pool instead of an async worker
+async fn run_blocking<T, F>(work: F) -> Result<T>
+where
+  T: Send + 'static,
+  F: FnOnce() -> Result<T> + Send + 'static,
+{
+  spawn_blocking(work)
+    .await
+    .map_err(|e| Error::from_reason(format!("Flashing task failed: {}", e)))?
+}
+
+/// Make a JS error out of a flashthing error, with its stable code leading the reason, e.g. `[WRONG_MODE] ...`
+fn flash_error(context: &str, err: flashthing::Error) -> Error {
+  Error::from_reason(format!("[{}] {}: {}", err.code(), context, err))
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> Result<std::sync::MutexGuard<'_, T>> {
+  mutex
+    .lock()
+    .map_err(|_| Error::from_reason("a previous flash panicked".to_string()))
+}
+
 fn create_callback(
   callback: Function<FlashEvent, Unknown<'static>>,
 ) -> Result<(Arc<FlashCallback>, FlasherCallbackHandler)> {
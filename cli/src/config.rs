@@ -0,0 +1,133 @@
+//! Named profiles of default options in `~/.config/flashthing/config.toml`, picked with `--profile`.
+
+use std::{collections::BTreeMap, fmt::Display, path::PathBuf, str::FromStr};
+
+use clap::ValueEnum;
+use flashthing::DeviceTarget;
+use serde::{Deserialize, Deserializer};
+
+/// Environment variable that points the CLI at a config file other than the default one
+pub const CONFIG_ENV: &str = "FLASHTHING_CONFIG";
+
+/// The config file, a default profile and the profiles by name
+///
+/// ```toml
+/// default-profile = "station"
+///
+/// [profiles.station]
+/// package-dir = "/srv/flashthing/thingos"
+/// artifacts-dir = "/srv/flashthing/artifacts"
+/// verbosity = "quiet"
+/// wait = 120
+/// usb-quirks = ["limited-bulk"]
+/// ```
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+  /// Profile used when `--profile` is not given
+  default_profile: Option<String>,
+  #[serde(default)]
+  profiles: BTreeMap<String, Profile>,
+}
+
+/// Defaults for options not given on the command line
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+  /// Package to flash when no path is given
+  pub package_dir: Option<PathBuf>,
+  /// How much to log
+  pub verbosity: Option<Verbosity>,
+  /// Device to talk to, as `usb` or `image:PATH`
+  #[serde(default, deserialize_with = "from_str")]
+  pub target: Option<DeviceTarget>,
+  /// Directory to keep each flash's report, checkpoint, read step outputs and rollback backups under
+  pub artifacts_dir: Option<PathBuf>,
+  /// Seconds to wait for the device to be put in USB mode
+  pub wait: Option<u64>,
+  /// Seconds a USB transfer can go without finishing before it is cancelled and retried, 0 to wait on it for good
+  pub transfer_watchdog: Option<u64>,
+  /// Largest USB transfer in KiB, 0 to send bulk writes whole
+  pub max_urb_size: Option<usize>,
+  /// Workarounds for the host's USB stack
+  #[serde(default)]
+  pub usb_quirks: Vec<UsbQuirk>,
+  /// Seconds flashing may take before it is aborted
+  pub deadline: Option<u64>,
+}
+
+/// How much the CLI logs
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Verbosity {
+  Quiet,
+  Normal,
+  Verbose,
+  Trace,
+}
+
+impl From<Verbosity> for flashthing::Verbosity {
+  fn from(verbosity: Verbosity) -> Self {
+    match verbosity {
+      Verbosity::Quiet => Self::Quiet,
+      Verbosity::Normal => Self::Normal,
+      Verbosity::Verbose => Self::Verbose,
+      Verbosity::Trace => Self::Trace,
+    }
+  }
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UsbQuirk {
+  LimitedBulk,
+}
+
+impl Profile {
+  /// Load the profile `name`, or the config's default profile if no name is given
+  ///
+  /// The config is read from [`CONFIG_ENV`] if it is set, or `~/.config/flashthing/config.toml`.
+  /// Without a config file or a profile to pick, every option keeps its usual default.
+  pub fn load(name: Option<&str>) -> Result<Self, String> {
+    let Some(path) = config_path() else {
+      return match name {
+        Some(name) => Err(format!("no config file to find profile {name:?} in")),
+        None => Ok(Self::default()),
+      };
+    };
+    let config = match std::fs::read_to_string(&path) {
+      Ok(toml) => {
+        toml::from_str::<Config>(&toml).map_err(|err| format!("invalid config {}: {}", path.display(), err))?
+      }
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound && name.is_none() => Config::default(),
+      Err(err) => return Err(format!("failed to read config {}: {}", path.display(), err)),
+    };
+
+    let Some(name) = name.or(config.default_profile.as_deref()) else {
+      return Ok(Self::default());
+    };
+    config
+      .profiles
+      .get(name)
+      .cloned()
+      .ok_or_else(|| format!("{} has no profile {:?}", path.display(), name))
+  }
+}
+
+/// Where the config file is
+fn config_path() -> Option<PathBuf> {
+  if let Some(path) = std::env::var_os(CONFIG_ENV) {
+    return Some(path.into());
+  }
+  let dir = std::env::var_os("XDG_CONFIG_HOME")
+    .map(PathBuf::from)
+    .or_else(|| dirs::home_dir().map(|home| home.join(".config")))?;
+  Some(dir.join("flashthing").join("config.toml"))
+}
+
+/// Parse an optional string field with the type's [`FromStr`]
+fn from_str<'de, D: Deserializer<'de>, T: FromStr<Err: Display>>(deserializer: D) -> Result<Option<T>, D::Error> {
+  Option::<String>::deserialize(deserializer)?
+    .map(|s| s.parse().map_err(serde::de::Error::custom))
+    .transpose()
+}
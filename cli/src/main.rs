@@ -1,42 +1,371 @@
+mod config;
 mod monitoring;
+mod shell;
+#[cfg(feature = "self-update")]
+mod update;
 
-use std::{env, ffi::OsStr, path::PathBuf};
+use std::{env, ffi::OsStr, path::PathBuf, time::Duration};
 
-use clap::Parser;
-use flashthing::Flasher;
+use clap::{CommandFactory, Parser, Subcommand};
+use config::{Profile, UsbQuirk, Verbosity};
+use flashthing::{
+  AmlogicSoC, CancelToken, DeviceProfile, DeviceTarget, Flasher, FlasherBuilder, StockProfile, UsbQuirks,
+};
 
 #[derive(Parser, Debug)]
 #[command(
   author = "Joey Eamigh",
   version = "0.1.0",
   about = "cli for flashing the Spotify Car Thing",
-  long_about = None
+  long_about = None,
+  args_conflicts_with_subcommands = true,
+  disable_version_flag = true
 )]
 struct Args {
-  /// Path to a zip file or a directory. Defaults to the current working directory if omitted.
+  #[command(subcommand)]
+  command: Option<Command>,
+  /// Path to a zip file or a directory. Defaults to the profile's `package-dir`, or the current working directory.
   path: Option<PathBuf>,
+  /// Take defaults for options not given from profile NAME in `~/.config/flashthing/config.toml`, instead of its `default-profile`.
+  #[arg(long, global = true, value_name = "NAME")]
+  profile: Option<String>,
+  /// How much to log. Ignored if RUST_LOG is set.
+  #[arg(long, global = true, value_name = "LEVEL")]
+  verbosity: Option<Verbosity>,
   /// Whether the directory or archive contains a stock dump with no `meta.json` file.
   #[arg(short, long, action)]
   stock: bool,
+  /// Layout of the stock dump: `superbird` for system partitions as `.ext2` images, `superbird-dump` for every partition as `.dump`. Implies `--stock`. Defaults to `superbird`.
+  #[arg(long, value_name = "PROFILE")]
+  stock_profile: Option<StockProfile>,
   /// Whether to unbrick the device.
   #[arg(long, action)]
   unbrick: bool,
-  /// setup host - this currently only sets up udev rules on Linux
+  /// setup host - sets up udev rules on Linux and checks for common access problems on macOS
   #[arg(long, action)]
   setup: bool,
+  /// Print the udev rules `--setup` would install, for installing them by hand.
+  #[arg(long, action)]
+  udev_rules: bool,
+  /// Also give members of GROUP access in the udev rules `--setup` installs, for headless machines with no logged-in user. Defaults to `plugdev` if GROUP is left out.
+  #[arg(long, value_name = "GROUP", num_args = 0..=1, default_missing_value = "plugdev")]
+  udev_group: Option<String>,
   /// Send a single u-boot command to a device in USB burn mode and print its response.
   #[arg(long, value_name = "CMD")]
   bulkcmd: Option<String>,
+  /// Time moving data to and from the memory of a device in USB burn mode, without writing to its eMMC.
+  #[arg(long, action)]
+  bench_transport: bool,
+  /// Enable libusb debug output on stderr, useful when reporting USB transport issues.
+  #[arg(long, action)]
+  usb_debug: bool,
+  /// Abort flashing if it has not finished within this many seconds.
+  #[arg(long, value_name = "SECONDS")]
+  deadline: Option<u64>,
+  /// Once flashing succeeds, wait up to SECONDS for the device to boot and warn if it does not.
+  #[arg(long, value_name = "SECONDS")]
+  verify_boot: Option<u64>,
+  /// Print the env variables `writeEnv` steps change, with the values they had before.
+  #[arg(long, action)]
+  diff_env: bool,
+  /// Back up the bootloader, env, fip and dtbo partitions and the boot hwpartitions to DIR before changing them, and restore them if flashing fails.
+  #[arg(long, value_name = "DIR")]
+  rollback_dir: Option<PathBuf>,
+  /// Write the `output` files of read steps to DIR instead of the current directory.
+  #[arg(long, value_name = "DIR")]
+  output_dir: Option<PathBuf>,
+  /// Keep the report, checkpoint, read step outputs and rollback backups of the flash together under DIR.
+  #[arg(long, value_name = "DIR")]
+  artifacts_dir: Option<PathBuf>,
+  /// Match file paths in `meta.json` to files that differ only in case, for packages made on case-insensitive filesystems.
+  #[arg(long, action)]
+  lenient_paths: bool,
+  /// Print the package as a shell script of the equivalent `update` tool commands instead of flashing it.
+  #[arg(long, action)]
+  export_script: bool,
+  /// Write a zip of the flash's trace level logs, report, package metadata, host details and USB descriptors to FILE, to attach to a bug report.
+  #[arg(long, value_name = "FILE")]
+  bug_report: Option<PathBuf>,
+  /// Skip flashing if the device says it already runs this version of the package, or a newer one.
+  #[arg(long, action)]
+  skip_installed: bool,
+  /// Start flashing at STEP, given as a step id or a number counting from 1, skipping the steps before it.
+  #[arg(long, value_name = "STEP")]
+  from_step: Option<String>,
+  /// Carry on the `--from-step` step at byte BYTES of its data, as printed when a flash was cancelled part way through a write.
+  #[arg(long, value_name = "BYTES", requires = "from_step")]
+  from_offset: Option<u64>,
+  /// Leave out STEP, given as a step id or a number counting from 1. Can be given more than once.
+  #[arg(long = "skip-step", value_name = "STEP")]
+  skip_steps: Vec<String>,
+  /// Let the package use the environment variable NAME as `${env_NAME}`. Can be given more than once.
+  #[arg(long = "allow-env", value_name = "NAME")]
+  allow_env: Vec<String>,
+  /// File of `name=value` lines with the values `provision` steps write to the next device, such as its serial number. Each value counts up once it is written.
+  #[arg(long, value_name = "FILE")]
+  provision_counter: Option<PathBuf>,
+  /// Per-device values for the package's variables, by device serial number, used instead of the package's `overrides.json`.
+  #[arg(long, value_name = "FILE")]
+  overrides: Option<PathBuf>,
+  /// Device to talk to: `usb`, or `image:PATH` to simulate one with a local disk image. Defaults to `usb`.
+  #[arg(long, global = true, value_name = "TARGET")]
+  target: Option<DeviceTarget>,
+  /// Work around a problem with the host's USB stack. `limited-bulk` moves data to the device 64 KiB at a time, for hosts like Raspberry Pis whose large bulk writes stall.
+  #[arg(long = "usb-quirk", global = true, value_name = "QUIRK")]
+  usb_quirks: Vec<UsbQuirk>,
+  /// Split bulk writes into USB transfers of at most KIB kibibytes, or 0 to send them whole. Defaults to 256 on Windows, whose USB stacks can fail larger writes, and 0 elsewhere.
+  #[arg(long, global = true, value_name = "KIB")]
+  max_urb_size: Option<usize>,
+  /// Cancel and retry a USB transfer that goes SECS seconds without finishing, or 0 to wait on it for good. Defaults to 30.
+  #[arg(long, global = true, value_name = "SECS")]
+  transfer_watchdog: Option<u64>,
+  /// Wait up to SECS seconds for the device to be put in USB mode, with instructions for doing so, instead of failing straight away.
+  #[arg(long, value_name = "SECS")]
+  wait: Option<u64>,
+  /// If the device is booted normally into firmware that runs adb, reboot it into USB burn mode through adb before flashing.
+  #[cfg(feature = "adb")]
+  #[arg(long, action)]
+  adb: bool,
+  /// Print version.
+  #[arg(short = 'V', long, action)]
+  version: bool,
+  /// With `--version`, print the supported metadata versions, features, built-in resources and device profiles too, as JSON.
+  #[arg(long, action, requires = "version")]
+  json: bool,
 }
 
-fn main() {
-  monitoring::init_logger();
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Save the device's env, partition table, partition contents and system slot digests to a snapshot file.
+  Snapshot {
+    /// Where to write the snapshot.
+    #[arg(default_value = "flashthing.snap")]
+    output: PathBuf,
+  },
+  /// Dump partitions or the whole disk to a directory, with a `manifest.json` of sizes and SHA-256 digests.
+  Dump {
+    /// Directory to write the dump to.
+    #[arg(default_value = "dump")]
+    output: PathBuf,
+    /// Partition to dump, can be given more than once. Defaults to every readable partition.
+    #[arg(short, long = "partition", value_name = "NAME", conflicts_with = "disk")]
+    partitions: Vec<String>,
+    /// Dump the whole user area to a single `disk.img` instead of one file per partition.
+    #[arg(long, action)]
+    disk: bool,
+    /// Carry on from where an interrupted dump into the same directory stopped.
+    #[arg(long, action)]
+    resume: bool,
+    /// Split files larger than GB gigabytes into `.001`, `.002`, … parts, e.g. `--split 4` for FAT32 drives.
+    #[arg(long, value_name = "GB")]
+    split: Option<u64>,
+  },
+  /// Check the device's partitions against the `hashes.json` manifest of a package.
+  Verify {
+    /// Package directory or zip, or the manifest itself. Defaults to the current working directory.
+    path: Option<PathBuf>,
+  },
+  /// Compare two snapshots and print what changed between them.
+  Diff {
+    /// Snapshot taken first.
+    before: PathBuf,
+    /// Snapshot taken after.
+    after: PathBuf,
+  },
+  /// Show what a package contains and whether it can be flashed, without a device connected.
+  Inspect {
+    /// Package directory or zip.
+    path: PathBuf,
+  },
+  /// List the devices flashed from this machine, with what each last had flashed and whether it worked.
+  Devices {
+    /// Show only the device with this serial number.
+    serial: Option<String>,
+    /// Print the records as JSON.
+    #[arg(long, action)]
+    json: bool,
+  },
+  /// Write a `meta.json` for a directory of images laid out for superbird-tool, so it can be flashed as a package.
+  Convert {
+    /// Directory of `<partition>.dump` images and an optional `env.txt`.
+    path: PathBuf,
+  },
+  /// Run commands on the device interactively, optionally recording them as a package.
+  Shell {
+    /// Record the commands that succeed and export them to DIR as a package with a `meta.json` on exit.
+    #[arg(long, value_name = "DIR")]
+    record: Option<PathBuf>,
+  },
+  /// Download a package from a registry, verify it and flash it, e.g. `install thingos@latest`.
+  #[cfg(feature = "http")]
+  Install {
+    /// Package to install as NAME or NAME@VERSION. Installs the latest version if none is given.
+    spec: String,
+    /// URL of the registry's JSON index. Defaults to the FLASHTHING_REGISTRY environment variable.
+    #[arg(long, value_name = "URL")]
+    registry: Option<String>,
+    /// Base64 Ed25519 key every release must be signed with. Defaults to the FLASHTHING_REGISTRY_KEY environment variable.
+    #[arg(long, value_name = "KEY")]
+    registry_key: Option<String>,
+    /// Directory to save the downloaded package in.
+    #[arg(short, long, default_value = ".")]
+    output: PathBuf,
+  },
+  /// Reach a device booted into firmware that runs adb, without holding buttons 1 & 4.
+  #[cfg(feature = "adb")]
+  Adb {
+    /// Serial of the device to talk to, needed when adb sees more than one.
+    #[arg(short, long)]
+    serial: Option<String>,
+    #[command(subcommand)]
+    command: AdbCommand,
+  },
+  /// Replace this binary with the newest release from GitHub, once its digest and signature check out.
+  #[cfg(feature = "self-update")]
+  SelfUpdate {
+    /// Only say whether there is a newer release.
+    #[arg(long, action)]
+    check: bool,
+    /// Base64 Ed25519 key the release must be signed with. Defaults to the FLASHTHING_RELEASE_KEY environment variable, or the key the binary was built with; without any of them nothing is downloaded.
+    #[arg(long, value_name = "KEY")]
+    key: Option<String>,
+  },
+  /// Print a completion script for SHELL, e.g. `flashthing-cli completions bash > /etc/bash_completion.d/flashthing-cli`.
+  Completions {
+    /// Shell to complete for.
+    shell: clap_complete::Shell,
+  },
+  /// Print the manpage, or write it and a page for each subcommand to DIR.
+  Man {
+    /// Directory to write the pages to, e.g. `/usr/local/share/man/man1`.
+    #[arg(long, value_name = "DIR")]
+    dir: Option<PathBuf>,
+  },
+}
 
+#[cfg(feature = "adb")]
+#[derive(Subcommand, Debug)]
+enum AdbCommand {
+  /// Reboot the device into USB burn mode, ready to flash.
+  RebootBurn,
+  /// Write IMAGE to PARTITION with `dd` on the device. The running firmware must not have the partition mounted.
+  Write {
+    /// Partition to write, e.g. `system_b`.
+    partition: String,
+    /// Image to write to it.
+    image: PathBuf,
+  },
+}
+
+fn main() {
   let args = Args::parse();
+  if args.version {
+    return version(args.json);
+  }
+  let defaults = Profile::load(args.profile.as_deref());
+  monitoring::init_logger(args.verbosity.or_else(|| defaults.as_ref().ok()?.verbosity));
+  let defaults = defaults.unwrap_or_else(|err| {
+    tracing::error!("{}", err);
+    std::process::exit(1);
+  });
+
+  if args.usb_debug
+    && let Err(err) = flashthing::AmlogicSoC::set_usb_log_level(flashthing::UsbLogLevel::Debug)
+  {
+    tracing::warn!("failed to enable libusb debug output: {}", err);
+  }
+
+  let target = args.target.or(defaults.target).unwrap_or_default();
+  let usb_quirks = if args.usb_quirks.is_empty() {
+    defaults.usb_quirks
+  } else {
+    args.usb_quirks
+  };
+  let quirks = UsbQuirks {
+    limited_bulk: usb_quirks.contains(&UsbQuirk::LimitedBulk),
+    max_urb_size: match args.max_urb_size.or(defaults.max_urb_size) {
+      Some(0) => None,
+      Some(kib) => Some(kib * 1024),
+      None => UsbQuirks::new().max_urb_size,
+    },
+    transfer_watchdog: match args.transfer_watchdog.or(defaults.transfer_watchdog).unwrap_or(30) {
+      0 => None,
+      secs => Some(Duration::from_secs(secs)),
+    },
+  };
+  let profile = DeviceProfile {
+    usb_quirks: quirks,
+    ..DeviceProfile::default()
+  };
+  let installed = match args.command {
+    Some(Command::Snapshot { output }) => return snapshot(&target, &profile, output),
+    Some(Command::Dump {
+      output,
+      partitions,
+      disk,
+      resume,
+      split,
+    }) => {
+      let options = flashthing::DumpOptions {
+        resume,
+        split_size: split.map(|gb| gb * 1000 * 1000 * 1000),
+      };
+      return dump(&target, &profile, output, partitions, disk, options);
+    }
+    Some(Command::Verify { path }) => return verify(&target, &profile, path),
+    Some(Command::Diff { before, after }) => return diff(before, after),
+    Some(Command::Inspect { path }) => return inspect(path),
+    Some(Command::Devices { serial, json }) => return devices(serial, json),
+    Some(Command::Convert { path }) => return convert(path),
+    Some(Command::Shell { record }) => {
+      let Ok(aml) = init(&target, &profile) else {
+        tracing::error!("could not find device!");
+        std::process::exit(1);
+      };
+      return shell::run(aml, record);
+    }
+    #[cfg(feature = "http")]
+    Some(Command::Install {
+      spec,
+      registry,
+      registry_key,
+      output,
+    }) => Some(install(&spec, registry, registry_key, &output)),
+    #[cfg(feature = "adb")]
+    Some(Command::Adb { serial, command }) => return adb(serial, command),
+    #[cfg(feature = "self-update")]
+    Some(Command::SelfUpdate { check, key }) => {
+      if let Err(err) = update::self_update(check, key) {
+        tracing::error!("failed to update: {}", err);
+        std::process::exit(1);
+      }
+      return;
+    }
+    Some(Command::Completions { shell }) => return completions(shell),
+    Some(Command::Man { dir }) => return man(dir),
+    None => None,
+  };
+
+  if args.udev_rules {
+    match flashthing::AmlogicSoC::host_setup_rules_with_group(args.udev_group.as_deref()) {
+      Ok(rules) => print!("{}", rules),
+      Err(err) => {
+        tracing::error!("failed to print udev rules: {}", err);
+        std::process::exit(1);
+      }
+    }
+    return;
+  }
+
   if args.setup {
     tracing::info!("setting up host...");
-    match flashthing::AmlogicSoC::host_setup() {
-      Ok(()) => tracing::info!("host set up successfully"),
+    match flashthing::AmlogicSoC::host_setup_with_group(&profile, args.udev_group.as_deref()) {
+      Ok(status) if status.findings.is_empty() => tracing::info!("host set up successfully"),
+      Ok(status) => {
+        for finding in status.findings {
+          tracing::warn!("{}", finding);
+        }
+      }
       Err(err) => tracing::error!("failed to set up host: {}", err),
     }
     return;
@@ -44,7 +373,7 @@ fn main() {
 
   if args.unbrick {
     tracing::info!("unbricking device...");
-    let Ok(aml) = flashthing::AmlogicSoC::init(None) else {
+    let Ok(aml) = init(&target, &profile) else {
       tracing::error!("could not find device!");
       panic!("could not find device!");
     };
@@ -58,7 +387,7 @@ fn main() {
   }
 
   if let Some(cmd) = args.bulkcmd {
-    let Ok(aml) = flashthing::AmlogicSoC::init(None) else {
+    let Ok(aml) = init(&target, &profile) else {
       tracing::error!("could not find device!");
       std::process::exit(1);
     };
@@ -73,35 +402,614 @@ fn main() {
     return;
   }
 
-  let path = args
-    .path
+  if args.bench_transport {
+    let Ok(aml) = init(&target, &profile) else {
+      tracing::error!("could not find device!");
+      std::process::exit(1);
+    };
+
+    return bench_transport(&aml);
+  }
+
+  let path = installed
+    .or(args.path)
+    .or(defaults.package_dir)
     .unwrap_or_else(|| env::current_dir().expect("could not determine current directory"));
 
-  match flash(path, args.stock) {
+  let path_policy = if args.lenient_paths {
+    flashthing::PathPolicy::Lenient
+  } else {
+    flashthing::PathPolicy::Strict
+  };
+  let mut builder = Flasher::builder()
+    .target(target)
+    .usb_quirks(quirks)
+    .path_policy(path_policy)
+    .skip(args.skip_steps)
+    .env_variables(args.allow_env);
+  if let Some(step) = args.from_step {
+    builder = builder.start_at(step);
+  }
+  if let Some(offset) = args.from_offset {
+    builder = builder.resume_offset(offset);
+  }
+  if let Some(counter) = args.provision_counter {
+    builder = builder.provisioner(flashthing::Provisioner::Counter(counter));
+  }
+  if let Some(path) = args.overrides {
+    match flashthing::Overrides::load(&path) {
+      Ok(overrides) => builder = builder.overrides(overrides),
+      Err(err) => {
+        tracing::error!("failed to load overrides from {}: {}", path.display(), err);
+        std::process::exit(1);
+      }
+    }
+  }
+  if let Some(deadline) = args.deadline.or(defaults.deadline) {
+    builder = builder.deadline(Duration::from_secs(deadline));
+  }
+  if let Some(secs) = args.verify_boot {
+    builder = builder.verify_boot(Duration::from_secs(secs));
+  }
+  if let Some(dir) = args.output_dir {
+    builder = builder.output_dir(dir);
+  }
+  if let Some(dir) = args.artifacts_dir.or(defaults.artifacts_dir) {
+    builder = builder.artifacts_dir(dir);
+  }
+  if let Some(dir) = args.rollback_dir {
+    builder = builder.transactional(dir);
+  }
+  if args.diff_env {
+    builder = builder.diff_env();
+  }
+  if let Some(secs) = args.wait.or(defaults.wait) {
+    builder = builder.callback(Some(std::sync::Arc::new(show_instructions)));
+    builder = builder.wait_for_device(Duration::from_secs(secs));
+  }
+  #[cfg(feature = "adb")]
+  if args.adb {
+    builder = builder.adb_mode_switch(flashthing::Adb::new());
+  }
+  builder = builder.cancel_token(cancel_on_ctrl_c());
+  match flash(
+    builder,
+    path,
+    args.stock_profile.or(args.stock.then(StockProfile::default)),
+    args.export_script,
+    args.skip_installed,
+    args.bug_report,
+  ) {
     Ok(()) => tracing::info!("done!"),
-    Err(err) => tracing::error!("failed to flash device: {}", err),
+    Err(flashthing::Error::Cancelled) => std::process::exit(130),
+    Err(err) => {
+      tracing::error!("failed to flash device: {} ({})", err, err.code());
+      #[cfg(feature = "adb")]
+      if matches!(err, flashthing::Error::WrongMode) && !args.adb {
+        suggest_adb();
+      }
+    }
+  }
+}
+
+/// Print what the user has to do to get the device in USB mode
+fn show_instructions(event: flashthing::Event) {
+  if let flashthing::Event::ActionRequired { instructions, .. } = event {
+    tracing::warn!("{}", instructions);
   }
 }
 
-fn flash(path: PathBuf, stock: bool) -> flashthing::Result<()> {
+/// Turn the first Ctrl-C into a request to stop at the next safe point, and the second into exiting right away
+fn cancel_on_ctrl_c() -> CancelToken {
+  let token = CancelToken::new();
+  let handler_token = token.clone();
+  let installed = ctrlc::set_handler(move || {
+    if handler_token.is_cancelled() {
+      tracing::error!("stopping right away, the device may be left with a partly written chunk");
+      std::process::exit(130);
+    }
+    tracing::warn!("stopping once the chunk being written is on the device; press Ctrl-C again to stop right away");
+    handler_token.cancel();
+  });
+  if let Err(err) = installed {
+    tracing::warn!("failed to install the Ctrl-C handler: {}", err);
+  }
+  token
+}
+
+/// Connect to the device, or the disk image standing in for it
+fn init(target: &DeviceTarget, profile: &DeviceProfile) -> flashthing::Result<AmlogicSoC> {
+  AmlogicSoC::init_with_target(None, profile.clone(), target.clone())
+}
+
+/// Time three rounds at each block size from the profile's, doubling up to 16 KiB
+fn bench_transport(aml: &AmlogicSoC) {
+  let profile = aml.profile();
+  let length = profile.max_transfer_size;
+  let mut block_length = profile.transfer_block_size;
+  while block_length <= 16 * 1024 {
+    for _ in 0..3 {
+      match aml.bench_transport(length, block_length) {
+        Ok(bench) => println!("{}", bench),
+        Err(err) => {
+          tracing::error!("transfer in {} byte blocks failed: {}", block_length, err);
+          std::process::exit(1);
+        }
+      }
+    }
+    block_length *= 2;
+  }
+}
+
+fn flash(
+  builder: FlasherBuilder,
+  path: PathBuf,
+  stock: Option<StockProfile>,
+  export_script: bool,
+  skip_installed: bool,
+  bug_report: Option<PathBuf>,
+) -> flashthing::Result<()> {
   let mut device = if path.is_file() && path.extension() == Some(OsStr::new("zip")) {
-    if stock {
-      Flasher::from_stock_archive(path, None)?
-    } else {
-      Flasher::from_archive(path, None)?
+    match stock {
+      Some(profile) => builder.from_stock_with(profile, path)?,
+      None => builder.from_archive(path)?,
     }
   } else if path.is_dir() {
-    if stock {
-      Flasher::from_stock_directory(path, None)?
-    } else {
-      Flasher::from_directory(path, None)?
+    match stock {
+      Some(profile) => builder.from_stock_with(profile, path)?,
+      None => builder.from_directory(path)?,
     }
   } else {
     tracing::error!("could not find anything to flash!");
     panic!("could not find anything to flash!");
   };
 
-  device.flash()?;
+  if export_script {
+    print!("{}", device.export_script());
+    return Ok(());
+  }
+  if skip_installed {
+    let check = device.check_update()?;
+    if !check.needs_update() {
+      tracing::info!(
+        "the device already runs {} {}{}, not flashing",
+        device.config().name,
+        device.config().version,
+        if check == flashthing::UpdateCheck::Newer {
+          " or newer"
+        } else {
+          ""
+        }
+      );
+      return Ok(());
+    }
+  }
+
+  if let Some(path) = bug_report {
+    device.enable_session_capture(path);
+  }
+  let result = loop {
+    match device.flash() {
+      Err(flashthing::Error::AwaitingUserInput(message)) => {
+        if let Err(err) = wait_for_enter(&message) {
+          break Err(err.into());
+        }
+      }
+      result => break result,
+    }
+  };
+
+  let report = device.report().clone();
+  let artifacts = device.artifacts().to_vec();
+  // release the device before saying it is safe to unplug or resume
+  drop(device);
+  for warning in &report.warnings {
+    tracing::warn!("{}", warning);
+  }
+  tracing::info!(
+    "{}/{} steps completed, {} written, {} skipped as unchanged",
+    report.steps_completed,
+    report.steps_total,
+    format_bytes(report.bytes_written),
+    format_bytes(report.bytes_skipped)
+  );
+  if !report.rolled_back.is_empty() {
+    tracing::warn!("flashing failed, rolled back: {}", report.rolled_back.join(", "));
+  }
+  if report.steps_failed > 0 {
+    tracing::warn!("{} step(s) failed and were skipped", report.steps_failed);
+  }
+  if report.steps_skipped > 0 {
+    tracing::info!("{} step(s) were left out", report.steps_skipped);
+  }
+  if let Some(step) = &report.resume_at {
+    let offset = report
+      .resume_offset
+      .map(|offset| format!(" --from-offset {}", offset))
+      .unwrap_or_default();
+    tracing::warn!(
+      "flashing cancelled and the device released; run the same command with `--from-step {}{}` to carry on",
+      step,
+      offset
+    );
+  } else if result.is_err()
+    && report.rolled_back.is_empty()
+    && let Some(failed) = report.steps.last().filter(|step| !step.completed)
+  {
+    tracing::info!(
+      "step {} ({}) failed; pass `--from-step {}` to carry on from it",
+      failed.index,
+      failed.id,
+      failed.id
+    );
+  }
+  if !report.env_changes.is_empty() {
+    tracing::info!("env changes:");
+    for change in &report.env_changes {
+      if let Some(before) = &change.before {
+        tracing::info!("  -{}={}", change.name, before);
+      }
+      tracing::info!("  +{}={}", change.name, change.after);
+    }
+  }
+  if let Some(total) = report.cumulative_bytes_written {
+    tracing::info!("{} written to this device across all sessions", format_bytes(total));
+  }
+  for path in &artifacts {
+    tracing::info!("wrote {}", path.display());
+  }
+
+  result
+}
 
+/// Point out the `adb` commands when the device that is booted normally runs adb
+#[cfg(feature = "adb")]
+/// Show a `wait` step's message and wait for the user to press Enter
+fn wait_for_enter(message: &str) -> std::io::Result<()> {
+  use std::io::{BufRead, Write};
+
+  print!("{} [press Enter to continue] ", message);
+  std::io::stdout().flush()?;
+  let mut line = String::new();
+  if std::io::stdin().lock().read_line(&mut line)? == 0 {
+    return Err(std::io::Error::new(
+      std::io::ErrorKind::UnexpectedEof,
+      "the package waits for user input, but stdin is closed",
+    ));
+  }
   Ok(())
 }
+
+fn suggest_adb() {
+  if flashthing::Adb::new()
+    .devices()
+    .is_ok_and(|devices| !devices.is_empty())
+  {
+    tracing::info!(
+      "the device is reachable over adb: pass `--adb` to reboot it into usb burn mode and flash it, or write images to it directly with `flashthing-cli adb write`"
+    );
+  }
+}
+
+#[cfg(feature = "adb")]
+fn adb(serial: Option<String>, command: AdbCommand) {
+  let mut adb = flashthing::Adb::new();
+  if let Some(serial) = serial {
+    adb = adb.serial(serial);
+  }
+
+  let result = match command {
+    AdbCommand::RebootBurn => adb.reboot_to_burn_mode(),
+    AdbCommand::Write { partition, image } => std::fs::File::open(&image)
+      .and_then(|file| Ok((file.metadata()?.len() as usize, file)))
+      .map_err(flashthing::Error::from)
+      .and_then(|(size, file)| {
+        adb.write_partition(&partition, std::io::BufReader::new(file), size, |progress| {
+          tracing::info!("{:.1}% written, {:.2} KB/s", progress.percent, progress.avg_rate);
+        })
+      }),
+  };
+  if let Err(err) = result {
+    tracing::error!("{}", err);
+    std::process::exit(1);
+  }
+  tracing::info!("done!");
+}
+
+fn snapshot(target: &DeviceTarget, profile: &DeviceProfile, output: PathBuf) {
+  let Ok(aml) = init(target, profile) else {
+    tracing::error!("could not find device!");
+    std::process::exit(1);
+  };
+
+  let result = flashthing::Snapshot::capture(&aml, |_| {}).and_then(|snapshot| snapshot.save(&output));
+  match result {
+    Ok(()) => tracing::info!("snapshot saved to {}", output.display()),
+    Err(err) => {
+      tracing::error!("failed to take snapshot: {}", err);
+      std::process::exit(1);
+    }
+  }
+}
+
+fn dump(
+  target: &DeviceTarget,
+  profile: &DeviceProfile,
+  output: PathBuf,
+  partitions: Vec<String>,
+  disk: bool,
+  options: flashthing::DumpOptions,
+) {
+  let Ok(aml) = init(target, profile) else {
+    tracing::error!("could not find device!");
+    std::process::exit(1);
+  };
+
+  let contents = if disk {
+    flashthing::DumpTarget::Disk
+  } else if partitions.is_empty() {
+    flashthing::DumpTarget::all_partitions(&aml)
+  } else {
+    flashthing::DumpTarget::Partitions(partitions)
+  };
+
+  match flashthing::DumpManifest::dump(&aml, &output, &contents, &options, |_, _| {}) {
+    Ok(manifest) => {
+      let total = manifest.files.iter().map(|file| file.size as u64).sum();
+      tracing::info!(
+        "dumped {} file(s), {} to {}",
+        manifest.files.len(),
+        format_bytes(total),
+        output.display()
+      );
+    }
+    Err(err) => {
+      tracing::error!("failed to dump device: {}", err);
+      std::process::exit(1);
+    }
+  }
+}
+
+fn verify(target: &DeviceTarget, profile: &DeviceProfile, path: Option<PathBuf>) {
+  let path = path.unwrap_or_else(|| env::current_dir().expect("could not determine current directory"));
+  let manifest = match flashthing::HashManifest::load(&path) {
+    Ok(manifest) => manifest,
+    Err(err) => {
+      tracing::error!("failed to load hash manifest from {}: {}", path.display(), err);
+      std::process::exit(1);
+    }
+  };
+  let Ok(aml) = init(target, profile) else {
+    tracing::error!("could not find device!");
+    std::process::exit(1);
+  };
+
+  let checks = match manifest.verify(&aml, |_| {}) {
+    Ok(checks) => checks,
+    Err(err) => {
+      tracing::error!("failed to verify device: {}", err);
+      std::process::exit(1);
+    }
+  };
+
+  let mut intact = true;
+  for check in &checks {
+    match (&check.actual, &check.error) {
+      _ if check.ok() => println!("{}: ok", check.name),
+      (Some(actual), _) => println!("{}: MISMATCH (expected {}, got {})", check.name, check.expected, actual),
+      (None, error) => println!("{}: UNREADABLE ({})", check.name, error.as_deref().unwrap_or_default()),
+    }
+    intact &= check.ok();
+  }
+  if !intact {
+    std::process::exit(1);
+  }
+}
+
+fn diff(before: PathBuf, after: PathBuf) {
+  let load = |path: &PathBuf| {
+    flashthing::Snapshot::load(path).unwrap_or_else(|err| {
+      tracing::error!("failed to load snapshot {}: {}", path.display(), err);
+      std::process::exit(1);
+    })
+  };
+
+  let changes = load(&before).diff(&load(&after));
+  if changes.is_empty() {
+    println!("no changes");
+  }
+  for change in changes {
+    println!("{}", change);
+  }
+}
+
+fn inspect(path: PathBuf) {
+  let package = match flashthing::Package::open(&path) {
+    Ok(package) => package,
+    Err(err) => {
+      tracing::error!("failed to open {}: {}", path.display(), err);
+      std::process::exit(1);
+    }
+  };
+
+  println!("{} {}: {}", package.name(), package.version(), package.description());
+  for step in package.steps() {
+    let partition = step.partition.map(|name| format!(" {name}")).unwrap_or_default();
+    println!("{:>4}. {}{} ({})", step.number, step.kind, partition, step.id);
+  }
+  for file in package.files() {
+    match file.size {
+      Some(size) => println!("{:>10}  {}", format_bytes(size), file.file_path),
+      None => println!("{:>10}  {}", "missing", file.file_path),
+    }
+  }
+  println!("{} in total", format_bytes(package.total_bytes()));
+
+  let compatibility = package.compatibility();
+  if compatibility.special_partitions {
+    tracing::warn!("the package writes special partitions");
+  }
+  if !compatibility.unknown_partitions.is_empty() {
+    tracing::warn!("unknown partitions: {}", compatibility.unknown_partitions.join(", "));
+  }
+  if !compatibility.is_compatible() {
+    tracing::error!("the package cannot be flashed by this version of flashthing");
+    std::process::exit(1);
+  }
+}
+
+fn devices(serial: Option<String>, json: bool) {
+  let Some(path) = flashthing::WearLedger::default_path() else {
+    tracing::error!("could not determine where the wear ledger is kept");
+    std::process::exit(1);
+  };
+  let ledger = match flashthing::WearLedger::load(&path) {
+    Ok(ledger) => ledger,
+    Err(err) => {
+      tracing::error!("failed to load wear ledger from {}: {}", path.display(), err);
+      std::process::exit(1);
+    }
+  };
+
+  let records = ledger
+    .list()
+    .into_iter()
+    .filter(|(record, _)| serial.as_ref().is_none_or(|serial| record == serial))
+    .collect::<Vec<_>>();
+  if let Some(serial) = serial.as_ref().filter(|_| records.is_empty()) {
+    tracing::error!("{} was never flashed from this machine", serial);
+    std::process::exit(1);
+  }
+  if json {
+    let records = records
+      .iter()
+      .map(|(serial, wear)| {
+        let mut record = serde_json::to_value(wear).expect("records serialize");
+        record["serial"] = (*serial).into();
+        record
+      })
+      .collect::<Vec<_>>();
+    println!("{}", serde_json::to_string_pretty(&records).expect("records serialize"));
+    return;
+  }
+  if records.is_empty() {
+    println!("no devices flashed yet");
+  }
+  for (serial, wear) in records {
+    println!(
+      "{}  {}  {}  {} flash(es), {} written{}",
+      serial,
+      wear.last_package.as_deref().unwrap_or("-"),
+      match &wear.last_error_code {
+        _ if wear.last_success => "ok".to_owned(),
+        Some(code) => format!("failed ({code})"),
+        None => "failed".to_owned(),
+      },
+      wear.sessions,
+      format_bytes(wear.bytes_written),
+      wear
+        .identity
+        .as_ref()
+        .map(|identity| format!(", rom {identity}"))
+        .unwrap_or_default()
+    );
+  }
+}
+
+fn convert(path: PathBuf) {
+  let meta = path.join("meta.json");
+  if meta.exists() {
+    tracing::error!("{} already exists", meta.display());
+    std::process::exit(1);
+  }
+
+  let result = flashthing::config::FlashConfig::from_superbird_tool_dir(&path).and_then(|config| {
+    std::fs::write(&meta, serde_json::to_string_pretty(&config)?)?;
+    Ok(config)
+  });
+  match result {
+    Ok(config) => tracing::info!("wrote {} with {} steps", meta.display(), config.steps.len()),
+    Err(err) => {
+      tracing::error!("failed to convert {}: {}", path.display(), err);
+      std::process::exit(1);
+    }
+  }
+}
+
+/// Download and verify the package `spec` names, returning where it was saved
+#[cfg(feature = "http")]
+fn install(spec: &str, registry: Option<String>, registry_key: Option<String>, output: &std::path::Path) -> PathBuf {
+  let Some(url) = registry.or_else(|| env::var("FLASHTHING_REGISTRY").ok()) else {
+    tracing::error!("no registry given, pass --registry or set FLASHTHING_REGISTRY");
+    std::process::exit(1);
+  };
+  let (name, version) = spec.split_once('@').unwrap_or((spec, flashthing::LATEST));
+
+  let registry = flashthing::Registry::new(url);
+  let registry = match registry_key.or_else(|| env::var("FLASHTHING_REGISTRY_KEY").ok()) {
+    Some(key) => registry.with_public_key(&key),
+    None => {
+      tracing::warn!("no registry key given, only checking the digest of the download");
+      Ok(registry)
+    }
+  };
+  match registry.and_then(|registry| registry.fetch(name, version, output)) {
+    Ok(path) => {
+      tracing::info!("downloaded {} to {}", spec, path.display());
+      path
+    }
+    Err(err) => {
+      tracing::error!("failed to install {}: {}", spec, err);
+      std::process::exit(1);
+    }
+  }
+}
+
+fn version(json: bool) {
+  if !json {
+    print!("{}", Args::command().render_version());
+    return;
+  }
+
+  let mut capabilities = flashthing::Capabilities::current();
+  if cfg!(feature = "self-update") {
+    capabilities.features.push("self-update".to_owned());
+  }
+  println!(
+    "{}",
+    serde_json::to_string_pretty(&capabilities).expect("capabilities serialize")
+  );
+}
+
+fn completions(shell: clap_complete::Shell) {
+  let mut command = Args::command();
+  let name = command.get_name().to_owned();
+  clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+}
+
+fn man(dir: Option<PathBuf>) {
+  let command = Args::command();
+  let result = match &dir {
+    Some(dir) => std::fs::create_dir_all(dir).and_then(|()| clap_mangen::generate_to(command, dir)),
+    None => clap_mangen::Man::new(command).render(&mut std::io::stdout()),
+  };
+  match (result, dir) {
+    (Ok(()), Some(dir)) => tracing::info!("wrote manpages to {}", dir.display()),
+    (Ok(()), None) => {}
+    (Err(err), _) => {
+      tracing::error!("failed to write manpages: {}", err);
+      std::process::exit(1);
+    }
+  }
+}
+
+fn format_bytes(bytes: u64) -> String {
+  const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+  let mut value = bytes as f64;
+  let mut unit = 0;
+  while value >= 1024.0 && unit < UNITS.len() - 1 {
+    value /= 1024.0;
+    unit += 1;
+  }
+  format!("{:.1} {}", value, UNITS[unit])
+}
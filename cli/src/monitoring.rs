@@ -1,4 +1,5 @@
-pub fn init_logger() {
+/// Log to stderr, filtered by RUST_LOG, then `verbosity`, then the build's defaults
+pub fn init_logger(verbosity: Option<crate::config::Verbosity>) {
   use tracing::metadata::LevelFilter;
   use tracing_subscriber::{
     EnvFilter, Layer, filter::Directive, fmt, fmt::format::FmtSpan, prelude::__tracing_subscriber_SubscriberExt,
@@ -12,8 +13,10 @@ pub fn init_logger() {
   #[cfg(debug_assertions)]
   let filter_directives = if let Ok(filter) = std::env::var("RUST_LOG") {
     filter
+  } else if let Some(verbosity) = verbosity {
+    flashthing::Verbosity::from(verbosity).directives().to_string()
   } else {
-    "flashthing_cli=trace,flashthing=trace,flashthing::aml::write_large_memory=debug".to_string()
+    "flashthing_cli=trace,flashthing=trace,flashthing::transfer=debug".to_string()
   };
 
   // directives for release builds
@@ -23,6 +26,8 @@ pub fn init_logger() {
   #[cfg(not(debug_assertions))]
   let filter_directives = if let Ok(filter) = std::env::var("RUST_LOG") {
     filter
+  } else if let Some(verbosity) = verbosity {
+    flashthing::Verbosity::from(verbosity).directives().to_string()
   } else {
     "flashthing_cli=info,flashthing=info".to_string()
   };
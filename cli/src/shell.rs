@@ -0,0 +1,131 @@
+use std::{
+  io::{self, BufRead, Write},
+  path::{Path, PathBuf},
+};
+
+use flashthing::{AmlogicSoC, Recorder, config::ResetMode};
+
+const HELP: &str = "commands:
+  identify                       identify the device
+  bulkcmd <command>              send a bulk command and print the response
+  run <address>                  run code at an address
+  write-memory <address> <file>  write a small file to memory
+  write-env <file>               import `name=value` lines into the U-Boot environment
+  restore <partition> <file>     write an image to a partition
+  write-boot <1|2> <file>        write boot0 or boot1
+  reset [soft|burn]              reset the device, normally or back into USB burn mode
+  log <message>                  add a message to the recording
+  help                           show this message
+  exit                           leave the shell, exporting the recording if there is one";
+
+/// Run commands typed on stdin against the device until `exit` or end of input
+///
+/// With `record`, every command that succeeds is recorded, and the session is exported to that
+/// directory as a package with a `meta.json` on the way out.
+pub fn run(aml: AmlogicSoC, record: Option<PathBuf>) {
+  let mut recorder = Recorder::new(aml);
+  println!("{HELP}");
+
+  let stdin = io::stdin();
+  let mut lines = stdin.lock().lines();
+  loop {
+    print!("flashthing> ");
+    let _ = io::stdout().flush();
+    let Some(Ok(line)) = lines.next() else {
+      break;
+    };
+    let line = line.trim();
+    let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+    match command {
+      "" => {}
+      "exit" | "quit" => break,
+      "help" => println!("{HELP}"),
+      command => {
+        if let Err(err) = execute(&mut recorder, command, rest.trim()) {
+          tracing::error!("{}: {}", command, err);
+        }
+      }
+    }
+  }
+
+  let Some(dir) = record else {
+    return;
+  };
+  if recorder.steps().is_empty() {
+    tracing::warn!("nothing was recorded, not exporting a package");
+    return;
+  }
+  let name = dir
+    .file_name()
+    .map_or_else(|| "recording".into(), |name| name.to_string_lossy());
+  match recorder.export(&dir, &name, "recorded with the flashthing shell") {
+    Ok(config) => tracing::info!(
+      "saved {} steps to {}",
+      config.steps.len(),
+      dir.join("meta.json").display()
+    ),
+    Err(err) => {
+      tracing::error!("failed to export the recording: {}", err);
+      std::process::exit(1);
+    }
+  }
+}
+
+fn execute(recorder: &mut Recorder, command: &str, args: &str) -> Result<(), String> {
+  let mut words = args.split_whitespace();
+  let mut arg = |name: &str| words.next().ok_or_else(|| format!("missing {name}, see `help`"));
+  match command {
+    "identify" => println!("{}", recorder.identify().map_err(|e| e.to_string())?),
+    "bulkcmd" if !args.is_empty() => print!("{}", recorder.bulkcmd(args).map_err(|e| e.to_string())?),
+    "bulkcmd" => return Err("missing command, see `help`".into()),
+    "run" => {
+      let address = parse_address(arg("address")?)?;
+      recorder.run(address, None).map_err(|e| e.to_string())?;
+    }
+    "write-memory" => {
+      let address = parse_address(arg("address")?)?;
+      let data = read(arg("file")?)?;
+      recorder.write_simple_memory(address, data).map_err(|e| e.to_string())?;
+    }
+    "write-env" => {
+      let env = String::from_utf8(read(arg("file")?)?).map_err(|e| e.to_string())?;
+      recorder.write_env(&env).map_err(|e| e.to_string())?;
+    }
+    "restore" => {
+      let partition = arg("partition")?;
+      let data = read(arg("file")?)?;
+      recorder.restore_partition(partition, data).map_err(|e| e.to_string())?;
+    }
+    "write-boot" => {
+      let hwpart = arg("hwpart")?
+        .parse()
+        .map_err(|_| "hwpart must be 1 or 2".to_string())?;
+      let data = read(arg("file")?)?;
+      recorder.write_boot_partition(hwpart, data).map_err(|e| e.to_string())?;
+    }
+    "reset" => {
+      let mode = match words.next() {
+        None | Some("soft") => ResetMode::Soft,
+        Some("burn") => ResetMode::Burn,
+        Some(mode) => return Err(format!("unknown reset mode \"{mode}\", expected soft or burn")),
+      };
+      recorder.reset(mode).map_err(|e| e.to_string())?;
+    }
+    "log" => recorder.log(args),
+    _ => return Err("unknown command, see `help`".into()),
+  }
+  Ok(())
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+  std::fs::read(Path::new(path)).map_err(|e| format!("failed to read {path}: {e}"))
+}
+
+/// Parse an address written in decimal or as `0x` hex
+fn parse_address(text: &str) -> Result<u32, String> {
+  let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+    Some(digits) => u32::from_str_radix(digits, 16),
+    None => text.parse(),
+  };
+  parsed.map_err(|_| format!("invalid address \"{text}\""))
+}
@@ -0,0 +1,152 @@
+//! Replacing the CLI with the newest build from the project's GitHub releases.
+
+use std::{cmp::Ordering, env, fs, path::Path, time::Duration};
+
+use flashthing::{Error, Registry, RegistryRelease, Result, compare_versions};
+use serde::Deserialize;
+
+/// Where GitHub describes the newest release
+const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/JoeyEamigh/flashthing/releases/latest";
+
+/// How long fetching the release description, digest or signature may take before giving up
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Environment variable holding the base64 Ed25519 key release binaries are signed with
+pub const RELEASE_KEY_ENV: &str = "FLASHTHING_RELEASE_KEY";
+
+/// The parts of a GitHub release that matter here
+#[derive(Deserialize, Debug)]
+struct GithubRelease {
+  tag_name: String,
+  assets: Vec<GithubAsset>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GithubAsset {
+  name: String,
+  browser_download_url: String,
+}
+
+impl GithubRelease {
+  fn asset(&self, name: &str) -> Option<&GithubAsset> {
+    self.assets.iter().find(|asset| asset.name == name)
+  }
+}
+
+/// Update the running binary to the newest release, or with `check`, only say whether there is one
+///
+/// Each release has a binary per platform, named `flashthing-cli-<os>-<arch>`, with its SHA-256
+/// digest as hex in `<binary>.sha256` and a base64 Ed25519 signature in `<binary>.sig` of the
+/// [`RegistryRelease::signed_message`] for the binary's name, the release version and the digest. The signature is checked against `key`, the [`RELEASE_KEY_ENV`] environment
+/// variable or the key the binary was built with, in that order. Without any of them nothing is
+/// downloaded, since a digest published next to the binary does not tell who published it.
+pub fn self_update(check: bool, key: Option<String>) -> Result<()> {
+  let release: GithubRelease = serde_json::from_str(&get(LATEST_RELEASE_URL)?)?;
+  let current = env!("CARGO_PKG_VERSION");
+  let latest = release.tag_name.trim_start_matches('v');
+  if compare_versions(latest, current) != Ordering::Greater {
+    tracing::info!("flashthing-cli {} is the newest release", current);
+    return Ok(());
+  }
+  if check {
+    tracing::info!("flashthing-cli {} is out, this is {}", latest, current);
+    return Ok(());
+  }
+
+  let key = key
+    .or_else(|| env::var(RELEASE_KEY_ENV).ok())
+    .or_else(|| option_env!("FLASHTHING_RELEASE_KEY").map(str::to_owned))
+    .filter(|key| !key.is_empty())
+    .ok_or_else(|| {
+      Error::Registry(format!(
+        "no release key to check flashthing-cli {} with; pass --key or set {}",
+        latest, RELEASE_KEY_ENV
+      ))
+    })?;
+  let registry = Registry::new(LATEST_RELEASE_URL).with_public_key(&key)?;
+
+  let name = asset_name();
+  let binary = release
+    .asset(&name)
+    .ok_or_else(|| Error::Registry(format!("release {} has no {} binary", latest, name)))?;
+  let digest = release
+    .asset(&format!("{name}.sha256"))
+    .ok_or_else(|| Error::Registry(format!("release {} has no digest for {}", latest, name)))?;
+  let signature = match release.asset(&format!("{name}.sig")) {
+    Some(signature) => Some(get(&signature.browser_download_url)?.trim().to_owned()),
+    None => None,
+  };
+  let download = RegistryRelease {
+    version: latest.to_owned(),
+    url: binary.browser_download_url.clone(),
+    sha256: get(&digest.browser_download_url)?
+      .split_whitespace()
+      .next()
+      .unwrap_or_default()
+      .to_owned(),
+    signature,
+  };
+
+  let exe = env::current_exe()?;
+  let update = exe.with_extension("new");
+  tracing::info!("downloading flashthing-cli {}", latest);
+  registry.download(&name, &download, &update)?;
+  replace(&exe, &update).inspect_err(|_| {
+    let _ = fs::remove_file(&update);
+  })?;
+  tracing::info!("updated flashthing-cli from {} to {}", current, latest);
+  Ok(())
+}
+
+/// Name of the release binary built for this platform
+fn asset_name() -> String {
+  format!(
+    "flashthing-cli-{}-{}{}",
+    env::consts::OS,
+    env::consts::ARCH,
+    env::consts::EXE_SUFFIX
+  )
+}
+
+/// Move `update` over the running binary at `exe`
+fn replace(exe: &Path, update: &Path) -> Result<()> {
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(update, fs::Permissions::from_mode(0o755))?;
+  }
+  // windows will not overwrite a running binary, but it will rename it out of the way
+  #[cfg(windows)]
+  {
+    let old = exe.with_extension("old");
+    let _ = fs::remove_file(&old);
+    fs::rename(exe, &old)?;
+    if let Err(err) = fs::rename(update, exe) {
+      // put the running binary back, or there is none left at `exe`
+      if let Err(restore) = fs::rename(&old, exe) {
+        tracing::error!(
+          "could not restore {} from {}: {}",
+          exe.display(),
+          old.display(),
+          restore
+        );
+      }
+      return Err(err.into());
+    }
+  }
+  #[cfg(not(windows))]
+  fs::rename(update, exe)?;
+  Ok(())
+}
+
+fn get(url: &str) -> Result<String> {
+  let agent: ureq::Agent = ureq::Agent::config_builder()
+    .timeout_global(Some(FETCH_TIMEOUT))
+    .build()
+    .into();
+  agent
+    .get(url)
+    .call()
+    .and_then(|mut response| response.body_mut().read_to_string())
+    .map_err(|e| Error::Registry(format!("fetching {} failed: {}", url, e)))
+}
@@ -0,0 +1,235 @@
+//! # flashthing-core
+//!
+//! The USB burn protocol of Amlogic SoCs, as spoken by the mask ROM, BL2, and U-Boot in USB burn
+//! mode. This is the layer [flashthing](https://crates.io/crates/flashthing) is built on, without
+//! its packaging, step engine, or any file format dependencies, for tools that only need to talk
+//! to the device, such as a factory provisioning daemon.
+//!
+//! ```no_run
+//! use flashthing_core::{Session, UsbIds, open_usb};
+//!
+//! let ids = UsbIds::superbird();
+//! let (transport, info) = open_usb(&ids)?;
+//! let session = Session::new(transport, info);
+//! let response = session.bulkcmd("amlmmc key")?;
+//! # Ok::<(), flashthing_core::Error>(())
+//! ```
+//!
+//! libusb (through `rusb`) is used by default; enabling the `nusb` feature switches to the
+//! pure-Rust `nusb` backend. The `serde` feature derives `Serialize` and `Deserialize` for
+//! [`DeviceInfo`] and [`SecureBootState`].
+
+mod session;
+mod transport;
+mod watchdog;
+
+use std::time::Duration;
+
+pub use session::{Session, amlc_checksum, amls_block};
+pub use transport::{Transport, UsbIds, UsbLogLevel, find_usb_device, open_usb, set_usb_log_level};
+
+/// Result type used throughout the crate
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Errors talking to the device
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+  /// Error from the USB subsystem
+  #[cfg(feature = "rusb")]
+  #[error("USB error: {0}")]
+  UsbError(#[from] rusb::Error),
+
+  /// Error from a USB transfer made by the nusb backend
+  #[cfg(feature = "nusb")]
+  #[error("USB transfer error: {0}")]
+  UsbTransfer(#[from] nusb::transfer::TransferError),
+
+  /// I/O related error
+  #[error("IO error: {0}")]
+  IoError(#[from] std::io::Error),
+
+  /// Error converting slices
+  #[error("slice conversion error: {0}")]
+  Bytes(#[from] std::array::TryFromSliceError),
+
+  /// Error when an operation is invalid in the current context
+  #[error("Invalid operation: {0}")]
+  InvalidOperation(String),
+
+  /// UTF-8 conversion error
+  #[error("UTF8 conversion error: {0}")]
+  Utf8Error(#[from] std::string::FromUtf8Error),
+
+  /// Error when the SoC's secure boot configuration has no matching BL2
+  #[error("unsupported secure boot configuration: {0}")]
+  UnsupportedSecureBoot(String),
+
+  /// Error when the USB session ended, e.g. because the device reset, and was not re-established
+  #[error("USB session lost: {0}")]
+  SessionLost(String),
+
+  /// Error when an operation was started after the session's deadline
+  #[error("timed out: {0}")]
+  Timeout(String),
+}
+
+impl Error {
+  /// A code for the kind of error that stays the same across versions, unlike the message
+  ///
+  /// Frontends and scripts should match on this rather than on the text of the error.
+  pub fn code(&self) -> &'static str {
+    match self {
+      #[cfg(feature = "rusb")]
+      Error::UsbError(err) => match err {
+        rusb::Error::Timeout => "USB_TIMEOUT",
+        rusb::Error::Pipe | rusb::Error::Overflow => "USB_STALL",
+        rusb::Error::NoDevice => "USB_DISCONNECTED",
+        rusb::Error::Access => "USB_ACCESS_DENIED",
+        rusb::Error::Busy => "USB_BUSY",
+        rusb::Error::NotFound => "USB_NOT_FOUND",
+        _ => "USB_ERROR",
+      },
+      #[cfg(feature = "nusb")]
+      Error::UsbTransfer(err) => match err {
+        nusb::transfer::TransferError::Stall => "USB_STALL",
+        nusb::transfer::TransferError::Disconnected => "USB_DISCONNECTED",
+        nusb::transfer::TransferError::Cancelled => "USB_CANCELLED",
+        _ => "USB_ERROR",
+      },
+      Error::IoError(err) => io_error_code(err),
+      Error::Bytes(_) => "INVALID_DATA",
+      Error::InvalidOperation(_) => "INVALID_OPERATION",
+      Error::Utf8Error(_) => "INVALID_UTF8",
+      Error::UnsupportedSecureBoot(_) => "UNSUPPORTED_SECURE_BOOT",
+      Error::SessionLost(_) => "SESSION_LOST",
+      Error::Timeout(_) => "TIMEOUT",
+    }
+  }
+
+  /// Whether the device halted the endpoint, by stalling or babbling (sending more than was asked for)
+  ///
+  /// The endpoint fails every transfer after that until its halt is cleared. Transports other than
+  /// the USB backends report a stall as an [`std::io::ErrorKind::BrokenPipe`] I/O error.
+  pub fn is_stall(&self) -> bool {
+    match self {
+      #[cfg(feature = "rusb")]
+      Error::UsbError(rusb::Error::Pipe | rusb::Error::Overflow) => true,
+      #[cfg(feature = "nusb")]
+      Error::UsbTransfer(nusb::transfer::TransferError::Stall) => true,
+      Error::IoError(err) => err.kind() == std::io::ErrorKind::BrokenPipe,
+      _ => false,
+    }
+  }
+}
+
+/// Code for an I/O error, which transports other than the USB backends report transfer timeouts as
+pub fn io_error_code(err: &std::io::Error) -> &'static str {
+  match err.kind() {
+    std::io::ErrorKind::TimedOut => "USB_TIMEOUT",
+    _ => "IO_ERROR",
+  }
+}
+
+/// The current mode of the Superbird device
+///
+/// The device can be in different modes depending on how it was powered on
+/// and what stage of the boot process it's in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceMode {
+  /// Normal operating mode (running regular firmware)
+  Normal,
+  /// USB mode (entered by holding buttons 1 & 4 during power-on)
+  Usb,
+  /// USB Burn mode (ready for flashing operations)
+  UsbBurn,
+  /// Device not detected
+  NotFound,
+}
+
+/// Details of a connected device and the USB interface used to talk to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct DeviceInfo {
+  /// USB vendor ID
+  pub vendor_id: u16,
+  /// USB product ID
+  pub product_id: u16,
+  /// Bus the device is attached to
+  pub bus_number: u8,
+  /// Address of the device on its bus
+  pub address: u8,
+  /// USB serial number, if the device reports one
+  #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+  pub serial: Option<String>,
+  /// Number of interfaces in the active configuration
+  pub interface_count: u8,
+  /// Interface that was claimed
+  pub interface_number: u8,
+  /// Alternate setting selected on the claimed interface
+  pub alt_setting: u8,
+  /// Address of the IN endpoint in use
+  pub endpoint_in: u8,
+  /// Address of the OUT endpoint in use
+  pub endpoint_out: u8,
+  /// Whether both endpoints are bulk endpoints
+  pub bulk: bool,
+}
+
+/// Secure boot state of the SoC, as burned into its efuses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SecureBootState {
+  /// The SoC only boots signed and encrypted BL2 images
+  Enabled,
+  /// The SoC boots unsigned BL2 images
+  Disabled,
+  /// The state could not be read
+  Unknown,
+}
+
+/// Timeout of control transfers and bulkcmd responses
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+// all requests
+pub const REQ_WRITE_MEM: u8 = 0x01;
+pub const REQ_READ_MEM: u8 = 0x02;
+pub const REQ_FILL_MEM: u8 = 0x03;
+pub const REQ_MODIFY_MEM: u8 = 0x04;
+pub const REQ_RUN_IN_ADDR: u8 = 0x05;
+pub const REQ_WRITE_AUX: u8 = 0x06;
+pub const REQ_READ_AUX: u8 = 0x07;
+
+pub const REQ_WR_LARGE_MEM: u8 = 0x11;
+pub const REQ_RD_LARGE_MEM: u8 = 0x12;
+pub const REQ_IDENTIFY_HOST: u8 = 0x20;
+
+pub const REQ_TPL_CMD: u8 = 0x30;
+pub const REQ_TPL_STAT: u8 = 0x31;
+
+pub const REQ_WRITE_MEDIA: u8 = 0x32;
+pub const REQ_READ_MEDIA: u8 = 0x33;
+
+pub const REQ_BULKCMD: u8 = 0x34;
+
+pub const REQ_PASSWORD: u8 = 0x35;
+pub const REQ_NOP: u8 = 0x36;
+
+pub const REQ_GET_AMLC: u8 = 0x50;
+pub const REQ_WRITE_AMLC: u8 = 0x60;
+
+/// Set in the address passed to [`REQ_RUN_IN_ADDR`] to keep the SoC powered while the code runs
+pub const FLAG_KEEP_POWER_ON: u32 = 0x10;
+
+pub const AMLC_AMLS_BLOCK_LENGTH: usize = 0x200;
+pub const AMLC_MAX_BLOCK_LENGTH: usize = 0x4000;
+pub const AMLC_MAX_TRANSFER_LENGTH: usize = 65536;
+
+pub const WRITE_MEDIA_CHEKSUM_ALG_NONE: u16 = 0x00ee;
+pub const WRITE_MEDIA_CHEKSUM_ALG_ADDSUM: u16 = 0x00ef;
+pub const WRITE_MEDIA_CHEKSUM_ALG_CRC32: u16 = 0x00f0;
+
+/// Efuse-backed security config register on g12a; bit 4 is set when secure boot is enforced
+pub const AO_SEC_SD_CFG10: u32 = 0xff800228;
+/// Bit of [`AO_SEC_SD_CFG10`] that is set when secure boot is enforced
+pub const SECURE_BOOT_BIT: u32 = 1 << 4;
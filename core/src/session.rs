@@ -0,0 +1,1237 @@
+//! A USB session with a device in USB or USB burn mode, and the requests it understands.
+
+use std::{
+  io::Read,
+  sync::{
+    Arc, Mutex,
+    atomic::{AtomicUsize, Ordering},
+  },
+  thread::sleep,
+  time::{Duration, Instant},
+};
+
+use crate::{
+  AMLC_AMLS_BLOCK_LENGTH, AMLC_MAX_BLOCK_LENGTH, AMLC_MAX_TRANSFER_LENGTH, AO_SEC_SD_CFG10, COMMAND_TIMEOUT,
+  DeviceInfo, Error, FLAG_KEEP_POWER_ON, REQ_BULKCMD, REQ_GET_AMLC, REQ_IDENTIFY_HOST, REQ_RD_LARGE_MEM, REQ_READ_MEM,
+  REQ_RUN_IN_ADDR, REQ_WR_LARGE_MEM, REQ_WRITE_AMLC, REQ_WRITE_MEM, Result, SECURE_BOOT_BIT, SecureBootState,
+  transport::Transport, watchdog::Watchdog,
+};
+
+/// An open connection to a device, speaking the Amlogic USB burn protocol
+///
+/// The session ends when the device resets or jumps to other code, e.g. after [`run`](Self::run)
+/// or [`boot_bl2`](Self::boot_bl2), or when it disconnects in the middle of a transfer. Every
+/// request after that fails with [`Error::SessionLost`], and the device has to be opened again.
+#[derive(Debug)]
+pub struct Session {
+  transport: SessionWatch,
+  info: DeviceInfo,
+  endpoint_in: u8,
+  endpoint_out: u8,
+  /// Why the USB session ended, once the device has reset, jumped to other code or disconnected
+  session_lost: Arc<Mutex<Option<String>>>,
+  /// When requests have to finish by, and what set that limit
+  deadline: Mutex<Option<(Instant, String)>>,
+}
+
+/// Smallest URB bulk writes are split down to after failing
+const MIN_URB_SIZE: usize = 4096;
+/// Size of the bulk packets URBs are split on, so the device sees the same stream of packets
+const BULK_PACKET_SIZE: usize = 512;
+
+/// Wraps the transport to notice the device disconnecting on its own, so the session is known to be lost
+///
+/// It also splits bulk writes into URBs of at most `max_urb` bytes, 0 meaning no limit, and clears
+/// endpoints the device halts or transfers the watchdog cancels before retrying the transfer once.
+#[derive(Debug)]
+struct SessionWatch {
+  transport: Arc<dyn Transport>,
+  session_lost: Arc<Mutex<Option<String>>>,
+  max_urb: AtomicUsize,
+  watchdog: Arc<Watchdog>,
+}
+
+impl Drop for SessionWatch {
+  fn drop(&mut self) {
+    self.watchdog.stop();
+  }
+}
+
+impl SessionWatch {
+  fn watch<T>(&self, result: Result<T>) -> Result<T> {
+    if let Err(Error::SessionLost(reason)) = &result
+      && let Ok(mut lost) = self.session_lost.lock()
+    {
+      tracing::debug!("usb session ended: {}", reason);
+      lost.get_or_insert_with(|| reason.clone());
+    }
+    result
+  }
+
+  /// Clear the halt on `endpoint` after `err` and check the device still answers control requests
+  fn recover_stall(&self, endpoint: u8, err: &Error) -> Result<()> {
+    tracing::warn!(
+      "transfer on endpoint {:#04x} failed ({}), clearing its halt and retrying",
+      endpoint,
+      err
+    );
+    self.watch(self.transport.clear_halt(endpoint))?;
+
+    // resync with a request the device answers in any state, so the retry starts from a clean slate
+    let mut buf = [0u8; 8];
+    if let Err(err) = self.watch(
+      self
+        .transport
+        .read_control(0xC0, REQ_IDENTIFY_HOST, 0, 0, &mut buf, COMMAND_TIMEOUT),
+    ) {
+      if matches!(err, Error::SessionLost(_)) {
+        return Err(err);
+      }
+      tracing::debug!("device did not answer after clearing the halt: {}", err);
+    }
+    Ok(())
+  }
+}
+
+impl Transport for SessionWatch {
+  fn write_control(
+    &self,
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    data: &[u8],
+    timeout: Duration,
+  ) -> Result<usize> {
+    self.watch(
+      self
+        .transport
+        .write_control(request_type, request, value, index, data, timeout),
+    )
+  }
+
+  fn read_control(
+    &self,
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    buf: &mut [u8],
+    timeout: Duration,
+  ) -> Result<usize> {
+    self.watch(
+      self
+        .transport
+        .read_control(request_type, request, value, index, buf, timeout),
+    )
+  }
+
+  fn write_bulk(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize> {
+    if data.is_empty() {
+      return self.watch(self.transport.write_bulk(endpoint, data, timeout));
+    }
+
+    let mut written = 0;
+    let mut stalled = false;
+    while written < data.len() {
+      let urb = match self.max_urb.load(Ordering::Relaxed) {
+        0 => data.len() - written,
+        max_urb => max_urb.min(data.len() - written),
+      };
+      let (result, hung) = self.watchdog.guard(endpoint, || {
+        self
+          .transport
+          .write_bulk(endpoint, &data[written..written + urb], timeout)
+      });
+      match result {
+        Ok(sent) => {
+          written += sent;
+          stalled = false;
+          if sent < urb {
+            break;
+          }
+        }
+        Err(err) if (hung || err.is_stall()) && !stalled => {
+          self.recover_stall(endpoint, &err)?;
+          stalled = true;
+        }
+        Err(err) if urb_rejected(&err) && urb > MIN_URB_SIZE => {
+          let max_urb = (urb / 2 / BULK_PACKET_SIZE * BULK_PACKET_SIZE).max(MIN_URB_SIZE);
+          tracing::warn!(
+            "bulk write of {} bytes failed ({}), splitting bulk writes into {} byte URBs",
+            urb,
+            err,
+            max_urb
+          );
+          self.max_urb.store(max_urb, Ordering::Relaxed);
+        }
+        Err(err) => return self.watch(Err(err)),
+      }
+    }
+    Ok(written)
+  }
+
+  fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+    match self
+      .watchdog
+      .guard(endpoint, || self.transport.read_bulk(endpoint, buf, timeout))
+    {
+      (Err(err), hung) if hung || err.is_stall() => {
+        self.recover_stall(endpoint, &err)?;
+        let (result, _) = self
+          .watchdog
+          .guard(endpoint, || self.transport.read_bulk(endpoint, buf, timeout));
+        self.watch(result)
+      }
+      (result, _) => self.watch(result),
+    }
+  }
+
+  fn clear_halt(&self, endpoint: u8) -> Result<()> {
+    self.watch(self.transport.clear_halt(endpoint))
+  }
+}
+
+impl Session {
+  /// Start a session on an opened transport, using the endpoints in `info`
+  pub fn new(transport: Box<dyn Transport>, info: DeviceInfo) -> Self {
+    let session_lost = Arc::new(Mutex::new(None));
+    Self {
+      transport: SessionWatch {
+        transport: Arc::from(transport),
+        session_lost: session_lost.clone(),
+        max_urb: AtomicUsize::new(0),
+        watchdog: Arc::default(),
+      },
+      endpoint_in: info.endpoint_in,
+      endpoint_out: info.endpoint_out,
+      info,
+      session_lost,
+      deadline: Mutex::new(None),
+    }
+  }
+
+  /// Get details of the device and the interface and endpoints chosen for it
+  pub fn info(&self) -> &DeviceInfo {
+    &self.info
+  }
+
+  /// Why the session ended, or `None` while it is still usable
+  pub fn session_lost(&self) -> Option<String> {
+    self.session_lost.lock().map(|lost| lost.clone()).unwrap_or_default()
+  }
+
+  /// Mark the session as ended, e.g. because the device is about to reset
+  pub fn end_session(&self, reason: String) {
+    tracing::debug!("usb session ended: {}", reason);
+    if let Ok(mut lost) = self.session_lost.lock() {
+      *lost = Some(reason);
+    }
+  }
+
+  /// Split bulk writes into URBs of at most `max_urb_size` bytes, or `None` to send each write whole
+  ///
+  /// Some host USB stacks, notably on Windows, fail bulk writes over a certain size. Whatever the
+  /// limit, a write the backend rejects with an I/O error is retried in halves down to 4 KiB, and
+  /// the smaller size is kept for the rest of the session. Sizes are rounded down to whole 512 byte
+  /// packets, so the device receives the same data either way.
+  pub fn set_max_urb_size(&self, max_urb_size: Option<usize>) {
+    let max_urb = max_urb_size.map_or(0, |size| (size / BULK_PACKET_SIZE * BULK_PACKET_SIZE).max(MIN_URB_SIZE));
+    self.transport.max_urb.store(max_urb, Ordering::Relaxed);
+  }
+
+  /// Largest URB bulk writes are split into, or `None` if they are sent whole
+  pub fn max_urb_size(&self) -> Option<usize> {
+    match self.transport.max_urb.load(Ordering::Relaxed) {
+      0 => None,
+      max_urb => Some(max_urb),
+    }
+  }
+
+  /// Cancel and retry bulk transfers that go `period` without finishing, or stop watching with `None`
+  ///
+  /// This catches transfers that hang without ever failing, a known libusb failure mode. The
+  /// transfer is cancelled from a watchdog thread, which calls `on_stall` with the transfer's
+  /// endpoint and how long it went without finishing. Its endpoint is then recovered like after a
+  /// stall, and the transfer is retried once. Both USB backends cancel bulk transfers; a transport
+  /// that doesn't implement [`Transport::cancel_transfers`] is only observed, and the retry waits
+  /// for the transfer to return by itself.
+  pub fn set_transfer_watchdog(
+    &self,
+    period: Option<Duration>,
+    on_stall: impl Fn(u8, Duration) + Send + Sync + 'static,
+  ) {
+    let transport = Arc::downgrade(&self.transport.transport);
+    self
+      .transport
+      .watchdog
+      .configure(period, Some(Arc::new(on_stall)), transport);
+  }
+
+  /// Set the time by which requests have to finish
+  ///
+  /// Transfers started after the deadline fail with [`Error::Timeout`], which breaks out of retry loops
+  /// that would otherwise keep a hung operation going. A single transfer is still bounded by its USB timeout.
+  pub fn set_deadline(&self, deadline: Option<(Instant, String)>) {
+    if let Ok(mut current) = self.deadline.lock() {
+      *current = deadline;
+    }
+  }
+
+  /// Fail with [`Error::Timeout`] if the deadline has passed
+  pub fn check_deadline(&self) -> Result<()> {
+    let Ok(deadline) = self.deadline.lock() else {
+      return Ok(());
+    };
+    match deadline.as_ref() {
+      Some((at, reason)) if Instant::now() >= *at => Err(Error::Timeout(reason.clone())),
+      _ => Ok(()),
+    }
+  }
+
+  /// Sleep for `duration`, failing with [`Error::Timeout`] if the deadline passes first
+  pub fn pause(&self, duration: Duration) -> Result<()> {
+    let deadline = self.deadline.lock().ok().and_then(|deadline| deadline.clone());
+    match deadline {
+      Some((at, reason)) if at < Instant::now() + duration => {
+        sleep(at.saturating_duration_since(Instant::now()));
+        Err(Error::Timeout(reason))
+      }
+      _ => {
+        sleep(duration);
+        Ok(())
+      }
+    }
+  }
+
+  /// Get the transport, failing if the session has ended or the deadline has passed
+  fn transport(&self) -> Result<&dyn Transport> {
+    if let Some(reason) = self.session_lost() {
+      return Err(Error::SessionLost(reason));
+    }
+    self.check_deadline()?;
+    Ok(&self.transport)
+  }
+
+  /// Get the transport for the AMLC handshake, which talks to BL2 over the session `run` ended
+  fn amlc_transport(&self) -> Result<&dyn Transport> {
+    self.check_deadline()?;
+    Ok(&self.transport)
+  }
+
+  /// Write data to device memory
+  ///
+  /// This writes a small amount of data (up to 64 bytes) to device memory.
+  /// For larger transfers, use `write_large_memory` instead.
+  ///
+  /// # Parameters
+  /// - `address`: The memory address to write to
+  /// - `data`: The data to write, must be <= 64 bytes
+  ///
+  /// # Returns
+  /// - `Result<()>`: Success or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn write_simple_memory(&self, address: u32, data: &[u8]) -> Result<()> {
+    tracing::debug!(
+      "writing simple memory at address: {:#X}, length: {}",
+      address,
+      data.len()
+    );
+    if data.len() > 64 {
+      return Err(Error::InvalidOperation("Maximum size of 64 bytes".into()));
+    }
+    let value = (address >> 16) as u16;
+    let index = (address & 0xffff) as u16;
+    self
+      .transport()?
+      .write_control(0x40, REQ_WRITE_MEM, value, index, data, COMMAND_TIMEOUT)?;
+    tracing::trace!(
+      "write_control completed for write_simple_memory at address: {:#X}",
+      address
+    );
+    Ok(())
+  }
+
+  /// Write arbitrary size data to device memory
+  ///
+  /// This breaks down larger transfers into multiple write_simple_memory operations.
+  ///
+  /// # Parameters
+  /// - `address`: The memory address to write to
+  /// - `data`: The data to write
+  ///
+  /// # Returns
+  /// - `Result<()>`: Success or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn write_memory(&self, address: u32, data: &[u8]) -> Result<()> {
+    tracing::debug!(
+      "writing memory starting at address: {:#X} with total length: {}",
+      address,
+      data.len()
+    );
+    let mut offset = 0;
+    let length = data.len();
+    while offset < length {
+      let chunk_size = std::cmp::min(64, length - offset);
+      self.write_simple_memory(address + offset as u32, &data[offset..offset + chunk_size])?;
+      tracing::trace!(
+        "chunk written for write_memory at address: {:#X}, new offset: {}",
+        address,
+        offset + chunk_size
+      );
+      offset += chunk_size;
+    }
+    Ok(())
+  }
+
+  /// Read a small amount of data from device memory
+  ///
+  /// This reads up to 64 bytes from device memory.
+  /// For larger transfers, use `read_memory` instead.
+  ///
+  /// # Parameters
+  /// - `address`: The memory address to read from
+  /// - `length`: The number of bytes to read (must be <= 64)
+  ///
+  /// # Returns
+  /// - `Result<Vec<u8>>`: The read data or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn read_simple_memory(&self, address: u32, length: usize) -> Result<Vec<u8>> {
+    tracing::debug!(
+      "reading simple memory at address: {:#X} with length: {}",
+      address,
+      length
+    );
+    if length == 0 {
+      return Ok(vec![]);
+    }
+    if length > 64 {
+      return Err(Error::InvalidOperation("Maximum size of 64 bytes".into()));
+    }
+    let value = (address >> 16) as u16;
+    let index = (address & 0xffff) as u16;
+    let mut buf = vec![0u8; length];
+    let read = self
+      .transport()?
+      .read_control(0xC0, REQ_READ_MEM, value, index, &mut buf, COMMAND_TIMEOUT)?;
+    tracing::trace!(
+      "read_control completed for read_simple_memory at address: {:#X}, bytes read: {}",
+      address,
+      read
+    );
+    if read != length {
+      return Err(Error::InvalidOperation("Incomplete read".into()));
+    }
+    Ok(buf)
+  }
+
+  /// Read arbitrary size data from device memory
+  ///
+  /// This breaks down larger transfers into multiple read_simple_memory operations.
+  ///
+  /// # Parameters
+  /// - `address`: The memory address to read from
+  /// - `length`: The number of bytes to read
+  ///
+  /// # Returns
+  /// - `Result<Vec<u8>>`: The read data or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn read_memory(&self, address: u32, length: usize) -> Result<Vec<u8>> {
+    tracing::debug!("reading memory at address: {:#X} with length: {}", address, length);
+    let mut data = vec![0u8; length];
+    let mut offset = 0;
+    while offset < length {
+      let read_length = std::cmp::min(64, length - offset);
+      let chunk = self.read_simple_memory(address + offset as u32, read_length)?;
+      data[offset..offset + read_length].copy_from_slice(&chunk);
+      tracing::trace!(
+        "chunk read for read_memory at address: {:#X}, offset: {}",
+        address,
+        offset
+      );
+      offset += read_length;
+    }
+    Ok(data)
+  }
+
+  /// Execute code at the specified memory address
+  ///
+  /// This ends the USB session: only the AMLC handshake with a freshly started BL2 can follow,
+  /// anything else needs a new session.
+  ///
+  /// # Parameters
+  /// - `address`: The memory address to execute code from
+  /// - `keep_power`: Whether to keep power on after execution
+  ///
+  /// # Returns
+  /// - `Result<()>`: Success or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn run(&self, address: u32, keep_power: Option<bool>) -> Result<()> {
+    let keep_power = keep_power.unwrap_or(true);
+    tracing::debug!("running at address: {:#X} with keep_power: {}", address, keep_power);
+    let data = if keep_power {
+      address | FLAG_KEEP_POWER_ON
+    } else {
+      address
+    };
+    let buffer = data.to_le_bytes();
+    let value = (address >> 16) as u16;
+    let index = (address & 0xffff) as u16;
+    self
+      .transport()?
+      .write_control(0x40, REQ_RUN_IN_ADDR, value, index, &buffer, COMMAND_TIMEOUT)?;
+    tracing::trace!("run command sent at address: {:#X}", address);
+    self.end_session(format!("the device started running code at {:#X}", address));
+    Ok(())
+  }
+
+  /// Identify the device
+  ///
+  /// # Returns
+  /// - `Result<String>`: The device identification string or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn identify(&self) -> Result<String> {
+    tracing::debug!("identifying device");
+    let mut buf = [0u8; 8];
+    let read = self
+      .transport()?
+      .read_control(0xC0, REQ_IDENTIFY_HOST, 0, 0, &mut buf, COMMAND_TIMEOUT)?;
+    tracing::trace!("identify response received: {:?} ({} bytes)", &buf, read);
+    if read != 8 {
+      return Err(Error::InvalidOperation("Failed to read identify data".into()));
+    }
+    Ok(String::from_utf8(buf.to_vec())?)
+  }
+
+  /// Detect whether the SoC enforces secure boot
+  ///
+  /// This reads the efuse-backed security config register, so it only needs the ROM (or
+  /// burn mode) USB protocol. The state decides which BL2 the device will accept.
+  ///
+  /// # Returns
+  /// - `SecureBootState`: The detected state, or `Unknown` if the register could not be read
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn secure_boot_state(&self) -> SecureBootState {
+    match self.read_simple_memory(AO_SEC_SD_CFG10, 4) {
+      Ok(buf) => {
+        let value = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        tracing::debug!("AO_SEC_SD_CFG10 = {:#010x}", value);
+        if value & SECURE_BOOT_BIT != 0 {
+          SecureBootState::Enabled
+        } else {
+          SecureBootState::Disabled
+        }
+      }
+      Err(e) => {
+        tracing::debug!("could not read secure boot state: {}", e);
+        SecureBootState::Unknown
+      }
+    }
+  }
+
+  /// Write large blocks of data to device memory
+  ///
+  /// This is used for writing firmware images and other large data blocks.
+  ///
+  /// # Parameters
+  /// - `memory_address`: The memory address to write to
+  /// - `data`: The data to write
+  /// - `block_length`: The size of each block to transfer
+  /// - `append_zeros`: Whether to pad data with zeros to match block_length
+  ///
+  /// # Returns
+  /// - `Result<()>`: Success or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn write_large_memory(
+    &self,
+    memory_address: u32,
+    data: &[u8],
+    block_length: usize,
+    append_zeros: bool,
+  ) -> Result<()> {
+    tracing::debug!(
+      "writing large memory to address: {:#X} with data length: {}",
+      memory_address,
+      data.len()
+    );
+
+    let total_len = padded_length(data.len(), block_length, append_zeros)?;
+    self.start_large_write(memory_address, total_len, block_length)?;
+
+    // full blocks go out straight from `data`, only a short last block is copied to pad it
+    let mut blocks = data.chunks_exact(block_length);
+    for (index, chunk) in blocks.by_ref().enumerate() {
+      self.write_large_block(index * block_length, chunk)?;
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+      let mut last = vec![0u8; block_length];
+      last[..tail.len()].copy_from_slice(tail);
+      self.write_large_block(data.len() - tail.len(), &last)?;
+    }
+
+    Ok(())
+  }
+
+  /// Write `length` bytes from `reader` to device memory in blocks, without holding it all in memory
+  ///
+  /// # Parameters
+  /// - `memory_address`: The memory address to write to
+  /// - `reader`: A reader providing the data to write
+  /// - `length`: The number of bytes to take from `reader`
+  /// - `block_length`: The size of each block to transfer
+  /// - `append_zeros`: Whether to pad data with zeros to match block_length
+  ///
+  /// # Returns
+  /// - `Result<()>`: Success or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn write_large_memory_from_reader<R: Read>(
+    &self,
+    memory_address: u32,
+    reader: &mut R,
+    length: usize,
+    block_length: usize,
+    append_zeros: bool,
+  ) -> Result<()> {
+    tracing::debug!(
+      "writing large memory to address: {:#X} from a reader with length: {}",
+      memory_address,
+      length
+    );
+
+    let total_len = padded_length(length, block_length, append_zeros)?;
+    self.start_large_write(memory_address, total_len, block_length)?;
+
+    let mut block = vec![0u8; block_length];
+    let mut data_offset = 0;
+    while data_offset < length {
+      let read_length = std::cmp::min(block_length, length - data_offset);
+      reader.read_exact(&mut block[..read_length])?;
+      block[read_length..].fill(0);
+      self.write_large_block(data_offset, &block)?;
+      data_offset += read_length;
+    }
+
+    Ok(())
+  }
+
+  /// Announce a large memory write of `total_len` bytes, which the device then expects in blocks
+  fn start_large_write(&self, memory_address: u32, total_len: usize, block_length: usize) -> Result<()> {
+    let block_count = (total_len / block_length) as u16;
+    let mut control_data = Vec::with_capacity(16);
+    control_data.extend_from_slice(&memory_address.to_le_bytes());
+    control_data.extend_from_slice(&(total_len as u32).to_le_bytes());
+    control_data.extend_from_slice(&0u32.to_le_bytes());
+    control_data.extend_from_slice(&0u32.to_le_bytes());
+
+    tracing::trace!("writing control data: {:?}", &control_data);
+    self.transport()?.write_control(
+      0x40,
+      REQ_WR_LARGE_MEM,
+      block_length as u16,
+      block_count,
+      &control_data,
+      COMMAND_TIMEOUT,
+    )?;
+    Ok(())
+  }
+
+  fn write_large_block(&self, data_offset: usize, chunk: &[u8]) -> Result<()> {
+    tracing::trace!(target: "flashthing::aml::write_large_memory", "writing actual data from offset: {:#X}", &data_offset);
+
+    self
+      .transport()?
+      .write_bulk(self.endpoint_out, chunk, Duration::from_millis(2000))?;
+
+    tracing::trace!(target: "flashthing::aml::write_large_memory", "wrote actual data from offset: {:#X}", &data_offset);
+    Ok(())
+  }
+
+  /// Read large blocks of data from device memory
+  ///
+  /// This is the read counterpart of `write_large_memory`, used to pull data staged
+  /// in memory (e.g. by `amlmmc read`) back to the host.
+  ///
+  /// # Parameters
+  /// - `memory_address`: The memory address to read from
+  /// - `length`: The number of bytes to read, must be a multiple of block_length
+  /// - `block_length`: The size of each block to transfer
+  ///
+  /// # Returns
+  /// - `Result<Vec<u8>>`: The read data or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn read_large_memory(&self, memory_address: u32, length: usize, block_length: usize) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; length];
+    self.read_large_memory_into(memory_address, &mut data, block_length)?;
+    Ok(data)
+  }
+
+  /// Read large blocks of data from device memory into `buf`, filling all of it
+  ///
+  /// Lets callers reading many chunks reuse one buffer instead of allocating each time.
+  ///
+  /// # Parameters
+  /// - `memory_address`: The memory address to read from
+  /// - `buf`: Where to read to, its length must be a multiple of block_length
+  /// - `block_length`: The size of each block to transfer
+  ///
+  /// # Returns
+  /// - `Result<()>`: Success or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn read_large_memory_into(&self, memory_address: u32, buf: &mut [u8], block_length: usize) -> Result<()> {
+    let length = buf.len();
+    tracing::debug!(
+      "reading large memory from address: {:#X} with length: {}",
+      memory_address,
+      length
+    );
+
+    if !length.is_multiple_of(block_length) {
+      return Err(Error::InvalidOperation(
+        "Large Data must be a multiple of block length".into(),
+      ));
+    }
+
+    let block_count = (length / block_length) as u16;
+    let mut control_data = Vec::with_capacity(16);
+    control_data.extend_from_slice(&memory_address.to_le_bytes());
+    control_data.extend_from_slice(&(length as u32).to_le_bytes());
+    control_data.extend_from_slice(&0u32.to_le_bytes());
+    control_data.extend_from_slice(&0u32.to_le_bytes());
+
+    tracing::trace!("writing control data: {:?}", &control_data);
+    self.transport()?.write_control(
+      0x40,
+      REQ_RD_LARGE_MEM,
+      block_length as u16,
+      block_count,
+      &control_data,
+      COMMAND_TIMEOUT,
+    )?;
+
+    for (index, chunk) in buf.chunks_exact_mut(block_length).enumerate() {
+      let read = self
+        .transport()?
+        .read_bulk(self.endpoint_in, chunk, Duration::from_millis(2000))?;
+      if read != block_length {
+        return Err(Error::InvalidOperation(format!(
+          "short read at offset {:#X}: {} of {} bytes",
+          index * block_length,
+          read,
+          block_length
+        )));
+      }
+    }
+
+    Ok(())
+  }
+
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn write_amlc_data(&self, offset: u32, data: &[u8]) -> Result<()> {
+    tracing::debug!("writing amlc data at offset: {:#X} with length: {}", offset, data.len());
+
+    self.amlc_transport()?.write_control(
+      0x40,
+      REQ_WRITE_AMLC,
+      (offset / AMLC_AMLS_BLOCK_LENGTH as u32) as u16,
+      (data.len() - 1) as u16,
+      &[],
+      COMMAND_TIMEOUT,
+    )?;
+    tracing::trace!("amlc header sent for data write at offset: {:#X}", offset);
+
+    let max_chunk_size = AMLC_MAX_BLOCK_LENGTH;
+    let mut data_offset = 0;
+    let write_length = data.len();
+    let mut remaining = write_length;
+
+    let bulk_timeout = Duration::from_millis(1000);
+
+    while remaining > 0 {
+      let block_length = std::cmp::min(remaining, max_chunk_size);
+      let chunk = &data[data_offset..data_offset + block_length];
+
+      let mut retries = 0;
+      let max_retries = 3;
+      let mut success = false;
+
+      while !success && retries < max_retries {
+        match self
+          .amlc_transport()?
+          .write_bulk(self.endpoint_out, chunk, bulk_timeout)
+        {
+          Ok(written) => {
+            if written == block_length {
+              success = true;
+              tracing::trace!(
+                "bulk write in AMLC data, data_offset: {}, chunk: {}",
+                data_offset,
+                block_length
+              );
+            } else {
+              tracing::warn!(
+                "Incomplete bulk write: {} of {} bytes. Retry {}/{}",
+                written,
+                block_length,
+                retries + 1,
+                max_retries
+              );
+              retries += 1;
+              sleep(Duration::from_millis(100));
+            }
+          }
+          Err(e) => {
+            tracing::warn!("Error in bulk write: {}. Retry {}/{}", e, retries + 1, max_retries);
+            retries += 1;
+            sleep(Duration::from_millis(100));
+
+            if retries >= max_retries {
+              return Err(e);
+            }
+          }
+        }
+      }
+
+      data_offset += block_length;
+      remaining -= block_length;
+
+      sleep(Duration::from_millis(10));
+    }
+
+    let mut ack_buf = [0u8; 16];
+    let mut retries = 0;
+    let max_retries = 3;
+    let mut read = 0;
+
+    while retries < max_retries {
+      match self
+        .amlc_transport()?
+        .read_bulk(self.endpoint_in, &mut ack_buf, bulk_timeout)
+      {
+        Ok(bytes_read) => {
+          read = bytes_read;
+          if read >= 4 {
+            break;
+          }
+          tracing::warn!("short ack read: {} bytes. retry {}/{}", read, retries + 1, max_retries);
+        }
+        Err(e) => {
+          tracing::warn!("error reading ack: {}. retry {}/{}", e, retries + 1, max_retries);
+        }
+      }
+      retries += 1;
+      sleep(Duration::from_millis(100));
+    }
+
+    tracing::trace!("received amlc ack: {:?} ({} bytes)", &ack_buf[..read], read);
+
+    if read < 4 {
+      return Err(Error::InvalidOperation("no acknowledgment received".into()));
+    }
+
+    let ack = String::from_utf8(ack_buf[0..4].to_vec())?;
+    if ack != "OKAY" {
+      return Err(Error::InvalidOperation(format!("invalid amlc data write ack: {}", ack)));
+    }
+
+    Ok(())
+  }
+
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn write_amlc_data_packet(&self, seq: u8, amlc_offset: u32, data: &[u8]) -> Result<()> {
+    tracing::debug!("writing amlc data packet, seq: {}, offset: {:#X}", seq, amlc_offset);
+
+    let data_len = data.len();
+    let max_transfer_length = AMLC_MAX_TRANSFER_LENGTH;
+    let transfer_count = data_len.div_ceil(max_transfer_length);
+
+    if data_len > 0 {
+      let mut offset = 0;
+      for i in 0..transfer_count {
+        let write_length = std::cmp::min(max_transfer_length, data_len - offset);
+        tracing::trace!(
+          "sending amlc data packet chunk {}/{} at offset: {} with length: {}",
+          i + 1,
+          transfer_count,
+          offset,
+          write_length
+        );
+
+        self.write_amlc_data(offset as u32, &data[offset..offset + write_length])?;
+        sleep(Duration::from_millis(50));
+
+        offset += write_length;
+      }
+    }
+
+    let amlc_data = amls_block(seq, data);
+    tracing::debug!("sending AMLS block with seq {} to offset {:#X}", seq, amlc_offset);
+    self.write_amlc_data(amlc_offset, &amlc_data)?;
+
+    Ok(())
+  }
+
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn get_boot_amlc(&self) -> Result<(u32, u32)> {
+    tracing::debug!("getting boot amlc data");
+    self.amlc_transport()?.write_control(
+      0x40,
+      REQ_GET_AMLC,
+      AMLC_AMLS_BLOCK_LENGTH as u16,
+      0,
+      &[],
+      COMMAND_TIMEOUT,
+    )?;
+    tracing::trace!("amlc get request sent");
+    let mut buf = vec![0u8; AMLC_AMLS_BLOCK_LENGTH];
+    let read = self
+      .amlc_transport()?
+      .read_bulk(self.endpoint_in, &mut buf, Duration::from_secs(2))?;
+    tracing::trace!("amlc data received, length: {}", read);
+    if read < AMLC_AMLS_BLOCK_LENGTH {
+      return Err(Error::InvalidOperation("No amlc data received".into()));
+    }
+    let tag = String::from_utf8(buf[0..4].to_vec())?;
+    if tag != "AMLC" {
+      return Err(Error::InvalidOperation(format!("invalid amlc request: {}", tag)));
+    }
+    let length = u32::from_le_bytes(buf[8..12].try_into()?);
+    let offset = u32::from_le_bytes(buf[12..16].try_into()?);
+    let mut ack = [0u8; 16];
+    ack[..4].copy_from_slice(b"OKAY");
+    self
+      .amlc_transport()?
+      .write_bulk(self.endpoint_out, &ack, Duration::from_secs(2))?;
+    tracing::trace!("acknowledgment sent for amlc data");
+    Ok((length, offset))
+  }
+
+  /// Load BL2 at `bl2_address`, run it, and serve it `bootloader` over AMLC
+  ///
+  /// The device re-enumerates in USB burn mode afterwards, which ends the session.
+  ///
+  /// # Parameters
+  /// - `bl2_address`: Address BL2 is loaded to and run from
+  /// - `bl2`: The BL2 image, signed and encrypted if the SoC enforces secure boot
+  /// - `bootloader`: The bootloader BL2 asks for
+  ///
+  /// # Returns
+  /// - `Result<()>`: Success or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn boot_bl2(&self, bl2_address: u32, bl2: &[u8], bootloader: &[u8]) -> Result<()> {
+    tracing::info!("sending bl2 binary to address {:#X}...", bl2_address);
+    self.write_large_memory(bl2_address, bl2, 4096, true)?;
+
+    tracing::info!("booting from bl2...");
+    self.run(bl2_address, Some(true))?;
+
+    tracing::debug!("waiting for bootloader to initialize...");
+    sleep(Duration::from_secs(2));
+
+    let mut prev_length: u32 = 0;
+    let mut prev_offset: u32 = 0;
+    let mut seq: u8 = 0;
+
+    let max_retries = 3;
+    let max_iterations = 50;
+    let mut iterations = 0;
+
+    tracing::info!("starting AMLC data transfer sequence...");
+
+    loop {
+      if iterations >= max_iterations {
+        return Err(Error::InvalidOperation("maximum iterations reached in bl2_boot".into()));
+      }
+      iterations += 1;
+
+      let mut retry_count = 0;
+      let (length, offset) = loop {
+        match self.get_boot_amlc() {
+          Ok(result) => break result,
+          Err(e) => {
+            retry_count += 1;
+            if retry_count >= max_retries {
+              tracing::error!("failed to get boot amlc data after {} attempts: {}", max_retries, e);
+              return Err(e);
+            }
+            tracing::warn!("failed to get boot amlc, retry {}/{}: {}", retry_count, max_retries, e);
+            sleep(Duration::from_millis(500));
+          }
+        }
+      };
+
+      tracing::debug!("amlc request: dataSize={}, offset={}, seq={}", length, offset, seq);
+
+      if length == prev_length && offset == prev_offset {
+        tracing::debug!("amlc transfer complete - received same length/offset twice");
+        break;
+      }
+
+      prev_length = length;
+      prev_offset = offset;
+
+      if offset as usize >= bootloader.len() {
+        tracing::warn!(
+          "amlc requested offset {} exceeds bootloader size {}",
+          offset,
+          bootloader.len()
+        );
+        let empty_slice = &[];
+        self.write_amlc_data_packet(seq, offset, empty_slice)?;
+      } else {
+        let actual_length = std::cmp::min(length as usize, bootloader.len() - offset as usize);
+        let data_slice = &bootloader[offset as usize..offset as usize + actual_length];
+
+        tracing::debug!("sending {} bytes at offset {} with seq {}", actual_length, offset, seq);
+        self.write_amlc_data_packet(seq, offset, data_slice)?;
+      }
+
+      seq = seq.wrapping_add(1);
+      sleep(Duration::from_millis(100));
+    }
+
+    tracing::info!("bl2 boot sequence completed successfully!");
+    self.end_session("the device re-enumerates after booting BL2".into());
+    Ok(())
+  }
+
+  /// Send a bulk command to the device
+  ///
+  /// # Parameters
+  /// - `command`: The command string to send
+  ///
+  /// # Returns
+  /// - `Result<String>`: The command response or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn bulkcmd(&self, command: &str) -> Result<String> {
+    tracing::debug!("sending bulk command: {:?}", command);
+    let mut command = command.as_bytes().to_vec();
+    command.push(0x00);
+    self
+      .transport()?
+      .write_control(0x40, REQ_BULKCMD, 0, 0, &command, COMMAND_TIMEOUT)?;
+    tracing::trace!("bulk command control write completed");
+
+    let mut buf = vec![0u8; 512];
+    let read = self
+      .transport()?
+      .read_bulk(self.endpoint_in, &mut buf, COMMAND_TIMEOUT)?;
+    tracing::trace!("bulk command response received, length: {}", read);
+
+    if read == 0 {
+      return Err(Error::InvalidOperation("No response received for bulk command".into()));
+    }
+    let slice = &buf[..read];
+    let start = slice.iter().position(|&b| b != 0).unwrap_or(0);
+    let end = slice.iter().rposition(|&b| b != 0).map(|pos| pos + 1).unwrap_or(0);
+    let trimmed = &slice[start..end];
+    let response = String::from_utf8(trimmed.to_vec())?;
+    if !response.to_lowercase().contains("success") {
+      return Err(Error::InvalidOperation(format!(
+        "Bulk command failed, response did not contain 'success': {}",
+        response
+      )));
+    }
+    Ok(response)
+  }
+
+  /// Send a bulkcmd that resets the device, ending the USB session
+  ///
+  /// The command is sent without waiting for a response, since the device resets before it can answer.
+  ///
+  /// # Parameters
+  /// - `command`: The reset command, e.g. `reset`, or `reboot update` to come back in USB burn mode
+  ///
+  /// # Returns
+  /// - `Result<()>`: Success or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn reset(&self, command: &str) -> Result<()> {
+    tracing::debug!("resetting device with: {:?}", command);
+    let mut bytes = command.as_bytes().to_vec();
+    bytes.push(0x00);
+    self
+      .transport()?
+      .write_control(0x40, REQ_BULKCMD, 0, 0, &bytes, COMMAND_TIMEOUT)?;
+    self.end_session(format!("the device was reset ({command})"));
+    Ok(())
+  }
+}
+
+/// Whether the backend rejected a bulk write as too large for the host, rather than the device failing it
+fn urb_rejected(err: &Error) -> bool {
+  #[cfg(feature = "rusb")]
+  if matches!(err, Error::UsbError(rusb::Error::Io)) {
+    return true;
+  }
+  let _ = err;
+  false
+}
+
+/// Length of a large memory write of `length` bytes once padded to whole blocks
+fn padded_length(length: usize, block_length: usize, append_zeros: bool) -> Result<usize> {
+  if append_zeros {
+    Ok(length.next_multiple_of(block_length))
+  } else if !length.is_multiple_of(block_length) {
+    Err(Error::InvalidOperation(
+      "Large Data must be a multiple of block length".into(),
+    ))
+  } else {
+    Ok(length)
+  }
+}
+
+/// Checksum BL2 expects in the AMLS block of a packet: the wrapping sum of `data` as little-endian
+/// 32-bit words, with the 1 to 3 bytes left over at the end summed as one shorter word
+pub fn amlc_checksum(data: &[u8]) -> u32 {
+  let mut checksum: u32 = 0;
+  let mut offset = 0;
+  while offset < data.len() {
+    let remaining = data.len() - offset;
+    let val: u32 = if remaining >= 4 {
+      let v = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+      offset += 4;
+      v
+    } else if remaining >= 3 {
+      let mut temp = [0u8; 4];
+      temp[..remaining].copy_from_slice(&data[offset..]);
+      offset += 3;
+      u32::from_le_bytes(temp) & 0xffffff
+    } else if remaining >= 2 {
+      let v = u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap()) as u32;
+      offset += 2;
+      v
+    } else {
+      let v = data[offset] as u32;
+      offset += 1;
+      v
+    };
+    checksum = checksum.wrapping_add(val);
+  }
+  checksum
+}
+
+/// The AMLS block that ends an AMLC packet, telling BL2 the packet's sequence number and checksum
+///
+/// # Parameters
+/// - `seq`: Sequence number BL2 asked for
+/// - `data`: The whole packet, already sent in AMLC blocks
+///
+/// # Returns
+/// - `Vec<u8>`: The block, [`AMLC_AMLS_BLOCK_LENGTH`] bytes long
+pub fn amls_block(seq: u8, data: &[u8]) -> Vec<u8> {
+  let mut block = vec![0u8; AMLC_AMLS_BLOCK_LENGTH];
+  block[0..4].copy_from_slice(b"AMLS"); // ! This is AMLS not AMLC for final packet - do not change
+  block[4] = seq;
+  block[8..12].copy_from_slice(&amlc_checksum(data).to_le_bytes());
+
+  if data.len() > 16 {
+    let copy_len = std::cmp::min(AMLC_AMLS_BLOCK_LENGTH - 16, data.len() - 16);
+    block[16..16 + copy_len].copy_from_slice(&data[16..16 + copy_len]);
+  }
+  block
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_amls_block() {
+    assert_eq!(amlc_checksum(&[]), 0);
+    assert_eq!(amlc_checksum(&[1, 0, 0, 0, 2, 0, 0, 0]), 3);
+    // leftover bytes are summed as one shorter word
+    assert_eq!(amlc_checksum(&[0, 0, 0, 0, 0xff, 0xff, 0xff]), 0xff_ffff);
+    assert_eq!(amlc_checksum(&[0xff, 0xff, 0xff, 0xff, 2]), 1);
+
+    let packet = (0..=255).collect::<Vec<u8>>();
+    let block = amls_block(7, &packet);
+    assert_eq!(block.len(), AMLC_AMLS_BLOCK_LENGTH);
+    assert_eq!(&block[..4], b"AMLS");
+    assert_eq!(block[4], 7);
+    assert_eq!(block[8..12], amlc_checksum(&packet).to_le_bytes());
+    assert_eq!(block[16..256], packet[16..]);
+    assert!(block[256..].iter().all(|byte| *byte == 0));
+  }
+
+  /// Accepts bulk writes of up to `limit` bytes, recording the size of each
+  #[cfg(feature = "rusb")]
+  #[derive(Debug)]
+  struct LimitedTransport {
+    limit: usize,
+    writes: Arc<Mutex<Vec<usize>>>,
+  }
+
+  #[cfg(feature = "rusb")]
+  impl Transport for LimitedTransport {
+    fn write_control(&self, _: u8, _: u8, _: u16, _: u16, data: &[u8], _: Duration) -> Result<usize> {
+      Ok(data.len())
+    }
+
+    fn read_control(&self, _: u8, _: u8, _: u16, _: u16, buf: &mut [u8], _: Duration) -> Result<usize> {
+      Ok(buf.len())
+    }
+
+    fn write_bulk(&self, _: u8, data: &[u8], _: Duration) -> Result<usize> {
+      if data.len() > self.limit {
+        return Err(Error::UsbError(rusb::Error::Io));
+      }
+      self.writes.lock().unwrap().push(data.len());
+      Ok(data.len())
+    }
+
+    fn read_bulk(&self, _: u8, buf: &mut [u8], _: Duration) -> Result<usize> {
+      Ok(buf.len())
+    }
+  }
+
+  #[cfg(feature = "rusb")]
+  #[test]
+  fn test_urb_splitting() {
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    let session = Session::new(
+      Box::new(LimitedTransport {
+        limit: 64 * 1024,
+        writes: writes.clone(),
+      }),
+      DeviceInfo {
+        vendor_id: 0x1b8e,
+        product_id: 0xc003,
+        bus_number: 1,
+        address: 1,
+        serial: None,
+        interface_count: 1,
+        interface_number: 0,
+        alt_setting: 0,
+        endpoint_in: 0x81,
+        endpoint_out: 0x01,
+        bulk: true,
+      },
+    );
+    let data = vec![0; 512 * 1024];
+    assert_eq!(
+      session
+        .transport()
+        .unwrap()
+        .write_bulk(1, &data[..64 * 1024], Duration::ZERO)
+        .unwrap(),
+      64 * 1024
+    );
+    assert_eq!(*writes.lock().unwrap(), [64 * 1024]);
+
+    session.set_max_urb_size(Some(256 * 1024));
+    writes.lock().unwrap().clear();
+    assert_eq!(
+      session
+        .transport()
+        .unwrap()
+        .write_bulk(1, &data, Duration::ZERO)
+        .unwrap(),
+      data.len()
+    );
+    assert_eq!(*writes.lock().unwrap(), [64 * 1024; 8]);
+    assert_eq!(session.max_urb_size(), Some(64 * 1024));
+
+    session.set_max_urb_size(Some(1000));
+    assert_eq!(session.max_urb_size(), Some(4096));
+  }
+}
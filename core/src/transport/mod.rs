@@ -0,0 +1,218 @@
+//! USB transports. libusb (through `rusb`) is used by default; enabling the `nusb` feature
+//! switches to the pure-Rust `nusb` backend, which needs no C library.
+
+use std::{borrow::Cow, time::Duration};
+
+use crate::{DeviceInfo, DeviceMode, Result};
+
+#[cfg(not(any(feature = "rusb", feature = "nusb")))]
+compile_error!("flashthing-core needs a USB backend, enable either the `rusb` or the `nusb` feature");
+
+#[cfg(feature = "nusb")]
+mod nusb_backend;
+#[cfg(all(feature = "rusb", not(feature = "nusb")))]
+mod rusb_backend;
+
+#[cfg(feature = "nusb")]
+use nusb_backend as backend;
+#[cfg(all(feature = "rusb", not(feature = "nusb")))]
+use rusb_backend as backend;
+
+/// The USB operations the Amlogic protocol is built on
+///
+/// The claimed interface is released when the transport is dropped. Implement it to talk to
+/// something other than a real device, such as an emulator in tests.
+pub trait Transport: Send + Sync + std::fmt::Debug {
+  /// Send a control transfer to the device, returning the number of bytes written
+  fn write_control(
+    &self,
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    data: &[u8],
+    timeout: Duration,
+  ) -> Result<usize>;
+
+  /// Receive a control transfer from the device, returning the number of bytes read
+  fn read_control(
+    &self,
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    buf: &mut [u8],
+    timeout: Duration,
+  ) -> Result<usize>;
+
+  /// Write to a bulk OUT endpoint, returning the number of bytes written
+  fn write_bulk(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize>;
+
+  /// Read from a bulk IN endpoint, returning the number of bytes read
+  fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize>;
+
+  /// Clear a halt (STALL) condition on an endpoint, so it accepts transfers again
+  ///
+  /// Transports whose endpoints never halt can leave this as is.
+  fn clear_halt(&self, endpoint: u8) -> Result<()> {
+    let _ = endpoint;
+    Ok(())
+  }
+
+  /// Cancel the bulk transfer another thread is blocked on, which then returns an error
+  ///
+  /// Called when a transfer has hung. Transports that can't cancel a blocking transfer leave this
+  /// as is, and the transfer is retried once it returns by itself.
+  fn cancel_transfers(&self) {}
+}
+
+/// Log level of the USB backend
+///
+/// Only libusb has a log level of its own; the `nusb` backend logs through the `log` crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbLogLevel {
+  /// No messages
+  None,
+  /// Errors only
+  Error,
+  /// Warnings and errors
+  Warning,
+  /// Informational messages, warnings, and errors
+  Info,
+  /// Everything, including debug messages
+  Debug,
+}
+
+/// How a device identifies itself on the bus in each of its modes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbIds {
+  /// USB vendor ID in USB (burn) mode
+  pub vendor_id: u16,
+  /// USB product ID in USB (burn) mode
+  pub product_id: u16,
+  /// USB product string reported by the mask ROM, as opposed to the burn mode bootloader
+  pub rom_product_string: Cow<'static, str>,
+  /// USB vendor and product ID when booted normally, if the device exposes a gadget
+  pub normal_mode_ids: Option<(u16, u16)>,
+}
+
+impl UsbIds {
+  /// IDs of the Spotify Car Thing (superbird)
+  pub const fn superbird() -> Self {
+    Self {
+      vendor_id: 0x1b8e,
+      product_id: 0xc003,
+      rom_product_string: Cow::Borrowed("GX-CHIP"),
+      normal_mode_ids: Some((0x18d1, 0x4e40)),
+    }
+  }
+}
+
+/// Look for a device with these IDs on the bus and report the mode it is in
+pub fn find_usb_device(ids: &UsbIds) -> DeviceMode {
+  backend::find_device(ids)
+}
+
+/// Open the device with these IDs and claim the interface used for burning
+pub fn open_usb(ids: &UsbIds) -> Result<(Box<dyn Transport>, DeviceInfo)> {
+  backend::open(ids)
+}
+
+/// Set the log level of the USB backend, where it has one
+///
+/// libusb logs to stderr, so this is mostly useful for debugging transport issues. This
+/// does nothing with the `nusb` backend.
+pub fn set_usb_log_level(level: UsbLogLevel) -> Result<()> {
+  backend::set_log_level(level)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct EndpointCandidate {
+  interface_number: u8,
+  alt_setting: u8,
+  address: u8,
+  is_in: bool,
+  is_bulk: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EndpointSelection {
+  interface_number: u8,
+  alt_setting: u8,
+  endpoint_in: u8,
+  endpoint_out: u8,
+  bulk: bool,
+}
+
+/// Pick the interface and endpoints to use from every endpoint in the active configuration
+///
+/// Interfaces and alt settings are considered in descriptor order. The first one with a bulk
+/// IN and a bulk OUT endpoint wins; failing that, the first with any IN and OUT endpoint.
+fn select_endpoints(candidates: &[EndpointCandidate]) -> Option<EndpointSelection> {
+  let mut settings = candidates
+    .iter()
+    .map(|ep| (ep.interface_number, ep.alt_setting))
+    .collect::<Vec<_>>();
+  settings.dedup();
+
+  let pick = |bulk_only: bool| {
+    settings.iter().find_map(|&(interface_number, alt_setting)| {
+      let endpoints = candidates
+        .iter()
+        .filter(|ep| ep.interface_number == interface_number && ep.alt_setting == alt_setting)
+        .filter(|ep| ep.is_bulk || !bulk_only);
+      let mut endpoints_in = endpoints.clone().filter(|ep| ep.is_in);
+      let mut endpoints_out = endpoints.filter(|ep| !ep.is_in);
+      let (endpoint_in, endpoint_out) = (endpoints_in.next()?, endpoints_out.next()?);
+      Some(EndpointSelection {
+        interface_number,
+        alt_setting,
+        endpoint_in: endpoint_in.address,
+        endpoint_out: endpoint_out.address,
+        bulk: endpoint_in.is_bulk && endpoint_out.is_bulk,
+      })
+    })
+  };
+
+  pick(true).or_else(|| pick(false))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_select_endpoints() {
+    let ep = |interface_number, alt_setting, address, is_bulk| EndpointCandidate {
+      interface_number,
+      alt_setting,
+      address,
+      is_in: address & 0x80 != 0,
+      is_bulk,
+    };
+
+    // composite device: interface 0 only has interrupt endpoints, interface 1 has bulk ones
+    let candidates = [
+      ep(0, 0, 0x81, false),
+      ep(0, 0, 0x01, false),
+      ep(1, 0, 0x82, true),
+      ep(1, 0, 0x02, true),
+    ];
+    assert_eq!(
+      select_endpoints(&candidates),
+      Some(EndpointSelection {
+        interface_number: 1,
+        alt_setting: 0,
+        endpoint_in: 0x82,
+        endpoint_out: 0x02,
+        bulk: true,
+      })
+    );
+
+    // no bulk pair anywhere, so fall back to the first usable pair
+    let selection = select_endpoints(&candidates[..2]).unwrap();
+    assert_eq!((selection.interface_number, selection.bulk), (0, false));
+
+    assert_eq!(select_endpoints(&[ep(0, 0, 0x81, true)]), None);
+  }
+}
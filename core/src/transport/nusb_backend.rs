@@ -0,0 +1,320 @@
+//! Pure-Rust backend, through `nusb`.
+
+use std::{
+  sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, Ordering},
+  },
+  task::{Context, Poll, Wake, Waker},
+  thread::{self, Thread},
+  time::{Duration, Instant},
+};
+
+use nusb::{
+  Interface,
+  transfer::{Control, ControlType, Direction, EndpointType, Recipient, RequestBuffer, TransferError},
+};
+
+use super::{EndpointCandidate, Transport, UsbIds, UsbLogLevel, select_endpoints};
+use crate::{DeviceInfo, DeviceMode, Error, Result};
+
+struct NusbTransport {
+  interface: Interface,
+  /// Set to cancel the transfer being waited on
+  cancel: AtomicBool,
+  /// Thread waiting on a transfer, woken up to cancel it
+  waiting: Mutex<Option<Thread>>,
+}
+
+impl NusbTransport {
+  /// Wait for `poll` until `deadline`, or until the transfer is cancelled with [`Transport::cancel_transfers`]
+  fn wait<T>(&self, mut poll: impl FnMut(&mut Context<'_>) -> Poll<T>, deadline: Instant) -> Option<T> {
+    self.cancel.store(false, Ordering::Relaxed);
+    *self.waiting.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(thread::current());
+    let result = block_on(
+      |cx| match self.cancel.load(Ordering::Relaxed) {
+        true => Poll::Ready(None),
+        false => poll(cx).map(Some),
+      },
+      Some(deadline),
+    )
+    .flatten();
+    *self.waiting.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = None;
+    result
+  }
+}
+
+impl std::fmt::Debug for NusbTransport {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("NusbTransport")
+      .field("interface", &self.interface.interface_number())
+      .finish()
+  }
+}
+
+impl Transport for NusbTransport {
+  fn write_control(
+    &self,
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    data: &[u8],
+    timeout: Duration,
+  ) -> Result<usize> {
+    let control = control(request_type, request, value, index);
+    self
+      .interface
+      .control_out_blocking(control, data, timeout)
+      .map_err(transfer_error)
+  }
+
+  fn read_control(
+    &self,
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    buf: &mut [u8],
+    timeout: Duration,
+  ) -> Result<usize> {
+    let control = control(request_type, request, value, index);
+    self
+      .interface
+      .control_in_blocking(control, buf, timeout)
+      .map_err(transfer_error)
+  }
+
+  fn write_bulk(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize> {
+    let mut queue = self.interface.bulk_out_queue(endpoint);
+    queue.submit(data.to_vec());
+
+    let completion = match self.wait(|cx| queue.poll_next(cx), Instant::now() + timeout) {
+      Some(completion) => completion,
+      None => {
+        // the transfer may still finish while it is being cancelled, so wait for its final status
+        queue.cancel_all();
+        let completion = block_on(|cx| queue.poll_next(cx), None).expect("waits without a deadline");
+        if completion.status.is_err() {
+          return Err(timed_out());
+        }
+        completion
+      }
+    };
+
+    completion.status.map_err(transfer_error)?;
+    Ok(completion.data.actual_length())
+  }
+
+  fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+    let mut queue = self.interface.bulk_in_queue(endpoint);
+    queue.submit(RequestBuffer::new(buf.len()));
+
+    let completion = match self.wait(|cx| queue.poll_next(cx), Instant::now() + timeout) {
+      Some(completion) => completion,
+      None => {
+        queue.cancel_all();
+        let completion = block_on(|cx| queue.poll_next(cx), None).expect("waits without a deadline");
+        if completion.status.is_err() {
+          return Err(timed_out());
+        }
+        completion
+      }
+    };
+
+    completion.status.map_err(transfer_error)?;
+    let read = std::cmp::min(completion.data.len(), buf.len());
+    buf[..read].copy_from_slice(&completion.data[..read]);
+    Ok(read)
+  }
+
+  fn clear_halt(&self, endpoint: u8) -> Result<()> {
+    Ok(self.interface.clear_halt(endpoint)?)
+  }
+
+  fn cancel_transfers(&self) {
+    self.cancel.store(true, Ordering::Relaxed);
+    if let Some(thread) = &*self.waiting.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+      thread.unpark();
+    }
+  }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+  fn wake(self: Arc<Self>) {
+    self.0.unpark();
+  }
+}
+
+/// Drive a transfer to completion on the current thread, giving up at `deadline`
+fn block_on<T>(mut poll: impl FnMut(&mut Context<'_>) -> Poll<T>, deadline: Option<Instant>) -> Option<T> {
+  let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+  let mut cx = Context::from_waker(&waker);
+  loop {
+    if let Poll::Ready(value) = poll(&mut cx) {
+      return Some(value);
+    }
+    match deadline {
+      Some(deadline) => {
+        let now = Instant::now();
+        if now >= deadline {
+          return None;
+        }
+        thread::park_timeout(deadline - now);
+      }
+      None => thread::park(),
+    }
+  }
+}
+
+/// Report a vanished device as a lost session rather than a generic USB error
+fn transfer_error(err: TransferError) -> Error {
+  match err {
+    TransferError::Disconnected => Error::SessionLost("the device disconnected".into()),
+    err => err.into(),
+  }
+}
+
+fn timed_out() -> Error {
+  Error::IoError(std::io::Error::new(
+    std::io::ErrorKind::TimedOut,
+    "USB transfer timed out",
+  ))
+}
+
+/// Split a raw `bmRequestType` into the parts nusb expects; the direction is implied by the call
+fn control(request_type: u8, request: u8, value: u16, index: u16) -> Control {
+  let control_type = match (request_type >> 5) & 0x03 {
+    0 => ControlType::Standard,
+    1 => ControlType::Class,
+    _ => ControlType::Vendor,
+  };
+  let recipient = match request_type & 0x1f {
+    0 => Recipient::Device,
+    1 => Recipient::Interface,
+    2 => Recipient::Endpoint,
+    _ => Recipient::Other,
+  };
+  Control {
+    control_type,
+    recipient,
+    request,
+    value,
+    index,
+  }
+}
+
+pub(super) fn set_log_level(level: UsbLogLevel) -> Result<()> {
+  tracing::debug!("nusb has no log level of its own, ignoring {:?}", level);
+  Ok(())
+}
+
+pub(super) fn open(ids: &UsbIds) -> Result<(Box<dyn Transport>, DeviceInfo)> {
+  let device_info = nusb::list_devices()?
+    .find(|device| device.vendor_id() == ids.vendor_id && device.product_id() == ids.product_id)
+    .ok_or_else(|| Error::InvalidOperation("Device not found".into()))?;
+  let device = device_info.open()?;
+
+  // only switch configuration when needed; doing so while another interface of a
+  // composite device is bound to a driver fails with Busy
+  if device
+    .active_configuration()
+    .map(|config| config.configuration_value())
+    .ok()
+    != Some(1)
+  {
+    device.set_configuration(1)?;
+  }
+
+  let config = device.active_configuration().map_err(std::io::Error::from)?;
+  let candidates = config
+    .interface_alt_settings()
+    .flat_map(|descriptor| {
+      let interface_number = descriptor.interface_number();
+      let alt_setting = descriptor.alternate_setting();
+      descriptor
+        .endpoints()
+        .map(move |ep| EndpointCandidate {
+          interface_number,
+          alt_setting,
+          address: ep.address(),
+          is_in: ep.direction() == Direction::In,
+          is_bulk: ep.transfer_type() == EndpointType::Bulk,
+        })
+        .collect::<Vec<_>>()
+    })
+    .collect::<Vec<_>>();
+  tracing::trace!("endpoint candidates: {:?}", candidates);
+
+  let selection = select_endpoints(&candidates)
+    .ok_or_else(|| Error::InvalidOperation("no interface with both IN and OUT endpoints found".into()))?;
+  tracing::debug!("selected endpoints: {:?}", selection);
+
+  let interface_number = selection.interface_number;
+  let interface = device.claim_interface(interface_number)?;
+  if selection.alt_setting != 0 {
+    interface.set_alt_setting(selection.alt_setting)?;
+  }
+
+  let info = DeviceInfo {
+    vendor_id: device_info.vendor_id(),
+    product_id: device_info.product_id(),
+    bus_number: device_info.bus_number(),
+    address: device_info.device_address(),
+    serial: device_info
+      .serial_number()
+      .map(|serial| serial.trim().to_owned())
+      .filter(|serial| !serial.is_empty()),
+    interface_count: config.num_interfaces(),
+    interface_number,
+    alt_setting: selection.alt_setting,
+    endpoint_in: selection.endpoint_in,
+    endpoint_out: selection.endpoint_out,
+    bulk: selection.bulk,
+  };
+
+  let transport = NusbTransport {
+    interface,
+    cancel: AtomicBool::new(false),
+    waiting: Mutex::new(None),
+  };
+  Ok((Box::new(transport), info))
+}
+
+#[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+pub(super) fn find_device(ids: &UsbIds) -> DeviceMode {
+  let devices = match nusb::list_devices() {
+    Ok(d) => d,
+    Err(_) => return DeviceMode::NotFound,
+  };
+  for device in devices {
+    // Match normal mode, e.g. vendor=0x18d1, product=0x4e40 on superbird
+    if ids.normal_mode_ids == Some((device.vendor_id(), device.product_id())) {
+      tracing::debug!("Found device booted normally, with USB Gadget (adb/usbnet) enabled");
+      return DeviceMode::Normal;
+    }
+    // Match USB burn/usb mode, e.g. vendor=0x1b8e, product=0xc003 on superbird
+    if device.vendor_id() == ids.vendor_id && device.product_id() == ids.product_id {
+      // the OS caches the product string, so the device does not need to be opened
+      match device.product_string() {
+        Some(prod) if prod == ids.rom_product_string => {
+          tracing::debug!("Found device booted in USB Mode (buttons 1 & 4 held at boot)");
+          return DeviceMode::Usb;
+        }
+        Some(_) => {
+          tracing::debug!("Found device booted in USB Burn Mode (ready for commands)");
+          return DeviceMode::UsbBurn;
+        }
+        None => {
+          tracing::debug!("Found device in USB Burn Mode (unable to read product string)");
+          return DeviceMode::UsbBurn;
+        }
+      }
+    }
+  }
+
+  tracing::debug!("No device found!");
+  DeviceMode::NotFound
+}
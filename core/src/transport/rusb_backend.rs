@@ -0,0 +1,373 @@
+//! libusb backend, through `rusb`.
+//!
+//! Bulk transfers go through libusb's asynchronous API rather than `rusb`'s blocking calls, since
+//! a blocking libusb transfer can't be cancelled from another thread when it hangs.
+
+use std::{
+  ffi::{c_int, c_uint, c_void},
+  sync::{Mutex, MutexGuard, OnceLock},
+  time::Duration,
+};
+
+use rusb::{Context, DeviceHandle, Direction, LogLevel, TransferType, UsbContext, ffi};
+
+use super::{EndpointCandidate, Transport, UsbIds, UsbLogLevel, select_endpoints};
+use crate::{DeviceInfo, DeviceMode, Error, Result};
+
+const STRING_TIMEOUT: Duration = Duration::from_millis(100);
+const LANGUAGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+static USB_CONTEXT: OnceLock<Context> = OnceLock::new();
+
+#[derive(Debug)]
+struct RusbTransport {
+  handle: DeviceHandle<Context>,
+  interface_number: u8,
+  /// Bulk transfer in flight, for [`Transport::cancel_transfers`] to cancel
+  in_flight: Mutex<Option<InFlight>>,
+}
+
+/// A submitted libusb transfer, valid until the thread that submitted it clears it and frees it
+#[derive(Debug)]
+struct InFlight(*mut ffi::libusb_transfer);
+
+// the pointer is only used under the `in_flight` lock, and libusb transfers can be cancelled from any thread
+unsafe impl Send for InFlight {}
+
+impl RusbTransport {
+  fn in_flight(&self) -> MutexGuard<'_, Option<InFlight>> {
+    self.in_flight.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  /// Run a bulk transfer of `length` bytes at `buffer` on `endpoint` and wait for it to finish
+  ///
+  /// Like `rusb`'s blocking transfers, one that times out or is cancelled after moving some data
+  /// returns how much it moved.
+  ///
+  /// # Safety
+  /// `buffer` has to be valid for `length` bytes, and writable for IN endpoints, until this returns.
+  unsafe fn bulk(&self, endpoint: u8, buffer: *mut u8, length: usize, timeout: Duration) -> Result<usize> {
+    let length = c_int::try_from(length).map_err(|_| usb_error(rusb::Error::InvalidParam))?;
+    // SAFETY: the transfer and the completion flag are only freed once libusb has called back,
+    // after which it no longer touches either, and it is unregistered first so it can't be cancelled
+    unsafe {
+      let transfer = ffi::libusb_alloc_transfer(0);
+      if transfer.is_null() {
+        return Err(usb_error(rusb::Error::NoMem));
+      }
+      let completed = Box::into_raw(Box::new(0 as c_int));
+      ffi::libusb_fill_bulk_transfer(
+        transfer,
+        self.handle.as_raw(),
+        endpoint,
+        buffer,
+        length,
+        transfer_done,
+        completed as *mut c_void,
+        timeout.as_millis().min(c_uint::MAX as u128) as c_uint,
+      );
+      let submitted = ffi::libusb_submit_transfer(transfer);
+      if submitted < 0 {
+        ffi::libusb_free_transfer(transfer);
+        drop(Box::from_raw(completed));
+        return Err(usb_error(libusb_error(submitted)));
+      }
+
+      *self.in_flight() = Some(InFlight(transfer));
+      let context = self.handle.context().as_raw();
+      let mut cancelled = false;
+      // the flag is set by the callback, which libusb runs from inside the event handling call
+      while std::ptr::read_volatile(completed) == 0 {
+        let handled = ffi::libusb_handle_events_completed(context, completed);
+        if handled < 0 && handled != ffi::constants::LIBUSB_ERROR_INTERRUPTED && !cancelled {
+          // libusb owns the buffer until the transfer calls back, so it can't just be abandoned
+          tracing::warn!("failed to handle libusb events: {}", libusb_error(handled));
+          ffi::libusb_cancel_transfer(transfer);
+          cancelled = true;
+        }
+      }
+      *self.in_flight() = None;
+
+      let (status, actual) = ((*transfer).status, (*transfer).actual_length as usize);
+      ffi::libusb_free_transfer(transfer);
+      drop(Box::from_raw(completed));
+
+      use ffi::constants::*;
+      match status {
+        LIBUSB_TRANSFER_COMPLETED => Ok(actual),
+        LIBUSB_TRANSFER_TIMED_OUT | LIBUSB_TRANSFER_CANCELLED if actual > 0 => Ok(actual),
+        LIBUSB_TRANSFER_TIMED_OUT | LIBUSB_TRANSFER_CANCELLED => Err(usb_error(rusb::Error::Timeout)),
+        LIBUSB_TRANSFER_STALL => Err(usb_error(rusb::Error::Pipe)),
+        LIBUSB_TRANSFER_NO_DEVICE => Err(usb_error(rusb::Error::NoDevice)),
+        LIBUSB_TRANSFER_OVERFLOW => Err(usb_error(rusb::Error::Overflow)),
+        _ => Err(usb_error(rusb::Error::Io)),
+      }
+    }
+  }
+}
+
+/// Completion callback of bulk transfers, run by libusb from `libusb_handle_events_completed`
+extern "system" fn transfer_done(transfer: *mut ffi::libusb_transfer) {
+  // SAFETY: user_data is the completion flag `bulk` allocated, which outlives the transfer
+  unsafe { *((*transfer).user_data as *mut c_int) = 1 };
+}
+
+/// The `rusb` error for a libusb error code
+fn libusb_error(code: c_int) -> rusb::Error {
+  use ffi::constants::*;
+  match code {
+    LIBUSB_ERROR_IO => rusb::Error::Io,
+    LIBUSB_ERROR_INVALID_PARAM => rusb::Error::InvalidParam,
+    LIBUSB_ERROR_ACCESS => rusb::Error::Access,
+    LIBUSB_ERROR_NO_DEVICE => rusb::Error::NoDevice,
+    LIBUSB_ERROR_NOT_FOUND => rusb::Error::NotFound,
+    LIBUSB_ERROR_BUSY => rusb::Error::Busy,
+    LIBUSB_ERROR_TIMEOUT => rusb::Error::Timeout,
+    LIBUSB_ERROR_OVERFLOW => rusb::Error::Overflow,
+    LIBUSB_ERROR_PIPE => rusb::Error::Pipe,
+    LIBUSB_ERROR_INTERRUPTED => rusb::Error::Interrupted,
+    LIBUSB_ERROR_NO_MEM => rusb::Error::NoMem,
+    LIBUSB_ERROR_NOT_SUPPORTED => rusb::Error::NotSupported,
+    _ => rusb::Error::Other,
+  }
+}
+
+impl Transport for RusbTransport {
+  fn write_control(
+    &self,
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    data: &[u8],
+    timeout: Duration,
+  ) -> Result<usize> {
+    self
+      .handle
+      .write_control(request_type, request, value, index, data, timeout)
+      .map_err(usb_error)
+  }
+
+  fn read_control(
+    &self,
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    buf: &mut [u8],
+    timeout: Duration,
+  ) -> Result<usize> {
+    self
+      .handle
+      .read_control(request_type, request, value, index, buf, timeout)
+      .map_err(usb_error)
+  }
+
+  fn write_bulk(&self, endpoint: u8, data: &[u8], timeout: Duration) -> Result<usize> {
+    // SAFETY: libusb only reads from the buffer of an OUT transfer
+    unsafe { self.bulk(endpoint, data.as_ptr() as *mut u8, data.len(), timeout) }
+  }
+
+  fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> Result<usize> {
+    // SAFETY: `buf` is borrowed mutably until the transfer has finished
+    unsafe { self.bulk(endpoint, buf.as_mut_ptr(), buf.len(), timeout) }
+  }
+
+  fn clear_halt(&self, endpoint: u8) -> Result<()> {
+    self.handle.clear_halt(endpoint).map_err(usb_error)
+  }
+
+  fn cancel_transfers(&self) {
+    if let Some(InFlight(transfer)) = &*self.in_flight() {
+      // SAFETY: the transfer stays allocated while it is registered, and the lock is held
+      let cancelled = unsafe { ffi::libusb_cancel_transfer(*transfer) };
+      if cancelled < 0 {
+        tracing::debug!("could not cancel the transfer in flight: {}", libusb_error(cancelled));
+      }
+    }
+  }
+}
+
+/// Report a vanished device as a lost session rather than a generic USB error
+fn usb_error(err: rusb::Error) -> Error {
+  match err {
+    rusb::Error::NoDevice => Error::SessionLost("the device disconnected".into()),
+    err => err.into(),
+  }
+}
+
+impl Drop for RusbTransport {
+  fn drop(&mut self) {
+    match self.handle.release_interface(self.interface_number) {
+      Ok(()) => tracing::trace!("successfully dropped usb interface"),
+      Err(err) => tracing::warn!("failed to release usb interface: {:?}", err),
+    }
+  }
+}
+
+/// Get the libusb context shared by device detection and connection
+///
+/// Creating a context enumerates every device on the system, so it is done once per process.
+fn usb_context() -> Result<Context> {
+  if let Some(context) = USB_CONTEXT.get() {
+    return Ok(context.clone());
+  }
+
+  let context = Context::new()?;
+  Ok(USB_CONTEXT.get_or_init(|| context).clone())
+}
+
+pub(super) fn set_log_level(level: UsbLogLevel) -> Result<()> {
+  let level = match level {
+    UsbLogLevel::None => LogLevel::None,
+    UsbLogLevel::Error => LogLevel::Error,
+    UsbLogLevel::Warning => LogLevel::Warning,
+    UsbLogLevel::Info => LogLevel::Info,
+    UsbLogLevel::Debug => LogLevel::Debug,
+  };
+  usb_context()?.set_log_level(level);
+  Ok(())
+}
+
+pub(super) fn open(ids: &UsbIds) -> Result<(Box<dyn Transport>, DeviceInfo)> {
+  let context = usb_context()?;
+  let handle = {
+    let device = context
+      .devices()?
+      .iter()
+      .find(|device| {
+        if let Ok(desc) = device.device_descriptor() {
+          desc.vendor_id() == ids.vendor_id && desc.product_id() == ids.product_id
+        } else {
+          false
+        }
+      })
+      .ok_or_else(|| Error::InvalidOperation("Device not found".into()))?;
+    device.open()?
+  };
+
+  // only switch configuration when needed; doing so while another interface of a
+  // composite device is bound to a driver fails with Busy
+  if handle.active_configuration()? != 1 {
+    handle.set_active_configuration(1)?;
+  }
+
+  let device = handle.device();
+  let config_desc = device.active_config_descriptor()?;
+  let candidates = config_desc
+    .interfaces()
+    .flat_map(|interface| interface.descriptors())
+    .flat_map(|descriptor| {
+      let interface_number = descriptor.interface_number();
+      let alt_setting = descriptor.setting_number();
+      descriptor
+        .endpoint_descriptors()
+        .map(move |ep| EndpointCandidate {
+          interface_number,
+          alt_setting,
+          address: ep.address(),
+          is_in: ep.direction() == Direction::In,
+          is_bulk: ep.transfer_type() == TransferType::Bulk,
+        })
+        .collect::<Vec<_>>()
+    })
+    .collect::<Vec<_>>();
+  tracing::trace!("endpoint candidates: {:?}", candidates);
+
+  let selection = select_endpoints(&candidates)
+    .ok_or_else(|| Error::InvalidOperation("no interface with both IN and OUT endpoints found".into()))?;
+  tracing::debug!("selected endpoints: {:?}", selection);
+
+  let interface_number = selection.interface_number;
+  handle.claim_interface(interface_number)?;
+  if selection.alt_setting != 0 {
+    handle.set_alternate_setting(interface_number, selection.alt_setting)?;
+  }
+
+  let desc = device.device_descriptor()?;
+  let info = DeviceInfo {
+    vendor_id: desc.vendor_id(),
+    product_id: desc.product_id(),
+    bus_number: device.bus_number(),
+    address: device.address(),
+    serial: read_serial(&handle),
+    interface_count: config_desc.num_interfaces(),
+    interface_number,
+    alt_setting: selection.alt_setting,
+    endpoint_in: selection.endpoint_in,
+    endpoint_out: selection.endpoint_out,
+    bulk: selection.bulk,
+  };
+
+  Ok((
+    Box::new(RusbTransport {
+      handle,
+      interface_number,
+      in_flight: Mutex::new(None),
+    }),
+    info,
+  ))
+}
+
+fn read_serial(handle: &DeviceHandle<Context>) -> Option<String> {
+  let desc = handle.device().device_descriptor().ok()?;
+  let lang = *handle.read_languages(LANGUAGE_TIMEOUT).ok()?.first()?;
+  handle
+    .read_serial_number_string(lang, &desc, STRING_TIMEOUT)
+    .ok()
+    .map(|serial| serial.trim().to_owned())
+    .filter(|serial| !serial.is_empty())
+}
+
+#[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+pub(super) fn find_device(ids: &UsbIds) -> DeviceMode {
+  let context = match usb_context() {
+    Ok(c) => c,
+    Err(_) => return DeviceMode::NotFound,
+  };
+  let devices = match context.devices() {
+    Ok(d) => d,
+    Err(_) => return DeviceMode::NotFound,
+  };
+  for device in devices.iter() {
+    let desc = match device.device_descriptor() {
+      Ok(d) => d,
+      Err(_) => continue,
+    };
+    // Match normal mode, e.g. vendor=0x18d1, product=0x4e40 on superbird
+    if ids.normal_mode_ids == Some((desc.vendor_id(), desc.product_id())) {
+      tracing::debug!("Found device booted normally, with USB Gadget (adb/usbnet) enabled");
+      return DeviceMode::Normal;
+    }
+    // Match USB burn/usb mode, e.g. vendor=0x1b8e, product=0xc003 on superbird
+    if desc.vendor_id() == ids.vendor_id && desc.product_id() == ids.product_id {
+      // Attempt to open device and read product string
+      match device.open() {
+        Ok(handle) => {
+          // Common language ID
+          let lang = handle.read_languages(LANGUAGE_TIMEOUT).unwrap_or_default();
+          let Some(lang) = lang.first() else {
+            tracing::debug!("Found device in USB Burn Mode (unable to read product string)");
+            return DeviceMode::UsbBurn;
+          };
+
+          let prod = handle.read_product_string(*lang, &desc, STRING_TIMEOUT).ok();
+          if prod.as_deref() == Some(ids.rom_product_string.as_ref()) {
+            tracing::debug!("Found device booted in USB Mode (buttons 1 & 4 held at boot)");
+            return DeviceMode::Usb;
+          } else {
+            tracing::debug!("Found device booted in USB Burn Mode (ready for commands)");
+            return DeviceMode::UsbBurn;
+          }
+        }
+        Err(_) => {
+          tracing::debug!("Found device in USB Burn Mode (unable to read product string)");
+          return DeviceMode::UsbBurn;
+        }
+      }
+    }
+  }
+
+  tracing::debug!("No device found!");
+  DeviceMode::NotFound
+}
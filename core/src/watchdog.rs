@@ -0,0 +1,143 @@
+//! Watching bulk transfers for ones that hang without ever failing, a known libusb failure mode, so
+//! they can be cancelled and retried instead of holding up the flash forever.
+
+use std::{
+  sync::{Arc, Condvar, Mutex, MutexGuard, Weak},
+  thread,
+  time::{Duration, Instant},
+};
+
+use crate::transport::Transport;
+
+/// Called with the endpoint of a transfer the watchdog cancelled and how long it went without finishing
+pub(crate) type StallHandler = Arc<dyn Fn(u8, Duration) + Send + Sync>;
+
+#[derive(Default)]
+struct WatchState {
+  /// How long a transfer may take before it counts as hung, or `None` while the watchdog is off
+  period: Option<Duration>,
+  on_stall: Option<StallHandler>,
+  /// Endpoint and start of the transfer in flight
+  in_flight: Option<(u8, Instant)>,
+  /// Set once the transfer in flight was cancelled for hanging
+  fired: bool,
+  /// Whether the watchdog thread has been started
+  running: bool,
+  /// Set when the session is dropped, which ends the watchdog thread
+  stopped: bool,
+}
+
+/// Cancels the transfer in flight once it has taken longer than the period
+#[derive(Default)]
+pub(crate) struct Watchdog {
+  state: Mutex<WatchState>,
+  changed: Condvar,
+}
+
+impl std::fmt::Debug for Watchdog {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Watchdog")
+      .field("period", &self.state().period)
+      .finish()
+  }
+}
+
+impl Watchdog {
+  fn state(&self) -> MutexGuard<'_, WatchState> {
+    self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  /// Watch transfers on `transport` for ones taking longer than `period`, or stop watching with `None`
+  pub(crate) fn configure(
+    self: &Arc<Self>,
+    period: Option<Duration>,
+    on_stall: Option<StallHandler>,
+    transport: Weak<dyn Transport>,
+  ) {
+    let mut state = self.state();
+    state.period = period;
+    state.on_stall = on_stall;
+    if period.is_some() && !state.running {
+      state.running = true;
+      let watchdog = self.clone();
+      if let Err(err) = thread::Builder::new()
+        .name("flashthing-watchdog".into())
+        .spawn(move || watchdog.run(transport))
+      {
+        tracing::warn!("failed to start the transfer watchdog: {}", err);
+        state.running = false;
+      }
+    }
+    self.changed.notify_all();
+  }
+
+  /// Run `transfer` on `endpoint` under watch, returning its result and whether it was cancelled for hanging
+  ///
+  /// A cancelled transfer may still have completed, so its result is returned as is.
+  pub(crate) fn guard<T>(&self, endpoint: u8, transfer: impl FnOnce() -> T) -> (T, bool) {
+    {
+      let mut state = self.state();
+      if state.period.is_none() {
+        drop(state);
+        return (transfer(), false);
+      }
+      state.in_flight = Some((endpoint, Instant::now()));
+      state.fired = false;
+      self.changed.notify_all();
+    }
+
+    let result = transfer();
+    let mut state = self.state();
+    state.in_flight = None;
+    (result, std::mem::take(&mut state.fired))
+  }
+
+  /// End the watchdog thread
+  pub(crate) fn stop(&self) {
+    self.state().stopped = true;
+    self.changed.notify_all();
+  }
+
+  fn run(&self, transport: Weak<dyn Transport>) {
+    let mut state = self.state();
+    loop {
+      if state.stopped {
+        return;
+      }
+      state = match (state.period, state.in_flight) {
+        (Some(period), Some((endpoint, since))) if !state.fired => {
+          let idle = since.elapsed();
+          if idle < period {
+            self
+              .changed
+              .wait_timeout(state, period - idle)
+              .unwrap_or_else(|poisoned| poisoned.into_inner())
+              .0
+          } else {
+            state.fired = true;
+            let on_stall = state.on_stall.clone();
+            drop(state);
+
+            tracing::warn!(
+              "transfer on endpoint {:#04x} made no progress for {:?}, cancelling it",
+              endpoint,
+              idle
+            );
+            match transport.upgrade() {
+              Some(transport) => transport.cancel_transfers(),
+              None => return,
+            }
+            if let Some(on_stall) = on_stall {
+              on_stall(endpoint, idle);
+            }
+            self.state()
+          }
+        }
+        _ => self
+          .changed
+          .wait(state)
+          .unwrap_or_else(|poisoned| poisoned.into_inner()),
+      };
+    }
+  }
+}
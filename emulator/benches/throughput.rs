@@ -0,0 +1,117 @@
+//! Benchmarks of the host side of flashing, run against the emulator so changes to chunking,
+//! framing or the step engine can be compared with `cargo bench -p flashthing-emulator`.
+//!
+//! The emulator answers instantly, so these measure flashthing's own overhead rather than USB.
+//! `flashthing-cli --bench-transport` measures the real thing on a device.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::{hint::black_box, io::Cursor};
+
+use common::{package, pattern};
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+use flashthing::{AmlogicSoC, DeviceProfile, Flasher, ThermalPolicy};
+use flashthing_core::{amlc_checksum, amls_block};
+use flashthing_emulator::Emulator;
+
+const MIB: usize = 1024 * 1024;
+
+fn connect(emulator: &Emulator) -> AmlogicSoC {
+  let profile = DeviceProfile {
+    thermal: ThermalPolicy::disabled(),
+    ..DeviceProfile::default()
+  };
+  AmlogicSoC::init_with_target(None, profile, emulator.target()).unwrap()
+}
+
+/// Splitting data into bulk transfers, to memory and through staging to the eMMC
+fn chunking(c: &mut Criterion) {
+  let emulator = Emulator::new().unwrap();
+  let aml = connect(&emulator);
+  let address = aml.profile().staging_address;
+  let data = pattern(8 * MIB);
+
+  let mut group = c.benchmark_group("chunking");
+  group.throughput(Throughput::Bytes(data.len() as u64));
+  for block_length in [4096, 16384] {
+    group.bench_function(format!("write_large_memory/{block_length}"), |b| {
+      b.iter(|| {
+        aml
+          .write_large_memory(address, black_box(&data), block_length, false)
+          .unwrap()
+      })
+    });
+  }
+  group.bench_function("read_large_memory/4096", |b| {
+    b.iter(|| aml.read_large_memory(address, data.len(), 4096).unwrap())
+  });
+
+  let disk = pattern(32 * MIB);
+  group.throughput(Throughput::Bytes(disk.len() as u64));
+  group.sample_size(10);
+  for compare_before_write in [false, true] {
+    group.bench_function(
+      format!("write_large_memory_to_disk/compare={compare_before_write}"),
+      |b| {
+        b.iter(|| {
+          aml
+            .write_large_memory_to_disk(
+              0,
+              &mut Cursor::new(&disk),
+              disk.len(),
+              4096,
+              false,
+              compare_before_write,
+              |_| {},
+            )
+            .unwrap()
+        })
+      },
+    );
+  }
+  group.finish();
+}
+
+/// Checksumming and framing the packets BL2 asks for over AMLC
+fn amlc(c: &mut Criterion) {
+  let packet = pattern(64 * 1024);
+
+  let mut group = c.benchmark_group("amlc");
+  group.throughput(Throughput::Bytes(packet.len() as u64));
+  group.bench_function("checksum", |b| b.iter(|| amlc_checksum(black_box(&packet))));
+  group.bench_function("amls_block", |b| b.iter(|| amls_block(0, black_box(&packet))));
+  group.finish();
+}
+
+/// What the step engine adds on top of the commands it sends: parsing, planning, variable
+/// substitution and reporting
+fn step_engine(c: &mut Criterion) {
+  let steps = (0..100)
+    .map(|i| format!(r#"{{ "type": "bulkcmd", "value": "setenv bench_{i} ${{flashthing_version}}" }}"#))
+    .collect::<Vec<_>>()
+    .join(", ");
+  let dir = package("bench-steps", &format!("[{steps}]"), &[]);
+  let emulator = Emulator::new().unwrap();
+
+  let mut group = c.benchmark_group("step_engine");
+  group.throughput(Throughput::Elements(100));
+  group.bench_function("bulkcmd_steps", |b| {
+    b.iter_batched(
+      || {
+        Flasher::builder()
+          .target(emulator.target())
+          .thermal_policy(ThermalPolicy::disabled())
+          .from_directory(dir.clone())
+          .unwrap()
+      },
+      |mut flasher| flasher.flash().unwrap(),
+      BatchSize::PerIteration,
+    )
+  });
+  group.finish();
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+criterion_group!(benches, chunking, amlc, step_engine);
+criterion_main!(benches);
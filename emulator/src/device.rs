@@ -0,0 +1,846 @@
+//! The device side of the burn protocol: the BootROM, BL2 asking for the bootloader over AMLC, and
+//! U-Boot in USB burn mode.
+
+use std::{
+  collections::BTreeMap,
+  io,
+  sync::{Arc, Condvar, Mutex, MutexGuard},
+  time::Duration,
+};
+
+use flashthing::{DeviceInfo, DeviceMode, DeviceProfile, Error, Result, Transport};
+
+use crate::{emmc::Emmc, fault::Fault};
+
+// requests, as the device sees them
+const REQ_WRITE_MEM: u8 = 0x01;
+const REQ_READ_MEM: u8 = 0x02;
+const REQ_RUN_IN_ADDR: u8 = 0x05;
+const REQ_WR_LARGE_MEM: u8 = 0x11;
+const REQ_RD_LARGE_MEM: u8 = 0x12;
+const REQ_IDENTIFY_HOST: u8 = 0x20;
+const REQ_BULKCMD: u8 = 0x34;
+const REQ_GET_AMLC: u8 = 0x50;
+const REQ_WRITE_AMLC: u8 = 0x60;
+
+const ENDPOINT_IN: u8 = 0x81;
+const ENDPOINT_OUT: u8 = 0x01;
+const SECTOR_SIZE: usize = 512;
+/// Size of the AMLC request and AMLS blocks
+const AMLC_BLOCK_LENGTH: usize = 512;
+/// Largest bulk transfer BL2 accepts while receiving AMLC data
+const AMLC_MAX_BLOCK_LENGTH: usize = 0x4000;
+/// Amount of bootloader BL2 asks for in each AMLC request
+const AMLC_REQUEST_LENGTH: u32 = 0x40000;
+/// Largest single control transfer of simple memory
+const MAX_SIMPLE_MEMORY: usize = 64;
+/// Efuse-backed security config register and its secure boot bit
+const AO_SEC_SD_CFG10: u32 = 0xff800228;
+const SECURE_BOOT_BIT: u32 = 1 << 4;
+/// Size of the simulated memory pages, which are allocated as they are written
+const PAGE_SIZE: usize = 64 * 1024;
+/// Longest a hung transfer waits to be cancelled, so a host that never does fails instead of hanging the test
+const HANG_LIMIT: Duration = Duration::from_secs(60);
+
+/// What the device is running
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum State {
+  /// The BootROM's USB mode
+  Rom,
+  /// BL2, asking for the bootloader over AMLC
+  Bl2(Bl2),
+  /// U-Boot in USB burn mode
+  Burn,
+  /// The regular firmware, which doesn't speak the burn protocol
+  Normal,
+  /// Stuck after running code that never answers, such as a BL2 it could not verify
+  Hung,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Bl2 {
+  /// Bootloader received so far
+  received: Vec<u8>,
+  /// The current request, as `(length, offset)`
+  request: Option<(u32, u32)>,
+  /// Data of the packet being sent for the current request
+  packet: Vec<u8>,
+  /// Sequence number the next AMLS block must carry
+  seq: u8,
+  /// Set once the whole bootloader has arrived, so the last request is repeated to end the transfer
+  complete: bool,
+  /// Set once the last request has been repeated, so the bootloader starts when it is acknowledged
+  booting: bool,
+}
+
+/// What the device expects next on the bulk endpoints
+#[derive(Debug)]
+enum Pending {
+  Idle,
+  /// `remaining` bytes of a large memory write in blocks of `block_length`, stored from `address`
+  Write {
+    address: u32,
+    remaining: usize,
+    block_length: usize,
+  },
+  /// `remaining` bytes of a large memory read in blocks of `block_length`, starting at `address`
+  Read {
+    address: u32,
+    remaining: usize,
+    block_length: usize,
+  },
+  /// A response to return on the next bulk read
+  Response(Vec<u8>),
+  /// AMLC data for `offset` in the current packet, acknowledged once all `length` bytes arrived
+  Amlc {
+    offset: usize,
+    length: usize,
+    data: Vec<u8>,
+  },
+}
+
+pub(crate) struct Device {
+  pub(crate) profile: DeviceProfile,
+  pub(crate) state: State,
+  /// Bumped whenever the device drops off the bus, which invalidates open transports
+  session: u64,
+  pub(crate) emmc: Emmc,
+  ram: BTreeMap<u32, Box<[u8]>>,
+  pending: Pending,
+  pub(crate) env: BTreeMap<String, String>,
+  pub(crate) commands: Vec<String>,
+  pub(crate) connections: usize,
+  /// The bootloader BL2 received over AMLC on the last boot
+  pub(crate) bootloader: Option<Vec<u8>>,
+  pub(crate) temperature: f64,
+  /// Serial number reported over USB
+  pub(crate) serial: Option<String>,
+  /// Faults that have yet to be injected
+  pub(crate) faults: Vec<Fault>,
+  /// Bulk OUT transfers received so far
+  bulk_writes: usize,
+  /// Whether the OUT endpoint is halted until the host clears it
+  out_halted: bool,
+  /// Whether the regular firmware keeps crashing, and if so, whether it is off the bus right now
+  boot_loop: Option<bool>,
+}
+
+impl Device {
+  pub(crate) fn new(profile: DeviceProfile, state: State, secure_boot: bool, faults: Vec<Fault>) -> Result<Self> {
+    let capacity = profile.partitions.iter().map(|part| part.end()).max().unwrap_or(0) * SECTOR_SIZE;
+    let mut device = Self {
+      emmc: Emmc::new(capacity as u64)?,
+      profile,
+      state,
+      session: 0,
+      ram: BTreeMap::new(),
+      pending: Pending::Idle,
+      env: BTreeMap::new(),
+      commands: Vec::new(),
+      connections: 0,
+      bootloader: None,
+      temperature: 45.0,
+      serial: None,
+      faults,
+      bulk_writes: 0,
+      out_halted: false,
+      boot_loop: None,
+    };
+    let efuse = if secure_boot { SECURE_BOOT_BIT } else { 0 };
+    device.write_ram(AO_SEC_SD_CFG10, &efuse.to_le_bytes());
+    Ok(device)
+  }
+
+  pub(crate) fn mode(&self) -> DeviceMode {
+    match self.state {
+      State::Rom | State::Bl2(_) | State::Hung => DeviceMode::Usb,
+      State::Burn => DeviceMode::UsbBurn,
+      State::Normal => DeviceMode::Normal,
+    }
+  }
+
+  /// The mode the host finds the device in, which for a device in a boot loop changes each time
+  pub(crate) fn find(&mut self) -> DeviceMode {
+    match &mut self.boot_loop {
+      Some(off_bus) if matches!(self.state, State::Normal) => {
+        *off_bus = !*off_bus;
+        if *off_bus {
+          return DeviceMode::NotFound;
+        }
+        DeviceMode::Normal
+      }
+      _ => self.mode(),
+    }
+  }
+
+  /// Re-enumerate as `state`, ending the current USB session
+  pub(crate) fn reenumerate(&mut self, state: State) {
+    tracing::debug!("emulator: re-enumerating as {:?}", state);
+    if !matches!(state, State::Normal) {
+      self.boot_loop = None;
+    } else if self.take_fault(|fault| matches!(fault, Fault::BootLoop)).is_some() {
+      self.boot_loop = Some(false);
+    }
+    self.state = state;
+    self.session += 1;
+    self.pending = Pending::Idle;
+  }
+
+  pub(crate) fn info(&self) -> DeviceInfo {
+    let (vendor_id, product_id) = match (&self.state, self.profile.normal_mode_ids) {
+      (State::Normal, Some(ids)) => ids,
+      _ => (self.profile.vendor_id, self.profile.product_id),
+    };
+    DeviceInfo {
+      vendor_id,
+      product_id,
+      bus_number: 1,
+      address: 1,
+      serial: self.serial.clone(),
+      interface_count: 1,
+      interface_number: 0,
+      alt_setting: 0,
+      endpoint_in: ENDPOINT_IN,
+      endpoint_out: ENDPOINT_OUT,
+      bulk: true,
+    }
+  }
+
+  fn write_ram(&mut self, address: u32, data: &[u8]) {
+    let mut written = 0;
+    while written < data.len() {
+      let at = address as usize + written;
+      let (page, offset) = ((at / PAGE_SIZE * PAGE_SIZE) as u32, at % PAGE_SIZE);
+      let length = (PAGE_SIZE - offset).min(data.len() - written);
+      let page = self
+        .ram
+        .entry(page)
+        .or_insert_with(|| vec![0; PAGE_SIZE].into_boxed_slice());
+      page[offset..offset + length].copy_from_slice(&data[written..written + length]);
+      written += length;
+    }
+  }
+
+  fn read_ram(&self, address: u32, buf: &mut [u8]) {
+    let mut read = 0;
+    while read < buf.len() {
+      let at = address as usize + read;
+      let (page, offset) = ((at / PAGE_SIZE * PAGE_SIZE) as u32, at % PAGE_SIZE);
+      let length = (PAGE_SIZE - offset).min(buf.len() - read);
+      match self.ram.get(&page) {
+        Some(page) => buf[read..read + length].copy_from_slice(&page[offset..offset + length]),
+        None => buf[read..read + length].fill(0),
+      }
+      read += length;
+    }
+  }
+
+  fn control_out(&mut self, request: u8, value: u16, index: u16, data: &[u8]) -> Result<()> {
+    let address = (value as u32) << 16 | index as u32;
+    match (&self.state, request) {
+      (State::Rom | State::Burn, REQ_WRITE_MEM) => {
+        if data.len() > MAX_SIMPLE_MEMORY {
+          return Err(stall(format!(
+            "{} bytes is too much for a simple memory write",
+            data.len()
+          )));
+        }
+        self.write_ram(address, data);
+      }
+      (State::Rom | State::Burn, REQ_WR_LARGE_MEM | REQ_RD_LARGE_MEM) => {
+        let header = |at: usize| {
+          data
+            .get(at..at + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+        let (Some(address), Some(length)) = (header(0), header(4)) else {
+          return Err(stall("large memory request without its 16 byte header".into()));
+        };
+        let (block_length, length) = (value as usize, length as usize);
+        if block_length == 0 || block_length * index as usize != length {
+          return Err(stall(format!(
+            "{index} blocks of {block_length} bytes do not add up to {length} bytes"
+          )));
+        }
+        self.pending = match request {
+          REQ_WR_LARGE_MEM => Pending::Write {
+            address,
+            remaining: length,
+            block_length,
+          },
+          _ => Pending::Read {
+            address,
+            remaining: length,
+            block_length,
+          },
+        };
+      }
+      (State::Rom | State::Burn, REQ_RUN_IN_ADDR) => self.run(address),
+      (State::Burn, REQ_BULKCMD) => {
+        let Some(end) = data.iter().position(|&b| b == 0) else {
+          return Err(stall("bulkcmd is not NUL-terminated".into()));
+        };
+        let command = String::from_utf8_lossy(&data[..end]).into_owned();
+        self.commands.push(command.clone());
+        let session = self.session;
+        if let Some(Fault::SlowBulkcmd { delay, .. }) = self.take_fault(
+          |fault| matches!(fault, Fault::SlowBulkcmd { command: prefix, .. } if command.starts_with(prefix.as_str())),
+        ) {
+          tracing::debug!("emulator: taking {:?} over {:?}", delay, command);
+          std::thread::sleep(delay);
+        }
+        let result = match self.bulkcmd_fault(&command) {
+          true => Err(Error::InvalidOperation("injected fault".into())),
+          false => self.bulkcmd(&command),
+        };
+        let response = match result {
+          Ok(response) => response,
+          Err(e) => {
+            tracing::debug!("emulator: {:?} failed: {}", command, e);
+            format!("failed: {e}")
+          }
+        };
+
+        // commands that take the device off the bus never answer
+        if self.session == session {
+          self.pending = Pending::Response(response.into_bytes());
+        }
+      }
+      (State::Bl2(bl2), REQ_GET_AMLC) => {
+        if value as usize != AMLC_BLOCK_LENGTH {
+          return Err(stall(format!("AMLC request of {value} bytes")));
+        }
+        let mut bl2 = bl2.clone();
+        let (length, offset) = match (bl2.complete, bl2.request) {
+          (true, Some(request)) => request,
+          (_, Some((length, offset))) => (AMLC_REQUEST_LENGTH, offset + length),
+          (_, None) => (AMLC_REQUEST_LENGTH, 0),
+        };
+        self.pending = Pending::Response(amlc_request(length, offset));
+
+        // the host stops once it sees the same request twice, and hands over once it acknowledges it
+        bl2.booting = bl2.complete;
+        bl2.request = Some((length, offset));
+        bl2.packet.clear();
+        self.state = State::Bl2(bl2);
+      }
+      (State::Bl2(_), REQ_WRITE_AMLC) => {
+        let length = index as usize + 1;
+        self.pending = Pending::Amlc {
+          offset: value as usize * AMLC_BLOCK_LENGTH,
+          length,
+          data: Vec::with_capacity(length),
+        };
+      }
+      (state, request) => return Err(stall(format!("{state:?} does not accept request {request:#04x}"))),
+    }
+    Ok(())
+  }
+
+  fn control_in(&mut self, request: u8, value: u16, index: u16, buf: &mut [u8]) -> Result<usize> {
+    match (&self.state, request) {
+      (State::Rom | State::Burn, REQ_IDENTIFY_HOST) => {
+        let identify: [u8; 8] = match self.state {
+          State::Rom => [0, 9, 2, 0, 0, 0, 0, 0],
+          _ => [0, 7, 0, 16, 0, 0, 0, 0],
+        };
+        let length = buf.len().min(identify.len());
+        buf[..length].copy_from_slice(&identify[..length]);
+        Ok(length)
+      }
+      (State::Rom | State::Burn, REQ_READ_MEM) => {
+        if buf.len() > MAX_SIMPLE_MEMORY {
+          return Err(stall(format!(
+            "{} bytes is too much for a simple memory read",
+            buf.len()
+          )));
+        }
+        self.read_ram((value as u32) << 16 | index as u32, buf);
+        Ok(buf.len())
+      }
+      (state, request) => Err(stall(format!("{state:?} does not accept request {request:#04x}"))),
+    }
+  }
+
+  fn bulk_out(&mut self, data: &[u8]) -> Result<usize> {
+    if self.out_halted {
+      return Err(stall("the endpoint is halted".into()));
+    }
+
+    self.bulk_writes += 1;
+    let nth = self.bulk_writes;
+    if self
+      .take_fault(|fault| matches!(fault, Fault::BulkWriteStall { nth: at } if *at == nth))
+      .is_some()
+    {
+      tracing::debug!("emulator: injecting a stall on bulk write {}", nth);
+      self.out_halted = true;
+      return Err(stall("injected fault".into()));
+    }
+    if self
+      .take_fault(|fault| matches!(fault, Fault::BulkWriteTimeout { nth: at } if *at == nth))
+      .is_some()
+    {
+      tracing::debug!("emulator: injecting a timeout on bulk write {}", nth);
+      self.pending = Pending::Idle;
+      return Err(timeout("injected fault"));
+    }
+
+    match std::mem::replace(&mut self.pending, Pending::Idle) {
+      Pending::Write {
+        address,
+        remaining,
+        block_length,
+      } => {
+        if data.len() != block_length {
+          return Err(stall(format!(
+            "expected a {block_length} byte block, got {}",
+            data.len()
+          )));
+        }
+        self.write_ram(address, data);
+        if remaining > block_length {
+          self.pending = Pending::Write {
+            address: address + block_length as u32,
+            remaining: remaining - block_length,
+            block_length,
+          };
+        }
+        Ok(data.len())
+      }
+      Pending::Amlc {
+        offset,
+        length,
+        data: mut received,
+      } => {
+        if data.len() > AMLC_MAX_BLOCK_LENGTH || received.len() + data.len() > length {
+          return Err(stall(format!("unexpected {} byte AMLC transfer", data.len())));
+        }
+        received.extend_from_slice(data);
+        self.pending = match received.len() == length {
+          true => self.amlc_received(offset, received),
+          false => Pending::Amlc {
+            offset,
+            length,
+            data: received,
+          },
+        };
+        Ok(data.len())
+      }
+      // the host acknowledges AMLC requests, which needs no answer
+      Pending::Idle if data.starts_with(b"OKAY") && matches!(self.state, State::Bl2(_)) => {
+        if let State::Bl2(bl2) = &mut self.state
+          && bl2.booting
+        {
+          tracing::debug!("emulator: bootloader received, starting U-Boot");
+          self.bootloader = Some(std::mem::take(&mut bl2.received));
+          self.reenumerate(State::Burn);
+        }
+        Ok(data.len())
+      }
+      pending => {
+        self.pending = pending;
+        Err(timeout("the device is not expecting bulk data"))
+      }
+    }
+  }
+
+  fn bulk_in(&mut self, buf: &mut [u8]) -> Result<usize> {
+    match std::mem::replace(&mut self.pending, Pending::Idle) {
+      Pending::Read {
+        address,
+        remaining,
+        block_length,
+      } => {
+        if buf.len() < block_length {
+          return Err(stall(format!(
+            "a {block_length} byte block does not fit in {} bytes",
+            buf.len()
+          )));
+        }
+        self.read_ram(address, &mut buf[..block_length]);
+        if remaining > block_length {
+          self.pending = Pending::Read {
+            address: address + block_length as u32,
+            remaining: remaining - block_length,
+            block_length,
+          };
+        }
+        Ok(block_length)
+      }
+      Pending::Response(response) => {
+        let length = buf.len().min(response.len());
+        buf[..length].copy_from_slice(&response[..length]);
+        Ok(length)
+      }
+      pending => {
+        self.pending = pending;
+        Err(timeout("the device has nothing to send"))
+      }
+    }
+  }
+
+  /// Start running the code at `address`
+  fn run(&mut self, address: u32) {
+    let bl2 = self.profile.bl2.as_deref();
+    let verified = match bl2 {
+      Some(bl2) => {
+        let mut loaded = vec![0; bl2.len()];
+        self.read_ram(address, &mut loaded);
+        loaded == bl2
+      }
+      None => true,
+    };
+
+    self.pending = Pending::Idle;
+    self.state = if self.state == State::Rom && address == self.profile.bl2_address && verified {
+      tracing::debug!("emulator: BL2 started");
+      State::Bl2(Bl2::default())
+    } else {
+      tracing::debug!("emulator: jumped to {:#X}, which never answers", address);
+      State::Hung
+    };
+  }
+
+  /// Handle a whole AMLC transfer, returning the acknowledgement
+  fn amlc_received(&mut self, offset: usize, data: Vec<u8>) -> Pending {
+    let State::Bl2(bl2) = &mut self.state else {
+      return Pending::Idle;
+    };
+    let Some((length, request_offset)) = bl2.request else {
+      return Pending::Idle;
+    };
+
+    if !(data.len() == AMLC_BLOCK_LENGTH && data.starts_with(b"AMLS")) {
+      if bl2.packet.len() < offset + data.len() {
+        bl2.packet.resize(offset + data.len(), 0);
+      }
+      bl2.packet[offset..offset + data.len()].copy_from_slice(&data);
+      return Pending::Response(okay());
+    }
+
+    let checksum = u32::from_le_bytes(data[8..12].try_into().unwrap());
+    if data[4] != bl2.seq || offset != request_offset as usize || checksum != amlc_checksum(&bl2.packet) {
+      tracing::debug!("emulator: AMLS block for {:#x} does not match what was sent", offset);
+      self.state = State::Hung;
+      return Pending::Response(b"FAIL".to_vec());
+    }
+
+    let packet = std::mem::take(&mut bl2.packet);
+    bl2.complete = packet.len() < length as usize;
+    bl2.received.extend_from_slice(&packet);
+    bl2.seq = bl2.seq.wrapping_add(1);
+    Pending::Response(okay())
+  }
+
+  /// Run a U-Boot command, returning its response
+  fn bulkcmd(&mut self, command: &str) -> Result<String> {
+    if self.profile.thermal.temperature_command.as_deref() == Some(command) {
+      return Ok(format!("success temp: {:.1}C", self.temperature));
+    }
+
+    let words = command.split_whitespace().collect::<Vec<_>>();
+    match words.as_slice() {
+      ["mmc", "dev", "1"] => self.emmc.select(0)?,
+      ["mmc", "dev", "1", hwpart] => self.emmc.select(parse_hex(hwpart)? as usize)?,
+      ["mmc", "dev", dev, ..] => return Err(Error::InvalidOperation(format!("no mmc device {dev}"))),
+      ["amlmmc", "key" | "env"] | ["saveenv"] => {}
+      ["amlmmc", "switch", "1", target] => {
+        let hwpart = match *target {
+          "user" => 0,
+          "boot0" => 1,
+          "boot1" => 2,
+          target => return Err(Error::InvalidOperation(format!("no hwpartition {target}"))),
+        };
+        self.emmc.select(hwpart)?;
+      }
+      ["mmc", direction @ ("read" | "write"), address, lba, count] => {
+        let offset = parse_hex(lba)? * SECTOR_SIZE as u64;
+        let length = parse_hex(count)? as usize * SECTOR_SIZE;
+        self.transfer(*direction == "write", parse_hex(address)? as u32, offset, length)?;
+      }
+      ["mmc", "erase", lba, count] => {
+        let offset = parse_hex(lba)? * SECTOR_SIZE as u64;
+        self
+          .emmc
+          .write(offset, &vec![0; parse_hex(count)? as usize * SECTOR_SIZE])?;
+      }
+      ["amlmmc", direction @ ("read" | "write"), name, address, offset, size] => {
+        let part = self
+          .profile
+          .partitions
+          .get(name)
+          .cloned()
+          .ok_or_else(|| Error::InvalidOperation(format!("no partition {name}")))?;
+        if part.name == "reserved" {
+          return Err(Error::InvalidOperation("amlmmc refuses the reserved partition".into()));
+        }
+        let (offset, length) = (parse_hex(offset)?, parse_hex(size)? as usize);
+        let part_size = (part.end() - part.offset) * SECTOR_SIZE;
+        if offset as usize + length > part_size {
+          return Err(Error::InvalidOperation(format!(
+            "{} is only {} bytes",
+            part.name, part_size
+          )));
+        }
+        let disk_offset = part.offset_bytes() as u64 + offset;
+        self.transfer(*direction == "write", parse_hex(address)? as u32, disk_offset, length)?;
+      }
+      ["env", "import", "-t", address, size] => {
+        let mut text = vec![0; parse_hex(size)? as usize];
+        self.read_ram(parse_hex(address)? as u32, &mut text);
+        for line in String::from_utf8_lossy(&text).lines() {
+          if let Some((name, value)) = line.trim_end_matches('\0').split_once('=') {
+            self.env.insert(name.to_owned(), value.to_owned());
+          }
+        }
+      }
+      ["printenv", name] => {
+        let value = self
+          .env
+          .get(*name)
+          .ok_or_else(|| Error::InvalidOperation(format!("{name} is not set")))?;
+        return Ok(format!("success {name}={value}"));
+      }
+      ["setenv", name] => {
+        self.env.remove(*name);
+      }
+      ["setenv", name, value @ ..] => {
+        self.env.insert((*name).to_owned(), value.join(" "));
+      }
+      ["reset"] | ["reboot"] => self.reenumerate(State::Normal),
+      ["reboot", "update"] => self.reenumerate(State::Burn),
+      _ => return Err(Error::InvalidOperation("unknown command".into())),
+    }
+    Ok("success".into())
+  }
+
+  /// Copy `length` bytes between memory at `address` and the selected hwpartition at `offset`
+  fn transfer(&mut self, write: bool, address: u32, offset: u64, length: usize) -> Result<()> {
+    let mut buf = vec![0; length];
+    if write {
+      self.read_ram(address, &mut buf);
+      let end = offset + length as u64;
+      let user_area = self.emmc.hwpart() == 0;
+      let disconnect = self.take_fault(
+        |fault| matches!(fault, Fault::Disconnect { offset: at, .. } if user_area && (offset..end).contains(at)),
+      );
+      if let Some(Fault::Disconnect { offset: at, returns_in }) = disconnect {
+        tracing::debug!("emulator: disconnecting during a write at {:#x}", at);
+        self.emmc.write(offset, &buf[..(at - offset) as usize])?;
+        let state = match returns_in {
+          DeviceMode::Usb => State::Rom,
+          DeviceMode::Normal | DeviceMode::NotFound => State::Normal,
+          DeviceMode::UsbBurn => State::Burn,
+        };
+        self.reenumerate(state);
+        return Ok(());
+      }
+      self.emmc.write(offset, &buf)
+    } else {
+      self.emmc.read(offset, &mut buf)?;
+      self.write_ram(address, &buf);
+      Ok(())
+    }
+  }
+}
+
+impl Device {
+  /// Whether the next bulk OUT transfer hangs, counting it if it does
+  fn bulk_out_hangs(&mut self) -> bool {
+    let nth = self.bulk_writes + 1;
+    let hangs = self
+      .take_fault(|fault| matches!(fault, Fault::BulkWriteHang { nth: at } if *at == nth))
+      .is_some();
+    if hangs {
+      tracing::debug!("emulator: hanging bulk write {}", nth);
+      self.bulk_writes = nth;
+    }
+    hangs
+  }
+
+  fn clear_halt(&mut self, endpoint: u8) {
+    if endpoint == ENDPOINT_OUT && self.out_halted {
+      tracing::debug!("emulator: halt cleared on endpoint {:#04x}", endpoint);
+      self.out_halted = false;
+    }
+  }
+
+  /// Remove and return the first pending fault matching `fires`
+  fn take_fault(&mut self, fires: impl Fn(&Fault) -> bool) -> Option<Fault> {
+    let index = self.faults.iter().position(fires)?;
+    Some(self.faults.remove(index))
+  }
+
+  /// Whether an injected fault makes `command` fail
+  fn bulkcmd_fault(&mut self, command: &str) -> bool {
+    let Some(index) = self.faults.iter().position(|fault| {
+      matches!(fault, Fault::BulkcmdFails { command: prefix, times } if *times > 0 && command.starts_with(prefix.as_str()))
+    }) else {
+      return false;
+    };
+    if let Fault::BulkcmdFails { times, .. } = &mut self.faults[index] {
+      *times -= 1;
+      if *times == 0 {
+        self.faults.remove(index);
+      }
+    }
+    true
+  }
+}
+
+/// The emulated device's end of a USB connection
+pub(crate) struct EmulatedTransport {
+  pub(crate) device: Arc<Mutex<Device>>,
+  pub(crate) session: u64,
+  /// Set to cancel a hung transfer
+  cancelled: Mutex<bool>,
+  cancel: Condvar,
+}
+
+impl std::fmt::Debug for EmulatedTransport {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("EmulatedTransport")
+      .field("session", &self.session)
+      .finish()
+  }
+}
+
+impl EmulatedTransport {
+  pub(crate) fn open(device: &Arc<Mutex<Device>>) -> Result<(Box<dyn Transport>, DeviceInfo)> {
+    let mut state = lock(device);
+    if matches!(state.mode(), DeviceMode::Normal | DeviceMode::NotFound) {
+      return Err(Error::NotFound);
+    }
+    state.connections += 1;
+    let transport = Self {
+      device: device.clone(),
+      session: state.session,
+      cancelled: Mutex::new(false),
+      cancel: Condvar::new(),
+    };
+    Ok((Box::new(transport), state.info()))
+  }
+
+  /// The device, as long as it is still on the session this transport was opened on
+  fn device(&self) -> Result<MutexGuard<'_, Device>> {
+    let device = lock(&self.device);
+    if device.session != self.session {
+      return Err(Error::SessionLost("the device disconnected".into()));
+    }
+    if device.state == State::Hung {
+      return Err(timeout("the device is not responding"));
+    }
+    Ok(device)
+  }
+
+  /// Block like a transfer that never finishes, until it is cancelled
+  fn hang(&self) -> Error {
+    let cancelled = self.cancelled.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let (mut cancelled, _) = self
+      .cancel
+      .wait_timeout_while(cancelled, HANG_LIMIT, |cancelled| !*cancelled)
+      .unwrap_or_else(|poisoned| poisoned.into_inner());
+    *cancelled = false;
+    timeout("the transfer was cancelled")
+  }
+}
+
+impl Transport for EmulatedTransport {
+  fn write_control(
+    &self,
+    _request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    data: &[u8],
+    _timeout: Duration,
+  ) -> flashthing_core::Result<usize> {
+    self.device()?.control_out(request, value, index, data)?;
+    Ok(data.len())
+  }
+
+  fn read_control(
+    &self,
+    _request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    buf: &mut [u8],
+    _timeout: Duration,
+  ) -> flashthing_core::Result<usize> {
+    Ok(self.device()?.control_in(request, value, index, buf)?)
+  }
+
+  fn write_bulk(&self, endpoint: u8, data: &[u8], _timeout: Duration) -> flashthing_core::Result<usize> {
+    if endpoint != ENDPOINT_OUT {
+      return Err(stall(format!("no OUT endpoint {endpoint:#04x}")).into());
+    }
+    let mut device = self.device()?;
+    if device.bulk_out_hangs() {
+      drop(device);
+      return Err(self.hang().into());
+    }
+    Ok(device.bulk_out(data)?)
+  }
+
+  fn read_bulk(&self, endpoint: u8, buf: &mut [u8], _timeout: Duration) -> flashthing_core::Result<usize> {
+    if endpoint != ENDPOINT_IN {
+      return Err(stall(format!("no IN endpoint {endpoint:#04x}")).into());
+    }
+    Ok(self.device()?.bulk_in(buf)?)
+  }
+
+  fn clear_halt(&self, endpoint: u8) -> flashthing_core::Result<()> {
+    self.device()?.clear_halt(endpoint);
+    Ok(())
+  }
+
+  fn cancel_transfers(&self) {
+    *self.cancelled.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = true;
+    self.cancel.notify_all();
+  }
+}
+
+pub(crate) fn lock(device: &Mutex<Device>) -> MutexGuard<'_, Device> {
+  device.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// A request the device rejects, which a real device reports by stalling the endpoint
+fn stall(reason: String) -> Error {
+  Error::IoError(io::Error::new(io::ErrorKind::BrokenPipe, reason))
+}
+
+/// A transfer the device never completes
+fn timeout(reason: &str) -> Error {
+  Error::IoError(io::Error::new(io::ErrorKind::TimedOut, reason))
+}
+
+fn okay() -> Vec<u8> {
+  let mut ack = vec![0u8; 16];
+  ack[..4].copy_from_slice(b"OKAY");
+  ack
+}
+
+fn amlc_request(length: u32, offset: u32) -> Vec<u8> {
+  let mut block = vec![0u8; AMLC_BLOCK_LENGTH];
+  block[0..4].copy_from_slice(b"AMLC");
+  block[8..12].copy_from_slice(&length.to_le_bytes());
+  block[12..16].copy_from_slice(&offset.to_le_bytes());
+  block
+}
+
+/// Checksum BL2 expects in an AMLS block: the sum of the data as little endian words
+fn amlc_checksum(data: &[u8]) -> u32 {
+  data.chunks(4).fold(0u32, |sum, word| {
+    let mut padded = [0u8; 4];
+    padded[..word.len()].copy_from_slice(word);
+    sum.wrapping_add(u32::from_le_bytes(padded))
+  })
+}
+
+/// Parse a number the way U-Boot does, as hex with or without a `0x` prefix
+fn parse_hex(text: &str) -> Result<u64> {
+  let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+  u64::from_str_radix(digits, 16).map_err(|_| Error::InvalidOperation(format!("{text:?} is not a hex number")))
+}
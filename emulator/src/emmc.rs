@@ -0,0 +1,137 @@
+//! The emulated eMMC: a user area backed by a temporary file and two boot hwpartitions in memory.
+
+use std::{
+  fs::{File, OpenOptions},
+  io::{Read, Seek, SeekFrom, Write},
+  path::PathBuf,
+  sync::atomic::{AtomicUsize, Ordering},
+};
+
+use flashthing::{Error, Result};
+
+/// Size of each boot hwpartition
+pub const BOOT_PARTITION_SIZE: usize = 4 * 1024 * 1024;
+
+/// Distinguishes the backing files of emulators in the same process
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) struct Emmc {
+  path: PathBuf,
+  user: File,
+  /// Size of the user area; the backing file only grows as far as it has been written
+  capacity: u64,
+  boot: [Vec<u8>; 2],
+  /// Selected hwpartition: 0 is the user area, 1 and 2 are boot0 and boot1
+  hwpart: usize,
+}
+
+impl Emmc {
+  /// Create an erased eMMC with a user area of `capacity` bytes
+  pub(crate) fn new(capacity: u64) -> Result<Self> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let path = std::env::temp_dir().join(format!("flashthing-emulator-{}-{id}.img", std::process::id()));
+    let user = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(true)
+      .open(&path)?;
+
+    Ok(Self {
+      path,
+      user,
+      capacity,
+      boot: [vec![0; BOOT_PARTITION_SIZE], vec![0; BOOT_PARTITION_SIZE]],
+      hwpart: 0,
+    })
+  }
+
+  pub(crate) fn select(&mut self, hwpart: usize) -> Result<()> {
+    if hwpart > 2 {
+      return Err(Error::InvalidOperation(format!("no hwpartition {hwpart}")));
+    }
+    self.hwpart = hwpart;
+    Ok(())
+  }
+
+  pub(crate) fn hwpart(&self) -> usize {
+    self.hwpart
+  }
+
+  /// Read from the selected hwpartition
+  pub(crate) fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+    match self.hwpart {
+      0 => self.read_user(offset, buf),
+      hwpart => {
+        let range = self.boot_range(offset, buf.len())?;
+        buf.copy_from_slice(&self.boot[hwpart - 1][range]);
+        Ok(())
+      }
+    }
+  }
+
+  /// Write to the selected hwpartition
+  pub(crate) fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+    match self.hwpart {
+      0 => {
+        self.check_user_range(offset, data.len())?;
+        self.user.seek(SeekFrom::Start(offset))?;
+        self.user.write_all(data)?;
+        Ok(())
+      }
+      hwpart => {
+        let range = self.boot_range(offset, data.len())?;
+        self.boot[hwpart - 1][range].copy_from_slice(data);
+        Ok(())
+      }
+    }
+  }
+
+  /// Read from the user area, whichever hwpartition is selected
+  pub(crate) fn read_user(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+    self.check_user_range(offset, buf.len())?;
+    self.user.seek(SeekFrom::Start(offset))?;
+
+    // the unwritten rest of the user area reads back erased
+    let mut read = 0;
+    while read < buf.len() {
+      match self.user.read(&mut buf[read..])? {
+        0 => break,
+        n => read += n,
+      }
+    }
+    buf[read..].fill(0);
+    Ok(())
+  }
+
+  pub(crate) fn boot(&self, index: usize) -> &[u8] {
+    &self.boot[index]
+  }
+
+  fn check_user_range(&self, offset: u64, length: usize) -> Result<()> {
+    if offset + length as u64 > self.capacity {
+      return Err(Error::InvalidOperation(format!(
+        "{length} bytes at {offset:#x} run past the end of the {} byte user area",
+        self.capacity
+      )));
+    }
+    Ok(())
+  }
+
+  fn boot_range(&self, offset: u64, length: usize) -> Result<std::ops::Range<usize>> {
+    let start = offset as usize;
+    if start + length > BOOT_PARTITION_SIZE {
+      return Err(Error::InvalidOperation(format!(
+        "{length} bytes at {offset:#x} run past the end of boot{}",
+        self.hwpart - 1
+      )));
+    }
+    Ok(start..start + length)
+  }
+}
+
+impl Drop for Emmc {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_file(&self.path);
+  }
+}
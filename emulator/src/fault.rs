@@ -0,0 +1,61 @@
+//! Failures the emulator injects on purpose, to exercise the host's retry, reconnect and rollback paths.
+
+use std::time::Duration;
+
+use flashthing::DeviceMode;
+
+/// A failure injected at a set point, once
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+  /// The `nth` bulk OUT transfer since the emulator was built (counting from 1) times out
+  ///
+  /// The transfer it belonged to is abandoned, as a device that missed part of one would.
+  BulkWriteTimeout {
+    /// Which bulk OUT transfer fails
+    nth: usize,
+  },
+  /// The `nth` bulk OUT transfer since the emulator was built (counting from 1) stalls
+  ///
+  /// The data is not taken, and the OUT endpoint stalls every transfer until the host clears its halt.
+  BulkWriteStall {
+    /// Which bulk OUT transfer stalls
+    nth: usize,
+  },
+  /// The `nth` bulk OUT transfer since the emulator was built (counting from 1) never finishes
+  ///
+  /// The transfer blocks without taking the data until the host cancels it, like a transfer libusb
+  /// loses track of.
+  BulkWriteHang {
+    /// Which bulk OUT transfer hangs
+    nth: usize,
+  },
+  /// The next `times` bulkcmds starting with `command` fail without doing anything
+  BulkcmdFails {
+    /// Prefix of the commands to fail, e.g. `mmc write`
+    command: String,
+    /// How many matching commands fail before they work again
+    times: usize,
+  },
+  /// The next bulkcmd starting with `command` takes `delay` before it is answered, like a slow
+  /// `mmc erase`
+  SlowBulkcmd {
+    /// Prefix of the command to slow down
+    command: String,
+    /// How long the device takes over it
+    delay: Duration,
+  },
+  /// The device drops off the bus when an eMMC write reaches `offset` bytes into the user area
+  ///
+  /// The write lands up to `offset`, the rest is lost, and the device comes back in `returns_in`.
+  Disconnect {
+    /// Byte offset in the user area where the write is cut off
+    offset: u64,
+    /// Mode the device is found in afterwards
+    returns_in: DeviceMode,
+  },
+  /// The regular firmware crashes as it boots, so once the device is reset into it, it is off the
+  /// bus and back in normal mode on alternate looks, as a device in a boot loop is
+  ///
+  /// The loop goes on until the device is powered on in another mode.
+  BootLoop,
+}
@@ -0,0 +1,241 @@
+//! An emulated Amlogic device for testing flashthing without hardware.
+//!
+//! [`Emulator`] implements the device side of the burn protocol: the BootROM's USB mode, BL2
+//! asking for the bootloader over AMLC, and U-Boot in USB burn mode with an eMMC backed by a
+//! temporary file. It plugs into flashthing as a [`DeviceTarget::Custom`], so whole flashes run
+//! against it exactly as they would against a device.
+//!
+//! ```no_run
+//! use flashthing::Flasher;
+//! use flashthing_emulator::Emulator;
+//!
+//! let emulator = Emulator::new()?;
+//! let mut flasher = Flasher::builder().target(emulator.target()).from_directory("package".into())?;
+//! flasher.flash()?;
+//! let logo = emulator.read_partition("logo", 4096)?;
+//! # Ok::<(), flashthing::Error>(())
+//! ```
+//!
+//! The emulator is stricter than most devices about the shape of transfers, so a request the host
+//! gets wrong fails the same way every time instead of only on some devices. Timeouts, failing
+//! commands and disconnects can be injected at set points with [`EmulatorBuilder::fault`].
+
+mod device;
+mod emmc;
+mod fault;
+
+use std::{
+  collections::BTreeMap,
+  sync::{Arc, Mutex},
+};
+
+use flashthing::{Connector, DeviceInfo, DeviceMode, DeviceProfile, DeviceTarget, Error, Result, Transport};
+
+use crate::device::{Device, EmulatedTransport, State, lock};
+pub use crate::{emmc::BOOT_PARTITION_SIZE, fault::Fault};
+
+/// An emulated device
+///
+/// Clones share the same device, so one can be handed to flashthing while another inspects it.
+#[derive(Clone)]
+pub struct Emulator {
+  device: Arc<Mutex<Device>>,
+}
+
+impl std::fmt::Debug for Emulator {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Emulator").field("mode", &self.mode()).finish()
+  }
+}
+
+impl Emulator {
+  /// Emulate a superbird in USB burn mode with an erased eMMC
+  pub fn new() -> Result<Self> {
+    Self::builder().build()
+  }
+
+  /// Builder for an emulator with a different profile or starting mode
+  pub fn builder() -> EmulatorBuilder {
+    EmulatorBuilder::default()
+  }
+
+  /// A target for connecting flashthing to this emulator
+  pub fn target(&self) -> DeviceTarget {
+    DeviceTarget::Custom(Arc::new(self.clone()))
+  }
+
+  /// The mode the device would be found in
+  pub fn mode(&self) -> DeviceMode {
+    lock(&self.device).mode()
+  }
+
+  /// Read from the eMMC user area
+  pub fn read_disk(&self, offset: u64, length: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0; length];
+    lock(&self.device).emmc.read_user(offset, &mut buf)?;
+    Ok(buf)
+  }
+
+  /// Read `length` bytes from the start of a partition in the user area
+  pub fn read_partition(&self, name: &str, length: usize) -> Result<Vec<u8>> {
+    let offset = {
+      let device = lock(&self.device);
+      let part = device.profile.partitions.resolve(name)?;
+      if length > part.size_bytes() {
+        return Err(Error::InvalidOperation(format!(
+          "{} is only {} bytes",
+          part.name,
+          part.size_bytes()
+        )));
+      }
+      part.offset_bytes() as u64
+    };
+    self.read_disk(offset, length)
+  }
+
+  /// Write to the eMMC user area, to set up what the device holds before a test
+  pub fn write_disk(&self, offset: u64, data: &[u8]) -> Result<()> {
+    let mut device = lock(&self.device);
+    let hwpart = device.emmc.hwpart();
+    device.emmc.select(0)?;
+    let result = device.emmc.write(offset, data);
+    device.emmc.select(hwpart)?;
+    result
+  }
+
+  /// Contents of a boot hwpartition, 1 for boot0 and 2 for boot1
+  pub fn boot_partition(&self, hwpart: u8) -> Vec<u8> {
+    assert!((1..=2).contains(&hwpart), "boot hwpart must be 1 or 2, got {hwpart}");
+    lock(&self.device).emmc.boot(hwpart as usize - 1).to_vec()
+  }
+
+  /// The U-Boot environment, as imported and set over USB
+  pub fn env(&self) -> BTreeMap<String, String> {
+    lock(&self.device).env.clone()
+  }
+
+  /// Every bulkcmd the device has received, in order
+  pub fn commands(&self) -> Vec<String> {
+    lock(&self.device).commands.clone()
+  }
+
+  /// How many times the device has been opened
+  pub fn connections(&self) -> usize {
+    lock(&self.device).connections
+  }
+
+  /// The bootloader BL2 received over AMLC, once the device has been booted from USB mode
+  pub fn bootloader(&self) -> Option<Vec<u8>> {
+    lock(&self.device).bootloader.clone()
+  }
+
+  /// Faults that have not been injected yet
+  pub fn pending_faults(&self) -> Vec<Fault> {
+    lock(&self.device).faults.clone()
+  }
+
+  /// Power the device back on in `mode`, as a user holding (or not holding) buttons 1 & 4 would
+  ///
+  /// # Panics
+  /// If `mode` is [`DeviceMode::NotFound`], since an emulated device is always connected.
+  pub fn power_on(&self, mode: DeviceMode) {
+    let state = match mode {
+      DeviceMode::Usb => State::Rom,
+      DeviceMode::UsbBurn => State::Burn,
+      DeviceMode::Normal => State::Normal,
+      DeviceMode::NotFound => panic!("an emulated device is always connected"),
+    };
+    lock(&self.device).reenumerate(state);
+  }
+
+  /// Set the temperature the profile's temperature command reports
+  pub fn set_temperature(&self, celsius: f64) {
+    lock(&self.device).temperature = celsius;
+  }
+}
+
+impl Connector for Emulator {
+  fn find_device(&self, _profile: &DeviceProfile) -> DeviceMode {
+    lock(&self.device).find()
+  }
+
+  fn open(&self, _profile: &DeviceProfile) -> Result<(Box<dyn Transport>, DeviceInfo)> {
+    EmulatedTransport::open(&self.device)
+  }
+}
+
+/// Builder for an [`Emulator`]
+#[derive(Debug)]
+pub struct EmulatorBuilder {
+  profile: DeviceProfile,
+  mode: DeviceMode,
+  secure_boot: bool,
+  serial: Option<String>,
+  faults: Vec<Fault>,
+}
+
+impl Default for EmulatorBuilder {
+  fn default() -> Self {
+    Self {
+      profile: DeviceProfile::default(),
+      mode: DeviceMode::UsbBurn,
+      secure_boot: true,
+      serial: None,
+      faults: Vec::new(),
+    }
+  }
+}
+
+impl EmulatorBuilder {
+  /// Set the profile of the emulated device (defaults to superbird)
+  ///
+  /// The partition table sets the size of the eMMC, and BL2 only starts if the host sends the
+  /// profile's `bl2`.
+  pub fn profile(mut self, profile: DeviceProfile) -> Self {
+    self.profile = profile;
+    self
+  }
+
+  /// Set the mode the device starts in (defaults to USB burn mode)
+  ///
+  /// [`DeviceMode::Usb`] starts in the BootROM, so the host has to boot BL2 and the bootloader first.
+  pub fn mode(mut self, mode: DeviceMode) -> Self {
+    self.mode = mode;
+    self
+  }
+
+  /// Set whether the efuses report secure boot as enforced (defaults to true, like retail devices)
+  pub fn secure_boot(mut self, secure_boot: bool) -> Self {
+    self.secure_boot = secure_boot;
+    self
+  }
+
+  /// Set the serial number the device reports over USB (defaults to none)
+  pub fn serial(mut self, serial: impl Into<String>) -> Self {
+    self.serial = Some(serial.into());
+    self
+  }
+
+  /// Inject `fault` once the device reaches it; faults can be added more than once
+  pub fn fault(mut self, fault: Fault) -> Self {
+    self.faults.push(fault);
+    self
+  }
+
+  /// Create the emulator and its eMMC
+  pub fn build(self) -> Result<Emulator> {
+    let state = match self.mode {
+      DeviceMode::Usb => State::Rom,
+      DeviceMode::UsbBurn => State::Burn,
+      DeviceMode::Normal => State::Normal,
+      DeviceMode::NotFound => {
+        return Err(Error::InvalidOperation("an emulated device is always connected".into()));
+      }
+    };
+    let mut device = Device::new(self.profile, state, self.secure_boot, self.faults)?;
+    device.serial = self.serial;
+    Ok(Emulator {
+      device: Arc::new(Mutex::new(device)),
+    })
+  }
+}
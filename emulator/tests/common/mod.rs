@@ -0,0 +1,20 @@
+use std::path::PathBuf;
+
+/// Write a package with `steps` and the given files to a fresh directory
+pub fn package(name: &str, steps: &str, files: &[(&str, &[u8])]) -> PathBuf {
+  let dir = std::env::temp_dir().join(format!("flashthing-emulator-{name}-{}", std::process::id()));
+  let _ = std::fs::remove_dir_all(&dir);
+  std::fs::create_dir_all(&dir).unwrap();
+  let meta =
+    format!(r#"{{ "name": "{name}", "version": "1.0.0", "description": "", "metadataVersion": 1, "steps": {steps} }}"#);
+  std::fs::write(dir.join("meta.json"), meta).unwrap();
+  for (file, data) in files {
+    std::fs::write(dir.join(file), data).unwrap();
+  }
+  dir
+}
+
+/// Bytes that differ from their neighbours, so misplaced writes show up
+pub fn pattern(length: usize) -> Vec<u8> {
+  (0..length).map(|i| (i % 253) as u8).collect()
+}
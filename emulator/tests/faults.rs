@@ -0,0 +1,490 @@
+mod common;
+
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use common::{package, pattern};
+use flashthing::{
+  AmlogicSoC, BootOutcome, Callback, DeviceMode, DeviceProfile, Error, Event, Flasher, ThermalPolicy, UsbQuirks,
+};
+use flashthing_emulator::{Emulator, Fault};
+
+fn partition_offset(name: &str) -> u64 {
+  DeviceProfile::default()
+    .partitions
+    .resolve(name)
+    .unwrap()
+    .offset_bytes() as u64
+}
+
+#[test]
+fn test_mmc_write_retry() {
+  let emulator = Emulator::builder()
+    .fault(Fault::BulkcmdFails {
+      command: "mmc write".into(),
+      times: 1,
+    })
+    .build()
+    .unwrap();
+  let logo = pattern(1024 * 1024);
+  let dir = package(
+    "mmc-write-retry",
+    r#"[{ "type": "writeLargeMemory", "value": { "partition": "logo", "data": { "filePath": "logo.img" }, "blockLength": 4096 } }]"#,
+    &[("logo.img", &logo)],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .thermal_policy(ThermalPolicy {
+      error_cooldown: Duration::from_millis(10),
+      ..ThermalPolicy::disabled()
+    })
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  let writes = emulator
+    .commands()
+    .iter()
+    .filter(|c| c.starts_with("mmc write"))
+    .count();
+  assert_eq!(writes, 2);
+  assert!(emulator.pending_faults().is_empty());
+  assert_eq!(emulator.read_partition("logo", logo.len()).unwrap(), logo);
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_failed_write_context() {
+  let emulator = Emulator::builder()
+    .fault(Fault::BulkcmdFails {
+      command: "amlmmc write".into(),
+      times: 3,
+    })
+    .build()
+    .unwrap();
+  let dir = package(
+    "write-context",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv first yes" },
+      { "type": "restorePartition", "value": { "name": "env", "data": { "filePath": "env.img" } } }
+    ]"#,
+    &[("env.img", &[0xa5; 64 * 1024])],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .thermal_policy(ThermalPolicy {
+      error_cooldown: Duration::from_millis(10),
+      ..ThermalPolicy::disabled()
+    })
+    .from_directory(dir.clone())
+    .unwrap();
+  let err = flasher.flash().unwrap_err();
+
+  let context = err.context().unwrap();
+  assert_eq!(context.step, Some(2));
+  assert_eq!(context.partition.as_deref(), Some("env"));
+  assert_eq!(context.retries, 2);
+  assert!(
+    err
+      .to_string()
+      .starts_with("step 2 (restorePartition env) at offset 0x0 after 2 retries: "),
+    "{err}"
+  );
+  assert_eq!(flasher.report().steps[1].error, Some(err.to_string()));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_staged_chunk_written_on_drop() {
+  let emulator = Emulator::builder()
+    .fault(Fault::BulkcmdFails {
+      command: "mmc write".into(),
+      times: 3,
+    })
+    .build()
+    .unwrap();
+  let profile = DeviceProfile {
+    thermal: ThermalPolicy {
+      error_cooldown: Duration::from_millis(10),
+      ..ThermalPolicy::disabled()
+    },
+    ..DeviceProfile::default()
+  };
+  let aml = AmlogicSoC::init_with_target(None, profile, emulator.target()).unwrap();
+  let data = pattern(64 * 1024);
+
+  // every retry fails, so the chunk is still staged when the connection goes away
+  assert!(
+    aml
+      .write_user_area(0x2000, &data[..], data.len(), false, |_| {})
+      .is_err()
+  );
+  assert_ne!(emulator.read_disk(0x2000 * 512, data.len()).unwrap(), data);
+  drop(aml);
+
+  assert_eq!(emulator.read_disk(0x2000 * 512, data.len()).unwrap(), data);
+  assert_eq!(emulator.commands().last().map(String::as_str), Some("mmc dev 1 0"));
+}
+
+#[test]
+fn test_step_retry_after_timeout() {
+  let emulator = Emulator::builder()
+    .fault(Fault::BulkWriteTimeout { nth: 5 })
+    .build()
+    .unwrap();
+  let logo = pattern(1024 * 1024);
+  let dir = package(
+    "timeout-retry",
+    r#"[{ "type": "writeLargeMemory", "value": { "partition": "logo", "data": { "filePath": "logo.img" }, "blockLength": 4096 }, "onError": { "retry": 1 } }]"#,
+    &[("logo.img", &logo)],
+  );
+
+  // with limited transfers the failed one can't be split any further, so the step is retried
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .usb_quirks(UsbQuirks {
+      limited_bulk: true,
+      ..UsbQuirks::new()
+    })
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  assert!(emulator.pending_faults().is_empty());
+  assert_eq!(emulator.read_partition("logo", logo.len()).unwrap(), logo);
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_step_timeout() {
+  let emulator = Emulator::builder()
+    .fault(Fault::SlowBulkcmd {
+      command: "setenv slow".into(),
+      delay: Duration::from_millis(500),
+    })
+    .build()
+    .unwrap();
+  let dir = package(
+    "step-timeout",
+    r#"[
+      { "id": "slow", "type": "bulkcmd", "value": "setenv slow yes", "timeoutMs": 200, "onError": "continue" },
+      { "type": "bulkcmd", "value": "setenv after yes" }
+    ]"#,
+    &[],
+  );
+  let timeouts = Arc::new(Mutex::new(Vec::new()));
+  let callback: Callback = {
+    let timeouts = timeouts.clone();
+    Arc::new(move |event| {
+      if let Event::Timeout { id, .. } = event {
+        timeouts.lock().unwrap().push(id);
+      }
+    })
+  };
+
+  // the step runs past its timeoutMs, and onError carries on with the next one
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .callback(Some(callback))
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  let report = flasher.report();
+  assert_eq!(report.steps_failed, 1);
+  assert_eq!(report.warnings.len(), 1);
+  assert_eq!(report.steps[0].error_code.as_deref(), Some("TIMEOUT"));
+  assert_eq!(*timeouts.lock().unwrap(), ["slow"]);
+  assert_eq!(emulator.env().get("after").map(String::as_str), Some("yes"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_flash_deadline() {
+  let emulator = Emulator::builder()
+    .fault(Fault::SlowBulkcmd {
+      command: "setenv slow".into(),
+      delay: Duration::from_millis(500),
+    })
+    .build()
+    .unwrap();
+  let dir = package(
+    "deadline",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv slow yes" },
+      { "type": "bulkcmd", "value": "setenv after yes" }
+    ]"#,
+    &[],
+  );
+  let timeouts = Arc::new(Mutex::new(0));
+  let callback: Callback = {
+    let timeouts = timeouts.clone();
+    Arc::new(move |event| {
+      if let Event::Timeout { .. } = event {
+        *timeouts.lock().unwrap() += 1;
+      }
+    })
+  };
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .callback(Some(callback))
+    .deadline(Duration::from_millis(200))
+    .from_directory(dir.clone())
+    .unwrap();
+  let err = flasher.flash().unwrap_err();
+  assert!(matches!(err.root(), Error::Timeout(_)), "{err}");
+  assert_eq!(err.code(), "TIMEOUT");
+  assert_eq!(*timeouts.lock().unwrap(), 1);
+  assert!(!emulator.env().contains_key("after"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_transfer_downshift() {
+  let emulator = Emulator::builder()
+    .fault(Fault::BulkWriteTimeout { nth: 3 })
+    .build()
+    .unwrap();
+  let aml = AmlogicSoC::init_with_target(None, DeviceProfile::default(), emulator.target()).unwrap();
+  let address = aml.profile().staging_address;
+  let data = pattern(1024 * 1024);
+
+  aml.write_large_memory(address, &data, 4096, true).unwrap();
+  assert!(emulator.pending_faults().is_empty());
+  assert_eq!(aml.bulk_transfer_limit(), 512 * 1024);
+  assert_eq!(aml.read_large_memory(address, data.len(), 4096).unwrap(), data);
+}
+
+#[test]
+fn test_stall_recovery() {
+  let emulator = Emulator::builder()
+    .fault(Fault::BulkWriteStall { nth: 3 })
+    .build()
+    .unwrap();
+  let aml = AmlogicSoC::init_with_target(None, DeviceProfile::default(), emulator.target()).unwrap();
+  let address = aml.profile().staging_address;
+  let data = pattern(1024 * 1024);
+
+  // the stalled transfer goes through once the halt is cleared, without splitting later ones
+  aml.write_large_memory(address, &data, 4096, true).unwrap();
+  assert!(emulator.pending_faults().is_empty());
+  assert_eq!(aml.bulk_transfer_limit(), aml.profile().max_transfer_size);
+  assert_eq!(aml.read_large_memory(address, data.len(), 4096).unwrap(), data);
+}
+
+#[test]
+fn test_hung_transfer_watchdog() {
+  let emulator = Emulator::builder()
+    .fault(Fault::BulkWriteHang { nth: 3 })
+    .build()
+    .unwrap();
+  let stalls = Arc::new(Mutex::new(Vec::new()));
+  let callback: Callback = {
+    let stalls = stalls.clone();
+    Arc::new(move |event| {
+      if let Event::TransferStalled { endpoint, .. } = event {
+        stalls.lock().unwrap().push(endpoint);
+      }
+    })
+  };
+  let profile = DeviceProfile {
+    usb_quirks: UsbQuirks {
+      transfer_watchdog: Some(Duration::from_millis(200)),
+      ..UsbQuirks::new()
+    },
+    ..DeviceProfile::default()
+  };
+  let aml = AmlogicSoC::init_with_target(Some(callback), profile, emulator.target()).unwrap();
+  let address = aml.profile().staging_address;
+  let data = pattern(1024 * 1024);
+
+  aml.write_large_memory(address, &data, 4096, true).unwrap();
+  assert!(emulator.pending_faults().is_empty());
+  assert_eq!(*stalls.lock().unwrap(), [0x01]);
+  assert_eq!(aml.read_large_memory(address, data.len(), 4096).unwrap(), data);
+}
+
+#[test]
+fn test_reconnect_after_disconnect() {
+  let emulator = Emulator::builder()
+    .fault(Fault::Disconnect {
+      offset: partition_offset("logo") + 1024 * 1024,
+      returns_in: DeviceMode::UsbBurn,
+    })
+    .build()
+    .unwrap();
+  let logo = pattern(3 * 1024 * 1024);
+  let dir = package(
+    "disconnect-retry",
+    r#"[{ "type": "writeLargeMemory", "value": { "partition": "logo", "data": { "filePath": "logo.img" }, "blockLength": 4096 }, "onError": { "retry": 1 } }]"#,
+    &[("logo.img", &logo)],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  assert_eq!(emulator.connections(), 2);
+  assert_eq!(emulator.read_partition("logo", logo.len()).unwrap(), logo);
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_rollback_after_disconnect() {
+  let env_offset = partition_offset("env");
+  let emulator = Emulator::builder()
+    .fault(Fault::Disconnect {
+      offset: partition_offset("logo") + 1024 * 1024,
+      returns_in: DeviceMode::UsbBurn,
+    })
+    .build()
+    .unwrap();
+  let original = pattern(64 * 1024);
+  emulator.write_disk(env_offset, &original).unwrap();
+  let dir = package(
+    "disconnect-rollback",
+    r#"[
+      { "type": "restorePartition", "value": { "name": "env", "data": { "filePath": "env.img" } } },
+      { "type": "writeLargeMemory", "value": { "partition": "logo", "data": { "filePath": "logo.img" }, "blockLength": 4096 } }
+    ]"#,
+    &[("env.img", &[0xa5; 64 * 1024]), ("logo.img", &pattern(3 * 1024 * 1024))],
+  );
+  let bundle = dir.join("bundle");
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .transactional(bundle)
+    .from_directory(dir.clone())
+    .unwrap();
+  assert!(flasher.flash().is_err());
+
+  assert_eq!(flasher.report().rolled_back, ["env"]);
+  assert_eq!(emulator.connections(), 2);
+  assert_eq!(emulator.read_partition("env", original.len()).unwrap(), original);
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_rollback_raw_writes() {
+  let env_offset = partition_offset("env");
+  let emulator = Emulator::new().unwrap();
+  let original = pattern(64 * 1024);
+  emulator.write_disk(env_offset, &original).unwrap();
+  let boot0 = emulator.boot_partition(1);
+  let steps = format!(
+    r#"[
+      {{ "type": "writeBootPartition", "value": {{ "hwpart": 1, "data": {{ "filePath": "boot0.bin" }} }} }},
+      {{ "type": "writeUserArea", "value": {{ "lba": {}, "data": {{ "filePath": "env.img" }} }} }},
+      {{ "type": "bulkcmd", "value": "nonexistent" }}
+    ]"#,
+    env_offset / 512
+  );
+  let dir = package(
+    "raw-rollback",
+    &steps,
+    &[("boot0.bin", &[0x5a; 4096]), ("env.img", &[0xa5; 4096])],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .transactional(dir.join("bundle"))
+    .from_directory(dir.clone())
+    .unwrap();
+  assert!(flasher.flash().is_err());
+
+  // restored newest first, and the boot hwpartition only as far as the step wrote
+  assert_eq!(flasher.report().rolled_back, ["env", "boot0"]);
+  assert_eq!(std::fs::read(dir.join("bundle/boot0.img")).unwrap().len(), 4096);
+  assert_eq!(emulator.read_partition("env", original.len()).unwrap(), original);
+  assert_eq!(emulator.boot_partition(1), boot0);
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_repeat_records_failed_iterations() {
+  let emulator = Emulator::builder()
+    .fault(Fault::BulkcmdFails {
+      command: "setenv round".into(),
+      times: 1,
+    })
+    .build()
+    .unwrap();
+  let dir = package(
+    "repeat",
+    r#"[
+      {
+        "type": "repeat",
+        "id": "burn-in",
+        "value": {
+          "count": 3,
+          "steps": [
+            { "type": "bulkcmd", "value": "setenv round yes", "id": "round" },
+            { "type": "bulkcmd", "value": "setenv after yes" }
+          ]
+        },
+        "onError": "continue"
+      }
+    ]"#,
+    &[],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  let report = flasher.report();
+  assert_eq!(report.steps_failed, 1);
+  let repeat = &report.repeats[0];
+  assert_eq!(
+    (repeat.id.as_str(), repeat.iterations, repeat.failed),
+    ("burn-in", 3, 1)
+  );
+  assert_eq!(
+    (repeat.failures[0].iteration, repeat.failures[0].step_id.as_str()),
+    (1, "round")
+  );
+  let commands = emulator.commands();
+  assert_eq!(commands.iter().filter(|c| *c == "setenv round yes").count(), 3);
+  // the failed iteration stopped at its first step
+  assert_eq!(commands.iter().filter(|c| *c == "setenv after yes").count(), 2);
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_boot_loop() {
+  let emulator = Emulator::builder().fault(Fault::BootLoop).build().unwrap();
+  let dir = package("loops", r#"[{ "type": "reset", "value": { "mode": "soft" } }]"#, &[]);
+  let cycles = Arc::new(Mutex::new(None));
+  let seen = cycles.clone();
+  let callback: Callback = Arc::new(move |event| {
+    if let Event::BootLoopDetected { cycles } = event {
+      *seen.lock().unwrap() = Some(cycles);
+    }
+  });
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .callback(Some(callback))
+    .verify_boot(Duration::from_secs(30))
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+  assert_eq!(flasher.report().boot, Some(BootOutcome::BootLoop { cycles: 3 }));
+  assert_eq!(*cycles.lock().unwrap(), Some(3));
+  assert!(
+    flasher
+      .report()
+      .warnings
+      .iter()
+      .any(|warning| warning.contains("boot loop"))
+  );
+  let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,1606 @@
+mod common;
+
+use std::{
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use common::{package, pattern};
+use flashthing::{
+  ARTIFACT_CHECKPOINT, ARTIFACT_OUTPUTS, ARTIFACT_REPORT, ActionKind, Adb, AmlogicSoC, BootOutcome, CancelToken,
+  DeviceMode, DeviceProfile, Error, Event, FlashOutcome, FlashReport, Flasher, Overrides, PartitionTable, Provisioner,
+  Recorder, UpdateCheck,
+};
+use flashthing_emulator::Emulator;
+
+#[test]
+fn test_flash_package() {
+  let emulator = Emulator::new().unwrap();
+  let logo = pattern(3 * 1024 * 1024);
+  let dir = package(
+    "flash",
+    r#"[
+      { "type": "writeLargeMemory", "value": { "partition": "logo", "data": { "filePath": "logo.img" }, "blockLength": 4096 } },
+      { "type": "writeEnv", "value": { "filePath": "env.txt" } },
+      { "type": "bulkcmd", "value": "setenv flashed yes" }
+    ]"#,
+    &[
+      ("logo.img", &logo),
+      ("env.txt", b"bootdelay=0\nbootcmd=run storeboot\n"),
+    ],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  let report = flasher.report();
+  assert!(report.success);
+  assert_eq!(report.steps_completed, 3);
+  assert_eq!(report.bytes_written, logo.len() as u64);
+  assert_eq!(report.resources, flashthing::resources::embedded_info()[..3]);
+  assert_eq!(emulator.read_partition("logo", logo.len()).unwrap(), logo);
+  let env = emulator.env();
+  assert_eq!(env["bootcmd"], "run storeboot");
+  assert_eq!(env["flashed"], "yes");
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_compare_before_write() {
+  let emulator = Emulator::new().unwrap();
+  let image = pattern(10 * 1024 * 1024);
+  let offset = |name: &str| {
+    DeviceProfile::default()
+      .partitions
+      .resolve(name)
+      .unwrap()
+      .offset_bytes() as u64
+  };
+  // the first 8MB chunk is already on both partitions, the rest isn't
+  for partition in ["system_a", "system_b"] {
+    emulator
+      .write_disk(offset(partition), &image[..8 * 1024 * 1024])
+      .unwrap();
+  }
+  let dir = package(
+    "compare",
+    r#"[
+      { "type": "writeLargeMemory", "value": { "partition": "system_a", "data": { "filePath": "system.img" }, "blockLength": 4096, "compareBeforeWrite": true } },
+      { "type": "restorePartition", "value": { "name": "system_b", "data": { "filePath": "system.img" }, "compareBeforeWrite": true } }
+    ]"#,
+    &[("system.img", &image)],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  let report = flasher.report();
+  assert!(report.success);
+  assert_eq!(report.bytes_skipped, 2 * 8 * 1024 * 1024);
+  assert_eq!(report.bytes_written, 2 * 2 * 1024 * 1024);
+  assert_eq!(emulator.read_partition("system_a", image.len()).unwrap(), image);
+  assert_eq!(emulator.read_partition("system_b", image.len()).unwrap(), image);
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_concat_files() {
+  let emulator = Emulator::new().unwrap();
+  let boot = pattern(1024 * 1024 + 512);
+  let rootfs = vec![0x5a; 2 * 1024 * 1024];
+  let dir = package(
+    "concat",
+    r#"[
+      { "type": "writeLargeMemory", "value": { "partition": "logo", "data": { "concat": ["boot.vfat", "rootfs.ext4"] }, "blockLength": 4096 } },
+      { "type": "restorePartition", "value": { "name": "misc", "data": { "concat": ["boot.vfat", { "filePath": "rootfs.ext4" }] } } }
+    ]"#,
+    &[("boot.vfat", &boot), ("rootfs.ext4", &rootfs)],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  let joined = [boot, rootfs].concat();
+  assert_eq!(flasher.report().bytes_written, 2 * joined.len() as u64);
+  assert_eq!(emulator.read_partition("logo", joined.len()).unwrap(), joined);
+  assert_eq!(emulator.read_partition("misc", joined.len()).unwrap(), joined);
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_slice_files() {
+  let emulator = Emulator::new().unwrap();
+  let dump = pattern(3 * 1024 * 1024);
+  let dir = package(
+    "slice",
+    r#"[
+      { "type": "restorePartition", "value": { "name": "logo", "data": { "filePath": "disk.img", "offset": "0x100000", "length": { "sectors": 2048 } } } },
+      { "type": "restorePartition", "value": { "name": "misc", "data": { "concat": [{ "filePath": "disk.img", "offset": 512, "length": 512 }, { "filePath": "disk.img", "offset": 3145216 }] } } }
+    ]"#,
+    &[("disk.img", &dump)],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  assert_eq!(flasher.report().bytes_written, 1024 * 1024 + 1024);
+  let logo = emulator.read_partition("logo", 1024 * 1024).unwrap();
+  assert_eq!(logo, dump[1024 * 1024..2 * 1024 * 1024]);
+  let misc = emulator.read_partition("misc", 1024).unwrap();
+  assert_eq!(misc, [&dump[512..1024], &dump[dump.len() - 512..]].concat());
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_apply_delta() {
+  let emulator = Emulator::new().unwrap();
+  let base = pattern(4096);
+  emulator
+    .write_disk(
+      PartitionTable::superbird().get("misc").unwrap().offset_bytes() as u64,
+      &base,
+    )
+    .unwrap();
+  // bsdiff control block: add 1 to the first 16 bytes of the base, then take 4 new bytes
+  let mut delta = [16i64, 4, 0]
+    .iter()
+    .flat_map(|value| value.to_le_bytes())
+    .collect::<Vec<_>>();
+  delta.extend([1; 16]);
+  delta.extend(b"tail");
+  let dir = package(
+    "delta",
+    r#"[{ "type": "applyDelta", "value": { "name": "misc", "delta": { "filePath": "misc.bsdiff" } } }]"#,
+    &[("misc.bsdiff", &delta)],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  let misc = emulator.read_partition("misc", 4096).unwrap();
+  let patched = base[..16].iter().map(|byte| byte.wrapping_add(1)).collect::<Vec<_>>();
+  assert_eq!(misc[..16], patched);
+  assert_eq!(&misc[16..20], b"tail");
+  assert_eq!(misc[20..], base[20..]);
+  assert_eq!(flasher.report().bytes_written, 20);
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_patch_bytes() {
+  let emulator = Emulator::new().unwrap();
+  let image = pattern(2 * 1024 * 1024);
+  let dir = package(
+    "patch",
+    r#"[
+      { "type": "restorePartition", "value": { "name": "logo", "data": { "filePath": "logo.img" }, "patches": [
+        { "offset": "0x10", "hex": "dead beef" },
+        { "offset": 1048574, "hex": "01020304" }
+      ] } },
+      { "type": "writeLargeMemory", "value": { "partition": "misc", "data": { "filePath": "logo.img" }, "blockLength": 4096, "patches": [
+        { "offset": 0, "hex": "ff" }
+      ] } }
+    ]"#,
+    &[("logo.img", &image)],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  let mut patched = image.clone();
+  patched[0x10..0x14].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+  patched[1048574..1048578].copy_from_slice(&[1, 2, 3, 4]);
+  assert_eq!(emulator.read_partition("logo", image.len()).unwrap(), patched);
+  let misc = emulator.read_partition("misc", image.len()).unwrap();
+  assert_eq!((misc[0], &misc[1..]), (0xff, &image[1..]));
+  let _ = std::fs::remove_dir_all(&dir);
+
+  // a patch past the end of the data fails before anything is written
+  let dir = package(
+    "patch-past-end",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv started yes" },
+      { "type": "restorePartition", "value": { "name": "logo", "data": { "filePath": "logo.img" }, "patches": [
+        { "offset": 2097151, "hex": "0102" }
+      ] } }
+    ]"#,
+    &[("logo.img", &image)],
+  );
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  let err = flasher.flash().unwrap_err();
+  assert!(err.to_string().contains("runs past the end"), "{err}");
+  assert!(!emulator.env().contains_key("started"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_trim_padding() {
+  let emulator = Emulator::new().unwrap();
+  let mut image = pattern(64 * 1024);
+  image.resize(1024 * 1024, 0);
+  let offset = |name: &str| {
+    DeviceProfile::default()
+      .partitions
+      .resolve(name)
+      .unwrap()
+      .offset_bytes() as u64
+  };
+  for partition in ["logo", "misc"] {
+    emulator
+      .write_disk(offset(partition), &vec![0x55; image.len()])
+      .unwrap();
+  }
+  let dir = package(
+    "trim",
+    r#"[
+      { "type": "restorePartition", "value": { "name": "logo", "data": { "filePath": "logo.img" }, "trimPadding": true } },
+      { "type": "restorePartition", "value": { "name": "misc", "data": { "filePath": "logo.img" }, "trimPadding": true, "eraseTrimmed": true } }
+    ]"#,
+    &[("logo.img", &image)],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  assert_eq!(flasher.report().bytes_written, 2 * 64 * 1024);
+  let logo = emulator.read_partition("logo", image.len()).unwrap();
+  assert_eq!(logo[..64 * 1024], image[..64 * 1024]);
+  assert!(logo[64 * 1024..].iter().all(|byte| *byte == 0x55));
+  assert_eq!(emulator.read_partition("misc", image.len()).unwrap(), image);
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_erase_trimmed_ff_padding() {
+  let emulator = Emulator::new().unwrap();
+  let mut image = pattern(64 * 1024);
+  image.resize(1024 * 1024, 0xff);
+  emulator
+    .write_disk(
+      DeviceProfile::default()
+        .partitions
+        .resolve("misc")
+        .unwrap()
+        .offset_bytes() as u64,
+      &vec![0x55; image.len()],
+    )
+    .unwrap();
+  let dir = package(
+    "trim-ff",
+    r#"[
+      { "type": "restorePartition", "value": { "name": "misc", "data": { "filePath": "misc.img" }, "trimPadding": true, "eraseTrimmed": true } }
+    ]"#,
+    &[("misc.img", &image)],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  // the emulated card erases to zeros, so the 0xff padding has to be written out
+  assert_eq!(emulator.read_partition("misc", image.len()).unwrap(), image);
+  let warnings = &flasher.report().warnings;
+  assert!(
+    warnings
+      .iter()
+      .any(|warning| warning.contains("writing the padding instead")),
+    "{warnings:?}"
+  );
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_failed_steps() {
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "failures",
+    r#"[
+      { "type": "bulkcmd", "value": "not a command", "onError": "continue" },
+      { "type": "bulkcmd", "value": "still not a command", "onError": { "retry": 2 } },
+      { "type": "bulkcmd", "value": "setenv unreachable yes" }
+    ]"#,
+    &[],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  assert!(flasher.flash().is_err());
+
+  let report = flasher.report();
+  assert!(!report.success);
+  assert_eq!(report.steps_failed, 1);
+  assert_eq!(report.warnings.len(), 1);
+  let steps = &report.steps;
+  assert_eq!(steps.len(), 2);
+  assert!(
+    steps
+      .iter()
+      .all(|step| !step.completed && step.id.starts_with("bulkcmd-"))
+  );
+  assert_eq!((steps[0].attempts, steps[1].attempts), (1, 3));
+  assert_eq!(steps[1].index, 2);
+  let attempts = emulator
+    .commands()
+    .iter()
+    .filter(|c| *c == "still not a command")
+    .count();
+  assert_eq!(attempts, 3);
+  assert!(!emulator.env().contains_key("unreachable"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_retry_refused_for_steps_that_move_the_device() {
+  let emulator = Emulator::new().unwrap();
+  for step in [
+    r#"{ "type": "reset", "value": { "mode": "burn" }, "onError": { "retry": 1 } }"#,
+    r#"{ "type": "reconnect", "value": { "timeout": 1000 }, "onError": { "retry": 1 } }"#,
+    r#"{ "type": "repeat", "value": { "count": 2, "steps": [{ "type": "run", "value": { "address": 4096 }, "onError": { "retry": 1 } }] } }"#,
+  ] {
+    let dir = package(
+      "retry-refused",
+      &format!(r#"[{{ "type": "bulkcmd", "value": "setenv first yes" }}, {}]"#, step),
+      &[],
+    );
+    let result = Flasher::builder().target(emulator.target()).from_directory(dir.clone());
+    assert!(matches!(result, Err(Error::InvalidOperation(message)) if message.contains("can't be retried")));
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+  // the package is refused before anything is sent to the device
+  assert!(emulator.commands().is_empty());
+  assert!(!emulator.env().contains_key("first"));
+}
+
+#[test]
+fn test_reset_and_reconnect() {
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "reconnect",
+    r#"[
+      { "type": "reset", "value": { "mode": "burn" } },
+      { "type": "reconnect", "value": { "timeout": 10000 } },
+      { "type": "bulkcmd", "value": "setenv reconnected yes" }
+    ]"#,
+    &[],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  assert_eq!(emulator.connections(), 2);
+  assert_eq!(emulator.env()["reconnected"], "yes");
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_boot_from_usb_mode() {
+  let emulator = Emulator::builder().mode(DeviceMode::Usb).build().unwrap();
+  let aml = AmlogicSoC::init_with_target(None, DeviceProfile::default(), emulator.target()).unwrap();
+
+  assert_eq!(emulator.mode(), DeviceMode::UsbBurn);
+  assert_eq!(
+    emulator.bootloader().as_deref(),
+    DeviceProfile::default().bootloader.as_deref()
+  );
+  aml.bulkcmd("amlmmc key").unwrap();
+}
+
+#[test]
+fn test_large_memory_padding() {
+  let emulator = Emulator::new().unwrap();
+  let aml = AmlogicSoC::init_with_target(None, DeviceProfile::default(), emulator.target()).unwrap();
+  let address = aml.profile().staging_address;
+  let data = pattern(3 * 4096 + 100);
+
+  aml.write_large_memory(address, &data, 4096, true).unwrap();
+  let read = aml.read_large_memory(address, 4 * 4096, 4096).unwrap();
+  assert_eq!(read[..data.len()], data);
+  assert!(read[data.len()..].iter().all(|byte| *byte == 0));
+
+  let streamed = data.iter().map(|byte| byte ^ 0xff).collect::<Vec<_>>();
+  aml
+    .write_large_memory_from_reader(address, &mut streamed.as_slice(), streamed.len(), 4096, true)
+    .unwrap();
+  let read = aml.read_large_memory(address, 4 * 4096, 4096).unwrap();
+  assert_eq!(read[..data.len()], streamed);
+  assert!(read[data.len()..].iter().all(|byte| *byte == 0));
+
+  let err = aml.write_large_memory(address, &data, 4096, false).unwrap_err();
+  assert!(err.to_string().contains("multiple of block length"), "{err}");
+}
+
+#[test]
+fn test_refuses_bl2_for_other_secure_boot_state() {
+  let emulator = Emulator::builder()
+    .mode(DeviceMode::Usb)
+    .secure_boot(false)
+    .build()
+    .unwrap();
+  let result = AmlogicSoC::init_with_target(None, DeviceProfile::default(), emulator.target());
+
+  assert!(matches!(result, Err(Error::UnsupportedSecureBoot(_))));
+  assert_eq!(emulator.mode(), DeviceMode::Usb);
+  assert_eq!(emulator.bootloader(), None);
+}
+
+#[test]
+fn test_step_by_step() {
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "step-by-step",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv first yes" },
+      { "type": "bulkcmd", "value": "setenv skipped yes", "id": "skipped" },
+      { "type": "bulkcmdStat", "value": "printenv first" },
+      { "type": "bulkcmd", "value": "setenv last yes" }
+    ]"#,
+    &[],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .skip(["skipped"])
+    .from_directory(dir.clone())
+    .unwrap();
+  assert_eq!(flasher.step().unwrap(), FlashOutcome::Normal);
+  assert!(emulator.env().contains_key("first"));
+  assert!(!emulator.env().contains_key("last"));
+
+  // skipped steps don't count as a step of their own
+  let FlashOutcome::BulkcmdStatResult(response) = flasher.step().unwrap() else {
+    panic!("bulkcmdStat should hand back its response");
+  };
+  assert!(response.ends_with("first=yes"), "{response}");
+  assert_eq!(flasher.current_step(), 4);
+
+  assert_eq!(flasher.step().unwrap(), FlashOutcome::Normal);
+  assert_eq!(flasher.step().unwrap(), FlashOutcome::Complete);
+  assert!(flasher.report().success);
+  assert_eq!(flasher.report().steps_completed, 3);
+
+  // the rest of the flash is over, so neither runs anything again
+  assert_eq!(flasher.step().unwrap(), FlashOutcome::Complete);
+  flasher.flash().unwrap();
+  assert_eq!(flasher.report().steps_completed, 3);
+  assert!(!emulator.env().contains_key("skipped"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_step_after_failure() {
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "step-after-failure",
+    r#"[
+      { "type": "bulkcmd", "value": "not a command" },
+      { "type": "bulkcmd", "value": "setenv after yes" }
+    ]"#,
+    &[],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  assert!(matches!(
+    flasher.step(),
+    Err(Error::BulkCmdFailed(_)) | Err(Error::Context { .. })
+  ));
+  assert!(!flasher.report().success);
+
+  // the failure sticks, so stepping on or flashing again neither succeeds nor runs the next step
+  let err = flasher.step().unwrap_err();
+  assert!(err.to_string().contains("flashing already failed"), "{err}");
+  assert!(flasher.flash().is_err());
+  assert!(!flasher.report().success);
+  assert_eq!(flasher.report().steps.len(), 1);
+  assert!(!emulator.env().contains_key("after"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_wait_for_user_input() {
+  let emulator = Emulator::new().unwrap();
+  let steps = r#"[
+      { "type": "bulkcmd", "value": "setenv first yes" },
+      { "type": "wait", "value": { "type": "userInput", "message": "Unplug the power cable" } },
+      { "type": "bulkcmd", "value": "setenv last yes" }
+    ]"#;
+  let dir = package("user-input", steps, &[]);
+
+  // step hands the message back and carries on when called again
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  assert_eq!(flasher.step().unwrap(), FlashOutcome::Normal);
+  assert_eq!(
+    flasher.step().unwrap(),
+    FlashOutcome::AwaitUserInput("Unplug the power cable".into())
+  );
+  assert!(!emulator.env().contains_key("last"));
+  assert_eq!(flasher.step().unwrap(), FlashOutcome::Normal);
+  assert_eq!(flasher.step().unwrap(), FlashOutcome::Complete);
+  assert!(flasher.report().success);
+  let _ = std::fs::remove_dir_all(&dir);
+
+  // flash stops at the wait and finishes the rest when called again
+  let emulator = Emulator::new().unwrap();
+  let dir = package("user-input", steps, &[]);
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  let err = flasher.flash().unwrap_err();
+  assert!(matches!(&err, Error::AwaitingUserInput(message) if message == "Unplug the power cable"));
+  assert_eq!(err.code(), "AWAITING_USER_INPUT");
+  assert!(emulator.env().contains_key("first"));
+  assert!(!emulator.env().contains_key("last"));
+  flasher.flash().unwrap();
+  assert!(flasher.report().success);
+  assert_eq!(emulator.env().get("last").map(String::as_str), Some("yes"));
+  let _ = std::fs::remove_dir_all(&dir);
+
+  // a repeat can't hand a prompt back, so waiting inside one is refused
+  let dir = package(
+    "user-input-repeat",
+    r#"[{ "type": "repeat", "value": { "count": 2, "steps": [{ "type": "wait", "value": { "type": "userInput", "message": "again?" } }] } }]"#,
+    &[],
+  );
+  let result = Flasher::builder().target(emulator.target()).from_directory(dir.clone());
+  assert!(matches!(result, Err(Error::InvalidOperation(message)) if message.contains("inside a repeat")));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_into_steps() {
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "into-steps",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv first yes" },
+      { "type": "bulkcmd", "value": "setenv confirmed yes", "id": "confirm" },
+      { "type": "bulkcmd", "value": "setenv dropped yes" },
+      { "type": "bulkcmd", "value": "setenv last yes" }
+    ]"#,
+    &[],
+  );
+
+  let flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  let mut steps = flasher.into_steps();
+  let mut seen = Vec::new();
+  for step in steps.by_ref() {
+    seen.push(step.number());
+    match step.number() {
+      2 => {
+        assert_eq!(step.id(), Some("confirm"));
+        step.skip().unwrap();
+      }
+      // dropping a handle without running it skips the step too
+      3 => drop(step),
+      _ => assert_eq!(step.run().unwrap(), FlashOutcome::Normal),
+    }
+  }
+  assert_eq!(seen, [1, 2, 3, 4]);
+
+  let flasher = steps.into_flasher().unwrap();
+  let report = flasher.report();
+  assert!(report.success);
+  assert!(report.finished_at.is_some());
+  assert_eq!(report.steps_completed, 2);
+  assert_eq!(report.steps_skipped, 2);
+  let env = emulator.env();
+  assert!(env.contains_key("first") && env.contains_key("last"));
+  assert!(!env.contains_key("confirmed") && !env.contains_key("dropped"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_read_output() {
+  let emulator = Emulator::new().unwrap();
+  let data = pattern(64);
+  let dir = package(
+    "read-output",
+    r#"[
+      { "type": "writeSimpleMemory", "value": { "address": "0x1080000", "data": { "filePath": "data.bin" } } },
+      { "type": "readSimpleMemory", "value": { "address": "0x1080000", "length": 64 }, "variable": "read", "output": { "filePath": "out/${flashthing_version}/read.bin" } },
+      { "type": "readLargeMemory", "value": { "address": "0x1080000", "length": 64 }, "output": { "filePath": "large.bin" } }
+    ]"#,
+    &[("data.bin", &data)],
+  );
+  let output = dir.join("output");
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .output_dir(output.clone())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  let version = &flasher.variables()["flashthing_version"];
+  assert_eq!(
+    std::fs::read(output.join("out").join(version).join("read.bin")).unwrap(),
+    data
+  );
+  assert_eq!(std::fs::read(output.join("large.bin")).unwrap(), data);
+  assert_eq!(
+    flasher.variables()["read"],
+    data.iter().map(|b| format!("{b:02x}")).collect::<String>()
+  );
+
+  let escaping = package(
+    "read-output-escaping",
+    r#"[{ "type": "readSimpleMemory", "value": { "address": 0, "length": 4 }, "output": { "filePath": "../read.bin" } }]"#,
+    &[],
+  );
+  let builder = Flasher::builder().target(emulator.target());
+  assert!(matches!(
+    builder.from_directory(escaping.clone()),
+    Err(Error::UnsafePath(_))
+  ));
+  let _ = std::fs::remove_dir_all(&dir);
+  let _ = std::fs::remove_dir_all(&escaping);
+}
+
+#[test]
+fn test_artifacts_dir() {
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "artifacts",
+    r#"[
+      { "type": "writeSimpleMemory", "value": { "address": "0x1080000", "data": { "filePath": "data.bin" } } },
+      { "type": "readSimpleMemory", "value": { "address": "0x1080000", "length": 16 }, "output": { "filePath": "read.bin" } }
+    ]"#,
+    &[("data.bin", &pattern(16))],
+  );
+  let artifacts = dir.join("artifacts");
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .artifacts_dir(artifacts.clone())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  let read = artifacts.join(ARTIFACT_OUTPUTS).join("read.bin");
+  let report = artifacts.join(ARTIFACT_REPORT);
+  assert_eq!(flasher.artifacts(), [read.clone(), report.clone()]);
+  assert_eq!(std::fs::read(read).unwrap(), pattern(16));
+  assert!(
+    std::fs::read_to_string(report)
+      .unwrap()
+      .contains(r#""stepsCompleted": 2"#)
+  );
+  assert!(!artifacts.join(ARTIFACT_CHECKPOINT).exists());
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_record_session() {
+  let emulator = Emulator::new().unwrap();
+  let logo = pattern(4096);
+  let aml = AmlogicSoC::init_with_target(None, DeviceProfile::default(), emulator.target()).unwrap();
+  let mut recorder = Recorder::new(aml);
+  recorder.bulkcmd("setenv recorded yes").unwrap();
+  assert!(recorder.bulkcmd("definitely-not-a-command").is_err());
+  recorder.write_env("from_file=1\n").unwrap();
+  recorder.log("restoring the logo");
+  recorder.restore_partition("logo", logo.clone()).unwrap();
+  assert_eq!(recorder.steps().len(), 4);
+
+  let dir = std::env::temp_dir().join(format!("flashthing-emulator-recorded-{}", std::process::id()));
+  let _ = std::fs::remove_dir_all(&dir);
+  let config = recorder.export(&dir, "recorded", "").unwrap();
+  assert_eq!(config.steps.len(), 4);
+  assert_eq!(std::fs::read(dir.join("data/04-logo.img")).unwrap(), logo);
+  drop(recorder);
+
+  let replay = Emulator::new().unwrap();
+  let mut flasher = Flasher::builder()
+    .target(replay.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+  let env = replay.env();
+  assert_eq!(env.get("recorded").map(String::as_str), Some("yes"));
+  assert_eq!(env.get("from_file").map(String::as_str), Some("1"));
+  assert_eq!(replay.read_partition("logo", logo.len()).unwrap(), logo);
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_export_script() {
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "script",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv note 'it works'", "id": "note" },
+      { "type": "restorePartition", "value": { "name": "logo", "data": { "filePath": "logo.img" } } },
+      { "type": "log", "value": "done", "id": "done" }
+    ]"#,
+    &[("logo.img", &pattern(16))],
+  );
+  let flasher = Flasher::builder()
+    .target(emulator.target())
+    .skip(["done"])
+    .from_directory(dir.clone())
+    .unwrap();
+
+  let script = flasher.export_script();
+  assert!(script.starts_with("#!/bin/sh\n"));
+  assert!(script.contains("# step 1 (note)\nupdate bulkcmd 'setenv note '\\''it works'\\'''\n"));
+  assert!(script.contains("update partition logo logo.img\n"));
+  assert!(script.contains("# skipped:\n# echo done\n"));
+  assert!(
+    emulator
+      .commands()
+      .iter()
+      .all(|command| !command.contains("setenv note"))
+  );
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_verify_boot() {
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "boots",
+    r#"[{ "type": "bulkcmd", "value": "setenv bootdelay 0" }]"#,
+    &[],
+  );
+  let booted = Arc::new(Mutex::new(Vec::new()));
+  let seen = booted.clone();
+  let callback: flashthing::Callback = Arc::new(move |event| match event {
+    Event::BootVerified { .. } => seen.lock().unwrap().push(true),
+    Event::BootTimeout { .. } => seen.lock().unwrap().push(false),
+    _ => {}
+  });
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .callback(Some(callback.clone()))
+    .verify_boot(Duration::from_secs(5))
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+  // the package left the device in USB burn mode, so it was reset to boot it
+  assert_eq!(emulator.mode(), DeviceMode::Normal);
+  assert!(matches!(flasher.report().boot, Some(BootOutcome::Verified { .. })));
+  drop(flasher);
+
+  // a device that comes back in USB burn mode did not boot, but the flash still succeeded
+  emulator.power_on(DeviceMode::UsbBurn);
+  std::fs::write(
+    dir.join("meta.json"),
+    r#"{ "name": "boots", "version": "1.0.0", "description": "", "metadataVersion": 1,
+      "steps": [{ "type": "reset", "value": { "mode": "burn" } }] }"#,
+  )
+  .unwrap();
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .callback(Some(callback))
+    .verify_boot(Duration::from_secs(1))
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+  assert_eq!(flasher.report().boot, Some(BootOutcome::Timeout { timeout_ms: 1000 }));
+  assert!(
+    flasher
+      .report()
+      .warnings
+      .iter()
+      .any(|warning| warning.contains("UsbBurn"))
+  );
+  assert_eq!(*booted.lock().unwrap(), [true, false]);
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_check_update() {
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "versioned",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv firmware_name versioned" },
+      { "type": "bulkcmd", "value": "setenv firmware_version 1.0.0" },
+      { "type": "bulkcmd", "value": "saveenv" }
+    ]"#,
+    &[],
+  );
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  assert_eq!(flasher.check_update().unwrap(), UpdateCheck::NotRecorded);
+  flasher.flash().unwrap();
+  assert_eq!(flasher.check_update().unwrap(), UpdateCheck::Same);
+  drop(flasher);
+
+  let aml = AmlogicSoC::init_with_target(None, DeviceProfile::default(), emulator.target()).unwrap();
+  let installed = aml.read_installed_firmware_info().unwrap().unwrap();
+  assert_eq!(installed.name.as_deref(), Some("versioned"));
+  assert_eq!((installed.version.as_str(), installed.channel), ("1.0.0", None));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_start_at_and_skip() {
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "partial",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv first yes" },
+      { "type": "bulkcmd", "value": "setenv second yes", "id": "second" },
+      { "type": "bulkcmd", "value": "setenv third yes", "id": "third" },
+      { "type": "bulkcmd", "value": "setenv fourth yes" }
+    ]"#,
+    &[],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .start_at("second")
+    .skip(["third"])
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  let report = flasher.report();
+  assert_eq!((report.steps_completed, report.steps_skipped), (2, 2));
+  assert_eq!(report.steps.iter().map(|step| step.index).collect::<Vec<_>>(), [2, 4]);
+  let env = emulator.env();
+  assert!(!env.contains_key("first") && !env.contains_key("third"));
+  assert_eq!((env["second"].as_str(), env["fourth"].as_str()), ("yes", "yes"));
+
+  let missing = Flasher::builder()
+    .target(emulator.target())
+    .start_at("fifth")
+    .from_directory(dir.clone());
+  assert!(missing.is_err());
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_assert_steps() {
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "assert",
+    r#"[
+      { "type": "identify", "variable": "version" },
+      { "type": "assert", "value": { "variable": "version", "equals": "0-7-0-16-0-0-0-0" } },
+      { "type": "bulkcmd", "value": "setenv active_slot _a" },
+      { "type": "bulkcmd", "value": "setenv checked yes" },
+      { "type": "bulkcmdStat", "value": "printenv checked", "variable": "checked" },
+      { "type": "assert", "value": { "variable": "checked", "matches": "checked=(yes|no)$" } },
+      { "type": "assert", "value": { "bulkcmd": "printenv active_slot", "contains": "_b", "message": "expected slot b" } },
+      { "type": "bulkcmd", "value": "setenv unreachable yes" }
+    ]"#,
+    &[],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  let err = flasher.flash().unwrap_err();
+
+  assert!(matches!(err.root(), Error::AssertionFailed(_)), "{err:?}");
+  assert_eq!(
+    err.to_string(),
+    r#"step 7 (assert): assertion failed: expected slot b (`printenv active_slot` is "success active_slot=_a", expected it to contain "_b")"#
+  );
+  assert_eq!(flasher.report().steps_completed, 6);
+  assert_eq!(flasher.variables()["checked"], "success checked=yes");
+  assert!(!emulator.env().contains_key("unreachable"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_capture_into_later_write() {
+  let emulator = Emulator::new().unwrap();
+  let offset = DeviceProfile::default()
+    .partitions
+    .resolve("logo")
+    .unwrap()
+    .offset_bytes();
+  let logo = pattern(64 * 1024);
+  let steps = format!(
+    r#"[
+      {{ "type": "bulkcmd", "value": "setenv logo_start {offset:#x}" }},
+      {{ "type": "bulkcmdStat", "value": "printenv logo_start", "capture": "=(?P<logo>0x[0-9a-f]+)$" }},
+      {{ "type": "writeLargeMemory", "value": {{ "address": "${{logo}} + 0x1000", "data": {{ "filePath": "logo.img" }}, "blockLength": 4096 }} }},
+      {{ "type": "bulkcmd", "value": "setenv written ${{logo}}" }}
+    ]"#
+  );
+  let dir = package("capture", &steps, &[("logo.img", &logo)]);
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  assert_eq!(flasher.variables()["logo"], format!("{offset:#x}"));
+  assert_eq!(emulator.read_disk(offset as u64 + 0x1000, logo.len()).unwrap(), logo);
+  assert_eq!(emulator.env()["written"], format!("{offset:#x}"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_env_diff_and_validation() {
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "env-diff",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv bootdelay 5" },
+      { "type": "bulkcmd", "value": "setenv board superbird" },
+      { "type": "writeEnv", "value": { "filePath": "env.txt" } }
+    ]"#,
+    &[("env.txt", b"# defaults\nbootdelay=1\nboard=superbird\nserial=abc\n")],
+  );
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .diff_env()
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  let changes = &flasher.report().env_changes;
+  assert_eq!(
+    changes
+      .iter()
+      .map(|change| (change.name.as_str(), change.before.as_deref(), change.after.as_str()))
+      .collect::<Vec<_>>(),
+    [("bootdelay", Some("5"), "1"), ("serial", None, "abc")]
+  );
+  assert_eq!(emulator.env()["bootdelay"], "1");
+  let _ = std::fs::remove_dir_all(&dir);
+
+  // a name set twice fails before anything is written
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "env-duplicate",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv started yes" },
+      { "type": "writeEnv", "value": { "filePath": "env.txt" } }
+    ]"#,
+    &[("env.txt", b"bootdelay=1\nbootcmd=run storeboot\nbootdelay=0\n")],
+  );
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  let err = flasher.flash().unwrap_err();
+  assert!(
+    err
+      .to_string()
+      .contains("env.txt:3: `bootdelay` is already set on line 1"),
+    "{err}"
+  );
+  assert!(!emulator.env().contains_key("started"));
+  let _ = std::fs::remove_dir_all(&dir);
+
+  // so does an env larger than the 64 KiB U-Boot saves, even though the env partition is 8 MiB
+  let emulator = Emulator::new().unwrap();
+  let big = format!("bootdelay=1\nsplash={}\n", "a".repeat(64 * 1024));
+  let dir = package(
+    "env-oversized",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv started yes" },
+      { "type": "writeEnv", "value": { "filePath": "env.txt" } }
+    ]"#,
+    &[("env.txt", big.as_bytes())],
+  );
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  let err = flasher.flash().unwrap_err();
+  assert!(err.to_string().contains("but only 65536 fit"), "{err}");
+  assert!(!emulator.env().contains_key("started"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_host_facts_in_env() {
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "facts",
+    r#"[
+      { "type": "log", "value": "flashing from ${host_os} for ${env_CARGO_PKG_NAME}" },
+      { "type": "writeEnv", "value": "flashed_at=${flash_date}\nflashed_by=${env_CARGO_PKG_NAME}\nflashed_with=${flashthing_version}\nbootcmd=run ${storeboot}\n" }
+    ]"#,
+    &[],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .env_variables(["CARGO_PKG_NAME"])
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  let env = emulator.env();
+  let variables = flasher.variables();
+  assert_eq!(env["flashed_by"], env!("CARGO_PKG_NAME"));
+  assert_eq!(env["flashed_at"], variables["flash_date"]);
+  assert_eq!(env["flashed_with"], variables["flashthing_version"]);
+  assert_eq!(env["bootcmd"], "run ${storeboot}");
+
+  // without allowing it, the environment variable stays out of reach
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  let err = flasher.flash().unwrap_err();
+  assert!(err.to_string().contains("env_CARGO_PKG_NAME"), "{err}");
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_write_boot_script() {
+  let emulator = Emulator::new().unwrap();
+  let script = "setenv bootargs console=ttyS0,115200 root=${root}\nbooti ${loadaddr} - ${fdtaddr}\n";
+  let dir = package(
+    "bootscript",
+    r#"[
+      { "type": "writeBootScript", "value": { "script": { "filePath": "boot.txt" }, "partition": "misc", "offsetInPartition": "0x200" } }
+    ]"#,
+    &[("boot.txt", script.as_bytes())],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+
+  let image = emulator.read_partition("misc", 1024).unwrap();
+  assert!(image[..0x200].iter().all(|byte| *byte == 0));
+  let image = &image[0x200..];
+  assert_eq!(image[..4], [0x27, 0x05, 0x19, 0x56]);
+  assert_eq!(&image[32..43], b"boot script");
+  assert_eq!(image[64..68], (script.len() as u32).to_be_bytes());
+  // `${...}` that are not package variables are left for U-Boot to expand
+  assert_eq!(&image[72..72 + script.len()], script.as_bytes());
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_invalid_image_fails_preflight() {
+  let emulator = Emulator::new().unwrap();
+  let mut kernel = vec![0x27, 0x05, 0x19, 0x56];
+  kernel.resize(4096, 0xaa);
+  let dir = package(
+    "badimage",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv started yes" },
+      { "type": "restorePartition", "value": { "name": "boot_a", "data": { "filePath": "boot.img" } } }
+    ]"#,
+    &[("boot.img", &kernel)],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  let err = flasher.flash().unwrap_err();
+  assert_eq!(err.code(), "INVALID_IMAGE");
+  assert!(err.to_string().contains("boot.img: uImage header checksum"), "{err}");
+  assert!(!emulator.env().contains_key("started"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_tarball_rootfs_fails_preflight() {
+  let emulator = Emulator::new().unwrap();
+  let mut tarball = vec![0; 4096];
+  tarball[257..262].copy_from_slice(b"ustar");
+  let dir = package(
+    "tarball",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv started yes" },
+      { "type": "restorePartition", "value": { "name": "system_a", "data": { "filePath": "rootfs.tar" } } }
+    ]"#,
+    &[("rootfs.tar", &tarball)],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  let err = flasher.flash().unwrap_err();
+  assert_eq!(err.code(), "INVALID_IMAGE");
+  assert!(err.to_string().contains("rootfs.tar: is a tar archive"), "{err}");
+  assert!(!emulator.env().contains_key("started"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_disk_image_layout_fails_preflight() {
+  let emulator = Emulator::new().unwrap();
+  // a GPT with every superbird partition but env
+  let mut disk = vec![0; 3 * 1024 * 1024];
+  disk[512..520].copy_from_slice(b"EFI PART");
+  disk[512 + 72..512 + 80].copy_from_slice(&2u64.to_le_bytes());
+  disk[512 + 80..512 + 84].copy_from_slice(&128u32.to_le_bytes());
+  disk[512 + 84..512 + 88].copy_from_slice(&128u32.to_le_bytes());
+  let table = DeviceProfile::superbird().partitions;
+  for (index, part) in table.iter().filter(|part| part.name != "env").enumerate() {
+    let entry = &mut disk[1024 + index * 128..][..128];
+    entry[..16].fill(0xaa);
+    entry[32..40].copy_from_slice(&(part.offset as u64).to_le_bytes());
+    entry[40..48].copy_from_slice(&((part.offset + part.size) as u64 - 1).to_le_bytes());
+    for (unit, at) in part.name.encode_utf16().zip((56..).step_by(2)) {
+      entry[at..at + 2].copy_from_slice(&unit.to_le_bytes());
+    }
+  }
+  let dir = package(
+    "disklayout",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv started yes" },
+      { "type": "writeUserArea", "value": { "lba": 0, "data": { "filePath": "disk.img" } } }
+    ]"#,
+    &[("disk.img", &disk)],
+  );
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  let err = flasher.flash().unwrap_err();
+  assert_eq!(err.code(), "INVALID_IMAGE");
+  assert!(
+    err
+      .to_string()
+      .contains("disk.img: its GPT partition table does not match the superbird layout: missing env partition"),
+    "{err}"
+  );
+  assert!(!emulator.env().contains_key("started"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_provision_from_counter() {
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "provision",
+    r#"[
+      {
+        "type": "provision",
+        "value": {
+          "variables": ["serial", "mac"],
+          "env": { "serialno": "${serial}", "ethaddr": "${mac}" },
+          "data": { "partition": "logo", "offsetInPartition": "0x1000", "text": "serial=${serial}\n" }
+        }
+      },
+      { "type": "bulkcmd", "value": "setenv label ${serial}" },
+      { "type": "bulkcmd", "value": "saveenv" }
+    ]"#,
+    &[],
+  );
+  let counter = dir.join("counter.txt");
+  std::fs::write(&counter, "serial=CT0099\nmac=02:00:00:00:00:ff\n").unwrap();
+
+  for (serial, mac) in [("CT0099", "02:00:00:00:00:ff"), ("CT0100", "02:00:00:00:01:00")] {
+    let mut flasher = Flasher::builder()
+      .target(emulator.target())
+      .provisioner(Provisioner::Counter(counter.clone()))
+      .from_directory(dir.clone())
+      .unwrap();
+    flasher.flash().unwrap();
+
+    let env = emulator.env();
+    assert_eq!(env["serialno"], serial);
+    assert_eq!(env["ethaddr"], mac);
+    assert_eq!(env["label"], serial);
+    let text = format!("serial={}\n", serial);
+    let logo = emulator.read_partition("logo", 0x1000 + text.len()).unwrap();
+    assert_eq!(&logo[0x1000..], text.as_bytes());
+  }
+  assert_eq!(
+    std::fs::read_to_string(&counter).unwrap(),
+    "serial=CT0101\nmac=02:00:00:00:01:01\n"
+  );
+
+  // without a provisioner the step fails, and the counter stays as it was
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  let err = flasher.flash().unwrap_err();
+  assert!(err.to_string().contains("provisioner"), "{err}");
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_device_overrides() {
+  let dir = package(
+    "overrides",
+    "[]",
+    &[(
+      "overrides.json",
+      br#"{ "devices": { "8RBC24A0001": { "slot": "b", "calibration": "0x1c" } } }"#,
+    )],
+  );
+  std::fs::write(
+    dir.join("meta.json"),
+    r#"{
+      "name": "overrides", "version": "1.0.0", "description": "", "metadataVersion": 1,
+      "variables": { "slot": 0, "calibration": 0 },
+      "steps": [{ "type": "bulkcmd", "value": "setenv fleet ${slot}-${calibration}" }]
+    }"#,
+  )
+  .unwrap();
+
+  let ledger = dir.join("wear.json");
+  let flash = |emulator: &Emulator, builder: flashthing::FlasherBuilder| {
+    let mut flasher = builder
+      .target(emulator.target())
+      .wear_ledger(Some(ledger.clone()))
+      .from_directory(dir.clone())
+      .unwrap();
+    flasher.flash().unwrap();
+    emulator.env()["fleet"].clone()
+  };
+
+  let listed = Emulator::builder().serial("8RBC24A0001").build().unwrap();
+  assert_eq!(flash(&listed, Flasher::builder()), "b-0x1c");
+  let unlisted = Emulator::builder().serial("8RBC24A0002").build().unwrap();
+  assert_eq!(flash(&unlisted, Flasher::builder()), "0-0");
+
+  // overrides given to the builder replace the package's
+  let given = Overrides::parse(r#"{ "devices": { "8RBC24A0002": { "slot": "a" } } }"#).unwrap();
+  assert_eq!(flash(&unlisted, Flasher::builder().overrides(given)), "a-0");
+  let wear = flashthing::WearLedger::load(&ledger).unwrap();
+  assert_eq!(wear.device("8RBC24A0001").map(|wear| wear.sessions), Some(1));
+  assert_eq!(wear.device("8RBC24A0002").map(|wear| wear.sessions), Some(2));
+
+  let typo = Overrides::parse(r#"{ "devices": { "8RBC24A0002": { "solt": "a" } } }"#).unwrap();
+  let builder = Flasher::builder().target(unlisted.target()).overrides(typo);
+  assert!(builder.from_directory(dir.clone()).is_err());
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_env_template() {
+  let env =
+    b"@if slot == b\nboot_slot=_b\n@else\nboot_slot=_a\n@end\nloadaddr=${LOAD_ADDR}\nbootcmd=run ${storeboot}\n";
+  let dir = package(
+    "template",
+    "[]",
+    &[
+      ("env.txt", env),
+      (
+        "overrides.json",
+        br#"{ "devices": { "8RBC24A0001": { "slot": "b" } } }"#,
+      ),
+    ],
+  );
+  std::fs::write(
+    dir.join("meta.json"),
+    r#"{
+      "name": "template", "version": "1.0.0", "description": "", "metadataVersion": 1,
+      "variables": { "slot": 0 },
+      "constants": { "LOAD_ADDR": 17301504 },
+      "steps": [{ "type": "writeEnv", "value": { "filePath": "env.txt", "template": true } }]
+    }"#,
+  )
+  .unwrap();
+
+  let flash = |emulator: &Emulator| {
+    let mut flasher = Flasher::builder()
+      .target(emulator.target())
+      .wear_ledger(None)
+      .from_directory(dir.clone())
+      .unwrap();
+    flasher.flash().unwrap();
+    emulator.env()
+  };
+  let listed = flash(&Emulator::builder().serial("8RBC24A0001").build().unwrap());
+  assert_eq!(listed["boot_slot"], "_b");
+  assert_eq!(listed["loadaddr"], "0x1080000");
+  assert_eq!(listed["bootcmd"], "run ${storeboot}");
+  let unlisted = flash(&Emulator::builder().serial("8RBC24A0002").build().unwrap());
+  assert_eq!(unlisted["boot_slot"], "_a");
+  let _ = std::fs::remove_dir_all(&dir);
+
+  // a template missing an @end fails before anything is written
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "template-unbalanced",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv started yes" },
+      { "type": "writeEnv", "value": { "filePath": "env.txt", "template": true } }
+    ]"#,
+    &[("env.txt", b"@if slot\nboot_slot=_b\n")],
+  );
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .from_directory(dir.clone())
+    .unwrap();
+  let err = flasher.flash().unwrap_err();
+  assert!(err.to_string().contains("env.txt: 1 @if without an @end"), "{err}");
+  assert!(!emulator.env().contains_key("started"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_report_hook() {
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "report-hook",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv first yes" },
+      { "type": "bulkcmd", "value": "not a command" }
+    ]"#,
+    &[],
+  );
+
+  let reports = Arc::new(Mutex::new(Vec::new()));
+  let hook_reports = reports.clone();
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .report_hook(Arc::new(move |report: &FlashReport| {
+      hook_reports.lock().unwrap().push(report.clone())
+    }))
+    .from_directory(dir.clone())
+    .unwrap();
+  assert!(flasher.flash().is_err());
+
+  // the hook gets the finished report of a failed flash too
+  let reports = reports.lock().unwrap();
+  assert_eq!(reports.len(), 1);
+  assert!(!reports[0].success);
+  assert_eq!(reports[0].steps_completed, 1);
+  assert!(reports[0].finished_at.is_some());
+  assert_eq!(reports[0].error, flasher.report().error);
+  assert_eq!(reports[0].error_code.as_deref(), Some("INVALID_OPERATION"));
+  assert_eq!(reports[0].steps[1].error_code.as_deref(), Some("INVALID_OPERATION"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_cancel_between_chunks() {
+  let emulator = Emulator::new().unwrap();
+  let logo = pattern(3 * 1024 * 1024);
+  let dir = package(
+    "cancel",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv first yes" },
+      { "id": "logo", "type": "writeLargeMemory", "value": { "partition": "logo", "data": { "filePath": "logo.img" }, "blockLength": 4096 } },
+      { "type": "bulkcmd", "value": "setenv last yes" }
+    ]"#,
+    &[("logo.img", &logo)],
+  );
+
+  // cancel as soon as the first chunk is written, like a Ctrl-C in the middle of the step
+  let token = CancelToken::new();
+  let callback_token = token.clone();
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .profile(DeviceProfile {
+      max_transfer_size: 1024 * 1024,
+      ..DeviceProfile::default()
+    })
+    .callback(Some(Arc::new(move |event| {
+      if let Event::FlashProgress(_) = event {
+        callback_token.cancel();
+      }
+    })))
+    .cancel_token(token)
+    .from_directory(dir.clone())
+    .unwrap();
+  assert!(matches!(flasher.flash(), Err(Error::Cancelled)));
+
+  let report = flasher.report();
+  assert_eq!(report.steps_completed, 1);
+  assert_eq!(report.resume_at.as_deref(), Some("logo"));
+  assert_eq!(report.bytes_written, 1024 * 1024);
+  assert_eq!(
+    emulator.read_partition("logo", 1024 * 1024).unwrap(),
+    logo[..1024 * 1024]
+  );
+  assert!(!emulator.env().contains_key("last"));
+  assert_eq!(report.resume_offset, Some(1024 * 1024));
+
+  // carrying on writes only the rest of the step
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .profile(DeviceProfile {
+      max_transfer_size: 1024 * 1024,
+      ..DeviceProfile::default()
+    })
+    .start_at("logo")
+    .resume_offset(1024 * 1024)
+    .from_directory(dir.clone())
+    .unwrap();
+  flasher.flash().unwrap();
+  let report = flasher.report();
+  assert_eq!(report.bytes_written, 2 * 1024 * 1024);
+  assert_eq!(report.resume_at, None);
+  assert_eq!(emulator.read_partition("logo", logo.len()).unwrap(), logo);
+  assert_eq!(emulator.env().get("last").map(String::as_str), Some("yes"));
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_adb_mode_switch() {
+  let missing = Adb::new().program("/nonexistent/adb");
+  let timeout = Duration::from_secs(1);
+
+  // a device already in burn mode is left alone, so adb is never run
+  let burn = Emulator::new().unwrap();
+  let mode = AmlogicSoC::request_burn_mode_via_adb(&missing, &DeviceProfile::default(), &burn.target(), timeout);
+  assert_eq!(mode.unwrap(), DeviceMode::UsbBurn);
+
+  let normal = Emulator::builder().mode(DeviceMode::Normal).build().unwrap();
+  let mode = AmlogicSoC::request_burn_mode_via_adb(&missing, &DeviceProfile::default(), &normal.target(), timeout);
+  assert!(matches!(mode, Err(Error::WrongMode)));
+  assert_eq!(normal.mode(), DeviceMode::Normal);
+}
+
+#[test]
+fn test_wait_for_usb_mode() {
+  let emulator = Emulator::builder().mode(DeviceMode::Normal).build().unwrap();
+  let actions = Arc::new(Mutex::new(Vec::new()));
+  let seen = actions.clone();
+  let callback: flashthing::Callback = Arc::new(move |event| {
+    if let Event::ActionRequired { kind, instructions } = event {
+      assert!(!instructions.is_empty());
+      seen.lock().unwrap().push(kind);
+    }
+  });
+
+  let timeout = Duration::from_millis(600);
+  let mode = AmlogicSoC::wait_for_usb_mode(Some(&callback), &DeviceProfile::default(), &emulator.target(), timeout);
+  assert!(matches!(mode, Err(Error::WrongMode)));
+  assert_eq!(*actions.lock().unwrap(), [ActionKind::HoldButtons]);
+
+  // the user restarts the device with the buttons held while the flasher waits
+  let user = emulator.clone();
+  let restart = std::thread::spawn(move || {
+    std::thread::sleep(Duration::from_millis(300));
+    user.power_on(DeviceMode::UsbBurn);
+  });
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .callback(Some(callback))
+    .wait_for_device(Duration::from_secs(10))
+    .from_json(
+      r#"{ "name": "wait", "version": "1.0.0", "description": "", "metadataVersion": 1,
+          "steps": [{ "type": "bulkcmd", "value": "setenv waited yes" }] }"#
+        .into(),
+    )
+    .unwrap();
+  restart.join().unwrap();
+  flasher.flash().unwrap();
+
+  assert_eq!(emulator.env().get("waited").map(String::as_str), Some("yes"));
+  assert_eq!(
+    *actions.lock().unwrap(),
+    [ActionKind::HoldButtons, ActionKind::HoldButtons]
+  );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_post_boot_check() {
+  use std::os::unix::fs::PermissionsExt;
+
+  let emulator = Emulator::new().unwrap();
+  let dir = package(
+    "smoke",
+    r#"[
+      { "type": "bulkcmd", "value": "setenv bootdelay 0" },
+      { "type": "postBootCheck", "value": { "timeout": 5000, "checks": [
+        { "command": "systemctl is-system-running", "equals": "running" },
+        { "command": "uname -r", "matches": "^6\\." },
+        { "command": "test -e /etc/flashed" }
+      ] } }
+    ]"#,
+    &[],
+  );
+  // stands in for adb, talking to a booted device with a system that is up
+  let program = dir.join("adb");
+  std::fs::write(
+    &program,
+    "#!/bin/sh\ncase \"$1 $2\" in\n\
+     'devices ') printf 'List of devices attached\\n8a3c2f41\\tdevice\\n' ;;\n\
+     'shell true') ;;\n\
+     'shell systemctl is-system-running') echo running ;;\n\
+     'shell uname -r') echo 5.10.0 ;;\n\
+     *) exit 1 ;;\nesac\n",
+  )
+  .unwrap();
+  std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .adb_mode_switch(Adb::new().program(&program))
+    .from_directory(dir.clone())
+    .unwrap();
+  let err = flasher.flash().unwrap_err();
+  assert_eq!(err.code(), "ASSERTION_FAILED");
+  // the device was reset to boot it, and every check ran
+  assert_eq!(emulator.mode(), DeviceMode::Normal);
+  let checks = &flasher.report().post_boot_checks;
+  assert_eq!(
+    checks.iter().map(|check| check.passed).collect::<Vec<_>>(),
+    [true, false, false]
+  );
+  assert_eq!(checks[1].output, "5.10.0");
+  assert!(err.to_string().contains("uname -r") && err.to_string().contains("/etc/flashed"));
+}
+
+#[test]
+fn test_session_capture() {
+  use std::io::Read;
+
+  let emulator = Emulator::builder().serial("8RBC24A0100").build().unwrap();
+  let dir = package(
+    "capture",
+    r#"[
+      { "type": "restorePartition", "value": { "name": "logo", "data": { "filePath": "logo.img" } } },
+      { "type": "bulkcmd", "value": "nonexistent" }
+    ]"#,
+    &[("logo.img", &pattern(4096))],
+  );
+  let mut flasher = Flasher::builder()
+    .target(emulator.target())
+    .wear_ledger(None)
+    .from_directory(dir.clone())
+    .unwrap();
+  let path = dir.join("bug-report.zip");
+  flasher.enable_session_capture(&path);
+  assert!(flasher.flash().is_err());
+  assert_eq!(flasher.artifacts(), std::slice::from_ref(&path));
+
+  let mut zip = zip::ZipArchive::new(std::fs::File::open(&path).unwrap()).unwrap();
+  let mut read = |name: &str| {
+    let mut contents = String::new();
+    zip.by_name(name).unwrap().read_to_string(&mut contents).unwrap();
+    contents
+  };
+  // debug lines are kept whatever the subscriber logs
+  assert!(read(flashthing::CAPTURE_LOG).contains("running bulkcmd"));
+  assert!(read("report.json").contains("\"success\": false"));
+  assert!(read("meta.json").contains("\"name\": \"capture\""));
+  let files = read("files.json");
+  assert!(files.contains("logo.img") && files.contains("\"size\": 4096"));
+  assert!(read("usb.json").contains("8RBC24A0100"));
+  assert!(read("host.json").contains("flashthing_version"));
+}
@@ -0,0 +1,348 @@
+//! Reaching a device that boots normally into firmware running adbd, through the `adb` tool, so
+//! it can be put in USB burn mode or written to without holding buttons 1 & 4.
+
+use std::{
+  ffi::OsString,
+  io::{Read, Write},
+  path::PathBuf,
+  process::{Command, Output, Stdio},
+  thread::sleep,
+  time::{Duration, Instant},
+};
+
+use crate::{AmlogicSoC, DeviceMode, DeviceTarget, Error, FlashProgress, Result, profile::DeviceProfile, transport};
+
+/// Environment variable naming the `adb` binary, used instead of the one on `PATH`
+pub const ADB_ENV: &str = "ADB";
+
+/// Bytes piped to `dd` between progress reports
+const WRITE_CHUNK: usize = 1024 * 1024;
+
+/// How often to ask adb for the device while waiting for it
+const ADB_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a device rebooted through adb gets to show up in USB burn mode
+pub const ADB_REBOOT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The `adb` command line tool, talking to one device or to whichever is connected
+#[derive(Debug, Clone)]
+pub struct Adb {
+  program: PathBuf,
+  serial: Option<String>,
+}
+
+impl Default for Adb {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl Adb {
+  /// `adb` from the `ADB` environment variable, or from `PATH`
+  pub fn new() -> Self {
+    let program = std::env::var_os(ADB_ENV).unwrap_or_else(|| OsString::from("adb"));
+    Self {
+      program: program.into(),
+      serial: None,
+    }
+  }
+
+  /// Use the `adb` binary at `program`
+  pub fn program(mut self, program: impl Into<PathBuf>) -> Self {
+    self.program = program.into();
+    self
+  }
+
+  /// Talk to the device with adb serial `serial`, needed when more than one is connected
+  pub fn serial(mut self, serial: impl Into<String>) -> Self {
+    self.serial = Some(serial.into());
+    self
+  }
+
+  fn command(&self) -> Command {
+    let mut command = Command::new(&self.program);
+    if let Some(serial) = &self.serial {
+      command.arg("-s").arg(serial);
+    }
+    command
+  }
+
+  /// Run adb with `args` and return what it printed
+  fn run(&self, args: &[&str]) -> Result<String> {
+    let output = self.output(args)?;
+    if !output.status.success() {
+      return Err(Error::Adb(format!(
+        "`adb {}` failed: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr).trim()
+      )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+  }
+
+  fn output(&self, args: &[&str]) -> Result<Output> {
+    self
+      .command()
+      .args(args)
+      .stdin(Stdio::null())
+      .output()
+      .map_err(|err| Error::Adb(format!("failed to run {}: {}", self.program.display(), err)))
+  }
+
+  /// Serials of the devices adb can talk to, leaving out unauthorized and offline ones
+  ///
+  /// # Returns
+  /// - `Result<Vec<String>>`: The serials, or an error if adb could not be run
+  pub fn devices(&self) -> Result<Vec<String>> {
+    self.run(&["devices"]).map(|output| parse_devices(&output))
+  }
+
+  /// Run `command` in a shell on the device and return its output
+  ///
+  /// # Parameters
+  /// - `command`: The shell command line
+  ///
+  /// # Returns
+  /// - `Result<String>`: What the command printed, or an error if adb failed
+  pub fn shell(&self, command: &str) -> Result<String> {
+    self.run(&["shell", command])
+  }
+
+  /// Run `command` in a shell on the device, returning whether it exited successfully and its output
+  ///
+  /// Unlike [`shell`](Self::shell), a command that fails is not an error, so what it printed can
+  /// still be checked. Only failing to run adb at all is.
+  pub fn shell_status(&self, command: &str) -> Result<(bool, String)> {
+    let output = self.output(&["shell", command])?;
+    Ok((
+      output.status.success(),
+      String::from_utf8_lossy(&output.stdout).into_owned(),
+    ))
+  }
+
+  /// Reboot the device into USB burn mode
+  ///
+  /// The device drops off adb straight away and shows up in USB burn mode a few seconds later.
+  ///
+  /// # Returns
+  /// - `Result<()>`: Success once the reboot was requested, or an error if adb failed
+  pub fn reboot_to_burn_mode(&self) -> Result<()> {
+    tracing::info!("rebooting the device into usb burn mode through adb");
+    self.run(&["reboot", "update"]).map(|_| ())
+  }
+
+  /// Write `size` bytes from `reader` to the partition `name`, through `dd` on the device
+  ///
+  /// The Amlogic kernel exposes each eMMC partition as `/dev/<name>`. The firmware the device is
+  /// running must not have the partition mounted, so this is meant for the inactive system slot,
+  /// or for partitions like `logo` the running system does not touch.
+  ///
+  /// # Parameters
+  /// - `name`: The partition to write
+  /// - `reader`: A reader providing the image
+  /// - `size`: The number of bytes to take from `reader`
+  /// - `progress_callback`: Function to call with progress updates
+  ///
+  /// # Returns
+  /// - `Result<()>`: Success once the data is synced to the eMMC, or an error
+  pub fn write_partition<R: Read, F: Fn(FlashProgress)>(
+    &self,
+    name: &str,
+    mut reader: R,
+    size: usize,
+    progress_callback: F,
+  ) -> Result<()> {
+    let device = partition_device(name)?;
+    if self.shell(&format!("test -b {device} && echo present"))?.trim() != "present" {
+      return Err(Error::Adb(format!("the device has no partition {device}")));
+    }
+
+    tracing::info!("writing {} bytes to {} through adb", size, device);
+    let mut dd = self
+      .command()
+      .args(["exec-in", &format!("dd of={device} bs={WRITE_CHUNK}")])
+      .stdin(Stdio::piped())
+      .stdout(Stdio::null())
+      .stderr(Stdio::piped())
+      .spawn()
+      .map_err(|err| Error::Adb(format!("failed to run {}: {}", self.program.display(), err)))?;
+
+    let start = Instant::now();
+    let written = match dd.stdin.take() {
+      Some(mut stdin) => pipe_chunks(&mut reader, &mut stdin, size, start, &progress_callback),
+      None => Err(Error::Adb("dd has no stdin".into())),
+    };
+    // a dd that gave up breaks the pipe, so its own error explains the failure better
+    let output = dd.wait_with_output()?;
+    if !output.status.success() {
+      return Err(Error::Adb(format!(
+        "dd to {} failed: {}",
+        device,
+        String::from_utf8_lossy(&output.stderr).trim()
+      )));
+    }
+    written?;
+
+    self.shell("sync")?;
+    tracing::info!("wrote {} to {} in {:?}", size, device, start.elapsed());
+    Ok(())
+  }
+}
+
+impl AmlogicSoC {
+  /// Reboot a device found booted normally into USB burn mode through adb, and wait for it to show up
+  ///
+  /// This spares holding buttons 1 & 4 on devices whose firmware runs adbd. Devices already in
+  /// USB or USB burn mode are left alone, so it can be called before every
+  /// [`init_with_target`](Self::init_with_target).
+  ///
+  /// # Parameters
+  /// - `adb`: The adb tool to reboot the device with
+  /// - `profile`: USB IDs of the device, in burn and normal mode
+  /// - `target`: Where to look for the device
+  /// - `timeout`: How long to wait for the device to re-enumerate
+  ///
+  /// # Returns
+  /// - `Result<DeviceMode>`: The mode the device is in now, or [`Error::WrongMode`] if it is booted
+  ///   normally and could not be rebooted, or did not come back in time
+  pub fn request_burn_mode_via_adb(
+    adb: &Adb,
+    profile: &DeviceProfile,
+    target: &DeviceTarget,
+    timeout: Duration,
+  ) -> Result<DeviceMode> {
+    let mode = transport::find_device(target, profile);
+    if mode != DeviceMode::Normal {
+      return Ok(mode);
+    }
+
+    if let Err(err) = adb.reboot_to_burn_mode() {
+      tracing::error!(
+        "device is booted in normal mode and could not be rebooted through adb: {}",
+        err
+      );
+      return Err(Error::WrongMode);
+    }
+
+    let start = Instant::now();
+    loop {
+      sleep(Duration::from_millis(500));
+      match transport::find_device(target, profile) {
+        mode @ (DeviceMode::Usb | DeviceMode::UsbBurn) => {
+          tracing::info!("device came back in {:?} after {:?}", mode, start.elapsed());
+          return Ok(mode);
+        }
+        _ if start.elapsed() >= timeout => {
+          tracing::error!(
+            "device did not come back in usb burn mode within {:?} of rebooting it",
+            timeout
+          );
+          return Err(Error::WrongMode);
+        }
+        _ => {}
+      }
+    }
+  }
+}
+
+/// Wait up to `timeout` for adb to be able to run commands on the device
+///
+/// # Returns
+/// - `Result<()>`: Success once a shell on the device answers, or [`Error::Adb`] if none did in time
+pub(crate) fn wait_for_adb(adb: &Adb, timeout: Duration) -> Result<()> {
+  tracing::info!("waiting up to {:?} for the device to show up in adb", timeout);
+  let start = Instant::now();
+  loop {
+    let ready = adb.devices().is_ok_and(|devices| match &adb.serial {
+      Some(serial) => devices.contains(serial),
+      None => !devices.is_empty(),
+    });
+    if ready && adb.shell("true").is_ok() {
+      tracing::info!("device showed up in adb after {:?}", start.elapsed());
+      return Ok(());
+    }
+    if start.elapsed() >= timeout {
+      return Err(Error::Adb(format!(
+        "the device did not show up in adb within {:?}",
+        timeout
+      )));
+    }
+    sleep(ADB_POLL_INTERVAL);
+  }
+}
+
+/// Copy `size` bytes from `reader` to `writer` a chunk at a time, reporting progress after each
+fn pipe_chunks<R: Read, W: Write>(
+  reader: &mut R,
+  writer: &mut W,
+  size: usize,
+  start: Instant,
+  progress_callback: &impl Fn(FlashProgress),
+) -> Result<()> {
+  let mut buf = vec![0u8; WRITE_CHUNK];
+  let mut offset = 0;
+  while offset < size {
+    let chunk_start = Instant::now();
+    let length = WRITE_CHUNK.min(size - offset);
+    reader.read_exact(&mut buf[..length])?;
+    writer.write_all(&buf[..length])?;
+    offset += length;
+
+    let elapsed_secs = start.elapsed().as_secs_f64();
+    let chunk_secs = chunk_start.elapsed().as_secs_f64();
+    let bytes_per_sec = if elapsed_secs > 0.0 {
+      offset as f64 / elapsed_secs
+    } else {
+      offset as f64
+    };
+    progress_callback(FlashProgress {
+      percent: offset as f64 / size as f64 * 100.0,
+      elapsed: elapsed_secs * 1000.0,
+      eta: (size - offset) as f64 / bytes_per_sec.max(1.0) * 1000.0,
+      rate: length as f64 / chunk_secs.max(f64::EPSILON) / 1024.0,
+      avg_chunk_time: elapsed_secs / offset.div_ceil(WRITE_CHUNK) as f64 * 1000.0,
+      avg_rate: bytes_per_sec / 1024.0,
+      step_id: None,
+    });
+  }
+  Ok(())
+}
+
+/// Serials of the ready devices in the output of `adb devices`
+fn parse_devices(output: &str) -> Vec<String> {
+  output
+    .lines()
+    .filter_map(|line| line.split_once('\t'))
+    .filter(|(_, state)| state.trim() == "device")
+    .map(|(serial, _)| serial.trim().to_string())
+    .collect()
+}
+
+/// Block device of the partition `name`, refusing names that would need quoting in a shell
+fn partition_device(name: &str) -> Result<String> {
+  if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+    return Err(Error::InvalidOperation(format!("invalid partition name: {name:?}")));
+  }
+  Ok(format!("/dev/{name}"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_devices() {
+    let output = "* daemon started successfully\nList of devices attached\n8a3c2f41\tdevice\nemulator-5554\toffline\n\
+                  0123\tunauthorized\n\n";
+    assert_eq!(parse_devices(output), ["8a3c2f41"]);
+    assert!(parse_devices("List of devices attached\n\n").is_empty());
+  }
+
+  #[test]
+  fn test_partition_device() {
+    assert_eq!(partition_device("system_b").unwrap(), "/dev/system_b");
+    assert!(partition_device("logo; reboot").is_err());
+    assert!(partition_device("../mmcblk0").is_err());
+    assert!(partition_device("").is_err());
+  }
+}
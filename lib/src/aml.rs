@@ -1,29 +1,121 @@
-use std::{io::Read, sync::Arc, thread::sleep, time::Duration};
+use std::{
+  io::{Read, Seek, SeekFrom, Write},
+  sync::{
+    Arc, Mutex,
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+  },
+  thread::sleep,
+  time::{Duration, Instant},
+};
 
-use rusb::{Context, DeviceHandle, Direction, UsbContext};
+pub use flashthing_core::{DeviceInfo, DeviceMode, SecureBootState, Session, Transport, UsbLogLevel};
+use serde::Serialize;
+use sha2::{Digest, Sha256, Sha512};
 
+pub use crate::transport::{Connector, DeviceTarget};
 use crate::{
-  ADDR_BL2, ADDR_TMP, AMLC_AMLS_BLOCK_LENGTH, AMLC_MAX_BLOCK_LENGTH, AMLC_MAX_TRANSFER_LENGTH, BL2_BIN, BOOTLOADER_BIN,
-  Callback, Error, Event, FLAG_KEEP_POWER_ON, PART_SECTOR_SIZE, PRODUCT_ID, REQ_BULKCMD, REQ_GET_AMLC,
-  REQ_IDENTIFY_HOST, REQ_READ_MEM, REQ_RUN_IN_ADDR, REQ_WR_LARGE_MEM, REQ_WRITE_AMLC, REQ_WRITE_MEM, Result,
-  TRANSFER_BLOCK_SIZE, TRANSFER_SIZE_THRESHOLD, UNBRICK_BIN_ZIP, VENDOR_ID, flash::FlashProgress,
-  partitions::PartitionInfo,
+  Callback, CancelToken, Error, Event, PART_SECTOR_SIZE, Result,
+  buffers::{BufferPool, PooledBuffer},
+  config::ResetMode,
+  flash::FlashProgress,
+  guidance,
+  logging::TRANSFER_TARGET,
+  partitions::{PartitionInfo, canonical_partition_name},
+  profile::DeviceProfile,
+  quirks::LIMITED_BULK_TRANSFER,
+  setup::HostSetupStatus,
+  spool::Spool,
+  telemetry,
+  thermal::parse_temperature,
+  transport,
+  verify::HashAlgorithm,
 };
 
-const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time for a resetting device to drop off the bus before looking for it again
+const RECONNECT_SETTLE: Duration = Duration::from_secs(2);
+
+/// Size of the bootloader image; dumps are often zero-padded past this
+const BOOTLOADER_SIZE: usize = 2 * 1024 * 1024;
+/// Bytes read back from each bootloader copy to confirm the write landed
+const BOOTLOADER_VERIFY_SIZE: usize = 64 * 1024;
+/// Sent when a connection that wrote to the eMMC is dropped, selecting the user area again in case
+/// a write to a boot partition was cut short
+///
+/// This does not flush anything and u-boot has no command that would: its mmc writes only return
+/// once the card has finished programming, and it never enables the eMMC's write cache, so data
+/// is already on the card by the time a write is answered.
+const USER_AREA_COMMAND: &str = "mmc dev 1 0";
 
-#[derive(Debug)]
 struct AmlInner {
-  handle: DeviceHandle<Context>,
-  interface_number: u8,
-  endpoint_in: u8,
-  endpoint_out: u8,
+  session: Session,
+  profile: DeviceProfile,
+  target: DeviceTarget,
+  callback: Option<Callback>,
+  bytes_written: AtomicU64,
+  bytes_skipped: AtomicU64,
+  /// Chunk buffers reused across transfers
+  buffers: BufferPool,
+  /// Most bytes moved to memory in one large memory write, lowered when larger ones fail
+  bulk_limit: AtomicUsize,
+  /// Set once the temperature command has failed, so it is not retried after every chunk
+  temperature_unavailable: AtomicBool,
+  /// Stops long writes between chunks once cancelled
+  cancel: Mutex<Option<CancelToken>>,
+  /// Byte of its data the next streaming write to the eMMC starts at, to carry on a cancelled one
+  resume_at: AtomicU64,
+  /// Bytes of the data of the last streaming write to the eMMC that are on the device
+  streamed: AtomicU64,
+  /// Command writing the chunk staged in DDR to the eMMC, kept while its last attempt failed and
+  /// nothing has touched the staging area since
+  staged: Mutex<Option<String>>,
+  /// Set once anything was written to the eMMC, so dropping the connection selects the user area
+  wrote: AtomicBool,
+}
+
+impl Drop for AmlInner {
+  fn drop(&mut self) {
+    let staged = self
+      .staged
+      .get_mut()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+      .take();
+    if (staged.is_none() && !*self.wrote.get_mut()) || self.session.session_lost().is_some() {
+      return;
+    }
+
+    // this also runs while unwinding, so failures are only logged and the deadline no longer applies
+    self.session.set_deadline(None);
+    if let Some(command) = staged {
+      tracing::warn!("writing the chunk left staged in memory before disconnecting");
+      if let Err(err) = self.session.bulkcmd(&command) {
+        tracing::warn!("failed to write the staged chunk ({}): {}", command, err);
+      }
+    }
+    match self.session.bulkcmd(USER_AREA_COMMAND) {
+      Ok(_) => tracing::debug!("selected the eMMC user area before disconnecting"),
+      Err(err) => tracing::warn!("failed to select the eMMC user area before disconnecting: {}", err),
+    }
+  }
+}
+
+/// Bytes written to (and skipped on) the eMMC over the lifetime of a connection
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct WriteStats {
+  /// Bytes actually written to the eMMC
+  pub bytes_written: u64,
+  /// Bytes that already matched on the device and were not rewritten
+  pub bytes_skipped: u64,
 }
 
 /// The main interface for interacting with Amlogic-based hardware
 ///
 /// This provides low-level access to the Amlogic SoC on the Superbird device,
 /// allowing for memory operations, partition management, and firmware flashing.
+///
+/// When the last clone is dropped, even while unwinding, a chunk whose write to the eMMC failed
+/// is written once more and, if anything was written, the eMMC user area is selected again before
+/// the USB interface is released.
 #[derive(Clone)]
 pub struct AmlogicSoC {
   inner: Arc<AmlInner>,
@@ -41,11 +133,36 @@ impl AmlogicSoC {
   /// # Returns
   /// - `Result<Self>`: A connected AmlogicSoC instance or an error
   pub fn init(callback: Option<Callback>) -> Result<Self> {
+    Self::init_with_profile(callback, DeviceProfile::default())
+  }
+
+  /// Initialize a connection to an Amlogic SoC device described by `profile`
+  ///
+  /// # Parameters
+  /// - `callback`: Optional callback function to receive status updates
+  /// - `profile`: USB IDs, memory layout, and boot blobs of the device
+  ///
+  /// # Returns
+  /// - `Result<Self>`: A connected AmlogicSoC instance or an error
+  pub fn init_with_profile(callback: Option<Callback>, profile: DeviceProfile) -> Result<Self> {
+    Self::init_with_target(callback, profile, DeviceTarget::Usb)
+  }
+
+  /// Initialize a connection to the device described by `profile` through `target`
+  ///
+  /// # Parameters
+  /// - `callback`: Optional callback function to receive status updates
+  /// - `profile`: USB IDs, memory layout, and boot blobs of the device
+  /// - `target`: A USB device, or a disk image standing in for one
+  ///
+  /// # Returns
+  /// - `Result<Self>`: A connected AmlogicSoC instance or an error
+  pub fn init_with_target(callback: Option<Callback>, profile: DeviceProfile, target: DeviceTarget) -> Result<Self> {
     if let Some(callback) = &callback {
       callback(Event::FindingDevice);
     };
 
-    let mode = find_device();
+    let mode = transport::find_device(&target, &profile);
     if let Some(callback) = &callback {
       callback(Event::DeviceMode(mode));
     };
@@ -53,7 +170,7 @@ impl AmlogicSoC {
     match mode {
       DeviceMode::Usb => {
         tracing::info!("device booted in usb mode - moving to usb burn mode");
-        let device = Self::connect(callback.clone())?;
+        let device = Self::connect(callback.clone(), profile.clone(), target.clone())?;
         if let Some(callback) = &callback {
           callback(Event::Bl2Boot);
         };
@@ -73,17 +190,19 @@ impl AmlogicSoC {
         tracing::error!(
           "device is booted in normal mode. make sure to power on the car thing while holding buttons 1 & 4"
         );
+        guidance::request_action(callback.as_ref(), mode);
         return Err(Error::WrongMode);
       }
       DeviceMode::NotFound => {
         tracing::error!("device not found!! make sure to power on the car thing while holding buttons 1 & 4");
+        guidance::request_action(callback.as_ref(), mode);
         return Err(Error::NotFound);
       }
     };
 
     let mut attempts = 0;
     while attempts < 3 {
-      match Self::connect(callback.clone()) {
+      match Self::connect(callback.clone(), profile.clone(), target.clone()) {
         Ok(dev) => return Ok(dev),
         Err(e) => {
           tracing::debug!("failed to connect to device: {}. Attempt {}/3", e, attempts + 1);
@@ -93,70 +212,288 @@ impl AmlogicSoC {
       }
     }
 
-    Self::connect(callback)
+    Self::connect(callback, profile, target)
   }
 
-  fn connect(callback: Option<Callback>) -> Result<Self> {
+  fn connect(callback: Option<Callback>, profile: DeviceProfile, target: DeviceTarget) -> Result<Self> {
     tracing::debug!("connecting to Amlogic device");
     if let Some(callback) = &callback {
       callback(Event::Connecting);
     };
 
-    let context = Context::new()?;
-    let handle = {
-      let device = context
-        .devices()?
-        .iter()
-        .find(|device| {
-          if let Ok(desc) = device.device_descriptor() {
-            desc.vendor_id() == VENDOR_ID && desc.product_id() == PRODUCT_ID
-          } else {
-            false
-          }
-        })
-        .ok_or_else(|| Error::InvalidOperation("Device not found".into()))?;
-      device.open()?
-    };
-
-    handle.set_active_configuration(1)?;
-    let interface_number: u8 = 0;
-    handle.claim_interface(interface_number)?;
-
-    let device = handle.device();
-    let config_desc = device.active_config_descriptor()?;
-    let interface = config_desc
-      .interfaces()
-      .find(|i| i.number() == interface_number)
-      .ok_or_else(|| Error::InvalidOperation("Interface not found".into()))?;
-    let descriptor = interface
-      .descriptors()
-      .next()
-      .ok_or_else(|| Error::InvalidOperation("No alt setting".into()))?;
-    let mut endpoint_in = None;
-    let mut endpoint_out = None;
-    for ep in descriptor.endpoint_descriptors() {
-      match ep.direction() {
-        Direction::In => endpoint_in = Some(ep.address()),
-        Direction::Out => endpoint_out = Some(ep.address()),
-      }
-    }
-    let endpoint_in = endpoint_in.ok_or_else(|| Error::InvalidOperation("IN endpoint not found".into()))?;
-    let endpoint_out = endpoint_out.ok_or_else(|| Error::InvalidOperation("OUT endpoint not found".into()))?;
-    tracing::info!("device connected, claiming interface {}", interface_number);
+    let (transport, info) = transport::open(&target, &profile)?;
+    tracing::info!("device connected on interface {}", info.interface_number);
     if let Some(callback) = &callback {
       callback(Event::Connected);
     };
 
+    let bulk_limit = match profile.usb_quirks.limited_bulk {
+      true => LIMITED_BULK_TRANSFER.min(profile.max_transfer_size),
+      false => profile.max_transfer_size,
+    };
+    let session = Session::new(transport, info);
+    session.set_max_urb_size(profile.usb_quirks.max_urb_size);
+    let stall_callback = callback.clone();
+    session.set_transfer_watchdog(profile.usb_quirks.transfer_watchdog, move |endpoint, stalled_for| {
+      if let Some(callback) = &stall_callback {
+        callback(Event::TransferStalled { endpoint, stalled_for });
+      }
+    });
     Ok(Self {
       inner: Arc::new(AmlInner {
-        handle,
-        interface_number,
-        endpoint_in,
-        endpoint_out,
+        session,
+        profile,
+        target,
+        callback,
+        bytes_written: AtomicU64::new(0),
+        bytes_skipped: AtomicU64::new(0),
+        buffers: BufferPool::default(),
+        bulk_limit: AtomicUsize::new(bulk_limit),
+        cancel: Mutex::new(None),
+        resume_at: AtomicU64::new(0),
+        streamed: AtomicU64::new(0),
+        staged: Mutex::new(None),
+        wrote: AtomicBool::new(false),
+        temperature_unavailable: AtomicBool::new(false),
       }),
     })
   }
 
+  /// Set the log level of the shared libusb context
+  ///
+  /// libusb logs to stderr, so this is mostly useful for debugging transport issues. This
+  /// does nothing when built with the `nusb` backend.
+  ///
+  /// # Parameters
+  /// - `level`: The libusb log level
+  ///
+  /// # Returns
+  /// - `Result<()>`: Success or an error if libusb could not be initialized
+  pub fn set_usb_log_level(level: UsbLogLevel) -> Result<()> {
+    transport::set_log_level(level)
+  }
+
+  /// Get the profile of the connected device
+  pub fn profile(&self) -> &DeviceProfile {
+    &self.inner.profile
+  }
+
+  /// Get what this connection talks to
+  pub fn target(&self) -> &DeviceTarget {
+    &self.inner.target
+  }
+
+  fn staging_address(&self) -> u32 {
+    self.inner.profile.staging_address
+  }
+
+  fn max_transfer_size(&self) -> usize {
+    self.inner.profile.max_transfer_size
+  }
+
+  fn transfer_block_size(&self) -> usize {
+    self.inner.profile.transfer_block_size
+  }
+
+  /// Get the USB serial number reported by the device, if any
+  pub fn serial_number(&self) -> Option<&str> {
+    self.device_info().serial.as_deref()
+  }
+
+  /// Get details of the connected device and the interface and endpoints chosen for it
+  pub fn device_info(&self) -> &DeviceInfo {
+    self.session().info()
+  }
+
+  /// Get the number of bytes written to and skipped on the eMMC through this connection
+  pub fn write_stats(&self) -> WriteStats {
+    WriteStats {
+      bytes_written: self.inner.bytes_written.load(Ordering::Relaxed),
+      bytes_skipped: self.inner.bytes_skipped.load(Ordering::Relaxed),
+    }
+  }
+
+  /// Whether the USB session is still usable
+  ///
+  /// The session ends when the device resets or jumps to other code, e.g. after [`run`](Self::run)
+  /// or [`bl2_boot`](Self::bl2_boot), or when it disconnects in the middle of a transfer. A new connection has to be made with
+  /// [`reconnect`](Self::reconnect) after that.
+  pub fn session_valid(&self) -> bool {
+    self.session_lost().is_none()
+  }
+
+  /// The protocol session with the device
+  pub fn session(&self) -> &Session {
+    &self.inner.session
+  }
+
+  fn session_lost(&self) -> Option<String> {
+    self.session().session_lost()
+  }
+
+  fn end_session(&self, reason: String) {
+    self.session().end_session(reason);
+  }
+
+  /// Set the time by which device operations have to finish
+  ///
+  /// Transfers started after the deadline fail with [`Error::Timeout`], which breaks out of retry loops
+  /// that would otherwise keep a hung step going. A single transfer is still bounded by its USB timeout.
+  pub(crate) fn set_deadline(&self, deadline: Option<(Instant, String)>) {
+    self.session().set_deadline(deadline);
+  }
+
+  fn check_deadline(&self) -> Result<()> {
+    Ok(self.session().check_deadline()?)
+  }
+
+  /// Sleep for `duration`, failing with [`Error::Timeout`] if the deadline passes first
+  pub(crate) fn pause(&self, duration: Duration) -> Result<()> {
+    Ok(self.session().pause(duration)?)
+  }
+
+  /// Wait for the device to re-enumerate and connect to it again
+  ///
+  /// The device is moved to USB burn mode if it comes back in USB mode, like [`init`](Self::init).
+  ///
+  /// # Parameters
+  /// - `timeout`: How long to wait for the device to show up again
+  ///
+  /// # Returns
+  /// - `Result<Self>`: A new connection, or [`Error::SessionLost`] if the device did not come back
+  pub fn reconnect(&self, timeout: Duration) -> Result<Self> {
+    let reason = self.session_lost().unwrap_or_else(|| "reconnect requested".into());
+    tracing::info!("reconnecting to device ({})", reason);
+    self.end_session(reason.clone());
+
+    // give the old enumeration time to disappear before looking for the new one
+    sleep(RECONNECT_SETTLE);
+    let start = Instant::now();
+    while transport::find_device(&self.inner.target, self.profile()) == DeviceMode::NotFound {
+      if start.elapsed() >= timeout {
+        return Err(Error::SessionLost(format!(
+          "{reason}, and the device did not come back within {timeout:?}"
+        )));
+      }
+      self.check_deadline()?;
+      sleep(Duration::from_millis(500));
+    }
+
+    let aml = Self::init_with_target(
+      self.inner.callback.clone(),
+      self.inner.profile.clone(),
+      self.inner.target.clone(),
+    )
+    .map_err(|err| Error::SessionLost(format!("{reason}, and reconnecting failed: {err}")))?;
+    // the host's USB stack is the same, so don't fail the transfers it couldn't make again
+    aml
+      .inner
+      .bulk_limit
+      .store(self.bulk_transfer_limit(), Ordering::Relaxed);
+    aml.session().set_max_urb_size(self.session().max_urb_size());
+    aml.set_cancel_token(self.cancel_token());
+    Ok(aml)
+  }
+
+  fn record_write(&self, bytes: usize) {
+    self.inner.wrote.store(true, Ordering::Relaxed);
+    self.inner.bytes_written.fetch_add(bytes as u64, Ordering::Relaxed);
+    telemetry::bytes_written(bytes);
+  }
+
+  fn record_skip(&self, bytes: usize) {
+    self.inner.bytes_skipped.fetch_add(bytes as u64, Ordering::Relaxed);
+    telemetry::bytes_skipped(bytes);
+  }
+
+  /// Read the SoC temperature with the thermal policy's command, if it has one that works
+  fn read_temperature(&self) -> Option<f64> {
+    let command = self.inner.profile.thermal.temperature_command.as_deref()?;
+    if self.inner.temperature_unavailable.load(Ordering::Relaxed) {
+      return None;
+    }
+
+    match self.bulkcmd(command).map(|response| parse_temperature(&response)) {
+      Ok(Some(temperature)) => Some(temperature),
+      result => {
+        tracing::warn!(
+          "temperature command {:?} gave no reading ({:?}), falling back to slow-write cooldowns",
+          command,
+          result
+        );
+        self.inner.temperature_unavailable.store(true, Ordering::Relaxed);
+        None
+      }
+    }
+  }
+
+  /// Pause after a chunk was written to the eMMC, as the thermal policy asks
+  ///
+  /// With a working temperature sensor this waits until the SoC has cooled below the resume
+  /// threshold; otherwise it pauses for a fixed time when the write was slow.
+  fn cool_down(&self, write_time: Duration) {
+    let policy = &self.inner.profile.thermal;
+    let notify = |temperature| {
+      if let Some(callback) = &self.inner.callback {
+        callback(Event::ThermalPause { temperature });
+      }
+    };
+
+    // let the next transfer report the timeout instead of pausing past the deadline
+    if self.check_deadline().is_err() {
+      return;
+    }
+
+    if let Some(mut temperature) = self.read_temperature() {
+      if temperature <= policy.pause_above {
+        return;
+      }
+
+      tracing::info!(
+        "device at {:.1}°C, pausing until it cools below {:.1}°C",
+        temperature,
+        policy.resume_below
+      );
+      notify(Some(temperature));
+      let paused = Instant::now();
+      while temperature >= policy.resume_below {
+        if self.check_deadline().is_err() {
+          break;
+        }
+        if paused.elapsed() >= policy.max_pause {
+          tracing::warn!(
+            "device still at {:.1}°C after {:?}, resuming anyway",
+            temperature,
+            policy.max_pause
+          );
+          break;
+        }
+        sleep(policy.poll_interval);
+        match self.read_temperature() {
+          Some(reading) => temperature = reading,
+          None => break,
+        }
+      }
+      telemetry::cooldown(paused.elapsed());
+      return;
+    }
+
+    if policy
+      .slow_write_threshold
+      .is_some_and(|threshold| write_time > threshold)
+    {
+      tracing::debug!(
+        target: TRANSFER_TARGET,
+        "mmc write took {}ms, cooling down for {:?}",
+        write_time.as_millis(),
+        policy.slow_write_cooldown
+      );
+      notify(None);
+      sleep(policy.slow_write_cooldown);
+      telemetry::cooldown(policy.slow_write_cooldown);
+    }
+  }
+
   /// Write data to device memory
   ///
   /// This writes a small amount of data (up to 64 bytes) to device memory.
@@ -170,25 +507,8 @@ impl AmlogicSoC {
   /// - `Result<()>`: Success or an error
   #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
   pub fn write_simple_memory(&self, address: u32, data: &[u8]) -> Result<()> {
-    tracing::debug!(
-      "writing simple memory at address: {:#X}, length: {}",
-      address,
-      data.len()
-    );
-    if data.len() > 64 {
-      return Err(Error::InvalidOperation("Maximum size of 64 bytes".into()));
-    }
-    let value = (address >> 16) as u16;
-    let index = (address & 0xffff) as u16;
-    self
-      .inner
-      .handle
-      .write_control(0x40, REQ_WRITE_MEM, value, index, data, COMMAND_TIMEOUT)?;
-    tracing::trace!(
-      "write_control completed for write_simple_memory at address: {:#X}",
-      address
-    );
-    Ok(())
+    self.forget_staged();
+    Ok(self.session().write_simple_memory(address, data)?)
   }
 
   /// Write arbitrary size data to device memory
@@ -203,24 +523,8 @@ impl AmlogicSoC {
   /// - `Result<()>`: Success or an error
   #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
   pub fn write_memory(&self, address: u32, data: &[u8]) -> Result<()> {
-    tracing::debug!(
-      "writing memory starting at address: {:#X} with total length: {}",
-      address,
-      data.len()
-    );
-    let mut offset = 0;
-    let length = data.len();
-    while offset < length {
-      let chunk_size = std::cmp::min(64, length - offset);
-      self.write_simple_memory(address + offset as u32, &data[offset..offset + chunk_size])?;
-      tracing::trace!(
-        "chunk written for write_memory at address: {:#X}, new offset: {}",
-        address,
-        offset + chunk_size
-      );
-      offset += chunk_size;
-    }
-    Ok(())
+    self.forget_staged();
+    Ok(self.session().write_memory(address, data)?)
   }
 
   /// Read a small amount of data from device memory
@@ -236,33 +540,7 @@ impl AmlogicSoC {
   /// - `Result<Vec<u8>>`: The read data or an error
   #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
   pub fn read_simple_memory(&self, address: u32, length: usize) -> Result<Vec<u8>> {
-    tracing::debug!(
-      "reading simple memory at address: {:#X} with length: {}",
-      address,
-      length
-    );
-    if length == 0 {
-      return Ok(vec![]);
-    }
-    if length > 64 {
-      return Err(Error::InvalidOperation("Maximum size of 64 bytes".into()));
-    }
-    let value = (address >> 16) as u16;
-    let index = (address & 0xffff) as u16;
-    let mut buf = vec![0u8; length];
-    let read = self
-      .inner
-      .handle
-      .read_control(0xC0, REQ_READ_MEM, value, index, &mut buf, COMMAND_TIMEOUT)?;
-    tracing::trace!(
-      "read_control completed for read_simple_memory at address: {:#X}, bytes read: {}",
-      address,
-      read
-    );
-    if read != length {
-      return Err(Error::InvalidOperation("Incomplete read".into()));
-    }
-    Ok(buf)
+    Ok(self.session().read_simple_memory(address, length)?)
   }
 
   /// Read arbitrary size data from device memory
@@ -277,25 +555,14 @@ impl AmlogicSoC {
   /// - `Result<Vec<u8>>`: The read data or an error
   #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
   pub fn read_memory(&self, address: u32, length: usize) -> Result<Vec<u8>> {
-    tracing::debug!("reading memory at address: {:#X} with length: {}", address, length);
-    let mut data = vec![0u8; length];
-    let mut offset = 0;
-    while offset < length {
-      let read_length = std::cmp::min(64, length - offset);
-      let chunk = self.read_simple_memory(address + offset as u32, read_length)?;
-      data[offset..offset + read_length].copy_from_slice(&chunk);
-      tracing::trace!(
-        "chunk read for read_memory at address: {:#X}, offset: {}",
-        address,
-        offset
-      );
-      offset += read_length;
-    }
-    Ok(data)
+    Ok(self.session().read_memory(address, length)?)
   }
 
   /// Execute code at the specified memory address
   ///
+  /// This ends the USB session: only the AMLC handshake with a freshly started BL2 can follow,
+  /// anything else needs [`reconnect`](Self::reconnect).
+  ///
   /// # Parameters
   /// - `address`: The memory address to execute code from
   /// - `keep_power`: Whether to keep power on after execution
@@ -304,22 +571,7 @@ impl AmlogicSoC {
   /// - `Result<()>`: Success or an error
   #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
   pub fn run(&self, address: u32, keep_power: Option<bool>) -> Result<()> {
-    let keep_power = keep_power.unwrap_or(true);
-    tracing::debug!("running at address: {:#X} with keep_power: {}", address, keep_power);
-    let data = if keep_power {
-      address | FLAG_KEEP_POWER_ON
-    } else {
-      address
-    };
-    let buffer = data.to_le_bytes();
-    let value = (address >> 16) as u16;
-    let index = (address & 0xffff) as u16;
-    self
-      .inner
-      .handle
-      .write_control(0x40, REQ_RUN_IN_ADDR, value, index, &buffer, COMMAND_TIMEOUT)?;
-    tracing::trace!("run command sent at address: {:#X}", address);
-    Ok(())
+    Ok(self.session().run(address, keep_power)?)
   }
 
   /// Identify the device
@@ -328,17 +580,19 @@ impl AmlogicSoC {
   /// - `Result<String>`: The device identification string or an error
   #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
   pub fn identify(&self) -> Result<String> {
-    tracing::debug!("identifying device");
-    let mut buf = [0u8; 8];
-    let read = self
-      .inner
-      .handle
-      .read_control(0xC0, REQ_IDENTIFY_HOST, 0, 0, &mut buf, COMMAND_TIMEOUT)?;
-    tracing::trace!("identify response received: {:?} ({} bytes)", &buf, read);
-    if read != 8 {
-      return Err(Error::InvalidOperation("Failed to read identify data".into()));
-    }
-    Ok(String::from_utf8(buf.to_vec())?)
+    Ok(self.session().identify()?)
+  }
+
+  /// Detect whether the SoC enforces secure boot
+  ///
+  /// This reads the efuse-backed security config register, so it only needs the ROM (or
+  /// burn mode) USB protocol. The state decides which BL2 the device will accept.
+  ///
+  /// # Returns
+  /// - `SecureBootState`: The detected state, or `Unknown` if the register could not be read
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn secure_boot_state(&self) -> SecureBootState {
+    self.session().secure_boot_state()
   }
 
   /// Write large blocks of data to device memory
@@ -353,7 +607,10 @@ impl AmlogicSoC {
   ///
   /// # Returns
   /// - `Result<()>`: Success or an error
-  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  #[cfg_attr(
+    feature = "instrument",
+    tracing::instrument(target = "flashthing::transfer", level = "trace", skip_all)
+  )]
   pub fn write_large_memory(
     &self,
     memory_address: u32,
@@ -361,62 +618,194 @@ impl AmlogicSoC {
     block_length: usize,
     append_zeros: bool,
   ) -> Result<()> {
-    tracing::debug!(
-      "writing large memory to address: {:#X} with data length: {}",
-      memory_address,
-      data.len()
-    );
-
-    let mut data_vec = data.to_vec();
-    if append_zeros {
-      let remainder = data_vec.len() % block_length;
-      if remainder != 0 {
-        let padding = block_length - remainder;
-        data_vec.extend(vec![0u8; padding]);
-      }
-    } else if !data_vec.len().is_multiple_of(block_length) {
+    if !append_zeros && !data.len().is_multiple_of(block_length) {
       return Err(Error::InvalidOperation(
         "Large Data must be a multiple of block length".into(),
       ));
     }
 
-    let total_bytes = data_vec.len() as u32;
-    let block_count = (data_vec.len() / block_length) as u16;
-    let mut control_data = Vec::with_capacity(16);
-    control_data.extend_from_slice(&memory_address.to_le_bytes());
-    control_data.extend_from_slice(&total_bytes.to_le_bytes());
-    control_data.extend_from_slice(&0u32.to_le_bytes());
-    control_data.extend_from_slice(&0u32.to_le_bytes());
-
-    tracing::trace!("writing control data: {:?}", &control_data);
-    self.inner.handle.write_control(
-      0x40,
-      REQ_WR_LARGE_MEM,
-      block_length as u16,
-      block_count,
-      &control_data,
-      COMMAND_TIMEOUT,
-    )?;
+    self.forget_staged();
+    let start = Instant::now();
+    let mut offset = 0;
+    loop {
+      let limit = self.block_limit(block_length);
+      let piece = &data[offset..data.len().min(offset + limit)];
+      match self
+        .session()
+        .write_large_memory(memory_address + offset as u32, piece, block_length, append_zeros)
+      {
+        Ok(()) => offset += piece.len(),
+        Err(err) => {
+          let err = Error::from(err);
+          if !self.downshift(&err, piece.len(), block_length) {
+            return Err(err);
+          }
+        }
+      }
+      if offset >= data.len() {
+        break;
+      }
+    }
+    telemetry::transfer(data.len(), start.elapsed());
+    Ok(())
+  }
+
+  /// Stop writes to the eMMC at the next chunk boundary once `token` is cancelled
+  ///
+  /// The chunk in flight is written in full, so the eMMC holds whole chunks up to where the write
+  /// stopped with [`Error::Cancelled`]. Kept across [`reconnect`](Self::reconnect).
+  pub fn set_cancel_token(&self, token: Option<CancelToken>) {
+    *self
+      .inner
+      .cancel
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner()) = token;
+  }
+
+  fn cancel_token(&self) -> Option<CancelToken> {
+    self
+      .inner
+      .cancel
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+      .clone()
+  }
+
+  /// Start the next streaming write to the eMMC at byte `offset` of its data, as far as a cancelled
+  /// write of the same data got, instead of at its start
+  ///
+  /// This applies to one [`write_large_memory_to_disk`](Self::write_large_memory_to_disk),
+  /// [`write_user_area`](Self::write_user_area) or [`restore_partition`](Self::restore_partition),
+  /// and 0 clears it.
+  pub(crate) fn resume_next_write(&self, offset: u64) {
+    self.inner.resume_at.store(offset, Ordering::Relaxed);
+    self.inner.streamed.store(0, Ordering::Relaxed);
+  }
+
+  /// Bytes of the data of the last streaming write to the eMMC that are on the device, which is
+  /// where to resume it once it stopped with [`Error::Cancelled`]
+  pub(crate) fn streamed(&self) -> u64 {
+    self.inner.streamed.load(Ordering::Relaxed)
+  }
+
+  /// Where a streaming write of `data_size` bytes starts, with `reader` moved past the data before it
+  fn resume_offset<R: Read>(&self, reader: &mut R, data_size: usize) -> Result<usize> {
+    let offset = self.inner.resume_at.swap(0, Ordering::Relaxed) as usize;
+    self.inner.streamed.store(offset as u64, Ordering::Relaxed);
+    if offset == 0 {
+      return Ok(0);
+    }
+    if offset > data_size || !offset.is_multiple_of(PART_SECTOR_SIZE) {
+      return Err(Error::InvalidOperation(format!(
+        "cannot resume a {} byte write at byte {}, it has to be a multiple of {} within the data",
+        data_size, offset, PART_SECTOR_SIZE
+      )));
+    }
+
+    tracing::info!("resuming the write at byte {} of {}", offset, data_size);
+    std::io::copy(&mut reader.take(offset as u64), &mut std::io::sink())?;
+    Ok(offset)
+  }
 
-    let mut data_offset = 0;
-    while data_offset < data_vec.len() {
-      let end = data_offset + block_length;
-      let chunk = &data_vec[data_offset..end];
-      tracing::trace!(target: "flashthing::aml::write_large_memory", "writing actual data from offset: {:#X}", &data_offset);
+  /// Fail with [`Error::Cancelled`] if the cancel token was cancelled
+  pub(crate) fn check_cancelled(&self) -> Result<()> {
+    match self.cancel_token() {
+      Some(token) if token.is_cancelled() => Err(Error::Cancelled),
+      _ => Ok(()),
+    }
+  }
 
-      self
-        .inner
-        .handle
-        .write_bulk(self.inner.endpoint_out, chunk, Duration::from_millis(2000))?;
+  /// Most bytes moved to the device's memory in one transfer
+  ///
+  /// This starts at the profile's `max_transfer_size`, or 64 KiB with [`UsbQuirks::limited_bulk`],
+  /// and is lowered for the rest of the connection when larger transfers fail.
+  ///
+  /// [`UsbQuirks::limited_bulk`]: crate::UsbQuirks::limited_bulk
+  pub fn bulk_transfer_limit(&self) -> usize {
+    self.inner.bulk_limit.load(Ordering::Relaxed)
+  }
 
-      tracing::trace!(target: "flashthing::aml::write_large_memory", "wrote actual data from offset: {:#X}", &data_offset);
+  /// [`Self::bulk_transfer_limit`] in whole blocks of `block_length`, and at least one block
+  fn block_limit(&self, block_length: usize) -> usize {
+    self.bulk_transfer_limit().max(block_length) / block_length * block_length
+  }
 
-      data_offset += block_length;
+  /// Halve the transfer limit after a transfer of `attempted` bytes failed with `err`
+  ///
+  /// # Returns
+  /// - `bool`: Whether the limit was lowered and the transfer is worth retrying
+  fn downshift(&self, err: &Error, attempted: usize, block_length: usize) -> bool {
+    // failed or timed out USB transfers, as opposed to e.g. the device going away
+    let transfer_failed = match err {
+      Error::Usb(_) | Error::Timeout(_) => true,
+      Error::IoError(err) => err.kind() == std::io::ErrorKind::TimedOut,
+      _ => false,
+    };
+    if !transfer_failed || attempted <= LIMITED_BULK_TRANSFER.max(block_length) {
+      return false;
     }
 
+    let limit = (attempted / 2).max(LIMITED_BULK_TRANSFER);
+    self.inner.bulk_limit.fetch_min(limit, Ordering::Relaxed);
+    tracing::warn!(
+      "transfer of {} bytes failed ({}), retrying with transfers of at most {} bytes",
+      attempted,
+      err,
+      limit
+    );
+    true
+  }
+
+  /// Write `length` bytes from `reader` to device memory, holding at most one transfer in memory
+  ///
+  /// # Parameters
+  /// - `memory_address`: The memory address to write to
+  /// - `reader`: A reader providing the data to write
+  /// - `length`: The number of bytes to take from `reader`
+  /// - `block_length`: The size of each block to transfer
+  /// - `append_zeros`: Whether to pad data with zeros to match block_length
+  ///
+  /// # Returns
+  /// - `Result<()>`: Success or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn write_large_memory_from_reader<R: std::io::Read>(
+    &self,
+    memory_address: u32,
+    reader: &mut R,
+    length: usize,
+    block_length: usize,
+    append_zeros: bool,
+  ) -> Result<()> {
+    // each transfer is read into a pooled buffer rather than streamed, so it can be retried in
+    // smaller pieces if it fails
+    let mut offset = 0;
+    while offset < length {
+      let limit = self.block_limit(block_length);
+      let mut piece = self.inner.buffers.take(limit.min(length - offset));
+      reader.read_exact(&mut piece)?;
+      self.write_large_memory(memory_address + offset as u32, &piece, block_length, append_zeros)?;
+      offset += piece.len();
+    }
     Ok(())
   }
 
+  /// Read large blocks of data from device memory
+  ///
+  /// This is the read counterpart of `write_large_memory`, used to pull data staged
+  /// in memory (e.g. by `amlmmc read`) back to the host.
+  ///
+  /// # Parameters
+  /// - `memory_address`: The memory address to read from
+  /// - `length`: The number of bytes to read, must be a multiple of block_length
+  /// - `block_length`: The size of each block to transfer
+  ///
+  /// # Returns
+  /// - `Result<Vec<u8>>`: The read data or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn read_large_memory(&self, memory_address: u32, length: usize, block_length: usize) -> Result<Vec<u8>> {
+    Ok(self.session().read_large_memory(memory_address, length, block_length)?)
+  }
+
   /// Write large blocks of data directly to a disk address with progress tracking
   ///
   /// # Parameters
@@ -425,10 +814,12 @@ impl AmlogicSoC {
   /// - `data_size`: The total size of data to write
   /// - `block_length`: The size of each block to transfer
   /// - `append_zeros`: Whether to pad data with zeros to match block_length
+  /// - `compare_before_write`: Whether to skip chunks the disk already holds
   /// - `progress_callback`: Function to call with progress updates
   ///
   /// # Returns
   /// - `Result<()>`: Success or an error
+  #[allow(clippy::too_many_arguments)]
   #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
   pub fn write_large_memory_to_disk<R: std::io::Read, F: Fn(FlashProgress)>(
     &self,
@@ -437,6 +828,7 @@ impl AmlogicSoC {
     data_size: usize,
     block_length: usize,
     append_zeros: bool,
+    compare_before_write: bool,
     progress_callback: F,
   ) -> Result<()> {
     tracing::debug!("streaming {} bytes to disk address: {:#X}", data_size, disk_address);
@@ -450,9 +842,9 @@ impl AmlogicSoC {
     self.bulkcmd("amlmmc key")?;
 
     let total_len = data_size;
-    let max_bytes_per_transfer = TRANSFER_SIZE_THRESHOLD;
-    let mut offset = 0;
-    let mut buffer = vec![0u8; max_bytes_per_transfer];
+    let max_bytes_per_transfer = self.max_transfer_size();
+    let start = self.resume_offset(reader, total_len)?;
+    let mut offset = start;
 
     while offset < total_len {
       let chunk_start_time = std::time::Instant::now();
@@ -460,38 +852,50 @@ impl AmlogicSoC {
       let remaining = total_len - offset;
       let write_length = std::cmp::min(remaining, max_bytes_per_transfer);
 
-      let data_slice = &mut buffer[..write_length];
-      reader.read_exact(data_slice)?;
-
-      self.write_large_memory(ADDR_TMP, &buffer[..write_length], block_length, append_zeros)?;
-
-      let start_time_cmd = std::time::Instant::now();
-      let mut retries = 0;
-      let max_retries = 3;
+      let disk_offset = disk_address as usize + offset;
+      if !self
+        .stage_chunk(
+          reader,
+          write_length,
+          block_length,
+          append_zeros,
+          compare_before_write,
+          |chunk| self.disk_region_matches(disk_offset / PART_SECTOR_SIZE, chunk),
+        )
+        .map_err(|e| e.with_context(|c| c.offset = Some(disk_offset as u64)))?
+      {
+        tracing::debug!(target: TRANSFER_TARGET, "disk region at {:#X} already matches, skipping write", disk_offset);
+        self.record_skip(write_length);
+      } else {
+        let start_time_cmd = std::time::Instant::now();
+        let mut retries = 0;
+        let max_retries = 3;
 
-      loop {
-        match self.bulkcmd(&format!(
-          "mmc write {:#X} {:#X} {:#X}",
-          ADDR_TMP,
-          (disk_address as usize + offset) / 512,
-          write_length / 512
-        )) {
-          Ok(_) => {
-            let elapsed = start_time_cmd.elapsed();
-            if elapsed > Duration::from_millis(3000) {
-              tracing::debug!("mmc write command took {}ms, cooling down for 5s", elapsed.as_millis());
-              sleep(Duration::from_secs(5));
-            }
-            break;
-          }
-          Err(e) => {
-            retries += 1;
-            if retries >= max_retries {
-              return Err(e);
-            }
-            sleep(Duration::from_secs(5)); // cooldown after error
+        loop {
+          match self.commit_staged(&format!(
+            "mmc write {:#X} {:#X} {:#X}",
+            self.staging_address(),
+            disk_offset / 512,
+            write_length / 512
+          )) {
+            Ok(_) => {
+              self.cool_down(start_time_cmd.elapsed());
+              break;
+            }
+            Err(e) => {
+              retries += 1;
+              if retries >= max_retries {
+                return Err(e.with_context(|c| {
+                  c.offset = Some(disk_offset as u64);
+                  c.retries = retries - 1;
+                }));
+              }
+              telemetry::retry("mmc_write");
+              sleep(self.inner.profile.thermal.error_cooldown);
+            }
           }
         }
+        self.record_write(write_length);
       }
 
       let chunk_time = chunk_start_time.elapsed();
@@ -504,14 +908,15 @@ impl AmlogicSoC {
       }
 
       offset += write_length;
+      self.inner.streamed.store(offset as u64, Ordering::Relaxed);
       let progress_percent = offset as f64 / total_len as f64 * 100.0;
 
       let elapsed = start_time.elapsed();
       let elapsed_secs = elapsed.as_secs_f64();
       let bytes_per_sec = if elapsed_secs > 0.0 {
-        offset as f64 / elapsed_secs
+        (offset - start) as f64 / elapsed_secs
       } else {
-        offset as f64
+        (offset - start) as f64
       };
 
       let remaining_bytes = total_len - offset;
@@ -521,7 +926,8 @@ impl AmlogicSoC {
         0.0
       };
 
-      tracing::info!(
+      tracing::debug!(
+        target: TRANSFER_TARGET,
         "progress: {:.1}% | elapsed: {:.1}s | eta: {:.1}s | rate: {:.2} KB/s | avg chunk: {:.1}s | avg rate: {:.2} KB/s",
         progress_percent,
         elapsed_secs,
@@ -538,7 +944,12 @@ impl AmlogicSoC {
         rate: write_length as f64 / chunk_time_secs / 1024.0,
         avg_chunk_time: avg_chunk_time_secs * 1000.0,
         avg_rate: bytes_per_sec / 1024.0,
+        step_id: None,
       });
+
+      if offset < total_len {
+        self.check_cancelled()?;
+      }
     }
 
     let total_elapsed = start_time.elapsed();
@@ -560,230 +971,17 @@ impl AmlogicSoC {
 
   #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
   pub fn write_amlc_data(&self, offset: u32, data: &[u8]) -> Result<()> {
-    tracing::debug!("writing amlc data at offset: {:#X} with length: {}", offset, data.len());
-
-    self.inner.handle.write_control(
-      0x40,
-      REQ_WRITE_AMLC,
-      (offset / AMLC_AMLS_BLOCK_LENGTH as u32) as u16,
-      (data.len() - 1) as u16,
-      &[],
-      COMMAND_TIMEOUT,
-    )?;
-    tracing::trace!("amlc header sent for data write at offset: {:#X}", offset);
-
-    let max_chunk_size = AMLC_MAX_BLOCK_LENGTH;
-    let mut data_offset = 0;
-    let write_length = data.len();
-    let mut remaining = write_length;
-
-    let bulk_timeout = Duration::from_millis(1000);
-
-    while remaining > 0 {
-      let block_length = std::cmp::min(remaining, max_chunk_size);
-      let chunk = &data[data_offset..data_offset + block_length];
-
-      let mut retries = 0;
-      let max_retries = 3;
-      let mut success = false;
-
-      while !success && retries < max_retries {
-        match self
-          .inner
-          .handle
-          .write_bulk(self.inner.endpoint_out, chunk, bulk_timeout)
-        {
-          Ok(written) => {
-            if written == block_length {
-              success = true;
-              tracing::trace!(
-                "bulk write in AMLC data, data_offset: {}, chunk: {}",
-                data_offset,
-                block_length
-              );
-            } else {
-              tracing::warn!(
-                "Incomplete bulk write: {} of {} bytes. Retry {}/{}",
-                written,
-                block_length,
-                retries + 1,
-                max_retries
-              );
-              retries += 1;
-              sleep(Duration::from_millis(100));
-            }
-          }
-          Err(e) => {
-            tracing::warn!("Error in bulk write: {}. Retry {}/{}", e, retries + 1, max_retries);
-            retries += 1;
-            sleep(Duration::from_millis(100));
-
-            if retries >= max_retries {
-              return Err(Error::UsbError(e));
-            }
-          }
-        }
-      }
-
-      data_offset += block_length;
-      remaining -= block_length;
-
-      sleep(Duration::from_millis(10));
-    }
-
-    let mut ack_buf = [0u8; 16];
-    let mut retries = 0;
-    let max_retries = 3;
-    let mut read = 0;
-
-    while retries < max_retries {
-      match self
-        .inner
-        .handle
-        .read_bulk(self.inner.endpoint_in, &mut ack_buf, bulk_timeout)
-      {
-        Ok(bytes_read) => {
-          read = bytes_read;
-          if read >= 4 {
-            break;
-          }
-          tracing::warn!("short ack read: {} bytes. retry {}/{}", read, retries + 1, max_retries);
-        }
-        Err(e) => {
-          tracing::warn!("error reading ack: {}. retry {}/{}", e, retries + 1, max_retries);
-        }
-      }
-      retries += 1;
-      sleep(Duration::from_millis(100));
-    }
-
-    tracing::trace!("received amlc ack: {:?} ({} bytes)", &ack_buf[..read], read);
-
-    if read < 4 {
-      return Err(Error::InvalidOperation("no acknowledgment received".into()));
-    }
-
-    let ack = String::from_utf8(ack_buf[0..4].to_vec())?;
-    if ack != "OKAY" {
-      return Err(Error::InvalidOperation(format!("invalid amlc data write ack: {}", ack)));
-    }
-
-    Ok(())
+    Ok(self.session().write_amlc_data(offset, data)?)
   }
 
   #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
   pub fn write_amlc_data_packet(&self, seq: u8, amlc_offset: u32, data: &[u8]) -> Result<()> {
-    tracing::debug!("writing amlc data packet, seq: {}, offset: {:#X}", seq, amlc_offset);
-
-    let data_len = data.len();
-    let max_transfer_length = AMLC_MAX_TRANSFER_LENGTH;
-    let transfer_count = data_len.div_ceil(max_transfer_length);
-
-    if data_len > 0 {
-      let mut offset = 0;
-      for i in 0..transfer_count {
-        let write_length = std::cmp::min(max_transfer_length, data_len - offset);
-        tracing::trace!(
-          "sending amlc data packet chunk {}/{} at offset: {} with length: {}",
-          i + 1,
-          transfer_count,
-          offset,
-          write_length
-        );
-
-        self.write_amlc_data(offset as u32, &data[offset..offset + write_length])?;
-        sleep(Duration::from_millis(50));
-
-        offset += write_length;
-      }
-    }
-
-    let checksum = self.amlc_checksum(data)?;
-
-    let mut amlc_header = [0u8; 16];
-    amlc_header[0..4].copy_from_slice(b"AMLS"); // ! This is AMLS not AMLC for final packet - do not change
-    amlc_header[4] = seq;
-    amlc_header[8..12].copy_from_slice(&checksum.to_le_bytes());
-
-    let mut amlc_data = vec![0u8; AMLC_AMLS_BLOCK_LENGTH];
-    amlc_data[0..16].copy_from_slice(&amlc_header);
-
-    if data.len() > 16 {
-      let copy_len = std::cmp::min(AMLC_AMLS_BLOCK_LENGTH - 16, data.len() - 16);
-      amlc_data[16..16 + copy_len].copy_from_slice(&data[16..16 + copy_len]);
-    }
-
-    tracing::debug!("sending AMLS block with seq {} to offset {:#X}", seq, amlc_offset);
-    self.write_amlc_data(amlc_offset, &amlc_data)?;
-
-    Ok(())
+    Ok(self.session().write_amlc_data_packet(seq, amlc_offset, data)?)
   }
 
   #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
   pub fn get_boot_amlc(&self) -> Result<(u32, u32)> {
-    tracing::debug!("getting boot amlc data");
-    self.inner.handle.write_control(
-      0x40,
-      REQ_GET_AMLC,
-      AMLC_AMLS_BLOCK_LENGTH as u16,
-      0,
-      &[],
-      COMMAND_TIMEOUT,
-    )?;
-    tracing::trace!("amlc get request sent");
-    let mut buf = vec![0u8; AMLC_AMLS_BLOCK_LENGTH];
-    let read = self
-      .inner
-      .handle
-      .read_bulk(self.inner.endpoint_in, &mut buf, Duration::from_secs(2))?;
-    tracing::trace!("amlc data received, length: {}", read);
-    if read < AMLC_AMLS_BLOCK_LENGTH {
-      return Err(Error::InvalidOperation("No amlc data received".into()));
-    }
-    let tag = String::from_utf8(buf[0..4].to_vec())?;
-    if tag != "AMLC" {
-      return Err(Error::InvalidOperation(format!("invalid amlc request: {}", tag)));
-    }
-    let length = u32::from_le_bytes(buf[8..12].try_into()?);
-    let offset = u32::from_le_bytes(buf[12..16].try_into()?);
-    let mut ack = [0u8; 16];
-    ack[..4].copy_from_slice(b"OKAY");
-    self
-      .inner
-      .handle
-      .write_bulk(self.inner.endpoint_out, &ack, Duration::from_secs(2))?;
-    tracing::trace!("acknowledgment sent for amlc data");
-    Ok((length, offset))
-  }
-
-  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
-  fn amlc_checksum(&self, data: &[u8]) -> Result<u32> {
-    let mut checksum: u32 = 0;
-    let mut offset = 0;
-    let uint32_max = u32::MAX as u64 + 1;
-    while offset < data.len() {
-      let remaining = data.len() - offset;
-      let val: u32 = if remaining >= 4 {
-        let v = u32::from_le_bytes(data[offset..offset + 4].try_into()?);
-        offset += 4;
-        v
-      } else if remaining >= 3 {
-        let mut temp = [0u8; 4];
-        temp[..remaining].copy_from_slice(&data[offset..]);
-        offset += 3;
-        u32::from_le_bytes(temp) & 0xffffff
-      } else if remaining >= 2 {
-        let v = u16::from_le_bytes(data[offset..offset + 2].try_into()?) as u32;
-        offset += 2;
-        v
-      } else {
-        let v = data[offset] as u32;
-        offset += 1;
-        v
-      };
-      checksum = ((checksum as u64 + (val as i64).unsigned_abs()) % uint32_max) as u32;
-    }
-    Ok(checksum)
+    Ok(self.session().get_boot_amlc()?)
   }
 
   /// Execute the BL2 boot sequence
@@ -791,89 +989,28 @@ impl AmlogicSoC {
   /// This boots the device using the specified BL2 and bootloader binaries.
   ///
   /// # Parameters
-  /// - `bl2`: Optional BL2 binary data (uses built-in if None)
+  /// - `bl2`: Optional BL2 binary data (uses the built-in BL2 matching the secure boot state if None)
   /// - `bootloader`: Optional bootloader binary data (uses built-in if None)
   ///
   /// # Returns
   /// - `Result<()>`: Success or an error
   #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
   pub fn bl2_boot(&self, bl2: Option<&[u8]>, bootloader: Option<&[u8]>) -> Result<()> {
-    let bl2 = bl2.unwrap_or(BL2_BIN);
-    let bootloader = bootloader.unwrap_or(BOOTLOADER_BIN);
-
-    tracing::info!("sending bl2 binary to address {:#X}...", ADDR_BL2);
-    self.write_large_memory(ADDR_BL2, bl2, 4096, true)?;
-
-    tracing::info!("booting from bl2...");
-    self.run(ADDR_BL2, Some(true))?;
-
-    tracing::debug!("waiting for bootloader to initialize...");
-    sleep(Duration::from_secs(2));
-
-    let mut prev_length: u32 = 0;
-    let mut prev_offset: u32 = 0;
-    let mut seq: u8 = 0;
-
-    let max_retries = 3;
-    let max_iterations = 50;
-    let mut iterations = 0;
-
-    tracing::info!("starting AMLC data transfer sequence...");
-
-    loop {
-      if iterations >= max_iterations {
-        return Err(Error::InvalidOperation("maximum iterations reached in bl2_boot".into()));
-      }
-      iterations += 1;
-
-      let mut retry_count = 0;
-      let (length, offset) = loop {
-        match self.get_boot_amlc() {
-          Ok(result) => break result,
-          Err(e) => {
-            retry_count += 1;
-            if retry_count >= max_retries {
-              tracing::error!("failed to get boot amlc data after {} attempts: {}", max_retries, e);
-              return Err(e);
-            }
-            tracing::warn!("failed to get boot amlc, retry {}/{}: {}", retry_count, max_retries, e);
-            sleep(Duration::from_millis(500));
-          }
-        }
-      };
-
-      tracing::debug!("amlc request: dataSize={}, offset={}, seq={}", length, offset, seq);
-
-      if length == prev_length && offset == prev_offset {
-        tracing::debug!("amlc transfer complete - received same length/offset twice");
-        break;
-      }
-
-      prev_length = length;
-      prev_offset = offset;
-
-      if offset as usize >= bootloader.len() {
-        tracing::warn!(
-          "amlc requested offset {} exceeds bootloader size {}",
-          offset,
-          bootloader.len()
-        );
-        let empty_slice = &[];
-        self.write_amlc_data_packet(seq, offset, empty_slice)?;
-      } else {
-        let actual_length = std::cmp::min(length as usize, bootloader.len() - offset as usize);
-        let data_slice = &bootloader[offset as usize..offset as usize + actual_length];
-
-        tracing::debug!("sending {} bytes at offset {} with seq {}", actual_length, offset, seq);
-        self.write_amlc_data_packet(seq, offset, data_slice)?;
-      }
-
-      seq = seq.wrapping_add(1);
-      sleep(Duration::from_millis(100));
-    }
+    let bl2 = match bl2 {
+      Some(bl2) => bl2,
+      None => embedded_bl2(self.profile(), self.secure_boot_state())?,
+    };
+    let bootloader = match bootloader {
+      Some(bootloader) => bootloader,
+      None => self.profile().bootloader.as_deref().ok_or_else(|| {
+        Error::InvalidOperation(format!(
+          "device profile {} has no built-in bootloader",
+          self.profile().name
+        ))
+      })?,
+    };
 
-    tracing::info!("bl2 boot sequence completed successfully!");
-    Ok(())
+    Ok(self.session().boot_bl2(self.profile().bl2_address, bl2, bootloader)?)
   }
 
   /// Send a bulk command to the device
@@ -885,37 +1022,78 @@ impl AmlogicSoC {
   /// - `Result<String>`: The command response or an error
   #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
   pub fn bulkcmd(&self, command: &str) -> Result<String> {
-    tracing::debug!("sending bulk command: {:?}", command);
-    let mut command = command.as_bytes().to_vec();
-    command.push(0x00);
+    self.forget_staged();
+    let start = Instant::now();
+    let response = self.session().bulkcmd(command)?;
+    telemetry::bulkcmd(start.elapsed());
+    Ok(response)
+  }
+
+  /// Send `command`, which writes the chunk staged in DDR to the eMMC
+  ///
+  /// If it fails, the command is kept until the staging area is next touched, so dropping the
+  /// connection can try once more to land the chunk whole rather than leave it half written.
+  fn commit_staged(&self, command: &str) -> Result<String> {
+    let result = self.bulkcmd(command);
+    if result.is_err() {
+      *self.staged() = Some(command.to_string());
+    }
+    result
+  }
+
+  fn staged(&self) -> std::sync::MutexGuard<'_, Option<String>> {
     self
       .inner
-      .handle
-      .write_control(0x40, REQ_BULKCMD, 0, 0, &command, COMMAND_TIMEOUT)?;
-    tracing::trace!("bulk command control write completed");
+      .staged
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
 
-    let mut buf = vec![0u8; 512];
-    let read = self
-      .inner
-      .handle
-      .read_bulk(self.inner.endpoint_in, &mut buf, COMMAND_TIMEOUT)?;
-    tracing::trace!("bulk command response received, length: {}", read);
+  /// Drop the failed commit, once the staging area no longer holds its chunk
+  fn forget_staged(&self) {
+    self.staged().take();
+  }
 
-    if read == 0 {
-      return Err(Error::InvalidOperation("No response received for bulk command".into()));
-    }
-    let slice = &buf[..read];
-    let start = slice.iter().position(|&b| b != 0).unwrap_or(0);
-    let end = slice.iter().rposition(|&b| b != 0).map(|pos| pos + 1).unwrap_or(0);
-    let trimmed = &slice[start..end];
-    let response = String::from_utf8(trimmed.to_vec())?;
-    if !response.to_lowercase().contains("success") {
-      return Err(Error::InvalidOperation(format!(
-        "Bulk command failed, response did not contain 'success': {}",
-        response
-      )));
+  /// Reset the device, ending the USB session
+  ///
+  /// The command is sent without waiting for a response, since the device resets before it can answer.
+  ///
+  /// # Parameters
+  /// - `mode`: Whether to reboot normally or back into USB burn mode
+  ///
+  /// # Returns
+  /// - `Result<()>`: Success or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn reset(&self, mode: ResetMode) -> Result<()> {
+    let command = match mode {
+      ResetMode::Soft => "reset",
+      ResetMode::Burn => "reboot update",
+    };
+    Ok(self.session().reset(command)?)
+  }
+
+  /// Send `env_data`, `name=value` lines, to the device and import it into the U-Boot environment
+  ///
+  /// # Returns
+  /// - `Result<()>`: Success or an error if the data is not ascii or the device rejects it
+  pub fn import_env(&self, env_data: &str) -> Result<()> {
+    if !env_data.is_ascii() {
+      return Err(Error::InvalidOperation("env data must be ascii".into()));
     }
-    Ok(response)
+
+    let env_data_bytes = env_data.as_bytes();
+    let env_size = env_data_bytes.len();
+    let staging_address = self.profile().staging_address;
+    let block_size = self.profile().transfer_block_size;
+
+    tracing::debug!("initializing env subsystem");
+    self.bulkcmd("amlmmc env")?;
+
+    tracing::debug!("sending env ({} bytes)", env_size);
+    self.write_large_memory(staging_address, env_data_bytes, block_size, true)?;
+
+    self.bulkcmd(&format!("env import -t {:#X} {:#X}", staging_address, env_size))?;
+    Ok(())
   }
 
   /// Validate the size of a partition
@@ -923,11 +1101,18 @@ impl AmlogicSoC {
   /// # Parameters
   /// - `part_name`: The name of the partition
   /// - `part_info`: Partition information
+  /// - `allow_special`: Permit the `reserved` partition, which is probed by raw offset
   ///
   /// # Returns
   /// - `Result<usize>`: The validated partition size or an error
   #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
-  pub fn validate_partition_size(&self, part_name: &str, part_info: &PartitionInfo) -> Result<usize> {
+  pub fn validate_partition_size(
+    &self,
+    part_name: &str,
+    part_info: &PartitionInfo,
+    allow_special: bool,
+  ) -> Result<usize> {
+    let part_name = &canonical_partition_name(part_name);
     tracing::debug!("validating partition size for partition: {}", part_name);
 
     if part_name == "cache" {
@@ -936,8 +1121,17 @@ impl AmlogicSoC {
     }
 
     if part_name == "reserved" {
-      tracing::warn!("The \"reserved\" partition cannot be read or written!");
-      return Err(Error::InvalidOperation("Reserved partition cannot be accessed".into()));
+      if !allow_special {
+        tracing::warn!("The \"reserved\" partition cannot be read or written without allowSpecialPartitions!");
+        return Err(Error::InvalidOperation("Reserved partition cannot be accessed".into()));
+      }
+
+      // amlmmc refuses this partition, so probe the last sector by its raw offset instead
+      let part_size = part_info.size_bytes();
+      tracing::warn!("accessing the \"reserved\" partition by raw offset, here be dragons");
+      self.bulkcmd("mmc dev 1 0")?;
+      self.read_disk_chunk(part_info.offset + part_info.size - 1, PART_SECTOR_SIZE)?;
+      return Ok(part_size);
     }
 
     let part_size = part_info.size * PART_SECTOR_SIZE;
@@ -952,7 +1146,7 @@ impl AmlogicSoC {
     match self.bulkcmd(&format!(
       "amlmmc read {} {:#x} {:#x} {:#x}",
       part_name,
-      ADDR_TMP,
+      self.staging_address(),
       part_size - PART_SECTOR_SIZE,
       PART_SECTOR_SIZE
     )) {
@@ -995,7 +1189,7 @@ impl AmlogicSoC {
           match self.bulkcmd(&format!(
             "amlmmc read {} {:#x} {:#x} {:#x}",
             part_name,
-            ADDR_TMP,
+            self.staging_address(),
             alt_size - PART_SECTOR_SIZE,
             PART_SECTOR_SIZE
           )) {
@@ -1044,7 +1238,7 @@ impl AmlogicSoC {
   ///
   /// # Parameters
   /// - `hwpart`: 1 for boot0, 2 for boot1.
-  /// - `data`: payload (signed boot.bin). Capped at `TRANSFER_SIZE_THRESHOLD`.
+  /// - `data`: payload (signed boot.bin). Capped at the profile's `max_transfer_size`.
   #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
   pub fn write_boot_partition(&self, hwpart: u8, data: &[u8]) -> Result<()> {
     if !(1..=2).contains(&hwpart) {
@@ -1052,11 +1246,11 @@ impl AmlogicSoC {
         "boot hwpart must be 1 or 2, got {hwpart}"
       )));
     }
-    if data.len() > TRANSFER_SIZE_THRESHOLD {
+    if data.len() > self.max_transfer_size() {
       return Err(Error::InvalidOperation(format!(
         "boot partition payload {} bytes exceeds single-transfer cap {}",
         data.len(),
-        TRANSFER_SIZE_THRESHOLD
+        self.max_transfer_size()
       )));
     }
 
@@ -1065,27 +1259,49 @@ impl AmlogicSoC {
     self.bulkcmd(&format!("mmc dev 1 {hwpart}"))?;
     self.bulkcmd("amlmmc key")?;
 
-    self.write_large_memory(ADDR_TMP, data, TRANSFER_BLOCK_SIZE, true)?;
+    self.write_large_memory(self.staging_address(), data, self.transfer_block_size(), true)?;
 
     let sector_count = data.len().div_ceil(PART_SECTOR_SIZE);
-    self.bulkcmd(&format!("mmc write {ADDR_TMP:#X} 0 {sector_count:#X}"))?;
+    self.commit_staged(&format!("mmc write {:#X} 0 {sector_count:#X}", self.staging_address()))?;
+    self.record_write(sector_count * PART_SECTOR_SIZE);
 
     self.bulkcmd("mmc dev 1 0")?;
     Ok(())
   }
 
+  /// Read the start of a boot hwpartition (boot0 / boot1).
+  ///
+  /// # Parameters
+  /// - `hwpart`: 1 for boot0, 2 for boot1.
+  /// - `offset`: byte to start at, a multiple of the sector size.
+  /// - `length`: bytes to read, at most the profile's `max_transfer_size`.
+  pub fn read_boot_partition(&self, hwpart: u8, offset: usize, length: usize) -> Result<Vec<u8>> {
+    if !(1..=2).contains(&hwpart) {
+      return Err(Error::InvalidOperation(format!(
+        "boot hwpart must be 1 or 2, got {hwpart}"
+      )));
+    }
+
+    self.bulkcmd(&format!("mmc dev 1 {hwpart}"))?;
+    let data = self.read_disk_chunk(offset / PART_SECTOR_SIZE, length);
+    self.bulkcmd("mmc dev 1 0")?;
+    data
+  }
+
   /// Stream bytes onto the user area at an absolute LBA, chunked with progress.
   ///
   /// Same DDR-stage + `mmc write` loop as `write_large_memory_to_disk`, but
   /// takes the LBA directly (no byte->sector conversion at the call site) and
   /// pins hwpart 0 up front so a prior `mmc dev 1 N` for a boot partition
-  /// doesn't leak into the write.
+  /// doesn't leak into the write. With `compare_before_write`, chunks the user
+  /// area already holds are read back and skipped instead of rewritten.
   #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
   pub fn write_user_area<R: Read, F: Fn(FlashProgress)>(
     &self,
     lba_offset: u32,
     mut reader: R,
     data_size: usize,
+    compare_before_write: bool,
     progress_callback: F,
   ) -> Result<()> {
     tracing::info!(
@@ -1101,9 +1317,9 @@ impl AmlogicSoC {
     self.bulkcmd("mmc dev 1 0")?;
     self.bulkcmd("amlmmc key")?;
 
-    let max_bytes_per_transfer = TRANSFER_SIZE_THRESHOLD;
-    let mut offset = 0;
-    let mut buffer = vec![0u8; max_bytes_per_transfer];
+    let max_bytes_per_transfer = self.max_transfer_size();
+    let start = self.resume_offset(&mut reader, data_size)?;
+    let mut offset = start;
 
     while offset < data_size {
       let chunk_start_time = std::time::Instant::now();
@@ -1111,40 +1327,55 @@ impl AmlogicSoC {
       let remaining = data_size - offset;
       let write_length = std::cmp::min(remaining, max_bytes_per_transfer);
 
-      let data_slice = &mut buffer[..write_length];
-      reader.read_exact(data_slice)?;
-
-      self.write_large_memory(ADDR_TMP, &buffer[..write_length], TRANSFER_BLOCK_SIZE, true)?;
-
       let chunk_lba = lba_offset as usize + offset / PART_SECTOR_SIZE;
-      let chunk_sectors = write_length / PART_SECTOR_SIZE;
+      if !self
+        .stage_chunk(
+          &mut reader,
+          write_length,
+          self.transfer_block_size(),
+          true,
+          compare_before_write,
+          |chunk| self.disk_region_matches(chunk_lba, chunk),
+        )
+        .map_err(|e| e.with_context(|c| c.offset = Some((chunk_lba * PART_SECTOR_SIZE) as u64)))?
+      {
+        tracing::debug!(target: TRANSFER_TARGET, "user area at LBA {chunk_lba:#X} already matches, skipping write");
+        self.record_skip(write_length);
+      } else {
+        let chunk_sectors = write_length / PART_SECTOR_SIZE;
 
-      let cmd_start = std::time::Instant::now();
-      let mut retries = 0;
-      let max_retries = 3;
-      loop {
-        match self.bulkcmd(&format!("mmc write {ADDR_TMP:#X} {chunk_lba:#X} {chunk_sectors:#X}")) {
-          Ok(_) => {
-            if cmd_start.elapsed() > Duration::from_millis(3000) {
-              tracing::debug!("mmc write took {}ms, cooling down 5s", cmd_start.elapsed().as_millis());
-              sleep(Duration::from_secs(5));
+        let cmd_start = std::time::Instant::now();
+        let mut retries = 0;
+        let max_retries = 3;
+        loop {
+          match self.commit_staged(&format!(
+            "mmc write {:#X} {chunk_lba:#X} {chunk_sectors:#X}",
+            self.staging_address()
+          )) {
+            Ok(_) => {
+              self.cool_down(cmd_start.elapsed());
+              break;
             }
-            break;
-          }
-          Err(e) => {
-            retries += 1;
-            if retries >= max_retries {
-              return Err(e);
+            Err(e) => {
+              retries += 1;
+              if retries >= max_retries {
+                return Err(e.with_context(|c| {
+                  c.offset = Some((chunk_lba * PART_SECTOR_SIZE) as u64);
+                  c.retries = retries - 1;
+                }));
+              }
+              telemetry::retry("mmc_write");
+              tracing::warn!(
+                "mmc write failed at LBA {chunk_lba:#X}, retrying ({}/{}): {}",
+                retries,
+                max_retries,
+                e
+              );
+              sleep(self.inner.profile.thermal.error_cooldown);
             }
-            tracing::warn!(
-              "mmc write failed at LBA {chunk_lba:#X}, retrying ({}/{}): {}",
-              retries,
-              max_retries,
-              e
-            );
-            sleep(Duration::from_secs(5));
           }
         }
+        self.record_write(write_length);
       }
 
       let chunk_time_secs = chunk_start_time.elapsed().as_secs_f64();
@@ -1156,12 +1387,13 @@ impl AmlogicSoC {
       }
 
       offset += write_length;
+      self.inner.streamed.store(offset as u64, Ordering::Relaxed);
       let progress_percent = offset as f64 / data_size as f64 * 100.0;
       let elapsed_secs = start_time.elapsed().as_secs_f64();
       let bytes_per_sec = if elapsed_secs > 0.0 {
-        offset as f64 / elapsed_secs
+        (offset - start) as f64 / elapsed_secs
       } else {
-        offset as f64
+        (offset - start) as f64
       };
       let eta_secs = if bytes_per_sec > 0.0 {
         (data_size - offset) as f64 / bytes_per_sec
@@ -1176,7 +1408,12 @@ impl AmlogicSoC {
         rate: write_length as f64 / chunk_time_secs / 1024.0,
         avg_chunk_time: avg_chunk_time_secs * 1000.0,
         avg_rate: bytes_per_sec / 1024.0,
+        step_id: None,
       });
+
+      if offset < data_size {
+        self.check_cancelled()?;
+      }
     }
 
     tracing::info!(
@@ -1187,6 +1424,104 @@ impl AmlogicSoC {
     Ok(())
   }
 
+  /// Erase `sectors` sectors of the user area starting at `lba` with `mmc erase`
+  ///
+  /// Erased sectors read back as zeros on most eMMC, superbird's included.
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn erase_user_area(&self, lba: u32, sectors: u32) -> Result<()> {
+    tracing::info!("erasing {} sectors of the user area starting at LBA {}", sectors, lba);
+    self.bulkcmd("mmc dev 1 0")?;
+    self.bulkcmd(&format!("mmc erase {lba:#X} {sectors:#X}"))?;
+    self.inner.wrote.store(true, Ordering::Relaxed);
+    Ok(())
+  }
+
+  /// Write the bootloader to every copy the device boots from, verifying each one
+  ///
+  /// The image is written to the boot0 and boot1 hardware partitions (where the eMMC has
+  /// them) and then to the user area, switching between them with `amlmmc switch`. The
+  /// `amlmmc write bootloader` command routinely times out over USB even when it succeeds,
+  /// so rather than trusting its response, the first sectors of each copy are read back and
+  /// compared against the image.
+  ///
+  /// # Parameters
+  /// - `data`: The bootloader image; zero padding past 2MB is ignored
+  /// - `compare_before_write`: Whether to skip copies that already match
+  ///
+  /// # Returns
+  /// - `Result<()>`: Success once every copy is verified, or an error naming the copy that failed
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn write_bootloader(&self, data: &[u8], compare_before_write: bool) -> Result<()> {
+    let data = if data.len() > BOOTLOADER_SIZE {
+      // dumps may be zero-padded to 4MB, but anything else past 2MB would be silently lost
+      if data[BOOTLOADER_SIZE..].iter().any(|&b| b != 0) {
+        return Err(Error::InvalidOperation(format!(
+          "bootloader image is {} bytes and has data past the {} byte bootloader area",
+          data.len(),
+          BOOTLOADER_SIZE
+        )));
+      }
+      &data[..BOOTLOADER_SIZE]
+    } else {
+      data
+    };
+
+    self.bulkcmd("amlmmc key")?;
+
+    let verify_len = std::cmp::min(data.len(), BOOTLOADER_VERIFY_SIZE);
+    let result = ["boot0", "boot1", "user"].iter().try_for_each(|target| {
+      if let Err(e) = self.bulkcmd(&format!("amlmmc switch 1 {target}")) {
+        if *target == "user" {
+          return Err(e);
+        }
+        tracing::info!("eMMC has no {} hardware partition, skipping: {}", target, e);
+        return Ok(());
+      }
+
+      if compare_before_write && self.read_partition_chunk("bootloader", 0, data.len())? == data {
+        tracing::debug!("bootloader on {} already matches, skipping write", target);
+        self.record_skip(data.len());
+        return Ok(());
+      }
+
+      tracing::info!("writing {} bytes of bootloader to {}", data.len(), target);
+      self.write_large_memory(self.staging_address(), data, self.transfer_block_size(), true)?;
+      if let Err(e) = self.bulkcmd(&format!(
+        "amlmmc write bootloader {:#x} 0 {:#x}",
+        self.staging_address(),
+        data.len()
+      )) {
+        tracing::debug!("bootloader write to {} did not acknowledge, verifying: {}", target, e);
+      }
+      self.record_write(data.len());
+
+      let mut attempts = 0;
+      loop {
+        sleep(Duration::from_secs(2));
+        attempts += 1;
+        match self.read_partition_chunk("bootloader", 0, verify_len) {
+          Ok(readback) if readback == data[..verify_len] => {
+            tracing::info!("bootloader on {} verified", target);
+            return Ok(());
+          }
+          Ok(_) => {
+            return Err(Error::InvalidOperation(format!(
+              "bootloader on {target} does not match the image after writing"
+            )));
+          }
+          Err(e) if attempts < 5 => tracing::debug!("device busy after bootloader write, retrying: {}", e),
+          Err(e) => return Err(e),
+        }
+      }
+    });
+
+    // always hand the user area back, even if a copy failed
+    if let Err(e) = self.bulkcmd("amlmmc switch 1 user") {
+      tracing::warn!("failed to switch back to the user area: {}", e);
+    }
+    result
+  }
+
   /// Restore a partition from a data source
   ///
   /// # Parameters
@@ -1194,6 +1529,7 @@ impl AmlogicSoC {
   /// - `part_size`: The size of the partition
   /// - `reader`: A reader providing the partition data
   /// - `file_size`: The size of the data being read
+  /// - `compare_before_write`: Whether to skip chunks the partition already holds
   /// - `progress_callback`: Function to call with progress updates
   ///
   /// # Returns
@@ -1205,21 +1541,33 @@ impl AmlogicSoC {
     part_size: usize,
     mut reader: R,
     file_size: usize,
+    compare_before_write: bool,
     progress_callback: F,
   ) -> Result<()> {
+    let part_name = &canonical_partition_name(part_name);
     tracing::debug!("restoring partition: {} with file size: {}", part_name, file_size);
 
-    let adjusted_part_size = if part_name == "bootloader" {
-      // Bootloader is only 2MB, though dumps may be zero-padded to 4MB
-      2 * 1024 * 1024
-    } else {
-      part_size
-    };
+    if part_name == "bootloader" {
+      let mut data = vec![0u8; file_size];
+      reader.read_exact(&mut data)?;
+      self.write_bootloader(&data, compare_before_write)?;
 
-    if file_size > adjusted_part_size && part_name != "bootloader" {
+      progress_callback(FlashProgress {
+        percent: 100.0,
+        elapsed: 0.0,
+        eta: 0.0,
+        rate: 0.0,
+        avg_chunk_time: 0.0,
+        avg_rate: 0.0,
+        step_id: None,
+      });
+      return Ok(());
+    }
+
+    if file_size > part_size {
       return Err(Error::InvalidOperation(format!(
         "file is larger than target partition: {} bytes vs {} bytes",
-        file_size, adjusted_part_size
+        file_size, part_size
       )));
     }
 
@@ -1230,9 +1578,9 @@ impl AmlogicSoC {
     self.bulkcmd("amlmmc key")?;
 
     let total_len = file_size;
-    let max_bytes_per_transfer = TRANSFER_SIZE_THRESHOLD;
-    let mut offset = 0;
-    let mut buffer = vec![0u8; max_bytes_per_transfer];
+    let max_bytes_per_transfer = self.max_transfer_size();
+    let start = self.resume_offset(&mut reader, total_len)?;
+    let mut offset = start;
 
     while offset < total_len {
       let chunk_start_time = std::time::Instant::now();
@@ -1240,50 +1588,57 @@ impl AmlogicSoC {
       let remaining = total_len - offset;
       let write_length = std::cmp::min(remaining, max_bytes_per_transfer);
 
-      let data_slice = &mut buffer[..write_length];
-      reader.read_exact(data_slice)?;
-
-      self.write_large_memory(ADDR_TMP, &buffer[..write_length], TRANSFER_BLOCK_SIZE, true)?;
-
-      let start_time_cmd = std::time::Instant::now();
-      let mut retries = 0;
-      let max_retries = 3;
-
-      // Special handling for bootloader partition
-      if part_name == "bootloader" {
-        // Bootloader writes always cause timeout - this is expected
-        match self.bulkcmd(&format!(
-          "amlmmc write {} {:#x} {:#x} {:#x}",
-          part_name, ADDR_TMP, offset, write_length
-        )) {
-          Ok(_) => tracing::debug!("bootloader write succeeded unexpectedly"),
-          Err(e) => tracing::debug!("expected timeout for bootloader write: {}", e),
-        }
-        sleep(Duration::from_secs(2)); // Allow time for write to complete
+      if !self
+        .stage_chunk(
+          &mut reader,
+          write_length,
+          self.transfer_block_size(),
+          true,
+          compare_before_write,
+          |chunk| self.partition_region_matches(part_name, offset, chunk),
+        )
+        .map_err(|e| {
+          e.with_context(|c| {
+            c.partition = Some(part_name.to_string());
+            c.offset = Some(offset as u64);
+          })
+        })?
+      {
+        tracing::debug!(target: TRANSFER_TARGET, "{} at {:#x} already matches, skipping write", part_name, offset);
+        self.record_skip(write_length);
       } else {
+        let start_time_cmd = std::time::Instant::now();
+        let mut retries = 0;
+        let max_retries = 3;
+
         loop {
-          match self.bulkcmd(&format!(
+          match self.commit_staged(&format!(
             "amlmmc write {} {:#x} {:#x} {:#x}",
-            part_name, ADDR_TMP, offset, write_length
+            part_name,
+            self.staging_address(),
+            offset,
+            write_length
           )) {
             Ok(_) => {
-              let elapsed = start_time_cmd.elapsed();
-              if elapsed > Duration::from_millis(3000) {
-                tracing::debug!("write command took {}ms, cooling down for 5s", elapsed.as_millis());
-                sleep(Duration::from_secs(5));
-              }
+              self.cool_down(start_time_cmd.elapsed());
               break;
             }
             Err(e) => {
               retries += 1;
               if retries >= max_retries {
-                return Err(e);
+                return Err(e.with_context(|c| {
+                  c.partition = Some(part_name.to_string());
+                  c.offset = Some(offset as u64);
+                  c.retries = retries - 1;
+                }));
               }
+              telemetry::retry("mmc_write");
               tracing::warn!("write command failed, retrying ({}/{}): {}", retries, max_retries, e);
-              sleep(Duration::from_secs(5)); // cooldown after error
+              sleep(self.inner.profile.thermal.error_cooldown);
             }
           }
         }
+        self.record_write(write_length);
       }
 
       let chunk_time = chunk_start_time.elapsed();
@@ -1296,14 +1651,15 @@ impl AmlogicSoC {
       }
 
       offset += write_length;
+      self.inner.streamed.store(offset as u64, Ordering::Relaxed);
       let progress_percent = offset as f64 / total_len as f64 * 100.0;
 
       let elapsed = start_time.elapsed();
       let elapsed_secs = elapsed.as_secs_f64();
       let bytes_per_sec = if elapsed_secs > 0.0 {
-        offset as f64 / elapsed_secs
+        (offset - start) as f64 / elapsed_secs
       } else {
-        offset as f64
+        (offset - start) as f64
       };
 
       let remaining_bytes = total_len - offset;
@@ -1313,7 +1669,8 @@ impl AmlogicSoC {
         0.0
       };
 
-      tracing::info!(
+      tracing::debug!(
+        target: TRANSFER_TARGET,
         "progress: {:.1}% | elapsed: {:.1}s | eta: {:.1}s | rate: {:.2} KB/s | avg chunk: {:.1}s | avg rate: {:.2} KB/s",
         progress_percent,
         elapsed_secs,
@@ -1330,7 +1687,12 @@ impl AmlogicSoC {
         rate: write_length as f64 / chunk_time_secs / 1024.0,
         avg_chunk_time: avg_chunk_time_secs * 1000.0,
         avg_rate: bytes_per_sec / 1024.0,
+        step_id: None,
       });
+
+      if offset < total_len {
+        self.check_cancelled()?;
+      }
     }
 
     let total_elapsed = start_time.elapsed();
@@ -1350,6 +1712,458 @@ impl AmlogicSoC {
     Ok(())
   }
 
+  /// Read a region of a partition into host memory
+  ///
+  /// # Parameters
+  /// - `part_name`: The name of the partition to read from
+  /// - `offset`: Byte offset into the partition, must be sector aligned
+  /// - `length`: The number of bytes to read, at most the profile's `max_transfer_size`
+  ///
+  /// # Returns
+  /// - `Result<Vec<u8>>`: The read data or an error
+  #[cfg_attr(
+    feature = "instrument",
+    tracing::instrument(target = "flashthing::transfer", level = "trace", skip_all)
+  )]
+  pub fn read_partition_chunk(&self, part_name: &str, offset: usize, length: usize) -> Result<Vec<u8>> {
+    let part_name = &canonical_partition_name(part_name);
+    if length > self.max_transfer_size() {
+      return Err(Error::InvalidOperation(format!(
+        "partition read of {} bytes exceeds single-transfer cap {}",
+        length,
+        self.max_transfer_size()
+      )));
+    }
+
+    self.bulkcmd(&format!(
+      "amlmmc read {} {:#x} {:#x} {:#x}",
+      part_name,
+      self.staging_address(),
+      offset,
+      length
+    ))?;
+
+    let padded = length.div_ceil(self.transfer_block_size()) * self.transfer_block_size();
+    let mut data = self.read_large_memory(self.staging_address(), padded, self.transfer_block_size())?;
+    data.truncate(length);
+    Ok(data)
+  }
+
+  /// Read a region of the currently selected mmc device into host memory
+  ///
+  /// # Parameters
+  /// - `lba`: Absolute LBA to start reading from
+  /// - `length`: The number of bytes to read, at most the profile's `max_transfer_size`
+  ///
+  /// # Returns
+  /// - `Result<Vec<u8>>`: The read data or an error
+  #[cfg_attr(
+    feature = "instrument",
+    tracing::instrument(target = "flashthing::transfer", level = "trace", skip_all)
+  )]
+  pub fn read_disk_chunk(&self, lba: usize, length: usize) -> Result<Vec<u8>> {
+    if length > self.max_transfer_size() {
+      return Err(Error::InvalidOperation(format!(
+        "disk read of {} bytes exceeds single-transfer cap {}",
+        length,
+        self.max_transfer_size()
+      )));
+    }
+
+    self.stage_disk_chunk(lba, length)?;
+
+    let padded = length.div_ceil(self.transfer_block_size()) * self.transfer_block_size();
+    let mut data = self.read_large_memory(self.staging_address(), padded, self.transfer_block_size())?;
+    data.truncate(length);
+    Ok(data)
+  }
+
+  /// Copy `length` bytes of the currently selected mmc device at `lba` to the staging area
+  fn stage_disk_chunk(&self, lba: usize, length: usize) -> Result<()> {
+    let sectors = length.div_ceil(PART_SECTOR_SIZE);
+    self.bulkcmd(&format!("mmc read {:#X} {lba:#X} {sectors:#X}", self.staging_address()))?;
+    Ok(())
+  }
+
+  /// Read `length` bytes from the staging area into a pooled buffer
+  fn read_staged(&self, length: usize) -> Result<PooledBuffer<'_>> {
+    let mut buf = self
+      .inner
+      .buffers
+      .take(length.div_ceil(self.transfer_block_size()) * self.transfer_block_size());
+    self
+      .session()
+      .read_large_memory_into(self.staging_address(), &mut buf, self.transfer_block_size())?;
+    buf.truncate(length);
+    Ok(buf)
+  }
+
+  /// Get the next `length` bytes of `reader` into the staging area, unless `matches` finds the
+  /// destination already holds them
+  ///
+  /// Without `compare_before_write` the chunk is sent as it is read from `reader`. With it, the
+  /// chunk is read into a pooled buffer to compare and is sent from there.
+  ///
+  /// # Returns
+  /// - `Result<bool>`: Whether the chunk was staged and still has to be written, or an error
+  fn stage_chunk<R: Read>(
+    &self,
+    reader: &mut R,
+    length: usize,
+    block_length: usize,
+    append_zeros: bool,
+    compare_before_write: bool,
+    matches: impl FnOnce(&[u8]) -> Result<bool>,
+  ) -> Result<bool> {
+    if !compare_before_write {
+      self.write_large_memory_from_reader(self.staging_address(), reader, length, block_length, append_zeros)?;
+      return Ok(true);
+    }
+
+    let mut chunk = self.inner.buffers.take(length);
+    reader.read_exact(&mut chunk)?;
+    if matches(&chunk)? {
+      return Ok(false);
+    }
+    self.write_large_memory(self.staging_address(), &chunk, block_length, append_zeros)?;
+    Ok(true)
+  }
+
+  /// Check whether the disk already holds `data` at `lba`, so the write can be skipped.
+  ///
+  /// The region is read back and compared on the host; both sides are already in memory,
+  /// so a direct comparison is as cheap as hashing and cannot collide.
+  fn disk_region_matches(&self, lba: usize, data: &[u8]) -> Result<bool> {
+    self.stage_disk_chunk(lba, data.len())?;
+    Ok(*self.read_staged(data.len())? == *data)
+  }
+
+  /// Check whether the partition already holds `data` at byte `offset`, so the write can be skipped
+  fn partition_region_matches(&self, part_name: &str, offset: usize, data: &[u8]) -> Result<bool> {
+    self.bulkcmd(&format!(
+      "amlmmc read {} {:#x} {:#x} {:#x}",
+      part_name,
+      self.staging_address(),
+      offset,
+      data.len()
+    ))?;
+    Ok(*self.read_staged(data.len())? == *data)
+  }
+
+  /// Read an entire partition into host memory with progress tracking
+  ///
+  /// # Parameters
+  /// - `part_name`: The name of the partition to read
+  /// - `part_size`: The size of the partition in bytes
+  /// - `progress_callback`: Function to call with progress updates
+  ///
+  /// # Returns
+  /// - `Result<Vec<u8>>`: The partition contents or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn read_partition<F: Fn(FlashProgress)>(
+    &self,
+    part_name: &str,
+    part_size: usize,
+    progress_callback: F,
+  ) -> Result<Vec<u8>> {
+    let part_name = &canonical_partition_name(part_name);
+    tracing::debug!("reading partition: {} with size: {}", part_name, part_size);
+
+    self.read_chunked(
+      part_size,
+      |offset, length| self.read_partition_chunk(part_name, offset, length),
+      progress_callback,
+    )
+  }
+
+  /// Read an entire partition by its raw offset on the user area, bypassing `amlmmc`
+  ///
+  /// This is how special partitions like `reserved`, which `amlmmc` refuses to access, are
+  /// backed up.
+  ///
+  /// # Parameters
+  /// - `part_info`: The partition to read
+  /// - `progress_callback`: Function to call with progress updates
+  ///
+  /// # Returns
+  /// - `Result<Vec<u8>>`: The partition contents or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn read_partition_raw<F: Fn(FlashProgress)>(
+    &self,
+    part_info: &PartitionInfo,
+    progress_callback: F,
+  ) -> Result<Vec<u8>> {
+    tracing::debug!(
+      "reading partition: {} raw from LBA {:#x} with size: {}",
+      part_info.name,
+      part_info.offset,
+      part_info.size_bytes()
+    );
+
+    self.bulkcmd("mmc dev 1 0")?;
+    self.read_chunked(
+      part_info.size_bytes(),
+      |offset, length| self.read_disk_chunk(part_info.offset + offset / PART_SECTOR_SIZE, length),
+      progress_callback,
+    )
+  }
+
+  /// Compute a digest of a partition, streaming it through the host in chunks
+  ///
+  /// Nothing is kept in memory, so this is how large partitions like the system slots are checked
+  /// against known-good hashes without dumping them.
+  ///
+  /// # Parameters
+  /// - `part_name`: The name of the partition to hash
+  /// - `algorithm`: The hash function to use
+  /// - `length`: Bytes to hash from the start of the partition, or the whole partition if `None`
+  /// - `progress_callback`: Function to call with progress updates
+  ///
+  /// # Returns
+  /// - `Result<String>`: The lowercase hex digest or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn hash_partition<F: Fn(FlashProgress)>(
+    &self,
+    part_name: &str,
+    algorithm: HashAlgorithm,
+    length: Option<usize>,
+    progress_callback: F,
+  ) -> Result<String> {
+    let part_info = self.profile().partitions.resolve(part_name)?;
+    let part_size = self.validate_partition_size(&part_info.name, part_info, false)?;
+    let length = match length {
+      Some(length) if length > part_size => {
+        return Err(Error::InvalidOperation(format!(
+          "cannot hash {} bytes of {}, which is only {} bytes",
+          length, part_info.name, part_size
+        )));
+      }
+      Some(length) => length,
+      None => part_size,
+    };
+
+    match algorithm {
+      HashAlgorithm::Sha256 => self.digest_partition::<Sha256, F>(&part_info.name, length, progress_callback),
+      HashAlgorithm::Sha512 => self.digest_partition::<Sha512, F>(&part_info.name, length, progress_callback),
+    }
+  }
+
+  pub(crate) fn digest_partition<D: Digest, F: Fn(FlashProgress)>(
+    &self,
+    part_name: &str,
+    length: usize,
+    progress_callback: F,
+  ) -> Result<String> {
+    let part_name = &canonical_partition_name(part_name);
+    tracing::debug!("hashing {} bytes of partition: {}", length, part_name);
+
+    let mut hasher = D::new();
+    self.stream_partition(
+      part_name,
+      length,
+      |chunk| {
+        hasher.update(chunk);
+        Ok(())
+      },
+      progress_callback,
+    )?;
+    Ok(to_hex(&hasher.finalize()))
+  }
+
+  /// Read the first `length` bytes of a partition through `amlmmc`, handing each chunk to `on_chunk`
+  pub(crate) fn stream_partition<C: FnMut(&[u8]) -> Result<()>, F: Fn(FlashProgress)>(
+    &self,
+    part_name: &str,
+    length: usize,
+    on_chunk: C,
+    progress_callback: F,
+  ) -> Result<()> {
+    self.stream_chunked(
+      0,
+      length,
+      |offset, length| self.read_partition_chunk(part_name, offset, length),
+      on_chunk,
+      progress_callback,
+    )
+  }
+
+  fn read_chunked<R: Fn(usize, usize) -> Result<Vec<u8>>, F: Fn(FlashProgress)>(
+    &self,
+    part_size: usize,
+    read_chunk: R,
+    progress_callback: F,
+  ) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(part_size);
+    self.stream_chunked(
+      0,
+      part_size,
+      read_chunk,
+      |chunk| {
+        data.extend_from_slice(chunk);
+        Ok(())
+      },
+      progress_callback,
+    )?;
+    Ok(data)
+  }
+
+  /// Read `start..part_size` in chunks of at most `max_transfer_size`, handing each to `on_chunk`
+  ///
+  /// `read_chunk` is called with an offset relative to the start of the region and a length.
+  pub(crate) fn stream_chunked<
+    R: Fn(usize, usize) -> Result<Vec<u8>>,
+    C: FnMut(&[u8]) -> Result<()>,
+    F: Fn(FlashProgress),
+  >(
+    &self,
+    start: usize,
+    part_size: usize,
+    read_chunk: R,
+    mut on_chunk: C,
+    progress_callback: F,
+  ) -> Result<()> {
+    let start_time = std::time::Instant::now();
+    let mut read = start;
+
+    while read < part_size {
+      let chunk_start_time = std::time::Instant::now();
+      let read_length = std::cmp::min(part_size - read, self.max_transfer_size());
+
+      let chunk = read_chunk(read, read_length)?;
+      on_chunk(&chunk)?;
+      read += chunk.len();
+
+      let chunk_time_secs = chunk_start_time.elapsed().as_secs_f64();
+      let elapsed_secs = start_time.elapsed().as_secs_f64();
+      let bytes_per_sec = if elapsed_secs > 0.0 {
+        (read - start) as f64 / elapsed_secs
+      } else {
+        (read - start) as f64
+      };
+      let eta_secs = if bytes_per_sec > 0.0 {
+        (part_size - read) as f64 / bytes_per_sec
+      } else {
+        0.0
+      };
+
+      progress_callback(FlashProgress {
+        percent: read as f64 / part_size as f64 * 100.0,
+        elapsed: elapsed_secs * 1000.0,
+        eta: eta_secs * 1000.0,
+        rate: read_length as f64 / chunk_time_secs / 1024.0,
+        avg_chunk_time: elapsed_secs * 1000.0 / (read - start).div_ceil(self.max_transfer_size()) as f64,
+        avg_rate: bytes_per_sec / 1024.0,
+        step_id: None,
+      });
+    }
+
+    tracing::info!(
+      "partition read complete: {} bytes in {:?}",
+      part_size - start,
+      start_time.elapsed()
+    );
+    Ok(())
+  }
+
+  /// Apply a binary delta to a partition, writing only the regions that changed
+  ///
+  /// The current partition contents are read back into a temporary file as the patch base, the
+  /// bsdiff patch is applied on the fly, and each reconstructed chunk is compared against the base
+  /// so that only the sectors that differ are sent back to the device.
+  ///
+  /// # Parameters
+  /// - `part_name`: The name of the partition to patch
+  /// - `part_size`: The size of the partition in bytes
+  /// - `delta`: The bsdiff patch, optionally zstd compressed
+  /// - `progress_callback`: Function to call with progress updates while reading the base
+  ///
+  /// # Returns
+  /// - `Result<usize>`: The number of bytes written to the device or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn apply_partition_delta<F: Fn(FlashProgress)>(
+    &self,
+    part_name: &str,
+    part_size: usize,
+    delta: &[u8],
+    progress_callback: F,
+  ) -> Result<usize> {
+    let part_name = &canonical_partition_name(part_name);
+    if part_name == "bootloader" {
+      return Err(Error::InvalidOperation(
+        "deltas cannot be applied to the bootloader, use restorePartition instead".into(),
+      ));
+    }
+
+    // the base is spooled to a file rather than held in memory, since partitions run to gigabytes
+    tracing::info!("reading {} to use as delta base", part_name);
+    let mut base = Spool::create("delta-base")?;
+    self.stream_chunked(
+      0,
+      part_size,
+      |offset, length| self.read_partition_chunk(part_name, offset, length),
+      |chunk| Ok(base.write_all(chunk)?),
+      progress_callback,
+    )?;
+    // a second handle reads back the part of the base each chunk of the new image replaces
+    let mut compare = std::fs::File::open(base.path())?;
+    let mut old = Vec::new();
+
+    self.bulkcmd("amlmmc key")?;
+
+    let mut bytes_written = 0;
+    let new_size = crate::delta::apply_bsdiff(
+      &mut base,
+      part_size,
+      delta,
+      self.max_transfer_size(),
+      |offset, chunk| {
+        if offset + chunk.len() > part_size {
+          return Err(Error::InvalidOperation(format!(
+            "patched image is larger than target partition: {} bytes",
+            part_size
+          )));
+        }
+
+        old.resize(chunk.len(), 0);
+        compare.seek(SeekFrom::Start(offset as u64))?;
+        compare.read_exact(&mut old)?;
+        let Some((start, end)) = crate::delta::changed_span(&old, chunk, PART_SECTOR_SIZE) else {
+          tracing::debug!(target: TRANSFER_TARGET, "delta chunk at {:#x} unchanged, skipping", offset);
+          self.record_skip(chunk.len());
+          return Ok(());
+        };
+
+        tracing::debug!(target: TRANSFER_TARGET, "writing changed region {:#x}..{:#x}", offset + start, offset + end);
+        self.write_large_memory(
+          self.staging_address(),
+          &chunk[start..end],
+          self.transfer_block_size(),
+          true,
+        )?;
+        self.commit_staged(&format!(
+          "amlmmc write {} {:#x} {:#x} {:#x}",
+          part_name,
+          self.staging_address(),
+          offset + start,
+          end - start
+        ))?;
+
+        bytes_written += end - start;
+        self.record_write(end - start);
+        self.record_skip(chunk.len() - (end - start));
+        Ok(())
+      },
+    )?;
+
+    tracing::info!(
+      "delta applied to {}: {} byte image, {} bytes written",
+      part_name,
+      new_size,
+      bytes_written
+    );
+    Ok(bytes_written)
+  }
+
   /// Execute the unbrick procedure
   ///
   /// This writes the emergency unbrick image to the device.
@@ -1360,7 +2174,13 @@ impl AmlogicSoC {
   pub fn unbrick(&self) -> Result<()> {
     tracing::info!("starting unbrick procedure...");
 
-    let cursor = std::io::Cursor::new(UNBRICK_BIN_ZIP);
+    let Some(unbrick) = self.profile().unbrick.as_deref() else {
+      return Err(Error::InvalidOperation(format!(
+        "device profile {} has no unbrick image",
+        self.profile().name
+      )));
+    };
+    let cursor = std::io::Cursor::new(unbrick);
 
     let mut archive = match zip::ZipArchive::new(cursor) {
       Ok(archive) => archive,
@@ -1379,16 +2199,24 @@ impl AmlogicSoC {
     };
 
     let file_size = file.size() as usize;
-    self.write_large_memory_to_disk(0, &mut file, file_size, TRANSFER_BLOCK_SIZE, true, |progress| {
-      tracing::info!(
-        "unbrick progress: {:.1}% | elapsed: {:.1}s | eta: {:.1}s | rate: {:.2} KB/s | avg rate: {:.2} KB/s",
-        progress.percent,
-        progress.elapsed,
-        progress.eta,
-        progress.rate,
-        progress.avg_rate
-      );
-    })?;
+    self.write_large_memory_to_disk(
+      0,
+      &mut file,
+      file_size,
+      self.transfer_block_size(),
+      true,
+      false,
+      |progress| {
+        tracing::info!(
+          "unbrick progress: {:.1}% | elapsed: {:.1}s | eta: {:.1}s | rate: {:.2} KB/s | avg rate: {:.2} KB/s",
+          progress.percent,
+          progress.elapsed,
+          progress.eta,
+          progress.rate,
+          progress.avg_rate
+        );
+      },
+    )?;
 
     tracing::info!("unbrick procedure completed successfully!");
     Ok(())
@@ -1396,102 +2224,126 @@ impl AmlogicSoC {
 
   /// Set up the host environment for USB access
   ///
-  /// On Linux, this creates udev rules to allow access to the device.
+  /// On Linux, this creates udev rules to allow access to the device. On macOS, which needs no
+  /// setup, it checks for another process holding the device and for a missing USB sandbox
+  /// entitlement. Inside Flatpak or Snap confinement nothing is written, since /etc is out of
+  /// reach; the status says what the user has to do on the host instead.
   ///
   /// # Returns
-  /// - `Result<()>`: Success or an error
-  pub fn host_setup() -> Result<()> {
+  /// - `Result<HostSetupStatus>`: What was done and what still has to be fixed by hand
+  pub fn host_setup() -> Result<HostSetupStatus> {
+    Self::host_setup_with_profile(&DeviceProfile::default())
+  }
+
+  /// Set up the host environment for USB access to the device `profile` describes
+  ///
+  /// Like [`host_setup`](Self::host_setup), but on macOS looks for another process holding a
+  /// device with the profile's USB IDs rather than the superbird's.
+  ///
+  /// # Returns
+  /// - `Result<HostSetupStatus>`: What was done and what still has to be fixed by hand
+  pub fn host_setup_with_profile(profile: &DeviceProfile) -> Result<HostSetupStatus> {
+    Self::host_setup_with_group(profile, None)
+  }
+
+  /// Set up the host environment for USB access, also giving members of `group` access on Linux
+  ///
+  /// Like [`host_setup_with_profile`](Self::host_setup_with_profile), but the udev rules also
+  /// give the group, e.g. `plugdev`, access to the device, for headless machines and remote
+  /// sessions where there is no logged-in user at the seat for `uaccess` to pick.
+  ///
+  /// # Returns
+  /// - `Result<HostSetupStatus>`: What was done and what still has to be fixed by hand
+  #[cfg_attr(not(target_os = "macos"), allow(unused_variables))]
+  pub fn host_setup_with_group(profile: &DeviceProfile, group: Option<&str>) -> Result<HostSetupStatus> {
+    if let Some(group) = group {
+      crate::setup::check_udev_group(group)?;
+    }
     #[cfg(target_os = "linux")]
-    crate::setup::setup_host_linux()?;
+    return crate::setup::setup_host_linux(group);
+    #[cfg(target_os = "macos")]
+    return crate::setup::setup_host_macos(profile);
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    Ok(HostSetupStatus {
+      confinement: crate::setup::confinement(),
+      ..Default::default()
+    })
+  }
 
-    Ok(())
+  /// Get the udev rules `host_setup` installs on Linux
+  ///
+  /// Useful on headless systems without polkit, or for packaging, where the caller installs
+  /// the rules itself (e.g. to `/etc/udev/rules.d/70-superbird.rules`).
+  ///
+  /// # Returns
+  /// - `String`: The rules file contents
+  pub fn host_setup_rules() -> String {
+    crate::setup::udev_rules(None)
   }
-}
 
-impl Drop for AmlogicSoC {
-  fn drop(&mut self) {
-    match self.inner.handle.release_interface(self.inner.interface_number) {
-      Ok(()) => tracing::trace!("successfully dropped usb interface"),
-      Err(err) => tracing::warn!("failed to release usb interface: {:?}", err),
+  /// Get the udev rules [`host_setup_with_group`](Self::host_setup_with_group) installs on Linux
+  ///
+  /// # Returns
+  /// - `Result<String>`: The rules file contents, or an error if `group` is not a valid group name
+  pub fn host_setup_rules_with_group(group: Option<&str>) -> Result<String> {
+    if let Some(group) = group {
+      crate::setup::check_udev_group(group)?;
     }
+    Ok(crate::setup::udev_rules(group))
   }
 }
 
-/// The current mode of the Superbird device
+/// Pick the profile's built-in BL2 for a secure boot state
 ///
-/// The device can be in different modes depending on how it was powered on
-/// and what stage of the boot process it's in.
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub enum DeviceMode {
-  /// Normal operating mode (running regular firmware)
-  Normal,
-  /// USB mode (entered by holding buttons 1 & 4 during power-on)
-  Usb,
-  /// USB Burn mode (ready for flashing operations)
-  UsbBurn,
-  /// Device not detected
-  NotFound,
-}
-
-#[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
-fn find_device() -> DeviceMode {
-  let context = match Context::new() {
-    Ok(c) => c,
-    Err(_) => return DeviceMode::NotFound,
-  };
-  let devices = match context.devices() {
-    Ok(d) => d,
-    Err(_) => return DeviceMode::NotFound,
+/// Profiles embed the encrypted BL2 shipped on retail devices, so unfused devices need a
+/// BL2 supplied through the `bl2Boot` step.
+fn embedded_bl2(profile: &DeviceProfile, state: SecureBootState) -> Result<&[u8]> {
+  let Some(bl2) = profile.bl2.as_deref() else {
+    return Err(Error::InvalidOperation(format!(
+      "device profile {} has no built-in BL2",
+      profile.name
+    )));
   };
-  for device in devices.iter() {
-    let desc = match device.device_descriptor() {
-      Ok(d) => d,
-      Err(_) => continue,
-    };
-    // Match normal mode: vendor=0x18d1, product=0x4e40
-    if desc.vendor_id() == 0x18d1 && desc.product_id() == 0x4e40 {
-      tracing::debug!("Found device booted normally, with USB Gadget (adb/usbnet) enabled");
-      return DeviceMode::Normal;
-    }
-    // Match USB burn/usb mode: vendor=0x1b8e, product=0xc003
-    if desc.vendor_id() == 0x1b8e && desc.product_id() == 0xc003 {
-      // Attempt to open device and read product string
-      match device.open() {
-        Ok(handle) => {
-          // Common language ID
-          let lang = handle.read_languages(COMMAND_TIMEOUT).unwrap_or_default();
-          let Some(lang) = lang.first() else {
-            tracing::debug!("Found device in USB Burn Mode (unable to read product string)");
-            return DeviceMode::UsbBurn;
-          };
-
-          let prod = handle
-            .read_product_string(*lang, &desc, Duration::from_millis(100))
-            .ok();
-          if prod.as_deref() == Some("GX-CHIP") {
-            tracing::debug!("Found device booted in USB Mode (buttons 1 & 4 held at boot)");
-            return DeviceMode::Usb;
-          } else {
-            tracing::debug!("Found device booted in USB Burn Mode (ready for commands)");
-            return DeviceMode::UsbBurn;
-          }
-        }
-        Err(_) => {
-          tracing::debug!("Found device in USB Burn Mode (unable to read product string)");
-          return DeviceMode::UsbBurn;
-        }
-      }
+
+  match state {
+    SecureBootState::Enabled => Ok(bl2),
+    SecureBootState::Unknown => {
+      tracing::warn!("could not detect secure boot state, assuming a retail device");
+      Ok(bl2)
     }
+    SecureBootState::Disabled => Err(Error::UnsupportedSecureBoot(
+      "secure boot is disabled on this device, but the built-in BL2 is encrypted for secure boot devices".into(),
+    )),
   }
+}
 
-  tracing::debug!("No device found!");
-  DeviceMode::NotFound
+/// Format a digest as lowercase hex
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
 
+  #[test]
+  fn test_embedded_bl2() {
+    let profile = DeviceProfile::superbird();
+    assert!(embedded_bl2(&profile, SecureBootState::Enabled).is_ok());
+    assert!(embedded_bl2(&profile, SecureBootState::Unknown).is_ok());
+    assert!(matches!(
+      embedded_bl2(&profile, SecureBootState::Disabled),
+      Err(Error::UnsupportedSecureBoot(_))
+    ));
+
+    let profile = DeviceProfile {
+      bl2: None,
+      ..DeviceProfile::superbird()
+    };
+    assert!(embedded_bl2(&profile, SecureBootState::Enabled).is_err());
+  }
+
   #[test]
   fn test_amlogic_soc_connect() {
     let soc = AmlogicSoC::init(None);
@@ -0,0 +1,413 @@
+//! Opening package archives and finding files in them.
+//!
+//! Archives are checked up front so a damaged or oversized zip fails before flashing starts, and
+//! files are looked up relative to the folder holding the package, wherever the archiver put it.
+
+use std::{
+  fs::File,
+  io::{self, BufReader, Read, Seek, SeekFrom},
+  path::Path,
+};
+
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::{Error, Result, flash::Zip};
+
+/// Signature of the end of central directory record
+const EOCD_SIGNATURE: [u8; 4] = [0x50, 0x4b, 0x05, 0x06];
+/// Signature of the zip64 end of central directory locator, which sits right before the record
+const ZIP64_LOCATOR_SIGNATURE: [u8; 4] = [0x50, 0x4b, 0x06, 0x07];
+/// Size of the end of central directory record without its comment
+const EOCD_SIZE: usize = 22;
+/// Size of the zip64 end of central directory locator
+const ZIP64_LOCATOR_SIZE: usize = 20;
+/// Largest offset a zip without zip64 records can address
+const ZIP32_LIMIT: u64 = u32::MAX as u64;
+
+/// Most a compressed entry can grow by over its contents, as a fraction of their size, plus a fixed
+/// allowance for headers; deflate and zstd both stay well within this for incompressible data
+const MAX_EXPANSION_DIVISOR: u64 = 128;
+const MAX_EXPANSION_SLACK: u64 = 1024;
+
+/// Open a package archive, checking that it is complete and can address all of its contents
+///
+/// An archive over 4 GiB, or one holding a file over 4 GiB, needs zip64 records, since the plain zip
+/// format stores offsets and sizes in 32 bits; some archivers silently write them truncated instead,
+/// which would otherwise only surface as a confusing zip error part way through a flash. A file
+/// over 4 GiB can compress into a much smaller archive, so each entry's recorded size is checked
+/// against its compressed size too, and the size is checked again as the entry is read.
+///
+/// # Parameters
+/// - `path`: Path to the zip archive
+///
+/// # Returns
+/// - `Result<Zip>`: The opened archive, [`Error::ArchiveTruncated`] or
+///   [`Error::ArchiveTooLargeFor32BitZip`]
+#[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+pub(crate) fn open_archive(path: &Path) -> Result<Zip> {
+  let mut file = File::open(path)?;
+  let len = file.metadata()?.len();
+
+  let tail_len = len.min((ZIP64_LOCATOR_SIZE + EOCD_SIZE + u16::MAX as usize) as u64);
+  file.seek(SeekFrom::Start(len - tail_len))?;
+  let mut tail = vec![0; tail_len as usize];
+  file.read_exact(&mut tail)?;
+  check_end_records(&tail, len, path)?;
+
+  file.seek(SeekFrom::Start(0))?;
+  let mut zip = ZipArchive::new(BufReader::new(file))?;
+  for index in 0..zip.len() {
+    let entry = zip.by_index_raw(index)?;
+    if entry.data_start() + entry.compressed_size() > len {
+      tracing::warn!("{} ends past the end of {}", entry.name(), path.display());
+      return Err(Error::ArchiveTruncated(path.to_owned()));
+    }
+    if !size_fits(entry.compression(), entry.compressed_size(), entry.size()) {
+      tracing::warn!(
+        "{} is {} bytes compressed but records only {} bytes of contents",
+        entry.name(),
+        entry.compressed_size(),
+        entry.size()
+      );
+      return Err(Error::ArchiveTooLargeFor32BitZip(path.to_owned()));
+    }
+  }
+  Ok(zip)
+}
+
+/// Whether `compressed_size` bytes of data compressed with `method` could hold `size` bytes of
+/// contents, which they can't when a size over 4 GiB was truncated to 32 bits
+fn size_fits(method: CompressionMethod, compressed_size: u64, size: u64) -> bool {
+  match method {
+    CompressionMethod::Stored => true,
+    _ => compressed_size <= size + size / MAX_EXPANSION_DIVISOR + MAX_EXPANSION_SLACK,
+  }
+}
+
+/// Open entry `index` of the archive at `path` on a handle of its own, so several entries can be
+/// read at once
+///
+/// `zip` is only used to find the entry. Stored, deflated and zstd entries can be read this way,
+/// and the contents are checked against the entry's size as they are read and its CRC-32 once read to
+/// the end.
+///
+/// # Returns
+/// - `Result<(u64, Box<dyn Read>)>`: The entry's uncompressed size and a reader over its contents
+pub(crate) fn open_entry(zip: &mut Zip, path: &Path, index: usize) -> Result<(u64, Box<dyn Read>)> {
+  let entry = zip.by_index_raw(index)?;
+  let name = entry.name().to_owned();
+  if entry.encrypted() {
+    return Err(Error::InvalidOperation(format!("{name} is encrypted")));
+  }
+  let (start, compressed_size, size, crc32) =
+    (entry.data_start(), entry.compressed_size(), entry.size(), entry.crc32());
+  let method = entry.compression();
+  drop(entry);
+
+  let mut file = File::open(path)?;
+  file.seek(SeekFrom::Start(start))?;
+  let raw = BufReader::new(file).take(compressed_size);
+  let reader: Box<dyn Read> = match method {
+    CompressionMethod::Stored => Box::new(raw),
+    CompressionMethod::Deflated => Box::new(flate2::bufread::DeflateDecoder::new(raw)),
+    CompressionMethod::Zstd => Box::new(zstd::Decoder::with_buffer(raw)?),
+    method => {
+      return Err(Error::InvalidOperation(format!(
+        "{name} is compressed with {method}, which can only be read on its own"
+      )));
+    }
+  };
+  Ok((
+    size,
+    Box::new(CheckedEntry {
+      inner: reader,
+      hasher: crc32fast::Hasher::new(),
+      crc32,
+      size,
+      read: 0,
+      name,
+    }),
+  ))
+}
+
+/// Reads an archive entry, failing if it holds more than its recorded size or, at the end, if the
+/// contents do not match its CRC-32
+struct CheckedEntry {
+  inner: Box<dyn Read>,
+  hasher: crc32fast::Hasher,
+  crc32: u32,
+  size: u64,
+  read: u64,
+  name: String,
+}
+
+impl Read for CheckedEntry {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let read = self.inner.read(buf)?;
+    self.read += read as u64;
+    if self.read > self.size {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+          "{} holds more than the {} bytes the archive records for it; re-create the archive with zip64 enabled",
+          self.name, self.size
+        ),
+      ));
+    }
+    self.hasher.update(&buf[..read]);
+    if read == 0 && !buf.is_empty() && self.hasher.clone().finalize() != self.crc32 {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} does not match its CRC-32 in the archive", self.name),
+      ));
+    }
+    Ok(read)
+  }
+}
+
+/// Entry name with `/` separators and no leading `./`, as some archivers write them otherwise
+fn normalize_entry_name(name: &str) -> String {
+  let name = name.replace('\\', "/");
+  let mut name = name.as_str();
+  while let Some(rest) = name.strip_prefix("./") {
+    name = rest;
+  }
+  name.to_owned()
+}
+
+/// Whether an entry was added by the OS that made the archive rather than being part of the package
+fn is_os_metadata(name: &str) -> bool {
+  let file = name.rsplit('/').next().unwrap_or(name);
+  name.starts_with("__MACOSX/")
+    || file.starts_with("._")
+    || file == ".DS_Store"
+    || file.eq_ignore_ascii_case("Thumbs.db")
+    || file.eq_ignore_ascii_case("desktop.ini")
+}
+
+/// Find the folder inside an archive that holds `marker`, either the root or one folder deep
+///
+/// Zipping a folder on macOS or Windows puts everything under that folder (and macOS adds a
+/// `__MACOSX` tree next to it), so the folder holding `marker` is treated as the package root.
+/// OS metadata entries are ignored.
+///
+/// # Returns
+/// - `Result<Option<String>>`: The root as an entry name prefix, empty or ending in `/`, or `None` if
+///   `marker` isn't in the archive
+pub(crate) fn package_root<R: Read + Seek>(zip: &ZipArchive<R>, marker: &str) -> Result<Option<String>> {
+  let mut roots = zip
+    .file_names()
+    .map(normalize_entry_name)
+    .filter(|name| !is_os_metadata(name))
+    .filter_map(|name| {
+      let root = name.strip_suffix(marker)?;
+      (root.is_empty() || (root.ends_with('/') && root.matches('/').count() == 1)).then(|| root.to_owned())
+    })
+    .collect::<Vec<_>>();
+  roots.sort();
+  roots.dedup();
+
+  match roots.as_slice() {
+    [] => Ok(None),
+    [root, ..] if root.is_empty() => Ok(Some(String::new())),
+    [root] => {
+      tracing::debug!("package root is {} inside the archive", root);
+      Ok(Some(root.clone()))
+    }
+    _ => Err(Error::InvalidOperation(format!(
+      "found {} in more than one folder of the archive: {}",
+      marker,
+      roots.join(", ")
+    ))),
+  }
+}
+
+/// Check the end of an archive of `len` bytes, given its last bytes in `tail`
+fn check_end_records(tail: &[u8], len: u64, path: &Path) -> Result<()> {
+  let Some(eocd) = find_eocd(tail) else {
+    return Err(Error::ArchiveTruncated(path.to_owned()));
+  };
+
+  let zip64 = eocd >= ZIP64_LOCATOR_SIZE && tail[eocd - ZIP64_LOCATOR_SIZE..].starts_with(&ZIP64_LOCATOR_SIGNATURE);
+  if len > ZIP32_LIMIT && !zip64 {
+    return Err(Error::ArchiveTooLargeFor32BitZip(path.to_owned()));
+  }
+  Ok(())
+}
+
+/// Offset of the end of central directory record in `tail`
+///
+/// The record ends with a variable length comment, so it is found by scanning backwards for a
+/// signature whose comment runs exactly to the end of the file.
+fn find_eocd(tail: &[u8]) -> Option<usize> {
+  if tail.len() < EOCD_SIZE {
+    return None;
+  }
+
+  (0..=tail.len() - EOCD_SIZE).rev().find(|&offset| {
+    let comment_len = u16::from_le_bytes([tail[offset + 20], tail[offset + 21]]) as usize;
+    tail[offset..].starts_with(&EOCD_SIGNATURE) && offset + EOCD_SIZE + comment_len == tail.len()
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::{Cursor, Write};
+
+  use zip::{ZipWriter, write::SimpleFileOptions};
+
+  use super::*;
+
+  fn archive() -> Vec<u8> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    zip.start_file("meta.json", SimpleFileOptions::default()).unwrap();
+    zip.write_all(b"{}").unwrap();
+    zip.set_comment("flashthing");
+    zip.finish().unwrap().into_inner()
+  }
+
+  #[test]
+  fn test_open_archive() {
+    let dir = std::env::temp_dir().join(format!("flashthing-archive-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("package.zip");
+    let data = archive();
+
+    std::fs::write(&path, &data).unwrap();
+    assert_eq!(open_archive(&path).unwrap().len(), 1);
+
+    std::fs::write(&path, &data[..data.len() - 8]).unwrap();
+    assert!(matches!(open_archive(&path), Err(Error::ArchiveTruncated(_))));
+    std::fs::write(&path, &data[..10]).unwrap();
+    assert!(matches!(open_archive(&path), Err(Error::ArchiveTruncated(_))));
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn test_open_entry() {
+    let dir = std::env::temp_dir().join(format!("flashthing-entry-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("package.zip");
+    let contents = (0..100_000).map(|i| (i % 7) as u8).collect::<Vec<_>>();
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for method in [
+      CompressionMethod::Stored,
+      CompressionMethod::Deflated,
+      CompressionMethod::Zstd,
+    ] {
+      let options = SimpleFileOptions::default().compression_method(method);
+      zip.start_file(format!("{method}.img"), options).unwrap();
+      zip.write_all(&contents).unwrap();
+    }
+    let data = zip.finish().unwrap().into_inner();
+    std::fs::write(&path, &data).unwrap();
+
+    let mut zip = open_archive(&path).unwrap();
+    let mut readers = (0..zip.len())
+      .map(|index| open_entry(&mut zip, &path, index).unwrap())
+      .collect::<Vec<_>>();
+    for (size, reader) in &mut readers {
+      let mut read = Vec::new();
+      reader.read_to_end(&mut read).unwrap();
+      assert_eq!((*size, read.as_slice()), (contents.len() as u64, contents.as_slice()));
+    }
+
+    // flip a byte of the stored entry's contents
+    let mut corrupt = data.clone();
+    let start = zip.by_index_raw(0).unwrap().data_start() as usize;
+    corrupt[start + 10] ^= 0xff;
+    std::fs::write(&path, &corrupt).unwrap();
+    let (_, mut reader) = open_entry(&mut zip, &path, 0).unwrap();
+    let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+    assert!(err.to_string().contains("CRC-32"), "{err}");
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn test_package_root() {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for name in [
+      "__MACOSX/package/._meta.json",
+      "__MACOSX/meta.json",
+      "package/meta.json",
+      "package\\images\\boot.img",
+      "package/._boot.img",
+      "package/nested/meta.json",
+    ] {
+      zip.start_file(name, SimpleFileOptions::default()).unwrap();
+    }
+    let zip = ZipArchive::new(zip.finish().unwrap()).unwrap();
+
+    assert_eq!(package_root(&zip, "meta.json").unwrap().unwrap(), "package/");
+    assert_eq!(package_root(&zip, "images/boot.img").unwrap().unwrap(), "package/");
+    assert_eq!(package_root(&zip, "boot.img").unwrap(), None);
+    assert_eq!(package_root(&zip, "hashes.json").unwrap(), None);
+  }
+
+  /// Set the uncompressed size of the only entry of `data`, as an archiver truncating it to 32 bits would
+  fn set_entry_size(data: &mut [u8], size: u32) {
+    // local file header, then central directory header
+    for (signature, offset) in [([0x50, 0x4b, 0x03, 0x04], 22), ([0x50, 0x4b, 0x01, 0x02], 24)] {
+      let header = data.windows(4).position(|window| window == signature).unwrap();
+      data[header + offset..header + offset + 4].copy_from_slice(&size.to_le_bytes());
+    }
+  }
+
+  #[test]
+  fn test_entry_too_large() {
+    let dir = std::env::temp_dir().join(format!("flashthing-entry-size-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("package.zip");
+    let entry = |contents: &[u8]| {
+      let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+      let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+      zip.start_file("rootfs.img", options).unwrap();
+      zip.write_all(contents).unwrap();
+      zip.finish().unwrap().into_inner()
+    };
+
+    // incompressible contents compress to more than the truncated size could
+    let mut state = 1u32;
+    let noise = (0..100_000)
+      .map(|_| {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (state >> 24) as u8
+      })
+      .collect::<Vec<_>>();
+    let mut data = entry(&noise);
+    set_entry_size(&mut data, 1000);
+    std::fs::write(&path, &data).unwrap();
+    assert!(matches!(open_archive(&path), Err(Error::ArchiveTooLargeFor32BitZip(_))));
+
+    // compressible contents can't be told apart up front, but fail as soon as they run past the size
+    let mut data = entry(&[0; 100_000]);
+    set_entry_size(&mut data, 1000);
+    std::fs::write(&path, &data).unwrap();
+    let mut zip = open_archive(&path).unwrap();
+    let (size, mut reader) = open_entry(&mut zip, &path, 0).unwrap();
+    assert_eq!(size, 1000);
+    let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+    assert!(err.to_string().contains("zip64"), "{err}");
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn test_zip64_required() {
+    let path = Path::new("package.zip");
+    let data = archive();
+    assert_eq!(find_eocd(&data), Some(data.len() - EOCD_SIZE - "flashthing".len()));
+    check_end_records(&data, data.len() as u64, path).unwrap();
+    assert!(matches!(
+      check_end_records(&data, 5 << 30, path),
+      Err(Error::ArchiveTooLargeFor32BitZip(_))
+    ));
+
+    // a zip64 locator right before the record makes the large size addressable
+    let eocd = find_eocd(&data).unwrap();
+    let mut zip64 = data[..eocd].to_vec();
+    zip64.extend_from_slice(&ZIP64_LOCATOR_SIGNATURE);
+    zip64.extend_from_slice(&[0; ZIP64_LOCATOR_SIZE - 4]);
+    zip64.extend_from_slice(&data[eocd..]);
+    check_end_records(&zip64, 5 << 30, path).unwrap();
+  }
+}
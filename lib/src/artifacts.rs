@@ -0,0 +1,47 @@
+//! Files a flash leaves behind, kept together under one directory.
+
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+use crate::{FlashReport, Result};
+
+/// File in the artifacts directory holding the [`FlashReport`] of the most recent flash
+pub const ARTIFACT_REPORT: &str = "report.json";
+
+/// File in the artifacts directory saying which step to resume a cancelled flash at
+pub const ARTIFACT_CHECKPOINT: &str = "checkpoint.json";
+
+/// Directory in the artifacts directory read steps write their `output` files to
+pub const ARTIFACT_OUTPUTS: &str = "outputs";
+
+/// Write the report of a finished flash, and a checkpoint if it can be resumed
+///
+/// A checkpoint left by an earlier flash is removed once a flash finishes without one.
+///
+/// # Returns
+/// - `Result<Vec<PathBuf>>`: The files written
+pub(crate) fn write_finished(dir: &Path, report: &FlashReport) -> Result<Vec<PathBuf>> {
+  fs::create_dir_all(dir)?;
+  let path = dir.join(ARTIFACT_REPORT);
+  fs::write(&path, serde_json::to_string_pretty(report)?)?;
+  let mut written = vec![path];
+
+  let path = dir.join(ARTIFACT_CHECKPOINT);
+  match &report.resume_at {
+    Some(step) => {
+      let checkpoint = serde_json::json!({
+        "package": report.package,
+        "version": report.version,
+        "resumeAt": step,
+        "resumeOffset": report.resume_offset,
+      });
+      fs::write(&path, serde_json::to_string_pretty(&checkpoint)?)?;
+      written.push(path);
+    }
+    None if path.exists() => fs::remove_file(&path)?,
+    None => {}
+  }
+  Ok(written)
+}
@@ -0,0 +1,103 @@
+//! Checking a flashed device boots, instead of leaving that to the user to find out.
+
+use std::{
+  thread::sleep,
+  time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{AmlogicSoC, DeviceMode, config::ResetMode, transport};
+
+/// How often to look for the device while waiting for it to boot
+const BOOT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long the device has to stay in normal mode to count as booted, rather than about to crash
+const BOOT_SETTLE: Duration = Duration::from_secs(3);
+
+/// Times the device can drop off the bus after showing up before it is taken to be in a boot loop
+const BOOT_LOOP_CYCLES: u32 = 3;
+
+/// Whether the device booted after flashing, see [`Flasher::verify_boot`](crate::Flasher::verify_boot)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "outcome", rename_all = "camelCase")]
+pub enum BootOutcome {
+  /// The device came up booted normally
+  #[serde(rename_all = "camelCase")]
+  Verified {
+    /// Milliseconds from the reset until the device showed up
+    after_ms: u64,
+  },
+  /// The device kept showing up and dropping off the bus again, so its firmware is crashing as it boots
+  #[serde(rename_all = "camelCase")]
+  BootLoop {
+    /// Times it dropped off the bus after showing up
+    cycles: u32,
+  },
+  /// The device did not come up booted normally in time
+  #[serde(rename_all = "camelCase")]
+  Timeout {
+    /// Milliseconds waited
+    timeout_ms: u64,
+  },
+}
+
+/// Reset the device if it is still in USB burn mode, and wait up to `timeout` for it to boot normally
+///
+/// The device has to stay in normal mode for [`BOOT_SETTLE`] to count as booted, unless the
+/// timeout runs out first. Each time it drops off the bus after showing up, in any mode, is a
+/// cycle, and [`BOOT_LOOP_CYCLES`] of them mean a boot loop.
+///
+/// # Returns
+/// - `(BootOutcome, DeviceMode)`: Whether it booted, and the mode it was last seen in
+pub(crate) fn wait_for_boot(aml: &AmlogicSoC, timeout: Duration) -> (BootOutcome, DeviceMode) {
+  if aml.session_valid() {
+    tracing::info!("resetting the device to check it boots");
+    if let Err(e) = aml.reset(ResetMode::Soft) {
+      tracing::warn!("failed to reset the device: {}", e);
+    }
+  }
+
+  tracing::info!("waiting up to {:?} for the device to boot", timeout);
+  let start = Instant::now();
+  let (mut present, mut cycles, mut normal_since) = (false, 0, None);
+  loop {
+    sleep(BOOT_POLL_INTERVAL);
+    let mode = transport::find_device(aml.target(), aml.profile());
+    if present && mode == DeviceMode::NotFound {
+      cycles += 1;
+      tracing::debug!(
+        "device dropped off the bus after {:?} (cycle {})",
+        start.elapsed(),
+        cycles
+      );
+    }
+    present = mode != DeviceMode::NotFound;
+    normal_since = match mode {
+      DeviceMode::Normal => normal_since.or(Some(start.elapsed())),
+      _ => None,
+    };
+
+    if cycles >= BOOT_LOOP_CYCLES {
+      return (BootOutcome::BootLoop { cycles }, mode);
+    }
+    let booted = normal_since.map(|since| (since, start.elapsed() - since >= BOOT_SETTLE));
+    match booted {
+      Some((since, true)) => {
+        tracing::info!("device booted after {:?}", since);
+        let after_ms = since.as_millis() as u64;
+        return (BootOutcome::Verified { after_ms }, mode);
+      }
+      // it is up, there is just no time left to see whether it stays up
+      Some((since, false)) if start.elapsed() >= timeout => {
+        let after_ms = since.as_millis() as u64;
+        return (BootOutcome::Verified { after_ms }, mode);
+      }
+      _ if start.elapsed() >= timeout => {
+        let timeout_ms = timeout.as_millis() as u64;
+        return (BootOutcome::Timeout { timeout_ms }, mode);
+      }
+      _ => {}
+    }
+  }
+}
@@ -0,0 +1,86 @@
+//! Framing plain-text U-Boot scripts as `boot.scr` images, the way `mkimage -T script -C none` does.
+
+use crate::config::ScriptArch;
+
+/// Magic number a legacy uImage header starts with
+const IH_MAGIC: u32 = 0x2705_1956;
+/// Size of a legacy uImage header
+const HEADER_LEN: usize = 64;
+/// Longest image name the header holds
+const IH_NMLEN: usize = 32;
+
+const IH_OS_LINUX: u8 = 5;
+const IH_TYPE_SCRIPT: u8 = 6;
+const IH_COMP_NONE: u8 = 0;
+
+/// Name in the header when a step does not give one
+pub(crate) const DEFAULT_SCRIPT_NAME: &str = "boot script";
+
+impl ScriptArch {
+  /// `IH_ARCH_*` value U-Boot knows the architecture by
+  fn ih_arch(self) -> u8 {
+    match self {
+      ScriptArch::Arm => 2,
+      ScriptArch::Arm64 => 22,
+    }
+  }
+}
+
+/// Wrap `script` in a legacy uImage header, ready for U-Boot's `source` command
+///
+/// Script images are multi-file images with a single file: the payload is a table of file sizes
+/// ended by a zero, then the script itself. `name` is cut to 32 bytes like `mkimage` does, and the
+/// timestamp is left at zero so the same script always makes the same image.
+pub(crate) fn script_image(script: &str, name: &str, arch: ScriptArch) -> Vec<u8> {
+  let mut payload = Vec::with_capacity(8 + script.len());
+  payload.extend_from_slice(&(script.len() as u32).to_be_bytes());
+  payload.extend_from_slice(&0u32.to_be_bytes());
+  payload.extend_from_slice(script.as_bytes());
+
+  let mut header = [0u8; HEADER_LEN];
+  header[0..4].copy_from_slice(&IH_MAGIC.to_be_bytes());
+  // 4..8 is the header checksum, 8..12 the timestamp, 16..24 the load address and entry point
+  header[12..16].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+  header[24..28].copy_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+  header[28] = IH_OS_LINUX;
+  header[29] = arch.ih_arch();
+  header[30] = IH_TYPE_SCRIPT;
+  header[31] = IH_COMP_NONE;
+  let name = &name.as_bytes()[..name.len().min(IH_NMLEN)];
+  header[32..32 + name.len()].copy_from_slice(name);
+  let hcrc = crc32fast::hash(&header);
+  header[4..8].copy_from_slice(&hcrc.to_be_bytes());
+
+  let mut image = header.to_vec();
+  image.extend_from_slice(&payload);
+  image
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_script_image() {
+    let script = "setenv bootargs console=ttyS0,115200\nboot\n";
+    let image = script_image(script, DEFAULT_SCRIPT_NAME, ScriptArch::default());
+    let word = |at: usize| u32::from_be_bytes(image[at..at + 4].try_into().unwrap());
+
+    assert_eq!(word(0), IH_MAGIC);
+    assert_eq!(word(12) as usize, 8 + script.len());
+    assert_eq!(word(24), crc32fast::hash(&image[HEADER_LEN..]));
+    let mut header = image[..HEADER_LEN].to_vec();
+    header[4..8].fill(0);
+    assert_eq!(word(4), crc32fast::hash(&header));
+    assert_eq!(image[28..32], [IH_OS_LINUX, 22, IH_TYPE_SCRIPT, IH_COMP_NONE]);
+    assert_eq!(
+      &image[32..32 + DEFAULT_SCRIPT_NAME.len()],
+      DEFAULT_SCRIPT_NAME.as_bytes()
+    );
+    assert_eq!((word(64) as usize, word(68)), (script.len(), 0));
+    assert_eq!(&image[72..], script.as_bytes());
+
+    let long = script_image("", &"x".repeat(40), ScriptArch::Arm);
+    assert_eq!((long[29], &long[32..64]), (2, "x".repeat(32).as_bytes()));
+  }
+}
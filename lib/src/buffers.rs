@@ -0,0 +1,93 @@
+//! Chunk buffers reused from one transfer to the next, so streaming an image doesn't allocate and
+//! fault in a fresh transfer-sized buffer for every chunk or step.
+
+use std::{
+  ops::{Deref, DerefMut},
+  sync::Mutex,
+};
+
+/// Most buffers kept for reuse: one for the chunk being written, one for reading it back to compare
+const MAX_FREE: usize = 2;
+
+/// Buffers of up to one transfer, handed out by [`BufferPool::take`] and returned when dropped
+#[derive(Debug, Default)]
+pub(crate) struct BufferPool {
+  free: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufferPool {
+  /// A buffer of `length` bytes, reusing a returned one when there is one
+  ///
+  /// The contents are whatever the buffer last held, so callers fill it before reading from it.
+  pub(crate) fn take(&self, length: usize) -> PooledBuffer<'_> {
+    let mut buf = self
+      .free
+      .lock()
+      .ok()
+      .and_then(|mut free| free.pop())
+      .unwrap_or_default();
+    buf.resize(length, 0);
+    PooledBuffer { buf, pool: self }
+  }
+}
+
+/// A buffer from a [`BufferPool`], given back to it when dropped
+pub(crate) struct PooledBuffer<'a> {
+  buf: Vec<u8>,
+  pool: &'a BufferPool,
+}
+
+impl PooledBuffer<'_> {
+  /// Shorten the buffer to `length` bytes, keeping its allocation
+  pub(crate) fn truncate(&mut self, length: usize) {
+    self.buf.truncate(length);
+  }
+}
+
+impl Deref for PooledBuffer<'_> {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    &self.buf
+  }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+  fn deref_mut(&mut self) -> &mut [u8] {
+    &mut self.buf
+  }
+}
+
+impl Drop for PooledBuffer<'_> {
+  fn drop(&mut self) {
+    if let Ok(mut free) = self.pool.free.lock()
+      && free.len() < MAX_FREE
+    {
+      free.push(std::mem::take(&mut self.buf));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_reuse() {
+    let pool = BufferPool::default();
+    let first = pool.take(4096);
+    let address = first.as_ptr();
+    drop(first);
+
+    let mut second = pool.take(1024);
+    assert_eq!(second.len(), 1024);
+    assert_eq!(second.as_ptr(), address);
+    second.truncate(512);
+    assert_eq!(second.len(), 512);
+
+    let held = [pool.take(16), pool.take(16), pool.take(16)];
+    drop(second);
+    drop(held);
+    assert_eq!(pool.free.lock().unwrap().len(), MAX_FREE);
+  }
+}
@@ -0,0 +1,33 @@
+//! Stopping a flash part way through, at a point that leaves the eMMC in a known state.
+
+use std::sync::{
+  Arc,
+  atomic::{AtomicBool, Ordering},
+};
+
+/// Asks a running flash to stop at the next safe point
+///
+/// Clones share the request, so a signal handler can hold one while the flasher holds another.
+/// The flash stops once the chunk being written is on the eMMC, or before the next step starts,
+/// and fails with [`Error::Cancelled`](crate::Error::Cancelled).
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+  requested: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+  /// A token that has not been cancelled
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Ask whatever holds a clone of this token to stop
+  pub fn cancel(&self) {
+    self.requested.store(true, Ordering::Relaxed);
+  }
+
+  /// Whether [`cancel`](Self::cancel) has been called on this token or a clone of it
+  pub fn is_cancelled(&self) -> bool {
+    self.requested.load(Ordering::Relaxed)
+  }
+}
@@ -0,0 +1,98 @@
+//! What this build of flashthing supports, for packages and frontends to check before relying on it.
+
+use serde::Serialize;
+
+use crate::{
+  DeviceProfile, SUPPORTED_META_VERSION_MAX, SUPPORTED_META_VERSION_MIN,
+  resources::{EmbeddedResource, embedded_info},
+};
+
+/// Cargo features that change what the crate can do, and whether each was enabled
+const FEATURES: &[(&str, bool)] = &[
+  ("rusb", cfg!(feature = "rusb")),
+  ("nusb", cfg!(feature = "nusb")),
+  ("adb", cfg!(feature = "adb")),
+  ("http", cfg!(feature = "http")),
+  ("metrics", cfg!(feature = "metrics")),
+];
+
+/// What this build of flashthing supports
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+  /// Version of the flashthing crate
+  pub version: String,
+  /// Oldest `metadataVersion` a package can have
+  pub min_metadata_version: usize,
+  /// Newest `metadataVersion` a package can have
+  pub max_metadata_version: usize,
+  /// Cargo features the crate was built with, e.g. `adb`
+  pub features: Vec<String>,
+  /// Blobs built into the crate, e.g. the BL2 sent to devices that are not given one
+  pub resources: Vec<EmbeddedResource>,
+  /// Devices there is a built-in [`DeviceProfile`] for
+  pub profiles: Vec<ProfileInfo>,
+}
+
+/// A built-in device profile
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileInfo {
+  /// Name of the profile, e.g. `superbird`
+  pub name: String,
+  /// USB vendor ID in USB (burn) mode
+  pub vendor_id: u16,
+  /// USB product ID in USB (burn) mode
+  pub product_id: u16,
+}
+
+impl Capabilities {
+  /// What the running build supports
+  pub fn current() -> Self {
+    Self {
+      version: env!("CARGO_PKG_VERSION").to_owned(),
+      min_metadata_version: SUPPORTED_META_VERSION_MIN,
+      max_metadata_version: SUPPORTED_META_VERSION_MAX,
+      features: FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| name.to_string())
+        .collect(),
+      resources: embedded_info(),
+      profiles: [DeviceProfile::superbird()]
+        .into_iter()
+        .map(|profile| ProfileInfo {
+          name: profile.name.into_owned(),
+          vendor_id: profile.vendor_id,
+          product_id: profile.product_id,
+        })
+        .collect(),
+    }
+  }
+
+  /// Whether the build can flash packages with this `metadataVersion`
+  pub fn supports_metadata_version(&self, version: usize) -> bool {
+    (self.min_metadata_version..=self.max_metadata_version).contains(&version)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_capabilities() {
+    let capabilities = Capabilities::current();
+    assert!(capabilities.supports_metadata_version(1) && !capabilities.supports_metadata_version(0));
+    assert_eq!(
+      capabilities.features.contains(&"rusb".to_owned()),
+      cfg!(feature = "rusb")
+    );
+    assert_eq!(capabilities.profiles[0].name, "superbird");
+
+    assert_eq!(capabilities.resources[0].name, "bl2");
+
+    let json = serde_json::to_value(&capabilities).unwrap();
+    assert_eq!(json["maxMetadataVersion"], SUPPORTED_META_VERSION_MAX);
+  }
+}
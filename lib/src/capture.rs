@@ -0,0 +1,101 @@
+//! Collecting what it takes to triage a flash into one zip, to attach to a bug report.
+
+use std::{
+  fs::File,
+  io::Write,
+  path::PathBuf,
+  sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+use zip::{ZipWriter, write::SimpleFileOptions};
+
+use crate::{DeviceInfo, DeviceTarget, FlashReport, Result, config::FlashConfig, logging::LogCapture, variables};
+
+/// File in a session capture holding every log line of the flash, down to trace level
+pub const CAPTURE_LOG: &str = "session.log";
+
+/// File in a session capture holding the [`FlashReport`]
+const CAPTURE_REPORT: &str = "report.json";
+
+/// File in a session capture holding the package's `meta.json`, as it was loaded
+const CAPTURE_META: &str = "meta.json";
+
+/// File in a session capture listing the package's files by size and digest
+const CAPTURE_FILES: &str = "files.json";
+
+/// File in a session capture describing the host
+const CAPTURE_HOST: &str = "host.json";
+
+/// File in a session capture holding the USB descriptors of the device
+const CAPTURE_USB: &str = "usb.json";
+
+/// A file a package's steps refer to, described without its contents
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CapturedFile {
+  pub file_path: String,
+  pub size: Option<u64>,
+  pub sha256: Option<String>,
+  pub error: Option<String>,
+}
+
+/// Logs kept while a session is captured, and where to write the zip once it finishes
+pub(crate) struct SessionCapture {
+  path: PathBuf,
+  lines: Arc<Mutex<Vec<String>>>,
+}
+
+impl SessionCapture {
+  pub(crate) fn new(path: PathBuf) -> Self {
+    Self {
+      path,
+      lines: Arc::default(),
+    }
+  }
+
+  /// Dispatcher that keeps every log line for the capture and forwards to `inner`
+  pub(crate) fn dispatch(&self, inner: tracing::Dispatch) -> tracing::Dispatch {
+    LogCapture::dispatch(self.lines.clone(), inner)
+  }
+
+  /// Write the zip
+  ///
+  /// # Returns
+  /// - `Result<PathBuf>`: Where the zip was written
+  pub(crate) fn write(
+    &self,
+    report: &FlashReport,
+    config: &FlashConfig,
+    files: &[CapturedFile],
+    device: &DeviceInfo,
+    target: &DeviceTarget,
+  ) -> Result<PathBuf> {
+    if let Some(parent) = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+      std::fs::create_dir_all(parent)?;
+    }
+    let mut zip = ZipWriter::new(File::create(&self.path)?);
+    let options = SimpleFileOptions::default();
+
+    let host = serde_json::json!({
+      "facts": variables::host_facts().into_iter().collect::<std::collections::BTreeMap<_, _>>(),
+      "target": target.to_string(),
+    });
+    let log = self.lines.lock().map(|lines| lines.join("\n")).unwrap_or_default();
+    let entries = [
+      (CAPTURE_LOG, log),
+      (CAPTURE_REPORT, serde_json::to_string_pretty(report)?),
+      (CAPTURE_META, serde_json::to_string_pretty(config)?),
+      (CAPTURE_FILES, serde_json::to_string_pretty(files)?),
+      (CAPTURE_HOST, serde_json::to_string_pretty(&host)?),
+      (CAPTURE_USB, serde_json::to_string_pretty(device)?),
+    ];
+    for (name, contents) in entries {
+      zip.start_file(name, options)?;
+      zip.write_all(contents.as_bytes())?;
+    }
+    zip.finish()?;
+    Ok(self.path.clone())
+  }
+}
@@ -1,8 +1,23 @@
-use std::{collections::HashMap, fs::read_to_string, io::Read, path::PathBuf};
+use std::{
+  collections::{BTreeMap, HashMap, HashSet},
+  fs::read_to_string,
+  io::Read,
+  path::PathBuf,
+};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error as _};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use zip::result::ZipError;
 
-use crate::{Error, Result, STOCK_META, SUPPORTED_META_VERSION_MAX, SUPPORTED_META_VERSION_MIN, flash::Zip};
+pub use crate::env::UBootEnv;
+use crate::{
+  Error, PART_SECTOR_SIZE, Result, SUPPORTED_META_VERSION_MAX, SUPPORTED_META_VERSION_MIN, StockProfile,
+  archive::package_root,
+  flash::Zip,
+  paths::{PathPolicy, normalize_file_path, resolve_in_archive},
+  variables::{self, DeferredStep},
+};
 
 /// Configuration for the flashing process
 ///
@@ -19,11 +34,21 @@ pub struct FlashConfig {
   /// Description of what the flash configuration does
   pub description: String,
   /// Sequence of steps to execute during flashing
-  pub steps: Vec<FlashStep>,
+  pub steps: Vec<Step>,
   /// Variables to store data between steps
   pub variables: Option<HashMap<String, usize>>,
+  /// Named numbers that steps can refer to as `${NAME}`, substituted when the config is loaded
+  #[serde(default, deserialize_with = "constants")]
+  pub constants: Option<HashMap<String, u64>>,
   /// Version of the metadata format
   pub metadata_version: usize,
+  /// Expert override permitting access to special partitions like `reserved`
+  pub allow_special_partitions: Option<bool>,
+  /// URL the flash report is posted to as JSON once flashing finishes, with the `http` feature
+  pub report_webhook: Option<String>,
+  /// Steps that refer to variables, as written, by step id
+  #[serde(skip)]
+  pub(crate) deferred: HashMap<String, DeferredStep>,
 }
 
 impl FlashConfig {
@@ -45,27 +70,25 @@ impl FlashConfig {
     }
 
     let json = read_to_string(meta)?;
-    let this: FlashConfig = serde_json::from_str(&json)?;
-    this.check_config_supported()?;
-    Ok(this)
+    Self::parse(json.as_bytes())
   }
 
   /// Load a flash configuration from a ZIP archive
   ///
+  /// `meta.json` may be at the root of the archive or inside a single top-level folder.
+  ///
   /// # Parameters
   /// - `zip`: ZIP archive containing a meta.json file
   ///
   /// # Returns
   /// - `Result<Self>`: The loaded configuration or an error
   pub fn from_archive(zip: &mut Zip) -> Result<Self> {
-    let mut meta_file = zip.by_name("meta.json")?;
+    let root = package_root(zip, "meta.json")?.ok_or(ZipError::FileNotFound)?;
+    let mut meta_file = zip.by_index(resolve_in_archive(zip, &root, "meta.json", PathPolicy::Strict)?)?;
 
     let mut json = String::new();
     meta_file.read_to_string(&mut json)?;
-
-    let this: FlashConfig = serde_json::from_str(&json)?;
-    this.check_config_supported()?;
-    Ok(this)
+    Self::parse(json.as_bytes())
   }
 
   /// Parse a flash configuration from a JSON string
@@ -76,9 +99,7 @@ impl FlashConfig {
   /// # Returns
   /// - `Result<Self>`: The parsed configuration or an error
   pub fn from_standalone(json: &str) -> Result<Self> {
-    let this: FlashConfig = serde_json::from_str(json)?;
-    this.check_config_supported()?;
-    Ok(this)
+    Self::parse(json.as_bytes())
   }
 
   /// Load the built-in stock flash configuration
@@ -86,28 +107,204 @@ impl FlashConfig {
   /// # Returns
   /// - `Result<Self>`: The stock configuration or an error
   pub fn from_stock() -> Result<Self> {
-    let this: FlashConfig = serde_json::from_slice(STOCK_META)?;
+    Self::from_stock_profile(StockProfile::default())
+  }
+
+  /// Load the built-in flash configuration for stock dumps laid out like `profile`
+  ///
+  /// # Returns
+  /// - `Result<Self>`: The stock configuration or an error
+  pub fn from_stock_profile(profile: StockProfile) -> Result<Self> {
+    Self::parse(profile.meta())
+  }
+
+  /// Parse meta.json contents, substituting `constants` into the steps and giving every step an id
+  ///
+  /// Steps that refer to variables are set aside to be loaded again when they run. A config without
+  /// constants or such steps is parsed straight from the text so errors keep their line numbers.
+  fn parse(json: &[u8]) -> Result<Self> {
+    let mut value: Value = serde_json::from_slice(json)?;
+    let constants = substitute_constants(&mut value)?;
+    let deferred = variables::defer_steps(&mut value)?;
+    let mut this: FlashConfig = if constants || !deferred.is_empty() {
+      serde_json::from_value(value)?
+    } else {
+      serde_json::from_slice(json)?
+    };
     this.check_config_supported()?;
+    this.assign_step_ids()?;
+
+    let ids = this
+      .steps
+      .iter()
+      .flat_map(Step::walk_steps)
+      .map(|step| step.id.clone().unwrap_or_default())
+      .collect::<Vec<_>>();
+    this.deferred = deferred
+      .into_iter()
+      .map(|(index, step)| (ids[index].clone(), step))
+      .collect();
     Ok(this)
   }
 
+  /// Give every step without an `id` one derived from its contents, and check that ids are unique
+  ///
+  /// A derived id is the step type followed by a digest of the step, so it stays the same when
+  /// steps around it are added, removed or skipped. Identical steps get `-2`, `-3`, … appended
+  /// in the order they appear.
+  fn assign_step_ids(&mut self) -> Result<()> {
+    let mut seen = HashSet::new();
+    for id in self
+      .steps
+      .iter()
+      .flat_map(Step::walk_steps)
+      .filter_map(|step| step.id.as_deref())
+    {
+      if id.is_empty() {
+        return Err(Error::InvalidOperation("step ids cannot be empty".into()));
+      }
+      if !seen.insert(id.to_owned()) {
+        return Err(Error::InvalidOperation(format!(
+          "step id `{}` is used by more than one step",
+          id
+        )));
+      }
+    }
+
+    fn assign(steps: &mut [Step], seen: &mut HashSet<String>) -> Result<()> {
+      for step in steps {
+        if step.id.is_none() {
+          let base = derived_step_id(&step.step)?;
+          let mut id = base.clone();
+          let mut copy = 1;
+          while !seen.insert(id.clone()) {
+            copy += 1;
+            id = format!("{}-{}", base, copy);
+          }
+          step.id = Some(id);
+        }
+        if let FlashStep::Repeat { value } = &mut step.step {
+          assign(&mut value.steps, seen)?;
+        }
+      }
+      Ok(())
+    }
+    assign(&mut self.steps, &mut seen)
+  }
+
   fn check_config_supported(&self) -> Result<()> {
     if !(SUPPORTED_META_VERSION_MIN..=SUPPORTED_META_VERSION_MAX).contains(&self.metadata_version) {
       return Err(Error::UnsupportedVersion(self.metadata_version));
     }
 
-    for step in &self.steps {
+    for step in self.steps.iter().flat_map(Step::walk_steps) {
+      if matches!(step.on_error, Some(OnError::Retry(_))) && !step.step.repeatable() {
+        return Err(Error::InvalidOperation(format!(
+          "{} steps can't be retried, since running one again does not start it over",
+          step.step.kind()
+        )));
+      }
+    }
+
+    for step in self.steps.iter().flat_map(Step::walk) {
       match step {
-        FlashStep::Identify { .. }
-        | FlashStep::ReadLargeMemory { .. }
-        | FlashStep::ReadSimpleMemory { .. }
-        | FlashStep::GetBootAMLC { .. }
-        | FlashStep::BulkcmdStat { .. }
-        | FlashStep::ValidatePartitionSize { .. } => return Err(Error::UnsupportedFeature(step.to_owned())),
-        FlashStep::Wait { value } => match value {
-          WaitValue::UserInput { .. } => return Err(Error::UnsupportedFeature(step.to_owned())),
-          WaitValue::Time { .. } => continue,
+        FlashStep::Repeat { value } if value.count == 0 || value.steps.is_empty() => {
+          return Err(Error::InvalidOperation(
+            "repeat needs a count of at least 1 and at least one step".into(),
+          ));
+        }
+        FlashStep::Assert { value } => {
+          let sources = [&value.variable, &value.bulkcmd].into_iter().flatten().count();
+          let checks = [&value.equals, &value.contains, &value.matches]
+            .into_iter()
+            .flatten()
+            .count();
+          if sources != 1 || checks != 1 {
+            return Err(Error::InvalidOperation(
+              "assert needs either a variable or a bulkcmd, and exactly one of equals, contains and matches".into(),
+            ));
+          }
+          if let Some(pattern) = &value.matches {
+            regex::Regex::new(pattern)
+              .map_err(|e| Error::InvalidOperation(format!("assert pattern `{}` is invalid: {}", pattern, e)))?;
+          }
+        }
+        FlashStep::Provision { value } => {
+          if value.variables.is_empty() {
+            return Err(Error::InvalidOperation("provision needs at least one variable".into()));
+          }
+          if value.env.is_none() && value.data.is_none() {
+            return Err(Error::InvalidOperation(
+              "provision needs env variables or data to write".into(),
+            ));
+          }
+          if let Some(offset) = value.data.as_ref().and_then(|data| data.offset_in_partition)
+            && !(offset.get() as usize).is_multiple_of(PART_SECTOR_SIZE)
+          {
+            return Err(Error::InvalidOperation(format!(
+              "provision data has to start on a {} byte sector",
+              PART_SECTOR_SIZE
+            )));
+          }
+        }
+        FlashStep::PostBootCheck { value } => {
+          if value.checks.is_empty() {
+            return Err(Error::InvalidOperation("postBootCheck needs at least one check".into()));
+          }
+          for check in &value.checks {
+            let matchers = [&check.equals, &check.contains, &check.matches]
+              .into_iter()
+              .flatten()
+              .count();
+            if matchers > 1 {
+              return Err(Error::InvalidOperation(format!(
+                "postBootCheck `{}` can have only one of equals, contains and matches",
+                check.command
+              )));
+            }
+            if let Some(pattern) = &check.matches {
+              regex::Regex::new(pattern).map_err(|e| {
+                Error::InvalidOperation(format!("postBootCheck pattern `{}` is invalid: {}", pattern, e))
+              })?;
+            }
+          }
+          #[cfg(not(feature = "adb"))]
+          return Err(Error::UnsupportedFeature(Box::new(step.to_owned())));
+        }
+        FlashStep::ReadLargeMemory { output, .. } | FlashStep::ReadSimpleMemory { output, .. } => {
+          if let Some(output) = output {
+            normalize_file_path(&output.file_path)?;
+          }
+        }
+        FlashStep::GetBootAMLC { .. } | FlashStep::ValidatePartitionSize { .. } => {
+          return Err(Error::UnsupportedFeature(Box::new(step.to_owned())));
+        }
+        FlashStep::WriteLargeMemory { value } => match (&value.address, &value.partition) {
+          (Some(_), None) if value.offset_in_partition.is_none() => continue,
+          (None, Some(_)) => continue,
+          _ => {
+            return Err(Error::InvalidOperation(
+              "writeLargeMemory needs either an address or a partition, and offsetInPartition only with a partition"
+                .into(),
+            ));
+          }
         },
+        FlashStep::Repeat { value } => {
+          // a repeat runs its steps itself, with nowhere to hand a prompt back to the caller
+          let waits = value.steps.iter().flat_map(Step::walk).any(|step| {
+            matches!(
+              step,
+              FlashStep::Wait {
+                value: WaitValue::UserInput { .. }
+              }
+            )
+          });
+          if waits {
+            return Err(Error::InvalidOperation(
+              "wait for user input can't be inside a repeat step".into(),
+            ));
+          }
+        }
         _ => continue,
       }
     }
@@ -116,6 +313,198 @@ impl FlashConfig {
   }
 }
 
+/// A number as written in meta.json: a JSON number or a string like `"0x9C00000"`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawNumber {
+  Number(u64),
+  String(String),
+}
+
+impl RawNumber {
+  fn value(&self) -> Option<u64> {
+    self.evaluate().ok()
+  }
+
+  /// The number, or the result of a string like `"0x9C00000 + 0x200"`
+  fn evaluate(&self) -> std::result::Result<u64, String> {
+    match self {
+      RawNumber::Number(number) => Ok(*number),
+      RawNumber::String(text) => variables::evaluate(text),
+    }
+  }
+
+  /// The value, or a deserialization error explaining why the string isn't a number
+  fn parse<E: serde::de::Error>(&self) -> std::result::Result<u64, E> {
+    self.evaluate().map_err(|reason| match self {
+      RawNumber::String(text) if text.contains("${") => {
+        E::custom(format!("unknown constant or variable in \"{text}\""))
+      }
+      _ => E::custom(reason),
+    })
+  }
+
+  fn is_hex(&self) -> bool {
+    matches!(self, RawNumber::String(text) if text.trim().to_ascii_lowercase().starts_with("0x"))
+  }
+}
+
+/// Parse a number written in decimal or as `0x` hex, with `_` allowed between digits
+pub(crate) fn parse_number(text: &str) -> Option<u64> {
+  let text = text.trim().replace('_', "");
+  match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+    Some(digits) => u64::from_str_radix(digits, 16).ok(),
+    None => text.parse().ok(),
+  }
+}
+
+/// Deserialize a numeric field that may also be written as a decimal or hex string
+fn number<'de, D: Deserializer<'de>, T: TryFrom<u64>>(deserializer: D) -> std::result::Result<T, D::Error> {
+  let value = RawNumber::deserialize(deserializer)?.parse()?;
+  T::try_from(value).map_err(|_| D::Error::custom(format!("{value:#x} is too large for this field")))
+}
+
+/// A byte address, offset or length that remembers how it was written
+///
+/// Accepts a number of bytes, a string like `"0x1080000"` or `{ "sectors": 319488 }` (512 byte
+/// sectors), and serializes back in the same form, so configs keep the units their authors chose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteValue<T> {
+  bytes: T,
+  form: ByteForm,
+}
+
+/// How a [`ByteValue`] was written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteForm {
+  /// A plain or decimal string number of bytes
+  Decimal,
+  /// A `0x` hex string number of bytes
+  Hex,
+  /// A number of 512 byte sectors
+  Sectors,
+}
+
+impl<T: Copy> ByteValue<T> {
+  /// The value in bytes
+  pub fn get(&self) -> T {
+    self.bytes
+  }
+
+  /// How the value was written
+  pub fn form(&self) -> ByteForm {
+    self.form
+  }
+}
+
+impl<T> From<T> for ByteValue<T> {
+  fn from(bytes: T) -> Self {
+    Self {
+      bytes,
+      form: ByteForm::Decimal,
+    }
+  }
+}
+
+impl<T: Copy + Into<u64>> Serialize for ByteValue<T> {
+  fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    let bytes: u64 = self.bytes.into();
+    match self.form {
+      ByteForm::Decimal => serializer.serialize_u64(bytes),
+      ByteForm::Hex => serializer.serialize_str(&format!("{bytes:#x}")),
+      ByteForm::Sectors => {
+        #[derive(Serialize)]
+        struct Sectors {
+          sectors: u64,
+        }
+        Sectors {
+          sectors: bytes / PART_SECTOR_SIZE as u64,
+        }
+        .serialize(serializer)
+      }
+    }
+  }
+}
+
+impl<'de, T: TryFrom<u64>> Deserialize<'de> for ByteValue<T> {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+      Sectors { sectors: RawNumber },
+      Number(RawNumber),
+    }
+
+    let (bytes, form) = match Raw::deserialize(deserializer)? {
+      Raw::Sectors { sectors } => {
+        let sectors: u64 = sectors.parse()?;
+        let bytes = sectors
+          .checked_mul(PART_SECTOR_SIZE as u64)
+          .ok_or_else(|| D::Error::custom(format!("{sectors} sectors is too large")))?;
+        (bytes, ByteForm::Sectors)
+      }
+      Raw::Number(raw) if raw.is_hex() => (raw.parse()?, ByteForm::Hex),
+      Raw::Number(raw) => (raw.parse()?, ByteForm::Decimal),
+    };
+    let bytes = T::try_from(bytes).map_err(|_| D::Error::custom(format!("{bytes:#x} is too large for this field")))?;
+    Ok(Self { bytes, form })
+  }
+}
+
+/// Deserialize the `constants` map, whose values may be numbers or decimal or hex strings
+fn constants<'de, D: Deserializer<'de>>(
+  deserializer: D,
+) -> std::result::Result<Option<HashMap<String, u64>>, D::Error> {
+  let Some(raw) = Option::<HashMap<String, RawNumber>>::deserialize(deserializer)? else {
+    return Ok(None);
+  };
+  raw
+    .into_iter()
+    .map(|(name, raw)| match raw.value() {
+      Some(value) => Ok((name, value)),
+      None => Err(D::Error::custom(format!("constant {name} is not a number"))),
+    })
+    .collect::<std::result::Result<_, _>>()
+    .map(Some)
+}
+
+/// Replace `${NAME}` in every string of the steps with the constant's value, as it was written
+///
+/// Names that aren't constants are left alone, since `${...}` is also U-Boot's variable syntax.
+///
+/// # Returns
+/// - `Result<bool>`: Whether the config has constants, or an error for a malformed constant
+fn substitute_constants(json: &mut Value) -> Result<bool> {
+  let Some(constants) = json.get("constants").filter(|constants| !constants.is_null()) else {
+    return Ok(false);
+  };
+
+  let mut replacements = Vec::new();
+  for (name, raw) in serde_json::from_value::<HashMap<String, RawNumber>>(constants.clone())? {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+      return Err(Error::InvalidOperation(format!("invalid constant name \"{}\"", name)));
+    }
+    let text = match raw {
+      RawNumber::Number(number) => number.to_string(),
+      RawNumber::String(ref text) if parse_number(text).is_some() => text.trim().to_owned(),
+      // an expression is substituted as its value, so it can't change the meaning of the text around it
+      RawNumber::String(ref text) if let Some(value) = raw.value() => format!("{:#x}", value),
+      RawNumber::String(text) => {
+        return Err(Error::InvalidOperation(format!(
+          "constant {} is not a number: \"{}\"",
+          name, text
+        )));
+      }
+    };
+    replacements.push((format!("${{{name}}}"), text));
+  }
+
+  if let Some(steps) = json.get_mut("steps") {
+    variables::substitute(steps, &replacements);
+  }
+  Ok(true)
+}
+
 /// Reference to a file in the flash package
 #[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -123,8 +512,75 @@ impl FlashConfig {
 pub struct MetaFile {
   /// Path to the file
   pub file_path: String,
-  /// Optional encoding for text files
+  /// How the file's contents are encoded: `hex` or `base64` for binary data stored as text, or
+  /// `binary` / `utf-8` (the default) for contents used as they are
   pub encoding: Option<String>,
+  /// Where in the (decoded) contents the data starts, defaulting to the start
+  pub offset: Option<ByteValue<u64>>,
+  /// How many bytes of data to take from `offset`, defaulting to the rest of the contents
+  pub length: Option<ByteValue<u64>>,
+  /// Whether a text file, such as a `writeEnv` file or boot script, is a template to render before
+  /// it is used
+  pub template: Option<bool>,
+}
+
+impl MetaFile {
+  /// Whether the contents have to be decoded before use, and so can't be streamed as they are
+  pub fn is_encoded(&self) -> bool {
+    matches!(
+      self.encoding.as_deref().map(str::to_ascii_lowercase).as_deref(),
+      Some("hex" | "base64")
+    )
+  }
+
+  /// Decode the raw contents of the file according to its `encoding`
+  ///
+  /// # Parameters
+  /// - `raw`: The contents as read from the package
+  ///
+  /// # Returns
+  /// - `Result<Vec<u8>>`: The decoded contents or an error for a malformed file or unknown encoding
+  pub fn decode(&self, raw: Vec<u8>) -> Result<Vec<u8>> {
+    match self.encoding.as_deref().map(str::to_ascii_lowercase).as_deref() {
+      None | Some("binary" | "utf-8" | "utf8") => Ok(raw),
+      Some("hex") => decode_hex(&String::from_utf8(raw)?),
+      Some("base64") => decode_base64(&String::from_utf8(raw)?),
+      Some(encoding) => Err(Error::InvalidOperation(format!(
+        "unsupported encoding \"{}\" for {}",
+        encoding, self.file_path
+      ))),
+    }
+  }
+
+  /// Decode the raw contents of the file, then take the part `offset` and `length` select
+  pub fn contents(&self, raw: Vec<u8>) -> Result<Vec<u8>> {
+    let mut data = self.decode(raw)?;
+    let (offset, length) = self.range(data.len() as u64)?;
+    data.truncate((offset + length) as usize);
+    data.drain(..offset as usize);
+    Ok(data)
+  }
+
+  /// Whether only part of the contents is used
+  pub fn is_sliced(&self) -> bool {
+    self.offset.is_some() || self.length.is_some()
+  }
+
+  /// Offset and length of the part of `size` bytes of contents that `offset` and `length` select
+  ///
+  /// # Returns
+  /// - `Result<(u64, u64)>`: The range, or an error if it runs past the end of the contents
+  pub fn range(&self, size: u64) -> Result<(u64, u64)> {
+    let offset = self.offset.map_or(0, |offset| offset.get());
+    let length = self.length.map_or(size.saturating_sub(offset), |length| length.get());
+    if offset.checked_add(length).is_none_or(|end| end > size) {
+      return Err(Error::InvalidOperation(format!(
+        "{} bytes at {:#x} of {} run past its end at {:#x}",
+        length, offset, self.file_path, size
+      )));
+    }
+    Ok((offset, length))
+  }
 }
 
 /// Data that can be either inline or from a file
@@ -133,8 +589,70 @@ pub struct MetaFile {
 pub enum DataOrFile {
   /// Inline binary data
   Data(Vec<u8>),
+  /// Inline binary data as a hex string, e.g. `{ "hex": "deadbeef" }`
+  Hex {
+    /// Hex digits, whitespace is ignored
+    hex: String,
+  },
+  /// Inline binary data as a base64 string, e.g. `{ "base64": "3q2+7w==" }`
+  Base64 {
+    /// Standard base64, whitespace is ignored
+    base64: String,
+  },
   /// Reference to a file containing the data
   File(MetaFile),
+  /// Files joined end to end, in order, e.g. `{ "concat": ["boot.vfat", "rootfs.ext4"] }`
+  Concat {
+    /// Files to join, each a path or a file reference like `{ "filePath": "boot.vfat" }`
+    #[serde(deserialize_with = "concat_parts")]
+    concat: Vec<MetaFile>,
+  },
+}
+
+fn concat_parts<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<MetaFile>, D::Error> {
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum Part {
+    Path(String),
+    File(MetaFile),
+  }
+
+  let parts = Vec::<Part>::deserialize(deserializer)?;
+  if parts.is_empty() {
+    return Err(D::Error::custom("concat needs at least one file"));
+  }
+  Ok(
+    parts
+      .into_iter()
+      .map(|part| match part {
+        Part::Path(file_path) => MetaFile {
+          file_path,
+          encoding: None,
+          offset: None,
+          length: None,
+          template: None,
+        },
+        Part::File(file) => file,
+      })
+      .collect(),
+  )
+}
+
+/// Decode hex digits, ignoring whitespace so long strings can be wrapped
+pub(crate) fn decode_hex(text: &str) -> Result<Vec<u8>> {
+  let digits = text.split_ascii_whitespace().collect::<String>();
+  let digits = digits.strip_prefix("0x").unwrap_or(&digits);
+  hex::decode(digits).map_err(|e| Error::InvalidOperation(format!("invalid hex data: {e}")))
+}
+
+/// Decode standard base64, ignoring whitespace so long strings can be wrapped
+pub(crate) fn decode_base64(text: &str) -> Result<Vec<u8>> {
+  use base64::Engine;
+
+  let text = text.split_ascii_whitespace().collect::<String>();
+  base64::engine::general_purpose::STANDARD
+    .decode(text)
+    .map_err(|e| Error::InvalidOperation(format!("invalid base64 data: {e}")))
 }
 
 /// String that can be either inline or from a file
@@ -147,6 +665,65 @@ pub enum StringOrFile {
   File(MetaFile),
 }
 
+/// A step in the `steps` array, along with the options every step type accepts
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Step {
+  /// The operation to perform
+  #[serde(flatten)]
+  pub step: FlashStep,
+  /// Stable identifier used to correlate events and report entries with the step
+  ///
+  /// Steps without one get an id derived from their contents when the configuration is loaded,
+  /// so this is always set on a loaded [`FlashConfig`].
+  pub id: Option<String>,
+  /// Abort the step if it runs for longer than this many milliseconds
+  pub timeout_ms: Option<u64>,
+  /// What to do when the step fails (defaults to aborting the flash)
+  pub on_error: Option<OnError>,
+}
+
+impl Step {
+  /// This step followed by every step nested in it, in the order they appear
+  pub(crate) fn walk_steps(&self) -> Vec<&Step> {
+    let mut steps = vec![self];
+    if let FlashStep::Repeat { value } = &self.step {
+      steps.extend(value.steps.iter().flat_map(Step::walk_steps));
+    }
+    steps
+  }
+
+  /// The operation of this step and of every step nested in it, in the order they appear
+  pub(crate) fn walk(&self) -> Vec<&FlashStep> {
+    self.walk_steps().into_iter().map(|step| &step.step).collect()
+  }
+}
+
+/// Id of a step that was not given one: its type and the start of a digest of its contents
+fn derived_step_id(step: &FlashStep) -> Result<String> {
+  // Value sorts object keys, so the digest doesn't depend on how the step was written
+  let value = serde_json::to_value(step)?;
+  let digest = Sha256::digest(serde_json::to_vec(&value)?);
+  Ok(format!("{}-{}", step.kind(), &hex::encode(digest)[..8]))
+}
+
+/// What the flasher does when a step fails
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum OnError {
+  /// Stop flashing and return the error
+  #[default]
+  Abort,
+  /// Record the failure as a warning and move on to the next step
+  Continue,
+  /// Run the step again up to this many times, then abort
+  ///
+  /// `run`, `bl2Boot`, `reset` and `reconnect` can't be retried: once they have moved the device
+  /// on, running them again doesn't find it where they started.
+  Retry(u32),
+}
+
 /// A step in the flashing process
 ///
 /// Each step represents a specific operation to perform during flashing.
@@ -170,6 +747,8 @@ pub enum FlashStep {
     value: String,
     /// Variable to store the result
     variable: Option<String>,
+    /// Regex whose named groups are stored as variables, from the first match in the result
+    capture: Option<String>,
   },
   /// Run code at an address
   Run {
@@ -190,15 +769,19 @@ pub enum FlashStep {
   ReadSimpleMemory {
     /// Read parameters
     value: ReadMemoryValue,
-    /// Variable to store the result
+    /// Variable to store the result, hex encoded
     variable: Option<String>,
+    /// File in the output directory to write the result to
+    output: Option<StepOutput>,
   },
   /// Read a large amount of data from memory
   ReadLargeMemory {
     /// Read parameters
     value: ReadMemoryValue,
-    /// Variable to store the result
+    /// Variable to store the result, hex encoded
     variable: Option<String>,
+    /// File in the output directory to write the result to
+    output: Option<StepOutput>,
   },
   /// Get AMLC boot information
   GetBootAMLC {
@@ -227,6 +810,11 @@ pub enum FlashStep {
     /// Restore parameters
     value: RestorePartitionValue,
   },
+  /// Apply a binary delta to a partition, writing only changed regions
+  ApplyDelta {
+    /// Delta parameters
+    value: ApplyDeltaValue,
+  },
   /// Write a boot hwpartition (boot0 / boot1) wholesale
   WriteBootPartition {
     /// Write parameters
@@ -242,6 +830,11 @@ pub enum FlashStep {
     /// Environment data
     value: StringOrFile,
   },
+  /// Wrap a plain-text U-Boot script in a uImage header, as `mkimage -T script` does, and write it to a partition
+  WriteBootScript {
+    /// Script parameters
+    value: WriteBootScriptValue,
+  },
   /// Log a message
   Log {
     /// Message to log
@@ -252,20 +845,145 @@ pub enum FlashStep {
     /// Wait parameters
     value: WaitValue,
   },
+  /// Reset the device, ending the current USB session
+  Reset {
+    /// Reset parameters
+    value: ResetValue,
+  },
+  /// Wait for the device to re-enumerate and open a new USB session
+  Reconnect {
+    /// Reconnect parameters
+    value: ReconnectValue,
+  },
+  /// Run a list of steps several times, e.g. to stress test the eMMC
+  Repeat {
+    /// Repeat parameters
+    value: RepeatValue,
+  },
+  /// Check a variable or the output of a bulk command, aborting if it is not as expected
+  Assert {
+    /// Assertion parameters
+    value: AssertValue,
+  },
+  /// Write values unique to the device, such as a serial number or MAC address, from the caller's provisioner
+  Provision {
+    /// Provisioning parameters
+    value: ProvisionValue,
+  },
+  /// Boot the device and run shell commands on it through adb, aborting if their output is not as expected
+  PostBootCheck {
+    /// Check parameters
+    value: PostBootCheckValue,
+  },
+}
+
+impl FlashStep {
+  /// The step's `type` as written in meta.json
+  pub(crate) fn kind(&self) -> &'static str {
+    match self {
+      FlashStep::Identify { .. } => "identify",
+      FlashStep::Bulkcmd { .. } => "bulkcmd",
+      FlashStep::BulkcmdStat { .. } => "bulkcmdStat",
+      FlashStep::Run { .. } => "run",
+      FlashStep::WriteSimpleMemory { .. } => "writeSimpleMemory",
+      FlashStep::WriteLargeMemory { .. } => "writeLargeMemory",
+      FlashStep::ReadSimpleMemory { .. } => "readSimpleMemory",
+      FlashStep::ReadLargeMemory { .. } => "readLargeMemory",
+      FlashStep::GetBootAMLC { .. } => "getBootAMLC",
+      FlashStep::WriteAMLCData { .. } => "writeAMLCData",
+      FlashStep::Bl2Boot { .. } => "bl2Boot",
+      FlashStep::ValidatePartitionSize { .. } => "validatePartitionSize",
+      FlashStep::RestorePartition { .. } => "restorePartition",
+      FlashStep::ApplyDelta { .. } => "applyDelta",
+      FlashStep::WriteBootPartition { .. } => "writeBootPartition",
+      FlashStep::WriteUserArea { .. } => "writeUserArea",
+      FlashStep::WriteEnv { .. } => "writeEnv",
+      FlashStep::WriteBootScript { .. } => "writeBootScript",
+      FlashStep::Log { .. } => "log",
+      FlashStep::Wait { .. } => "wait",
+      FlashStep::Reset { .. } => "reset",
+      FlashStep::Reconnect { .. } => "reconnect",
+      FlashStep::Repeat { .. } => "repeat",
+      FlashStep::Assert { .. } => "assert",
+      FlashStep::Provision { .. } => "provision",
+      FlashStep::PostBootCheck { .. } => "postBootCheck",
+    }
+  }
+
+  /// The partition the step works on by name, whose offsets are relative to its start
+  pub(crate) fn partition(&self) -> Option<&str> {
+    match self {
+      FlashStep::ValidatePartitionSize { value, .. } => Some(&value.name),
+      FlashStep::RestorePartition { value } => Some(&value.name),
+      FlashStep::ApplyDelta { value } => Some(&value.name),
+      FlashStep::WriteBootScript { value } => Some(&value.partition),
+      _ => None,
+    }
+  }
+
+  /// The data a write step replaces bytes in as it is written, and the patches it applies
+  pub(crate) fn patches(&self) -> Option<(&DataOrFile, &[Patch])> {
+    let (data, patches) = match self {
+      FlashStep::WriteLargeMemory { value } => (&value.data, &value.patches),
+      FlashStep::RestorePartition { value } => (&value.data, &value.patches),
+      FlashStep::WriteBootPartition { value } => (&value.data, &value.patches),
+      FlashStep::WriteUserArea { value } => (&value.data, &value.patches),
+      _ => return None,
+    };
+    Some((data, patches.as_deref()?))
+  }
+
+  /// Whether running the step again after it failed part way starts it over
+  ///
+  /// Steps that hand the device over to code they loaded, reset it or wait for it to come back
+  /// leave it somewhere else by the time they fail, so [`OnError::Retry`] is refused for them.
+  pub(crate) fn repeatable(&self) -> bool {
+    !matches!(
+      self,
+      FlashStep::Run { .. } | FlashStep::Bl2Boot { .. } | FlashStep::Reset { .. } | FlashStep::Reconnect { .. }
+    )
+  }
+
+  /// Whether the step streams its data to disk in chunks, so a cancelled run can carry on part way
+  pub(crate) fn resumes_mid_write(&self) -> bool {
+    matches!(
+      self,
+      FlashStep::WriteLargeMemory { .. } | FlashStep::RestorePartition { .. } | FlashStep::WriteUserArea { .. }
+    )
+  }
+
+  /// Whether the step talks to the device over a live USB session
+  ///
+  /// The AMLC steps are excluded since they continue the handshake with a BL2 started by `run`.
+  /// `repeat` is excluded since each of its steps checks for itself.
+  pub(crate) fn needs_session(&self) -> bool {
+    match self {
+      FlashStep::Log { .. }
+      | FlashStep::Wait { .. }
+      | FlashStep::GetBootAMLC { .. }
+      | FlashStep::WriteAMLCData { .. }
+      | FlashStep::Reconnect { .. }
+      | FlashStep::Repeat { .. }
+      | FlashStep::PostBootCheck { .. } => false,
+      FlashStep::Assert { value } => value.bulkcmd.is_some(),
+      FlashStep::Provision { value } => value.env.is_some() || value.data.is_some(),
+      _ => true,
+    }
+  }
 }
 
 #[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct RunValue {
-  pub address: u32,
+  pub address: ByteValue<u32>,
   pub keep_power: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct WriteSimpleMemoryValue {
-  pub address: u32,
+  pub address: ByteValue<u32>,
   pub data: DataOrFile,
 }
 
@@ -273,23 +991,54 @@ pub struct WriteSimpleMemoryValue {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct WriteLargeMemoryValue {
-  pub address: u32,
+  /// absolute disk address to write to; give either this or `partition`.
+  pub address: Option<ByteValue<u32>>,
+  /// partition to write to, resolved against the device's partition table.
+  pub partition: Option<String>,
+  /// where in `partition` to start writing; defaults to its start.
+  pub offset_in_partition: Option<ByteValue<u32>>,
   pub data: DataOrFile,
+  #[serde(deserialize_with = "number")]
   pub block_length: usize,
   pub append_zeros: Option<bool>,
+  /// skip 8MB regions the disk already holds instead of rewriting them.
+  pub compare_before_write: Option<bool>,
+  /// permit the write to run past the end of the partition it starts in.
+  pub allow_cross_partition: Option<bool>,
+  /// bytes to replace in `data` as it is written.
+  pub patches: Option<Vec<Patch>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ReadMemoryValue {
-  pub address: u32,
-  pub length: usize,
+  pub address: ByteValue<u32>,
+  pub length: ByteValue<u32>,
+}
+
+/// Bytes a write step replaces in its data as it is written, such as a serial number or feature flag
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Patch {
+  /// Where in the data the bytes go
+  pub offset: ByteValue<u64>,
+  /// The bytes, as hex
+  pub hex: String,
+}
+
+/// File a step writes what it read to, in the flasher's output directory
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StepOutput {
+  /// Path of the file, relative to the output directory
+  pub file_path: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct WriteAMLCDataValue {
   pub seq: u8,
+  #[serde(deserialize_with = "number")]
   pub amlc_offset: u32,
   pub data: DataOrFile,
 }
@@ -307,27 +1056,81 @@ pub struct ValidatePartitionSizeValue {
   pub name: String,
 }
 
+#[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct RestorePartitionValue {
   pub name: String,
   pub data: DataOrFile,
+  /// skip 8MB regions the partition already holds instead of rewriting them.
+  pub compare_before_write: Option<bool>,
+  /// second confirmation required, along with `allowSpecialPartitions`, to write a special partition.
+  pub confirm_special_partition: Option<bool>,
+  /// stop before the run of zero or 0xff padding the file ends with instead of writing it.
+  pub trim_padding: Option<bool>,
+  /// with `trimPadding`, erase the trimmed region instead of leaving what the partition held there.
+  pub erase_trimmed: Option<bool>,
+  /// bytes to replace in `data` as it is written.
+  pub patches: Option<Vec<Patch>>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ApplyDeltaValue {
+  /// name of the partition the delta was generated against.
+  pub name: String,
+  /// bsdiff patch, optionally zstd compressed.
+  pub delta: DataOrFile,
+}
+
+#[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct WriteBootPartitionValue {
   /// eMMC hwpart index: 1 = boot0, 2 = boot1.
   pub hwpart: u8,
   pub data: DataOrFile,
+  /// bytes to replace in `data` as it is written.
+  pub patches: Option<Vec<Patch>>,
 }
 
+#[serde_with::skip_serializing_none]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct WriteUserAreaValue {
   /// absolute LBA on hwpart 0; sector size is 512.
+  #[serde(deserialize_with = "number")]
   pub lba: u32,
   pub data: DataOrFile,
+  /// skip 8MB regions the user area already holds instead of rewriting them.
+  pub compare_before_write: Option<bool>,
+  /// bytes to replace in `data` as it is written.
+  pub patches: Option<Vec<Patch>>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WriteBootScriptValue {
+  /// U-Boot commands, one per line, as they would be given to `mkimage -d`.
+  pub script: StringOrFile,
+  /// partition to write the image to.
+  pub partition: String,
+  /// where in `partition` to write it; defaults to its start.
+  pub offset_in_partition: Option<ByteValue<u32>>,
+  /// image name in the header, at most 32 bytes; defaults to `boot script`.
+  pub name: Option<String>,
+  /// architecture in the header; defaults to arm64.
+  pub arch: Option<ScriptArch>,
+}
+
+/// CPU architecture recorded in a boot script's uImage header
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ScriptArch {
+  Arm,
+  #[default]
+  Arm64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -337,6 +1140,107 @@ pub enum WaitValue {
   Time { time: u64 },
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ResetValue {
+  pub mode: ResetMode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ResetMode {
+  /// reboot normally.
+  Soft,
+  /// reboot back into USB burn mode.
+  Burn,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconnectValue {
+  /// milliseconds to wait for the device to come back; defaults to 30000.
+  pub timeout: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RepeatValue {
+  /// how many times to run the steps.
+  pub count: u32,
+  /// steps to run in each iteration, with the same options as top-level steps.
+  pub steps: Vec<Step>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertValue {
+  /// variable to check, set by an earlier step; give either this or `bulkcmd`.
+  pub variable: Option<String>,
+  /// bulk command to run, checking its response.
+  pub bulkcmd: Option<String>,
+  /// the value must be exactly this; give one of `equals`, `contains` and `matches`.
+  pub equals: Option<String>,
+  /// the value must contain this.
+  pub contains: Option<String>,
+  /// the value must match this regular expression somewhere.
+  pub matches: Option<String>,
+  /// explanation shown when the assertion fails.
+  pub message: Option<String>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PostBootCheckValue {
+  /// milliseconds to wait for the device to boot and show up in adb; defaults to 120000.
+  pub timeout: Option<u64>,
+  /// commands to run on the device, in order; all of them run even if one fails.
+  pub checks: Vec<PostBootCommand>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PostBootCommand {
+  /// shell command line to run, e.g. `systemctl is-system-running`.
+  pub command: String,
+  /// the trimmed output must be exactly this; give at most one of `equals`, `contains` and `matches`,
+  /// or none to only require the command to exit successfully.
+  pub equals: Option<String>,
+  /// the output must contain this.
+  pub contains: Option<String>,
+  /// the output must match this regular expression somewhere.
+  pub matches: Option<String>,
+  /// explanation shown when the check fails.
+  pub message: Option<String>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ProvisionValue {
+  /// variables the provisioner sets for the device, such as `serial` and `mac`.
+  pub variables: Vec<String>,
+  /// U-Boot env variables to set, whose values may refer to any variable.
+  pub env: Option<BTreeMap<String, String>>,
+  /// text to write into a partition, which may refer to any variable.
+  pub data: Option<ProvisionDataValue>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ProvisionDataValue {
+  /// partition to write to.
+  pub partition: String,
+  /// where in `partition` to start writing, on a sector boundary; defaults to its start.
+  pub offset_in_partition: Option<ByteValue<u32>>,
+  /// text to write; the rest of its last sector is zeroed.
+  pub text: String,
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -408,12 +1312,11 @@ mod tests {
     let config = FlashConfig::from_standalone(json).expect("mainline meta.json should parse");
     assert_eq!(config.metadata_version, 2);
     assert_eq!(config.steps.len(), 5);
-    matches!(&config.steps[1], FlashStep::WriteBootPartition { value } if value.hwpart == 1);
-    matches!(&config.steps[3], FlashStep::WriteUserArea { value } if value.lba == 0);
+    matches!(&config.steps[1].step, FlashStep::WriteBootPartition { value } if value.hwpart == 1);
+    matches!(&config.steps[3].step, FlashStep::WriteUserArea { value } if value.lba == 0);
   }
 
   #[test]
-  #[should_panic]
   fn test_simple_firmware() {
     let json = r#"
         {
@@ -535,4 +1438,398 @@ mod tests {
     let vars = config.variables.expect("Missing variables");
     assert_eq!(vars.get("readData"), Some(&0));
   }
+
+  #[test]
+  fn test_reset_and_reconnect() {
+    let json = r#"
+        {
+          "name": "reset",
+          "version": "1.0.0",
+          "description": "Reset and reconnect",
+          "steps": [
+            { "type": "reset", "value": { "mode": "burn" } },
+            { "type": "reconnect", "value": { "timeout": 60000 } },
+            { "type": "reset", "value": { "mode": "soft" } },
+            { "type": "reconnect", "value": {} }
+          ],
+          "metadataVersion": 1
+        }
+        "#;
+    let config = FlashConfig::from_standalone(json).unwrap();
+    assert!(matches!(
+      &config.steps[0].step,
+      FlashStep::Reset {
+        value: ResetValue { mode: ResetMode::Burn }
+      }
+    ));
+    assert!(matches!(
+      &config.steps[1].step,
+      FlashStep::Reconnect {
+        value: ReconnectValue { timeout: Some(60000) }
+      }
+    ));
+    assert!(matches!(
+      &config.steps[2].step,
+      FlashStep::Reset {
+        value: ResetValue { mode: ResetMode::Soft }
+      }
+    ));
+    assert!(matches!(
+      &config.steps[3].step,
+      FlashStep::Reconnect {
+        value: ReconnectValue { timeout: None }
+      }
+    ));
+  }
+
+  #[test]
+  fn test_step_timeout() {
+    let json = r#"
+        {
+          "name": "timeouts",
+          "version": "1.0.0",
+          "description": "Steps with timeouts",
+          "steps": [
+            { "type": "bulkcmd", "value": "mmc erase 0 1000", "timeoutMs": 5000 },
+            { "type": "bulkcmd", "value": "setenv a b", "timeoutMs": 100, "onError": "continue" },
+            { "type": "bulkcmd", "value": "saveenv" }
+          ],
+          "metadataVersion": 1
+        }
+        "#;
+    let config = FlashConfig::from_standalone(json).unwrap();
+    let timeouts: Vec<_> = config.steps.iter().map(|step| step.timeout_ms).collect();
+    assert_eq!(timeouts, [Some(5000), Some(100), None]);
+    assert!(
+      serde_json::to_string(&config.steps[0])
+        .unwrap()
+        .contains("\"timeoutMs\":5000")
+    );
+  }
+
+  #[test]
+  fn test_on_error() {
+    let config = |steps: &str| {
+      FlashConfig::from_standalone(&format!(
+        r#"{{ "name": "on-error", "version": "1.0.0", "description": "onError", "steps": {}, "metadataVersion": 1 }}"#,
+        steps
+      ))
+    };
+    let loaded = config(
+      r#"[
+        { "type": "bulkcmd", "value": "setenv a b", "onError": "continue" },
+        { "type": "restorePartition", "value": { "name": "logo", "data": { "filePath": "logo.img" } }, "onError": { "retry": 2 } },
+        { "type": "bulkcmd", "value": "saveenv", "onError": "abort" },
+        { "type": "bulkcmd", "value": "reset" }
+      ]"#,
+    )
+    .unwrap();
+    let on_error: Vec<_> = loaded.steps.iter().map(|step| step.on_error).collect();
+    assert_eq!(
+      on_error,
+      [
+        Some(OnError::Continue),
+        Some(OnError::Retry(2)),
+        Some(OnError::Abort),
+        None
+      ]
+    );
+
+    let err = config(r#"[{ "type": "reconnect", "value": {}, "onError": { "retry": 1 } }]"#)
+      .err()
+      .unwrap();
+    assert!(err.to_string().contains("reconnect steps can't be retried"), "{err}");
+    assert!(config(r#"[{ "type": "reconnect", "value": {}, "onError": "continue" }]"#).is_ok());
+  }
+
+  #[test]
+  fn test_inline_encodings() {
+    let parse = |json: &str| serde_json::from_str::<DataOrFile>(json).unwrap();
+    assert!(matches!(parse("[1, 2]"), DataOrFile::Data(data) if data == [1, 2]));
+    assert!(
+      matches!(parse(r#"{ "hex": "dead beef" }"#), DataOrFile::Hex { hex } if decode_hex(&hex).unwrap() == [0xde, 0xad, 0xbe, 0xef])
+    );
+    assert!(
+      matches!(parse(r#"{ "base64": "3q2+\n7w==" }"#), DataOrFile::Base64 { base64 } if decode_base64(&base64).unwrap() == [0xde, 0xad, 0xbe, 0xef])
+    );
+    assert!(matches!(parse(r#"{ "filePath": "env.txt" }"#), DataOrFile::File(_)));
+    assert!(
+      matches!(parse(r#"{ "concat": ["boot.vfat", { "filePath": "rootfs.ext4" }] }"#), DataOrFile::Concat { concat } if concat.len() == 2 && concat[1].file_path == "rootfs.ext4")
+    );
+    assert!(decode_hex("abc").is_err());
+
+    let file = |encoding: Option<&str>| MetaFile {
+      file_path: "env.txt".to_owned(),
+      encoding: encoding.map(str::to_owned),
+      offset: None,
+      length: None,
+      template: None,
+    };
+    assert!(!file(None).is_encoded());
+    assert!(file(Some("HEX")).is_encoded());
+    assert_eq!(file(None).decode(b"0x00".to_vec()).unwrap(), b"0x00");
+    assert_eq!(file(Some("hex")).decode(b"0x00ff\n".to_vec()).unwrap(), [0x00, 0xff]);
+    assert_eq!(file(Some("base64")).decode(b"AP8=".to_vec()).unwrap(), [0x00, 0xff]);
+    assert!(file(Some("latin1")).decode(vec![]).is_err());
+
+    let DataOrFile::File(slice) = parse(r#"{ "filePath": "disk.img", "offset": "0x2", "length": 3 }"#) else {
+      panic!("not a file");
+    };
+    assert!(slice.is_sliced() && !file(None).is_sliced());
+    assert_eq!(slice.range(8).unwrap(), (2, 3));
+    assert_eq!(slice.contents(b"abcdefgh".to_vec()).unwrap(), b"cde");
+    assert!(slice.range(4).is_err());
+    let tail = MetaFile { length: None, ..slice };
+    assert_eq!(tail.contents(b"abcdefgh".to_vec()).unwrap(), b"cdefgh");
+    assert!(tail.range(1).is_err());
+  }
+
+  #[test]
+  fn test_constants() {
+    let json = r#"
+        {
+          "metadataVersion": 2,
+          "name": "constants",
+          "version": "0.1.0",
+          "description": "constants.",
+          "constants": { "ROOTFS_OFFSET": 163577856, "LOAD_ADDR": "0x0108_0000" },
+          "steps": [
+            { "type": "writeLargeMemory", "value": { "address": "${ROOTFS_OFFSET}", "data": [0], "blockLength": "0x1000" } },
+            { "type": "bulkcmd", "value": "mmc read ${LOAD_ADDR} ${loadaddr}" },
+            { "type": "run", "value": { "address": "${LOAD_ADDR}" } }
+          ]
+        }
+    "#;
+    let config = FlashConfig::from_standalone(json).unwrap();
+    assert_eq!(config.constants.as_ref().unwrap()["LOAD_ADDR"], 0x1080000);
+    assert!(matches!(
+      &config.steps[0].step,
+      FlashStep::WriteLargeMemory { value: WriteLargeMemoryValue { address: Some(address), block_length: 0x1000, .. } }
+        if address.get() == 163577856
+    ));
+    assert!(
+      matches!(&config.steps[1].step, FlashStep::Bulkcmd { value } if value == "mmc read 0x0108_0000 ${loadaddr}")
+    );
+    assert!(matches!(
+      &config.steps[2].step,
+      FlashStep::Run { value: RunValue { address, .. } } if address.get() == 0x1080000
+    ));
+
+    assert!(FlashConfig::from_standalone(&json.replace("${ROOTFS_OFFSET}", "${ROOTFS}")).is_err());
+    assert!(FlashConfig::from_standalone(&json.replace("163577856", "\"big\"")).is_err());
+    assert!(FlashConfig::from_standalone(&json.replace("0x0108_0000", "0x1_0000_0000")).is_err());
+    assert_eq!(parse_number("0X9C00000"), Some(0x9c00000));
+    assert_eq!(parse_number("1_024"), Some(1024));
+    assert_eq!(parse_number("0x"), None);
+
+    let computed = json.replace(
+      r#""address": "${LOAD_ADDR}""#,
+      r#""address": "(${LOAD_ADDR} + ${SIZE}) * 2", "keepPower": true"#,
+    );
+    let computed = computed.replace(r#""ROOTFS_OFFSET""#, r#""SIZE": "0x100 + 0x100", "ROOTFS_OFFSET""#);
+    let config = FlashConfig::from_standalone(&computed).unwrap();
+    assert!(matches!(
+      &config.steps[2].step,
+      FlashStep::Run { value: RunValue { address, .. } } if address.get() == (0x1080000 + 0x200) * 2
+    ));
+    assert!(FlashConfig::from_standalone(&computed.replace("* 2", "- 0x2000000")).is_err());
+  }
+
+  #[test]
+  fn test_byte_value() {
+    let parse = |json: &str| serde_json::from_str::<ByteValue<u32>>(json);
+    let round_trip = |json: &str| serde_json::to_string(&parse(json).unwrap()).unwrap();
+
+    assert_eq!(parse("17301504").unwrap().get(), 0x1080000);
+    assert_eq!(parse(r#""0x1080000""#).unwrap().get(), 0x1080000);
+    assert_eq!(parse(r#""0x1080000""#).unwrap().form(), ByteForm::Hex);
+    assert_eq!(parse(r#"{ "sectors": 319488 }"#).unwrap().get(), 319488 * 512);
+    assert_eq!(parse(r#"{ "sectors": "0x4e000" }"#).unwrap().form(), ByteForm::Sectors);
+    assert_eq!(round_trip("17301504"), "17301504");
+    assert_eq!(round_trip(r#""0x1080000""#), r#""0x1080000""#);
+    assert_eq!(round_trip(r#"{ "sectors": 319488 }"#), r#"{"sectors":319488}"#);
+
+    assert!(parse(r#"{ "sectors": 8388608 }"#).is_err());
+    assert!(parse(r#""0x1_0000_0000""#).is_err());
+    assert!(parse(r#""sectors""#).is_err());
+  }
+
+  #[test]
+  fn test_write_large_memory_target() {
+    let config = |value: &str| {
+      FlashConfig::from_standalone(&format!(
+        r#"{{
+          "metadataVersion": 1, "name": "target", "version": "0.1.0", "description": "target.",
+          "steps": [{{ "type": "writeLargeMemory", "value": {{ {value}, "data": [0], "blockLength": 4096 }} }}]
+        }}"#
+      ))
+    };
+
+    let parsed = config(r#""partition": "logo", "offsetInPartition": { "sectors": 8 }"#).unwrap();
+    assert!(matches!(
+      &parsed.steps[0].step,
+      FlashStep::WriteLargeMemory { value: WriteLargeMemoryValue { address: None, partition: Some(partition), offset_in_partition: Some(offset), .. } }
+        if partition == "logo" && offset.get() == 4096
+    ));
+    assert!(config(r#""address": "0x2700000""#).is_ok());
+    assert!(config(r#""address": 0, "partition": "logo""#).is_err());
+    assert!(config(r#""address": 0, "offsetInPartition": 512"#).is_err());
+    assert!(config(r#""appendZeros": true"#).is_err());
+  }
+  #[test]
+  fn test_step_ids() {
+    let config = |steps: &str| {
+      FlashConfig::from_standalone(&format!(
+        r#"{{ "metadataVersion": 2, "name": "ids", "version": "0.1.0", "description": "ids.", "steps": [{steps}] }}"#
+      ))
+    };
+    let ids = |config: &FlashConfig| {
+      config
+        .steps
+        .iter()
+        .map(|step| step.id.clone().unwrap())
+        .collect::<Vec<_>>()
+    };
+
+    let parsed = config(
+      r#"{ "type": "bulkcmd", "value": "amlmmc key" },
+         { "type": "log", "value": "hi", "id": "greeting" },
+         { "type": "bulkcmd", "value": "amlmmc key" }"#,
+    )
+    .unwrap();
+    let parsed = ids(&parsed);
+    assert!(parsed[0].starts_with("bulkcmd-"), "{}", parsed[0]);
+    assert_eq!(parsed[1], "greeting");
+    assert_eq!(parsed[2], format!("{}-2", parsed[0]));
+
+    // derived ids don't depend on the position of the step
+    let shifted =
+      config(r#"{ "type": "log", "value": "first" }, { "type": "bulkcmd", "value": "amlmmc key" }"#).unwrap();
+    assert_eq!(ids(&shifted)[1], parsed[0]);
+
+    assert!(
+      config(r#"{ "type": "log", "value": "a", "id": "x" }, { "type": "log", "value": "b", "id": "x" }"#).is_err()
+    );
+    assert!(config(r#"{ "type": "log", "value": "a", "id": "" }"#).is_err());
+  }
+
+  #[test]
+  fn test_repeat() {
+    let config = |count: u32, id: &str, nested: &str| {
+      FlashConfig::from_standalone(&format!(
+        r#"{{
+          "metadataVersion": 2, "name": "repeat", "version": "0.1.0", "description": "repeat.",
+          "steps": [
+            {{ "type": "log", "value": "start", "id": "{id}" }},
+            {{ "type": "repeat", "value": {{ "count": {count}, "steps": [
+              {{ "type": "log", "value": "round", "id": "round" }},
+              {nested}
+            ] }} }}
+          ]
+        }}"#
+      ))
+    };
+    let bulkcmd = r#"{ "type": "bulkcmd", "value": "amlmmc key" }"#;
+
+    let parsed = config(2, "start", bulkcmd).unwrap();
+    let FlashStep::Repeat { value } = &parsed.steps[1].step else {
+      panic!("not a repeat step");
+    };
+    assert_eq!(value.count, 2);
+    assert_eq!(value.steps[0].id.as_deref(), Some("round"));
+    assert!(value.steps[1].id.as_deref().unwrap().starts_with("bulkcmd-"));
+
+    // nested steps share the id namespace and are checked like top-level ones
+    assert!(config(2, "round", bulkcmd).is_err());
+    assert!(config(2, "start", r#"{ "type": "getBootAMLC" }"#).is_err());
+    assert!(config(0, "start", bulkcmd).is_err());
+  }
+
+  #[test]
+  fn test_assert() {
+    let config = |value: &str| {
+      FlashConfig::from_standalone(&format!(
+        r#"{{
+          "metadataVersion": 2, "name": "assert", "version": "0.1.0", "description": "assert.",
+          "steps": [{{ "type": "assert", "value": {{ {value} }} }}]
+        }}"#
+      ))
+    };
+
+    let parsed = config(r#""variable": "version", "equals": "0-7-0-16-0-0-0-0""#).unwrap();
+    assert!(!parsed.steps[0].step.needs_session());
+    let parsed = config(r#""bulkcmd": "printenv active_slot", "contains": "_a", "message": "boot slot a""#).unwrap();
+    assert!(parsed.steps[0].step.needs_session());
+    assert!(config(r#""bulkcmd": "printenv serial", "matches": "^success serial=\\w+$""#).is_ok());
+
+    assert!(config(r#""equals": "a""#).is_err());
+    assert!(config(r#""variable": "a", "bulkcmd": "printenv a", "equals": "a""#).is_err());
+    assert!(config(r#""variable": "a""#).is_err());
+    assert!(config(r#""variable": "a", "equals": "a", "contains": "a""#).is_err());
+    assert!(config(r#""variable": "a", "matches": "(unclosed""#).is_err());
+  }
+
+  #[test]
+  fn test_step_kind() {
+    let data = r#"{ "filePath": "a.img" }"#;
+    let steps = [
+      r#"{ "type": "identify" }"#.to_owned(),
+      r#"{ "type": "bulkcmd", "value": "saveenv" }"#.into(),
+      r#"{ "type": "bulkcmdStat", "value": "printenv" }"#.into(),
+      r#"{ "type": "run", "value": { "address": 0 } }"#.into(),
+      format!(r#"{{ "type": "writeSimpleMemory", "value": {{ "address": 0, "data": {data} }} }}"#),
+      format!(r#"{{ "type": "writeLargeMemory", "value": {{ "address": 0, "data": {data}, "blockLength": 4096 }} }}"#),
+      r#"{ "type": "readSimpleMemory", "value": { "address": 0, "length": 4 } }"#.into(),
+      r#"{ "type": "readLargeMemory", "value": { "address": 0, "length": 4 } }"#.into(),
+      r#"{ "type": "getBootAMLC" }"#.into(),
+      format!(r#"{{ "type": "writeAMLCData", "value": {{ "seq": 0, "amlcOffset": 0, "data": {data} }} }}"#),
+      format!(r#"{{ "type": "bl2Boot", "value": {{ "bl2": {data}, "bootloader": {data} }} }}"#),
+      r#"{ "type": "validatePartitionSize", "value": { "name": "env" } }"#.into(),
+      format!(r#"{{ "type": "restorePartition", "value": {{ "name": "env", "data": {data} }} }}"#),
+      format!(r#"{{ "type": "applyDelta", "value": {{ "name": "env", "delta": {data} }} }}"#),
+      format!(r#"{{ "type": "writeBootPartition", "value": {{ "hwpart": 1, "data": {data} }} }}"#),
+      format!(r#"{{ "type": "writeUserArea", "value": {{ "lba": 0, "data": {data} }} }}"#),
+      r#"{ "type": "writeEnv", "value": "bootdelay=0" }"#.into(),
+      r#"{ "type": "log", "value": "hi" }"#.into(),
+      r#"{ "type": "wait", "value": { "type": "time", "time": 1 } }"#.into(),
+      r#"{ "type": "reset", "value": { "mode": "soft" } }"#.into(),
+      r#"{ "type": "reconnect", "value": {} }"#.into(),
+      r#"{ "type": "repeat", "value": { "count": 1, "steps": [] } }"#.into(),
+      r#"{ "type": "assert", "value": { "variable": "a", "equals": "a" } }"#.into(),
+      r#"{ "type": "provision", "value": { "variables": ["a"] } }"#.into(),
+    ];
+    for json in steps {
+      let step: FlashStep = serde_json::from_str(&json).unwrap();
+      assert_eq!(serde_json::to_value(&step).unwrap()["type"], step.kind(), "{json}");
+    }
+  }
+
+  #[test]
+  fn test_provision() {
+    let config = |value: &str| {
+      FlashConfig::from_standalone(&format!(
+        r#"{{
+          "metadataVersion": 2, "name": "provision", "version": "0.1.0", "description": "provision.",
+          "steps": [
+            {{ "type": "provision", "value": {{ {value} }} }},
+            {{ "type": "bulkcmd", "value": "setenv label ${{serial}}" }}
+          ]
+        }}"#
+      ))
+    };
+
+    let parsed = config(r#""variables": ["serial"], "env": { "serialno": "${serial}" }"#).unwrap();
+    assert!(parsed.steps[0].step.needs_session());
+    // the provision step fills in its own variables, later steps are loaded with them
+    assert!(!parsed.deferred.contains_key(parsed.steps[0].id.as_ref().unwrap()));
+    assert!(parsed.deferred.contains_key(parsed.steps[1].id.as_ref().unwrap()));
+    let data = r#""variables": ["serial"], "data": { "partition": "misc", "offsetInPartition": { "sectors": 2 }, "text": "${serial}" }"#;
+    assert!(config(data).is_ok());
+
+    assert!(config(r#""variables": [], "env": { "serialno": "${serial}" }"#).is_err());
+    assert!(config(r#""variables": ["serial"]"#).is_err());
+    let unaligned =
+      r#""variables": ["serial"], "data": { "partition": "misc", "offsetInPartition": 100, "text": "${serial}" }"#;
+    assert!(config(unaligned).is_err());
+  }
 }
@@ -0,0 +1,197 @@
+//! Binary delta (bsdiff) patch application.
+//!
+//! Patches use the raw stream format produced by the `bsdiff` crate: a sequence of control
+//! triples (`mix_len`, `copy_len`, `seek_len`, each an 8-byte sign-magnitude integer), each
+//! followed by `mix_len` diff bytes and `copy_len` extra bytes. Patches may optionally be
+//! compressed with zstd, which is detected from the frame magic.
+
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use crate::{Error, Result};
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Apply a bsdiff patch against the `old_len` bytes of `old`, handing the reconstructed image to
+/// `on_chunk` in `chunk_size` pieces along with the byte offset of each piece.
+///
+/// `old` is only read where the patch refers to it, so it can be a file rather than held in memory.
+///
+/// Returns the total size of the reconstructed image.
+pub(crate) fn apply_bsdiff<B: Read + Seek, F: FnMut(usize, &[u8]) -> Result<()>>(
+  old: &mut B,
+  old_len: usize,
+  patch: &[u8],
+  chunk_size: usize,
+  mut on_chunk: F,
+) -> Result<usize> {
+  let mut patch: Box<dyn Read + '_> = if patch.starts_with(&ZSTD_MAGIC) {
+    tracing::debug!("delta is zstd compressed");
+    Box::new(zstd::Decoder::new(Cursor::new(patch))?)
+  } else {
+    Box::new(Cursor::new(patch))
+  };
+
+  let mut chunk = Vec::with_capacity(chunk_size);
+  let mut chunk_offset = 0;
+  let mut old_pos: i64 = 0;
+
+  let mut push = |bytes: &[u8], chunk: &mut Vec<u8>| -> Result<()> {
+    let mut bytes = bytes;
+    while !bytes.is_empty() {
+      let take = std::cmp::min(chunk_size - chunk.len(), bytes.len());
+      chunk.extend_from_slice(&bytes[..take]);
+      bytes = &bytes[take..];
+      if chunk.len() == chunk_size {
+        on_chunk(chunk_offset, chunk)?;
+        chunk_offset += chunk.len();
+        chunk.clear();
+      }
+    }
+    Ok(())
+  };
+
+  let mut control = [0u8; 24];
+  while read_control(&mut patch, &mut control)? {
+    let mix_len = to_len(offtin(&control[0..8]))?;
+    let copy_len = to_len(offtin(&control[8..16]))?;
+    let seek_len = offtin(&control[16..24]);
+
+    let mut mix = vec![0u8; mix_len];
+    patch.read_exact(&mut mix)?;
+    let start = to_len(old_pos)?;
+    if start.checked_add(mix_len).is_none_or(|end| end > old_len) {
+      return Err(Error::InvalidOperation(format!(
+        "delta reads {} bytes at offset {:#x} past the end of the {} byte base image",
+        mix_len, start, old_len
+      )));
+    }
+    let mut base = vec![0u8; mix_len];
+    old.seek(SeekFrom::Start(start as u64))?;
+    old.read_exact(&mut base)?;
+    for (byte, base) in mix.iter_mut().zip(&base) {
+      *byte = byte.wrapping_add(*base);
+    }
+    push(&mix, &mut chunk)?;
+
+    let mut extra = vec![0u8; copy_len];
+    patch.read_exact(&mut extra)?;
+    push(&extra, &mut chunk)?;
+
+    old_pos += mix_len as i64 + seek_len;
+  }
+
+  if !chunk.is_empty() {
+    on_chunk(chunk_offset, &chunk)?;
+    chunk_offset += chunk.len();
+  }
+
+  Ok(chunk_offset)
+}
+
+/// Locate the first and last differing bytes of two equal-length slices, widened to whole
+/// sectors of `sector_size` bytes.
+pub(crate) fn changed_span(old: &[u8], new: &[u8], sector_size: usize) -> Option<(usize, usize)> {
+  let first = old.iter().zip(new).position(|(a, b)| a != b)?;
+  let last = old.iter().zip(new).rposition(|(a, b)| a != b)?;
+
+  let start = first - first % sector_size;
+  let end = std::cmp::min((last + 1).div_ceil(sector_size) * sector_size, new.len());
+  Some((start, end))
+}
+
+fn read_control<R: Read>(patch: &mut R, buf: &mut [u8; 24]) -> Result<bool> {
+  let mut filled = 0;
+  while filled < buf.len() {
+    match patch.read(&mut buf[filled..])? {
+      0 if filled == 0 => return Ok(false),
+      0 => {
+        return Err(Error::InvalidOperation(
+          "delta ends in the middle of a control block".into(),
+        ));
+      }
+      n => filled += n,
+    }
+  }
+  Ok(true)
+}
+
+fn offtin(buf: &[u8]) -> i64 {
+  let y = i64::from_le_bytes(buf.try_into().expect("control fields are 8 bytes"));
+  if y & (1 << 63) == 0 { y } else { -(y & !(1 << 63)) }
+}
+
+fn to_len(value: i64) -> Result<usize> {
+  usize::try_from(value).map_err(|_| Error::InvalidOperation(format!("invalid length in delta: {}", value)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn control(mix: i64, copy: i64, seek: i64) -> Vec<u8> {
+    let enc = |v: i64| {
+      if v < 0 {
+        ((-v) | (1 << 63)).to_le_bytes()
+      } else {
+        v.to_le_bytes()
+      }
+    };
+    [enc(mix), enc(copy), enc(seek)].concat()
+  }
+
+  #[test]
+  fn test_apply_bsdiff() {
+    let old = b"hello world".to_vec();
+    // keep "hello ", append "there", then seek back and repeat "hello "
+    let mut patch = control(6, 5, 0);
+    patch.extend([0u8; 6]);
+    patch.extend(b"there");
+    patch.extend(control(0, 0, -6));
+    patch.extend(control(6, 0, 0));
+    patch.extend([0u8; 6]);
+
+    let mut out = vec![];
+    let size = apply_bsdiff(&mut Cursor::new(&old), old.len(), &patch, 4, |offset, chunk| {
+      assert_eq!(offset, out.len());
+      out.extend_from_slice(chunk);
+      Ok(())
+    })
+    .unwrap();
+
+    assert_eq!(size, out.len());
+    assert_eq!(out, b"hello therehello ");
+  }
+
+  #[test]
+  fn test_apply_bsdiff_zstd() {
+    let old = vec![1u8; 16];
+    let mut patch = control(16, 0, 0);
+    patch.extend([1u8; 16]);
+    let patch = zstd::encode_all(Cursor::new(patch), 0).unwrap();
+
+    let mut out = vec![];
+    apply_bsdiff(&mut Cursor::new(&old), old.len(), &patch, 1024, |_, chunk| {
+      out.extend_from_slice(chunk);
+      Ok(())
+    })
+    .unwrap();
+    assert_eq!(out, vec![2u8; 16]);
+  }
+
+  #[test]
+  fn test_apply_bsdiff_out_of_bounds() {
+    let patch = control(8, 0, 0).into_iter().chain([0u8; 8]).collect::<Vec<_>>();
+    assert!(apply_bsdiff(&mut Cursor::new([0u8; 4]), 4, &patch, 4, |_, _| Ok(())).is_err());
+  }
+
+  #[test]
+  fn test_changed_span() {
+    let old = [0u8; 2048];
+    let mut new = old;
+    assert_eq!(changed_span(&old, &new, 512), None);
+
+    new[700] = 1;
+    new[1100] = 1;
+    assert_eq!(changed_span(&old, &new, 512), Some((512, 1536)));
+  }
+}
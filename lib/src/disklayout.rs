@@ -0,0 +1,298 @@
+//! Reading the partition table inside a whole-disk image and comparing it to the device's layout,
+//! so an image made for another layout is caught before it is written rather than at boot.
+
+use std::{
+  borrow::Cow,
+  io::{self, Read},
+};
+
+use crate::{PART_SECTOR_SIZE, PartitionInfo, PartitionTable};
+
+/// Bytes at the start of a disk holding an MBR and a GPT with the usual 128 entries
+const GPT_HEAD: usize = 34 * PART_SECTOR_SIZE;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// MBR partition type of the protective entry covering a GPT disk
+const MBR_PROTECTIVE: u8 = 0xee;
+/// Magic of the Amlogic partition table U-Boot keeps at the start of the `reserved` partition
+const MPT_MAGIC: &[u8] = b"MPT\0";
+/// Bytes of an MPT with its largest number of partitions
+const MPT_LEN: usize = 24 + MPT_MAX_PARTS * MPT_ENTRY_LEN;
+const MPT_MAX_PARTS: usize = 32;
+const MPT_ENTRY_LEN: usize = 40;
+/// Size an MPT gives the last partition to have it fill the rest of the disk
+const MPT_SIZE_REST: u64 = u64::MAX;
+
+/// A partition table found in a disk image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DiskLayout {
+  /// Kind of table: `GPT`, `MPT` (Amlogic) or `MBR`
+  pub kind: &'static str,
+  /// Partitions in the table; an MBR has no names, so they are called `partition <n>`
+  pub partitions: Vec<PartitionInfo>,
+}
+
+/// Read the partition table at the start of a disk image, if it has one
+///
+/// A GPT is preferred over an Amlogic MPT, which is looked for at the start of `expected`'s
+/// `reserved` partition, and an MPT over a plain MBR. `reader` is consumed up to the table found.
+pub(crate) fn read_layout(reader: &mut dyn Read, expected: &PartitionTable) -> io::Result<Option<DiskLayout>> {
+  let mut head = Vec::with_capacity(GPT_HEAD);
+  reader.take(GPT_HEAD as u64).read_to_end(&mut head)?;
+  if let Some(layout) = gpt(&head) {
+    return Ok(Some(layout));
+  }
+
+  if let Some(reserved) = expected.get("reserved")
+    && let Some(skip) = reserved.offset_bytes().checked_sub(head.len())
+  {
+    io::copy(&mut reader.take(skip as u64), &mut io::sink())?;
+    let mut table = Vec::with_capacity(MPT_LEN);
+    reader.take(MPT_LEN as u64).read_to_end(&mut table)?;
+    if let Some(layout) = mpt(&table) {
+      return Ok(Some(layout));
+    }
+  }
+
+  Ok(mbr(&head))
+}
+
+/// How `layout` differs from `expected`, one line per difference; empty when they match
+///
+/// Partitions are matched by name, or for an MBR by where they start. Sizes are compared too,
+/// except for an MPT's last partition when it is set to fill the rest of the disk.
+pub(crate) fn differences(layout: &DiskLayout, expected: &PartitionTable) -> Vec<String> {
+  let mut differences = Vec::new();
+  let size_matches = |found: &PartitionInfo, part: &PartitionInfo| {
+    found.size == part.size || Some(found.size) == part.size_alt || found.size_alt == Some(usize::MAX)
+  };
+  let check_size = |differences: &mut Vec<String>, found: &PartitionInfo, part: &PartitionInfo| {
+    if !size_matches(found, part) {
+      differences.push(format!(
+        "{} is {:#x} sectors instead of {:#x}",
+        part.name, found.size, part.size
+      ));
+    }
+  };
+
+  if layout.kind == "MBR" {
+    for found in &layout.partitions {
+      match expected.iter().find(|part| part.offset == found.offset) {
+        Some(part) => check_size(&mut differences, found, part),
+        None => differences.push(format!(
+          "{} at sector {:#x} does not start any partition",
+          found.name, found.offset
+        )),
+      }
+    }
+    return differences;
+  }
+
+  for part in expected.iter() {
+    let Some(found) = layout.partitions.iter().find(|found| found.name == part.name) else {
+      differences.push(format!("missing {} partition", part.name));
+      continue;
+    };
+    if found.offset != part.offset {
+      differences.push(format!(
+        "{} starts at sector {:#x} instead of {:#x}",
+        part.name, found.offset, part.offset
+      ));
+    }
+    check_size(&mut differences, found, part);
+  }
+  for found in &layout.partitions {
+    if expected.get(&found.name).is_none() {
+      differences.push(format!("unexpected {} partition", found.name));
+    }
+  }
+  differences
+}
+
+fn gpt(head: &[u8]) -> Option<DiskLayout> {
+  let header = head.get(PART_SECTOR_SIZE..2 * PART_SECTOR_SIZE)?;
+  if !header.starts_with(GPT_SIGNATURE) {
+    return None;
+  }
+  let entries_at = (le64(header, 72)? as usize).checked_mul(PART_SECTOR_SIZE)?;
+  let count = le32(header, 80)? as usize;
+  let entry_len = le32(header, 84)? as usize;
+  if entry_len < 128 {
+    return None;
+  }
+
+  let partitions = (0..count)
+    .map_while(|index| head.get(entries_at + index * entry_len..)?.get(..128))
+    .filter(|entry| entry[..16].iter().any(|byte| *byte != 0))
+    .filter_map(|entry| {
+      let (first, last) = (le64(entry, 32)? as usize, le64(entry, 40)? as usize);
+      let name = entry[56..128]
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|unit| *unit != 0)
+        .collect::<Vec<_>>();
+      Some(PartitionInfo {
+        name: Cow::Owned(String::from_utf16_lossy(&name)),
+        offset: first,
+        size: (last + 1).checked_sub(first)?,
+        size_alt: None,
+      })
+    })
+    .collect();
+  Some(DiskLayout {
+    kind: "GPT",
+    partitions,
+  })
+}
+
+fn mpt(table: &[u8]) -> Option<DiskLayout> {
+  if !table.starts_with(MPT_MAGIC) {
+    return None;
+  }
+  let count = (le32(table, 16)? as usize).min(MPT_MAX_PARTS);
+
+  let partitions = table
+    .get(24..)?
+    .chunks_exact(MPT_ENTRY_LEN)
+    .take(count)
+    .map(|entry| {
+      let name = entry[..16].split(|byte| *byte == 0).next().unwrap_or_default();
+      let size = le64(entry, 16).unwrap_or_default();
+      PartitionInfo {
+        name: Cow::Owned(String::from_utf8_lossy(name).into_owned()),
+        offset: (le64(entry, 24).unwrap_or_default() as usize) / PART_SECTOR_SIZE,
+        size: (size as usize) / PART_SECTOR_SIZE,
+        size_alt: (size == MPT_SIZE_REST).then_some(usize::MAX),
+      }
+    })
+    .collect();
+  Some(DiskLayout {
+    kind: "MPT",
+    partitions,
+  })
+}
+
+fn mbr(head: &[u8]) -> Option<DiskLayout> {
+  if head.get(510..512) != Some(&[0x55, 0xaa]) {
+    return None;
+  }
+
+  let partitions = head[446..510]
+    .chunks_exact(16)
+    .enumerate()
+    .filter(|(_, entry)| entry[4] != 0 && entry[4] != MBR_PROTECTIVE && le32(entry, 12) != Some(0))
+    .map(|(index, entry)| PartitionInfo {
+      name: Cow::Owned(format!("partition {}", index + 1)),
+      offset: le32(entry, 8).unwrap_or_default() as usize,
+      size: le32(entry, 12).unwrap_or_default() as usize,
+      size_alt: None,
+    })
+    .collect::<Vec<_>>();
+  (!partitions.is_empty()).then_some(DiskLayout {
+    kind: "MBR",
+    partitions,
+  })
+}
+
+fn le32(bytes: &[u8], at: usize) -> Option<u32> {
+  Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn le64(bytes: &[u8], at: usize) -> Option<u64> {
+  Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Start of a disk with a GPT holding `partitions`, as `(name, first sector, sectors)`
+  fn gpt_disk(partitions: &[(&str, u64, u64)]) -> Vec<u8> {
+    let mut disk = vec![0; GPT_HEAD];
+    let header = &mut disk[PART_SECTOR_SIZE..];
+    header[..8].copy_from_slice(GPT_SIGNATURE);
+    header[72..80].copy_from_slice(&2u64.to_le_bytes());
+    header[80..84].copy_from_slice(&128u32.to_le_bytes());
+    header[84..88].copy_from_slice(&128u32.to_le_bytes());
+    for (index, (name, first, sectors)) in partitions.iter().enumerate() {
+      let entry = &mut disk[2 * PART_SECTOR_SIZE + index * 128..][..128];
+      entry[..16].fill(0xaa);
+      entry[32..40].copy_from_slice(&first.to_le_bytes());
+      entry[40..48].copy_from_slice(&(first + sectors - 1).to_le_bytes());
+      for (unit, at) in name.encode_utf16().zip((56..).step_by(2)) {
+        entry[at..at + 2].copy_from_slice(&unit.to_le_bytes());
+      }
+    }
+    disk
+  }
+
+  #[test]
+  fn test_gpt_layout() {
+    let expected = PartitionTable::superbird();
+    let parts = expected
+      .iter()
+      .map(|part| (part.name.as_ref(), part.offset as u64, part.size.max(1) as u64))
+      .collect::<Vec<_>>();
+
+    let disk = gpt_disk(&parts);
+    let layout = read_layout(&mut disk.as_slice(), &expected).unwrap().unwrap();
+    assert_eq!((layout.kind, layout.partitions.len()), ("GPT", expected.len()));
+    assert_eq!(differences(&layout, &expected), ["cache is 0x1 sectors instead of 0x0"]);
+
+    let mut shifted = parts.clone();
+    shifted.retain(|(name, ..)| *name != "env");
+    shifted.iter_mut().find(|(name, ..)| *name == "logo").unwrap().1 += 8;
+    let layout = read_layout(&mut gpt_disk(&shifted).as_slice(), &expected)
+      .unwrap()
+      .unwrap();
+    let found = differences(&layout, &expected);
+    assert!(found.contains(&"missing env partition".to_owned()), "{found:?}");
+    assert!(found.contains(&"logo starts at sector 0x4e008 instead of 0x4e000".to_owned()));
+  }
+
+  #[test]
+  fn test_mpt_and_mbr_layout() {
+    let expected = PartitionTable::new(vec![
+      PartitionInfo {
+        name: Cow::Borrowed("reserved"),
+        offset: 64,
+        size: 64,
+        size_alt: None,
+      },
+      PartitionInfo {
+        name: Cow::Borrowed("data"),
+        offset: 128,
+        size: 1024,
+        size_alt: None,
+      },
+    ]);
+    let mut disk = vec![0; 64 * PART_SECTOR_SIZE + MPT_LEN];
+    let table = &mut disk[64 * PART_SECTOR_SIZE..];
+    table[..4].copy_from_slice(MPT_MAGIC);
+    table[16..20].copy_from_slice(&2u32.to_le_bytes());
+    for (index, (name, offset, size)) in [("reserved", 64u64, 64u64), ("data", 128, MPT_SIZE_REST)]
+      .into_iter()
+      .enumerate()
+    {
+      let entry = &mut table[24 + index * MPT_ENTRY_LEN..][..MPT_ENTRY_LEN];
+      entry[..name.len()].copy_from_slice(name.as_bytes());
+      entry[16..24].copy_from_slice(&size.saturating_mul(512).to_le_bytes());
+      entry[24..32].copy_from_slice(&(offset * 512).to_le_bytes());
+    }
+    let layout = read_layout(&mut disk.as_slice(), &expected).unwrap().unwrap();
+    assert_eq!(layout.kind, "MPT");
+    assert!(differences(&layout, &expected).is_empty());
+
+    let mut disk = vec![0; GPT_HEAD];
+    disk[446 + 4] = 0x83;
+    disk[446 + 8..446 + 12].copy_from_slice(&100u32.to_le_bytes());
+    disk[446 + 12..446 + 16].copy_from_slice(&64u32.to_le_bytes());
+    disk[510..512].copy_from_slice(&[0x55, 0xaa]);
+    let layout = read_layout(&mut disk.as_slice(), &expected).unwrap().unwrap();
+    assert_eq!(
+      differences(&layout, &expected),
+      ["partition 1 at sector 0x64 does not start any partition"]
+    );
+
+    assert_eq!(read_layout(&mut [0u8; 4096].as_slice(), &expected).unwrap(), None);
+  }
+}
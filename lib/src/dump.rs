@@ -0,0 +1,689 @@
+//! Dumping partitions or the whole disk to files, with a manifest to check them against on restore.
+
+use std::{
+  fs::{self, File, OpenOptions},
+  io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+  ops::Range,
+  path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+  AmlogicSoC, DeviceInfo, Error, PART_SECTOR_SIZE, Result, aml::to_hex, flash::FlashProgress, report::unix_now,
+};
+
+/// Name of the manifest written next to a dump
+pub const DUMP_MANIFEST: &str = "manifest.json";
+/// File the whole user area is dumped to
+const DISK_FILE: &str = "disk.img";
+/// Sidecar tracking how far a dump has got, removed once it finishes
+const CHECKPOINT_FILE: &str = "dump.checkpoint.json";
+
+/// What to dump
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DumpTarget {
+  /// The named partitions, each to its own file
+  Partitions(Vec<String>),
+  /// The whole user area, from the first sector to the end of `data`, as a single file
+  Disk,
+}
+
+impl DumpTarget {
+  /// Every partition that can be read without allowSpecialPartitions, skipping `reserved` and the
+  /// empty `cache`
+  pub fn all_partitions(aml: &AmlogicSoC) -> Self {
+    Self::Partitions(
+      aml
+        .profile()
+        .partitions
+        .iter()
+        .filter(|part| part.name != "reserved" && part.size > 0)
+        .map(|part| part.name.to_string())
+        .collect(),
+    )
+  }
+}
+
+/// How a dump is made
+#[derive(Debug, Clone, Default)]
+pub struct DumpOptions {
+  /// Carry on from the checkpoint an interrupted dump left in the directory, if there is one
+  pub resume: bool,
+  /// Split files larger than this many bytes into `<file>.001`, `<file>.002`, … parts, e.g. to fit
+  /// FAT32's 4 GiB file size limit
+  pub split_size: Option<u64>,
+}
+
+/// Progress of a dump, saved next to it until it finishes
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DumpCheckpoint {
+  /// Manifest of the files finished so far
+  manifest: DumpManifest,
+  /// Part size the dump was started with
+  split_size: Option<u64>,
+  /// File being written when the checkpoint was saved
+  current: Option<PartialFile>,
+}
+
+/// A file that was part way through being dumped
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct PartialFile {
+  /// Path relative to the dump directory
+  file: String,
+  /// Byte range of the file already written and flushed
+  completed: Range<usize>,
+}
+
+impl DumpCheckpoint {
+  fn load(dir: &Path) -> Result<Option<Self>> {
+    let path = dir.join(CHECKPOINT_FILE);
+    if !path.is_file() {
+      return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&fs::read_to_string(path)?)?))
+  }
+
+  /// Write the checkpoint, replacing the previous one in one step so an interruption can't leave it half written
+  ///
+  /// The new checkpoint is synced before it replaces the old one, so after a power loss the
+  /// checkpoint on disk is one of the two rather than an empty file.
+  fn save(&self, dir: &Path) -> Result<()> {
+    let partial = dir.join(format!("{CHECKPOINT_FILE}.tmp"));
+    let mut file = File::create(&partial)?;
+    file.write_all(serde_json::to_string(self)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(partial, dir.join(CHECKPOINT_FILE))?;
+    Ok(())
+  }
+}
+
+/// Record of a dump, saved as `manifest.json` in the dump directory
+///
+/// Partition files are named the way `--stock` restores expect (`<name>.dump`, or `<name>.ext2`
+/// for the system slots), so a dump of the stock partitions can be flashed back as is.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DumpManifest {
+  /// Version of flashthing that made the dump
+  pub tool_version: String,
+  /// Unix timestamp (seconds) the dump was started at
+  pub created_at: u64,
+  /// Name of the device profile used
+  pub profile: String,
+  /// USB details of the device
+  pub device: DeviceInfo,
+  /// Response to the identify request
+  pub identify: String,
+  /// Dumped files in the order they were read
+  pub files: Vec<DumpedFile>,
+}
+
+/// One file of a dump
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DumpedFile {
+  /// Path relative to the dump directory
+  pub file: String,
+  /// Partition the file holds, `None` for a whole-disk dump
+  pub partition: Option<String>,
+  /// Offset from the start of the user area in sectors
+  pub offset: usize,
+  /// Size in bytes
+  pub size: usize,
+  /// Lowercase hex SHA-256 of the file
+  pub sha256: String,
+  /// Parts the file was split into, in order, `None` if it was written whole
+  pub parts: Option<Vec<String>>,
+}
+
+impl DumpManifest {
+  /// Dump partitions or the whole disk to `dir` and write `manifest.json` next to them
+  ///
+  /// Each file is hashed as it is written, so nothing larger than one transfer is held in memory.
+  /// Progress is checkpointed to a sidecar after every transfer, so with [`DumpOptions::resume`]
+  /// an interrupted dump carries on from the last completed transfer instead of starting over.
+  ///
+  /// # Parameters
+  /// - `aml`: The connected device
+  /// - `dir`: Directory to write the dump to, created if missing
+  /// - `target`: What to dump
+  /// - `options`: How to dump it
+  /// - `progress_callback`: Function to call with the file being written and its progress
+  ///
+  /// # Returns
+  /// - `Result<Self>`: The saved manifest or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn dump<F: Fn(&str, FlashProgress)>(
+    aml: &AmlogicSoC,
+    dir: &Path,
+    target: &DumpTarget,
+    options: &DumpOptions,
+    progress_callback: F,
+  ) -> Result<Self> {
+    if options.split_size == Some(0) {
+      return Err(Error::InvalidOperation("split size must be larger than zero".into()));
+    }
+    fs::create_dir_all(dir)?;
+    let manifest = Self {
+      tool_version: env!("CARGO_PKG_VERSION").into(),
+      created_at: unix_now(),
+      profile: aml.profile().name.to_string(),
+      device: aml.device_info().clone(),
+      identify: aml.identify()?,
+      files: Vec::new(),
+    };
+
+    let mut checkpoint = match DumpCheckpoint::load(dir)? {
+      Some(checkpoint) if options.resume => {
+        if checkpoint.manifest.identify != manifest.identify
+          || checkpoint.manifest.device.serial != manifest.device.serial
+        {
+          return Err(Error::InvalidOperation(format!(
+            "the interrupted dump in {} was taken from a different device",
+            dir.display()
+          )));
+        }
+        if checkpoint.split_size != options.split_size {
+          return Err(Error::InvalidOperation(format!(
+            "the interrupted dump in {} was split into parts of {:?} bytes, not {:?}",
+            dir.display(),
+            checkpoint.split_size,
+            options.split_size
+          )));
+        }
+        tracing::info!(
+          "resuming dump in {}, {} file(s) already done",
+          dir.display(),
+          checkpoint.manifest.files.len()
+        );
+        checkpoint
+      }
+      _ => {
+        if options.resume {
+          tracing::warn!("no interrupted dump in {}, starting over", dir.display());
+        }
+        DumpCheckpoint {
+          manifest,
+          split_size: options.split_size,
+          current: None,
+        }
+      }
+    };
+
+    for planned in plan(aml, target)? {
+      if checkpoint.manifest.files.iter().any(|done| done.file == planned.file) {
+        tracing::info!("{} was already dumped, skipping", planned.file);
+        continue;
+      }
+
+      let (sha256, parts) = dump_file(aml, dir, &planned, &mut checkpoint, &progress_callback)?;
+      checkpoint.manifest.files.push(DumpedFile {
+        file: planned.file,
+        partition: planned.partition,
+        offset: planned.offset,
+        size: planned.size,
+        sha256,
+        parts,
+      });
+      checkpoint.current = None;
+      checkpoint.save(dir)?;
+    }
+
+    checkpoint.manifest.save(dir)?;
+    if dir.join(CHECKPOINT_FILE).is_file() {
+      fs::remove_file(dir.join(CHECKPOINT_FILE))?;
+    }
+    Ok(checkpoint.manifest)
+  }
+
+  /// Load `manifest.json` from a dump directory
+  pub fn load(dir: &Path) -> Result<Self> {
+    let path = dir.join(DUMP_MANIFEST);
+    if !path.is_file() {
+      return Err(Error::FileMissing(path));
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+  }
+
+  /// Write the manifest to `manifest.json` in `dir`
+  pub fn save(&self, dir: &Path) -> Result<()> {
+    fs::write(dir.join(DUMP_MANIFEST), serde_json::to_string_pretty(self)?)?;
+    Ok(())
+  }
+
+  /// Check that every file in the manifest is present in `dir` with the recorded size and SHA-256
+  ///
+  /// Files that were split are joined back together and checked as a whole.
+  ///
+  /// # Returns
+  /// - `Result<()>`: `Ok` if the dump is intact, or [`Error::ManifestMismatch`] naming the first bad file
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn verify_files(&self, dir: &Path) -> Result<()> {
+    for entry in &self.files {
+      let (size, mut reader) = open_joined(&dir.join(&entry.file))?;
+      if size as usize != entry.size {
+        return Err(Error::ManifestMismatch(format!(
+          "{} is {} bytes, expected {}",
+          entry.file, size, entry.size
+        )));
+      }
+
+      tracing::info!("checking {} against the dump manifest", entry.file);
+      let mut hasher = Sha256::new();
+      io::copy(&mut reader, &mut DigestWriter(&mut hasher))?;
+      let sha256 = to_hex(&hasher.finalize());
+      if sha256 != entry.sha256.to_lowercase() {
+        return Err(Error::ManifestMismatch(format!(
+          "{} has sha256 {}, expected {}",
+          entry.file, sha256, entry.sha256
+        )));
+      }
+    }
+    Ok(())
+  }
+}
+
+/// A file the dump is going to write
+struct PlannedFile {
+  file: String,
+  partition: Option<String>,
+  offset: usize,
+  size: usize,
+}
+
+/// Work out the files a dump writes, validating each partition's size up front
+fn plan(aml: &AmlogicSoC, target: &DumpTarget) -> Result<Vec<PlannedFile>> {
+  match target {
+    DumpTarget::Partitions(names) => names
+      .iter()
+      .map(|name| {
+        let part_info = aml.profile().partitions.resolve(name)?;
+        Ok(PlannedFile {
+          file: dump_file_name(&part_info.name),
+          partition: Some(part_info.name.to_string()),
+          offset: part_info.offset,
+          size: aml.validate_partition_size(&part_info.name, part_info, false)?,
+        })
+      })
+      .collect(),
+    DumpTarget::Disk => {
+      let data = aml.profile().partitions.resolve("data")?;
+      Ok(vec![PlannedFile {
+        file: DISK_FILE.into(),
+        partition: None,
+        offset: 0,
+        size: data.offset_bytes() + aml.validate_partition_size(&data.name, data, false)?,
+      }])
+    }
+  }
+}
+
+/// Dump one file, picking up after the checkpointed range if it belongs to this file
+///
+/// # Returns
+/// - `Result<(String, Option<Vec<String>>)>`: The SHA-256 of the whole file and the parts it was split into
+fn dump_file<F: Fn(&str, FlashProgress)>(
+  aml: &AmlogicSoC,
+  dir: &Path,
+  planned: &PlannedFile,
+  checkpoint: &mut DumpCheckpoint,
+  progress_callback: &F,
+) -> Result<(String, Option<Vec<String>>)> {
+  let path = dir.join(&planned.file);
+  if planned.partition.is_none() {
+    aml.bulkcmd("mmc dev 1 0")?;
+  }
+  let read_chunk = |offset: usize, length: usize| match &planned.partition {
+    Some(name) => aml.read_partition_chunk(name, offset, length),
+    None => aml.read_disk_chunk(planned.offset + offset / PART_SECTOR_SIZE, length),
+  };
+
+  let mut start = match checkpoint.current.take() {
+    Some(partial) if partial.file == planned.file && partial.completed.start == 0 => partial.completed.end,
+    _ => 0,
+  };
+  let mut hasher = Sha256::new();
+  if start > 0 {
+    let tail_len = aml.profile().max_transfer_size.min(start);
+    match read_prefix(&path, start, tail_len, &mut hasher)? {
+      Some(tail) if read_chunk(start - tail_len, tail_len)? == tail => {
+        tracing::info!("resuming {} at {:#x} of {:#x}", planned.file, start, planned.size);
+      }
+      _ => {
+        tracing::warn!("{} no longer matches the device, dumping it again", planned.file);
+        start = 0;
+        hasher = Sha256::new();
+      }
+    }
+  }
+  if start == 0 {
+    tracing::info!("dumping {} bytes to {}", planned.size, planned.file);
+  }
+
+  let mut writer = PartWriter::open(dir, &planned.file, checkpoint.split_size, start as u64)?;
+  checkpoint.current = Some(PartialFile {
+    file: planned.file.clone(),
+    completed: 0..start,
+  });
+  aml.stream_chunked(
+    start,
+    planned.size,
+    read_chunk,
+    |chunk| {
+      hasher.update(chunk);
+      writer.write_all(chunk)?;
+      // the data has to be on disk before the checkpoint claims it is
+      writer.sync()?;
+      if let Some(current) = &mut checkpoint.current {
+        current.completed.end += chunk.len();
+      }
+      checkpoint.save(dir)
+    },
+    |progress| progress_callback(&planned.file, progress),
+  )?;
+  Ok((to_hex(&hasher.finalize()), writer.parts()))
+}
+
+/// Hash the first `end` bytes of a partly written dump file, returning the last `tail_len` of them
+///
+/// Returns `None` if the file is missing or shorter than `end`.
+fn read_prefix<D: Digest>(path: &Path, end: usize, tail_len: usize, hasher: &mut D) -> Result<Option<Vec<u8>>> {
+  let (size, mut reader) = match open_joined(path) {
+    Ok(opened) => opened,
+    Err(Error::FileMissing(_)) => return Ok(None),
+    Err(e) => return Err(e),
+  };
+  if (size as usize) < end {
+    return Ok(None);
+  }
+
+  io::copy(
+    &mut reader.by_ref().take((end - tail_len) as u64),
+    &mut DigestWriter(hasher),
+  )?;
+  let mut tail = vec![0; tail_len];
+  reader.read_exact(&mut tail)?;
+  hasher.update(&tail);
+  Ok(Some(tail))
+}
+
+/// Path of part `index` (counting from 1) of a split file
+fn part_path(path: &Path, index: usize) -> PathBuf {
+  let mut name = path.as_os_str().to_owned();
+  name.push(format!(".{index:03}"));
+  PathBuf::from(name)
+}
+
+/// Paths of the `.001`, `.002`, … parts of a split file, empty if it wasn't split
+fn split_parts(path: &Path) -> Vec<PathBuf> {
+  (1..)
+    .map(|index| part_path(path, index))
+    .take_while(|part| part.is_file())
+    .collect()
+}
+
+/// Open a file written by a dump, joining its parts back together if it was split
+///
+/// # Returns
+/// - `Result<(u64, Box<dyn Read>)>`: The total size and a reader over the whole contents
+pub(crate) fn open_joined(path: &Path) -> Result<(u64, Box<dyn Read>)> {
+  if path.is_file() {
+    let file = File::open(path)?;
+    return Ok((file.metadata()?.len(), Box::new(BufReader::new(file))));
+  }
+
+  let parts = split_parts(path);
+  if parts.is_empty() {
+    return Err(Error::FileMissing(path.to_owned()));
+  }
+  tracing::debug!("joining {} parts of {}", parts.len(), path.display());
+
+  let mut size = 0;
+  let mut reader: Box<dyn Read> = Box::new(io::empty());
+  for part in parts {
+    let file = File::open(part)?;
+    size += file.metadata()?.len();
+    reader = Box::new(reader.chain(BufReader::new(file)));
+  }
+  Ok((size, reader))
+}
+
+/// Writes a dump file, starting a new `.NNN` part whenever the current one reaches the split size
+struct PartWriter<'a> {
+  path: PathBuf,
+  file: &'a str,
+  split_size: Option<u64>,
+  /// Number of the part being written, counting from 1, or 0 when not splitting
+  index: usize,
+  part: BufWriter<File>,
+  part_len: u64,
+}
+
+impl<'a> PartWriter<'a> {
+  /// Open `file` in `dir` for writing from byte `start`, dropping anything past it from an earlier attempt
+  fn open(dir: &Path, file: &'a str, split_size: Option<u64>, start: u64) -> Result<Self> {
+    let path = dir.join(file);
+    let (index, part_len) = match split_size {
+      Some(split) => {
+        let index = start.div_ceil(split).max(1);
+        (index as usize, start - (index - 1) * split)
+      }
+      None => (0, start),
+    };
+
+    if split_size.is_some() && path.is_file() {
+      fs::remove_file(&path)?;
+    }
+    for stale in split_parts(&path).into_iter().skip(index) {
+      fs::remove_file(stale)?;
+    }
+
+    let part_file = if split_size.is_some() {
+      part_path(&path, index)
+    } else {
+      path.clone()
+    };
+    let mut part = OpenOptions::new()
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(part_file)?;
+    part.set_len(part_len)?;
+    part.seek(SeekFrom::End(0))?;
+
+    Ok(Self {
+      path,
+      file,
+      split_size,
+      index,
+      part: BufWriter::new(part),
+      part_len,
+    })
+  }
+
+  /// Flush buffered data and sync it to disk, so it survives a power loss
+  fn sync(&mut self) -> io::Result<()> {
+    self.part.flush()?;
+    self.part.get_ref().sync_data()
+  }
+
+  /// Names of the parts written, relative to the dump directory, or `None` when not splitting
+  fn parts(&self) -> Option<Vec<String>> {
+    self.split_size?;
+    Some(
+      (1..=self.index)
+        .map(|index| format!("{}.{index:03}", self.file))
+        .collect(),
+    )
+  }
+}
+
+impl Write for PartWriter<'_> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let Some(split) = self.split_size else {
+      return self.part.write(buf);
+    };
+
+    if self.part_len == split {
+      // the checkpoint only syncs the part being written, so this one has to be synced now
+      self.sync()?;
+      self.index += 1;
+      self.part = BufWriter::new(File::create(part_path(&self.path, self.index))?);
+      self.part_len = 0;
+    }
+    let length = buf.len().min((split - self.part_len) as usize);
+    let written = self.part.write(&buf[..length])?;
+    self.part_len += written as u64;
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.part.flush()
+  }
+}
+
+/// Adapter to hash whatever is written to it
+struct DigestWriter<'a, D: Digest>(&'a mut D);
+
+impl<D: Digest> Write for DigestWriter<'_, D> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.0.update(buf);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+/// File name a partition is dumped to, matching the stock restore layout
+fn dump_file_name(part_name: &str) -> String {
+  match part_name {
+    "system_a" | "system_b" => format!("{part_name}.ext2"),
+    _ => format!("{part_name}.dump"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_verify_files() {
+    let dir = std::env::temp_dir().join(format!("flashthing-dump-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let data = [[1; 16], [2; 16]].concat();
+    fs::write(dir.join("env.dump"), &data).unwrap();
+    let sha256 = to_hex(&Sha256::digest(&data));
+
+    let mut manifest = DumpManifest {
+      tool_version: env!("CARGO_PKG_VERSION").into(),
+      created_at: 0,
+      profile: "superbird".into(),
+      device: crate::fixtures::device_info(None),
+      identify: String::new(),
+      files: vec![DumpedFile {
+        file: "env.dump".into(),
+        partition: Some("env".into()),
+        offset: 237568,
+        size: 32,
+        sha256,
+        parts: None,
+      }],
+    };
+    manifest.save(&dir).unwrap();
+    let loaded = DumpManifest::load(&dir).unwrap();
+    assert_eq!(loaded.files, manifest.files);
+    loaded.verify_files(&dir).unwrap();
+
+    manifest.files[0].sha256 = "00".into();
+    assert!(matches!(manifest.verify_files(&dir), Err(Error::ManifestMismatch(_))));
+    manifest.files[0].size = 16;
+    assert!(matches!(manifest.verify_files(&dir), Err(Error::ManifestMismatch(_))));
+    let _ = fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn test_split() {
+    let dir = std::env::temp_dir().join(format!("flashthing-split-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("disk.img"), [0; 4]).unwrap();
+    let data: Vec<u8> = (0..25).collect();
+
+    let mut writer = PartWriter::open(&dir, "disk.img", Some(10), 0).unwrap();
+    writer.write_all(&data[..12]).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+
+    // resuming part way through the second part, after a third part was left behind
+    fs::write(dir.join("disk.img.003"), [0; 4]).unwrap();
+    let mut writer = PartWriter::open(&dir, "disk.img", Some(10), 11).unwrap();
+    writer.write_all(&data[11..]).unwrap();
+    writer.flush().unwrap();
+    assert_eq!(
+      writer.parts().unwrap(),
+      ["disk.img.001", "disk.img.002", "disk.img.003"]
+    );
+    drop(writer);
+
+    assert!(!dir.join("disk.img").exists());
+    assert_eq!(fs::read(dir.join("disk.img.003")).unwrap(), &data[20..]);
+    let (size, mut reader) = open_joined(&dir.join("disk.img")).unwrap();
+    let mut joined = Vec::new();
+    reader.read_to_end(&mut joined).unwrap();
+    assert_eq!((size, joined), (25, data));
+
+    // resuming exactly at a part boundary keeps writing the full part until it overflows
+    let writer = PartWriter::open(&dir, "disk.img", Some(10), 20).unwrap();
+    assert_eq!(writer.parts().unwrap(), ["disk.img.001", "disk.img.002"]);
+    assert!(!dir.join("disk.img.003").exists());
+    let _ = fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn test_resume() {
+    let dir = std::env::temp_dir().join(format!("flashthing-resume-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let device: Vec<u8> = (0..64).collect();
+
+    // 32 bytes were flushed before the interruption, plus some that never made it into the checkpoint
+    let path = dir.join("logo.dump");
+    fs::write(&path, &device[..40]).unwrap();
+    let mut hasher = Sha256::new();
+    let tail = read_prefix(&path, 32, 16, &mut hasher).unwrap().unwrap();
+    assert_eq!(tail, &device[16..32]);
+    hasher.update(&device[32..]);
+    assert_eq!(hasher.finalize(), Sha256::digest(&device));
+    assert!(read_prefix(&path, 48, 16, &mut Sha256::new()).unwrap().is_none());
+    assert!(
+      read_prefix(&dir.join("boot_a.dump"), 32, 16, &mut Sha256::new())
+        .unwrap()
+        .is_none()
+    );
+
+    let checkpoint = DumpCheckpoint {
+      manifest: DumpManifest {
+        tool_version: env!("CARGO_PKG_VERSION").into(),
+        created_at: 0,
+        profile: "superbird".into(),
+        device: crate::fixtures::device_info(None),
+        identify: String::new(),
+        files: Vec::new(),
+      },
+      split_size: None,
+      current: Some(PartialFile {
+        file: "logo.dump".into(),
+        completed: 0..32,
+      }),
+    };
+    checkpoint.save(&dir).unwrap();
+    let loaded = DumpCheckpoint::load(&dir).unwrap().unwrap();
+    assert_eq!(loaded.current, checkpoint.current);
+    let _ = fs::remove_dir_all(&dir);
+  }
+}
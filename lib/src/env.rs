@@ -0,0 +1,281 @@
+//! The U-Boot environment, as the `name=value` text `env import -t` reads and as the image saved to
+//! the `env` partition, so a malformed or oversized env fails before it is sent instead of deep in
+//! `env import`, and env dumps can be read and changed offline.
+
+use std::collections::HashMap;
+
+use crate::{Error, Result};
+
+/// Bytes of the CRC-32 that starts a saved environment
+const ENV_CRC_LEN: usize = 4;
+
+/// U-Boot environment: variables and their values, in the order they were set
+///
+/// Converts between the `name=value` lines `env import -t` reads and the image U-Boot saves to the
+/// `env` partition, a little endian CRC-32 of the rest followed by each `name=value` ended by a zero,
+/// a zero ending the list, and zeros filling it out to the environment's size.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UBootEnv {
+  vars: Vec<(String, String)>,
+}
+
+impl UBootEnv {
+  /// An environment with no variables
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Parse `name=value` lines, as `env import -t` reads them
+  ///
+  /// Blank lines and lines starting with `#` are skipped.
+  ///
+  /// # Returns
+  /// - `Result<Self>`: The environment, or an error naming the line for text that is not ascii, a
+  ///   line without `=` or with an empty or spaced name, or a name set twice
+  pub fn from_text(text: &str) -> Result<Self> {
+    Self::parse_text("env", text)
+  }
+
+  /// Parse `name=value` lines like [`from_text`](Self::from_text), naming the file they came from in errors
+  pub(crate) fn parse_text(name: &str, text: &str) -> Result<Self> {
+    let invalid = |number: usize, problem: String| Error::InvalidOperation(format!("{}:{}: {}", name, number, problem));
+    let mut env = Self::new();
+    let mut lines = HashMap::new();
+    for (index, line) in text.lines().enumerate() {
+      let number = index + 1;
+      if !line.is_ascii() {
+        return Err(invalid(number, "env data must be ascii".to_owned()));
+      }
+      if line.trim().is_empty() || line.starts_with('#') {
+        continue;
+      }
+      let Some((key, value)) = line.split_once('=') else {
+        return Err(invalid(number, format!("`{}` is not a name=value line", line)));
+      };
+      if key.is_empty() || key.contains(char::is_whitespace) {
+        return Err(invalid(number, format!("`{}` is not a valid variable name", key)));
+      }
+      if let Some(first) = lines.insert(key.to_owned(), number) {
+        return Err(invalid(number, format!("`{}` is already set on line {}", key, first)));
+      }
+      env.vars.push((key.to_owned(), value.to_owned()));
+    }
+    Ok(env)
+  }
+
+  /// The variables as `name=value` lines, ready for `env import -t`
+  pub fn to_text(&self) -> String {
+    self
+      .vars
+      .iter()
+      .map(|(key, value)| format!("{}={}\n", key, value))
+      .collect()
+  }
+
+  /// Parse an environment image, such as the start of an `env` partition dump cut to the size of
+  /// the environment
+  ///
+  /// # Returns
+  /// - `Result<Self>`: The environment, or an error if the image is too short or its CRC does not
+  ///   match
+  pub fn from_image(image: &[u8]) -> Result<Self> {
+    let (Some(crc), Some(data)) = (image.get(..ENV_CRC_LEN), image.get(ENV_CRC_LEN..)) else {
+      return Err(Error::InvalidImage(
+        "an env image has to be at least 4 bytes long".into(),
+      ));
+    };
+    let (stored, actual) = (
+      u32::from_le_bytes(crc.try_into().unwrap_or_default()),
+      crc32fast::hash(data),
+    );
+    if stored != actual {
+      return Err(Error::InvalidImage(format!(
+        "env image CRC is {:#010x} but its data has {:#010x}; is it cut to the size of the environment?",
+        stored, actual
+      )));
+    }
+    Ok(Self::from_image_unchecked(image))
+  }
+
+  /// Parse an environment image without checking its CRC, e.g. when the size of the environment
+  /// the CRC covers is not known
+  ///
+  /// Entries that are not `name=value` are skipped, and everything after the list's end is ignored.
+  pub fn from_image_unchecked(image: &[u8]) -> Self {
+    let vars = image
+      .get(ENV_CRC_LEN..)
+      .unwrap_or_default()
+      .split(|&byte| byte == 0)
+      .take_while(|entry| !entry.is_empty())
+      .filter_map(|entry| {
+        let entry = String::from_utf8_lossy(entry);
+        let (key, value) = entry.split_once('=')?;
+        Some((key.to_owned(), value.to_owned()))
+      })
+      .collect();
+    Self { vars }
+  }
+
+  /// The environment as an image of `size` bytes, the size of the environment U-Boot was built with
+  ///
+  /// # Returns
+  /// - `Result<Vec<u8>>`: The image, or an error if the variables do not fit in `size` bytes
+  pub fn to_image(&self, size: usize) -> Result<Vec<u8>> {
+    self.check_size("env", size)?;
+    let mut image = vec![0; ENV_CRC_LEN];
+    for (key, value) in &self.vars {
+      image.extend_from_slice(key.as_bytes());
+      image.push(b'=');
+      image.extend_from_slice(value.as_bytes());
+      image.push(0);
+    }
+    image.resize(size, 0);
+    let crc = crc32fast::hash(&image[ENV_CRC_LEN..]);
+    image[..ENV_CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+    Ok(image)
+  }
+
+  /// Bytes the environment takes in an image, before the zeros filling it out
+  pub fn stored_size(&self) -> usize {
+    ENV_CRC_LEN
+      + self
+        .vars
+        .iter()
+        .map(|(key, value)| key.len() + value.len() + 2)
+        .sum::<usize>()
+      + 1
+  }
+
+  /// Check that the environment fits in `room` bytes, naming `name` in the error
+  pub(crate) fn check_size(&self, name: &str, room: usize) -> Result<()> {
+    let size = self.stored_size();
+    if size > room {
+      return Err(Error::InvalidOperation(format!(
+        "{}: the environment takes {} bytes once saved, but only {} fit",
+        name, size, room
+      )));
+    }
+    Ok(())
+  }
+
+  /// The value of variable `name`, if it is set
+  pub fn get(&self, name: &str) -> Option<&str> {
+    self
+      .vars
+      .iter()
+      .find(|(key, _)| key == name)
+      .map(|(_, value)| value.as_str())
+  }
+
+  /// Set variable `name`, keeping its place if it is already set, and return its old value
+  pub fn set(&mut self, name: impl Into<String>, value: impl Into<String>) -> Option<String> {
+    let (name, value) = (name.into(), value.into());
+    match self.vars.iter_mut().find(|(key, _)| *key == name) {
+      Some((_, old)) => Some(std::mem::replace(old, value)),
+      None => {
+        self.vars.push((name, value));
+        None
+      }
+    }
+  }
+
+  /// Unset variable `name`, returning its value if it was set
+  pub fn remove(&mut self, name: &str) -> Option<String> {
+    let index = self.vars.iter().position(|(key, _)| key == name)?;
+    Some(self.vars.remove(index).1)
+  }
+
+  /// The variables and their values, in order
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+    self.vars.iter().map(|(key, value)| (key.as_str(), value.as_str()))
+  }
+
+  /// Number of variables set
+  pub fn len(&self) -> usize {
+    self.vars.len()
+  }
+
+  /// Whether no variables are set
+  pub fn is_empty(&self) -> bool {
+    self.vars.is_empty()
+  }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for UBootEnv {
+  fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+    let mut env = Self::new();
+    for (key, value) in iter {
+      env.set(key, value);
+    }
+    env
+  }
+}
+
+impl IntoIterator for UBootEnv {
+  type Item = (String, String);
+  type IntoIter = std::vec::IntoIter<(String, String)>;
+
+  fn into_iter(self) -> Self::IntoIter {
+    self.vars.into_iter()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_text() {
+    let env = UBootEnv::parse_text("env.txt", "# boot\nbootdelay=1\n\nbootcmd=run a=b\n").unwrap();
+    assert_eq!(
+      env.iter().collect::<Vec<_>>(),
+      [("bootdelay", "1"), ("bootcmd", "run a=b")]
+    );
+    assert_eq!(env.to_text(), "bootdelay=1\nbootcmd=run a=b\n");
+    assert_eq!(env.stored_size(), 4 + 12 + 16 + 1);
+    assert!(env.check_size("env.txt", 33).is_ok());
+    let err = env.check_size("env.txt", 32).unwrap_err();
+    assert!(err.to_string().contains("takes 33 bytes"), "{err}");
+
+    let err = UBootEnv::parse_text("env.txt", "a=1\nb=2\na=3\n").unwrap_err();
+    assert!(
+      err.to_string().contains("env.txt:3: `a` is already set on line 1"),
+      "{err}"
+    );
+    assert!(UBootEnv::from_text("bootdelay\n").is_err());
+    assert!(UBootEnv::from_text("=1\n").is_err());
+    assert!(UBootEnv::from_text("boot delay=1\n").is_err());
+    assert!(UBootEnv::from_text("name=caf\u{e9}\n").is_err());
+  }
+
+  #[test]
+  fn test_image() {
+    let mut env = UBootEnv::from_iter([("bootdelay", "1"), ("slot", "a")]);
+    assert_eq!(env.set("slot", "b").as_deref(), Some("a"));
+    assert_eq!(env.set("serial", "abc"), None);
+    assert_eq!(env.remove("bootdelay").as_deref(), Some("1"));
+    assert_eq!(env.iter().collect::<Vec<_>>(), [("slot", "b"), ("serial", "abc")]);
+
+    let image = env.to_image(64).unwrap();
+    assert_eq!(image.len(), 64);
+    assert_eq!(&image[4..22], b"slot=b\0serial=abc\0");
+    assert_eq!(UBootEnv::from_image(&image).unwrap(), env);
+    assert!(env.to_image(21).is_err());
+
+    // a dump of the whole partition has more after the environment than its CRC covers
+    let mut dump = image.clone();
+    dump.resize(128, 0xff);
+    assert!(UBootEnv::from_image(&dump).is_err());
+    assert_eq!(UBootEnv::from_image_unchecked(&dump), env);
+
+    let mut corrupt = image;
+    corrupt[10] ^= 1;
+    assert!(UBootEnv::from_image(&corrupt).is_err());
+    assert!(UBootEnv::from_image(&[0; 3]).is_err());
+
+    let mut data = vec![0xde, 0xad, 0xbe, 0xef];
+    data.extend_from_slice(b"bootdelay=1\0slot=a\0novalue\0\0garbage=1\0");
+    let env = UBootEnv::from_image_unchecked(&data);
+    assert_eq!(env.iter().collect::<Vec<_>>(), [("bootdelay", "1"), ("slot", "a")]);
+  }
+}
@@ -0,0 +1,272 @@
+//! Rate limiting and delivery of events to the caller's callback, so frequent progress updates
+//! cannot swamp a GUI event loop or a N-API threadsafe function.
+
+use std::{
+  collections::VecDeque,
+  sync::{Arc, Condvar, Mutex, MutexGuard},
+  time::{Duration, Instant},
+};
+
+use crate::{Callback, Event};
+
+/// Default minimum time between two [`Event::FlashProgress`] events
+pub const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How events are handed to the callback
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EventDelivery {
+  /// Call the callback on the flashing thread, which waits for it to return
+  #[default]
+  Blocking,
+  /// Queue events for a separate thread, so a slow callback never holds up flashing
+  ///
+  /// Progress the callback has not caught up with is replaced by the newest update. Other events
+  /// are never dropped and arrive in order.
+  LatestWins,
+}
+
+/// Drops progress events that arrive less than `interval` after the last one delivered
+struct Throttle {
+  interval: Duration,
+  last: Mutex<Option<Instant>>,
+}
+
+impl Throttle {
+  fn admits(&self, event: &Event) -> bool {
+    let Ok(mut last) = self.last.lock() else {
+      return true;
+    };
+    match event {
+      Event::FlashProgress(progress) => {
+        let now = Instant::now();
+        // the final update is always delivered, so the caller never stops short of 100%
+        let due = progress.percent >= 100.0 || last.is_none_or(|at| now.duration_since(at) >= self.interval);
+        if due {
+          *last = Some(now);
+        }
+        due
+      }
+      Event::Step(..) => {
+        // the first update of every step goes through
+        *last = None;
+        true
+      }
+      _ => true,
+    }
+  }
+}
+
+#[derive(Default)]
+struct QueueState {
+  events: VecDeque<Event>,
+  /// Set while the callback is handling an event
+  busy: bool,
+  /// Set once nothing can send events any more
+  closed: bool,
+}
+
+/// Events waiting for the delivery thread of [`EventDelivery::LatestWins`]
+#[derive(Default)]
+pub(crate) struct EventQueue {
+  state: Mutex<QueueState>,
+  changed: Condvar,
+}
+
+impl EventQueue {
+  fn state(&self) -> MutexGuard<'_, QueueState> {
+    self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+  }
+
+  fn push(&self, event: Event) {
+    let mut state = self.state();
+    match (state.events.back_mut(), event) {
+      (Some(queued @ Event::FlashProgress(_)), event @ Event::FlashProgress(_)) => *queued = event,
+      (_, event) => state.events.push_back(event),
+    }
+    self.changed.notify_all();
+  }
+
+  fn close(&self) {
+    self.state().closed = true;
+    self.changed.notify_all();
+  }
+
+  /// Wait until every queued event has been handled
+  pub(crate) fn flush(&self) {
+    let mut state = self.state();
+    while !state.events.is_empty() || state.busy {
+      state = self
+        .changed
+        .wait(state)
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    }
+  }
+
+  fn deliver(&self, callback: Callback) {
+    loop {
+      let event = {
+        let mut state = self.state();
+        loop {
+          if let Some(event) = state.events.pop_front() {
+            state.busy = true;
+            break event;
+          }
+          if state.closed {
+            return;
+          }
+          state = self
+            .changed
+            .wait(state)
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+      };
+
+      callback(event);
+      self.state().busy = false;
+      self.changed.notify_all();
+    }
+  }
+}
+
+/// Closes the queue once the last copy of the callback feeding it is dropped
+struct QueueSender(Arc<EventQueue>);
+
+impl Drop for QueueSender {
+  fn drop(&mut self) {
+    self.0.close();
+  }
+}
+
+/// Wrap `callback` so it gets progress at most once per `interval`, delivered as `delivery` says
+///
+/// # Returns
+/// - `(Callback, Option<Arc<EventQueue>>)`: The wrapped callback, and the queue to flush when
+///   events are delivered on a separate thread
+pub(crate) fn coalesce(
+  callback: Callback,
+  interval: Duration,
+  delivery: EventDelivery,
+) -> (Callback, Option<Arc<EventQueue>>) {
+  let throttle = Throttle {
+    interval,
+    last: Mutex::new(None),
+  };
+
+  match delivery {
+    EventDelivery::Blocking => (
+      Arc::new(move |event| {
+        if throttle.admits(&event) {
+          callback(event);
+        }
+      }),
+      None,
+    ),
+    EventDelivery::LatestWins => {
+      let queue = Arc::new(EventQueue::default());
+      let delivering = queue.clone();
+      std::thread::spawn(move || delivering.deliver(callback));
+
+      let sender = QueueSender(queue.clone());
+      (
+        Arc::new(move |event| {
+          if throttle.admits(&event) {
+            sender.0.push(event);
+          }
+        }),
+        Some(queue),
+      )
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    FlashProgress,
+    config::{FlashStep, Step},
+  };
+
+  fn progress(percent: f64) -> Event {
+    Event::FlashProgress(FlashProgress {
+      percent,
+      elapsed: 0.0,
+      eta: 0.0,
+      rate: 0.0,
+      avg_chunk_time: 0.0,
+      avg_rate: 0.0,
+      step_id: None,
+    })
+  }
+
+  fn percents(events: &[Event]) -> Vec<f64> {
+    events
+      .iter()
+      .filter_map(|event| match event {
+        Event::FlashProgress(progress) => Some(progress.percent),
+        _ => None,
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_progress_throttle() {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let sink = received.clone();
+    let (callback, queue) = coalesce(
+      Arc::new(move |event| sink.lock().unwrap().push(event)),
+      Duration::from_secs(3600),
+      EventDelivery::Blocking,
+    );
+    assert!(queue.is_none());
+
+    for percent in [10.0, 20.0, 30.0, 100.0] {
+      callback(progress(percent));
+    }
+    callback(Event::Step(
+      1,
+      Step {
+        step: FlashStep::Identify { variable: None },
+        id: None,
+        timeout_ms: None,
+        on_error: None,
+      },
+    ));
+    callback(progress(5.0));
+    callback(progress(6.0));
+
+    let received = received.lock().unwrap();
+    assert_eq!(percents(&received), [10.0, 100.0, 5.0]);
+    assert_eq!(received.len(), 4);
+  }
+
+  #[test]
+  fn test_latest_wins() {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let sink = received.clone();
+    let (callback, queue) = coalesce(
+      Arc::new(move |event| {
+        // a callback far slower than the updates it gets
+        std::thread::sleep(Duration::from_millis(20));
+        sink.lock().unwrap().push(event);
+      }),
+      Duration::ZERO,
+      EventDelivery::LatestWins,
+    );
+
+    callback(Event::Connected);
+    for percent in 1..=100 {
+      callback(progress(percent as f64));
+    }
+    callback(Event::Resetting);
+    queue.unwrap().flush();
+
+    let received = received.lock().unwrap();
+    assert!(matches!(received.first(), Some(Event::Connected)));
+    assert!(matches!(received.last(), Some(Event::Resetting)));
+    let percents = percents(&received);
+    assert!(percents.len() < 100, "{percents:?}");
+    assert_eq!(percents.last(), Some(&100.0));
+    assert!(percents.is_sorted());
+  }
+}
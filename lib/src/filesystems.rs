@@ -0,0 +1,143 @@
+//! Sniffing the images a package writes to system partitions, so a tarball or an image too big
+//! for the partition is caught before the device is touched instead of leaving it unbootable.
+
+use crate::{Error, PART_SECTOR_SIZE, PartitionInfo, Result};
+
+/// Bytes of a file needed to find an ext2/3/4 or squashfs superblock
+pub(crate) const SUPERBLOCK_HEAD: usize = 2048;
+
+/// Where the ext2/3/4 superblock starts
+const EXT_SUPERBLOCK: usize = 1024;
+const EXT_MAGIC: u16 = 0xef53;
+const EXT_INCOMPAT_64BIT: u32 = 0x80;
+const SQUASHFS_MAGIC: &[u8] = b"hsqs";
+
+/// Formats that are not filesystems but keep being written to system partitions, and how to spot them
+const NOT_FILESYSTEMS: &[(usize, &[u8], &str)] = &[
+  (257, b"ustar", "a tar archive"),
+  (0, &[0x1f, 0x8b], "a gzip archive"),
+  (0, &[0xfd, b'7', b'z', b'X', b'Z', 0], "an xz archive"),
+  (0, &[0x28, 0xb5, 0x2f, 0xfd], "a zstd archive"),
+  (0, b"BZh", "a bzip2 archive"),
+  (0, b"PK\x03\x04", "a zip archive"),
+  (
+    0,
+    &[0x3a, 0xff, 0x26, 0xed],
+    "an Android sparse image, which must be unsparsed with simg2img first",
+  ),
+];
+
+/// Filesystem found at the start of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Filesystem {
+  Ext { size: u64 },
+  Squashfs { size: u64 },
+}
+
+/// Whether a partition holds a root filesystem, i.e. is `system`, an A/B `system_*` slot or `rootfs`
+pub(crate) fn is_rootfs(partition: &str) -> bool {
+  partition.starts_with("system") || partition == "rootfs"
+}
+
+/// Check that `head`, the start of a file written to the rootfs partition `partition`, is an ext2/3/4
+/// or squashfs image that fits in it
+///
+/// Archives and sparse images are errors, since they are never what a system partition should hold.
+/// Anything else unrecognised only gets a warning back, since some packages ship other filesystems.
+pub(crate) fn check_rootfs(name: &str, head: &[u8], partition: &PartitionInfo) -> Result<Option<String>> {
+  let invalid = |problem: String| Error::InvalidImage(format!("{name}: {problem}"));
+  if let Some((_, _, what)) = NOT_FILESYSTEMS
+    .iter()
+    .find(|(at, magic, _)| head.get(*at..*at + magic.len()) == Some(*magic))
+  {
+    return Err(invalid(format!(
+      "is {what}, not a filesystem image for {}",
+      partition.name
+    )));
+  }
+
+  let Some(filesystem) = sniff(head) else {
+    return Ok(Some(format!(
+      "{name} does not look like an ext4 or squashfs image, but is written to {}",
+      partition.name
+    )));
+  };
+  let (Filesystem::Ext { size } | Filesystem::Squashfs { size }) = filesystem;
+  let room = ((partition.end() - partition.offset) * PART_SECTOR_SIZE) as u64;
+  if size > room {
+    return Err(invalid(format!(
+      "declares a {size} byte filesystem, which does not fit in {} ({room} bytes)",
+      partition.name
+    )));
+  }
+  Ok(None)
+}
+
+/// Find the filesystem `head` starts with and the size its superblock declares
+fn sniff(head: &[u8]) -> Option<Filesystem> {
+  if head.get(..4) == Some(SQUASHFS_MAGIC) {
+    let bytes_used = u64::from_le_bytes(head.get(40..48)?.try_into().ok()?);
+    return Some(Filesystem::Squashfs { size: bytes_used });
+  }
+
+  let superblock = head.get(EXT_SUPERBLOCK..EXT_SUPERBLOCK + 1024)?;
+  if u16::from_le_bytes([superblock[0x38], superblock[0x39]]) != EXT_MAGIC {
+    return None;
+  }
+  let le32 = |at: usize| u32::from_le_bytes(superblock[at..at + 4].try_into().unwrap_or_default()) as u64;
+  let mut blocks = le32(0x04);
+  if le32(0x60) as u32 & EXT_INCOMPAT_64BIT != 0 {
+    blocks |= le32(0x150) << 32;
+  }
+  let block_size = 1024u64.checked_shl(le32(0x18) as u32)?;
+  Some(Filesystem::Ext {
+    size: blocks.saturating_mul(block_size),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use std::borrow::Cow;
+
+  use super::*;
+
+  /// Start of an ext4 image with `blocks` blocks of 4 KiB
+  fn ext4(blocks: u32) -> Vec<u8> {
+    let mut head = vec![0; SUPERBLOCK_HEAD];
+    let superblock = &mut head[EXT_SUPERBLOCK..];
+    superblock[0x04..0x08].copy_from_slice(&blocks.to_le_bytes());
+    superblock[0x18..0x1c].copy_from_slice(&2u32.to_le_bytes());
+    superblock[0x38..0x3a].copy_from_slice(&EXT_MAGIC.to_le_bytes());
+    head
+  }
+
+  #[test]
+  fn test_check_rootfs() {
+    let system = PartitionInfo {
+      name: Cow::Borrowed("system_a"),
+      offset: 0,
+      size: 2048,
+      size_alt: None,
+    };
+    assert!(is_rootfs("system_b") && !is_rootfs("boot_a"));
+
+    assert_eq!(sniff(&ext4(256)), Some(Filesystem::Ext { size: 1024 * 1024 }));
+    assert_eq!(check_rootfs("system.img", &ext4(256), &system).unwrap(), None);
+    let err = check_rootfs("system.img", &ext4(257), &system).unwrap_err();
+    assert!(err.to_string().contains("does not fit in system_a"), "{err}");
+
+    let mut squashfs = vec![0; SUPERBLOCK_HEAD];
+    squashfs[..4].copy_from_slice(SQUASHFS_MAGIC);
+    squashfs[40..48].copy_from_slice(&4096u64.to_le_bytes());
+    assert_eq!(check_rootfs("system.sqsh", &squashfs, &system).unwrap(), None);
+
+    let mut tarball = vec![0; SUPERBLOCK_HEAD];
+    tarball[257..262].copy_from_slice(b"ustar");
+    let err = check_rootfs("rootfs.tar", &tarball, &system).unwrap_err();
+    assert!(err.to_string().contains("is a tar archive"), "{err}");
+    assert!(check_rootfs("rootfs.tar.gz", &[0x1f, 0x8b, 8, 0], &system).is_err());
+
+    let warning = check_rootfs("system.bin", &[0; SUPERBLOCK_HEAD], &system).unwrap();
+    assert!(warning.is_some_and(|warning| warning.contains("system.bin")));
+  }
+}
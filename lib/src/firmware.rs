@@ -0,0 +1,159 @@
+//! What firmware a device already runs, to tell whether flashing a package would change anything.
+
+use std::cmp::Ordering;
+
+use crate::{AmlogicSoC, Error, Flasher, Result, config::FlashConfig};
+
+/// U-Boot environment variable firmware stores its name in, e.g. `thingos`
+pub const FIRMWARE_NAME_VAR: &str = "firmware_name";
+/// U-Boot environment variable firmware stores its version in, e.g. `1.4.2`
+pub const FIRMWARE_VERSION_VAR: &str = "firmware_version";
+/// U-Boot environment variable firmware stores the update channel it follows in, e.g. `stable`
+pub const FIRMWARE_CHANNEL_VAR: &str = "firmware_channel";
+
+/// Firmware a device says it runs, from its U-Boot environment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstalledFirmware {
+  /// Name of the firmware, if it recorded one
+  pub name: Option<String>,
+  /// Version of the firmware
+  pub version: String,
+  /// Update channel the firmware follows, if it recorded one
+  pub channel: Option<String>,
+}
+
+/// How the firmware on a device compares to a package
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateCheck {
+  /// The device does not say what it runs, e.g. because it runs stock firmware
+  NotRecorded,
+  /// The device runs firmware with a different name than the package
+  OtherFirmware,
+  /// The device runs an older version of the package
+  Older,
+  /// The device already runs the package's version
+  Same,
+  /// The device runs a newer version than the package, so flashing would downgrade it
+  Newer,
+}
+
+impl UpdateCheck {
+  /// Whether flashing the package would install something the device does not already run
+  ///
+  /// Downgrades are not counted as updates.
+  pub fn needs_update(&self) -> bool {
+    matches!(self, Self::NotRecorded | Self::OtherFirmware | Self::Older)
+  }
+}
+
+impl InstalledFirmware {
+  /// Compare the installed firmware to a package by name and version
+  ///
+  /// Firmware that did not record its name is taken to be the package, so only the versions are compared.
+  pub fn check(&self, config: &FlashConfig) -> UpdateCheck {
+    if self.name.as_ref().is_some_and(|name| *name != config.name) {
+      return UpdateCheck::OtherFirmware;
+    }
+    match compare_versions(&self.version, &config.version) {
+      Ordering::Less => UpdateCheck::Older,
+      Ordering::Equal => UpdateCheck::Same,
+      Ordering::Greater => UpdateCheck::Newer,
+    }
+  }
+}
+
+impl AmlogicSoC {
+  /// Read what firmware the device runs from its U-Boot environment
+  ///
+  /// Firmware records itself in [`FIRMWARE_NAME_VAR`], [`FIRMWARE_VERSION_VAR`] and
+  /// [`FIRMWARE_CHANNEL_VAR`], which a package can set with `setenv` and `saveenv` bulk commands as
+  /// its last steps. Stock firmware sets none of them.
+  ///
+  /// # Returns
+  /// - `Result<Option<InstalledFirmware>>`: The installed firmware, or `None` if the environment has no `firmware_version`
+  pub fn read_installed_firmware_info(&self) -> Result<Option<InstalledFirmware>> {
+    let Some(version) = self.read_env_var(FIRMWARE_VERSION_VAR)? else {
+      return Ok(None);
+    };
+    Ok(Some(InstalledFirmware {
+      name: self.read_env_var(FIRMWARE_NAME_VAR)?,
+      version,
+      channel: self.read_env_var(FIRMWARE_CHANNEL_VAR)?,
+    }))
+  }
+
+  /// Value of an environment variable, or `None` if it is not set
+  fn read_env_var(&self, name: &str) -> Result<Option<String>> {
+    // U-Boot fails `printenv` for a variable that is not set
+    let response = match self.bulkcmd(&format!("printenv {name}")) {
+      Ok(response) => response,
+      Err(Error::InvalidOperation(_)) => return Ok(None),
+      Err(err) => return Err(err),
+    };
+    let value = response
+      .trim()
+      .trim_start_matches("success")
+      .trim()
+      .strip_prefix(&format!("{name}="))
+      .map(|value| value.trim_end_matches('\0').to_owned());
+    Ok(value.filter(|value| !value.is_empty()))
+  }
+}
+
+impl Flasher {
+  /// Compare the firmware the device runs to the package, to skip flashing what it already has
+  pub fn check_update(&self) -> Result<UpdateCheck> {
+    Ok(match self.device().read_installed_firmware_info()? {
+      Some(installed) => installed.check(self.config()),
+      None => UpdateCheck::NotRecorded,
+    })
+  }
+}
+
+/// Compare two versions, with dotted numbers compared numerically and anything else as text
+///
+/// A leading `v` is ignored, so `v1.10.0` is newer than `1.9.2`.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+  fn key(version: &str) -> Vec<(u64, &str)> {
+    version
+      .trim()
+      .trim_start_matches('v')
+      .split(['.', '-', '+'])
+      .map(|part| match part.parse() {
+        Ok(number) => (number, ""),
+        Err(_) => (0, part),
+      })
+      .collect()
+  }
+  key(a).cmp(&key(b))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_compare_versions() {
+    assert_eq!(compare_versions("1.10.0", "1.9.2"), Ordering::Greater);
+    assert_eq!(compare_versions("v1.4.2", "1.4.2"), Ordering::Equal);
+    assert_eq!(compare_versions("1.4", "1.4.1"), Ordering::Less);
+
+    let config = FlashConfig::from_standalone(
+      r#"{ "name": "thingos", "version": "1.4.2", "description": "", "metadataVersion": 1, "steps": [] }"#,
+    )
+    .unwrap();
+    let installed = |name: Option<&str>, version: &str| InstalledFirmware {
+      name: name.map(str::to_owned),
+      version: version.to_owned(),
+      channel: None,
+    };
+    assert_eq!(installed(Some("thingos"), "1.4.2").check(&config), UpdateCheck::Same);
+    assert_eq!(installed(None, "1.3.0").check(&config), UpdateCheck::Older);
+    assert_eq!(installed(Some("thingos"), "2.0").check(&config), UpdateCheck::Newer);
+    assert_eq!(
+      installed(Some("nixos"), "1.4.2").check(&config),
+      UpdateCheck::OtherFirmware
+    );
+    assert!(UpdateCheck::OtherFirmware.needs_update() && !UpdateCheck::Newer.needs_update());
+  }
+}
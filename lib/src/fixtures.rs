@@ -0,0 +1,20 @@
+//! Values shared by unit tests.
+
+use crate::DeviceInfo;
+
+/// A superbird in USB mode, as enumeration finds it
+pub(crate) fn device_info(serial: Option<&str>) -> DeviceInfo {
+  DeviceInfo {
+    vendor_id: 0x1b8e,
+    product_id: 0xc003,
+    bus_number: 1,
+    address: 2,
+    serial: serial.map(str::to_owned),
+    interface_count: 1,
+    interface_number: 0,
+    alt_setting: 0,
+    endpoint_in: 0x81,
+    endpoint_out: 0x01,
+    bulk: true,
+  }
+}
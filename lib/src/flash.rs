@@ -1,23 +1,56 @@
 use std::{
+  collections::{HashMap, HashSet},
   fs::File,
-  io::{BufReader, Cursor, Read},
-  path::PathBuf,
-  thread::sleep,
-  time::Duration,
+  io::{self, BufReader, Cursor, Read, Write},
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::{Duration, Instant},
 };
 
-use zip::ZipArchive;
+use zip::{ZipArchive, result::ZipError};
 
 use crate::{
-  ADDR_TMP, AmlogicSoC, Callback, Error, Event, Result, TRANSFER_BLOCK_SIZE,
+  AmlogicSoC, Callback, CancelToken, DUMP_MANIFEST, DeviceMode, DeviceTarget, DumpManifest, Error, Event,
+  PART_SECTOR_SIZE, Result, ThermalPolicy, UsbLogLevel, UsbQuirks,
+  archive::{open_archive, open_entry, package_root},
+  artifacts::{self, ARTIFACT_OUTPUTS},
+  boot::{self, BootOutcome},
+  bootscript,
+  capture::{CapturedFile, SessionCapture},
   config::{
-    BL2BootValue, DataOrFile, FlashConfig, FlashStep, ReadMemoryValue, RestorePartitionValue, RunValue, StringOrFile,
-    ValidatePartitionSizeValue, WaitValue, WriteAMLCDataValue, WriteBootPartitionValue, WriteLargeMemoryValue,
-    WriteSimpleMemoryValue, WriteUserAreaValue,
+    ApplyDeltaValue, AssertValue, BL2BootValue, DataOrFile, FlashConfig, FlashStep, MetaFile, OnError, Patch,
+    ProvisionValue, ReadMemoryValue, ReconnectValue, RepeatValue, ResetValue, RestorePartitionValue, RunValue, Step,
+    StepOutput, StringOrFile, UBootEnv, ValidatePartitionSizeValue, WaitValue, WriteAMLCDataValue,
+    WriteBootPartitionValue, WriteBootScriptValue, WriteLargeMemoryValue, WriteSimpleMemoryValue, WriteUserAreaValue,
+    decode_base64, decode_hex,
   },
-  partitions::SUPERBIRD_PARTITIONS,
+  disklayout,
+  dump::open_joined,
+  events::{self, DEFAULT_PROGRESS_INTERVAL, EventDelivery, EventQueue},
+  filesystems, images,
+  logging::{LogFilter, LogMirror, Verbosity},
+  overrides::{OVERRIDES_FILE, Overrides},
+  package, padding,
+  patches::{self, Patched},
+  paths::{PathPolicy, normalize_file_path, resolve_in_archive, resolve_in_directory},
+  plan,
+  profile::DeviceProfile,
+  provision::{ProvisionRequest, Provisioner},
+  report::{EnvChange, FlashReport, IterationFailure, RepeatResult, ReportHook, StepResult, unix_now},
+  resources,
+  rollback::{Backup, CRITICAL_PARTITIONS, RollbackBundle, critical_overlaps},
+  stock::StockProfile,
+  telemetry, template, variables,
+  wear::WearLedger,
 };
 
+/// How long to wait for the device to come back after a step reset it
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Milliseconds a `postBootCheck` step waits for the device to boot and show up in adb
+#[cfg(feature = "adb")]
+const POST_BOOT_TIMEOUT_MS: u64 = 120_000;
+
 /// Type alias for zip archive reading from a file
 pub type Zip = ZipArchive<BufReader<File>>;
 
@@ -31,8 +64,8 @@ pub enum FlashMode {
   Standalone,
   /// Using files from a directory
   Directory(PathBuf),
-  /// Using files from a ZIP archive
-  Archive(ZipArchive<BufReader<File>>),
+  /// Using files from a ZIP archive, relative to the package root inside it, and the archive's path
+  Archive(ZipArchive<BufReader<File>>, String, PathBuf),
 }
 
 /// Progress information for flashing operations
@@ -52,454 +85,2468 @@ pub struct FlashProgress {
   pub avg_chunk_time: f64,
   /// Average transfer rate in KiB/s
   pub avg_rate: f64,
+  /// Id of the step making progress, when reported by a [`Flasher`]
+  pub step_id: Option<String>,
 }
 
 /// The main interface for flashing firmware to a Superbird device
 ///
 /// This provides high-level operations for loading and flashing firmware
 /// based on a configuration file.
+///
+/// A `Flasher` is `Send` and `Sync`, so it can be built on one thread and flashed from another,
+/// such as a blocking task of an async runtime.
 pub struct Flasher {
   aml: AmlogicSoC,
   mode: FlashMode,
   config: FlashConfig,
 
   step: usize,
+  step_id: Option<String>,
+  skipped: Vec<bool>,
+  /// Byte of its data the first step run starts writing at, to carry on a cancelled write
+  resume_offset: Option<u64>,
+  variables: HashMap<String, String>,
+  env_variables: Vec<String>,
+  provisioner: Option<Provisioner>,
+  overrides: Option<Overrides>,
+  report_hook: Option<ReportHook>,
   callback: Option<Callback>,
+  events: Option<Arc<EventQueue>>,
+  log_mirror: Option<tracing::Dispatch>,
+  deadline: Option<Duration>,
+  verify_boot: Option<Duration>,
+  #[cfg(feature = "adb")]
+  adb: Option<crate::Adb>,
+  rollback: Option<RollbackBundle>,
+  wear_ledger: Option<PathBuf>,
+  diff_env: bool,
+  path_policy: PathPolicy,
+  output_dir: PathBuf,
+  artifacts_dir: Option<PathBuf>,
+  artifacts: Vec<PathBuf>,
+  report: FlashReport,
+  capture: Option<SessionCapture>,
+  progress: Progress,
+}
+
+/// How far a [`Flasher`] got through its steps
+enum Progress {
+  NotStarted,
+  Running {
+    /// Index of the next step to consider running
+    next: usize,
+    /// When flashing has to be done by, and the reason given if it is not
+    deadline: Option<(Instant, String)>,
+  },
+  Finished,
+  /// Flashing stopped on an error, described by this message
+  Failed(String),
 }
 
+// the package source, device session and callbacks are all shared across threads by bindings
+const _: () = {
+  const fn assert_send_sync<T: Send + Sync>() {}
+  assert_send_sync::<Flasher>();
+  assert_send_sync::<AmlogicSoC>();
+};
+
 impl Flasher {
   /// Execute the flash process based on the loaded configuration
   ///
-  /// This will run through all steps defined in the flash configuration.
+  /// This will run through all steps defined in the flash configuration, or the ones left if
+  /// some were already run with [`step`](Self::step).
   ///
   /// # Returns
   /// - `Result<()>`: Success or an error
   pub fn flash(&mut self) -> Result<()> {
-    tracing::info!("beginning flashing process!");
+    loop {
+      match self.step()? {
+        FlashOutcome::Complete => return Ok(()),
+        FlashOutcome::AwaitUserInput(message) => return Err(Error::AwaitingUserInput(message)),
+        _ => {}
+      }
+    }
+  }
 
-    // i hate clones like this but i need self to be mutable due to the zip
-    let steps = self.config.steps.clone();
-    for step in &steps {
-      tracing::trace!("starting step: {:?}", step);
+  /// Execute the next step of the flash process, starting it if this is the first step
+  ///
+  /// This lets applications run their own logic between steps, such as asking for confirmation,
+  /// and act on what each step produced. A step that fails and is skipped with `onError: "continue"`
+  /// returns [`FlashOutcome::Normal`], with its error in the [`report`](Self::report). After the
+  /// last step, the report is finalized and every call returns [`FlashOutcome::Complete`].
+  ///
+  /// Once a step fails and ends flashing, the report is finalized the same way and every later
+  /// call returns an error rather than running more steps. To carry on, build a new flasher with
+  /// [`FlasherBuilder::start_at`] set to the failed step.
+  ///
+  /// # Returns
+  /// - `Result<FlashOutcome>`: What the step produced, or the error that ended flashing
+  pub fn step(&mut self) -> Result<FlashOutcome> {
+    let result = match self.log_mirror.clone() {
+      Some(dispatch) => tracing::dispatcher::with_default(&dispatch, || self.advance()),
+      None => self.advance(),
+    };
 
-      self.step += 1;
-      if let Some(callback) = &self.callback {
-        callback(Event::Step(self.step, step.clone()));
-      }
-
-      let outcome = match step {
-        FlashStep::Identify { variable } => self.identify(variable)?,
-        FlashStep::Bulkcmd { value } => self.bulkcmd(value)?,
-        FlashStep::BulkcmdStat { value, variable } => self.bulkcmd_stat(value, variable)?,
-        FlashStep::Run { value } => self.run(value)?,
-        FlashStep::WriteSimpleMemory { value } => self.write_simple_memory(value)?,
-        FlashStep::WriteLargeMemory { value } => self.write_large_memory(value)?,
-        FlashStep::ReadSimpleMemory { value, variable } => self.read_simple_memory(value, variable)?,
-        FlashStep::ReadLargeMemory { value, variable } => self.read_large_memory(value, variable)?,
-        FlashStep::GetBootAMLC { variable } => self.get_boot_amlc(variable)?,
-        FlashStep::WriteAMLCData { value } => self.write_amlc_data(value)?,
-        FlashStep::Bl2Boot { value } => self.bl2_boot(value)?,
-        FlashStep::ValidatePartitionSize { value, variable } => self.validate_partition_size(value, variable)?,
-        FlashStep::RestorePartition { value } => self.restore_partition(value)?,
-        FlashStep::WriteBootPartition { value } => self.write_boot_partition(value)?,
-        FlashStep::WriteUserArea { value } => self.write_user_area(value)?,
-        FlashStep::WriteEnv { value } => self.write_env(value)?,
-        FlashStep::Log { value } => self.log(value)?,
-        FlashStep::Wait { value } => self.wait(value)?,
-      };
+    // the log mirror is kept, so logs stay mirrored for as long as the flasher is used
+    if matches!(self.progress, Progress::Finished | Progress::Failed(_)) {
+      self.callback = None;
+      if let Some(events) = &self.events {
+        events.flush();
+      }
+    }
+    result
+  }
 
-      match outcome {
-        FlashOutcome::Normal => continue,
-        _ => tracing::warn!("handling return values is currently not supported: {:?}", &outcome),
+  fn advance(&mut self) -> Result<FlashOutcome> {
+    if matches!(self.progress, Progress::NotStarted) {
+      tracing::info!("beginning flashing process!");
+      if let Err(err) = self.begin() {
+        return self.finish(Err(err));
       }
     }
+    match &self.progress {
+      Progress::Finished => return Ok(FlashOutcome::Complete),
+      Progress::Failed(message) => {
+        return Err(Error::InvalidOperation(format!(
+          "flashing already failed ({}); build a new flasher to carry on",
+          message
+        )));
+      }
+      _ => {}
+    }
+
+    match self.run_next() {
+      Ok(Some(outcome)) => Ok(outcome),
+      Ok(None) => self.finish(Ok(())),
+      Err(err) => self.finish(Err(err)),
+    }
+  }
 
-    self.callback = None;
+  fn begin(&mut self) -> Result<()> {
+    self.begin_report();
+    self.variables = self
+      .config
+      .variables
+      .iter()
+      .flatten()
+      .map(|(name, value)| (name.clone(), value.to_string()))
+      .chain(variables::host_facts())
+      .chain(variables::environment(&self.env_variables))
+      .collect();
+    if let Some(serial) = &self.report.device_serial
+      && let Some(values) = self
+        .overrides
+        .as_ref()
+        .and_then(|overrides| overrides.for_device(serial))
+    {
+      tracing::info!("applying overrides for device {}: {:?}", serial, values);
+      self.variables.extend(values.clone());
+    }
+    if let Some(bundle) = &mut self.rollback {
+      bundle.clear();
+    }
+    self.artifacts.clear();
+    self.preflight()?;
+
+    self.progress = Progress::Running {
+      next: 0,
+      deadline: self.deadline.map(|limit| {
+        (
+          Instant::now() + limit,
+          format!("flashing did not finish within {:?}", limit),
+        )
+      }),
+    };
     Ok(())
   }
 
-  fn identify(&self, variable: &Option<String>) -> Result<FlashOutcome> {
-    tracing::debug!("running identify with variable {:?}", variable);
-    let start_time = std::time::Instant::now();
-    let result = self.aml.identify();
-    let elapsed = start_time.elapsed();
-    tracing::trace!("identify completed in {:?}", elapsed);
-    Ok(FlashOutcome::IdentifyResult(result?))
+  /// Reset the device if it is still in USB burn mode, and wait up to `timeout` for it to boot
+  ///
+  /// The device counts as booted once it enumerates in normal mode, as firmware with adb does.
+  /// The outcome is recorded in [`FlashReport::boot`] and sent as [`Event::BootVerified`],
+  /// [`Event::BootLoopDetected`] if it keeps dropping off the bus and coming back, or
+  /// [`Event::BootTimeout`]. [`FlasherBuilder::verify_boot`] runs this after the last step, before
+  /// the report is submitted, which is what most callers want.
+  ///
+  /// A device that does not boot is not an error here, since the flash itself already finished.
+  pub fn verify_boot(&mut self, timeout: Duration) -> BootOutcome {
+    if self.aml.session_valid()
+      && let Some(callback) = &self.callback
+    {
+      callback(Event::Resetting);
+    }
+    let (outcome, mode) = boot::wait_for_boot(&self.aml, timeout);
+    match outcome {
+      BootOutcome::Verified { after_ms } => {
+        if let Some(callback) = &self.callback {
+          callback(Event::BootVerified {
+            elapsed: Duration::from_millis(after_ms),
+          });
+        }
+      }
+      BootOutcome::BootLoop { cycles } => {
+        let warning = format!(
+          "device is in a boot loop, it dropped off the bus {} times after showing up; the firmware or its env is bad, not the cable",
+          cycles
+        );
+        tracing::error!("{}", warning);
+        self.report.warnings.push(warning);
+        if let Some(callback) = &self.callback {
+          callback(Event::BootLoopDetected { cycles });
+        }
+      }
+      BootOutcome::Timeout { .. } => {
+        let warning = match mode {
+          DeviceMode::Usb | DeviceMode::UsbBurn => format!(
+            "device came back in {:?} mode instead of booting within {:?}, the bootloader may have found nothing to boot",
+            mode, timeout
+          ),
+          _ => format!("device did not boot within {:?}", timeout),
+        };
+        tracing::error!("{}", warning);
+        self.report.warnings.push(warning);
+        if let Some(callback) = &self.callback {
+          callback(Event::BootTimeout { timeout, mode });
+        }
+      }
+    }
+    self.report.boot = Some(outcome);
+    outcome
   }
 
-  fn bulkcmd(&self, value: &str) -> Result<FlashOutcome> {
-    tracing::debug!("running bulkcmd with value {:?}", value);
-    let start_time = std::time::Instant::now();
-    let result = self.aml.bulkcmd(value);
-    let elapsed = start_time.elapsed();
-    tracing::trace!("bulkcmd completed in {:?}", elapsed);
-    result?;
-    Ok(FlashOutcome::Normal)
+  /// The package's `meta.json`, as loaded for flashing
+  pub fn config(&self) -> &FlashConfig {
+    &self.config
   }
 
-  fn bulkcmd_stat(&self, value: &str, variable: &Option<String>) -> Result<FlashOutcome> {
-    tracing::debug!(
-      "running bulkcmd_stat with value {:?} and variable {:?}",
-      value,
-      variable
-    );
-    let start_time = std::time::Instant::now();
-    let result = self.aml.bulkcmd(value);
-    let elapsed = start_time.elapsed();
-    tracing::trace!("bulkcmd_stat completed in {:?}", elapsed);
-    Ok(FlashOutcome::BulkcmdStatResult(result?))
+  pub(crate) fn device(&self) -> &AmlogicSoC {
+    &self.aml
   }
 
-  fn run(&self, value: &RunValue) -> Result<FlashOutcome> {
-    tracing::debug!("running run with value {:?}", value);
-    let start_time = std::time::Instant::now();
-    let result = self.aml.run(value.address, value.keep_power);
-    let elapsed = start_time.elapsed();
-    tracing::trace!("run completed in {:?}", elapsed);
-    result?;
-    Ok(FlashOutcome::Normal)
+  /// Whether each step was left out, by [`FlasherBuilder::skip`] or because it comes before the start step
+  pub(crate) fn skipped(&self) -> &[bool] {
+    &self.skipped
   }
 
-  fn write_simple_memory(&mut self, value: &WriteSimpleMemoryValue) -> Result<FlashOutcome> {
-    tracing::debug!("running write_simple_memory with value {:?}", value);
-    let data = self.handle_data_or_file(&value.data)?;
+  /// Index of the step [`step`](Self::step) would run next, or `None` if there are none left
+  pub(crate) fn next_pending(&self) -> Option<usize> {
+    let from = match &self.progress {
+      Progress::NotStarted => 0,
+      Progress::Running { next, .. } => *next,
+      Progress::Finished | Progress::Failed(_) => return None,
+    };
+    (from..self.skipped.len()).find(|index| !self.skipped[*index])
+  }
 
-    let start_time = std::time::Instant::now();
-    let result = self.aml.write_simple_memory(value.address, &data);
-    let elapsed = start_time.elapsed();
-    tracing::trace!("write_simple_memory completed in {:?}", elapsed);
+  /// Leave out a step that has not run yet, as if it was given to [`FlasherBuilder::skip`]
+  pub(crate) fn skip_step(&mut self, index: usize) {
+    if self.skipped[index] {
+      return;
+    }
+    self.skipped[index] = true;
+    if matches!(self.progress, Progress::Running { .. }) {
+      self.report.steps_skipped += 1;
+    }
+  }
 
-    result?;
-    Ok(FlashOutcome::Normal)
+  /// Roll back if flashing failed, then finalize and submit the report
+  ///
+  /// A cancelled flash is not rolled back, so it can be resumed where it stopped.
+  fn finish(&mut self, result: Result<()>) -> Result<FlashOutcome> {
+    if let Err(Error::Cancelled) = result {
+      if let Some(bundle) = self.rollback.as_ref().filter(|bundle| !bundle.is_empty()) {
+        tracing::warn!(
+          "not rolling back a cancelled flash so it can be resumed; the backups are in {}",
+          bundle.dir().display()
+        );
+      }
+    } else if result.is_err() {
+      self.roll_back();
+    } else if let Some(timeout) = self.verify_boot {
+      self.verify_boot(timeout);
+    }
+    self.finish_report(&result);
+    self.write_artifacts();
+    self.write_session_capture();
+    self.submit_report();
+    self.progress = match &result {
+      Ok(()) => Progress::Finished,
+      Err(err) => Progress::Failed(err.to_string()),
+    };
+    result.map(|_| FlashOutcome::Complete)
   }
 
-  fn write_large_memory(&mut self, value: &WriteLargeMemoryValue) -> Result<FlashOutcome> {
-    tracing::debug!("running write_large_memory with value {:?}", value);
-    let start_time = std::time::Instant::now();
+  /// Run the next step that was not skipped, or return `None` if there are no steps left
+  fn run_next(&mut self) -> Result<Option<FlashOutcome>> {
+    let Progress::Running { next, deadline } = &self.progress else {
+      return Ok(None);
+    };
+    let (mut index, deadline) = (*next, deadline.clone());
+    while index < self.skipped.len() && self.skipped[index] {
+      self.step += 1;
+      let id = self.config.steps[index].id.as_deref().unwrap_or_default();
+      tracing::info!("skipping step {} ({})", self.step, id);
+      index += 1;
+    }
+    self.progress = Progress::Running {
+      next: index + 1,
+      deadline: deadline.clone(),
+    };
+    // i hate clones like this but i need self to be mutable due to the zip
+    let Some(entry) = self.config.steps.get(index).cloned() else {
+      return Ok(None);
+    };
+    let Step {
+      step,
+      id,
+      timeout_ms,
+      on_error,
+    } = &entry;
+
+    self.step += 1;
+    // where to pick up again with `start_at` if flashing is cancelled during this step
+    let checkpoint = id.clone().unwrap_or_else(|| self.step.to_string());
+    if let Err(err) = self.aml.check_cancelled() {
+      tracing::warn!("flashing cancelled before step {}", self.step);
+      self.report.resume_at = Some(checkpoint);
+      return Err(err);
+    }
+    if let Some(offset) = self.resume_offset.filter(|_| !step.resumes_mid_write()) {
+      tracing::warn!(
+        "step {} does not stream its data, so it runs from the start rather than byte {}",
+        self.step,
+        offset
+      );
+    }
+    tracing::trace!("starting step: {:?}", step);
+    self.step_id = id.clone();
+    let resolved = self.with_variables(&entry);
+    if let Some(callback) = &self.callback {
+      callback(Event::Step(self.step, resolved.as_ref().unwrap_or(&entry).clone()));
+    }
+
+    let on_error = on_error.unwrap_or_default();
+    let (result, attempts) = match resolved {
+      Ok(entry) => self.attempt_with_retries(&entry.step, *timeout_ms, on_error, &deadline),
+      Err(err) => (Err(err), 1),
+    };
+    self.resume_offset = None;
+    let streamed = self.aml.streamed();
+    self.aml.resume_next_write(0);
+
+    let outcome = match result {
+      Ok(outcome) => outcome,
+      Err(err) => {
+        self.record_step(attempts, Some(&err));
+        if matches!(err, Error::Cancelled) {
+          tracing::warn!("flashing cancelled during step {}", self.step);
+          self.report.resume_at = Some(checkpoint);
+          self.report.resume_offset = Some(streamed).filter(|streamed| *streamed > 0 && step.resumes_mid_write());
+          return Err(err);
+        }
+        if on_error != OnError::Continue || deadline_passed(&deadline) {
+          return Err(err);
+        }
+        let warning = format!("step {} failed and was skipped: {}", self.step, err);
+        tracing::warn!("{}", warning);
+        self.report.warnings.push(warning);
+        self.report.steps_failed += 1;
+        return Ok(Some(FlashOutcome::Normal));
+      }
+    };
 
-    let (file_size, mut file) = handle_data_or_file_stream(&value.data, &mut self.mode)?;
+    self.record_step(attempts, None);
+    self.report.steps_completed += 1;
+    Ok(Some(outcome))
+  }
 
-    let caller_callback = self.callback.clone();
-    let progress_callback = |progress: FlashProgress| {
-      if let Some(callback) = &caller_callback {
-        callback(Event::FlashProgress(progress.clone()));
-      };
+  /// Run a step, retrying it as many times as its `onError` allows
+  ///
+  /// # Returns
+  /// - `(Result<FlashOutcome>, u32)`: The result of the last attempt, with the step attached to its
+  ///   error, and how many attempts were made
+  fn attempt_with_retries(
+    &mut self,
+    step: &FlashStep,
+    timeout_ms: Option<u64>,
+    on_error: OnError,
+    deadline: &Option<(Instant, String)>,
+  ) -> (Result<FlashOutcome>, u32) {
+    let retries = match on_error {
+      OnError::Retry(retries) => retries,
+      _ => 0,
     };
+    let mut attempt = 0;
+    let start = Instant::now();
+    loop {
+      match self.attempt_step(step, timeout_ms, deadline) {
+        Err(err) if attempt < retries && !deadline_passed(deadline) && !matches!(err, Error::Cancelled) => {
+          attempt += 1;
+          tracing::warn!("step {} failed, retrying ({}/{}): {}", self.step, attempt, retries, err);
+          telemetry::retry("step");
+        }
+        result => {
+          telemetry::step(step, start.elapsed(), result.is_ok());
+          let result = result.map_err(|err| {
+            err.with_context(|context| {
+              context.step.get_or_insert(self.step);
+              context.step_type.get_or_insert(step.kind());
+              if context.partition.is_none() {
+                context.partition = step.partition().map(String::from);
+              }
+              context.retries += attempt;
+            })
+          });
+          return (result, attempt + 1);
+        }
+      }
+    }
+  }
 
-    self.aml.write_large_memory_to_disk(
-      value.address,
-      &mut file,
-      file_size,
-      value.block_length,
-      value.append_zeros.unwrap_or(true),
-      progress_callback,
-    )?;
+  /// Add the outcome of the current step to the report and tell the callback about it
+  fn record_step(&mut self, attempts: u32, error: Option<&Error>) {
+    let id = self.step_id.clone().unwrap_or_default();
+    let code = error.map(Error::code);
+    let error = error.map(|e| e.to_string());
+    if let Some(callback) = &self.callback {
+      callback(match (&error, code) {
+        (Some(error), Some(code)) => Event::StepFailed {
+          step: self.step,
+          id: id.clone(),
+          error: error.clone(),
+          code,
+        },
+        _ => Event::StepCompleted {
+          step: self.step,
+          id: id.clone(),
+        },
+      });
+    }
+    self.report.steps.push(StepResult {
+      index: self.step,
+      id,
+      completed: error.is_none(),
+      attempts,
+      error,
+      error_code: code.map(String::from),
+    });
+  }
 
-    let elapsed = start_time.elapsed();
-    tracing::trace!("write_large_memory completed in {:?}", elapsed);
-    Ok(FlashOutcome::Normal)
+  /// Check the write steps against the partition table before anything is written
+  ///
+  /// A raw disk write that starts inside a partition must end inside it too, unless the step sets
+  /// `allowCrossPartition`, since running into the next partition is the easiest way to brick a device.
+  ///
+  /// Steps that refer to variables are checked once they are loaded with them, just before they run.
+  fn preflight(&mut self) -> Result<()> {
+    let steps = self.config.steps.clone();
+    let writes = steps
+      .iter()
+      .enumerate()
+      .filter(|(index, _)| !self.skipped[*index])
+      .flat_map(|(index, step)| step.walk_steps().into_iter().map(move |step| (index, step)))
+      .filter(|(_, step)| !step.id.as_ref().is_some_and(|id| self.config.deferred.contains_key(id)))
+      .collect::<Vec<_>>();
+    let mut checked = HashSet::new();
+    for (index, step) in writes {
+      if let FlashStep::WriteLargeMemory { value } = &step.step {
+        self.check_write(index + 1, value)?;
+      }
+      for file in package::step_files(&step.step) {
+        let range = (
+          file.offset.map(|offset| offset.get()),
+          file.length.map(|length| length.get()),
+        );
+        if checked.insert((file.file_path.clone(), range)) {
+          self.check_image(index + 1, file)?;
+        }
+      }
+      self.check_rootfs(index + 1, &step.step)?;
+      self.check_disk_image(index + 1, &step.step)?;
+      self.check_patches(index + 1, &step.step)?;
+      self.check_template(index + 1, &step.step)?;
+      self.check_env(index + 1, &step.step)?;
+    }
+    Ok(())
   }
 
-  fn read_simple_memory(&self, value: &ReadMemoryValue, variable: &Option<String>) -> Result<FlashOutcome> {
-    tracing::debug!(
-      "running read_simple_memory with value {:?} and variable {:?}",
-      value,
-      variable
-    );
-    let start_time = std::time::Instant::now();
-    let result = self.aml.read_simple_memory(value.address, value.length);
-    let elapsed = start_time.elapsed();
-    tracing::trace!("read_simple_memory completed in {:?}", elapsed);
-    result?;
-    Ok(FlashOutcome::Normal)
+  /// Check the text a `writeEnv` step imports, unless it is a template that only takes its shape
+  /// when it is rendered
+  ///
+  /// Files that cannot be read are left for the step to fail on.
+  fn check_env(&mut self, step: usize, flash_step: &FlashStep) -> Result<()> {
+    let FlashStep::WriteEnv { value } = flash_step else {
+      return Ok(());
+    };
+    if let StringOrFile::File(file) = value
+      && file.template.unwrap_or(false)
+    {
+      return Ok(());
+    }
+    let Ok(text) = self.handle_string_or_file(value) else {
+      return Ok(());
+    };
+    self.parse_env(value, &text).map(drop).inspect_err(|err| {
+      tracing::error!("step {} would write an invalid env: {}", step, err);
+    })
   }
 
-  fn read_large_memory(&self, value: &ReadMemoryValue, variable: &Option<String>) -> Result<FlashOutcome> {
-    tracing::debug!(
-      "running read_large_memory with value {:?} and variable {:?}",
-      value,
-      variable
-    );
-    let start_time = std::time::Instant::now();
-    let result = self.aml.read_memory(value.address, value.length);
-    let elapsed = start_time.elapsed();
-    tracing::trace!("read_large_memory completed in {:?}", elapsed);
-    result?;
-    Ok(FlashOutcome::Normal)
+  /// Check that a text file marked as a template parses, so a typo in it fails before anything is
+  /// written rather than at the end of the flash
+  ///
+  /// Files that cannot be read are left for the step to fail on.
+  fn check_template(&mut self, step: usize, flash_step: &FlashStep) -> Result<()> {
+    let value = match flash_step {
+      FlashStep::WriteEnv { value } => value,
+      FlashStep::WriteBootScript { value } => &value.script,
+      _ => return Ok(()),
+    };
+    let StringOrFile::File(file) = value else {
+      return Ok(());
+    };
+    if !file.template.unwrap_or(false) {
+      return Ok(());
+    }
+    let Ok(text) = self.handle_string_or_file(value) else {
+      return Ok(());
+    };
+    template::check(&file.file_path, &text).inspect_err(|err| {
+      tracing::error!("step {} has an invalid template: {}", step, err);
+    })
   }
 
-  fn get_boot_amlc(&self, variable: &Option<String>) -> Result<FlashOutcome> {
-    tracing::debug!("running get_boot_amlc with variable {:?}", variable);
-    let start_time = std::time::Instant::now();
-    let result = self.aml.get_boot_amlc();
-    let elapsed = start_time.elapsed();
-    tracing::trace!("get_boot_amlc completed in {:?}", elapsed);
-    result?;
-    Ok(FlashOutcome::Normal)
+  /// Check that a step's patches are valid hex and fit in the data they patch
+  ///
+  /// Files that cannot be opened are left for the step to fail on.
+  fn check_patches(&mut self, step: usize, flash_step: &FlashStep) -> Result<()> {
+    let Some((data, patches)) = flash_step.patches() else {
+      return Ok(());
+    };
+    let Ok((size, _)) = handle_data_or_file_stream(data, &mut self.mode, self.path_policy) else {
+      return Ok(());
+    };
+    patches::decode_patches(patches, size as u64)
+      .map(drop)
+      .inspect_err(|err| {
+        tracing::error!("step {} has a patch that cannot be applied: {}", step, err);
+      })
   }
 
-  fn write_amlc_data(&mut self, value: &WriteAMLCDataValue) -> Result<FlashOutcome> {
-    tracing::debug!("running write_amlc_data with value {:?}", value);
-    let data = self.handle_data_or_file(&value.data)?;
+  /// Check that a file written whole to a system partition is a filesystem image that fits in it
+  ///
+  /// Unknown partitions and files that cannot be opened are left for the step to fail on.
+  fn check_rootfs(&mut self, step: usize, flash_step: &FlashStep) -> Result<()> {
+    let (partition, file) = match flash_step {
+      FlashStep::RestorePartition {
+        value: RestorePartitionValue {
+          name,
+          data: DataOrFile::File(file),
+          ..
+        },
+      } => (name, file),
+      FlashStep::WriteLargeMemory {
+        value:
+          WriteLargeMemoryValue {
+            partition: Some(name),
+            offset_in_partition: None,
+            data: DataOrFile::File(file),
+            ..
+          },
+      } => (name, file),
+      _ => return Ok(()),
+    };
+    let Ok(partition) = self.aml.profile().partitions.resolve(partition).cloned() else {
+      return Ok(());
+    };
+    if !filesystems::is_rootfs(&partition.name) {
+      return Ok(());
+    }
 
-    let start_time = std::time::Instant::now();
-    let result = self.aml.write_amlc_data_packet(value.seq, value.amlc_offset, &data);
-    let elapsed = start_time.elapsed();
-    tracing::trace!("write_amlc_data completed in {:?}", elapsed);
+    let data = DataOrFile::File(file.clone());
+    let Ok((_, reader)) = handle_data_or_file_stream(&data, &mut self.mode, self.path_policy) else {
+      return Ok(());
+    };
+    let mut head = Vec::with_capacity(filesystems::SUPERBLOCK_HEAD);
+    reader
+      .take(filesystems::SUPERBLOCK_HEAD as u64)
+      .read_to_end(&mut head)?;
+    let warning = filesystems::check_rootfs(&file.file_path, &head, &partition).inspect_err(|err| {
+      tracing::error!("step {} would write something other than a filesystem: {}", step, err);
+    })?;
+    if let Some(warning) = warning {
+      tracing::warn!("step {}: {}", step, warning);
+      self.report.warnings.push(format!("step {}: {}", step, warning));
+    }
+    Ok(())
+  }
 
-    result?;
-    Ok(FlashOutcome::Normal)
+  /// Check a file a step writes, if it is a uImage or FIT image, before anything is written
+  ///
+  /// Files that cannot be opened are left for the step to fail on.
+  fn check_image(&mut self, step: usize, file: &MetaFile) -> Result<()> {
+    let data = DataOrFile::File(file.clone());
+    let Ok((size, mut reader)) = handle_data_or_file_stream(&data, &mut self.mode, self.path_policy) else {
+      return Ok(());
+    };
+    let mut head = [0; 4];
+    if size > images::MAX_IMAGE_SIZE || reader.read_exact(&mut head).is_err() || !images::is_image(&head) {
+      return Ok(());
+    }
+
+    let mut image = head.to_vec();
+    reader.read_to_end(&mut image)?;
+    drop(reader);
+    images::check_image(&file.file_path, &image, self.aml.profile().dram_size).inspect_err(|err| {
+      tracing::error!("step {} would write an invalid image: {}", step, err);
+    })
   }
 
-  fn bl2_boot(&mut self, value: &BL2BootValue) -> Result<FlashOutcome> {
-    tracing::debug!("running bl2_boot with value {:?}", value);
-    let bl2 = self.handle_data_or_file(&value.bl2)?;
-    let bootloader = self.handle_data_or_file(&value.bootloader)?;
+  /// Check that a whole-disk image has the partition layout of the device it is written to
+  ///
+  /// A file counts as a whole-disk image when it is written from the start of the disk and runs past
+  /// the first partition, so writing just the bootloader is not checked.
+  fn check_disk_image(&mut self, step: usize, flash_step: &FlashStep) -> Result<()> {
+    let file = match flash_step {
+      FlashStep::WriteLargeMemory {
+        value:
+          WriteLargeMemoryValue {
+            address: Some(address),
+            data: DataOrFile::File(file),
+            ..
+          },
+      } if address.get() == 0 => file,
+      FlashStep::WriteUserArea {
+        value: WriteUserAreaValue {
+          lba: 0,
+          data: DataOrFile::File(file),
+          ..
+        },
+      } => file,
+      _ => return Ok(()),
+    };
+    let profile = self.aml.profile().clone();
+    let data = DataOrFile::File(file.clone());
+    let Ok((size, mut reader)) = handle_data_or_file_stream(&data, &mut self.mode, self.path_policy) else {
+      return Ok(());
+    };
+    if profile
+      .partitions
+      .iter()
+      .next()
+      .is_none_or(|first| size <= first.end() * PART_SECTOR_SIZE)
+    {
+      return Ok(());
+    }
 
-    let start_time = std::time::Instant::now();
-    let result = self.aml.bl2_boot(Some(&bl2), Some(&bootloader));
-    let elapsed = start_time.elapsed();
-    tracing::trace!("bl2_boot completed in {:?}", elapsed);
+    let Some(layout) = disklayout::read_layout(&mut reader, &profile.partitions)? else {
+      let warning = format!(
+        "step {}: {} is written over the whole disk but has no partition table",
+        step, file.file_path
+      );
+      tracing::warn!("{}", warning);
+      self.report.warnings.push(warning);
+      return Ok(());
+    };
+    let differences = disklayout::differences(&layout, &profile.partitions);
+    if differences.is_empty() {
+      return Ok(());
+    }
+    tracing::error!(
+      "step {} would write a disk image with a different layout: {:?}",
+      step,
+      differences
+    );
+    Err(Error::InvalidImage(format!(
+      "{}: its {} partition table does not match the {} layout: {}",
+      file.file_path,
+      layout.kind,
+      profile.name,
+      differences.join("; ")
+    )))
+  }
 
-    result?;
-    Ok(FlashOutcome::Normal)
+  fn check_write(&mut self, step: usize, value: &WriteLargeMemoryValue) -> Result<()> {
+    if value.allow_cross_partition.unwrap_or(false) {
+      return Ok(());
+    }
+
+    let partitions = &self.aml.profile().partitions;
+    let start = match (&value.address, &value.partition) {
+      (Some(address), _) => address.get() as usize,
+      (None, Some(partition)) => {
+        let offset = value.offset_in_partition.map_or(0, |offset| offset.get() as usize);
+        partitions.resolve(partition)?.offset_bytes() + offset
+      }
+      (None, None) => return Ok(()),
+    };
+    let (length, _) = handle_data_or_file_stream(&value.data, &mut self.mode, self.path_policy)?;
+
+    if let Some(overrun) = partitions.overrun(start, length) {
+      tracing::error!("step {} would write past the end of its partition: {}", step, overrun);
+      return Err(Error::PartitionOverrun(format!(
+        "step {}: {}; set allowCrossPartition on the step if this is intended",
+        step, overrun
+      )));
+    }
+    Ok(())
   }
 
-  fn validate_partition_size(
-    &self,
-    value: &ValidatePartitionSizeValue,
-    variable: &Option<String>,
-  ) -> Result<FlashOutcome> {
-    tracing::debug!(
-      "running validate_partition_size with value {:?} and variable {:?}",
-      value,
-      variable
-    );
+  /// Load a step that refers to variables again with their current values
+  fn with_variables(&mut self, entry: &Step) -> Result<Step> {
+    let Some(deferred) = entry.id.as_ref().and_then(|id| self.config.deferred.get(id)) else {
+      return Ok(entry.clone());
+    };
+    let step = deferred.resolve(&self.variables)?;
+    tracing::debug!("loaded step {} with variables: {:?}", self.step, step);
+    if let FlashStep::WriteLargeMemory { value } = &step {
+      self.check_write(self.step, value)?;
+    }
+    Ok(Step { step, ..entry.clone() })
+  }
 
-    let part_name = &value.name;
-    let part_info = match SUPERBIRD_PARTITIONS.get(part_name.as_str()) {
-      Some(info) => info,
-      None => {
-        tracing::error!("Error: Invalid partition name: {}", part_name);
-        return Ok(FlashOutcome::ValidatePartitionResult(None, None));
+  /// Run a step once, reconnecting first if the session was lost and enforcing its time limits
+  fn attempt_step(
+    &mut self,
+    step: &FlashStep,
+    timeout_ms: Option<u64>,
+    deadline: &Option<(Instant, String)>,
+  ) -> Result<FlashOutcome> {
+    let step_deadline = match timeout_ms {
+      Some(timeout) => {
+        let at = Instant::now() + Duration::from_millis(timeout);
+        match deadline {
+          Some(deadline) if deadline.0 < at => Some(deadline.clone()),
+          _ => Some((at, format!("step {} did not finish within {}ms", self.step, timeout))),
+        }
       }
+      None => deadline.clone(),
     };
+    self.aml.set_deadline(step_deadline.clone());
 
-    match self.aml.validate_partition_size(part_name, part_info) {
-      Ok(part_size) => {
-        let part_offset = part_info.offset;
-        Ok(FlashOutcome::ValidatePartitionResult(
-          Some(part_size),
-          Some(part_offset),
-        ))
+    if step.needs_session() && !self.aml.session_valid() {
+      self.aml = self.aml.reconnect(RECONNECT_TIMEOUT)?;
+      self.aml.set_deadline(step_deadline.clone());
+    }
+    // only the first attempt carries on where a cancelled flash stopped
+    let resume_offset = self.resume_offset.take().filter(|_| step.resumes_mid_write());
+    self.aml.resume_next_write(resume_offset.unwrap_or(0));
+
+    let result = self
+      .back_up_critical_regions(step)
+      .and_then(|_| self.run_step(step, &step_deadline));
+    self.aml.set_deadline(None);
+    if let Some(Error::Timeout(reason)) = result.as_ref().err().map(Error::root) {
+      tracing::error!("aborting step {}: {}", self.step, reason);
+      if let Some(callback) = &self.callback {
+        callback(Event::Timeout {
+          step: self.step,
+          id: self.step_id.clone().unwrap_or_default(),
+          reason: reason.clone(),
+        });
       }
-      Err(_) => Ok(FlashOutcome::ValidatePartitionResult(None, None)),
     }
+    result
+  }
+
+  /// Save the critical regions `step` is about to change, if flashing transactionally
+  fn back_up_critical_regions(&mut self, step: &FlashStep) -> Result<()> {
+    if self.rollback.is_none() {
+      return Ok(());
+    }
+    for (backup, length) in self.critical_regions(step)? {
+      let Some(bundle) = &mut self.rollback else {
+        return Ok(());
+      };
+      let saved = bundle.saved_len(&backup).unwrap_or(0) as usize;
+      if saved >= length {
+        continue;
+      }
+
+      tracing::info!("backing up {} before changing it", backup.name());
+      let aml = &self.aml;
+      bundle.save(backup.clone(), |file| match &backup {
+        Backup::Partition(name) => aml.stream_partition(name, length, |chunk| Ok(file.write_all(chunk)?), |_| {}),
+        Backup::BootPartition(hwpart) => {
+          Ok(file.write_all(&aml.read_boot_partition(*hwpart, saved, length - saved)?)?)
+        }
+      })?;
+      let written = [bundle.image_path(&backup), bundle.dir().join("meta.json")];
+      written.into_iter().for_each(|path| self.record_artifact(path));
+    }
+    Ok(())
+  }
+
+  /// Critical regions `step` writes to, and how many bytes of each to back up
+  ///
+  /// Partitions are backed up whole, whether a step writes them by name or runs over them in a
+  /// raw write to the user area. Boot hwpartitions are backed up as far as the step writes.
+  fn critical_regions(&mut self, step: &FlashStep) -> Result<Vec<(Backup, usize)>> {
+    let partitions = self.aml.profile().partitions.clone();
+    let names = match step {
+      FlashStep::WriteEnv { .. } => vec!["env".to_owned()],
+      FlashStep::Provision { value } if value.env.is_some() => vec!["env".to_owned()],
+      FlashStep::Provision {
+        value: ProvisionValue { data: Some(data), .. },
+      } => vec![partitions.resolve(&data.partition)?.name.to_string()],
+      FlashStep::RestorePartition {
+        value: RestorePartitionValue { name, .. },
+      }
+      | FlashStep::ApplyDelta {
+        value: ApplyDeltaValue { name, .. },
+      }
+      | FlashStep::WriteBootScript {
+        value: WriteBootScriptValue { partition: name, .. },
+      } => vec![partitions.resolve(name)?.name.to_string()],
+      FlashStep::WriteUserArea { value } => {
+        let (length, _) = handle_data_or_file_stream(&value.data, &mut self.mode, self.path_policy)?;
+        critical_overlaps(&partitions, value.lba as usize * PART_SECTOR_SIZE, length)
+      }
+      FlashStep::WriteLargeMemory { value } => {
+        let start = match (&value.address, &value.partition) {
+          (Some(address), _) => address.get() as usize,
+          (None, Some(partition)) => {
+            let offset = value.offset_in_partition.map_or(0, |offset| offset.get() as usize);
+            partitions.resolve(partition)?.offset_bytes() + offset
+          }
+          (None, None) => return Ok(Vec::new()),
+        };
+        // the last block may be padded out with zeros
+        let (length, _) = handle_data_or_file_stream(&value.data, &mut self.mode, self.path_policy)?;
+        let block = value.block_length.max(1);
+        critical_overlaps(&partitions, start, length.div_ceil(block) * block)
+      }
+      FlashStep::WriteBootPartition { value } => {
+        let (length, _) = handle_data_or_file_stream(&value.data, &mut self.mode, self.path_policy)?;
+        let length = length.div_ceil(PART_SECTOR_SIZE) * PART_SECTOR_SIZE;
+        return Ok(vec![(Backup::BootPartition(value.hwpart), length)]);
+      }
+      _ => return Ok(Vec::new()),
+    };
+
+    names
+      .into_iter()
+      .filter(|name| CRITICAL_PARTITIONS.contains(&name.as_str()))
+      .map(|name| {
+        let part_size = self
+          .aml
+          .validate_partition_size(&name, partitions.resolve(&name)?, false)?;
+        Ok((Backup::Partition(name), part_size))
+      })
+      .collect()
+  }
+
+  /// Add a file to the ones [`artifacts`](Self::artifacts) lists, if it is not there yet
+  fn record_artifact(&mut self, path: PathBuf) {
+    if !self.artifacts.contains(&path) {
+      self.artifacts.push(path);
+    }
+  }
+
+  /// Write the report, and a checkpoint if flashing can be resumed, to the artifacts directory
+  fn write_artifacts(&mut self) {
+    let Some(dir) = &self.artifacts_dir else {
+      return;
+    };
+    match artifacts::write_finished(dir, &self.report) {
+      Ok(written) => written.into_iter().for_each(|path| self.record_artifact(path)),
+      // the flash itself is done by now, so this only costs the caller the files
+      Err(e) => tracing::warn!("failed to write flash artifacts to {}: {}", dir.display(), e),
+    }
+  }
+
+  /// Write the session capture enabled with [`enable_session_capture`](Self::enable_session_capture)
+  fn write_session_capture(&mut self) {
+    let Some(capture) = self.capture.take() else {
+      return;
+    };
+    let mut files = (self.config.steps.iter())
+      .flat_map(|step| step.walk())
+      .flat_map(package::step_files)
+      .map(|file| file.file_path.clone())
+      .collect::<Vec<_>>();
+    files.sort();
+    files.dedup();
+    let files = files
+      .into_iter()
+      .map(
+        |file_path| match package::digest_file(&mut self.mode, &file_path, self.path_policy) {
+          Ok((size, sha256)) => CapturedFile {
+            file_path,
+            size: Some(size),
+            sha256: Some(sha256),
+            error: None,
+          },
+          Err(e) => CapturedFile {
+            file_path,
+            size: None,
+            sha256: None,
+            error: Some(e.to_string()),
+          },
+        },
+      )
+      .collect::<Vec<_>>();
+
+    match capture.write(
+      &self.report,
+      &self.config,
+      &files,
+      self.aml.device_info(),
+      self.aml.target(),
+    ) {
+      Ok(path) => {
+        tracing::info!("wrote session capture to {}", path.display());
+        self.record_artifact(path);
+      }
+      Err(e) => tracing::warn!("failed to write session capture: {}", e),
+    }
+  }
+
+  /// Restore the partitions backed up so far, after a step failed in transactional mode
+  fn roll_back(&mut self) {
+    let Some(bundle) = self.rollback.take() else {
+      return;
+    };
+    // restoring isn't part of the step that failed
+    self.step_id = None;
+    if !bundle.is_empty() {
+      self.restore_bundle(&bundle);
+    }
+    self.rollback = Some(bundle);
+  }
+
+  fn restore_bundle(&mut self, bundle: &RollbackBundle) {
+    tracing::warn!(
+      "flashing failed, restoring backed up partitions from {}",
+      bundle.dir().display()
+    );
+    if !self.aml.session_valid() {
+      match self.aml.reconnect(RECONNECT_TIMEOUT) {
+        Ok(aml) => self.aml = aml,
+        Err(e) => {
+          let warning = format!(
+            "could not reconnect to roll back: {}; restore the bundle at {} by hand",
+            e,
+            bundle.dir().display()
+          );
+          tracing::error!("{}", warning);
+          self.report.warnings.push(warning);
+          return;
+        }
+      }
+    }
+
+    let progress_callback = progress_callback(&self.callback, &self.step_id);
+    for backup in bundle.backups() {
+      let name = backup.name();
+      let restored = File::open(bundle.image_path(backup))
+        .map_err(Error::from)
+        .and_then(|mut image| match backup {
+          Backup::Partition(name) => {
+            let part_info = self.aml.profile().partitions.resolve(name)?;
+            let part_size = self.aml.validate_partition_size(name, part_info, false)?;
+            let size = image.metadata()?.len() as usize;
+            self
+              .aml
+              .restore_partition(name, part_size, image, size, true, &progress_callback)
+          }
+          Backup::BootPartition(hwpart) => {
+            let mut data = Vec::new();
+            image.read_to_end(&mut data)?;
+            self.aml.write_boot_partition(*hwpart, &data)
+          }
+        });
+      match restored {
+        Ok(()) => {
+          tracing::info!("rolled back {}", name);
+          self.report.rolled_back.push(name.to_owned());
+        }
+        Err(e) => {
+          let warning = format!(
+            "failed to roll back {}: {}; the original is saved in {}",
+            name,
+            e,
+            bundle.dir().display()
+          );
+          tracing::error!("{}", warning);
+          self.report.warnings.push(warning);
+        }
+      }
+    }
+  }
+
+  fn run_step(&mut self, step: &FlashStep, deadline: &Option<(Instant, String)>) -> Result<FlashOutcome> {
+    match step {
+      FlashStep::Identify { variable } => self.identify(variable),
+      FlashStep::Bulkcmd { value } => self.bulkcmd(value),
+      FlashStep::BulkcmdStat {
+        value,
+        variable,
+        capture,
+      } => self.bulkcmd_stat(value, variable, capture),
+      FlashStep::Run { value } => self.run(value),
+      FlashStep::WriteSimpleMemory { value } => self.write_simple_memory(value),
+      FlashStep::WriteLargeMemory { value } => self.write_large_memory(value),
+      FlashStep::ReadSimpleMemory {
+        value,
+        variable,
+        output,
+      } => self.read_simple_memory(value, variable, output),
+      FlashStep::ReadLargeMemory {
+        value,
+        variable,
+        output,
+      } => self.read_large_memory(value, variable, output),
+      FlashStep::GetBootAMLC { variable } => self.get_boot_amlc(variable),
+      FlashStep::WriteAMLCData { value } => self.write_amlc_data(value),
+      FlashStep::Bl2Boot { value } => self.bl2_boot(value),
+      FlashStep::ValidatePartitionSize { value, variable } => self.validate_partition_size(value, variable),
+      FlashStep::RestorePartition { value } => self.restore_partition(value),
+      FlashStep::ApplyDelta { value } => self.apply_delta(value),
+      FlashStep::WriteBootPartition { value } => self.write_boot_partition(value),
+      FlashStep::WriteUserArea { value } => self.write_user_area(value),
+      FlashStep::WriteEnv { value } => self.write_env(value),
+      FlashStep::WriteBootScript { value } => self.write_boot_script(value),
+      FlashStep::Log { value } => self.log(value),
+      FlashStep::Wait { value } => self.wait(value),
+      FlashStep::Reset { value } => self.reset(value),
+      FlashStep::Reconnect { value } => self.reconnect(value),
+      FlashStep::Repeat { value } => self.repeat(value, deadline),
+      FlashStep::Assert { value } => self.assert(value),
+      FlashStep::Provision { value } => self.provision(value),
+      #[cfg(feature = "adb")]
+      FlashStep::PostBootCheck { value } => self.post_boot_check(value),
+      #[cfg(not(feature = "adb"))]
+      FlashStep::PostBootCheck { .. } => Err(Error::UnsupportedFeature(Box::new(step.to_owned()))),
+    }
+  }
+
+  fn begin_report(&mut self) {
+    self.report = FlashReport {
+      package: self.config.name.clone(),
+      version: self.config.version.clone(),
+      device_serial: self.aml.serial_number().map(str::to_owned),
+      started_at: Some(unix_now()),
+      steps_total: self.config.steps.len(),
+      steps_skipped: self.skipped.iter().filter(|skipped| **skipped).count(),
+      resources: resources::profile_info(self.aml.profile()),
+      ..Default::default()
+    };
+    for warning in plan::dependency_warnings(&self.config.steps, &self.skipped, &self.config.deferred) {
+      tracing::warn!("{}", warning);
+      self.report.warnings.push(warning);
+    }
+
+    let Some(serial) = &self.report.device_serial else {
+      return;
+    };
+    let Some(wear) = self
+      .wear_ledger
+      .as_deref()
+      .and_then(load_wear_ledger)
+      .and_then(|ledger| ledger.device(serial).cloned())
+    else {
+      return;
+    };
+
+    let package = format!("{}@{}", self.report.package, self.report.version);
+    if wear.last_package.as_deref() != Some(package.as_str()) {
+      return;
+    }
+
+    let full_writes = self
+      .config
+      .steps
+      .iter()
+      .zip(&self.skipped)
+      .filter(|(_, skipped)| !**skipped)
+      .flat_map(|(step, _)| step.walk())
+      .filter(|step| match step {
+        FlashStep::WriteLargeMemory { value } => !value.compare_before_write.unwrap_or(false),
+        FlashStep::RestorePartition { value } => !value.compare_before_write.unwrap_or(false),
+        FlashStep::WriteUserArea { value } => !value.compare_before_write.unwrap_or(false),
+        _ => false,
+      })
+      .count();
+    if full_writes > 0 {
+      let warning = format!(
+        "{} was already flashed to this device; {} write step(s) without compareBeforeWrite will rewrite unchanged data",
+        package, full_writes
+      );
+      tracing::warn!("{}", warning);
+      self.report.warnings.push(warning);
+    }
+  }
+
+  fn finish_report(&mut self, result: &Result<()>) {
+    let stats = self.aml.write_stats();
+    self.report.finished_at = Some(unix_now());
+    self.report.success = result.is_ok();
+    self.report.error = result.as_ref().err().map(|e| e.to_string());
+    self.report.error_code = result.as_ref().err().map(|e| e.code().to_string());
+    self.report.bytes_written = stats.bytes_written;
+    self.report.bytes_skipped = stats.bytes_skipped;
+
+    if self.report.device_serial.is_none() {
+      tracing::debug!("device did not report a serial number, not recording wear");
+      return;
+    }
+    let Some(path) = &self.wear_ledger else {
+      return;
+    };
+    let Some(mut ledger) = load_wear_ledger(path) else {
+      return;
+    };
+
+    let Some(wear) = ledger.record(&self.report) else {
+      return;
+    };
+    self.report.cumulative_bytes_written = Some(wear.bytes_written);
+    tracing::info!(
+      "wrote {} bytes this session, {} bytes to this device in total",
+      stats.bytes_written,
+      wear.bytes_written
+    );
+
+    if let Err(e) = ledger.save(path) {
+      tracing::warn!("failed to save wear ledger to {}: {}", path.display(), e);
+    }
+  }
+
+  /// Hand the finished report to the report hook and the package's `reportWebhook`
+  ///
+  /// Failing to submit it is only logged, since the flash itself is over by now.
+  fn submit_report(&self) {
+    if let Some(hook) = &self.report_hook {
+      hook(&self.report);
+    }
+    let Some(url) = &self.config.report_webhook else {
+      return;
+    };
+    #[cfg(feature = "http")]
+    {
+      tracing::info!("posting flash report to {}", url);
+      if let Err(e) = self.report.post(url) {
+        tracing::warn!("{}", e);
+      }
+    }
+    #[cfg(not(feature = "http"))]
+    tracing::warn!(
+      "not posting flash report to {}, flashthing was built without the http feature",
+      url
+    );
+  }
+
+  fn identify(&mut self, variable: &Option<String>) -> Result<FlashOutcome> {
+    tracing::debug!("running identify with variable {:?}", variable);
+    let start_time = std::time::Instant::now();
+    let result = self.aml.identify();
+    tracing::info!("secure boot: {:?}", self.aml.secure_boot_state());
+    let elapsed = start_time.elapsed();
+    tracing::trace!("identify completed in {:?}", elapsed);
+
+    // the version bytes, written the way other amlogic tools print them, e.g. `0-7-0-16-0-0-0-0`
+    let version = result?
+      .bytes()
+      .map(|byte| byte.to_string())
+      .collect::<Vec<_>>()
+      .join("-");
+    tracing::info!("identify: {}", version);
+    self.report.device_identity = Some(version.clone());
+    self.set_variable(variable, version.clone());
+    Ok(FlashOutcome::IdentifyResult(version))
+  }
+
+  fn bulkcmd(&self, value: &str) -> Result<FlashOutcome> {
+    tracing::debug!("running bulkcmd with value {:?}", value);
+    let start_time = std::time::Instant::now();
+    let result = self.aml.bulkcmd(value);
+    let elapsed = start_time.elapsed();
+    tracing::trace!("bulkcmd completed in {:?}", elapsed);
+    result?;
+    Ok(FlashOutcome::Normal)
+  }
+
+  fn bulkcmd_stat(&mut self, value: &str, variable: &Option<String>, capture: &Option<String>) -> Result<FlashOutcome> {
+    tracing::debug!(
+      "running bulkcmd_stat with value {:?} and variable {:?}",
+      value,
+      variable
+    );
+    let start_time = std::time::Instant::now();
+    let result = self.aml.bulkcmd(value);
+    let elapsed = start_time.elapsed();
+    tracing::trace!("bulkcmd_stat completed in {:?}", elapsed);
+    let response = result?;
+    tracing::info!("{}: {}", value, response);
+
+    if let Some(pattern) = capture {
+      let regex = variables::capture_regex(pattern)?;
+      let captures = regex.captures(&response).ok_or_else(|| {
+        Error::InvalidOperation(format!(
+          "response to `{}` does not match capture `{}`: {}",
+          value, pattern, response
+        ))
+      })?;
+      for name in regex.capture_names().flatten() {
+        if let Some(group) = captures.name(name) {
+          self.set_variable(&Some(name.to_owned()), group.as_str().to_owned());
+        }
+      }
+    }
+    self.set_variable(variable, response.clone());
+    Ok(FlashOutcome::BulkcmdStatResult(response))
+  }
+
+  /// Store the result of a step in `variable`, if the step names one
+  fn set_variable(&mut self, variable: &Option<String>, value: String) {
+    if let Some(name) = variable {
+      tracing::debug!("setting variable {} to {:?}", name, value);
+      self.variables.insert(name.clone(), value);
+    }
+  }
+
+  fn assert(&self, value: &AssertValue) -> Result<FlashOutcome> {
+    tracing::debug!("running assert with value {:?}", value);
+    let (subject, actual) = match (&value.variable, &value.bulkcmd) {
+      (Some(name), _) => {
+        let actual = self.variables.get(name).ok_or_else(|| {
+          Error::AssertionFailed(format!(
+            "variable `{}` is not set, give it to identify or bulkcmdStat first",
+            name
+          ))
+        })?;
+        (format!("variable `{}`", name), actual.clone())
+      }
+      (None, Some(command)) => (format!("`{}`", command), self.aml.bulkcmd(command)?),
+      (None, None) => return Err(Error::InvalidOperation("assert needs a variable or a bulkcmd".into())),
+    };
+
+    let Some((passed, expected)) = expect(&actual, &value.equals, &value.contains, &value.matches)? else {
+      return Err(Error::InvalidOperation(
+        "assert needs equals, contains or matches".into(),
+      ));
+    };
+    if passed {
+      tracing::info!("{} is \"{}\" as expected", subject, actual);
+      return Ok(FlashOutcome::Normal);
+    }
+
+    let detail = format!("{} is \"{}\", expected it {}", subject, actual, expected);
+    Err(Error::AssertionFailed(match &value.message {
+      Some(message) => format!("{} ({})", message, detail),
+      None => detail,
+    }))
+  }
+
+  fn run(&self, value: &RunValue) -> Result<FlashOutcome> {
+    tracing::debug!("running run with value {:?}", value);
+    let start_time = std::time::Instant::now();
+    let result = self.aml.run(value.address.get(), value.keep_power);
+    let elapsed = start_time.elapsed();
+    tracing::trace!("run completed in {:?}", elapsed);
+    result?;
+    Ok(FlashOutcome::Normal)
+  }
+
+  fn write_simple_memory(&mut self, value: &WriteSimpleMemoryValue) -> Result<FlashOutcome> {
+    tracing::debug!("running write_simple_memory with value {:?}", value);
+    let data = self.handle_data_or_file(&value.data)?;
+
+    let start_time = std::time::Instant::now();
+    let result = self.aml.write_simple_memory(value.address.get(), &data);
+    let elapsed = start_time.elapsed();
+    tracing::trace!("write_simple_memory completed in {:?}", elapsed);
+
+    result?;
+    Ok(FlashOutcome::Normal)
+  }
+
+  fn write_large_memory(&mut self, value: &WriteLargeMemoryValue) -> Result<FlashOutcome> {
+    tracing::debug!("running write_large_memory with value {:?}", value);
+    let start_time = std::time::Instant::now();
+
+    let (address, room) = match (&value.address, &value.partition) {
+      (Some(address), None) => (address.get(), None),
+      (None, Some(partition)) => {
+        let offset = value.offset_in_partition.map_or(0, |offset| offset.get() as usize);
+        let (address, room) = self.partition_address(partition, offset)?;
+        (address, Some((partition, room)))
+      }
+      _ => {
+        return Err(Error::InvalidOperation(
+          "writeLargeMemory needs either an address or a partition".into(),
+        ));
+      }
+    };
+
+    let (file_size, mut file) =
+      patched_stream(&value.data, value.patches.as_deref(), &mut self.mode, self.path_policy)?;
+    if let Some((partition, room)) = room
+      && file_size > room
+      && !value.allow_cross_partition.unwrap_or(false)
+    {
+      return Err(Error::InvalidOperation(format!(
+        "{} bytes do not fit in partition {}, which has {} bytes from the write offset",
+        file_size, partition, room
+      )));
+    }
+
+    let progress_callback = progress_callback(&self.callback, &self.step_id);
+
+    self.aml.write_large_memory_to_disk(
+      address,
+      &mut file,
+      file_size,
+      value.block_length,
+      value.append_zeros.unwrap_or(true),
+      value.compare_before_write.unwrap_or(false),
+      progress_callback,
+    )?;
+
+    let elapsed = start_time.elapsed();
+    tracing::trace!("write_large_memory completed in {:?}", elapsed);
+    Ok(FlashOutcome::Normal)
+  }
+
+  /// Disk address of `offset` bytes into a partition, and how many bytes fit from there
+  fn partition_address(&self, partition: &str, offset: usize) -> Result<(u32, usize)> {
+    let part_name = self.aml.profile().partitions.resolve(partition)?.name.to_string();
+    let (part_size, part_offset) = match self.validate_partition_size(
+      &ValidatePartitionSizeValue {
+        name: part_name.clone(),
+      },
+      &None,
+    )? {
+      FlashOutcome::ValidatePartitionResult(Some(size), Some(offset)) => (size, offset),
+      _ => return Err(Error::InvalidOperation("Failed to validate partition size!".into())),
+    };
+
+    if offset >= part_size {
+      return Err(Error::InvalidOperation(format!(
+        "offset {:#x} is past the end of partition {} ({} bytes)",
+        offset, part_name, part_size
+      )));
+    }
+
+    let address = part_offset * PART_SECTOR_SIZE + offset;
+    tracing::debug!("writing {} at {:#x} on disk", part_name, address);
+    let address = u32::try_from(address)
+      .map_err(|_| Error::InvalidOperation(format!("{} is past the 4GiB a disk address can reach", part_name)))?;
+    Ok((address, part_size - offset))
+  }
+
+  fn read_simple_memory(
+    &mut self,
+    value: &ReadMemoryValue,
+    variable: &Option<String>,
+    output: &Option<StepOutput>,
+  ) -> Result<FlashOutcome> {
+    tracing::debug!(
+      "running read_simple_memory with value {:?} and variable {:?}",
+      value,
+      variable
+    );
+    let start_time = std::time::Instant::now();
+    let result = self
+      .aml
+      .read_simple_memory(value.address.get(), value.length.get() as usize);
+    let elapsed = start_time.elapsed();
+    tracing::trace!("read_simple_memory completed in {:?}", elapsed);
+    let data = result?;
+    self.store_read(&data, variable, output)?;
+    Ok(FlashOutcome::ReadResult(data))
+  }
+
+  fn read_large_memory(
+    &mut self,
+    value: &ReadMemoryValue,
+    variable: &Option<String>,
+    output: &Option<StepOutput>,
+  ) -> Result<FlashOutcome> {
+    tracing::debug!(
+      "running read_large_memory with value {:?} and variable {:?}",
+      value,
+      variable
+    );
+    let start_time = std::time::Instant::now();
+    let result = self.aml.read_memory(value.address.get(), value.length.get() as usize);
+    let elapsed = start_time.elapsed();
+    tracing::trace!("read_large_memory completed in {:?}", elapsed);
+    let data = result?;
+    self.store_read(&data, variable, output)?;
+    Ok(FlashOutcome::ReadResult(data))
+  }
+
+  /// Keep what a read step read in its `variable`, hex encoded, and its `output` file
+  fn store_read(&mut self, data: &[u8], variable: &Option<String>, output: &Option<StepOutput>) -> Result<()> {
+    self.set_variable(variable, hex::encode(data));
+    let Some(output) = output else {
+      return Ok(());
+    };
+
+    let path = self.output_dir.join(normalize_file_path(&output.file_path)?);
+    if let Some(parent) = path.parent() {
+      std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, data)?;
+    tracing::info!("wrote {} bytes to {}", data.len(), path.display());
+    self.record_artifact(path);
+    Ok(())
+  }
+
+  fn get_boot_amlc(&self, variable: &Option<String>) -> Result<FlashOutcome> {
+    tracing::debug!("running get_boot_amlc with variable {:?}", variable);
+    let start_time = std::time::Instant::now();
+    let result = self.aml.get_boot_amlc();
+    let elapsed = start_time.elapsed();
+    tracing::trace!("get_boot_amlc completed in {:?}", elapsed);
+    let (length, offset) = result?;
+    Ok(FlashOutcome::GetBootAMLCResult(length, offset))
+  }
+
+  fn write_amlc_data(&mut self, value: &WriteAMLCDataValue) -> Result<FlashOutcome> {
+    tracing::debug!("running write_amlc_data with value {:?}", value);
+    let data = self.handle_data_or_file(&value.data)?;
+
+    let start_time = std::time::Instant::now();
+    let result = self.aml.write_amlc_data_packet(value.seq, value.amlc_offset, &data);
+    let elapsed = start_time.elapsed();
+    tracing::trace!("write_amlc_data completed in {:?}", elapsed);
+
+    result?;
+    Ok(FlashOutcome::Normal)
+  }
+
+  fn bl2_boot(&mut self, value: &BL2BootValue) -> Result<FlashOutcome> {
+    tracing::debug!("running bl2_boot with value {:?}", value);
+    let bl2 = self.handle_data_or_file(&value.bl2)?;
+    let bootloader = self.handle_data_or_file(&value.bootloader)?;
+
+    let start_time = std::time::Instant::now();
+    let result = self.aml.bl2_boot(Some(&bl2), Some(&bootloader));
+    let elapsed = start_time.elapsed();
+    tracing::trace!("bl2_boot completed in {:?}", elapsed);
+
+    result?;
+    Ok(FlashOutcome::Normal)
+  }
+
+  fn validate_partition_size(
+    &self,
+    value: &ValidatePartitionSizeValue,
+    variable: &Option<String>,
+  ) -> Result<FlashOutcome> {
+    tracing::debug!(
+      "running validate_partition_size with value {:?} and variable {:?}",
+      value,
+      variable
+    );
+
+    let part_info = match self.aml.profile().partitions.resolve(&value.name) {
+      Ok(info) => info,
+      Err(e) => {
+        tracing::error!("Error: {}", e);
+        return Ok(FlashOutcome::ValidatePartitionResult(None, None));
+      }
+    };
+
+    let allow_special = self.config.allow_special_partitions.unwrap_or(false);
+    match self
+      .aml
+      .validate_partition_size(&part_info.name, part_info, allow_special)
+    {
+      Ok(part_size) => {
+        let part_offset = part_info.offset;
+        Ok(FlashOutcome::ValidatePartitionResult(
+          Some(part_size),
+          Some(part_offset),
+        ))
+      }
+      Err(_) => Ok(FlashOutcome::ValidatePartitionResult(None, None)),
+    }
+  }
+
+  fn restore_partition(&mut self, value: &RestorePartitionValue) -> Result<FlashOutcome> {
+    tracing::debug!("running restore_partition with value {:?}", value);
+
+    let part_name = &self.aml.profile().partitions.resolve(&value.name)?.name.to_string();
+    let validate_result = match self.validate_partition_size(
+      &ValidatePartitionSizeValue {
+        name: part_name.clone(),
+      },
+      &None,
+    )? {
+      FlashOutcome::ValidatePartitionResult(size, offset) => (size, offset),
+      _ => (None, None),
+    };
+
+    let (part_size, part_offset) = match validate_result {
+      (Some(size), Some(offset)) => (size, offset),
+      _ => return Err(Error::InvalidOperation("Failed to validate partition size!".into())),
+    };
+
+    let patches = value.patches.as_deref();
+    let (mut file_size, mut file_reader) = patched_stream(&value.data, patches, &mut self.mode, self.path_policy)?;
+    let full_size = file_size;
+    let mut padding = None;
+    if value.trim_padding.unwrap_or(false) && part_name != "bootloader" && file_size <= part_size {
+      padding = padding::find_padding(file_reader)?;
+      (_, file_reader) = patched_stream(&value.data, patches, &mut self.mode, self.path_policy)?;
+      if let Some(padding) = padding {
+        tracing::info!(
+          "trimming {} bytes of {:#04x} padding from the end of the image for {}",
+          file_size - padding.data_len,
+          padding.byte,
+          part_name
+        );
+        file_size = padding.data_len;
+      }
+    }
+
+    let progress_callback = progress_callback(&self.callback, &self.step_id);
+
+    // only reachable with allowSpecialPartitions; amlmmc refuses it, so write by raw offset
+    if part_name == "reserved" {
+      if !value.confirm_special_partition.unwrap_or(false) {
+        return Err(Error::InvalidOperation(
+          "writing the reserved partition also requires confirmSpecialPartition on the step".into(),
+        ));
+      }
+      if file_size > part_size {
+        return Err(Error::InvalidOperation(format!(
+          "image is {} bytes but the reserved partition is only {} bytes",
+          file_size, part_size
+        )));
+      }
+
+      tracing::warn!("writing {} bytes to the reserved partition", file_size);
+      self.aml.write_user_area(
+        part_offset as u32,
+        file_reader,
+        file_size,
+        value.compare_before_write.unwrap_or(false),
+        progress_callback,
+      )?;
+    } else {
+      self.aml.restore_partition(
+        part_name,
+        part_size,
+        file_reader,
+        file_size,
+        value.compare_before_write.unwrap_or(false),
+        progress_callback,
+      )?;
+    }
+
+    if let Some(padding) = padding.filter(|_| value.erase_trimmed.unwrap_or(false)) {
+      let lba = part_offset + file_size / PART_SECTOR_SIZE;
+      let sectors = full_size.div_ceil(PART_SECTOR_SIZE) - file_size / PART_SECTOR_SIZE;
+      self.aml.erase_user_area(lba as u32, sectors as u32)?;
+
+      // cards erase to zeros or to 0xff, so check which this one does before trusting it
+      let erased = self.aml.read_disk_chunk(lba, PART_SECTOR_SIZE)?;
+      if erased.iter().any(|byte| *byte != padding.byte) {
+        let warning = format!(
+          "erased sectors of {} read back as {:#04x}, not the {:#04x} padding; writing the padding instead",
+          part_name, erased[0], padding.byte
+        );
+        tracing::warn!("{}", warning);
+        self.report.warnings.push(warning);
+        let length = sectors * PART_SECTOR_SIZE;
+        self.aml.write_user_area(
+          lba as u32,
+          io::repeat(padding.byte).take(length as u64),
+          length,
+          false,
+          |_| {},
+        )?;
+      }
+    }
+
+    Ok(FlashOutcome::Normal)
+  }
+
+  fn apply_delta(&mut self, value: &ApplyDeltaValue) -> Result<FlashOutcome> {
+    tracing::debug!("running apply_delta with value {:?}", value);
+
+    let part_name = &self.aml.profile().partitions.resolve(&value.name)?.name.to_string();
+    if part_name == "reserved" {
+      return Err(Error::InvalidOperation(
+        "deltas cannot be applied to the reserved partition".into(),
+      ));
+    }
+
+    let part_size = match self.validate_partition_size(
+      &ValidatePartitionSizeValue {
+        name: part_name.clone(),
+      },
+      &None,
+    )? {
+      FlashOutcome::ValidatePartitionResult(Some(size), Some(_)) => size,
+      _ => return Err(Error::InvalidOperation("Failed to validate partition size!".into())),
+    };
+
+    let delta = self.handle_data_or_file(&value.delta)?;
+
+    let progress_callback = progress_callback(&self.callback, &self.step_id);
+
+    let start_time = std::time::Instant::now();
+    self
+      .aml
+      .apply_partition_delta(part_name, part_size, &delta, progress_callback)?;
+    tracing::trace!("apply_delta completed in {:?}", start_time.elapsed());
+
+    Ok(FlashOutcome::Normal)
+  }
+
+  fn write_boot_partition(&mut self, value: &WriteBootPartitionValue) -> Result<FlashOutcome> {
+    tracing::debug!("running write_boot_partition with value {:?}", value);
+    let mut data = self.handle_data_or_file(&value.data)?;
+    if let Some(patches) = &value.patches {
+      let patches = patches::decode_patches(patches, data.len() as u64)?;
+      patches::apply(&mut data, 0, &patches);
+    }
+
+    let start_time = std::time::Instant::now();
+    self.aml.write_boot_partition(value.hwpart, &data)?;
+    tracing::trace!("write_boot_partition completed in {:?}", start_time.elapsed());
+
+    Ok(FlashOutcome::Normal)
+  }
+
+  fn write_user_area(&mut self, value: &WriteUserAreaValue) -> Result<FlashOutcome> {
+    tracing::debug!("running write_user_area with value {:?}", value);
+    let (file_size, file) = patched_stream(&value.data, value.patches.as_deref(), &mut self.mode, self.path_policy)?;
+
+    let progress_callback = progress_callback(&self.callback, &self.step_id);
+
+    let start_time = std::time::Instant::now();
+    self.aml.write_user_area(
+      value.lba,
+      file,
+      file_size,
+      value.compare_before_write.unwrap_or(false),
+      progress_callback,
+    )?;
+    tracing::trace!("write_user_area completed in {:?}", start_time.elapsed());
+
+    Ok(FlashOutcome::Normal)
+  }
+
+  fn write_env(&mut self, value: &StringOrFile) -> Result<FlashOutcome> {
+    tracing::debug!("running write_env with value {:?}", value);
+
+    let mut env_data = self.handle_string_or_file(value)?;
+    // a string value already had its variables substituted when the step was loaded
+    if let StringOrFile::File(file) = value {
+      env_data = self.file_text(file, &env_data)?;
+    }
+    let env = self.parse_env(value, &env_data)?;
+    if self.diff_env {
+      self.record_env_changes(&env);
+    }
+
+    self.import_env(&env_data)
+  }
+
+  /// Parse the text of a `writeEnv` step and check it fits in the device's environment once saved
+  fn parse_env(&self, value: &StringOrFile, text: &str) -> Result<UBootEnv> {
+    let name = match value {
+      StringOrFile::File(file) => file.file_path.as_str(),
+      StringOrFile::String(_) => "writeEnv",
+    };
+    let env = UBootEnv::parse_text(name, text)?;
+    // the saved environment is CONFIG_ENV_SIZE, far smaller than the env partition it is saved to
+    env.check_size(name, self.aml.profile().env_size)?;
+    Ok(env)
+  }
+
+  /// Record in the report which variables of `env` change the device's environment, and what they held
+  fn record_env_changes(&mut self, env: &UBootEnv) {
+    for (name, after) in env.iter() {
+      // printenv fails for a variable that is not set
+      let before = self
+        .aml
+        .bulkcmd(&format!("printenv {}", name))
+        .ok()
+        .and_then(|response| {
+          let (_, value) = response.split_once(&format!("{}=", name))?;
+          Some(value.trim_end_matches(['\0', '\r', '\n']).to_owned())
+        });
+      if before.as_deref() != Some(after) {
+        tracing::info!("env {}: {:?} -> {:?}", name, before, after);
+        self.report.env_changes.push(EnvChange {
+          name: name.to_owned(),
+          before,
+          after: after.to_owned(),
+        });
+      }
+    }
+  }
+
+  /// Substitute variables into text read from `file`, rendering it first if it is a template
+  ///
+  /// Templates can also use the config's constants, in hex.
+  fn file_text(&self, file: &MetaFile, text: &str) -> Result<String> {
+    if !file.template.unwrap_or(false) {
+      return Ok(variables::substitute_text(text, &self.variables));
+    }
+    let mut values = self.variables.clone();
+    for (name, value) in self.config.constants.iter().flatten() {
+      values.entry(name.clone()).or_insert_with(|| format!("{value:#x}"));
+    }
+    template::render(&file.file_path, text, &values)
+  }
+
+  fn write_boot_script(&mut self, value: &WriteBootScriptValue) -> Result<FlashOutcome> {
+    tracing::debug!("running write_boot_script with value {:?}", value);
+
+    let mut script = self.handle_string_or_file(&value.script)?;
+    if let StringOrFile::File(file) = &value.script {
+      script = self.file_text(file, &script)?;
+    }
+    let mut image = bootscript::script_image(
+      &script,
+      value.name.as_deref().unwrap_or(bootscript::DEFAULT_SCRIPT_NAME),
+      value.arch.unwrap_or_default(),
+    );
+    // mmc write works in whole sectors, and U-Boot reads only as much as the header says
+    image.resize(image.len().next_multiple_of(PART_SECTOR_SIZE), 0);
+
+    let offset = value.offset_in_partition.map_or(0, |offset| offset.get() as usize);
+    if !offset.is_multiple_of(PART_SECTOR_SIZE) {
+      return Err(Error::InvalidOperation(format!(
+        "boot script offset {:#x} is not a multiple of the {} byte sector size",
+        offset, PART_SECTOR_SIZE
+      )));
+    }
+    let (address, room) = self.partition_address(&value.partition, offset)?;
+    if image.len() > room {
+      return Err(Error::InvalidOperation(format!(
+        "{} byte boot script does not fit in partition {}, which has {} bytes from the write offset",
+        image.len(),
+        value.partition,
+        room
+      )));
+    }
+
+    let progress_callback = progress_callback(&self.callback, &self.step_id);
+    let start_time = std::time::Instant::now();
+    self.aml.write_large_memory_to_disk(
+      address,
+      &mut Cursor::new(&image),
+      image.len(),
+      PART_SECTOR_SIZE,
+      true,
+      false,
+      progress_callback,
+    )?;
+    tracing::trace!("write_boot_script completed in {:?}", start_time.elapsed());
+
+    Ok(FlashOutcome::Normal)
+  }
+
+  /// Send `env_data`, `name=value` lines, to the device and import it into the U-Boot environment
+  fn import_env(&mut self, env_data: &str) -> Result<FlashOutcome> {
+    let start_time = std::time::Instant::now();
+    self.aml.import_env(env_data)?;
+
+    let elapsed = start_time.elapsed();
+    tracing::trace!("write_env completed in {:?}", elapsed);
+
+    Ok(FlashOutcome::Normal)
+  }
+
+  /// Boot the device, wait for adb to reach it and run each check's command, recording the results in the report
+  ///
+  /// Every check runs even if an earlier one failed, so the report shows the whole picture; the
+  /// step then fails if any of them did.
+  #[cfg(feature = "adb")]
+  fn post_boot_check(&mut self, value: &crate::config::PostBootCheckValue) -> Result<FlashOutcome> {
+    tracing::debug!("running postBootCheck with value {:?}", value);
+    if self.aml.session_valid() {
+      tracing::info!("resetting the device to run checks on it once it boots");
+      self.aml.reset(crate::config::ResetMode::Soft)?;
+    }
+    let adb = self.adb.clone().unwrap_or_default();
+    let timeout = Duration::from_millis(value.timeout.unwrap_or(POST_BOOT_TIMEOUT_MS));
+    crate::adb::wait_for_adb(&adb, timeout)?;
+
+    let mut failures = Vec::new();
+    for check in &value.checks {
+      let (succeeded, output) = adb.shell_status(&check.command)?;
+      let output = output.trim().to_owned();
+      let (passed, expected) = match expect(&output, &check.equals, &check.contains, &check.matches)? {
+        Some(result) => result,
+        None => (succeeded, "to exit successfully".to_owned()),
+      };
+      if passed {
+        tracing::info!("`{}` gave \"{}\" as expected", check.command, output);
+      } else {
+        let detail = format!("`{}` gave \"{}\", expected it {}", check.command, output, expected);
+        tracing::error!("{}", detail);
+        failures.push(match &check.message {
+          Some(message) => format!("{} ({})", message, detail),
+          None => detail,
+        });
+      }
+      self.report.post_boot_checks.push(crate::report::PostBootCheckResult {
+        command: check.command.clone(),
+        output,
+        passed,
+      });
+    }
+
+    if !failures.is_empty() {
+      return Err(Error::AssertionFailed(failures.join("; ")));
+    }
+    Ok(FlashOutcome::Normal)
+  }
+
+  fn provision(&mut self, value: &ProvisionValue) -> Result<FlashOutcome> {
+    tracing::debug!("running provision with value {:?}", value);
+    let Some(provisioner) = self.provisioner.clone() else {
+      return Err(Error::InvalidOperation(
+        "provision needs a provisioner, such as a counter file, to be given to the flasher".into(),
+      ));
+    };
+
+    let request = ProvisionRequest {
+      step_id: self.step_id.clone().unwrap_or_default(),
+      device_serial: self.report.device_serial.clone(),
+      variables: value.variables.clone(),
+    };
+    let values = provisioner.provision(&request)?;
+    tracing::info!("provisioning device with {:?}", values);
+    self.variables.extend(values);
+
+    if let Some(env) = &value.env {
+      let env_data = env
+        .iter()
+        .map(|(name, value)| format!("{}={}\n", name, variables::substitute_text(value, &self.variables)))
+        .collect::<String>();
+      self.import_env(&env_data)?;
+    }
+
+    if let Some(data) = &value.data {
+      // the disk is written in whole sectors, so the rest of the last one is zeroed
+      let mut text = variables::substitute_text(&data.text, &self.variables).into_bytes();
+      text.resize(text.len().next_multiple_of(PART_SECTOR_SIZE), 0);
+      let offset = data.offset_in_partition.map_or(0, |offset| offset.get() as usize);
+      let (address, room) = self.partition_address(&data.partition, offset)?;
+      if text.len() > room {
+        return Err(Error::InvalidOperation(format!(
+          "{} bytes do not fit in partition {}, which has {} bytes from the write offset",
+          text.len(),
+          data.partition,
+          room
+        )));
+      }
+      self.aml.write_large_memory_to_disk(
+        address,
+        &mut text.as_slice(),
+        text.len(),
+        self.aml.profile().transfer_block_size,
+        true,
+        false,
+        progress_callback(&self.callback, &self.step_id),
+      )?;
+    }
+
+    // only hand out the next values once these are written, so a failed step can try them again
+    provisioner.advance()?;
+    Ok(FlashOutcome::Normal)
+  }
+
+  fn log(&self, value: &str) -> Result<FlashOutcome> {
+    tracing::debug!("running log with value {:?}", value);
+    tracing::info!(">> {:?}", value);
+    Ok(FlashOutcome::Normal)
+  }
+
+  fn wait(&self, value: &WaitValue) -> Result<FlashOutcome> {
+    tracing::debug!("running wait with value {:?}", value);
+    match value {
+      WaitValue::UserInput { message } => return Ok(FlashOutcome::AwaitUserInput(message.clone())),
+      WaitValue::Time { time } => self.aml.pause(Duration::from_millis(*time))?,
+    }
+    Ok(FlashOutcome::Normal)
+  }
+
+  fn reset(&self, value: &ResetValue) -> Result<FlashOutcome> {
+    tracing::debug!("running reset with value {:?}", value);
+    self.aml.reset(value.mode)?;
+    Ok(FlashOutcome::Normal)
+  }
+
+  /// Run the steps of a `repeat` step `count` times, recording failed iterations in the report
+  ///
+  /// A failed step ends its iteration, unless it has `onError: "continue"`, and the next iteration
+  /// starts. Once every iteration has run, the `repeat` step fails if any of them did.
+  fn repeat(&mut self, value: &RepeatValue, deadline: &Option<(Instant, String)>) -> Result<FlashOutcome> {
+    tracing::debug!(
+      "running repeat {} times with {} step(s)",
+      value.count,
+      value.steps.len()
+    );
+    let repeat_id = self.step_id.clone();
+    let mut result = RepeatResult {
+      id: repeat_id.clone().unwrap_or_default(),
+      ..Default::default()
+    };
+
+    let mut aborted = None;
+    'iterations: for iteration in 1..=value.count {
+      tracing::info!("repeat {}: iteration {}/{}", result.id, iteration, value.count);
+      result.iterations = iteration;
+      for entry in &value.steps {
+        let Step {
+          id,
+          timeout_ms,
+          on_error,
+          ..
+        } = entry;
+        self.step_id = id.clone();
+        let on_error = on_error.unwrap_or_default();
+        let attempt = match self.with_variables(entry) {
+          Ok(entry) => self.attempt_with_retries(&entry.step, *timeout_ms, on_error, deadline),
+          Err(err) => (Err(err), 1),
+        };
+        let (Err(err), _) = attempt else {
+          continue;
+        };
+        if deadline_passed(deadline) {
+          aborted = Some(err);
+          break 'iterations;
+        }
+
+        let step_id = id.clone().unwrap_or_default();
+        if on_error == OnError::Continue {
+          let warning = format!(
+            "step {} failed in iteration {} of repeat {} and was skipped: {}",
+            step_id, iteration, result.id, err
+          );
+          tracing::warn!("{}", warning);
+          self.report.warnings.push(warning);
+          continue;
+        }
+        tracing::warn!(
+          "iteration {} of repeat {} failed at step {}: {}",
+          iteration,
+          result.id,
+          step_id,
+          err
+        );
+        result.failed += 1;
+        result.failures.push(IterationFailure {
+          iteration,
+          step_id,
+          error: err.to_string(),
+          error_code: err.code().to_string(),
+        });
+        continue 'iterations;
+      }
+    }
+
+    self.step_id = repeat_id;
+    let summary = format!(
+      "{} of {} iterations of repeat {} failed",
+      result.failed, result.iterations, result.id
+    );
+    let failed = result.failed > 0;
+    self.report.repeats.push(result);
+    match aborted {
+      Some(err) => Err(err),
+      None if failed => Err(Error::InvalidOperation(summary)),
+      None => Ok(FlashOutcome::Normal),
+    }
+  }
+
+  fn reconnect(&mut self, value: &ReconnectValue) -> Result<FlashOutcome> {
+    tracing::debug!("running reconnect with value {:?}", value);
+    if self.aml.session_valid() {
+      tracing::info!("usb session is still valid, keeping the current connection");
+      return Ok(FlashOutcome::Normal);
+    }
+
+    let timeout = value.timeout.map(Duration::from_millis).unwrap_or(RECONNECT_TIMEOUT);
+    self.aml = self.aml.reconnect(timeout)?;
+    Ok(FlashOutcome::Normal)
+  }
+
+  fn handle_data_or_file(&mut self, data_or_file: &DataOrFile) -> Result<Vec<u8>> {
+    tracing::debug!("handling data or file {:?}", data_or_file);
+    match data_or_file {
+      DataOrFile::Data(data) => Ok(data.to_owned()),
+      DataOrFile::Hex { hex } => decode_hex(hex),
+      DataOrFile::Base64 { base64 } => decode_base64(base64),
+      DataOrFile::Concat { .. } => {
+        let (size, mut reader) = handle_data_or_file_stream(data_or_file, &mut self.mode, self.path_policy)?;
+        let mut data = Vec::with_capacity(size);
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+      }
+      DataOrFile::File(meta_file) => {
+        let data = match &mut self.mode {
+          FlashMode::Standalone => {
+            tracing::warn!("trying to read a file in standalone mode!!");
+            let (_, mut file) = open_joined(&PathBuf::from(&meta_file.file_path))?;
+            let mut data = vec![];
+            file.read_to_end(&mut data)?;
+            data
+          }
+          FlashMode::Directory(path) => {
+            let (_, mut file) = open_joined(&resolve_in_directory(path, &meta_file.file_path, self.path_policy)?)?;
+            let mut data = vec![];
+            file.read_to_end(&mut data)?;
+            data
+          }
+          FlashMode::Archive(zip, root, _) => {
+            tracing::warn!("reading whole file into memory! is this what you want??");
+            let mut found = zip.by_index(resolve_in_archive(zip, root, &meta_file.file_path, self.path_policy)?)?;
+            let mut data = vec![];
+            found.read_to_end(&mut data)?;
+            data
+          }
+        };
+        meta_file.contents(data)
+      }
+    }
+  }
+
+  fn handle_string_or_file(&mut self, string_or_file: &StringOrFile) -> Result<String> {
+    tracing::debug!("handling string or file {:?}", string_or_file);
+    match string_or_file {
+      StringOrFile::String(data) => Ok(data.clone()),
+      StringOrFile::File(file) => {
+        let data = match &mut self.mode {
+          FlashMode::Standalone => {
+            tracing::warn!("trying to read a string file in standalone mode");
+            let path = PathBuf::from(&file.file_path);
+            std::fs::read(path)?
+          }
+          FlashMode::Directory(base_path) => {
+            let path = resolve_in_directory(base_path, &file.file_path, self.path_policy)?;
+            std::fs::read(path)?
+          }
+          FlashMode::Archive(zip, root, _) => {
+            let mut zip_file = zip.by_index(resolve_in_archive(zip, root, &file.file_path, self.path_policy)?)?;
+            let mut data = vec![];
+            zip_file.read_to_end(&mut data)?;
+            data
+          }
+        };
+        Ok(String::from_utf8(file.contents(data)?)?)
+      }
+    }
+  }
+
+  /// get the report for the most recent flash. it is finalized when `flash` returns, or `step` returns
+  /// [`FlashOutcome::Complete`] or an error, even on failure
+  pub fn report(&self) -> &FlashReport {
+    &self.report
+  }
+
+  /// get the files the most recent flash produced: read step outputs, partition backups, the
+  /// report and checkpoint written to the [artifacts directory](FlasherBuilder::artifacts_dir),
+  /// and the [session capture](Self::enable_session_capture)
+  pub fn artifacts(&self) -> &[PathBuf] {
+    &self.artifacts
+  }
+
+  /// Keep every log line from here on, down to trace level, and once flashing finishes write them
+  /// to a zip at `path` for a bug report
+  ///
+  /// Along with the log, the zip holds the [`FlashReport`], the package's `meta.json`, the size
+  /// and SHA-256 of each file the package refers to but not the files themselves, the host's OS,
+  /// architecture and flashthing version, and the USB descriptors of the device. Only logs from
+  /// the thread flashing are kept. The zip is listed in [`artifacts`](Self::artifacts).
+  pub fn enable_session_capture(&mut self, path: impl Into<PathBuf>) {
+    let capture = SessionCapture::new(path.into());
+    let inner = (self.log_mirror.clone()).unwrap_or_else(|| tracing::dispatcher::get_default(tracing::Dispatch::clone));
+    self.log_mirror = Some(capture.dispatch(inner));
+    self.capture = Some(capture);
+  }
+
+  /// get the total number of steps in the flash config
+  pub fn num_steps(&self) -> usize {
+    self.config.steps.len()
+  }
+
+  /// get the steps of the flash config in the order they run, each with its `id`
+  pub fn steps(&self) -> &[Step] {
+    &self.config.steps
+  }
+
+  /// get the variables set by the most recent flash, starting from the config's `variables`
+  pub fn variables(&self) -> &HashMap<String, String> {
+    &self.variables
+  }
+
+  /// get current step in the flashing process
+  pub fn current_step(&self) -> usize {
+    self.step + 1
+  }
+
+  /// Create a builder for a Flasher with non-default options, such as a device profile
+  pub fn builder() -> FlasherBuilder {
+    FlasherBuilder::default()
+  }
+
+  /// Create a new Flasher where the flash files are relative to the `cwd`.
+  /// `path` MUST be the path to a directory.
+  ///
+  /// NOTE: Car Thing is expected to be plugged in at time of creation.
+  ///
+  /// # Parameters
+  /// - `path`: [PathBuf] path to a directory
+  pub fn from_directory(path: PathBuf, callback: Option<Callback>) -> Result<Self> {
+    Self::builder().callback(callback).from_directory(path)
+  }
+
+  /// Create a new Flasher where the zip archive is relative to the `cwd`.
+  /// `path` MUST be the path to a zip archive.
+  ///
+  /// NOTE: Car Thing is expected to be plugged in at time of creation.
+  ///
+  /// # Parameters
+  /// - `path`: [PathBuf] path to the zip archive
+  pub fn from_archive(path: PathBuf, callback: Option<Callback>) -> Result<Self> {
+    Self::builder().callback(callback).from_archive(path)
+  }
+
+  /// Create a new Flasher from a standalone `meta.json`.
+  /// This type of flasher will attempt to access files relative to cwd.
+  ///
+  /// NOTE: Car Thing is expected to be plugged in at time of creation.
+  ///
+  /// # Parameters
+  /// - `meta`: [String] stringified json
+  pub fn from_json(meta: String, callback: Option<Callback>) -> Result<Self> {
+    Self::builder().callback(callback).from_json(meta)
+  }
+
+  /// Create a new Flasher where the flash files are relative to the `cwd`.
+  /// `path` MUST be the path to a directory. This can only be used for stock flashing.
+  ///
+  /// NOTE: Car Thing is expected to be plugged in at time of creation.
+  ///
+  /// # Parameters
+  /// - `path`: [PathBuf] path to a directory
+  pub fn from_stock_directory(path: PathBuf, callback: Option<Callback>) -> Result<Self> {
+    Self::builder().callback(callback).from_stock_directory(path)
+  }
+
+  /// Create a new Flasher where the zip archive is relative to the `cwd`.
+  /// `path` MUST be the path to a zip archive. This can only be used for stock flashing.
+  ///
+  /// NOTE: Car Thing is expected to be plugged in at time of creation.
+  ///
+  /// # Parameters
+  /// - `path`: [PathBuf] path to the zip archive
+  pub fn from_stock_archive(path: PathBuf, callback: Option<Callback>) -> Result<Self> {
+    Self::builder().callback(callback).from_stock_archive(path)
   }
+}
 
-  fn restore_partition(&mut self, value: &RestorePartitionValue) -> Result<FlashOutcome> {
-    tracing::debug!("running restore_partition with value {:?}", value);
+/// Builder for a [`Flasher`]
+///
+/// Options are set first, then one of the `from_*` methods loads the flash package and
+/// connects to the device.
+#[derive(Default)]
+pub struct FlasherBuilder {
+  callback: Option<Callback>,
+  profile: DeviceProfile,
+  target: DeviceTarget,
+  usb_log_level: Option<UsbLogLevel>,
+  mirror_logs: Option<tracing::Level>,
+  verbosity: Option<Verbosity>,
+  thermal_policy: Option<ThermalPolicy>,
+  usb_quirks: Option<UsbQuirks>,
+  deadline: Option<Duration>,
+  verify_boot: Option<Duration>,
+  rollback_dir: Option<PathBuf>,
+  wear_ledger: Option<Option<PathBuf>>,
+  diff_env: bool,
+  path_policy: PathPolicy,
+  output_dir: Option<PathBuf>,
+  artifacts_dir: Option<PathBuf>,
+  progress_interval: Option<Duration>,
+  event_delivery: EventDelivery,
+  start_at: Option<String>,
+  resume_offset: Option<u64>,
+  skip: Vec<String>,
+  env_variables: Vec<String>,
+  provisioner: Option<Provisioner>,
+  overrides: Option<Overrides>,
+  report_hook: Option<ReportHook>,
+  cancel_token: Option<CancelToken>,
+  wait_for_device: Option<Duration>,
+  #[cfg(feature = "adb")]
+  adb: Option<crate::Adb>,
+}
 
-    let part_name = &value.name;
-    let validate_result = match self.validate_partition_size(
-      &ValidatePartitionSizeValue {
-        name: part_name.clone(),
-      },
-      &None,
-    )? {
-      FlashOutcome::ValidatePartitionResult(size, offset) => (size, offset),
-      _ => (None, None),
-    };
+impl FlasherBuilder {
+  /// Set the callback that receives flash events
+  pub fn callback(mut self, callback: Option<Callback>) -> Self {
+    self.callback = callback;
+    self
+  }
 
-    let (part_size, _) = match validate_result {
-      (Some(size), Some(offset)) => (size, offset),
-      _ => return Err(Error::InvalidOperation("Failed to validate partition size!".into())),
-    };
+  /// Set the profile of the device being flashed (defaults to superbird)
+  pub fn profile(mut self, profile: DeviceProfile) -> Self {
+    self.profile = profile;
+    self
+  }
 
-    let (file_size, file_reader) = handle_data_or_file_stream(&value.data, &mut self.mode)?;
+  /// Set what to flash (defaults to a device attached over USB)
+  ///
+  /// [`DeviceTarget::Image`] flashes a local disk image instead, to try out a package without hardware.
+  pub fn target(mut self, target: DeviceTarget) -> Self {
+    self.target = target;
+    self
+  }
 
-    let caller_callback = self.callback.clone();
-    let progress_callback = |progress: FlashProgress| {
-      if let Some(callback) = &caller_callback {
-        callback(Event::FlashProgress(progress.clone()));
-      };
-    };
+  /// Set the log level of libusb, which logs to stderr
+  pub fn usb_log_level(mut self, level: UsbLogLevel) -> Self {
+    self.usb_log_level = Some(level);
+    self
+  }
 
+  /// Set when to pause long writes to let the device cool down, overriding the profile's policy
+  pub fn thermal_policy(mut self, policy: ThermalPolicy) -> Self {
+    self.thermal_policy = Some(policy);
     self
-      .aml
-      .restore_partition(part_name, part_size, file_reader, file_size, progress_callback)?;
+  }
 
-    Ok(FlashOutcome::Normal)
+  /// Set workarounds for the host's USB stack, overriding the profile's
+  ///
+  /// [`UsbQuirks::limited_bulk`] lets hosts like Raspberry Pis flash without first failing the large
+  /// transfers they can't make.
+  pub fn usb_quirks(mut self, quirks: UsbQuirks) -> Self {
+    self.usb_quirks = Some(quirks);
+    self
   }
 
-  fn write_boot_partition(&mut self, value: &WriteBootPartitionValue) -> Result<FlashOutcome> {
-    tracing::debug!("running write_boot_partition with value {:?}", value);
-    let data = self.handle_data_or_file(&value.data)?;
+  /// Abort flashing if it has not finished within `deadline` of [`Flasher::flash`] being called
+  ///
+  /// The running step fails with [`Error::Timeout`] at its next transfer or wait, and
+  /// [`Event::Timeout`] is sent to the callback. Steps can also set their own `timeoutMs`.
+  pub fn deadline(mut self, deadline: Duration) -> Self {
+    self.deadline = Some(deadline);
+    self
+  }
 
-    let start_time = std::time::Instant::now();
-    self.aml.write_boot_partition(value.hwpart, &data)?;
-    tracing::trace!("write_boot_partition completed in {:?}", start_time.elapsed());
+  /// Once every step has succeeded, wait up to `timeout` for the device to boot normally
+  ///
+  /// See [`Flasher::verify_boot`]. The device is reset first if the package left it in USB burn
+  /// mode, and a device that does not boot in time is a warning in the report, not an error.
+  pub fn verify_boot(mut self, timeout: Duration) -> Self {
+    self.verify_boot = Some(timeout);
+    self
+  }
 
-    Ok(FlashOutcome::Normal)
+  /// Stop flashing at the next safe point once `token` is cancelled, e.g. from a Ctrl-C handler
+  ///
+  /// The chunk being written is finished first, so the eMMC is never left with a half-written
+  /// chunk. [`Flasher::flash`] then fails with [`Error::Cancelled`], and
+  /// [`FlashReport::resume_at`](crate::FlashReport::resume_at) has the step to pass to
+  /// [`start_at`](Self::start_at) to carry on, and
+  /// [`FlashReport::resume_offset`](crate::FlashReport::resume_offset) how far into a streaming
+  /// write it got, to pass to [`resume_offset`](Self::resume_offset). A
+  /// [`transactional`](Self::transactional) flash is not rolled back when cancelled.
+  pub fn cancel_token(mut self, token: CancelToken) -> Self {
+    self.cancel_token = Some(token);
+    self
   }
 
-  fn write_user_area(&mut self, value: &WriteUserAreaValue) -> Result<FlashOutcome> {
-    tracing::debug!("running write_user_area with value {:?}", value);
-    let (file_size, file) = handle_data_or_file_stream(&value.data, &mut self.mode)?;
+  /// Wait up to `timeout` for the device to be put in USB mode, instead of failing straight away
+  ///
+  /// [`Event::ActionRequired`] tells the callback what the user has to do in the meantime. See
+  /// [`AmlogicSoC::wait_for_usb_mode`].
+  pub fn wait_for_device(mut self, timeout: Duration) -> Self {
+    self.wait_for_device = Some(timeout);
+    self
+  }
 
-    let caller_callback = self.callback.clone();
-    let progress_callback = |progress: FlashProgress| {
-      if let Some(callback) = &caller_callback {
-        callback(Event::FlashProgress(progress.clone()));
-      };
-    };
+  /// Reboot a device found booted normally into USB burn mode through `adb` instead of failing
+  /// with [`Error::WrongMode`]
+  ///
+  /// See [`AmlogicSoC::request_burn_mode_via_adb`]. `postBootCheck` steps run their commands
+  /// through the same `adb`, and through [`Adb::new`](crate::Adb::new) without it.
+  #[cfg(feature = "adb")]
+  pub fn adb_mode_switch(mut self, adb: crate::Adb) -> Self {
+    self.adb = Some(adb);
+    self
+  }
 
-    let start_time = std::time::Instant::now();
+  /// Flash transactionally, backing up critical partitions to `bundle_dir` before changing them
+  ///
+  /// The bootloader, env, fip and dtbo partitions are read back before the first step that writes
+  /// them, by name or in a raw write to the user area that runs over them, and so is as much of a
+  /// boot hwpartition as a `writeBootPartition` step writes. If a later step fails, the saved copies are restored and listed in
+  /// [`FlashReport::rolled_back`](crate::FlashReport::rolled_back). The bundle is a flash package
+  /// of its own, so it can also be restored by hand.
+  pub fn transactional(mut self, bundle_dir: PathBuf) -> Self {
+    self.rollback_dir = Some(bundle_dir);
     self
-      .aml
-      .write_user_area(value.lba, file, file_size, progress_callback)?;
-    tracing::trace!("write_user_area completed in {:?}", start_time.elapsed());
+  }
 
-    Ok(FlashOutcome::Normal)
+  /// Set the file wear is recorded in, or `None` to not record it (defaults to
+  /// [`WearLedger::default_path`])
+  pub fn wear_ledger(mut self, path: Option<PathBuf>) -> Self {
+    self.wear_ledger = Some(path);
+    self
   }
 
-  fn write_env(&mut self, value: &StringOrFile) -> Result<FlashOutcome> {
-    tracing::debug!("running write_env with value {:?}", value);
+  /// Record the variables `writeEnv` steps change, and the values they had, in
+  /// [`FlashReport::env_changes`](crate::FlashReport::env_changes)
+  ///
+  /// Reading the old values takes a `printenv` for each variable, so this is off unless asked for.
+  pub fn diff_env(mut self) -> Self {
+    self.diff_env = true;
+    self
+  }
 
-    let env_data = self.handle_string_or_file(value)?;
+  /// Set the directory read steps write their `output` files to (defaults to the current directory)
+  pub fn output_dir(mut self, dir: PathBuf) -> Self {
+    self.output_dir = Some(dir);
+    self
+  }
 
-    if !env_data.is_ascii() {
-      return Err(Error::InvalidOperation("env data must be ascii".into()));
-    }
+  /// Keep everything a flash produces together under `dir`
+  ///
+  /// The [`FlashReport`] is written to [`ARTIFACT_REPORT`](crate::ARTIFACT_REPORT) when flashing
+  /// finishes, and [`ARTIFACT_CHECKPOINT`](crate::ARTIFACT_CHECKPOINT) records where to resume a
+  /// cancelled flash. Read step `output` files go in [`ARTIFACT_OUTPUTS`](crate::ARTIFACT_OUTPUTS)
+  /// unless [`output_dir`](Self::output_dir) is set, and relative output and
+  /// [`transactional`](Self::transactional) bundle directories are taken relative to `dir`.
+  /// [`Flasher::artifacts`] lists the files produced.
+  pub fn artifacts_dir(mut self, dir: PathBuf) -> Self {
+    self.artifacts_dir = Some(dir);
+    self
+  }
 
-    let env_data_bytes = env_data.as_bytes();
-    let env_size = env_data_bytes.len();
-    let start_time = std::time::Instant::now();
+  /// Set how `filePath`s in the configuration are matched to files in the package (defaults to strict)
+  ///
+  /// Paths that are absolute or climb out of the package are rejected with [`Error::UnsafePath`]
+  /// under either policy.
+  pub fn path_policy(mut self, policy: PathPolicy) -> Self {
+    self.path_policy = policy;
+    self
+  }
 
-    tracing::debug!("initializing env subsystem");
-    self.aml.bulkcmd("amlmmc env")?;
+  /// Start flashing at `step`, skipping every step before it
+  ///
+  /// `step` is a step id, or the step's position counting from 1. Useful for re-running a
+  /// package that failed part way through without rewriting everything before the failure.
+  /// Skipped steps that later steps rely on, such as the `writeEnv` before a `saveenv`, are
+  /// listed in the report's warnings.
+  pub fn start_at(mut self, step: impl Into<String>) -> Self {
+    self.start_at = Some(step.into());
+    self
+  }
 
-    tracing::debug!("sending env ({} bytes)", env_size);
+  /// Carry on the step given to [`start_at`](Self::start_at) at byte `offset` of its data,
+  /// rather than writing it from the start
+  ///
+  /// `offset` is the [`FlashReport::resume_offset`](crate::FlashReport::resume_offset) a cancelled
+  /// flash left, so a multi-gigabyte write does not start over. Steps other than
+  /// `writeLargeMemory`, `restorePartition` and `writeUserArea` always run from the start.
+  pub fn resume_offset(mut self, offset: u64) -> Self {
+    self.resume_offset = Some(offset);
     self
-      .aml
-      .write_large_memory(ADDR_TMP, env_data_bytes, TRANSFER_BLOCK_SIZE, true)?;
+  }
 
+  /// Leave out `steps`, given as ids or positions counting from 1 like [`FlasherBuilder::start_at`]
+  pub fn skip<S: Into<String>>(mut self, steps: impl IntoIterator<Item = S>) -> Self {
+    self.skip.extend(steps.into_iter().map(Into::into));
     self
-      .aml
-      .bulkcmd(&format!("env import -t {:#X} {:#X}", ADDR_TMP, env_size))?;
+  }
 
-    let elapsed = start_time.elapsed();
-    tracing::trace!("write_env completed in {:?}", elapsed);
+  /// Let steps use these environment variables of the host, as `${env_NAME}`
+  ///
+  /// Only the names given here are read, so a package can't pick up secrets from the environment.
+  /// Variables that aren't set are left unset, and steps using them fail.
+  pub fn env_variables<S: Into<String>>(mut self, names: impl IntoIterator<Item = S>) -> Self {
+    self.env_variables.extend(names.into_iter().map(Into::into));
+    self
+  }
 
-    Ok(FlashOutcome::Normal)
+  /// Set where `provision` steps get the values unique to the device being flashed
+  ///
+  /// A package with a `provision` step fails at that step without one.
+  pub fn provisioner(mut self, provisioner: Provisioner) -> Self {
+    self.provisioner = Some(provisioner);
+    self
   }
 
-  fn log(&self, value: &str) -> Result<FlashOutcome> {
-    tracing::debug!("running log with value {:?}", value);
-    tracing::info!(">> {:?}", value);
-    Ok(FlashOutcome::Normal)
+  /// Set per-device values for the package's `variables`, instead of the package's own [`OVERRIDES_FILE`]
+  pub fn overrides(mut self, overrides: Overrides) -> Self {
+    self.overrides = Some(overrides);
+    self
   }
 
-  fn wait(&self, value: &WaitValue) -> Result<FlashOutcome> {
-    tracing::debug!("running wait with value {:?}", value);
-    match value {
-      WaitValue::UserInput { .. } => panic!("wait for user input is not supported!"),
-      WaitValue::Time { time } => sleep(Duration::from_millis(*time)),
-    }
-    Ok(FlashOutcome::Normal)
+  /// Call `hook` with the report once each flash has finished, whether it succeeded or not
+  ///
+  /// The hook runs on the flashing thread before [`Flasher::flash`] returns, e.g. to submit the
+  /// report to a fleet dashboard.
+  pub fn report_hook(mut self, hook: ReportHook) -> Self {
+    self.report_hook = Some(hook);
+    self
   }
 
-  fn handle_data_or_file(&mut self, data_or_file: &DataOrFile) -> Result<Vec<u8>> {
-    tracing::debug!("handling data or file {:?}", data_or_file);
-    match data_or_file {
-      DataOrFile::Data(data) => Ok(data.to_owned()),
-      DataOrFile::File(file) => match &mut self.mode {
-        FlashMode::Standalone => {
-          tracing::warn!("trying to read a file in standalone mode!!");
-          let mut file = File::open(PathBuf::from(&file.file_path))?;
-          let mut data = vec![];
-          file.read_to_end(&mut data)?;
-          Ok(data)
-        }
-        FlashMode::Directory(path) => {
-          let path = path.join(&file.file_path);
-          let mut file = File::open(path)?;
-          let mut data = vec![];
-          file.read_to_end(&mut data)?;
-          Ok(data)
-        }
-        FlashMode::Archive(zip) => {
-          tracing::warn!("reading whole file into memory! is this what you want??");
-          let file_name = if file.file_path.starts_with("./") {
-            file.file_path.replacen("./", "", 1)
-          } else {
-            file.file_path.clone()
-          };
-          let mut found = zip.by_name(&file_name)?;
-          let mut data = vec![];
-          found.read_to_end(&mut data)?;
-          Ok(data)
-        }
-      },
-    }
+  /// Set the minimum time between two [`Event::FlashProgress`] events (defaults to 100ms)
+  ///
+  /// Updates in between are dropped. The first update of each step and the one reaching 100%
+  /// are always sent. [`Duration::ZERO`] sends every update.
+  pub fn progress_interval(mut self, interval: Duration) -> Self {
+    self.progress_interval = Some(interval);
+    self
   }
 
-  fn handle_string_or_file(&mut self, string_or_file: &StringOrFile) -> Result<String> {
-    tracing::debug!("handling string or file {:?}", string_or_file);
-    match string_or_file {
-      StringOrFile::String(data) => Ok(data.clone()),
-      StringOrFile::File(file) => match &mut self.mode {
-        FlashMode::Standalone => {
-          tracing::warn!("trying to read a string file in standalone mode");
-          let path = PathBuf::from(&file.file_path);
-          std::fs::read_to_string(path).map_err(Error::from)
-        }
-        FlashMode::Directory(base_path) => {
-          let path = base_path.join(&file.file_path);
-          std::fs::read_to_string(path).map_err(Error::from)
-        }
-        FlashMode::Archive(zip) => {
-          let file_name = if file.file_path.starts_with("./") {
-            file.file_path.replacen("./", "", 1)
-          } else {
-            file.file_path.clone()
-          };
-          let mut zip_file = zip.by_name(&file_name)?;
-          let mut data = String::new();
-          zip_file.read_to_string(&mut data)?;
-          Ok(data)
-        }
-      },
-    }
+  /// Set how events are handed to the callback (defaults to [`EventDelivery::Blocking`])
+  ///
+  /// With [`EventDelivery::LatestWins`], [`Flasher::flash`] still waits for the callback to
+  /// receive every queued event before returning.
+  pub fn event_delivery(mut self, delivery: EventDelivery) -> Self {
+    self.event_delivery = delivery;
+    self
   }
 
-  /// get the total number of steps in the flash config
-  pub fn num_steps(&self) -> usize {
-    self.config.steps.len()
+  /// Send log records at or above `level` to the callback as [`Event::Log`]
+  ///
+  /// Records are still passed on to the default tracing subscriber, if there is one. Logs are
+  /// mirrored while connecting and flashing.
+  pub fn mirror_logs(mut self, level: tracing::Level) -> Self {
+    self.mirror_logs = Some(level);
+    self
   }
 
-  /// get current step in the flashing process
-  pub fn current_step(&self) -> usize {
-    self.step + 1
+  /// Only log what `verbosity` allows while connecting and flashing
+  ///
+  /// This filters both the default tracing subscriber and logs mirrored with
+  /// [`mirror_logs`](Self::mirror_logs). Without it, what is logged is up to the subscriber.
+  pub fn verbosity(mut self, verbosity: Verbosity) -> Self {
+    self.verbosity = Some(verbosity);
+    self
   }
 
   /// Create a new Flasher where the flash files are relative to the `cwd`.
   /// `path` MUST be the path to a directory.
+  /// If the directory holds a dump `manifest.json`, every file it lists is checked first.
   ///
   /// NOTE: Car Thing is expected to be plugged in at time of creation.
   ///
   /// # Parameters
   /// - `path`: [PathBuf] path to a directory
-  pub fn from_directory(path: PathBuf, callback: Option<Callback>) -> Result<Self> {
+  pub fn from_directory(self, path: PathBuf) -> Result<Flasher> {
     tracing::debug!("creating new flasher from directory at {:?}", &path);
 
-    Ok(Self {
-      config: FlashConfig::from_directory(&path)?,
-      mode: FlashMode::Directory(path),
-      aml: AmlogicSoC::init(callback.clone())?,
-      step: 0,
-      callback,
-    })
+    let config = FlashConfig::from_directory(&path)?;
+    verify_dump(&path)?;
+    self.build(config, FlashMode::Directory(path))
   }
 
   /// Create a new Flasher where the zip archive is relative to the `cwd`.
@@ -509,23 +2556,18 @@ impl Flasher {
   ///
   /// # Parameters
   /// - `path`: [PathBuf] path to the zip archive
-  pub fn from_archive(path: PathBuf, callback: Option<Callback>) -> Result<Self> {
+  pub fn from_archive(self, path: PathBuf) -> Result<Flasher> {
     tracing::debug!("creating new flasher from archive at {:?}", &path);
 
     if !path.exists() || !path.is_file() {
       return Err(Error::NotFound);
     }
 
-    let reader = BufReader::new(File::open(&path)?);
-    let mut zip = ZipArchive::new(reader)?;
+    let mut zip = open_archive(&path)?;
 
-    Ok(Self {
-      config: FlashConfig::from_archive(&mut zip)?,
-      mode: FlashMode::Archive(zip),
-      aml: AmlogicSoC::init(callback.clone())?,
-      step: 0,
-      callback,
-    })
+    let config = FlashConfig::from_archive(&mut zip)?;
+    let root = package_root(&zip, "meta.json")?.unwrap_or_default();
+    self.build(config, FlashMode::Archive(zip, root, path))
   }
 
   /// Create a new Flasher from a standalone `meta.json`.
@@ -535,35 +2577,23 @@ impl Flasher {
   ///
   /// # Parameters
   /// - `meta`: [String] stringified json
-  pub fn from_json(meta: String, callback: Option<Callback>) -> Result<Self> {
+  pub fn from_json(self, meta: String) -> Result<Flasher> {
     tracing::debug!("creating new flasher from json string {:?}", &meta);
 
-    Ok(Self {
-      mode: FlashMode::Standalone,
-      config: FlashConfig::from_standalone(&meta)?,
-      aml: AmlogicSoC::init(callback.clone())?,
-      step: 0,
-      callback,
-    })
+    let config = FlashConfig::from_standalone(&meta)?;
+    self.build(config, FlashMode::Standalone)
   }
 
   /// Create a new Flasher where the flash files are relative to the `cwd`.
   /// `path` MUST be the path to a directory. This can only be used for stock flashing.
+  /// If the directory holds a dump `manifest.json`, every file it lists is checked first.
   ///
   /// NOTE: Car Thing is expected to be plugged in at time of creation.
   ///
   /// # Parameters
   /// - `path`: [PathBuf] path to a directory
-  pub fn from_stock_directory(path: PathBuf, callback: Option<Callback>) -> Result<Self> {
-    tracing::debug!("creating new flasher from directory at {:?}", &path);
-
-    Ok(Self {
-      config: FlashConfig::from_stock()?,
-      mode: FlashMode::Directory(path),
-      aml: AmlogicSoC::init(callback.clone())?,
-      step: 0,
-      callback,
-    })
+  pub fn from_stock_directory(self, path: PathBuf) -> Result<Flasher> {
+    self.stock_directory(StockProfile::default(), path)
   }
 
   /// Create a new Flasher where the zip archive is relative to the `cwd`.
@@ -573,64 +2603,361 @@ impl Flasher {
   ///
   /// # Parameters
   /// - `path`: [PathBuf] path to the zip archive
-  pub fn from_stock_archive(path: PathBuf, callback: Option<Callback>) -> Result<Self> {
-    tracing::debug!("creating new flasher from archive at {:?}", &path);
+  pub fn from_stock_archive(self, path: PathBuf) -> Result<Flasher> {
+    self.stock_archive(StockProfile::default(), path)
+  }
+
+  /// Create a new Flasher for a stock dump laid out like `profile`, rather than the default layout.
+  /// `path` can be a directory or a zip archive, and is handled like
+  /// [`from_stock_directory`](Self::from_stock_directory) or [`from_stock_archive`](Self::from_stock_archive).
+  ///
+  /// NOTE: Car Thing is expected to be plugged in at time of creation.
+  ///
+  /// # Parameters
+  /// - `profile`: [StockProfile] naming the files the dump holds
+  /// - `path`: [PathBuf] path to a directory or zip archive
+  pub fn from_stock_with(self, profile: StockProfile, path: PathBuf) -> Result<Flasher> {
+    if path.is_dir() {
+      self.stock_directory(profile, path)
+    } else {
+      self.stock_archive(profile, path)
+    }
+  }
+
+  fn stock_directory(self, profile: StockProfile, path: PathBuf) -> Result<Flasher> {
+    tracing::debug!(
+      "creating new flasher from directory at {:?} with stock profile {}",
+      &path,
+      profile
+    );
+
+    let config = FlashConfig::from_stock_profile(profile)?;
+    verify_dump(&path)?;
+    self.build(config, FlashMode::Directory(path))
+  }
+
+  fn stock_archive(self, profile: StockProfile, path: PathBuf) -> Result<Flasher> {
+    tracing::debug!(
+      "creating new flasher from archive at {:?} with stock profile {}",
+      &path,
+      profile
+    );
 
     if !path.exists() || !path.is_file() {
       return Err(Error::NotFound);
     }
 
-    let reader = BufReader::new(File::open(&path)?);
-    let zip = ZipArchive::new(reader)?;
+    let zip = open_archive(&path)?;
+
+    // stock dumps have no meta.json, but always include the bootloader
+    let config = FlashConfig::from_stock_profile(profile)?;
+    let root = package_root(&zip, "bootloader.dump")?.unwrap_or_default();
+    self.build(config, FlashMode::Archive(zip, root, path))
+  }
+
+  fn build(mut self, config: FlashConfig, mut mode: FlashMode) -> Result<Flasher> {
+    let skipped = plan::skipped_steps(&config.steps, self.start_at.as_deref(), &self.skip)?;
+    let overrides = match self.overrides.take() {
+      Some(overrides) => Some(overrides),
+      None => package_overrides(&mut mode)?,
+    };
+    if let Some(overrides) = &overrides {
+      overrides.check(&config)?;
+    }
+    if let Some(policy) = self.thermal_policy.take() {
+      self.profile.thermal = policy;
+    }
+    if let Some(quirks) = self.usb_quirks {
+      self.profile.usb_quirks = quirks;
+    }
+    if let Some(level) = self.usb_log_level {
+      AmlogicSoC::set_usb_log_level(level)?;
+    }
+
+    let interval = self.progress_interval.unwrap_or(DEFAULT_PROGRESS_INTERVAL);
+    let (callback, events) = match self.callback.take() {
+      Some(callback) => {
+        let (callback, events) = events::coalesce(callback, interval, self.event_delivery);
+        (Some(callback), events)
+      }
+      None => (None, None),
+    };
+    self.callback = callback;
+
+    let log_mirror = match (&self.callback, self.mirror_logs) {
+      (Some(callback), Some(level)) => Some(LogMirror::dispatch(callback.clone(), level)),
+      _ => None,
+    };
+    let log_mirror = match self.verbosity {
+      Some(verbosity) => {
+        let inner = log_mirror.unwrap_or_else(|| tracing::dispatcher::get_default(tracing::Dispatch::clone));
+        Some(LogFilter::dispatch(verbosity, inner))
+      }
+      None => log_mirror,
+    };
+    let connect = || {
+      #[cfg(feature = "adb")]
+      if let Some(adb) = &self.adb {
+        AmlogicSoC::request_burn_mode_via_adb(adb, &self.profile, &self.target, crate::ADB_REBOOT_TIMEOUT)?;
+      }
+      if let Some(timeout) = self.wait_for_device {
+        AmlogicSoC::wait_for_usb_mode(self.callback.as_ref(), &self.profile, &self.target, timeout)?;
+      }
+      AmlogicSoC::init_with_target(self.callback.clone(), self.profile.clone(), self.target.clone())
+    };
+    let aml = match &log_mirror {
+      Some(dispatch) => tracing::dispatcher::with_default(dispatch, connect)?,
+      None => connect()?,
+    };
+    aml.set_cancel_token(self.cancel_token);
 
-    Ok(Self {
-      config: FlashConfig::from_stock()?,
-      mode: FlashMode::Archive(zip),
-      aml: AmlogicSoC::init(callback.clone())?,
+    Ok(Flasher {
+      config,
+      mode,
+      aml,
       step: 0,
-      callback,
+      step_id: None,
+      skipped,
+      resume_offset: self.resume_offset,
+      variables: HashMap::new(),
+      env_variables: self.env_variables,
+      provisioner: self.provisioner,
+      overrides,
+      report_hook: self.report_hook,
+      callback: self.callback,
+      events,
+      log_mirror,
+      deadline: self.deadline,
+      verify_boot: self.verify_boot,
+      #[cfg(feature = "adb")]
+      adb: self.adb,
+      rollback: self
+        .rollback_dir
+        .map(|dir| RollbackBundle::new(artifact_path(&self.artifacts_dir, dir))),
+      wear_ledger: self.wear_ledger.unwrap_or_else(WearLedger::default_path),
+      diff_env: self.diff_env,
+      path_policy: self.path_policy,
+      output_dir: match (self.output_dir, &self.artifacts_dir) {
+        (Some(dir), _) => artifact_path(&self.artifacts_dir, dir),
+        (None, Some(artifacts)) => artifacts.join(ARTIFACT_OUTPUTS),
+        (None, None) => PathBuf::from("."),
+      },
+      artifacts_dir: self.artifacts_dir,
+      artifacts: Vec::new(),
+      report: FlashReport::default(),
+      capture: None,
+      progress: Progress::NotStarted,
     })
   }
 }
 
+/// `dir` under the artifacts directory if there is one and `dir` is relative
+fn artifact_path(artifacts_dir: &Option<PathBuf>, dir: PathBuf) -> PathBuf {
+  match artifacts_dir {
+    Some(artifacts) if dir.is_relative() => artifacts.join(dir),
+    _ => dir,
+  }
+}
+
+/// Check a directory made by [`DumpManifest::dump`] against its `manifest.json` before flashing from it
+fn verify_dump(path: &Path) -> Result<()> {
+  if !path.join(DUMP_MANIFEST).is_file() {
+    return Ok(());
+  }
+  tracing::info!("found a dump manifest, checking files before flashing");
+  DumpManifest::load(path)?.verify_files(path)
+}
+
+/// The [`OVERRIDES_FILE`] next to the package's `meta.json`, if it has one
+fn package_overrides(mode: &mut FlashMode) -> Result<Option<Overrides>> {
+  let json = match mode {
+    FlashMode::Standalone => return Ok(None),
+    FlashMode::Directory(path) => match path.join(OVERRIDES_FILE) {
+      path if path.is_file() => std::fs::read_to_string(path)?,
+      _ => return Ok(None),
+    },
+    FlashMode::Archive(zip, root, _) => match resolve_in_archive(zip, root, OVERRIDES_FILE, PathPolicy::Strict) {
+      Ok(index) => {
+        let mut json = String::new();
+        zip.by_index(index)?.read_to_string(&mut json)?;
+        json
+      }
+      Err(Error::Zip(ZipError::FileNotFound)) => return Ok(None),
+      Err(err) => return Err(err),
+    },
+  };
+  tracing::info!("found {} in the package", OVERRIDES_FILE);
+  Overrides::parse(&json).map(Some)
+}
+
+fn deadline_passed(deadline: &Option<(Instant, String)>) -> bool {
+  deadline.as_ref().is_some_and(|(at, _)| Instant::now() >= *at)
+}
+
+/// Check `actual` against whichever of `equals`, `contains` and `matches` is given
+///
+/// # Returns
+/// - `Result<Option<(bool, String)>>`: Whether it passed and what was expected, `None` if no check is given,
+///   or an error if the pattern is invalid
+fn expect(
+  actual: &str,
+  equals: &Option<String>,
+  contains: &Option<String>,
+  matches: &Option<String>,
+) -> Result<Option<(bool, String)>> {
+  Ok(Some(match (equals, contains, matches) {
+    (Some(expected), _, _) => (actual == expected, format!("to equal \"{}\"", expected)),
+    (_, Some(expected), _) => (
+      actual.contains(expected.as_str()),
+      format!("to contain \"{}\"", expected),
+    ),
+    (_, _, Some(pattern)) => {
+      let regex = regex::Regex::new(pattern)
+        .map_err(|e| Error::InvalidOperation(format!("pattern `{}` is invalid: {}", pattern, e)))?;
+      (regex.is_match(actual), format!("to match `{}`", pattern))
+    }
+    _ => return Ok(None),
+  }))
+}
+
+fn load_wear_ledger(path: &Path) -> Option<WearLedger> {
+  match WearLedger::load(path) {
+    Ok(ledger) => Some(ledger),
+    Err(e) => {
+      tracing::warn!("failed to load wear ledger from {}: {}", path.display(), e);
+      None
+    }
+  }
+}
+
+/// Callback passing progress of the step `step_id` on to the caller as [`Event::FlashProgress`]
+fn progress_callback(callback: &Option<Callback>, step_id: &Option<String>) -> impl Fn(FlashProgress) + use<> {
+  let callback = callback.clone();
+  let step_id = step_id.clone();
+  move |mut progress: FlashProgress| {
+    if let Some(callback) = &callback {
+      progress.step_id = step_id.clone();
+      callback(Event::FlashProgress(progress));
+    }
+  }
+}
+
 fn handle_data_or_file_stream<'a>(
   data_or_file: &'a DataOrFile,
   mode: &'a mut FlashMode,
+  policy: PathPolicy,
 ) -> Result<(usize, Box<dyn Read + 'a>)> {
   tracing::debug!("handling data or file {:?}", data_or_file);
   match data_or_file {
     DataOrFile::Data(data) => Ok((data.len(), Box::new(Cursor::new(data)))),
-    DataOrFile::File(file) => match mode {
-      FlashMode::Standalone => {
-        tracing::warn!("trying to read a file in standalone mode!!");
-        let file_path = PathBuf::from(&file.file_path);
-        let file = File::open(file_path)?;
-        Ok((file.metadata()?.len() as usize, Box::new(BufReader::new(file))))
-      }
-      FlashMode::Directory(path) => {
-        let file_path = path.join(&file.file_path);
-        let file = File::open(file_path)?;
-        Ok((file.metadata()?.len() as usize, Box::new(BufReader::new(file))))
-      }
-      FlashMode::Archive(zip) => {
-        let file_name = if file.file_path.starts_with("./") {
-          &file.file_path.replacen("./", "", 1)
-        } else {
-          &file.file_path
-        };
+    DataOrFile::Hex { hex } => {
+      let data = decode_hex(hex)?;
+      Ok((data.len(), Box::new(Cursor::new(data))))
+    }
+    DataOrFile::Base64 { base64 } => {
+      let data = decode_base64(base64)?;
+      Ok((data.len(), Box::new(Cursor::new(data))))
+    }
+    DataOrFile::File(meta_file) => {
+      let (size, mut file): (usize, Box<dyn Read + 'a>) = match mode {
+        FlashMode::Standalone => {
+          tracing::warn!("trying to read a file in standalone mode!!");
+          let (size, file) = open_joined(&PathBuf::from(&meta_file.file_path))?;
+          (size as usize, file)
+        }
+        FlashMode::Directory(path) => {
+          let (size, file) = open_joined(&resolve_in_directory(path, &meta_file.file_path, policy)?)?;
+          (size as usize, file)
+        }
+        FlashMode::Archive(zip, root, _) => {
+          let file = zip.by_index(resolve_in_archive(zip, root, &meta_file.file_path, policy)?)?;
+          (file.size() as usize, Box::new(file))
+        }
+      };
 
-        let file = zip.by_name(file_name)?;
-        Ok((file.size() as usize, Box::new(file)))
+      if !meta_file.is_encoded() {
+        return slice_stream(meta_file, size as u64, file);
       }
-    },
+      // encoded files are small text blobs, so decode them whole
+      let mut raw = Vec::with_capacity(size);
+      file.read_to_end(&mut raw)?;
+      let data = meta_file.contents(raw)?;
+      Ok((data.len(), Box::new(Cursor::new(data))))
+    }
+    DataOrFile::Concat { concat } => {
+      let mut size = 0;
+      let mut reader: Box<dyn Read + 'a> = Box::new(io::empty());
+      for part in concat {
+        let (part_size, part_reader) = open_part(part, mode, policy)?;
+        size += part_size;
+        reader = Box::new(reader.chain(part_reader));
+      }
+      tracing::debug!("joining {} files, {} bytes in all", concat.len(), size);
+      Ok((size, reader))
+    }
   }
 }
 
+/// Stream the data of a write step with its `patches` applied as it is read
+fn patched_stream<'a>(
+  data_or_file: &'a DataOrFile,
+  patches: Option<&[Patch]>,
+  mode: &'a mut FlashMode,
+  policy: PathPolicy,
+) -> Result<(usize, Box<dyn Read + 'a>)> {
+  let (size, reader) = handle_data_or_file_stream(data_or_file, mode, policy)?;
+  let Some(patches) = patches else {
+    return Ok((size, reader));
+  };
+  let patches = patches::decode_patches(patches, size as u64)?;
+  tracing::debug!("patching {} places in {} bytes", patches.len(), size);
+  Ok((size, Box::new(Patched::new(reader, patches))))
+}
+
+/// Open one of the files of a [`DataOrFile::Concat`], on a handle of its own so they can be chained
+fn open_part(meta_file: &MetaFile, mode: &mut FlashMode, policy: PathPolicy) -> Result<(usize, Box<dyn Read>)> {
+  let (size, mut file) = match mode {
+    FlashMode::Standalone => open_joined(&PathBuf::from(&meta_file.file_path))?,
+    FlashMode::Directory(path) => open_joined(&resolve_in_directory(path, &meta_file.file_path, policy)?)?,
+    FlashMode::Archive(zip, root, path) => {
+      let index = resolve_in_archive(zip, root, &meta_file.file_path, policy)?;
+      open_entry(zip, path, index)?
+    }
+  };
+
+  if !meta_file.is_encoded() {
+    return slice_stream(meta_file, size, file);
+  }
+  let mut raw = Vec::with_capacity(size as usize);
+  file.read_to_end(&mut raw)?;
+  let data = meta_file.contents(raw)?;
+  Ok((data.len(), Box::new(Cursor::new(data))))
+}
+
+/// Skip to the `offset` of a file that is streamed as it is, and stop reading after its `length`
+fn slice_stream<'a>(
+  meta_file: &MetaFile,
+  size: u64,
+  mut file: Box<dyn Read + 'a>,
+) -> Result<(usize, Box<dyn Read + 'a>)> {
+  if !meta_file.is_sliced() {
+    return Ok((size as usize, file));
+  }
+  let (offset, length) = meta_file.range(size)?;
+  let skipped = io::copy(&mut file.by_ref().take(offset), &mut io::sink())?;
+  if skipped < offset {
+    return Err(Error::InvalidOperation(format!(
+      "{} ended at {:#x}, before its offset {:#x}",
+      meta_file.file_path, skipped, offset
+    )));
+  }
+  Ok((length as usize, Box::new(file.take(length))))
+}
+
 /// Result of a flash step execution
 ///
-/// This represents the outcome of executing a single flash step.
-#[derive(Debug)]
-#[allow(dead_code)] // this is for if i decide to support handing control back or variables
+/// This represents the outcome of executing a single flash step, as returned by [`Flasher::step`].
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FlashOutcome {
   /// flash step completed normally, continue flash
   ///
@@ -638,27 +2965,28 @@ pub enum FlashOutcome {
   Normal,
   /// flash completed, all steps finished
   ///
-  /// calling flasher.flash() now will do nothing
+  /// calling flasher.flash() or flasher.step() now will do nothing
   Complete,
   /// wait for user input
   ///
-  /// you should display message string until user input, then call flasher.flash() again to continue.
+  /// you should display message string until user input, then call flasher.step() again to continue.
+  /// flasher.flash() stops here with [`Error::AwaitingUserInput`] instead, and carries on when called again.
   AwaitUserInput(String),
   /// result of a bulkcmdStat
   ///
-  /// you should handle this result, then call flasher.flash() again to continue.
+  /// you should handle this result, then call flasher.step() again to continue.
   BulkcmdStatResult(String),
   /// result of a bytes read
   ///
-  /// you should handle this result, then call flasher.flash() again to continue.
+  /// you should handle this result, then call flasher.step() again to continue.
   ReadResult(Vec<u8>),
   /// result of an identify step
   ///
-  /// you should handle this result, then call flasher.flash() again to continue.
+  /// you should handle this result, then call flasher.step() again to continue.
   IdentifyResult(String),
-  /// result of a get boot amlc step
+  /// result of a get boot amlc step, the length and offset the BL2 asked for
   ///
-  /// you should handle this result, then call flasher.flash() again to continue.
+  /// you should handle this result, then call flasher.step() again to continue.
   GetBootAMLCResult(u32, u32),
   /// result of a validate partition size step
   ///
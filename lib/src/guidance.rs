@@ -0,0 +1,113 @@
+//! Telling the user how to get the device into USB mode, and waiting for them to do it.
+
+use std::{
+  fmt,
+  thread::sleep,
+  time::{Duration, Instant},
+};
+
+use crate::{AmlogicSoC, Callback, DeviceMode, DeviceTarget, Error, Event, Result, profile::DeviceProfile, transport};
+
+/// How often to look for the device while waiting for it
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Something the user has to do before the device can be flashed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionKind {
+  /// The device is booted normally and has to be restarted with buttons 1 & 4 held
+  HoldButtons,
+  /// No device was found, so it has to be plugged in with buttons 1 & 4 held
+  Replug,
+}
+
+impl ActionKind {
+  /// The action needed for a device found in `mode`, or `None` if it can be flashed as it is
+  pub fn for_mode(mode: DeviceMode) -> Option<Self> {
+    match mode {
+      DeviceMode::Normal => Some(Self::HoldButtons),
+      DeviceMode::NotFound => Some(Self::Replug),
+      DeviceMode::Usb | DeviceMode::UsbBurn => None,
+    }
+  }
+
+  /// Step by step instructions to show the user
+  pub fn instructions(&self) -> &'static str {
+    match self {
+      Self::HoldButtons => {
+        "The Car Thing is running its normal firmware. Unplug it, then hold buttons 1 and 4 (the top buttons \
+         furthest left) while plugging it back in, and keep holding them until the screen stays dark."
+      }
+      Self::Replug => {
+        "No Car Thing was found. Hold buttons 1 and 4 (the top buttons furthest left) while plugging it in, \
+         using a data cable on a port directly on this computer, and keep holding them until the screen stays dark."
+      }
+    }
+  }
+
+  /// The error to fail with if the action is never taken
+  pub(crate) fn error(&self) -> Error {
+    match self {
+      Self::HoldButtons => Error::WrongMode,
+      Self::Replug => Error::NotFound,
+    }
+  }
+}
+
+impl fmt::Display for ActionKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.instructions())
+  }
+}
+
+/// Tell `callback` what the user has to do about a device in `mode`, if anything
+pub(crate) fn request_action(callback: Option<&Callback>, mode: DeviceMode) -> Option<ActionKind> {
+  let kind = ActionKind::for_mode(mode)?;
+  if let Some(callback) = callback {
+    callback(Event::ActionRequired {
+      kind,
+      instructions: kind.instructions().to_string(),
+    });
+  }
+  Some(kind)
+}
+
+impl AmlogicSoC {
+  /// Wait for the device to show up in USB or USB burn mode, telling the callback what the user has to do
+  ///
+  /// [`Event::ActionRequired`] is sent whenever the device is found booted normally or not at all,
+  /// and again each time that changes, so frontends can show live instructions while waiting.
+  ///
+  /// # Parameters
+  /// - `callback`: Optional callback function to receive the instructions
+  /// - `profile`: USB IDs of the device, in burn and normal mode
+  /// - `target`: Where to look for the device
+  /// - `timeout`: How long to wait
+  ///
+  /// # Returns
+  /// - `Result<DeviceMode>`: The mode the device showed up in, or [`Error::WrongMode`] or
+  ///   [`Error::NotFound`] if it was still booted normally or missing when the time ran out
+  pub fn wait_for_usb_mode(
+    callback: Option<&Callback>,
+    profile: &DeviceProfile,
+    target: &DeviceTarget,
+    timeout: Duration,
+  ) -> Result<DeviceMode> {
+    let start = Instant::now();
+    let mut last = None;
+    loop {
+      let mode = transport::find_device(target, profile);
+      let Some(kind) = ActionKind::for_mode(mode) else {
+        return Ok(mode);
+      };
+      if last != Some(kind) {
+        tracing::info!("waiting for the device: {}", kind);
+        request_action(callback, mode);
+        last = Some(kind);
+      }
+      if start.elapsed() >= timeout {
+        return Err(kind.error());
+      }
+      sleep(POLL_INTERVAL.min(timeout.saturating_sub(start.elapsed())));
+    }
+  }
+}
@@ -0,0 +1,372 @@
+//! Checking the uImage and FIT images a package writes before flashing, so a kernel built for
+//! another architecture or a corrupted bootloader is caught before anything is written.
+
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+use crate::{Error, Result};
+
+/// Magic number a legacy uImage header starts with
+const UIMAGE_MAGIC: u32 = 0x2705_1956;
+/// Magic number a flattened device tree, and so a FIT image, starts with
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// Size of a legacy uImage header
+const UIMAGE_HEADER_LEN: usize = 64;
+
+const IH_TYPE_KERNEL: u8 = 2;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// Largest file read whole to check it; anything bigger is a filesystem, not a kernel or bootloader
+pub(crate) const MAX_IMAGE_SIZE: usize = 256 * 1024 * 1024;
+
+/// Architectures Amlogic SoCs run, as their uImage `IH_ARCH_*` value and FIT name
+const ARCHES: &[(u8, &str)] = &[(2, "arm"), (22, "arm64")];
+
+/// Names of the `IH_ARCH_*` values packages are most likely to be built for by mistake
+const ARCH_NAMES: &[(u8, &str)] = &[
+  (2, "arm"),
+  (3, "x86"),
+  (5, "mips"),
+  (7, "powerpc"),
+  (22, "arm64"),
+  (24, "x86_64"),
+  (26, "riscv"),
+];
+
+/// Whether `head`, the first four bytes of a file, are those of a uImage or a device tree
+pub(crate) fn is_image(head: &[u8]) -> bool {
+  head
+    .get(..4)
+    .is_some_and(|magic| matches!(be32(magic), UIMAGE_MAGIC | FDT_MAGIC))
+}
+
+/// Check a uImage or FIT image's checksums and hashes, and that it was built for ARM and loads
+/// within the `dram_size` bytes of DDR
+///
+/// Device trees that are not FIT images, i.e. have no `/images` node, pass as they are.
+pub(crate) fn check_image(name: &str, data: &[u8], dram_size: u64) -> Result<()> {
+  let result = match data.get(..4).map(be32) {
+    Some(UIMAGE_MAGIC) => check_uimage(data, dram_size),
+    Some(FDT_MAGIC) => check_fit(data, dram_size),
+    _ => Ok(()),
+  };
+  result.map_err(|problem| Error::InvalidImage(format!("{name}: {problem}")))
+}
+
+fn check_uimage(data: &[u8], dram_size: u64) -> std::result::Result<(), String> {
+  if data.len() < UIMAGE_HEADER_LEN {
+    return Err("uImage is shorter than its header".into());
+  }
+  let field = |at: usize| be32(&data[at..at + 4]);
+  let mut header = data[..UIMAGE_HEADER_LEN].to_vec();
+  header[4..8].fill(0);
+  if crc32fast::hash(&header) != field(4) {
+    return Err("uImage header checksum does not match".into());
+  }
+  let size = field(12) as usize;
+  let Some(payload) = data.get(UIMAGE_HEADER_LEN..UIMAGE_HEADER_LEN + size) else {
+    return Err(format!(
+      "uImage is truncated: its header says {} bytes of data, but the file has {}",
+      size,
+      data.len() - UIMAGE_HEADER_LEN
+    ));
+  };
+  if crc32fast::hash(payload) != field(24) {
+    return Err("uImage data checksum does not match".into());
+  }
+
+  let arch = data[29];
+  if !ARCHES.iter().any(|(value, _)| *value == arch) {
+    let name = ARCH_NAMES
+      .iter()
+      .find(|(value, _)| *value == arch)
+      .map_or_else(|| format!("architecture {arch}"), |(_, name)| name.to_string());
+    return Err(format!("uImage is built for {name}, not arm or arm64"));
+  }
+
+  let (load, entry) = (field(16) as u64, field(20) as u64);
+  if data[30] == IH_TYPE_KERNEL || load != 0 {
+    check_load("uImage", load, size as u64, dram_size)?;
+  }
+  if data[30] == IH_TYPE_KERNEL && entry >= dram_size {
+    return Err(format!(
+      "uImage entry point {:#x} is past the end of the {:#x} bytes of DDR",
+      entry, dram_size
+    ));
+  }
+  Ok(())
+}
+
+fn check_fit(data: &[u8], dram_size: u64) -> std::result::Result<(), String> {
+  let root = parse_fdt(data)?;
+  let Some(images) = root.child("images") else {
+    return Ok(());
+  };
+
+  // external data, from `mkimage -E`, follows the device tree at the next 4 byte boundary
+  let external = (be32(&data[4..8]) as usize).next_multiple_of(4);
+  for image in &images.children {
+    let what = format!("FIT image {}", image.name);
+    let payload = match (
+      image.prop("data"),
+      image.prop("data-offset"),
+      image.prop("data-position"),
+    ) {
+      (Some(payload), _, _) => payload,
+      (None, offset, position) => {
+        let start = match (offset, position) {
+          (Some(offset), _) => external + number(offset)? as usize,
+          (None, Some(position)) => number(position)? as usize,
+          (None, None) => return Err(format!("{what} has no data")),
+        };
+        let size = number(image.prop("data-size").ok_or(format!("{what} has no data-size"))?)? as usize;
+        start
+          .checked_add(size)
+          .and_then(|end| data.get(start..end))
+          .ok_or(format!("{what} is truncated: its data ends past the end of the file"))?
+      }
+    };
+
+    if let Some(arch) = image.prop("arch").map(string)
+      && !ARCHES.iter().any(|(_, name)| *name == arch)
+    {
+      return Err(format!("{what} is built for {arch}, not arm or arm64"));
+    }
+    if let Some(load) = image.prop("load") {
+      check_load(&what, number(load)?, payload.len() as u64, dram_size)?;
+    }
+    if let Some(entry) = image.prop("entry").map(number).transpose()?
+      && entry >= dram_size
+    {
+      return Err(format!(
+        "{what} entry point {:#x} is past the end of the {:#x} bytes of DDR",
+        entry, dram_size
+      ));
+    }
+
+    for hash in image.children.iter().filter(|node| node.name.starts_with("hash")) {
+      let (Some(algo), Some(expected)) = (hash.prop("algo").map(string), hash.prop("value")) else {
+        continue;
+      };
+      let actual = match algo {
+        "crc32" => crc32fast::hash(payload).to_be_bytes().to_vec(),
+        "sha256" => Sha256::digest(payload).to_vec(),
+        "sha384" => Sha384::digest(payload).to_vec(),
+        "sha512" => Sha512::digest(payload).to_vec(),
+        _ => {
+          tracing::debug!("not checking the {} hash of {}", algo, what);
+          continue;
+        }
+      };
+      if actual != expected {
+        return Err(format!(
+          "{what} {algo} hash does not match: expected {}, got {}",
+          hex::encode(expected),
+          hex::encode(actual)
+        ));
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Check `size` bytes loaded at `load` fit in the DDR
+fn check_load(what: &str, load: u64, size: u64, dram_size: u64) -> std::result::Result<(), String> {
+  if load.saturating_add(size) > dram_size {
+    return Err(format!(
+      "{what} loads {:#x} bytes at {:#x}, past the end of the {:#x} bytes of DDR",
+      size, load, dram_size
+    ));
+  }
+  Ok(())
+}
+
+/// A node of a flattened device tree
+#[derive(Debug, Default)]
+struct Node<'a> {
+  name: &'a str,
+  props: Vec<(&'a str, &'a [u8])>,
+  children: Vec<Node<'a>>,
+}
+
+impl<'a> Node<'a> {
+  fn child(&self, name: &str) -> Option<&Node<'a>> {
+    self.children.iter().find(|child| child.name == name)
+  }
+
+  fn prop(&self, name: &str) -> Option<&'a [u8]> {
+    self
+      .props
+      .iter()
+      .find(|(prop, _)| *prop == name)
+      .map(|(_, value)| *value)
+  }
+}
+
+/// Parse the structure block of a flattened device tree into its root node
+fn parse_fdt(data: &[u8]) -> std::result::Result<Node<'_>, String> {
+  let truncated = || "device tree is truncated".to_owned();
+  let word = |at: usize| data.get(at..at + 4).map(be32).ok_or_else(truncated);
+  let (struct_at, strings_at) = (word(8)? as usize, word(12)? as usize);
+
+  let mut stack: Vec<Node> = Vec::new();
+  let mut at = struct_at;
+  loop {
+    let token = word(at)?;
+    at += 4;
+    match token {
+      FDT_BEGIN_NODE => {
+        let name = c_str(data, at).ok_or_else(truncated)?;
+        at = (at + name.len() + 1).next_multiple_of(4);
+        stack.push(Node {
+          name,
+          ..Default::default()
+        });
+      }
+      FDT_END_NODE => {
+        let node = stack.pop().ok_or("device tree closes a node it never opened")?;
+        match stack.last_mut() {
+          Some(parent) => parent.children.push(node),
+          None => return Ok(node),
+        }
+      }
+      FDT_PROP => {
+        let (len, name_at) = (word(at)? as usize, word(at + 4)? as usize);
+        let value = data.get(at + 8..at + 8 + len).ok_or_else(truncated)?;
+        let name = c_str(data, strings_at + name_at).ok_or_else(truncated)?;
+        at = (at + 8 + len).next_multiple_of(4);
+        stack.last_mut().ok_or_else(truncated)?.props.push((name, value));
+      }
+      FDT_NOP => {}
+      FDT_END => return Err("device tree ends inside a node".into()),
+      token => return Err(format!("device tree has unknown token {token:#x}")),
+    }
+  }
+}
+
+/// The NUL-terminated string at `at`
+fn c_str(data: &[u8], at: usize) -> Option<&str> {
+  let rest = data.get(at..)?;
+  let end = rest.iter().position(|byte| *byte == 0)?;
+  std::str::from_utf8(&rest[..end]).ok()
+}
+
+/// A string property, without its NUL terminator
+fn string(value: &[u8]) -> &str {
+  std::str::from_utf8(value.strip_suffix(&[0]).unwrap_or(value)).unwrap_or_default()
+}
+
+/// A one or two cell number property
+fn number(value: &[u8]) -> std::result::Result<u64, String> {
+  match value.len() {
+    4 => Ok(be32(value) as u64),
+    8 => Ok(u64::from_be_bytes(value.try_into().unwrap_or_default())),
+    len => Err(format!("device tree number property is {len} bytes long")),
+  }
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+  u32::from_be_bytes(bytes[..4].try_into().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{bootscript::script_image, config::ScriptArch};
+
+  const DRAM: u64 = 512 * 1024 * 1024;
+
+  /// A FIT image holding `kernel` as its one image, with its properties and a sha256 hash
+  fn fit(kernel: &[u8], props: &[(&str, &[u8])], hash: &[u8]) -> Vec<u8> {
+    let mut strings = Vec::new();
+    let mut structure = Vec::new();
+    let node = |structure: &mut Vec<u8>, name: &str| {
+      structure.extend_from_slice(&FDT_BEGIN_NODE.to_be_bytes());
+      structure.extend_from_slice(name.as_bytes());
+      structure.push(0);
+      structure.resize(structure.len().next_multiple_of(4), 0);
+    };
+    let mut prop = |structure: &mut Vec<u8>, name: &str, value: &[u8]| {
+      structure.extend_from_slice(&FDT_PROP.to_be_bytes());
+      structure.extend_from_slice(&(value.len() as u32).to_be_bytes());
+      structure.extend_from_slice(&(strings.len() as u32).to_be_bytes());
+      strings.extend_from_slice(name.as_bytes());
+      strings.push(0);
+      structure.extend_from_slice(value);
+      structure.resize(structure.len().next_multiple_of(4), 0);
+    };
+    node(&mut structure, "");
+    node(&mut structure, "images");
+    node(&mut structure, "kernel");
+    prop(&mut structure, "data", kernel);
+    for (name, value) in props {
+      prop(&mut structure, name, value);
+    }
+    node(&mut structure, "hash-1");
+    prop(&mut structure, "algo", b"sha256\0");
+    prop(&mut structure, "value", hash);
+    for _ in 0..4 {
+      structure.extend_from_slice(&FDT_END_NODE.to_be_bytes());
+    }
+    structure.extend_from_slice(&FDT_END.to_be_bytes());
+
+    let mut fdt = vec![0; 40];
+    let strings_at = 40 + structure.len();
+    let total = strings_at + strings.len();
+    for (at, value) in [(0, FDT_MAGIC), (4, total as u32), (8, 40), (12, strings_at as u32)] {
+      fdt[at..at + 4].copy_from_slice(&value.to_be_bytes());
+    }
+    fdt.extend_from_slice(&structure);
+    fdt.extend_from_slice(&strings);
+    fdt
+  }
+
+  #[test]
+  fn test_check_uimage() {
+    let image = script_image("boot\n", "boot", ScriptArch::Arm64);
+    assert!(is_image(&image));
+    check_image("boot.scr", &image, DRAM).unwrap();
+
+    let mut x86 = image.clone();
+    x86[29] = 3;
+    x86[4..8].fill(0);
+    let hcrc = crc32fast::hash(&x86[..UIMAGE_HEADER_LEN]);
+    x86[4..8].copy_from_slice(&hcrc.to_be_bytes());
+    let err = check_image("boot.scr", &x86, DRAM).unwrap_err();
+    assert_eq!(err.code(), "INVALID_IMAGE");
+    assert!(err.to_string().contains("boot.scr: uImage is built for x86"), "{err}");
+
+    let mut corrupt = image.clone();
+    *corrupt.last_mut().unwrap() ^= 1;
+    let err = check_image("boot.scr", &corrupt, DRAM).unwrap_err();
+    assert!(err.to_string().contains("data checksum"), "{err}");
+    let err = check_image("boot.scr", &image[..image.len() - 1], DRAM).unwrap_err();
+    assert!(err.to_string().contains("truncated"), "{err}");
+  }
+
+  #[test]
+  fn test_check_fit() {
+    let kernel = b"not really a kernel";
+    let hash = Sha256::digest(kernel);
+    let load = 0x0108_0000u32.to_be_bytes();
+    let image = fit(kernel, &[("arch", b"arm64\0"), ("load", &load)], &hash);
+    assert!(is_image(&image));
+    check_image("fit.itb", &image, DRAM).unwrap();
+
+    let err = check_image("fit.itb", &fit(kernel, &[("arch", b"x86_64\0")], &hash), DRAM).unwrap_err();
+    assert!(
+      err.to_string().contains("FIT image kernel is built for x86_64"),
+      "{err}"
+    );
+    let err = check_image("fit.itb", &fit(kernel, &[], &[0; 32]), DRAM).unwrap_err();
+    assert!(err.to_string().contains("sha256 hash does not match"), "{err}");
+    let high = 0x4000_0000u32.to_be_bytes();
+    let err = check_image("fit.itb", &fit(kernel, &[("load", &high)], &hash), DRAM).unwrap_err();
+    assert!(err.to_string().contains("past the end"), "{err}");
+    assert!(check_image("fit.itb", &image[..image.len() - 8], DRAM).is_err());
+  }
+}
@@ -0,0 +1,185 @@
+//! Packages laid out for the Python superbird-tool, which have no `meta.json`.
+
+use std::path::Path;
+
+use crate::{
+  Error, Result,
+  config::{DataOrFile, FlashConfig, FlashStep, MetaFile, RestorePartitionValue, Step, StringOrFile},
+  partitions::PartitionTable,
+};
+
+/// Extensions superbird-tool and community archives give partition images, in order of preference
+const IMAGE_EXTENSIONS: [&str; 5] = ["dump", "img", "bin", "ext2", "ext4"];
+
+/// Env file superbird-tool's `--send_env` imports into the U-Boot environment
+const ENV_FILE: &str = "env.txt";
+
+impl FlashConfig {
+  /// Build a flash configuration for a directory laid out for superbird-tool
+  ///
+  /// A `<partition>.dump` image restores that partition, as superbird-tool's `--restore_device`
+  /// does; `.img`, `.bin`, `.ext2` and `.ext4` images and partition aliases like `boot0` are
+  /// recognized too. An `env.txt` is imported into the environment after the partitions are
+  /// restored, as with `--send_env`.
+  ///
+  /// # Parameters
+  /// - `path`: The directory of images
+  ///
+  /// # Returns
+  /// - `Result<Self>`: The equivalent configuration, or an error if nothing in the directory is recognized
+  pub fn from_superbird_tool_dir(path: &Path) -> Result<Self> {
+    if !path.is_dir() {
+      return Err(Error::NotDir(path.to_owned()));
+    }
+
+    let table = PartitionTable::superbird();
+    let mut found = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+      let file = entry?.file_name().to_string_lossy().into_owned();
+      let Some((stem, extension)) = file.rsplit_once('.') else {
+        continue;
+      };
+      let Some(rank) = IMAGE_EXTENSIONS
+        .iter()
+        .position(|known| extension.eq_ignore_ascii_case(known))
+      else {
+        continue;
+      };
+      let Ok(part) = table.resolve(stem) else {
+        tracing::debug!("ignoring {}, which is not named after a partition", file);
+        continue;
+      };
+      if part.name == "reserved" || part.size == 0 {
+        tracing::warn!("ignoring {}, the {} partition is not restored", file, part.name);
+        continue;
+      }
+      found.push((part.offset, rank, part.name.to_string(), file));
+    }
+    // one image per partition, in on-disk order
+    found.sort();
+    found.dedup_by(|later, first| later.0 == first.0);
+
+    let mut steps = found
+      .into_iter()
+      .map(|(_, _, name, file_path)| FlashStep::RestorePartition {
+        value: RestorePartitionValue {
+          name,
+          data: DataOrFile::File(MetaFile {
+            file_path,
+            encoding: None,
+            offset: None,
+            length: None,
+            template: None,
+          }),
+          compare_before_write: None,
+          confirm_special_partition: None,
+          trim_padding: None,
+          erase_trimmed: None,
+          patches: None,
+        },
+      })
+      .collect::<Vec<_>>();
+    if path.join(ENV_FILE).is_file() {
+      steps.push(FlashStep::WriteEnv {
+        value: StringOrFile::File(MetaFile {
+          file_path: ENV_FILE.to_owned(),
+          encoding: None,
+          offset: None,
+          length: None,
+          template: None,
+        }),
+      });
+    }
+    if steps.is_empty() {
+      return Err(Error::InvalidOperation(format!(
+        "found no partition images or {} in {}",
+        ENV_FILE,
+        path.display()
+      )));
+    }
+    steps.insert(
+      0,
+      FlashStep::Bulkcmd {
+        value: "amlmmc part 1".to_owned(),
+      },
+    );
+
+    let name = path
+      .file_name()
+      .map_or_else(|| "superbird-tool dump".into(), |name| name.to_string_lossy());
+    let config = FlashConfig {
+      name: name.into_owned(),
+      version: "1.0.0".to_owned(),
+      description: "converted from a superbird-tool directory".to_owned(),
+      steps: steps
+        .into_iter()
+        .map(|step| Step {
+          step,
+          id: None,
+          timeout_ms: None,
+          on_error: None,
+        })
+        .collect(),
+      variables: None,
+      constants: None,
+      metadata_version: 1,
+      allow_special_partitions: None,
+      report_webhook: None,
+      deferred: Default::default(),
+    };
+    // round trip to give the steps their ids and check the result like any other package
+    Self::from_standalone(&serde_json::to_string(&config)?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_superbird_tool_dir() {
+    let dir = std::env::temp_dir().join(format!("flashthing-legacy-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    for file in [
+      "system_a.ext2",
+      "system_a.dump",
+      "boot0.img",
+      "env.txt",
+      "cache.dump",
+      "notes.md",
+    ] {
+      std::fs::write(dir.join(file), b"").unwrap();
+    }
+
+    let config = FlashConfig::from_superbird_tool_dir(&dir).unwrap();
+    let steps = config
+      .steps
+      .iter()
+      .map(|step| match &step.step {
+        FlashStep::Bulkcmd { value } => value.clone(),
+        FlashStep::RestorePartition {
+          value:
+            RestorePartitionValue {
+              name,
+              data: DataOrFile::File(file),
+              ..
+            },
+        } => format!("{name} {}", file.file_path),
+        FlashStep::WriteEnv { .. } => "env".to_owned(),
+        step => panic!("unexpected step {:?}", step),
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(
+      steps,
+      ["amlmmc part 1", "bootloader boot0.img", "system_a system_a.dump", "env"]
+    );
+
+    std::fs::remove_file(dir.join("env.txt")).unwrap();
+    std::fs::remove_file(dir.join("system_a.ext2")).unwrap();
+    std::fs::remove_file(dir.join("system_a.dump")).unwrap();
+    std::fs::remove_file(dir.join("boot0.img")).unwrap();
+    assert!(FlashConfig::from_superbird_tool_dir(&dir).is_err());
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+}
@@ -21,8 +21,10 @@
 //! use flashthing::{AmlogicSoC, Flasher, Event};
 //! use std::{path::PathBuf, sync::Arc};
 //!
-//! // Set up USB access for the device (on Linux, but no-op for other OSes so fine to call)
-//! AmlogicSoC::host_setup().unwrap();
+//! // Set up USB access for the device (installs udev rules on Linux, only checks for problems elsewhere)
+//! for finding in AmlogicSoC::host_setup().unwrap().findings {
+//!     eprintln!("{}", finding);
+//! }
 //!
 //! // Create a callback to handle events
 //! let callback = Arc::new(|event: Event| {
@@ -31,7 +33,7 @@
 //!             println!("Progress: {:.1}%, ETA: {:.1}s", progress.percent, progress.eta / 1000.0);
 //!         },
 //!         Event::Step(step_index, step) => {
-//!             println!("Step {}: {:?}", step_index, step);
+//!             println!("Step {} ({}): {:?}", step_index, step.id.unwrap_or_default(), step.step);
 //!         },
 //!         Event::DeviceMode(mode) => {
 //!             println!("Device mode: {:?}", mode);
@@ -50,6 +52,12 @@
 //! flasher.flash().unwrap();
 //! ```
 //!
+//! ## Protocol Layer
+//!
+//! The USB burn protocol itself lives in the `flashthing-core` crate, which has no packaging or
+//! file format dependencies. [`AmlogicSoC::session`] gives access to it, and its types are
+//! re-exported here.
+//!
 //! ## Device Connection
 //!
 //! To use this library, the Spotify Car Thing must be connected via USB and placed
@@ -60,19 +68,108 @@
 //! The flashing process is guided by a `meta.json` file that specifies a sequence
 //! of operations to perform. See the schema documentation for details on the format.
 
+#[cfg(feature = "adb")]
+mod adb;
 mod aml;
+mod archive;
+mod artifacts;
+mod boot;
+mod bootscript;
+mod buffers;
+mod cancel;
+mod capabilities;
+mod capture;
+mod delta;
+mod disklayout;
+mod dump;
+mod env;
+mod events;
+mod filesystems;
+mod firmware;
+#[cfg(test)]
+mod fixtures;
 mod flash;
+mod guidance;
+mod images;
+mod legacy;
+mod logging;
+mod overrides;
+mod package;
+mod padding;
 mod partitions;
+mod patches;
+mod paths;
+mod plan;
+mod profile;
+mod provision;
+mod quirks;
+mod recorder;
+#[cfg(feature = "http")]
+mod registry;
+mod report;
+mod rollback;
+mod script;
 mod setup;
+mod snapshot;
+mod spool;
+mod steps;
+mod stock;
+mod telemetry;
+mod template;
+mod thermal;
+mod throughput;
+mod transport;
+mod variables;
+mod verify;
+mod wear;
 
 /// Configuration types for the flashing process
 pub mod config;
+/// Sizes and digests of the blobs built into the crate
+pub mod resources;
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
+#[cfg(feature = "adb")]
+pub use adb::{ADB_ENV, ADB_REBOOT_TIMEOUT, Adb};
 pub use aml::*;
-use config::FlashStep;
-pub use flash::{FlashProgress, Flasher};
+pub use artifacts::{ARTIFACT_CHECKPOINT, ARTIFACT_OUTPUTS, ARTIFACT_REPORT};
+pub use boot::BootOutcome;
+pub use cancel::CancelToken;
+pub use capabilities::{Capabilities, ProfileInfo};
+pub use capture::CAPTURE_LOG;
+use config::Step;
+pub use dump::{DUMP_MANIFEST, DumpManifest, DumpOptions, DumpTarget, DumpedFile};
+pub use events::{DEFAULT_PROGRESS_INTERVAL, EventDelivery};
+pub use firmware::{
+  FIRMWARE_CHANNEL_VAR, FIRMWARE_NAME_VAR, FIRMWARE_VERSION_VAR, InstalledFirmware, UpdateCheck, compare_versions,
+};
+pub use flash::{FlashOutcome, FlashProgress, Flasher, FlasherBuilder};
+pub use guidance::ActionKind;
+pub use logging::{TRANSFER_TARGET, Verbosity};
+pub use overrides::{OVERRIDES_FILE, Overrides};
+pub use package::{Compatibility, Package, PackageFile, StepSummary};
+pub use partitions::{PartitionInfo, PartitionTable, canonical_partition_name};
+pub use paths::PathPolicy;
+pub use profile::DeviceProfile;
+pub use provision::{ProvisionCallback, ProvisionRequest, Provisioner};
+pub use quirks::UsbQuirks;
+pub use recorder::Recorder;
+#[cfg(feature = "http")]
+pub use registry::{LATEST, Registry, RegistryIndex, RegistryPackage, RegistryRelease};
+pub use report::{EnvChange, FlashReport, IterationFailure, PostBootCheckResult, RepeatResult, ReportHook, StepResult};
+pub use resources::EmbeddedResource;
+pub use setup::{Confinement, HostSetupStatus};
+pub use snapshot::{PartitionState, Snapshot, SnapshotChange};
+pub use steps::{StepExecution, Steps};
+pub use stock::StockProfile;
+#[cfg(feature = "metrics")]
+pub use telemetry::describe_metrics;
+pub use thermal::ThermalPolicy;
+pub use throughput::TransportBench;
+pub use variables::{ENV_PREFIX, HOST_FACTS};
+pub use verify::{ExpectedHash, HASH_MANIFEST, HashAlgorithm, HashManifest, PartitionCheck};
+pub use wear::{DeviceWear, WearLedger};
 
 /// Callback type for receiving flash events
 ///
@@ -90,6 +187,13 @@ pub enum Event {
   FindingDevice,
   /// Indicates the device was found and reports its current mode
   DeviceMode(DeviceMode),
+  /// Indicates the device has to be put in USB mode by the user before it can be flashed
+  ActionRequired {
+    /// What the user has to do
+    kind: ActionKind,
+    /// Instructions to show the user
+    instructions: String,
+  },
   /// Indicates the tool is attempting to connect to the device
   Connecting,
   /// Indicates a successful connection to the device
@@ -100,10 +204,76 @@ pub enum Event {
   Resetting,
   /// Indicates movement to a new flashing step
   ///
-  /// Parameters: (step_index, step_details)
-  Step(usize, FlashStep),
+  /// Parameters: (step_index, step_details), where the details include the step's `id`
+  Step(usize, Step),
+  /// Indicates a step finished successfully
+  StepCompleted {
+    /// Index of the step
+    step: usize,
+    /// Id of the step
+    id: String,
+  },
+  /// Indicates a step failed for good, after any retries, whether or not flashing carries on
+  StepFailed {
+    /// Index of the step
+    step: usize,
+    /// Id of the step
+    id: String,
+    /// Error of the last attempt
+    error: String,
+    /// [`Error::code`] of the last attempt
+    code: &'static str,
+  },
   /// Provides progress information for the current flashing step
   FlashProgress(FlashProgress),
+  /// Indicates writing paused to let the device cool down
+  ThermalPause {
+    /// Temperature in °C that triggered the pause, `None` when pausing after a slow write
+    temperature: Option<f64>,
+  },
+  /// A log record, mirrored from tracing when enabled with [`FlasherBuilder::mirror_logs`]
+  Log {
+    /// Severity of the record
+    level: tracing::Level,
+    /// Module the record came from
+    target: String,
+    /// Formatted message, followed by any other fields as `name=value`
+    message: String,
+  },
+  /// Indicates a step was aborted for running past its `timeoutMs` or the flasher's deadline
+  Timeout {
+    /// Index of the step that was aborted
+    step: usize,
+    /// Id of the step that was aborted
+    id: String,
+    /// Which limit was exceeded
+    reason: String,
+  },
+  /// Indicates a bulk transfer hung and was cancelled to be retried, see [`UsbQuirks::transfer_watchdog`]
+  TransferStalled {
+    /// Endpoint of the transfer
+    endpoint: u8,
+    /// How long the transfer went without finishing
+    stalled_for: Duration,
+  },
+  /// Indicates the device booted normally after flashing, see [`Flasher::verify_boot`]
+  BootVerified {
+    /// How long it took to come up after the reset
+    elapsed: Duration,
+  },
+  /// Indicates the device kept dropping off the bus and coming back while [`Flasher::verify_boot`]
+  /// waited for it, the sign of firmware that crashes as it boots
+  BootLoopDetected {
+    /// Times it dropped off the bus after showing up
+    cycles: u32,
+  },
+  /// Indicates the device did not boot normally within the time [`Flasher::verify_boot`] waited
+  BootTimeout {
+    /// How long it was given
+    timeout: Duration,
+    /// Mode the device was last seen in, e.g. USB mode if the bootloader found nothing to boot
+    mode: DeviceMode,
+  },
 }
 
 /// Result type used throughout the crate
@@ -113,8 +283,8 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
   /// Error from the USB subsystem
-  #[error("USB error: {0}")]
-  UsbError(#[from] rusb::Error),
+  #[error(transparent)]
+  Usb(flashthing_core::Error),
 
   /// I/O related error
   #[error("IO error: {0}")]
@@ -140,6 +310,10 @@ pub enum Error {
   #[error("device in wrong mode!")]
   WrongMode,
 
+  /// Error running `adb`, or from the command it ran on the device
+  #[error("adb error: {0}")]
+  Adb(String),
+
   /// Error when a bulk command fails
   #[error("bulkcmd failed: {0}")]
   BulkCmdFailed(String),
@@ -149,8 +323,11 @@ pub enum Error {
   UnsupportedVersion(usize),
 
   /// Error when a feature in meta.json is not supported
+  ///
+  /// The step is boxed to keep `Error` small, since steps grew well past the size of every other
+  /// variant. Up to 0.2.2 this held the [`FlashStep`](config::FlashStep) itself.
   #[error("unsupported `meta.json` feature: {:?}", 0)]
-  UnsupportedFeature(config::FlashStep),
+  UnsupportedFeature(Box<config::FlashStep>),
 
   /// JSON deserialization error
   #[error("failed to deserialize json: {0}")]
@@ -172,82 +349,263 @@ pub enum Error {
   #[error("zip error: {0}")]
   Zip(#[from] zip::result::ZipError),
 
+  /// Error when the SoC's secure boot configuration has no matching BL2
+  #[error("unsupported secure boot configuration: {0}")]
+  UnsupportedSecureBoot(String),
+
+  /// Error when the USB session ended, e.g. because the device reset, and was not re-established
+  #[error("USB session lost: {0}")]
+  SessionLost(String),
+
+  /// Error when a step ran past its `timeoutMs` or the flasher's deadline
+  #[error("timed out: {0}")]
+  Timeout(String),
+
+  /// Error when a dump does not match its `manifest.json`
+  #[error("dump does not match its manifest: {0}")]
+  ManifestMismatch(String),
+
+  /// Error when an archive is cut short, e.g. by an interrupted download
+  #[error("archive {0} is truncated or incomplete, try downloading it again")]
+  ArchiveTruncated(std::path::PathBuf),
+
+  /// Error when an archive over 4 GiB, or holding a file over 4 GiB, was written without zip64
+  /// records, so its offsets or sizes are wrong
+  #[error("archive {0} holds more than 4 GiB but is not a zip64 archive, re-create it with zip64 enabled")]
+  ArchiveTooLargeFor32BitZip(std::path::PathBuf),
+
+  /// Error when a `filePath` is absolute or climbs out of the package with `..`
+  #[error("file path \"{0}\" points outside the package")]
+  UnsafePath(String),
+
+  /// Error when a write would run past the end of the partition it starts in
+  #[error("write crosses a partition boundary: {0}")]
+  PartitionOverrun(String),
+
+  /// Error when an `assert` step finds a value other than the one expected
+  #[error("assertion failed: {0}")]
+  AssertionFailed(String),
+
+  /// Error when a [`CancelToken`] stopped the operation at a safe point
+  #[error("cancelled")]
+  Cancelled,
+
+  /// Error fetching a package registry's index or one of its packages
+  #[error("registry error: {0}")]
+  Registry(String),
+
+  /// Error when a download does not match the digest or signature it was published with
+  #[error("verification failed: {0}")]
+  VerificationFailed(String),
+
+  /// Error when a uImage or FIT image a package writes is corrupt or cannot run on the device
+  #[error("invalid image: {0}")]
+  InvalidImage(String),
+
+  /// Error when a partition name cannot be resolved
+  #[error("unknown partition \"{name}\", valid partitions are: {valid}", name = .0, valid = .1.join(", "))]
+  UnknownPartition(String, Vec<String>),
+
+  /// Returned by [`Flasher::flash`] when it reaches a `wait` step for user input, with its message
+  ///
+  /// The flash is still running: show the message, then call `flash` again to carry on after it.
+  #[error("waiting for user input: {0}")]
+  AwaitingUserInput(String),
+
+  /// An error with where it happened attached, e.g. the step and byte offset a write failed at
+  #[error("{context}: {source}")]
+  Context {
+    /// Where the error happened
+    context: Box<ErrorContext>,
+    /// The error itself
+    #[source]
+    source: Box<Error>,
+  },
+
   #[cfg(target_os = "linux")]
   /// whoami error
+  #[deprecated(note = "no longer returned, since the udev rules no longer name the user")]
   #[error("whoami error: {0}")]
   Whoami(#[from] whoami::Error),
 }
 
+impl Error {
+  /// A code for the kind of error that stays the same across versions, unlike the message
+  ///
+  /// Frontends and scripts should match on this, e.g. `WRONG_MODE` or `USB_TIMEOUT`, rather than
+  /// on the text of the error. Flash reports and [`Event::StepFailed`] carry it too.
+  pub fn code(&self) -> &'static str {
+    match self {
+      Error::Usb(err) => err.code(),
+      Error::IoError(err) => flashthing_core::io_error_code(err),
+      Error::Bytes(_) => "INVALID_DATA",
+      Error::InvalidOperation(_) => "INVALID_OPERATION",
+      Error::Utf8Error(_) => "INVALID_UTF8",
+      Error::NotFound => "DEVICE_NOT_FOUND",
+      Error::WrongMode => "WRONG_MODE",
+      Error::Adb(_) => "ADB_FAILED",
+      Error::BulkCmdFailed(_) => "BULKCMD_FAILED",
+      Error::UnsupportedVersion(_) => "META_UNSUPPORTED_VERSION",
+      Error::UnsupportedFeature(_) => "META_UNSUPPORTED_FEATURE",
+      Error::Json(_) => "INVALID_JSON",
+      Error::NotDir(_) => "NOT_A_DIRECTORY",
+      Error::NoMeta(_) => "META_MISSING",
+      Error::FileMissing(_) => "FILE_MISSING",
+      Error::Zip(_) => "ARCHIVE_INVALID",
+      Error::UnsupportedSecureBoot(_) => "UNSUPPORTED_SECURE_BOOT",
+      Error::SessionLost(_) => "SESSION_LOST",
+      Error::Timeout(_) => "TIMEOUT",
+      Error::ManifestMismatch(_) => "MANIFEST_MISMATCH",
+      Error::ArchiveTruncated(_) => "ARCHIVE_TRUNCATED",
+      Error::ArchiveTooLargeFor32BitZip(_) => "ARCHIVE_NOT_ZIP64",
+      Error::UnsafePath(_) => "UNSAFE_PATH",
+      Error::PartitionOverrun(_) => "PARTITION_OVERRUN",
+      Error::AssertionFailed(_) => "ASSERTION_FAILED",
+      Error::Cancelled => "CANCELLED",
+      Error::Registry(_) => "REGISTRY_FAILED",
+      Error::VerificationFailed(_) => "VERIFICATION_FAILED",
+      Error::InvalidImage(_) => "INVALID_IMAGE",
+      Error::UnknownPartition(..) => "UNKNOWN_PARTITION",
+      Error::AwaitingUserInput(_) => "AWAITING_USER_INPUT",
+      Error::Context { source, .. } => source.code(),
+      #[cfg(target_os = "linux")]
+      #[allow(deprecated)]
+      Error::Whoami(_) => "WHOAMI_FAILED",
+    }
+  }
+
+  /// Attach where the error happened, adding to any context it already has
+  ///
+  /// [`Error::Cancelled`] is returned as is, since stopping at a safe point is not a failure.
+  pub fn with_context(self, fill: impl FnOnce(&mut ErrorContext)) -> Self {
+    match self {
+      Error::Cancelled => self,
+      Error::Context { mut context, source } => {
+        fill(&mut context);
+        Error::Context { context, source }
+      }
+      err => {
+        let mut context = ErrorContext::default();
+        fill(&mut context);
+        Error::Context {
+          context: Box::new(context),
+          source: Box::new(err),
+        }
+      }
+    }
+  }
+
+  /// Where the error happened, if that is known
+  pub fn context(&self) -> Option<&ErrorContext> {
+    match self {
+      Error::Context { context, .. } => Some(context),
+      _ => None,
+    }
+  }
+
+  /// The error itself, without the context attached to it
+  pub fn root(&self) -> &Error {
+    match self {
+      Error::Context { source, .. } => source.root(),
+      err => err,
+    }
+  }
+}
+
+/// Where an error happened, see [`Error::Context`]
+///
+/// Displays as e.g. `step 7 (restorePartition system_a) at offset 0x3A000000 after 3 retries`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+  /// Index of the step that failed
+  pub step: Option<usize>,
+  /// `type` of the step that failed, as written in meta.json
+  pub step_type: Option<&'static str>,
+  /// Partition being written or read
+  pub partition: Option<String>,
+  /// Byte offset of the chunk that failed, into `partition` if there is one and on the disk otherwise
+  pub offset: Option<u64>,
+  /// Times the failed operation was retried before giving up
+  pub retries: u32,
+}
+
+impl std::fmt::Display for ErrorContext {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let mut parts = Vec::new();
+    let what = [self.step_type, self.partition.as_deref()]
+      .into_iter()
+      .flatten()
+      .collect::<Vec<_>>()
+      .join(" ");
+    match self.step {
+      Some(step) if what.is_empty() => parts.push(format!("step {}", step)),
+      Some(step) => parts.push(format!("step {} ({})", step, what)),
+      None if !what.is_empty() => parts.push(what),
+      None => {}
+    }
+    if let Some(offset) = self.offset {
+      parts.push(format!("at offset {:#X}", offset));
+    }
+    match self.retries {
+      0 => {}
+      1 => parts.push("after 1 retry".to_string()),
+      retries => parts.push(format!("after {} retries", retries)),
+    }
+    f.write_str(&parts.join(" "))
+  }
+}
+
+impl From<flashthing_core::Error> for Error {
+  fn from(err: flashthing_core::Error) -> Self {
+    use flashthing_core::Error as Core;
+    match err {
+      Core::IoError(err) => Self::IoError(err),
+      Core::Bytes(err) => Self::Bytes(err),
+      Core::InvalidOperation(message) => Self::InvalidOperation(message),
+      Core::Utf8Error(err) => Self::Utf8Error(err),
+      Core::UnsupportedSecureBoot(message) => Self::UnsupportedSecureBoot(message),
+      Core::SessionLost(reason) => Self::SessionLost(reason),
+      Core::Timeout(reason) => Self::Timeout(reason),
+      err => Self::Usb(err),
+    }
+  }
+}
+
+/// Lets transports written against this crate's errors, like the disk image target, report them to the protocol layer
+impl From<Error> for flashthing_core::Error {
+  fn from(err: Error) -> Self {
+    match err {
+      Error::Usb(err) => err,
+      Error::IoError(err) => Self::IoError(err),
+      Error::Bytes(err) => Self::Bytes(err),
+      Error::InvalidOperation(message) => Self::InvalidOperation(message),
+      Error::Utf8Error(err) => Self::Utf8Error(err),
+      Error::UnsupportedSecureBoot(message) => Self::UnsupportedSecureBoot(message),
+      Error::SessionLost(reason) => Self::SessionLost(reason),
+      Error::Timeout(reason) => Self::Timeout(reason),
+      Error::Context { source, .. } => (*source).into(),
+      err => Self::InvalidOperation(err.to_string()),
+    }
+  }
+}
+
 const SUPPORTED_META_VERSION_MIN: usize = 1;
 const SUPPORTED_META_VERSION_MAX: usize = 2;
 
 const BL2_BIN: &[u8] = include_bytes!("../resources/superbird.bl2.encrypted.bin");
 const BOOTLOADER_BIN: &[u8] = include_bytes!("../resources/superbird.bootloader.img");
 const UNBRICK_BIN_ZIP: &[u8] = include_bytes!("../resources/unbrick.bin.zip");
-const STOCK_META: &[u8] = include_bytes!("../resources/stock-meta.json");
 
 const VENDOR_ID: u16 = 0x1b8e;
 const PRODUCT_ID: u16 = 0xc003;
 
-#[allow(dead_code)]
 const VENDOR_ID_BOOTED: u16 = 0x1d6b;
-#[allow(dead_code)]
 const PRODUCT_ID_BOOTED: u16 = 0x1014;
 
 const ADDR_BL2: u32 = 0xfffa0000;
 const TRANSFER_SIZE_THRESHOLD: usize = 8 * 1024 * 1024;
 const ADDR_TMP: u32 = 0x1080000;
-
-// all requests
-const REQ_WRITE_MEM: u8 = 0x01;
-const REQ_READ_MEM: u8 = 0x02;
-#[allow(dead_code)]
-const REQ_FILL_MEM: u8 = 0x03;
-#[allow(dead_code)]
-const REQ_MODIFY_MEM: u8 = 0x04;
-const REQ_RUN_IN_ADDR: u8 = 0x05;
-#[allow(dead_code)]
-const REQ_WRITE_AUX: u8 = 0x06;
-#[allow(dead_code)]
-const REQ_READ_AUX: u8 = 0x07;
-
-const REQ_WR_LARGE_MEM: u8 = 0x11;
-#[allow(dead_code)]
-const REQ_RD_LARGE_MEM: u8 = 0x12;
-const REQ_IDENTIFY_HOST: u8 = 0x20;
-
-#[allow(dead_code)]
-const REQ_TPL_CMD: u8 = 0x30;
-#[allow(dead_code)]
-const REQ_TPL_STAT: u8 = 0x31;
-
-#[allow(dead_code)]
-const REQ_WRITE_MEDIA: u8 = 0x32;
-#[allow(dead_code)]
-const REQ_READ_MEDIA: u8 = 0x33;
-
-const REQ_BULKCMD: u8 = 0x34;
-
-#[allow(dead_code)]
-const REQ_PASSWORD: u8 = 0x35;
-#[allow(dead_code)]
-const REQ_NOP: u8 = 0x36;
-
-const REQ_GET_AMLC: u8 = 0x50;
-const REQ_WRITE_AMLC: u8 = 0x60;
-
-const FLAG_KEEP_POWER_ON: u32 = 0x10;
-
-const AMLC_AMLS_BLOCK_LENGTH: usize = 0x200;
-const AMLC_MAX_BLOCK_LENGTH: usize = 0x4000;
-const AMLC_MAX_TRANSFER_LENGTH: usize = 65536;
-
-#[allow(dead_code)]
-const WRITE_MEDIA_CHEKSUM_ALG_NONE: u16 = 0x00ee;
-#[allow(dead_code)]
-const WRITE_MEDIA_CHEKSUM_ALG_ADDSUM: u16 = 0x00ef;
-#[allow(dead_code)]
-const WRITE_MEDIA_CHEKSUM_ALG_CRC32: u16 = 0x00f0;
+const ENV_SIZE: usize = 64 * 1024; // bytes, CONFIG_ENV_SIZE of the superbird U-Boot
 
 // Constants for partition operations
 const PART_SECTOR_SIZE: usize = 512; // bytes, size of sectors used in partition table
@@ -0,0 +1,380 @@
+//! Mirrors tracing records into the event callback, so embedders get logs without a subscriber,
+//! filters them by [`Verbosity`], and keeps them for a session capture.
+
+use std::{
+  cell::Cell,
+  fmt::Write,
+  sync::{Arc, Mutex},
+  time::Instant,
+};
+
+use tracing::{
+  Dispatch, Level, Metadata,
+  span::{Attributes, Id, Record},
+  subscriber::{Interest, Subscriber},
+};
+
+use crate::{Callback, Event};
+
+/// Target of the logs written for every chunk of a transfer, at debug level
+///
+/// Long flashes write thousands of chunks, so these are kept out of the way of everything else
+/// and only [`Verbosity::Trace`] shows them. With an `EnvFilter`, `flashthing::transfer=info`
+/// hides them.
+pub const TRANSFER_TARGET: &str = "flashthing::transfer";
+
+/// How much the flasher logs, for embedders that do not want to write tracing directives
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Verbosity {
+  /// Only warnings and errors
+  Quiet,
+  /// What each step is doing, without per-chunk transfer logs
+  #[default]
+  Normal,
+  /// Debug logs too, still without per-chunk transfer logs
+  Verbose,
+  /// Everything, including a log for every chunk transferred
+  Trace,
+}
+
+impl Verbosity {
+  /// Most verbose level logged for `target`
+  fn max_level(&self, target: &str) -> Level {
+    let level = match self {
+      Self::Quiet => Level::WARN,
+      Self::Normal => Level::INFO,
+      Self::Verbose => Level::DEBUG,
+      Self::Trace => Level::TRACE,
+    };
+    if *self != Self::Trace && target.starts_with(TRANSFER_TARGET) {
+      return level.min(Level::INFO);
+    }
+    level
+  }
+
+  /// Whether a record is logged at this verbosity
+  pub fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+    *metadata.level() <= self.max_level(metadata.target())
+  }
+
+  /// The same filtering as `EnvFilter` directives, for embedders that install their own subscriber
+  pub fn directives(&self) -> &'static str {
+    match self {
+      Self::Quiet => "flashthing=warn",
+      Self::Normal => "flashthing=info",
+      Self::Verbose => "flashthing=debug,flashthing::transfer=info",
+      Self::Trace => "flashthing=trace",
+    }
+  }
+}
+
+thread_local! {
+  /// Set while a record is being handed to the callback, so logging from inside it cannot recurse
+  static MIRRORING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Subscriber that sends events at or above `level` to the callback, then passes everything on
+/// to whatever subscriber was the default when it was created
+pub(crate) struct LogMirror {
+  callback: Callback,
+  level: Level,
+  inner: Dispatch,
+}
+
+impl LogMirror {
+  /// Build a dispatcher that mirrors to `callback` and forwards to the current default
+  pub(crate) fn dispatch(callback: Callback, level: Level) -> Dispatch {
+    let inner = tracing::dispatcher::get_default(Dispatch::clone);
+    Dispatch::new(Self { callback, level, inner })
+  }
+
+  fn mirrors(&self, metadata: &Metadata<'_>) -> bool {
+    metadata.is_event() && *metadata.level() <= self.level
+  }
+}
+
+impl Subscriber for LogMirror {
+  fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+    // the answer depends on the inner subscriber too, so ask every time
+    Interest::sometimes()
+  }
+
+  fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+    self.mirrors(metadata) || self.inner.enabled(metadata)
+  }
+
+  fn new_span(&self, span: &Attributes<'_>) -> Id {
+    self.inner.new_span(span)
+  }
+
+  fn record(&self, span: &Id, values: &Record<'_>) {
+    self.inner.record(span, values)
+  }
+
+  fn record_follows_from(&self, span: &Id, follows: &Id) {
+    self.inner.record_follows_from(span, follows)
+  }
+
+  fn event(&self, event: &tracing::Event<'_>) {
+    let metadata = event.metadata();
+    if self.inner.enabled(metadata) {
+      self.inner.event(event);
+    }
+    if !self.mirrors(metadata) || MIRRORING.get() {
+      return;
+    }
+
+    let mut message = String::new();
+    event.record(&mut MessageVisitor(&mut message));
+
+    MIRRORING.set(true);
+    (self.callback)(Event::Log {
+      level: *metadata.level(),
+      target: metadata.target().to_owned(),
+      message,
+    });
+    MIRRORING.set(false);
+  }
+
+  fn enter(&self, span: &Id) {
+    self.inner.enter(span)
+  }
+
+  fn exit(&self, span: &Id) {
+    self.inner.exit(span)
+  }
+
+  fn clone_span(&self, id: &Id) -> Id {
+    self.inner.clone_span(id)
+  }
+
+  fn try_close(&self, id: Id) -> bool {
+    self.inner.try_close(id)
+  }
+}
+
+/// Subscriber that passes on only what a [`Verbosity`] logs
+pub(crate) struct LogFilter {
+  verbosity: Verbosity,
+  inner: Dispatch,
+}
+
+impl LogFilter {
+  /// Build a dispatcher that filters by `verbosity` and forwards to `inner`
+  pub(crate) fn dispatch(verbosity: Verbosity, inner: Dispatch) -> Dispatch {
+    Dispatch::new(Self { verbosity, inner })
+  }
+}
+
+impl Subscriber for LogFilter {
+  fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+    Interest::sometimes()
+  }
+
+  fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+    self.verbosity.enabled(metadata) && self.inner.enabled(metadata)
+  }
+
+  fn new_span(&self, span: &Attributes<'_>) -> Id {
+    self.inner.new_span(span)
+  }
+
+  fn record(&self, span: &Id, values: &Record<'_>) {
+    self.inner.record(span, values)
+  }
+
+  fn record_follows_from(&self, span: &Id, follows: &Id) {
+    self.inner.record_follows_from(span, follows)
+  }
+
+  fn event(&self, event: &tracing::Event<'_>) {
+    if self.enabled(event.metadata()) {
+      self.inner.event(event);
+    }
+  }
+
+  fn enter(&self, span: &Id) {
+    self.inner.enter(span)
+  }
+
+  fn exit(&self, span: &Id) {
+    self.inner.exit(span)
+  }
+
+  fn clone_span(&self, id: &Id) -> Id {
+    self.inner.clone_span(id)
+  }
+
+  fn try_close(&self, id: Id) -> bool {
+    self.inner.try_close(id)
+  }
+}
+
+/// Subscriber that keeps every event as a line of text, whatever its level, then passes everything
+/// on to another subscriber
+pub(crate) struct LogCapture {
+  lines: Arc<Mutex<Vec<String>>>,
+  start: Instant,
+  inner: Dispatch,
+}
+
+impl LogCapture {
+  /// Build a dispatcher that appends to `lines` and forwards to `inner`
+  pub(crate) fn dispatch(lines: Arc<Mutex<Vec<String>>>, inner: Dispatch) -> Dispatch {
+    Dispatch::new(Self {
+      lines,
+      start: Instant::now(),
+      inner,
+    })
+  }
+}
+
+impl Subscriber for LogCapture {
+  fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+    Interest::sometimes()
+  }
+
+  fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+    metadata.is_event() || self.inner.enabled(metadata)
+  }
+
+  fn new_span(&self, span: &Attributes<'_>) -> Id {
+    self.inner.new_span(span)
+  }
+
+  fn record(&self, span: &Id, values: &Record<'_>) {
+    self.inner.record(span, values)
+  }
+
+  fn record_follows_from(&self, span: &Id, follows: &Id) {
+    self.inner.record_follows_from(span, follows)
+  }
+
+  fn event(&self, event: &tracing::Event<'_>) {
+    let metadata = event.metadata();
+    if self.inner.enabled(metadata) {
+      self.inner.event(event);
+    }
+
+    let mut message = String::new();
+    event.record(&mut MessageVisitor(&mut message));
+    let line = format!(
+      "{:>10.3} {:>5} {}: {}",
+      self.start.elapsed().as_secs_f64(),
+      metadata.level(),
+      metadata.target(),
+      message
+    );
+    if let Ok(mut lines) = self.lines.lock() {
+      lines.push(line);
+    }
+  }
+
+  fn enter(&self, span: &Id) {
+    self.inner.enter(span)
+  }
+
+  fn exit(&self, span: &Id) {
+    self.inner.exit(span)
+  }
+
+  fn clone_span(&self, id: &Id) -> Id {
+    self.inner.clone_span(id)
+  }
+
+  fn try_close(&self, id: Id) -> bool {
+    self.inner.try_close(id)
+  }
+}
+
+/// Formats the `message` field as-is and any other fields as `name=value`
+struct MessageVisitor<'a>(&'a mut String);
+
+impl tracing::field::Visit for MessageVisitor<'_> {
+  fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+    if field.name() == "message" {
+      let _ = write!(self.0, "{:?}", value);
+    } else {
+      if !self.0.is_empty() {
+        self.0.push(' ');
+      }
+      let _ = write!(self.0, "{}={:?}", field.name(), value);
+    }
+  }
+
+  fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+    if field.name() == "message" {
+      self.0.push_str(value);
+    } else {
+      if !self.0.is_empty() {
+        self.0.push(' ');
+      }
+      let _ = write!(self.0, "{}={}", field.name(), value);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+
+  use super::*;
+
+  #[test]
+  fn test_log_mirror() {
+    let logs = Arc::new(Mutex::new(Vec::new()));
+    let sink = logs.clone();
+    let callback: Callback = Arc::new(move |event| {
+      if let Event::Log { level, message, .. } = event {
+        // logging from the callback must not loop back into it
+        tracing::warn!("mirrored {}", message);
+        sink.lock().unwrap().push((level, message));
+      }
+    });
+
+    tracing::dispatcher::with_default(&LogMirror::dispatch(callback, Level::INFO), || {
+      tracing::debug!("too verbose");
+      tracing::info!(step = 3, "restoring {}", "boot_a");
+      tracing::error!("failed");
+    });
+
+    let logs = logs.lock().unwrap();
+    assert_eq!(
+      *logs,
+      vec![
+        (Level::INFO, "restoring boot_a step=3".to_owned()),
+        (Level::ERROR, "failed".to_owned())
+      ]
+    );
+  }
+
+  #[test]
+  fn test_verbosity() {
+    let logs = Arc::new(Mutex::new(Vec::new()));
+    let sink = logs.clone();
+    let callback: Callback = Arc::new(move |event| {
+      if let Event::Log { message, .. } = event {
+        sink.lock().unwrap().push(message);
+      }
+    });
+
+    let log = || {
+      tracing::debug!("reading boot_a");
+      tracing::debug!(target: TRANSFER_TARGET, "progress: 50.0%");
+      tracing::info!("restoring boot_a");
+      tracing::warn!("device is warm");
+    };
+    let mut logged = Vec::new();
+    for verbosity in [
+      Verbosity::Quiet,
+      Verbosity::Normal,
+      Verbosity::Verbose,
+      Verbosity::Trace,
+    ] {
+      let mirror = LogMirror::dispatch(callback.clone(), Level::TRACE);
+      tracing::dispatcher::with_default(&LogFilter::dispatch(verbosity, mirror), log);
+      logged.push(std::mem::take(&mut *logs.lock().unwrap()).len());
+    }
+    assert_eq!(logged, [1, 2, 3, 4]);
+  }
+}
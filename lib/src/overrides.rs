@@ -0,0 +1,136 @@
+//! Per-device values for a package's `variables`, so one package can flash a known fleet with
+//! settings like calibration data or the boot slot differing from device to device.
+
+use std::{collections::HashMap, fs::read_to_string, path::Path};
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{Error, Result, config::FlashConfig};
+
+/// Name of the overrides file a package may hold next to its `meta.json`
+pub const OVERRIDES_FILE: &str = "overrides.json";
+
+/// Values for a package's `variables` by device serial number
+///
+/// When a listed device is flashed, its values are merged over the package's `variables`, so steps
+/// referring to them as `${name}` get the device's value instead.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Overrides {
+  /// Variable values by the serial number the device reports over USB
+  #[serde(deserialize_with = "devices")]
+  pub devices: HashMap<String, HashMap<String, String>>,
+}
+
+impl Overrides {
+  /// Load an overrides file
+  ///
+  /// # Parameters
+  /// - `path`: Path to the file, usually named [`OVERRIDES_FILE`]
+  ///
+  /// # Returns
+  /// - `Result<Self>`: The overrides or an error
+  pub fn load(path: &Path) -> Result<Self> {
+    Self::parse(&read_to_string(path)?)
+  }
+
+  /// Parse overrides from a JSON string
+  ///
+  /// # Returns
+  /// - `Result<Self>`: The overrides or an error
+  pub fn parse(json: &str) -> Result<Self> {
+    serde_json::from_str(json).map_err(|e| Error::InvalidOperation(format!("{} is invalid: {}", OVERRIDES_FILE, e)))
+  }
+
+  /// The values for the device with serial number `serial`, if it is listed
+  pub fn for_device(&self, serial: &str) -> Option<&HashMap<String, String>> {
+    self.devices.get(serial)
+  }
+
+  /// Check that every overridden name is one of the `variables` of `config`
+  ///
+  /// Overrides can only replace values the package already has, so a typo can't go unnoticed.
+  pub(crate) fn check(&self, config: &FlashConfig) -> Result<()> {
+    let variables = config.variables.as_ref();
+    for (serial, values) in &self.devices {
+      if let Some(name) = values
+        .keys()
+        .find(|name| !variables.is_some_and(|variables| variables.contains_key(*name)))
+      {
+        return Err(Error::InvalidOperation(format!(
+          "overrides for device {} set `{}`, which isn't one of the package's variables",
+          serial, name
+        )));
+      }
+    }
+    Ok(())
+  }
+}
+
+/// A value in an overrides file, which may be written as a string or a number
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawValue {
+  String(String),
+  Number(serde_json::Number),
+}
+
+fn devices<'de, D: Deserializer<'de>>(
+  deserializer: D,
+) -> std::result::Result<HashMap<String, HashMap<String, String>>, D::Error> {
+  let raw = HashMap::<String, HashMap<String, RawValue>>::deserialize(deserializer)?;
+  Ok(
+    raw
+      .into_iter()
+      .map(|(serial, values)| {
+        let values = values
+          .into_iter()
+          .map(|(name, value)| {
+            let value = match value {
+              RawValue::String(text) => text,
+              RawValue::Number(number) => number.to_string(),
+            };
+            (name, value)
+          })
+          .collect();
+        (serial, values)
+      })
+      .collect(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_overrides() {
+    let overrides = Overrides::parse(
+      r#"{
+        "devices": {
+          "8RBC24A0001": { "slot": "b", "calibration": 4660 },
+          "8RBC24A0002": { "slot": "a" }
+        }
+      }"#,
+    )
+    .unwrap();
+    let device = overrides.for_device("8RBC24A0001").unwrap();
+    assert_eq!(device["slot"], "b");
+    assert_eq!(device["calibration"], "4660");
+    assert!(overrides.for_device("8RBC24A0003").is_none());
+    assert!(Overrides::parse(r#"{ "devices": { "8RBC24A0001": { "slot": ["a"] } } }"#).is_err());
+
+    let config = FlashConfig::from_standalone(
+      r#"{
+        "metadataVersion": 2, "name": "overrides", "version": "0.1.0", "description": "overrides.",
+        "variables": { "slot": 0, "calibration": 0 },
+        "steps": [{ "type": "bulkcmd", "value": "setenv slot ${slot}" }]
+      }"#,
+    )
+    .unwrap();
+    assert!(overrides.check(&config).is_ok());
+
+    let typo = Overrides::parse(r#"{ "devices": { "8RBC24A0001": { "solt": "b" } } }"#).unwrap();
+    let err = typo.check(&config).unwrap_err();
+    assert!(err.to_string().contains("`solt`"), "{err}");
+  }
+}
@@ -0,0 +1,333 @@
+//! Looking inside a flash package without a device connected, e.g. to show it before flashing.
+
+use std::{
+  io::{self, Read},
+  path::Path,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+  DeviceProfile, Error, Result, SUPPORTED_META_VERSION_MAX, SUPPORTED_META_VERSION_MIN,
+  archive::{open_archive, package_root},
+  config::{DataOrFile, FlashConfig, FlashStep, MetaFile, StringOrFile},
+  dump::open_joined,
+  flash::FlashMode,
+  paths::{PathPolicy, resolve_in_archive, resolve_in_directory},
+};
+
+/// A flash package opened to read, without connecting to a device
+pub struct Package {
+  config: FlashConfig,
+  mode: FlashMode,
+  files: Vec<PackageFile>,
+}
+
+/// A file a package's steps refer to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageFile {
+  /// The `filePath` as written in `meta.json`
+  pub file_path: String,
+  /// Size in bytes as stored in the package, or `None` if the package does not have it
+  pub size: Option<u64>,
+}
+
+/// What a step does, in enough detail to list it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepSummary {
+  /// Position of the step, counting from 1
+  pub number: usize,
+  /// The step's id
+  pub id: String,
+  /// The step's `type` as written in `meta.json`
+  pub kind: &'static str,
+  /// The partition the step works on, if it names one
+  pub partition: Option<String>,
+  /// The files the step reads, including those of steps nested in it
+  pub files: Vec<String>,
+}
+
+/// Whether a package can be flashed by this version of flashthing to a Car Thing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compatibility {
+  /// Version of the metadata format the package is written in
+  pub metadata_version: usize,
+  /// Oldest and newest metadata versions this version of flashthing supports
+  pub supported_versions: (usize, usize),
+  /// Whether the package asks for special partitions like `reserved` with `allowSpecialPartitions`
+  pub special_partitions: bool,
+  /// Partitions the steps name that the Car Thing does not have
+  pub unknown_partitions: Vec<String>,
+  /// Files the steps refer to that the package does not have
+  pub missing_files: Vec<String>,
+}
+
+impl Compatibility {
+  /// Whether nothing stands in the way of flashing the package
+  pub fn is_compatible(&self) -> bool {
+    (self.supported_versions.0..=self.supported_versions.1).contains(&self.metadata_version)
+      && self.unknown_partitions.is_empty()
+      && self.missing_files.is_empty()
+  }
+}
+
+impl Package {
+  /// Open a package directory or zip archive and list the files its steps refer to
+  ///
+  /// # Parameters
+  /// - `path`: A directory with a `meta.json`, or a zip archive with one at its root or in a single top-level folder
+  ///
+  /// # Returns
+  /// - `Result<Self>`: The package, or an error if it is missing or its `meta.json` is invalid
+  pub fn open(path: &Path) -> Result<Self> {
+    let (config, mode) = if path.is_dir() {
+      (
+        FlashConfig::from_directory(&path.to_path_buf())?,
+        FlashMode::Directory(path.to_owned()),
+      )
+    } else if path.is_file() {
+      let mut zip = open_archive(path)?;
+      let config = FlashConfig::from_archive(&mut zip)?;
+      let root = package_root(&zip, "meta.json")?.unwrap_or_default();
+      (config, FlashMode::Archive(zip, root, path.to_owned()))
+    } else {
+      return Err(Error::NotFound);
+    };
+
+    let mut this = Self {
+      config,
+      mode,
+      files: Vec::new(),
+    };
+    let mut paths = this
+      .config
+      .steps
+      .iter()
+      .flat_map(|step| step.walk())
+      .flat_map(step_files)
+      .map(|file| file.file_path.clone())
+      .collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+    this.files = paths
+      .into_iter()
+      .map(|file_path| PackageFile {
+        size: this.size(&file_path),
+        file_path,
+      })
+      .collect();
+    Ok(this)
+  }
+
+  /// Name of the package
+  pub fn name(&self) -> &str {
+    &self.config.name
+  }
+
+  /// Version of the package
+  pub fn version(&self) -> &str {
+    &self.config.version
+  }
+
+  /// Description of what the package does
+  pub fn description(&self) -> &str {
+    &self.config.description
+  }
+
+  /// The package's `meta.json`, as it is loaded for flashing
+  pub fn config(&self) -> &FlashConfig {
+    &self.config
+  }
+
+  /// The steps of the package in the order they run
+  pub fn steps(&self) -> Vec<StepSummary> {
+    (self.config.steps.iter().enumerate())
+      .map(|(index, step)| {
+        let mut files = (step.walk().into_iter())
+          .flat_map(step_files)
+          .map(|file| file.file_path.clone())
+          .collect::<Vec<_>>();
+        files.dedup();
+        StepSummary {
+          number: index + 1,
+          id: step.id.clone().unwrap_or_default(),
+          kind: step.step.kind(),
+          partition: written_partition(&step.step).map(str::to_owned),
+          files,
+        }
+      })
+      .collect()
+  }
+
+  /// The files the steps refer to, sorted by path, each listed once
+  pub fn files(&self) -> &[PackageFile] {
+    &self.files
+  }
+
+  /// Total size of the files the steps refer to, counting each file once
+  pub fn total_bytes(&self) -> u64 {
+    self.files.iter().filter_map(|file| file.size).sum()
+  }
+
+  /// SHA-256 digest of a file in the package, as lowercase hex
+  ///
+  /// This reads the whole file, so it is left to callers that want it rather than done on open.
+  pub fn digest(&mut self, file_path: &str) -> Result<String> {
+    digest_file(&mut self.mode, file_path, PathPolicy::Strict).map(|(_, digest)| digest)
+  }
+
+  /// What could stop the package being flashed to a Car Thing
+  pub fn compatibility(&self) -> Compatibility {
+    let partitions = DeviceProfile::default().partitions;
+    let mut unknown_partitions = (self.config.steps.iter())
+      .flat_map(|step| step.walk())
+      .filter_map(written_partition)
+      // variables only get values while flashing
+      .filter(|name| !name.contains("${") && partitions.resolve(name).is_err())
+      .map(str::to_owned)
+      .collect::<Vec<_>>();
+    unknown_partitions.sort();
+    unknown_partitions.dedup();
+
+    Compatibility {
+      metadata_version: self.config.metadata_version,
+      supported_versions: (SUPPORTED_META_VERSION_MIN, SUPPORTED_META_VERSION_MAX),
+      special_partitions: self.config.allow_special_partitions.unwrap_or(false),
+      unknown_partitions,
+      missing_files: (self.files.iter())
+        .filter(|file| file.size.is_none())
+        .map(|file| file.file_path.clone())
+        .collect(),
+    }
+  }
+
+  fn size(&mut self, file_path: &str) -> Option<u64> {
+    match &mut self.mode {
+      FlashMode::Directory(root) => {
+        let path = resolve_in_directory(root, file_path, PathPolicy::Strict).ok()?;
+        open_joined(&path).ok().map(|(size, _)| size)
+      }
+      FlashMode::Archive(zip, root, _) => {
+        let index = resolve_in_archive(zip, root, file_path, PathPolicy::Strict).ok()?;
+        zip.by_index(index).ok().map(|file| file.size())
+      }
+      FlashMode::Standalone => None,
+    }
+  }
+}
+
+/// Size and SHA-256 digest of a file in a package, the digest as lowercase hex
+pub(crate) fn digest_file(mode: &mut FlashMode, file_path: &str, policy: PathPolicy) -> Result<(u64, String)> {
+  let mut reader: Box<dyn Read + '_> = match mode {
+    FlashMode::Directory(root) => open_joined(&resolve_in_directory(root, file_path, policy)?)?.1,
+    FlashMode::Archive(zip, root, _) => {
+      let index = resolve_in_archive(zip, root, file_path, policy)?;
+      Box::new(zip.by_index(index)?)
+    }
+    FlashMode::Standalone => return Err(Error::NotFound),
+  };
+  let mut hasher = Sha256::new();
+  let size = io::copy(&mut reader, &mut hasher)?;
+  Ok((size, hex::encode(hasher.finalize())))
+}
+
+/// The partition a step names, if any
+fn written_partition(step: &FlashStep) -> Option<&str> {
+  match step {
+    FlashStep::WriteLargeMemory { value } => value.partition.as_deref(),
+    FlashStep::Provision { value } => value.data.as_ref().map(|data| data.partition.as_str()),
+    step => step.partition(),
+  }
+}
+
+/// The files one step reads, not counting steps nested in it
+pub(crate) fn step_files(step: &FlashStep) -> Vec<&MetaFile> {
+  fn data(data: &DataOrFile) -> Vec<&MetaFile> {
+    match data {
+      DataOrFile::File(file) => vec![file],
+      DataOrFile::Concat { concat } => concat.iter().collect(),
+      _ => Vec::new(),
+    }
+  }
+  match step {
+    FlashStep::WriteSimpleMemory { value } => data(&value.data),
+    FlashStep::WriteLargeMemory { value } => data(&value.data),
+    FlashStep::WriteAMLCData { value } => data(&value.data),
+    FlashStep::Bl2Boot { value } => [data(&value.bl2), data(&value.bootloader)]
+      .into_iter()
+      .flatten()
+      .collect(),
+    FlashStep::RestorePartition { value } => data(&value.data),
+    FlashStep::ApplyDelta { value } => data(&value.delta),
+    FlashStep::WriteBootPartition { value } => data(&value.data),
+    FlashStep::WriteUserArea { value } => data(&value.data),
+    FlashStep::WriteEnv {
+      value: StringOrFile::File(file),
+    } => vec![file],
+    FlashStep::WriteBootScript { value } => match &value.script {
+      StringOrFile::File(file) => vec![file],
+      StringOrFile::String(_) => Vec::new(),
+    },
+    _ => Vec::new(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_open_package() {
+    let dir = std::env::temp_dir().join(format!("flashthing-package-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let meta = r#"{
+      "name": "inspect", "version": "1.2.3", "description": "a package", "metadataVersion": 1,
+      "steps": [
+        { "type": "restorePartition", "value": { "name": "logo", "data": { "filePath": "logo.img" } }, "id": "logo" },
+        { "type": "repeat", "value": { "count": 2, "steps": [
+          { "type": "restorePartition", "value": { "name": "logo", "data": { "filePath": "logo.img" } } },
+          { "type": "restorePartition", "value": { "name": "nonexistent", "data": { "filePath": "missing.img" } } }
+        ] } }
+      ]
+    }"#;
+    std::fs::write(dir.join("meta.json"), meta).unwrap();
+    std::fs::write(dir.join("logo.img"), [7; 100]).unwrap();
+
+    let mut package = Package::open(&dir).unwrap();
+    assert_eq!((package.name(), package.version()), ("inspect", "1.2.3"));
+    assert_eq!(
+      package.files(),
+      [
+        PackageFile {
+          file_path: "logo.img".into(),
+          size: Some(100)
+        },
+        PackageFile {
+          file_path: "missing.img".into(),
+          size: None
+        },
+      ]
+    );
+    assert_eq!(package.total_bytes(), 100);
+
+    let steps = package.steps();
+    assert_eq!(steps[0].partition.as_deref(), Some("logo"));
+    assert_eq!(
+      (steps[1].kind, steps[1].files.clone()),
+      ("repeat", vec!["logo.img".into(), "missing.img".into()])
+    );
+
+    let compatibility = package.compatibility();
+    assert_eq!(compatibility.unknown_partitions, ["nonexistent"]);
+    assert_eq!(compatibility.missing_files, ["missing.img"]);
+    assert!(!compatibility.is_compatible());
+
+    assert_eq!(
+      package.digest("logo.img").unwrap(),
+      hex::encode(Sha256::digest([7; 100]))
+    );
+    assert!(package.digest("missing.img").is_err());
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+}
@@ -0,0 +1,88 @@
+//! Finding the zero or 0xff padding dumps are filled out to their partition's size with, so
+//! restoring one can stop where its data does.
+
+use std::io::{self, Read};
+
+use crate::PART_SECTOR_SIZE;
+
+/// Bytes read at a time while looking for the padding
+const SCAN_CHUNK: usize = 1024 * 1024;
+
+/// Padding a file ends with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Padding {
+  /// Bytes before the padding, rounded up to a whole sector
+  pub data_len: usize,
+  /// Byte the file is padded with, 0 or 0xff
+  pub byte: u8,
+}
+
+/// Read `reader` to the end and find where the padding it ends with starts
+///
+/// Returns `None` when the file does not end in zeros or 0xff, or ends in less than a sector of them.
+pub(crate) fn find_padding(mut reader: impl Read) -> io::Result<Option<Padding>> {
+  let mut buf = vec![0; SCAN_CHUNK];
+  let (mut len, mut last) = (0, None);
+  // one past the last byte that is not a zero, and not 0xff
+  let (mut zero_from, mut ff_from) = (0, 0);
+  loop {
+    let read = match reader.read(&mut buf) {
+      Ok(0) => break,
+      Ok(read) => read,
+      Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+      Err(err) => return Err(err),
+    };
+    let chunk = &buf[..read];
+    if let Some(at) = chunk.iter().rposition(|byte| *byte != 0) {
+      zero_from = len + at + 1;
+    }
+    if let Some(at) = chunk.iter().rposition(|byte| *byte != 0xff) {
+      ff_from = len + at + 1;
+    }
+    last = chunk.last().copied();
+    len += read;
+  }
+
+  let (byte, data_end) = match last {
+    Some(0) => (0, zero_from),
+    Some(0xff) => (0xff, ff_from),
+    _ => return Ok(None),
+  };
+  let data_len = data_end.next_multiple_of(PART_SECTOR_SIZE);
+  Ok((data_len + PART_SECTOR_SIZE <= len).then_some(Padding { data_len, byte }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_find_padding() {
+    let mut dump = vec![0xaa; 1000];
+    dump.resize(SCAN_CHUNK + 4096, 0);
+    assert_eq!(
+      find_padding(dump.as_slice()).unwrap(),
+      Some(Padding {
+        data_len: 1024,
+        byte: 0
+      })
+    );
+
+    let mut erased = vec![0; 512];
+    erased.resize(2048, 0xff);
+    assert_eq!(
+      find_padding(erased.as_slice()).unwrap(),
+      Some(Padding {
+        data_len: 512,
+        byte: 0xff
+      })
+    );
+
+    // less than a sector of padding, or none at all
+    assert_eq!(find_padding([1u8; 1000].as_slice()).unwrap(), None);
+    let mut short = vec![1; 1000];
+    short.resize(1100, 0);
+    assert_eq!(find_padding(short.as_slice()).unwrap(), None);
+    assert_eq!(find_padding([0u8; 0].as_slice()).unwrap(), None);
+  }
+}
@@ -1,13 +1,19 @@
-//! Partitions for Superbird, extracted from output of: bulkcmd 'amlmmc part 1'
+//! Partition layouts. The Superbird table is extracted from output of: bulkcmd 'amlmmc part 1'
 
-use std::collections::HashMap;
+use std::borrow::Cow;
 
-use lazy_static::lazy_static;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{Error, PART_SECTOR_SIZE, Result};
 
 /// Information about a partition on the device
-#[derive(Debug, Clone)]
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
 pub struct PartitionInfo {
-  /// Offset in bytes
+  /// Partition name as known to U-Boot
+  pub name: Cow<'static, str>,
+  /// Offset in 512-byte sectors
   pub offset: usize,
   /// Size in 512-byte sectors
   pub size: usize,
@@ -15,100 +21,342 @@ pub struct PartitionInfo {
   pub size_alt: Option<usize>,
 }
 
-lazy_static! {
-    /// Partition table for Superbird
-    pub static ref SUPERBIRD_PARTITIONS: HashMap<&'static str, PartitionInfo> = {
-        let mut m = HashMap::new();
-        m.insert("bootloader", PartitionInfo {
-            offset: 0,
-            size: 4096,
-            size_alt: None,
-        });
-        m.insert("reserved", PartitionInfo {
-            offset: 73728,
-            size: 131072,
-            size_alt: None,
-        });
-        m.insert("cache", PartitionInfo {
-            offset: 221184,
-            size: 0,
-            size_alt: None,
-        });
-        m.insert("env", PartitionInfo {
-            offset: 237568,
-            size: 16384,
-            size_alt: None,
-        });
-        m.insert("fip_a", PartitionInfo {
-            offset: 270336,
-            size: 8192,
-            size_alt: None,
-        });
-        m.insert("fip_b", PartitionInfo {
-            offset: 294912,
-            size: 8192,
-            size_alt: None,
-        });
-        m.insert("logo", PartitionInfo {
-            offset: 319488,
-            size: 16384,
-            size_alt: None,
-        });
-        m.insert("dtbo_a", PartitionInfo {
-            offset: 352256,
-            size: 8192,
-            size_alt: None,
-        });
-        m.insert("dtbo_b", PartitionInfo {
-            offset: 376832,
-            size: 8192,
-            size_alt: None,
-        });
-        m.insert("vbmeta_a", PartitionInfo {
-            offset: 401408,
-            size: 2048,
-            size_alt: None,
-        });
-        m.insert("vbmeta_b", PartitionInfo {
-            offset: 419840,
-            size: 2048,
-            size_alt: None,
-        });
-        m.insert("boot_a", PartitionInfo {
-            offset: 438272,
-            size: 32768,
-            size_alt: None,
-        });
-        m.insert("boot_b", PartitionInfo {
-            offset: 487424,
-            size: 32768,
-            size_alt: None,
-        });
-        m.insert("system_a", PartitionInfo {
-            offset: 536576,
-            size: 1056856,
-            size_alt: None,
-        });
-        m.insert("system_b", PartitionInfo {
-            offset: 1609816,
-            size: 1056856,
-            size_alt: None,
-        });
-        m.insert("misc", PartitionInfo {
-            offset: 2683056,
-            size: 16384,
-            size_alt: None,
-        });
-        m.insert("settings", PartitionInfo {
-            offset: 2715824,
-            size: 524288,
-            size_alt: None,
-        });
-        m.insert("data", PartitionInfo {
-            offset: 3256496,
-            size: 4476752,
-            size_alt: Some(4378448),  // some devices have a smaller data partition
-        });
-        m
+impl PartitionInfo {
+  const fn new(name: &'static str, offset: usize, size: usize) -> Self {
+    Self {
+      name: Cow::Borrowed(name),
+      offset,
+      size,
+      size_alt: None,
+    }
+  }
+
+  /// Offset of the partition in bytes
+  pub fn offset_bytes(&self) -> usize {
+    self.offset * PART_SECTOR_SIZE
+  }
+
+  /// Size of the partition in bytes
+  pub fn size_bytes(&self) -> usize {
+    self.size * PART_SECTOR_SIZE
+  }
+
+  /// First sector past the end of the partition
+  ///
+  /// Uses the larger of `size` and `size_alt`, since either may be present on a given device.
+  pub fn end(&self) -> usize {
+    self.offset + std::cmp::max(self.size, self.size_alt.unwrap_or(0))
+  }
+
+  /// Whether the given absolute sector falls inside the partition
+  pub fn contains(&self, lba: usize) -> bool {
+    (self.offset..self.end()).contains(&lba)
+  }
+}
+
+/// A partition layout, kept in on-disk order
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct PartitionTable {
+  partitions: Cow<'static, [PartitionInfo]>,
+}
+
+const SUPERBIRD: &[PartitionInfo] = &[
+  PartitionInfo::new("bootloader", 0, 4096),
+  PartitionInfo::new("reserved", 73728, 131072),
+  PartitionInfo::new("cache", 221184, 0),
+  PartitionInfo::new("env", 237568, 16384),
+  PartitionInfo::new("fip_a", 270336, 8192),
+  PartitionInfo::new("fip_b", 294912, 8192),
+  PartitionInfo::new("logo", 319488, 16384),
+  PartitionInfo::new("dtbo_a", 352256, 8192),
+  PartitionInfo::new("dtbo_b", 376832, 8192),
+  PartitionInfo::new("vbmeta_a", 401408, 2048),
+  PartitionInfo::new("vbmeta_b", 419840, 2048),
+  PartitionInfo::new("boot_a", 438272, 32768),
+  PartitionInfo::new("boot_b", 487424, 32768),
+  PartitionInfo::new("system_a", 536576, 1056856),
+  PartitionInfo::new("system_b", 1609816, 1056856),
+  PartitionInfo::new("misc", 2683056, 16384),
+  PartitionInfo::new("settings", 2715824, 524288),
+  PartitionInfo {
+    name: Cow::Borrowed("data"),
+    offset: 3256496,
+    size: 4476752,
+    size_alt: Some(4378448), // some devices have a smaller data partition
+  },
+];
+
+/// Alternate spellings accepted for partitions, after normalization
+const ALIASES: &[(&str, &str)] = &[
+  ("boot0", "bootloader"),
+  ("uboot", "bootloader"),
+  ("u_boot", "bootloader"),
+  ("userdata", "data"),
+];
+
+/// Normalize a partition name to the form U-Boot expects
+///
+/// Names are trimmed, lowercased, and have `-` replaced with `_`, then common aliases
+/// (`boot0`, `uboot`, `userdata`) are mapped to their canonical names. This does not check
+/// that the partition exists, see [`PartitionTable::resolve`] for that.
+pub fn canonical_partition_name(name: &str) -> String {
+  let name = name.trim().to_lowercase().replace('-', "_");
+  match ALIASES.iter().find(|(alias, _)| *alias == name) {
+    Some((_, canonical)) => (*canonical).to_owned(),
+    None => name,
+  }
+}
+
+impl PartitionTable {
+  /// Build a table from a list of partitions, sorting them into on-disk order
+  pub fn new(mut partitions: Vec<PartitionInfo>) -> Self {
+    partitions.sort_by_key(|part| part.offset);
+    Self {
+      partitions: Cow::Owned(partitions),
+    }
+  }
+
+  /// Partition table for Superbird
+  pub const fn superbird() -> Self {
+    Self {
+      partitions: Cow::Borrowed(SUPERBIRD),
+    }
+  }
+
+  /// Look up a partition by name
+  pub fn get(&self, name: &str) -> Option<&PartitionInfo> {
+    self.partitions.iter().find(|part| part.name == name)
+  }
+
+  /// Look up a partition by name, accepting aliases and any case
+  ///
+  /// # Returns
+  /// - `Result<&PartitionInfo>`: The partition or an error listing the valid names
+  pub fn resolve(&self, name: &str) -> Result<&PartitionInfo> {
+    self
+      .get(&canonical_partition_name(name))
+      .ok_or_else(|| Error::UnknownPartition(name.to_owned(), self.iter().map(|part| part.name.to_string()).collect()))
+  }
+
+  /// Iterate over the partitions in on-disk order
+  pub fn iter(&self) -> impl Iterator<Item = &PartitionInfo> {
+    self.partitions.iter()
+  }
+
+  /// Number of partitions in the table
+  pub fn len(&self) -> usize {
+    self.partitions.len()
+  }
+
+  /// Whether the table has no partitions
+  pub fn is_empty(&self) -> bool {
+    self.partitions.is_empty()
+  }
+
+  /// Find the partition holding the given absolute byte offset
+  ///
+  /// # Returns
+  /// - `Option<(&PartitionInfo, usize)>`: The partition and the byte offset relative to its start
+  pub fn locate(&self, byte_offset: usize) -> Option<(&PartitionInfo, usize)> {
+    let lba = byte_offset / PART_SECTOR_SIZE;
+    self
+      .partitions
+      .iter()
+      .find(|part| part.contains(lba))
+      .map(|part| (part, byte_offset - part.offset_bytes()))
+  }
+
+  /// Describe how a write of `length` bytes at an absolute byte offset runs past the end of the
+  /// partition it starts in
+  ///
+  /// # Returns
+  /// - `Option<String>`: Where the write crosses the boundary, or `None` if it stays inside the
+  ///   partition or doesn't start in one
+  pub fn overrun(&self, byte_offset: usize, length: usize) -> Option<String> {
+    let (part, _) = self.locate(byte_offset)?;
+    let part_end = part.end() * PART_SECTOR_SIZE;
+    let write_end = byte_offset + length;
+    if write_end <= part_end {
+      return None;
+    }
+
+    let reached = self
+      .partitions
+      .iter()
+      .filter(|other| other.offset >= part.end() && other.offset_bytes() < write_end)
+      .map(|other| other.name.as_ref())
+      .collect::<Vec<_>>();
+    let into = match reached.as_slice() {
+      [] => "unallocated space".to_owned(),
+      [next] => format!("partition {}", next),
+      _ => format!("partitions {}", reached.join(", ")),
     };
+    Some(format!(
+      "{:#x} bytes at {:#x} end at {:#x}, {} bytes past the end of partition {} at {:#x} and into {}",
+      length,
+      byte_offset,
+      write_end,
+      write_end - part_end,
+      part.name,
+      part_end,
+      into
+    ))
+  }
+
+  /// Convert an offset within a named partition to an absolute byte offset on the disk
+  pub fn absolute_offset(&self, name: &str, offset: usize) -> Option<usize> {
+    self.get(name).map(|part| part.offset_bytes() + offset)
+  }
+
+  /// Pairs of partitions whose sector ranges overlap
+  ///
+  /// Every pair is checked, not just neighbours, since one partition can contain several others.
+  pub fn overlaps(&self) -> Vec<(&PartitionInfo, &PartitionInfo)> {
+    let mut overlaps = Vec::new();
+    for (i, part) in self.partitions.iter().enumerate() {
+      // the table is sorted by offset, so nothing after the first partition starting past `part`'s
+      // end can overlap it
+      for other in self.partitions[i + 1..]
+        .iter()
+        .take_while(|other| other.offset < part.end())
+      {
+        overlaps.push((part, other));
+      }
+    }
+    overlaps
+  }
+
+  /// Unallocated sector ranges between consecutive partitions, as `(start, end)` pairs
+  pub fn gaps(&self) -> Vec<(usize, usize)> {
+    self
+      .partitions
+      .windows(2)
+      .filter(|pair| pair[0].end() < pair[1].offset)
+      .map(|pair| (pair[0].end(), pair[1].offset))
+      .collect()
+  }
+
+  /// Check that the table is usable: no duplicate names and no overlapping partitions
+  pub fn validate(&self) -> Result<()> {
+    for (i, part) in self.partitions.iter().enumerate() {
+      if self.partitions[..i].iter().any(|other| other.name == part.name) {
+        return Err(Error::InvalidOperation(format!(
+          "partition table has duplicate partition: {}",
+          part.name
+        )));
+      }
+    }
+
+    if let Some((a, b)) = self.overlaps().first() {
+      return Err(Error::InvalidOperation(format!(
+        "partition {} (sectors {:#x}..{:#x}) overlaps {} (starting at sector {:#x})",
+        a.name,
+        a.offset,
+        a.end(),
+        b.name,
+        b.offset
+      )));
+    }
+
+    Ok(())
+  }
+}
+
+impl Default for PartitionTable {
+  fn default() -> Self {
+    Self::superbird()
+  }
+}
+
+impl<'de> Deserialize<'de> for PartitionTable {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    Vec::<PartitionInfo>::deserialize(deserializer).map(Self::new)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_superbird_table() {
+    let table = PartitionTable::superbird();
+    table.validate().unwrap();
+    assert_eq!(table.len(), 18);
+    assert_eq!(table.get("system_a").map(|part| part.offset), Some(536576));
+    assert!(table.iter().zip(table.iter().skip(1)).all(|(a, b)| a.offset < b.offset));
+  }
+
+  #[test]
+  fn test_locate() {
+    let table = PartitionTable::superbird();
+    let offset = table.absolute_offset("boot_b", 0x1000).unwrap();
+    let (part, relative) = table.locate(offset).unwrap();
+    assert_eq!(part.name, "boot_b");
+    assert_eq!(relative, 0x1000);
+
+    // between bootloader and reserved
+    assert!(table.locate(4096 * PART_SECTOR_SIZE).is_none());
+    assert!(table.gaps().contains(&(4096, 73728)));
+  }
+
+  #[test]
+  fn test_resolve() {
+    let table = PartitionTable::superbird();
+    assert_eq!(table.resolve("SYSTEM-A").unwrap().name, "system_a");
+    assert_eq!(table.resolve(" boot0 ").unwrap().name, "bootloader");
+    assert_eq!(table.resolve("userdata").unwrap().name, "data");
+
+    let err = table.resolve("system_c").unwrap_err().to_string();
+    assert!(err.contains("system_c") && err.contains("system_a"));
+  }
+
+  #[test]
+  fn test_overrun() {
+    let table = PartitionTable::superbird();
+    let logo = table.get("logo").unwrap();
+    assert_eq!(table.overrun(logo.offset_bytes(), logo.size_bytes()), None);
+    assert_eq!(table.overrun(logo.offset_bytes() + 512, logo.size_bytes() - 512), None);
+    assert_eq!(
+      table.overrun(logo.offset_bytes() + 512, logo.size_bytes()).unwrap(),
+      "0x800000 bytes at 0x9c00200 end at 0xa400200, 512 bytes past the end of partition logo at 0xa400000 and into unallocated space"
+    );
+    let (fip_a, fip_b) = (table.get("fip_a").unwrap(), table.get("fip_b").unwrap());
+    let length = fip_b.offset_bytes() - fip_a.offset_bytes() + 1;
+    assert!(
+      table
+        .overrun(fip_a.offset_bytes(), length)
+        .unwrap()
+        .ends_with("into partition fip_b")
+    );
+
+    // writes that start outside every partition aren't checked
+    assert_eq!(table.overrun(4096 * PART_SECTOR_SIZE, 1 << 30), None);
+  }
+
+  #[test]
+  fn test_overlaps() {
+    let table = PartitionTable::new(vec![PartitionInfo::new("b", 100, 50), PartitionInfo::new("a", 0, 120)]);
+    assert_eq!(table.iter().next().map(|part| part.name.as_ref()), Some("a"));
+    assert_eq!(table.overlaps().len(), 1);
+    assert!(table.validate().is_err());
+
+    // a partition containing two others overlaps both, not only its neighbour
+    let table = PartitionTable::new(vec![
+      PartitionInfo::new("outer", 0, 300),
+      PartitionInfo::new("first", 100, 50),
+      PartitionInfo::new("second", 200, 50),
+      PartitionInfo::new("after", 300, 10),
+    ]);
+    let names = table
+      .overlaps()
+      .into_iter()
+      .map(|(a, b)| (a.name.as_ref(), b.name.as_ref()))
+      .collect::<Vec<_>>();
+    assert_eq!(names, [("outer", "first"), ("outer", "second")]);
+
+    let json = serde_json::to_string(&table).unwrap();
+    assert_eq!(serde_json::from_str::<PartitionTable>(&json).unwrap(), table);
+  }
 }
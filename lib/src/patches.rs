@@ -0,0 +1,113 @@
+//! Replacing a few bytes of an image as it is streamed to the device, so small per-device tweaks
+//! such as a serial number don't need a modified copy of the whole image.
+
+use std::io::{self, Read};
+
+use crate::{
+  Error, Result,
+  config::{Patch, decode_hex},
+};
+
+/// Bytes to replace, decoded and checked against the size of the data they go in
+pub(crate) type Patches = Vec<(u64, Vec<u8>)>;
+
+/// Decode `patches` and check that each fits in `size` bytes of data
+pub(crate) fn decode_patches(patches: &[Patch], size: u64) -> Result<Patches> {
+  patches
+    .iter()
+    .map(|patch| {
+      let offset = patch.offset.get();
+      let bytes = decode_hex(&patch.hex)?;
+      if offset.checked_add(bytes.len() as u64).is_none_or(|end| end > size) {
+        return Err(Error::InvalidOperation(format!(
+          "patch of {} bytes at {:#x} runs past the end of the {} bytes it patches",
+          bytes.len(),
+          offset,
+          size
+        )));
+      }
+      Ok((offset, bytes))
+    })
+    .collect()
+}
+
+/// Apply the parts of `patches` that fall in `window`, which starts `start` bytes into the data
+///
+/// Patches are applied in order, so a later one wins where two overlap.
+pub(crate) fn apply(window: &mut [u8], start: u64, patches: &Patches) {
+  let end = start + window.len() as u64;
+  for (offset, bytes) in patches {
+    let (from, to) = ((*offset).max(start), (offset + bytes.len() as u64).min(end));
+    if from < to {
+      window[(from - start) as usize..(to - start) as usize]
+        .copy_from_slice(&bytes[(from - offset) as usize..(to - offset) as usize]);
+    }
+  }
+}
+
+/// Reader that applies patches to the data it reads
+pub(crate) struct Patched<R> {
+  inner: R,
+  position: u64,
+  patches: Patches,
+}
+
+impl<R: Read> Patched<R> {
+  pub(crate) fn new(inner: R, patches: Patches) -> Self {
+    Self {
+      inner,
+      position: 0,
+      patches,
+    }
+  }
+}
+
+impl<R: Read> Read for Patched<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let read = self.inner.read(buf)?;
+    apply(&mut buf[..read], self.position, &self.patches);
+    self.position += read as u64;
+    Ok(read)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_patched() {
+    let patches = [
+      Patch {
+        offset: 3.into(),
+        hex: "aabbcc".to_owned(),
+      },
+      Patch {
+        offset: 4.into(),
+        hex: "dd".to_owned(),
+      },
+    ];
+    let patches = decode_patches(&patches, 8).unwrap();
+
+    // read a byte at a time, so the patch is split across reads
+    let mut reader = Patched::new(io::BufReader::with_capacity(1, [0u8; 8].as_slice()), patches.clone());
+    let mut data = vec![];
+    reader.read_to_end(&mut data).unwrap();
+    assert_eq!(data, [0, 0, 0, 0xaa, 0xdd, 0xcc, 0, 0]);
+
+    let mut whole = [0u8; 8];
+    apply(&mut whole, 0, &patches);
+    assert_eq!(whole, data.as_slice());
+
+    let past_end = Patch {
+      offset: 7.into(),
+      hex: "0102".to_owned(),
+    };
+    assert!(decode_patches(&[past_end], 8).is_err());
+    let not_hex = Patch {
+      offset: 0.into(),
+      hex: "xyz".to_owned(),
+    };
+    assert!(decode_patches(&[not_hex], 8).is_err());
+  }
+}
@@ -0,0 +1,203 @@
+//! Resolving `filePath`s from a `meta.json` to files in a package directory or archive.
+
+use std::{
+  fs,
+  io::{Read, Seek},
+  path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use zip::{ZipArchive, result::ZipError};
+
+use crate::{Error, Result};
+
+/// How `filePath`s are matched to files in a package
+///
+/// Both policies treat `\` as a separator, drop `./` components and reject paths that are absolute
+/// or climb out of the package with `..`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum PathPolicy {
+  /// Paths must match the case of the file exactly
+  #[default]
+  Strict,
+  /// A path with no exact match may match a single file that differs only in case
+  Lenient,
+}
+
+/// Turn a `filePath` into a path relative to the package root, with `/` separators
+///
+/// # Returns
+/// - `Result<String>`: The normalized path or [`Error::UnsafePath`] if it leaves the package
+pub(crate) fn normalize_file_path(path: &str) -> Result<String> {
+  let normalized = path.replace('\\', "/");
+  let drive = normalized.as_bytes().get(1) == Some(&b':') && normalized.starts_with(|c: char| c.is_ascii_alphabetic());
+  if normalized.starts_with('/') || drive {
+    return Err(Error::UnsafePath(path.to_owned()));
+  }
+
+  let mut parts = Vec::new();
+  for part in normalized.split('/') {
+    match part {
+      "" | "." => {}
+      ".." => {
+        if parts.pop().is_none() {
+          return Err(Error::UnsafePath(path.to_owned()));
+        }
+      }
+      part => parts.push(part),
+    }
+  }
+
+  if parts.is_empty() {
+    return Err(Error::InvalidOperation(format!("\"{}\" does not name a file", path)));
+  }
+  Ok(parts.join("/"))
+}
+
+/// Find the file a `filePath` refers to in a package directory
+///
+/// With [`PathPolicy::Lenient`], each component without an exact match is matched ignoring case.
+/// A path with no match at all is returned as written, so opening it reports the missing file.
+pub(crate) fn resolve_in_directory(root: &Path, file_path: &str, policy: PathPolicy) -> Result<PathBuf> {
+  let relative = normalize_file_path(file_path)?;
+  let exact = root.join(&relative);
+  if policy == PathPolicy::Strict || exact.exists() {
+    return Ok(exact);
+  }
+
+  let mut resolved = root.to_path_buf();
+  for part in relative.split('/') {
+    if resolved.join(part).exists() {
+      resolved.push(part);
+      continue;
+    }
+
+    let Ok(entries) = fs::read_dir(&resolved) else {
+      return Ok(exact);
+    };
+    let matches = entries
+      .filter_map(|entry| entry.ok())
+      .map(|entry| entry.file_name())
+      .filter(|name| name.to_str().is_some_and(|name| name.eq_ignore_ascii_case(part)))
+      .collect::<Vec<_>>();
+    match matches.as_slice() {
+      [] => return Ok(exact),
+      [name] => resolved.push(name),
+      _ => return Err(ambiguous(file_path, matches.iter().map(|name| name.to_string_lossy()))),
+    }
+  }
+
+  tracing::debug!("resolved {} to {}", file_path, resolved.display());
+  Ok(resolved)
+}
+
+/// Index of the archive entry a `filePath` refers to, relative to the package `root` inside it
+///
+/// Entry names are compared after turning `\` into `/`, so archives written with Windows separators
+/// still work. With [`PathPolicy::Lenient`] a path with no exact match may match ignoring case.
+pub(crate) fn resolve_in_archive<R: Read + Seek>(
+  zip: &ZipArchive<R>,
+  root: &str,
+  file_path: &str,
+  policy: PathPolicy,
+) -> Result<usize> {
+  let name = format!("{root}{}", normalize_file_path(file_path)?);
+  let entries = || (0..zip.len()).filter_map(|index| Some((index, zip.name_for_index(index)?.replace('\\', "/"))));
+
+  if let Some(index) = zip
+    .index_for_name(&name)
+    .or_else(|| entries().find(|(_, entry)| *entry == name).map(|(index, _)| index))
+  {
+    return Ok(index);
+  }
+  if policy == PathPolicy::Strict {
+    return Err(Error::Zip(ZipError::FileNotFound));
+  }
+
+  let matches = entries()
+    .filter(|(_, entry)| entry.eq_ignore_ascii_case(&name))
+    .collect::<Vec<_>>();
+  match matches.as_slice() {
+    [] => Err(Error::Zip(ZipError::FileNotFound)),
+    [(index, entry)] => {
+      tracing::debug!("resolved {} to {}", file_path, entry);
+      Ok(*index)
+    }
+    _ => Err(ambiguous(file_path, matches.iter().map(|(_, entry)| entry.as_str()))),
+  }
+}
+
+fn ambiguous<S: AsRef<str>>(file_path: &str, matches: impl Iterator<Item = S>) -> Error {
+  let matches = matches.map(|name| name.as_ref().to_owned()).collect::<Vec<_>>();
+  Error::InvalidOperation(format!(
+    "\"{}\" matches more than one file when ignoring case: {}",
+    file_path,
+    matches.join(", ")
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Cursor;
+
+  use zip::{ZipWriter, write::SimpleFileOptions};
+
+  use super::*;
+
+  #[test]
+  fn test_normalize_file_path() {
+    assert_eq!(normalize_file_path("./images/./boot.img").unwrap(), "images/boot.img");
+    assert_eq!(normalize_file_path(".\\images\\boot.img").unwrap(), "images/boot.img");
+    assert_eq!(normalize_file_path("images/../boot.img").unwrap(), "boot.img");
+    for path in [
+      "../boot.img",
+      "images/../../boot.img",
+      "/etc/passwd",
+      "C:\\boot.img",
+      "..\\boot.img",
+    ] {
+      assert!(matches!(normalize_file_path(path), Err(Error::UnsafePath(_))), "{path}");
+    }
+    assert!(normalize_file_path("./").is_err());
+  }
+
+  #[test]
+  fn test_resolve() {
+    let dir = std::env::temp_dir().join(format!("flashthing-paths-{}", std::process::id()));
+    fs::create_dir_all(dir.join("Images")).unwrap();
+    fs::write(dir.join("Images").join("Boot.img"), []).unwrap();
+
+    let strict = resolve_in_directory(&dir, "./images/boot.img", PathPolicy::Strict).unwrap();
+    assert_eq!(strict, dir.join("images/boot.img"));
+    let lenient = resolve_in_directory(&dir, "./images/boot.img", PathPolicy::Lenient).unwrap();
+    assert_eq!(lenient, dir.join("Images").join("Boot.img"));
+    assert!(resolve_in_directory(&dir, "../boot.img", PathPolicy::Lenient).is_err());
+    let _ = fs::remove_dir_all(&dir);
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for name in ["package/Images\\Boot.img", "package/a.bin", "package/A.bin"] {
+      zip.start_file(name, SimpleFileOptions::default()).unwrap();
+    }
+    let zip = ZipArchive::new(zip.finish().unwrap()).unwrap();
+
+    assert_eq!(
+      resolve_in_archive(&zip, "package/", "Images/Boot.img", PathPolicy::Strict).unwrap(),
+      0
+    );
+    assert!(resolve_in_archive(&zip, "package/", "images/boot.img", PathPolicy::Strict).is_err());
+    assert_eq!(
+      resolve_in_archive(&zip, "package/", "images/boot.img", PathPolicy::Lenient).unwrap(),
+      0
+    );
+    assert_eq!(
+      resolve_in_archive(&zip, "package/", "a.bin", PathPolicy::Lenient).unwrap(),
+      1
+    );
+    assert!(resolve_in_archive(&zip, "package/", "a.BIN", PathPolicy::Lenient).is_err());
+    assert!(matches!(
+      resolve_in_archive(&zip, "package/", "../package/a.bin", PathPolicy::Strict),
+      Err(Error::UnsafePath(_))
+    ));
+  }
+}
@@ -0,0 +1,163 @@
+//! Choosing which steps of a flash config run, so part of a package can be re-run without
+//! starting over.
+
+use std::collections::HashMap;
+
+use crate::{
+  Error, Result,
+  config::{FlashStep, Step},
+  partitions::canonical_partition_name,
+  variables::DeferredStep,
+};
+
+/// Work out which steps to skip when starting at `start_at` and leaving out `skip`
+///
+/// Steps are referred to by id, or by their position counting from 1.
+///
+/// # Returns
+/// - `Result<Vec<bool>>`: Whether each step is skipped, or an error if a step could not be found
+pub(crate) fn skipped_steps(steps: &[Step], start_at: Option<&str>, skip: &[String]) -> Result<Vec<bool>> {
+  let start = match start_at {
+    Some(step) => find_step(steps, step)?,
+    None => 0,
+  };
+  let mut skipped = (0..steps.len()).map(|index| index < start).collect::<Vec<_>>();
+  for step in skip {
+    skipped[find_step(steps, step)?] = true;
+  }
+  Ok(skipped)
+}
+
+/// Describe every skipped step that a step which still runs relies on
+pub(crate) fn dependency_warnings(
+  steps: &[Step],
+  skipped: &[bool],
+  deferred: &HashMap<String, DeferredStep>,
+) -> Vec<String> {
+  let mut warnings = Vec::new();
+  for (later, step) in steps.iter().enumerate().filter(|(index, _)| !skipped[*index]) {
+    let uses = (step.id.as_ref())
+      .and_then(|id| deferred.get(id))
+      .map(DeferredStep::variables)
+      .unwrap_or_default();
+    let dependency = steps[..later]
+      .iter()
+      .enumerate()
+      .rev()
+      .find_map(|(earlier, dependency)| {
+        let reason = step.step.depends_on(&dependency.step).or_else(|| {
+          (uses.iter())
+            .any(|name| dependency.step.sets_variable(name))
+            .then_some("uses the variables it sets")
+        })?;
+        Some((earlier, reason))
+      });
+    if let Some((earlier, reason)) = dependency
+      && skipped[earlier]
+    {
+      warnings.push(format!(
+        "step {} ({}) is skipped, but step {} ({}) {}",
+        earlier + 1,
+        id(&steps[earlier]),
+        later + 1,
+        id(step),
+        reason
+      ));
+    }
+  }
+  warnings
+}
+
+fn find_step(steps: &[Step], step: &str) -> Result<usize> {
+  if let Some(index) = steps.iter().position(|s| s.id.as_deref() == Some(step)) {
+    return Ok(index);
+  }
+  match step.parse::<usize>() {
+    Ok(position) if (1..=steps.len()).contains(&position) => Ok(position - 1),
+    _ => Err(Error::InvalidOperation(format!(
+      "no step has the id `{}`, and it is not a step number between 1 and {}",
+      step,
+      steps.len()
+    ))),
+  }
+}
+
+fn id(step: &Step) -> &str {
+  step.id.as_deref().unwrap_or_default()
+}
+
+impl FlashStep {
+  /// Whether this step relies on `earlier` having run, and if so why
+  fn depends_on(&self, earlier: &FlashStep) -> Option<&'static str> {
+    match (self, earlier) {
+      (FlashStep::Run { .. }, FlashStep::WriteSimpleMemory { .. }) => Some("runs the code it loads"),
+      (FlashStep::GetBootAMLC { .. } | FlashStep::WriteAMLCData { .. }, FlashStep::Run { .. }) => {
+        Some("continues the handshake with the BL2 it starts")
+      }
+      (FlashStep::ApplyDelta { value }, _) => {
+        let name = canonical_partition_name(&value.name);
+        let written = match earlier {
+          FlashStep::RestorePartition { value } => canonical_partition_name(&value.name),
+          FlashStep::ApplyDelta { value } => canonical_partition_name(&value.name),
+          FlashStep::WriteLargeMemory { value } => canonical_partition_name(value.partition.as_deref()?),
+          _ => return None,
+        };
+        (written == name).then_some("applies a delta on top of what it writes")
+      }
+      (FlashStep::Bulkcmd { value }, FlashStep::WriteEnv { .. }) if value.trim() == "saveenv" => {
+        Some("saves the environment it writes")
+      }
+      (FlashStep::Bulkcmd { value }, FlashStep::Provision { value: provision })
+        if value.trim() == "saveenv" && provision.env.is_some() =>
+      {
+        Some("saves the environment it writes")
+      }
+      (FlashStep::Assert { value }, _)
+        if value
+          .variable
+          .as_deref()
+          .is_some_and(|name| earlier.sets_variable(name)) =>
+      {
+        Some("checks the variable it sets")
+      }
+      _ => None,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::FlashConfig;
+
+  #[test]
+  fn test_skipped_steps() {
+    let config = FlashConfig::from_standalone(
+      r#"{
+        "metadataVersion": 2, "name": "plan", "version": "0.1.0", "description": "plan.",
+        "steps": [
+          { "type": "bulkcmd", "value": "amlmmc key", "id": "key" },
+          { "type": "restorePartition", "value": { "name": "system_a", "data": { "filePath": "system.img" } }, "id": "system" },
+          { "type": "writeEnv", "value": { "filePath": "env.txt" }, "id": "env" },
+          { "type": "applyDelta", "value": { "name": "system-a", "delta": { "filePath": "system.delta" } }, "id": "delta" },
+          { "type": "bulkcmd", "value": "saveenv", "id": "save" }
+        ]
+      }"#,
+    )
+    .unwrap();
+    let steps = &config.steps;
+
+    let skipped = skipped_steps(steps, Some("env"), &["4".into()]).unwrap();
+    assert_eq!(skipped, [true, true, false, true, false]);
+    assert!(dependency_warnings(steps, &skipped, &config.deferred).is_empty());
+
+    let skipped = skipped_steps(steps, None, &["system".into(), "env".into()]).unwrap();
+    let warnings = dependency_warnings(steps, &skipped, &config.deferred);
+    assert_eq!(warnings.len(), 2, "{warnings:?}");
+    assert!(warnings[0].starts_with("step 2 (system) is skipped, but step 4 (delta)"));
+    assert!(warnings[1].starts_with("step 3 (env) is skipped, but step 5 (save)"));
+
+    assert!(skipped_steps(steps, Some("missing"), &[]).is_err());
+    assert!(skipped_steps(steps, None, &["6".into()]).is_err());
+  }
+}
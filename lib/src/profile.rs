@@ -0,0 +1,117 @@
+//! Per-device parameters for Amlogic burn-mode devices.
+
+use std::borrow::Cow;
+
+use flashthing_core::UsbIds;
+
+use crate::{
+  ADDR_BL2, ADDR_TMP, BL2_BIN, BOOTLOADER_BIN, ENV_SIZE, PRODUCT_ID, TRANSFER_BLOCK_SIZE, TRANSFER_SIZE_THRESHOLD,
+  UNBRICK_BIN_ZIP, VENDOR_ID, partitions::PartitionTable, quirks::UsbQuirks, thermal::ThermalPolicy,
+};
+
+/// Everything the flasher needs to know about a particular Amlogic device
+///
+/// The Superbird profile is the default. Other S905-family boards can be flashed by
+/// supplying a profile with their USB IDs, memory layout, and boot blobs.
+#[derive(Clone)]
+pub struct DeviceProfile {
+  /// Human readable name of the device
+  pub name: Cow<'static, str>,
+  /// USB vendor ID in USB (burn) mode
+  pub vendor_id: u16,
+  /// USB product ID in USB (burn) mode
+  pub product_id: u16,
+  /// USB product string reported by the mask ROM, as opposed to the burn mode bootloader
+  pub rom_product_string: Cow<'static, str>,
+  /// USB vendor and product ID when booted normally, if the device exposes a gadget
+  pub normal_mode_ids: Option<(u16, u16)>,
+  /// Address BL2 is loaded to and run from
+  pub bl2_address: u32,
+  /// DDR address data is staged at before being written to the eMMC
+  pub staging_address: u32,
+  /// Largest amount of data staged in DDR at once
+  pub max_transfer_size: usize,
+  /// Size of the DDR, which starts at address 0; kernels and other images must load within it
+  pub dram_size: u64,
+  /// Size of each bulk transfer block
+  pub transfer_block_size: usize,
+  /// Built-in BL2, used when a step does not supply one
+  pub bl2: Option<Cow<'static, [u8]>>,
+  /// Built-in bootloader served to BL2 over AMLC
+  pub bootloader: Option<Cow<'static, [u8]>>,
+  /// Zipped `unbrick.bin` disk image used by `unbrick`
+  pub unbrick: Option<Cow<'static, [u8]>>,
+  /// Size of the U-Boot environment (`CONFIG_ENV_SIZE`), which an env has to fit in once saved
+  pub env_size: usize,
+  /// Partition layout of the eMMC
+  pub partitions: PartitionTable,
+  /// When to pause long writes to let the device cool down
+  pub thermal: ThermalPolicy,
+  /// Workarounds for the host's USB stack
+  pub usb_quirks: UsbQuirks,
+}
+
+impl DeviceProfile {
+  /// Profile for the Spotify Car Thing (superbird)
+  pub const fn superbird() -> Self {
+    Self {
+      name: Cow::Borrowed("superbird"),
+      vendor_id: VENDOR_ID,
+      product_id: PRODUCT_ID,
+      rom_product_string: Cow::Borrowed("GX-CHIP"),
+      normal_mode_ids: Some((0x18d1, 0x4e40)),
+      bl2_address: ADDR_BL2,
+      staging_address: ADDR_TMP,
+      max_transfer_size: TRANSFER_SIZE_THRESHOLD,
+      dram_size: 512 * 1024 * 1024,
+      transfer_block_size: TRANSFER_BLOCK_SIZE,
+      bl2: Some(Cow::Borrowed(BL2_BIN)),
+      bootloader: Some(Cow::Borrowed(BOOTLOADER_BIN)),
+      unbrick: Some(Cow::Borrowed(UNBRICK_BIN_ZIP)),
+      env_size: ENV_SIZE,
+      partitions: PartitionTable::superbird(),
+      thermal: ThermalPolicy::new(),
+      usb_quirks: UsbQuirks::new(),
+    }
+  }
+}
+
+impl DeviceProfile {
+  /// The IDs this device is found by on the bus
+  pub fn usb_ids(&self) -> UsbIds {
+    UsbIds {
+      vendor_id: self.vendor_id,
+      product_id: self.product_id,
+      rom_product_string: self.rom_product_string.clone(),
+      normal_mode_ids: self.normal_mode_ids,
+    }
+  }
+}
+
+impl Default for DeviceProfile {
+  fn default() -> Self {
+    Self::superbird()
+  }
+}
+
+impl std::fmt::Debug for DeviceProfile {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    // the blobs are hundreds of KB, so only show whether they are present
+    f.debug_struct("DeviceProfile")
+      .field("name", &self.name)
+      .field("vendor_id", &format_args!("{:#06x}", self.vendor_id))
+      .field("product_id", &format_args!("{:#06x}", self.product_id))
+      .field("bl2_address", &format_args!("{:#x}", self.bl2_address))
+      .field("staging_address", &format_args!("{:#x}", self.staging_address))
+      .field("max_transfer_size", &self.max_transfer_size)
+      .field("dram_size", &format_args!("{:#x}", self.dram_size))
+      .field("transfer_block_size", &self.transfer_block_size)
+      .field("bl2", &self.bl2.as_ref().map(|blob| blob.len()))
+      .field("bootloader", &self.bootloader.as_ref().map(|blob| blob.len()))
+      .field("unbrick", &self.unbrick.as_ref().map(|blob| blob.len()))
+      .field("env_size", &format_args!("{:#x}", self.env_size))
+      .field("thermal", &self.thermal)
+      .field("usb_quirks", &self.usb_quirks)
+      .finish_non_exhaustive()
+  }
+}
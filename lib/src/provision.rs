@@ -0,0 +1,209 @@
+//! Values unique to each device, like a serial number or MAC address, handed out to `provision`
+//! steps by a [`Provisioner`].
+
+use std::{
+  collections::HashMap,
+  fs,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+use crate::{Error, Result};
+
+/// What a `provision` step asks its [`Provisioner`] for
+#[derive(Debug, Clone)]
+pub struct ProvisionRequest {
+  /// Id of the `provision` step
+  pub step_id: String,
+  /// Serial number the device reported over USB, if it has one
+  pub device_serial: Option<String>,
+  /// Variables the step needs a value for
+  pub variables: Vec<String>,
+}
+
+/// Callback handing out the values for one device, or an error to fail the step with
+pub type ProvisionCallback =
+  Arc<dyn Fn(&ProvisionRequest) -> std::result::Result<HashMap<String, String>, String> + Send + Sync>;
+
+/// Where `provision` steps get the values for the device being flashed
+#[derive(Clone)]
+pub enum Provisioner {
+  /// A file of `name=value` lines holding the values for the next device
+  ///
+  /// Once a device is provisioned, every value in the file counts up by one: a MAC address like
+  /// `02:00:00:00:00:ff` as a whole, anything else by the digits it ends in, keeping leading zeros.
+  /// Blank lines and lines starting with `#` are kept as they are. The file shouldn't be shared by
+  /// flashes running at the same time.
+  Counter(PathBuf),
+  /// A callback, e.g. asking a database or label printer for the next free serial number
+  Callback(ProvisionCallback),
+}
+
+impl Provisioner {
+  /// Get the values for the device being flashed
+  ///
+  /// # Returns
+  /// - `Result<HashMap<String, String>>`: A value for every variable of `request`, or an error if
+  ///   the provisioner failed or left one out
+  pub(crate) fn provision(&self, request: &ProvisionRequest) -> Result<HashMap<String, String>> {
+    let mut values = match self {
+      Provisioner::Counter(path) => parse_counter(&read_counter(path)?)
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value.to_owned()))
+        .collect::<HashMap<_, _>>(),
+      Provisioner::Callback(callback) => {
+        callback(request).map_err(|e| Error::InvalidOperation(format!("provisioner failed: {}", e)))?
+      }
+    };
+    if let Some(missing) = request.variables.iter().find(|name| !values.contains_key(*name)) {
+      return Err(Error::InvalidOperation(format!(
+        "provisioner has no value for `{}`",
+        missing
+      )));
+    }
+    values.retain(|name, _| request.variables.contains(name));
+    Ok(values)
+  }
+
+  /// Move on to the next device once the values from [`Provisioner::provision`] were written
+  pub(crate) fn advance(&self) -> Result<()> {
+    let Provisioner::Counter(path) = self else {
+      return Ok(());
+    };
+
+    let text = read_counter(path)?;
+    let mut next = String::with_capacity(text.len());
+    for line in text.lines() {
+      match split_line(line) {
+        Some((name, value)) => {
+          let value = count_up(value).ok_or_else(|| {
+            Error::InvalidOperation(format!(
+              "`{}` in {} has no number to count up: {}",
+              name,
+              path.display(),
+              value
+            ))
+          })?;
+          next.push_str(&format!("{}={}\n", name, value));
+        }
+        None => {
+          next.push_str(line);
+          next.push('\n');
+        }
+      }
+    }
+
+    // write the whole file at once, so a crash can't leave it half written and hand out a value twice
+    let staged = path.with_extension("next");
+    fs::write(&staged, next)?;
+    fs::rename(&staged, path)?;
+    tracing::debug!("advanced provisioning counter {}", path.display());
+    Ok(())
+  }
+}
+
+fn read_counter(path: &Path) -> Result<String> {
+  fs::read_to_string(path)
+    .map_err(|e| Error::InvalidOperation(format!("couldn't read provisioning counter {}: {}", path.display(), e)))
+}
+
+fn parse_counter(text: &str) -> Vec<(&str, &str)> {
+  text.lines().filter_map(split_line).collect()
+}
+
+fn split_line(line: &str) -> Option<(&str, &str)> {
+  let line = line.trim();
+  if line.is_empty() || line.starts_with('#') {
+    return None;
+  }
+  let (name, value) = line.split_once('=')?;
+  Some((name.trim(), value.trim()))
+}
+
+/// The value after `value`: the next MAC address, or `value` with the digits it ends in counted up
+fn count_up(value: &str) -> Option<String> {
+  let groups = value.split([':', '-']).collect::<Vec<_>>();
+  if groups.len() == 6 && groups.iter().all(|group| group.len() == 2) {
+    let mac = u64::from_str_radix(&groups.concat(), 16).ok()?;
+    let next = format!("{:012x}", mac.checked_add(1).filter(|next| *next <= 0xffff_ffff_ffff)?);
+    let next = match value.chars().any(|c| c.is_ascii_uppercase()) {
+      true => next.to_uppercase(),
+      false => next,
+    };
+    let separator = &value[2..3];
+    return Some(
+      (0..6)
+        .map(|group| &next[group * 2..group * 2 + 2])
+        .collect::<Vec<_>>()
+        .join(separator),
+    );
+  }
+
+  let prefix = value.trim_end_matches(|c: char| c.is_ascii_digit());
+  let mut digits = value.as_bytes()[prefix.len()..].to_vec();
+  if digits.is_empty() {
+    return None;
+  }
+  // add one from the last digit, carrying like on paper so any number of digits works
+  let mut at = digits.len();
+  loop {
+    if at == 0 {
+      digits.insert(0, b'1');
+      break;
+    }
+    at -= 1;
+    if digits[at] == b'9' {
+      digits[at] = b'0';
+    } else {
+      digits[at] += 1;
+      break;
+    }
+  }
+  Some(format!("{}{}", prefix, String::from_utf8(digits).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_count_up() {
+    assert_eq!(count_up("CT000123").as_deref(), Some("CT000124"));
+    assert_eq!(count_up("CT-0999").as_deref(), Some("CT-1000"));
+    assert_eq!(count_up("99").as_deref(), Some("100"));
+    assert_eq!(count_up("02:00:00:00:00:ff").as_deref(), Some("02:00:00:00:01:00"));
+    assert_eq!(count_up("02-00-00-00-00-0F").as_deref(), Some("02-00-00-00-00-10"));
+    assert_eq!(count_up("ff:ff:ff:ff:ff:ff"), None);
+    assert_eq!(count_up("batch"), None);
+  }
+
+  #[test]
+  fn test_counter() {
+    let path = std::env::temp_dir().join(format!("flashthing-provision-{}.txt", std::process::id()));
+    fs::write(&path, "# batch 7\nserial = CT000041\nmac=02:00:00:00:00:29\n").unwrap();
+    let provisioner = Provisioner::Counter(path.clone());
+    let request = ProvisionRequest {
+      step_id: "provision".into(),
+      device_serial: None,
+      variables: vec!["serial".into(), "mac".into()],
+    };
+
+    let values = provisioner.provision(&request).unwrap();
+    assert_eq!(values["serial"], "CT000041");
+    assert_eq!(values["mac"], "02:00:00:00:00:29");
+
+    provisioner.advance().unwrap();
+    assert_eq!(
+      fs::read_to_string(&path).unwrap(),
+      "# batch 7\nserial=CT000042\nmac=02:00:00:00:00:2a\n"
+    );
+
+    let request = ProvisionRequest {
+      variables: vec!["hostname".into()],
+      ..request
+    };
+    let err = provisioner.provision(&request).unwrap_err();
+    assert!(err.to_string().contains("`hostname`"), "{err}");
+    let _ = fs::remove_file(&path);
+  }
+}
@@ -0,0 +1,65 @@
+//! Workarounds for host USB stacks that can't keep up with the transfers made by default.
+
+use std::time::Duration;
+
+/// Size of each memory write with [`UsbQuirks::limited_bulk`], and the smallest failed writes are
+/// split down to
+pub(crate) const LIMITED_BULK_TRANSFER: usize = 64 * 1024;
+
+/// Largest URB bulk writes are sent in by default, as some Windows USB stacks fail writes over
+/// 256 KiB to the device
+#[cfg(windows)]
+const DEFAULT_MAX_URB_SIZE: Option<usize> = Some(256 * 1024);
+#[cfg(not(windows))]
+const DEFAULT_MAX_URB_SIZE: Option<usize> = None;
+
+/// How long a bulk transfer may go without finishing before the watchdog cancels it, well past the
+/// longest timeout of any single transfer
+const DEFAULT_TRANSFER_WATCHDOG: Duration = Duration::from_secs(30);
+
+/// Workarounds for the host's USB stack
+///
+/// Flashing adapts to a host whose large transfers fail without any quirks set: a failed memory
+/// write is retried in halves down to 64 KiB, and later writes stay at the size that worked. The
+/// quirks skip straight to what such hosts need.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbQuirks {
+  /// Move data to the device's memory 64 KiB at a time instead of up to the profile's
+  /// `max_transfer_size` at once
+  ///
+  /// For hosts like Raspberry Pis, whose bulk writes stall on large transfers.
+  pub limited_bulk: bool,
+  /// Split each bulk write into URBs of at most this many bytes, or `None` to send it whole
+  ///
+  /// This defaults to 256 KiB on Windows, whose USB stacks can fail larger writes, and `None`
+  /// elsewhere. Whatever it is set to, a write failing with a USB I/O error is retried in smaller
+  /// URBs instead of failing the step.
+  pub max_urb_size: Option<usize>,
+  /// Cancel and retry a bulk transfer that goes this long without finishing or failing, or `None`
+  /// to wait on it for good
+  ///
+  /// libusb transfers are known to occasionally hang instead of timing out. The watchdog cancels
+  /// them, clears the endpoint and retries, emitting [`Event::TransferStalled`]. Defaults to 30
+  /// seconds.
+  ///
+  /// [`Event::TransferStalled`]: crate::Event::TransferStalled
+  pub transfer_watchdog: Option<Duration>,
+}
+
+impl UsbQuirks {
+  /// No quirks beyond the platform's default [`max_urb_size`](Self::max_urb_size) and the default
+  /// [`transfer_watchdog`](Self::transfer_watchdog)
+  pub const fn new() -> Self {
+    Self {
+      limited_bulk: false,
+      max_urb_size: DEFAULT_MAX_URB_SIZE,
+      transfer_watchdog: Some(DEFAULT_TRANSFER_WATCHDOG),
+    }
+  }
+}
+
+impl Default for UsbQuirks {
+  fn default() -> Self {
+    Self::new()
+  }
+}
@@ -0,0 +1,205 @@
+//! Recording operations run by hand on a device, to export them as a flash package.
+
+use std::{fs, io::Cursor, path::Path};
+
+use crate::{
+  AmlogicSoC, Result, SUPPORTED_META_VERSION_MAX,
+  config::{
+    DataOrFile, FlashConfig, FlashStep, MetaFile, ResetMode, ResetValue, RestorePartitionValue, RunValue, Step,
+    StringOrFile, WriteBootPartitionValue, WriteSimpleMemoryValue,
+  },
+};
+
+/// Directory in an exported package the data of recorded steps is written to
+const DATA_DIR: &str = "data";
+
+/// Runs operations on a device and records each one that succeeds as a step
+///
+/// Once a procedure works, [`export`](Self::export) writes the steps out as a package with a
+/// `meta.json`, and the data that was sent in files next to it, so it can be flashed again with
+/// [`Flasher`](crate::Flasher) or shared. Operations that fail are not recorded.
+pub struct Recorder {
+  aml: AmlogicSoC,
+  steps: Vec<FlashStep>,
+  /// Data of recorded steps, by path relative to the package
+  files: Vec<(String, Vec<u8>)>,
+}
+
+impl Recorder {
+  /// Record operations run on `aml`
+  pub fn new(aml: AmlogicSoC) -> Self {
+    Self {
+      aml,
+      steps: Vec::new(),
+      files: Vec::new(),
+    }
+  }
+
+  /// The device operations are run on, for anything that should not be recorded
+  pub fn device(&self) -> &AmlogicSoC {
+    &self.aml
+  }
+
+  /// The steps recorded so far
+  pub fn steps(&self) -> &[FlashStep] {
+    &self.steps
+  }
+
+  /// Identify the device, recorded as an `identify` step
+  pub fn identify(&mut self) -> Result<String> {
+    let version = self.aml.identify()?;
+    self.steps.push(FlashStep::Identify { variable: None });
+    Ok(version)
+  }
+
+  /// Send a bulk command, recorded as a `bulkcmd` step
+  pub fn bulkcmd(&mut self, command: &str) -> Result<String> {
+    let response = self.aml.bulkcmd(command)?;
+    self.steps.push(FlashStep::Bulkcmd {
+      value: command.to_owned(),
+    });
+    Ok(response)
+  }
+
+  /// Run code at `address`, recorded as a `run` step
+  pub fn run(&mut self, address: u32, keep_power: Option<bool>) -> Result<()> {
+    self.aml.run(address, keep_power)?;
+    self.steps.push(FlashStep::Run {
+      value: RunValue {
+        address: address.into(),
+        keep_power,
+      },
+    });
+    Ok(())
+  }
+
+  /// Write a small amount of data to memory, recorded as a `writeSimpleMemory` step
+  pub fn write_simple_memory(&mut self, address: u32, data: Vec<u8>) -> Result<()> {
+    self.aml.write_simple_memory(address, &data)?;
+    let data = DataOrFile::File(self.save("memory", "bin", data));
+    self.steps.push(FlashStep::WriteSimpleMemory {
+      value: WriteSimpleMemoryValue {
+        address: address.into(),
+        data,
+      },
+    });
+    Ok(())
+  }
+
+  /// Import `name=value` lines into the U-Boot environment, recorded as a `writeEnv` step
+  pub fn write_env(&mut self, env_data: &str) -> Result<()> {
+    self.aml.import_env(env_data)?;
+    let file = self.save("env", "txt", env_data.as_bytes().to_vec());
+    self.steps.push(FlashStep::WriteEnv {
+      value: StringOrFile::File(file),
+    });
+    Ok(())
+  }
+
+  /// Write an image to a partition, recorded as a `restorePartition` step
+  pub fn restore_partition(&mut self, name: &str, data: Vec<u8>) -> Result<()> {
+    let part_info = self.aml.profile().partitions.resolve(name)?;
+    let name = part_info.name.to_string();
+    let part_size = self.aml.validate_partition_size(&name, part_info, false)?;
+    self
+      .aml
+      .restore_partition(&name, part_size, Cursor::new(&data), data.len(), false, |_| {})?;
+
+    let data = DataOrFile::File(self.save(&name, "img", data));
+    self.steps.push(FlashStep::RestorePartition {
+      value: RestorePartitionValue {
+        name,
+        data,
+        compare_before_write: None,
+        confirm_special_partition: None,
+        trim_padding: None,
+        erase_trimmed: None,
+        patches: None,
+      },
+    });
+    Ok(())
+  }
+
+  /// Write a boot hwpartition, 1 for boot0 or 2 for boot1, recorded as a `writeBootPartition` step
+  pub fn write_boot_partition(&mut self, hwpart: u8, data: Vec<u8>) -> Result<()> {
+    self.aml.write_boot_partition(hwpart, &data)?;
+    let data = DataOrFile::File(self.save(&format!("boot{}", hwpart - 1), "bin", data));
+    self.steps.push(FlashStep::WriteBootPartition {
+      value: WriteBootPartitionValue {
+        hwpart,
+        data,
+        patches: None,
+      },
+    });
+    Ok(())
+  }
+
+  /// Reset the device, recorded as a `reset` step
+  pub fn reset(&mut self, mode: ResetMode) -> Result<()> {
+    self.aml.reset(mode)?;
+    self.steps.push(FlashStep::Reset {
+      value: ResetValue { mode },
+    });
+    Ok(())
+  }
+
+  /// Add a `log` step, e.g. to explain the steps that follow it
+  pub fn log(&mut self, message: &str) {
+    self.steps.push(FlashStep::Log {
+      value: message.to_owned(),
+    });
+  }
+
+  /// Write the recorded steps to `dir` as a package named `name`
+  ///
+  /// The data of each step goes in a file under `data/`, referred to by the step's `filePath`.
+  /// The package is loaded back before returning, so what is written is known to be valid.
+  ///
+  /// # Returns
+  /// - `Result<FlashConfig>`: The `meta.json` written, as loaded back from `dir`
+  pub fn export(&self, dir: &Path, name: &str, description: &str) -> Result<FlashConfig> {
+    let config = FlashConfig {
+      name: name.to_owned(),
+      version: "1.0.0".to_owned(),
+      description: description.to_owned(),
+      steps: self
+        .steps
+        .iter()
+        .map(|step| Step {
+          step: step.clone(),
+          id: None,
+          timeout_ms: None,
+          on_error: None,
+        })
+        .collect(),
+      variables: None,
+      constants: None,
+      metadata_version: SUPPORTED_META_VERSION_MAX,
+      allow_special_partitions: None,
+      report_webhook: None,
+      deferred: Default::default(),
+    };
+
+    fs::create_dir_all(dir.join(DATA_DIR))?;
+    for (path, data) in &self.files {
+      fs::write(dir.join(path), data)?;
+    }
+    fs::write(dir.join("meta.json"), serde_json::to_string_pretty(&config)?)?;
+    tracing::info!("exported {} recorded steps to {}", self.steps.len(), dir.display());
+
+    FlashConfig::from_directory(&dir.to_path_buf())
+  }
+
+  /// Keep data a step sent, to write to a file in the package on export
+  fn save(&mut self, name: &str, extension: &str, data: Vec<u8>) -> MetaFile {
+    let file_path = format!("{}/{:02}-{}.{}", DATA_DIR, self.steps.len() + 1, name, extension);
+    self.files.push((file_path.clone(), data));
+    MetaFile {
+      file_path,
+      encoding: None,
+      offset: None,
+      length: None,
+      template: None,
+    }
+  }
+}
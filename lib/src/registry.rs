@@ -0,0 +1,391 @@
+//! Finding and downloading community packages listed in a static JSON index.
+
+use std::{
+  fs::{self, File},
+  io::{self, Read, Write},
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{Error, Result, firmware::compare_versions};
+
+/// How long fetching the index may take before giving up, and a download may take to connect
+/// and start answering
+const INDEX_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a download may take altogether before giving up
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Version to ask [`Registry::fetch`] for to get the newest release of a package
+pub const LATEST: &str = "latest";
+
+/// The JSON document a registry serves at its index URL
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistryIndex {
+  /// Every package the registry lists
+  pub packages: Vec<RegistryPackage>,
+}
+
+/// A package listed in a registry
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistryPackage {
+  /// Name used to fetch the package, e.g. `thingos`
+  pub name: String,
+  /// What the package is
+  #[serde(default)]
+  pub description: String,
+  /// Releases of the package, in any order
+  pub versions: Vec<RegistryRelease>,
+}
+
+/// One release of a package in a registry
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistryRelease {
+  /// Version of the release, e.g. `1.2.0`
+  pub version: String,
+  /// Where the package's zip archive is, absolute or relative to the index URL
+  pub url: String,
+  /// SHA-256 digest of the archive, as hex
+  pub sha256: String,
+  /// Base64 Ed25519 signature of the release's [`signed_message`](Self::signed_message), by the
+  /// registry's key
+  pub signature: Option<String>,
+}
+
+impl RegistryRelease {
+  /// What a release of package `name` is signed as: the name, version and SHA-256 digest as
+  /// lowercase hex, each on a line of its own
+  ///
+  /// Signing all three rather than the digest alone keeps a valid signature from being moved to
+  /// another package or version, such as an older release with a known flaw.
+  pub fn signed_message(&self, name: &str) -> String {
+    format!(
+      "{}\n{}\n{}\n",
+      name,
+      self.version,
+      self.sha256.trim().to_ascii_lowercase()
+    )
+  }
+}
+
+impl RegistryPackage {
+  /// The release for `version`, or the newest release for [`LATEST`]
+  pub fn release(&self, version: &str) -> Option<&RegistryRelease> {
+    if version == LATEST {
+      return self
+        .versions
+        .iter()
+        .max_by(|a, b| compare_versions(&a.version, &b.version));
+    }
+    self.versions.iter().find(|release| release.version == version)
+  }
+}
+
+/// A client for a package registry: a static JSON index listing packages and where to download them
+///
+/// There is no server to speak of, so a registry can be hosted anywhere files can be, like GitHub
+/// Pages. Every download is checked against the SHA-256 digest the index gives for it, and with
+/// [`with_public_key`](Self::with_public_key), against an Ed25519 signature of the package name,
+/// version and digest too, so a tampered archive or index entry, or one signed for another package
+/// or version, is refused before it is flashed.
+#[derive(Debug, Clone)]
+pub struct Registry {
+  index_url: String,
+  public_key: Option<Vec<u8>>,
+}
+
+impl Registry {
+  /// A registry whose index is at `index_url`
+  pub fn new(index_url: impl Into<String>) -> Self {
+    Self {
+      index_url: index_url.into(),
+      public_key: None,
+    }
+  }
+
+  /// Require every release to be signed by the Ed25519 key `public_key`, given as base64
+  pub fn with_public_key(mut self, public_key: &str) -> Result<Self> {
+    let key = crate::config::decode_base64(public_key)?;
+    if key.len() != 32 {
+      return Err(Error::Registry(format!(
+        "an Ed25519 public key is 32 bytes, got {}",
+        key.len()
+      )));
+    }
+    self.public_key = Some(key);
+    Ok(self)
+  }
+
+  /// URL of the registry's index
+  pub fn index_url(&self) -> &str {
+    &self.index_url
+  }
+
+  /// Every package the registry lists
+  pub fn list(&self) -> Result<Vec<RegistryPackage>> {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+      .timeout_global(Some(INDEX_TIMEOUT))
+      .build()
+      .into();
+    let body = agent
+      .get(&self.index_url)
+      .call()
+      .and_then(|mut response| response.body_mut().read_to_string())
+      .map_err(|e| Error::Registry(format!("fetching the index at {} failed: {}", self.index_url, e)))?;
+    let index: RegistryIndex = serde_json::from_str(&body)?;
+    Ok(index.packages)
+  }
+
+  /// Download a release of a package to `dir` and verify it
+  ///
+  /// # Parameters
+  /// - `name`: Name of the package in the index
+  /// - `version`: Version to download, or [`LATEST`] for the newest
+  /// - `dir`: Directory to save the archive in, created if it does not exist
+  ///
+  /// # Returns
+  /// - `Result<PathBuf>`: Path of the archive, ready to pass to [`Flasher::from_archive`](crate::Flasher::from_archive),
+  ///   or an error if the package is not listed or the download does not match its digest or signature
+  pub fn fetch(&self, name: &str, version: &str, dir: &Path) -> Result<PathBuf> {
+    let packages = self.list()?;
+    let package = packages
+      .iter()
+      .find(|package| package.name == name)
+      .ok_or_else(|| Error::Registry(format!("{} is not in the registry at {}", name, self.index_url)))?;
+    let release = package
+      .release(version)
+      .ok_or_else(|| Error::Registry(format!("{} has no version {}", name, version)))?;
+
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}-{}.zip", sanitize(name), sanitize(&release.version)));
+    tracing::info!("downloading {} {}", name, release.version);
+    self.download(name, release, &path)?;
+    Ok(path)
+  }
+
+  /// Download a release of package `name` to `path` and verify it, the same way
+  /// [`fetch`](Self::fetch) does
+  ///
+  /// The download goes to `path` with `.part` appended, and is only moved to `path` once its
+  /// digest and signature check out, so `path` never holds a file that did not.
+  pub fn download(&self, name: &str, release: &RegistryRelease, path: &Path) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    let url = self.resolve(&release.url);
+    tracing::debug!("downloading {}", url);
+
+    let digest = download(&url, &partial).inspect_err(|_| {
+      let _ = fs::remove_file(&partial);
+    })?;
+    if let Err(err) = self.verify(name, release, &digest) {
+      let _ = fs::remove_file(&partial);
+      return Err(err);
+    }
+    fs::rename(&partial, path)?;
+    Ok(())
+  }
+
+  /// Check a download's digest against the release, and its signature if the registry has a key
+  fn verify(&self, name: &str, release: &RegistryRelease, digest: &[u8]) -> Result<()> {
+    if !hex::encode(digest).eq_ignore_ascii_case(release.sha256.trim()) {
+      return Err(Error::VerificationFailed(format!(
+        "{} has SHA-256 {}, the index expects {}",
+        release.url,
+        hex::encode(digest),
+        release.sha256
+      )));
+    }
+    let Some(key) = &self.public_key else {
+      return Ok(());
+    };
+    // a line break would let one name and version pass for another in the signed message
+    if name.contains('\n') || release.version.contains('\n') {
+      return Err(Error::VerificationFailed(format!(
+        "{} has a line break in its name or version",
+        release.url
+      )));
+    }
+    let signature = release
+      .signature
+      .as_deref()
+      .ok_or_else(|| Error::VerificationFailed(format!("{} is not signed", release.url)))?;
+    let signature = crate::config::decode_base64(signature)?;
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, key)
+      .verify(release.signed_message(name).as_bytes(), &signature)
+      .map_err(|_| Error::VerificationFailed(format!("{} has an invalid signature", release.url)))
+  }
+
+  /// Resolve a release URL relative to the index
+  fn resolve(&self, url: &str) -> String {
+    if url.contains("://") {
+      return url.to_owned();
+    }
+    match self.index_url.rsplit_once('/') {
+      Some((base, _)) => format!("{}/{}", base, url.trim_start_matches('/')),
+      None => url.to_owned(),
+    }
+  }
+}
+
+/// Stream `url` to `path`, hashing it on the way
+fn download(url: &str, path: &Path) -> Result<Vec<u8>> {
+  let agent: ureq::Agent = ureq::Agent::config_builder()
+    .timeout_global(Some(DOWNLOAD_TIMEOUT))
+    .timeout_connect(Some(INDEX_TIMEOUT))
+    .timeout_recv_response(Some(INDEX_TIMEOUT))
+    .build()
+    .into();
+  let mut response = agent
+    .get(url)
+    .call()
+    .map_err(|e| Error::Registry(format!("downloading {} failed: {}", url, e)))?;
+  let mut reader = response.body_mut().as_reader();
+  let mut file = File::create(path)?;
+  let mut hasher = Sha256::new();
+  let mut buf = vec![0; 64 * 1024];
+  loop {
+    let read = match reader.read(&mut buf) {
+      Ok(0) => break,
+      Ok(read) => read,
+      Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+      Err(e) => return Err(Error::Registry(format!("downloading {} failed: {}", url, e))),
+    };
+    hasher.update(&buf[..read]);
+    file.write_all(&buf[..read])?;
+  }
+  file.sync_all()?;
+  Ok(hasher.finalize().to_vec())
+}
+
+/// Keep a name from the index to characters that are safe in a file name
+fn sanitize(name: &str) -> String {
+  name
+    .chars()
+    .map(|c| {
+      if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+        c
+      } else {
+        '_'
+      }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    io::{BufRead, BufReader},
+    net::TcpListener,
+  };
+
+  use base64::Engine;
+  use ring::signature::{Ed25519KeyPair, KeyPair};
+
+  use super::*;
+
+  /// Serve `responses` by path until every one has been asked for once
+  fn serve(responses: Vec<(&'static str, Vec<u8>)>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+      for stream in listener.incoming() {
+        let mut reader = BufReader::new(stream.unwrap());
+        let mut request = String::new();
+        reader.read_line(&mut request).unwrap();
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 2 {
+          line.clear();
+        }
+        let path = request.split_whitespace().nth(1).unwrap_or_default().to_owned();
+        let mut stream = reader.into_inner();
+        match responses.iter().find(|(served, _)| *served == path) {
+          Some((_, body)) => {
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).unwrap();
+            stream.write_all(body).unwrap();
+          }
+          None => stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+            .unwrap(),
+        }
+      }
+    });
+    base
+  }
+
+  #[test]
+  fn test_fetch() {
+    let rng = ring::rand::SystemRandom::new();
+    let key = Ed25519KeyPair::from_pkcs8(Ed25519KeyPair::generate_pkcs8(&rng).unwrap().as_ref()).unwrap();
+    let public_key = base64::engine::general_purpose::STANDARD.encode(key.public_key());
+
+    let archive = b"not really a zip".to_vec();
+    let digest = hex::encode(Sha256::digest(&archive));
+    let sign = |name: &str, version: &str| {
+      let message = format!("{name}\n{version}\n{digest}\n");
+      base64::engine::general_purpose::STANDARD.encode(key.sign(message.as_bytes()))
+    };
+    let index = serde_json::json!({
+      "packages": [{
+        "name": "thingos",
+        "versions": [
+          { "version": "1.9.0", "url": "old.zip", "sha256": "00" },
+          { "version": "1.10.0", "url": "packages/thingos.zip", "sha256": digest, "signature": sign("thingos", "1.10.0") },
+          { "version": "1.10.1", "url": "tampered.zip", "sha256": digest, "signature": sign("thingos", "1.10.1") },
+          // the right archive and a valid signature, but made for an older release
+          { "version": "1.11.0", "url": "packages/thingos.zip", "sha256": digest, "signature": sign("thingos", "1.10.0") }
+        ]
+      }, {
+        // and one made for another package
+        "name": "thingos-beta",
+        "versions": [
+          { "version": "1.10.0", "url": "packages/thingos.zip", "sha256": digest, "signature": sign("thingos", "1.10.0") }
+        ]
+      }]
+    });
+    let base = serve(vec![
+      ("/index.json", index.to_string().into_bytes()),
+      ("/packages/thingos.zip", archive.clone()),
+      ("/tampered.zip", b"something else".to_vec()),
+    ]);
+    let dir = std::env::temp_dir().join(format!("flashthing-registry-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    let registry = Registry::new(format!("{base}/index.json"))
+      .with_public_key(&public_key)
+      .unwrap();
+    let packages = registry.list().unwrap();
+    assert_eq!(packages[0].release(LATEST).unwrap().version, "1.11.0");
+
+    let path = registry.fetch("thingos", "1.10.0", &dir).unwrap();
+    assert_eq!(path, dir.join("thingos-1.10.0.zip"));
+    assert_eq!(fs::read(&path).unwrap(), archive);
+
+    let err = registry.fetch("thingos", "1.10.1", &dir).unwrap_err();
+    assert_eq!(err.code(), "VERIFICATION_FAILED");
+    for (name, version) in [("thingos", LATEST), ("thingos-beta", "1.10.0")] {
+      let err = registry.fetch(name, version, &dir).unwrap_err();
+      assert!(err.to_string().contains("invalid signature"), "{err}");
+    }
+    assert!(!dir.join("thingos-1.10.1.zip").exists());
+    assert!(!dir.join("thingos-1.10.1.zip.part").exists());
+    assert_eq!(
+      registry.fetch("nixos", LATEST, &dir).unwrap_err().code(),
+      "REGISTRY_FAILED"
+    );
+
+    // without a key, the digest is still checked but a signature is not needed
+    let registry = Registry::new(format!("{base}/index.json"));
+    assert_eq!(
+      registry.fetch("thingos", "1.9.0", &dir).unwrap_err().code(),
+      "REGISTRY_FAILED"
+    );
+    let _ = fs::remove_dir_all(&dir);
+  }
+}
@@ -0,0 +1,227 @@
+//! Summary of a flash session, built up by the [`Flasher`](crate::Flasher) as it runs.
+
+use std::{
+  sync::Arc,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::EmbeddedResource;
+
+/// Callback receiving the report once a flash has finished, whether it succeeded or not
+pub type ReportHook = Arc<dyn Fn(&FlashReport) + Send + Sync>;
+
+/// How long posting a report to a `reportWebhook` may take
+#[cfg(feature = "http")]
+const WEBHOOK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Report describing the outcome of a flash session
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FlashReport {
+  /// Name of the flashed package
+  pub package: String,
+  /// Version of the flashed package
+  pub version: String,
+  /// Serial number of the device, if it reports one
+  pub device_serial: Option<String>,
+  /// ROM version the last `identify` step read from the device, e.g. `0-7-0-16-0-0-0-0`
+  pub device_identity: Option<String>,
+  /// Unix timestamp (seconds) the flash started at
+  pub started_at: Option<u64>,
+  /// Unix timestamp (seconds) the flash finished at
+  pub finished_at: Option<u64>,
+  /// Number of steps that completed
+  pub steps_completed: usize,
+  /// Total number of steps in the package
+  pub steps_total: usize,
+  /// Number of steps that failed and were skipped with `onError: "continue"`
+  pub steps_failed: usize,
+  /// Number of steps left out with `start_at` or `skip` on the [`FlasherBuilder`](crate::FlasherBuilder)
+  #[serde(default)]
+  pub steps_skipped: usize,
+  /// Whether every step completed successfully
+  pub success: bool,
+  /// Error message if the flash failed
+  pub error: Option<String>,
+  /// [`Error::code`](crate::Error::code) of the error, if the flash failed
+  pub error_code: Option<String>,
+  /// Bytes written to the eMMC during this session
+  pub bytes_written: u64,
+  /// Bytes compare-before-write avoided rewriting during this session
+  pub bytes_skipped: u64,
+  /// Bytes written to this device across all recorded sessions, including this one
+  pub cumulative_bytes_written: Option<u64>,
+  /// Partitions restored from the rollback bundle after a step failed in transactional mode
+  pub rolled_back: Vec<String>,
+  /// Non-fatal issues noticed while flashing
+  pub warnings: Vec<String>,
+  /// Outcome of every step that ran, in order
+  #[serde(default)]
+  pub steps: Vec<StepResult>,
+  /// Outcome of every `repeat` step that ran, in order
+  #[serde(default)]
+  pub repeats: Vec<RepeatResult>,
+  /// Step to start at to carry on after the flash was cancelled, as an id or a number counting from 1
+  pub resume_at: Option<String>,
+  /// Byte of the `resume_at` step's data to carry on from, when a streaming write was cancelled part way
+  pub resume_offset: Option<u64>,
+  /// Whether the device booted after flashing, if [`Flasher::verify_boot`](crate::Flasher::verify_boot) checked
+  pub boot: Option<crate::BootOutcome>,
+  /// Outcome of every command `postBootCheck` steps ran on the booted device, in order
+  #[serde(default)]
+  pub post_boot_checks: Vec<PostBootCheckResult>,
+  /// BL2, bootloader and unbrick image the device profile sends, by size and digest
+  #[serde(default)]
+  pub resources: Vec<EmbeddedResource>,
+  /// Variables `writeEnv` steps changed, with [`FlasherBuilder::diff_env`](crate::FlasherBuilder::diff_env)
+  #[serde(default)]
+  pub env_changes: Vec<EnvChange>,
+}
+
+impl FlashReport {
+  /// Post the report as JSON to `url`
+  #[cfg(feature = "http")]
+  pub(crate) fn post(&self, url: &str) -> crate::Result<()> {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+      .timeout_global(Some(WEBHOOK_TIMEOUT))
+      .build()
+      .into();
+    agent
+      .post(url)
+      .header("Content-Type", "application/json")
+      .send(serde_json::to_string(self)?)
+      .map_err(|e| crate::Error::InvalidOperation(format!("posting the flash report to {} failed: {}", url, e)))?;
+    Ok(())
+  }
+}
+
+/// Outcome of a step that ran during a flash session
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct StepResult {
+  /// Index of the step, counting from 1 like [`Event::Step`](crate::Event::Step)
+  pub index: usize,
+  /// Id of the step
+  pub id: String,
+  /// Whether the step completed
+  pub completed: bool,
+  /// Number of times the step was run, including retries
+  pub attempts: u32,
+  /// Error of the last attempt, if the step failed
+  pub error: Option<String>,
+  /// [`Error::code`](crate::Error::code) of the last attempt, if the step failed
+  pub error_code: Option<String>,
+}
+
+/// Outcome of a command a `postBootCheck` step ran on the booted device
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PostBootCheckResult {
+  /// The shell command line
+  pub command: String,
+  /// What the command printed, trimmed
+  pub output: String,
+  /// Whether the output was as expected
+  pub passed: bool,
+}
+
+/// Variable of the U-Boot environment a `writeEnv` step changed
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct EnvChange {
+  /// Name of the variable
+  pub name: String,
+  /// Value the device had before, if the variable was set
+  pub before: Option<String>,
+  /// Value the step set
+  pub after: String,
+}
+
+/// Outcome of the iterations of a `repeat` step
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct RepeatResult {
+  /// Id of the `repeat` step
+  pub id: String,
+  /// Number of iterations that ran
+  pub iterations: u32,
+  /// Number of iterations in which a step failed
+  pub failed: u32,
+  /// Which step failed in each failed iteration, and why
+  pub failures: Vec<IterationFailure>,
+}
+
+/// A failed iteration of a `repeat` step
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct IterationFailure {
+  /// Iteration that failed, counting from 1
+  pub iteration: u32,
+  /// Id of the step that failed
+  pub step_id: String,
+  /// Error of the step's last attempt
+  pub error: String,
+  /// [`Error::code`](crate::Error::code) of the step's last attempt
+  #[serde(default)]
+  pub error_code: String,
+}
+
+pub(crate) fn unix_now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|duration| duration.as_secs())
+    .unwrap_or_default()
+}
+
+#[cfg(all(test, feature = "http"))]
+mod tests {
+  use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+  };
+
+  use super::*;
+
+  #[test]
+  fn test_post() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/reports", listener.local_addr().unwrap());
+    let server = std::thread::spawn(move || {
+      let (stream, _) = listener.accept().unwrap();
+      let mut reader = BufReader::new(stream);
+      let mut length = 0;
+      let mut line = String::new();
+      while reader.read_line(&mut line).unwrap() > 2 {
+        if let Some((name, value)) = line.split_once(':')
+          && name.eq_ignore_ascii_case("content-length")
+        {
+          length = value.trim().parse().unwrap();
+        }
+        line.clear();
+      }
+      let mut body = vec![0; length];
+      reader.read_exact(&mut body).unwrap();
+      let mut stream = reader.into_inner();
+      stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+      body
+    });
+
+    let report = FlashReport {
+      package: "nixos-superbird".into(),
+      version: "1.0.0".into(),
+      success: true,
+      ..Default::default()
+    };
+    report.post(&url).unwrap();
+    let posted: FlashReport = serde_json::from_slice(&server.join().unwrap()).unwrap();
+    assert_eq!(posted.package, "nixos-superbird");
+    assert!(posted.success);
+
+    assert!(report.post("http://127.0.0.1:1/reports").is_err());
+  }
+}
@@ -0,0 +1,115 @@
+//! Identifying the blobs built into the crate, so a report says exactly which BL2 and bootloader were sent.
+
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{BL2_BIN, BOOTLOADER_BIN, DeviceProfile, StockProfile, UNBRICK_BIN_ZIP};
+
+/// A blob built into the crate, or supplied by a [`DeviceProfile`] in its place
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct EmbeddedResource {
+  /// What the blob is: `bl2`, `bootloader`, `unbrick`, or `stockMeta:<profile>` for a [`StockProfile`]'s `meta.json`
+  pub name: String,
+  /// Size in bytes
+  pub size: u64,
+  /// SHA-256 digest, as hex
+  pub sha256: String,
+  /// Version the blob declares, if it has one, e.g. a stock `meta.json`'s `version`
+  pub version: Option<String>,
+}
+
+impl EmbeddedResource {
+  fn new(name: &str, blob: &[u8]) -> Self {
+    Self {
+      name: name.to_owned(),
+      size: blob.len() as u64,
+      sha256: hex::encode(Sha256::digest(blob)),
+      version: None,
+    }
+  }
+}
+
+/// Sizes, digests and versions of the BL2, bootloader, unbrick image and stock `meta.json`s built into the crate
+///
+/// The digests are worked out once and kept for the life of the process.
+pub fn embedded_info() -> Vec<EmbeddedResource> {
+  static INFO: OnceLock<Vec<EmbeddedResource>> = OnceLock::new();
+  INFO
+    .get_or_init(|| {
+      let stock_metas = StockProfile::ALL.iter().map(|profile| EmbeddedResource {
+        version: serde_json::from_slice::<serde_json::Value>(profile.meta())
+          .ok()
+          .and_then(|meta| meta["version"].as_str().map(str::to_owned)),
+        ..EmbeddedResource::new(&format!("stockMeta:{}", profile.name), profile.meta())
+      });
+      [
+        EmbeddedResource::new("bl2", BL2_BIN),
+        EmbeddedResource::new("bootloader", BOOTLOADER_BIN),
+        EmbeddedResource::new("unbrick", UNBRICK_BIN_ZIP),
+      ]
+      .into_iter()
+      .chain(stock_metas)
+      .collect()
+    })
+    .clone()
+}
+
+/// Describe the boot blobs `profile` sends, reusing the built-in digests where it did not replace them
+pub(crate) fn profile_info(profile: &DeviceProfile) -> Vec<EmbeddedResource> {
+  let embedded = embedded_info();
+  [
+    ("bl2", profile.bl2.as_deref(), BL2_BIN),
+    ("bootloader", profile.bootloader.as_deref(), BOOTLOADER_BIN),
+    ("unbrick", profile.unbrick.as_deref(), UNBRICK_BIN_ZIP),
+  ]
+  .into_iter()
+  .filter_map(|(name, blob, builtin)| {
+    let blob = blob?;
+    let resource = match embedded.iter().find(|resource| resource.name == name) {
+      Some(resource) if std::ptr::eq(blob, builtin) => resource.clone(),
+      _ => EmbeddedResource::new(name, blob),
+    };
+    Some(resource)
+  })
+  .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::borrow::Cow;
+
+  use super::*;
+
+  #[test]
+  fn test_embedded_info() {
+    let info = embedded_info();
+    let names = info.iter().map(|resource| resource.name.as_str()).collect::<Vec<_>>();
+    assert_eq!(
+      names,
+      [
+        "bl2",
+        "bootloader",
+        "unbrick",
+        "stockMeta:superbird",
+        "stockMeta:superbird-dump"
+      ]
+    );
+    assert_eq!(info[0].size, BL2_BIN.len() as u64);
+    assert_eq!(info[0].sha256, hex::encode(Sha256::digest(BL2_BIN)));
+    assert_eq!(info[3].version.as_deref(), Some("1.0.0"));
+
+    assert_eq!(profile_info(&DeviceProfile::superbird()), info[..3]);
+    let profile = DeviceProfile {
+      bl2: Some(Cow::Owned(vec![0; 16])),
+      unbrick: None,
+      ..DeviceProfile::superbird()
+    };
+    let custom = profile_info(&profile);
+    assert_eq!(custom.len(), 2);
+    assert_eq!((custom[0].size, &custom[1]), (16, &info[1]));
+  }
+}
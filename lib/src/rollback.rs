@@ -0,0 +1,244 @@
+//! Copies of critical partitions taken before a transactional flash changes them.
+
+use std::{
+  fs::{self, File, OpenOptions},
+  path::{Path, PathBuf},
+};
+
+use crate::{
+  PartitionTable, Result,
+  config::{DataOrFile, FlashConfig, FlashStep, MetaFile, RestorePartitionValue, Step, WriteBootPartitionValue},
+};
+
+/// Partitions a transactional flash backs up before the first step that changes them
+pub(crate) const CRITICAL_PARTITIONS: [&str; 6] = ["bootloader", "env", "fip_a", "fip_b", "dtbo_a", "dtbo_b"];
+
+/// A region of the eMMC a transactional flash backs up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Backup {
+  /// A whole critical partition of the user area
+  Partition(String),
+  /// The start of boot hwpartition 1 (boot0) or 2 (boot1), as far as the writes to it reach
+  BootPartition(u8),
+}
+
+impl Backup {
+  /// Name the backup is saved and reported under
+  pub(crate) fn name(&self) -> String {
+    match self {
+      Self::Partition(name) => name.clone(),
+      Self::BootPartition(hwpart) => format!("boot{}", hwpart - 1),
+    }
+  }
+}
+
+/// Critical partitions of `table` that writing `length` bytes from byte `start` of the user area
+/// would change
+pub(crate) fn critical_overlaps(table: &PartitionTable, start: usize, length: usize) -> Vec<String> {
+  table
+    .iter()
+    .filter(|part| CRITICAL_PARTITIONS.contains(&part.name.as_ref()))
+    .filter(|part| part.offset_bytes() < start + length && start < part.offset_bytes() + part.size_bytes())
+    .map(|part| part.name.to_string())
+    .collect()
+}
+
+/// Original contents of the critical regions a flash has touched so far
+///
+/// Each copy is streamed to an image in `dir` as it is read, alongside a `meta.json` that restores
+/// them, so an automatic rollback reads them back from there and the bundle can be flashed by hand
+/// if the host goes away mid-flash.
+pub(crate) struct RollbackBundle {
+  dir: PathBuf,
+  /// Regions saved so far, in the order they were first saved, and the bytes saved of each
+  saved: Vec<(Backup, u64)>,
+}
+
+impl RollbackBundle {
+  pub(crate) fn new(dir: PathBuf) -> Self {
+    Self { dir, saved: Vec::new() }
+  }
+
+  /// Directory the bundle is written to
+  pub(crate) fn dir(&self) -> &Path {
+    &self.dir
+  }
+
+  /// Forget the copies from a previous flash
+  pub(crate) fn clear(&mut self) {
+    self.saved.clear();
+  }
+
+  /// Whether nothing has been backed up yet
+  pub(crate) fn is_empty(&self) -> bool {
+    self.saved.is_empty()
+  }
+
+  /// Bytes of `backup` saved so far, if it has been backed up
+  pub(crate) fn saved_len(&self, backup: &Backup) -> Option<u64> {
+    self
+      .saved
+      .iter()
+      .find(|(saved, _)| saved == backup)
+      .map(|(_, len)| *len)
+  }
+
+  /// Image `backup` is saved in
+  pub(crate) fn image_path(&self, backup: &Backup) -> PathBuf {
+    self.dir.join(format!("{}.img", backup.name()))
+  }
+
+  /// Back up a region, or more of one already backed up, and rewrite the bundle's `meta.json` to
+  /// include it
+  ///
+  /// `write` is handed the region's image, empty the first time and open for appending after.
+  pub(crate) fn save<W: FnOnce(&mut File) -> Result<()>>(&mut self, backup: Backup, write: W) -> Result<()> {
+    fs::create_dir_all(&self.dir)?;
+    let path = self.image_path(&backup);
+    let mut file = match self.saved_len(&backup) {
+      Some(_) => OpenOptions::new().append(true).open(&path)?,
+      None => File::create(&path)?,
+    };
+    write(&mut file)?;
+    file.sync_all()?;
+    let len = file.metadata()?.len();
+    match self.saved.iter_mut().find(|(saved, _)| *saved == backup) {
+      Some((_, saved_len)) => *saved_len = len,
+      None => self.saved.push((backup, len)),
+    }
+
+    let meta = serde_json::to_string_pretty(&self.restore_config())?;
+    fs::write(self.dir.join("meta.json"), meta)?;
+    Ok(())
+  }
+
+  /// Saved regions in the order they should be restored, most recently saved first
+  pub(crate) fn backups(&self) -> impl Iterator<Item = &Backup> {
+    self.saved.iter().rev().map(|(backup, _)| backup)
+  }
+
+  fn restore_config(&self) -> FlashConfig {
+    FlashConfig {
+      name: "rollback".into(),
+      version: crate::report::unix_now().to_string(),
+      description: "Restores the partitions a transactional flash backed up before changing them".into(),
+      steps: self
+        .backups()
+        .map(|backup| {
+          let name = backup.name();
+          let data = DataOrFile::File(MetaFile {
+            file_path: format!("{name}.img"),
+            encoding: None,
+            offset: None,
+            length: None,
+            template: None,
+          });
+          let step = match backup {
+            Backup::Partition(_) => FlashStep::RestorePartition {
+              value: RestorePartitionValue {
+                name: name.clone(),
+                data,
+                compare_before_write: Some(true),
+                confirm_special_partition: None,
+                trim_padding: None,
+                erase_trimmed: None,
+                patches: None,
+              },
+            },
+            Backup::BootPartition(hwpart) => FlashStep::WriteBootPartition {
+              value: WriteBootPartitionValue {
+                hwpart: *hwpart,
+                data,
+                patches: None,
+              },
+            },
+          };
+          Step {
+            step,
+            id: Some(format!("restore-{name}")),
+            timeout_ms: None,
+            on_error: None,
+          }
+        })
+        .collect(),
+      variables: None,
+      constants: None,
+      metadata_version: 1,
+      allow_special_partitions: None,
+      report_webhook: None,
+      deferred: Default::default(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Write;
+
+  use super::*;
+  use crate::PART_SECTOR_SIZE;
+
+  #[test]
+  fn test_bundle_meta() {
+    let dir = std::env::temp_dir().join(format!("flashthing-rollback-{}", std::process::id()));
+    let mut bundle = RollbackBundle::new(dir.clone());
+    let env = Backup::Partition("env".into());
+    bundle.save(env.clone(), |file| Ok(file.write_all(&[1; 16])?)).unwrap();
+    bundle
+      .save(Backup::BootPartition(1), |file| Ok(file.write_all(&[2; 512])?))
+      .unwrap();
+    bundle
+      .save(Backup::BootPartition(1), |file| Ok(file.write_all(&[3; 512])?))
+      .unwrap();
+    assert_eq!(bundle.saved_len(&env), Some(16));
+    assert_eq!(bundle.saved_len(&Backup::BootPartition(1)), Some(1024));
+    assert_eq!(bundle.saved_len(&Backup::Partition("dtbo_a".into())), None);
+
+    let config = FlashConfig::from_directory(&dir).unwrap();
+    let steps = config
+      .steps
+      .iter()
+      .map(|step| match &step.step {
+        FlashStep::RestorePartition { value } => value.name.clone(),
+        FlashStep::WriteBootPartition { value } => format!("hwpart {}", value.hwpart),
+        step => panic!("unexpected step {:?}", step),
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(steps, ["hwpart 1", "env"]);
+    assert_eq!(fs::read(dir.join("env.img")).unwrap(), vec![1; 16]);
+    assert_eq!(fs::read(dir.join("boot0.img")).unwrap()[512..], [3; 512]);
+
+    // a new flash starts the images over
+    bundle.clear();
+    bundle.save(env.clone(), |file| Ok(file.write_all(&[4; 8])?)).unwrap();
+    assert_eq!(fs::read(dir.join("env.img")).unwrap(), vec![4; 8]);
+    let _ = fs::remove_dir_all(&dir);
+  }
+
+  #[test]
+  fn test_critical_overlaps() {
+    let table = crate::DeviceProfile::default().partitions;
+    let part = |name: &str| table.get(name).unwrap().clone();
+    let (env, fip_a, logo) = (part("env"), part("fip_a"), part("logo"));
+
+    // a whole-disk image from LBA 0 runs over every critical partition before logo
+    assert_eq!(
+      critical_overlaps(&table, 0, logo.offset_bytes()),
+      ["bootloader", "env", "fip_a", "fip_b"]
+    );
+    assert_eq!(critical_overlaps(&table, 0, PART_SECTOR_SIZE), ["bootloader"]);
+    assert_eq!(critical_overlaps(&table, env.offset_bytes() + 4096, 1), ["env"]);
+    // ends right where env starts, or starts right where it ends
+    assert!(critical_overlaps(&table, env.offset_bytes() - 4096, 4096).is_empty());
+    assert_eq!(
+      critical_overlaps(&table, env.offset_bytes() + env.size_bytes() - 1, 2),
+      ["env"]
+    );
+    assert_eq!(
+      critical_overlaps(&table, fip_a.offset_bytes() + fip_a.size_bytes(), 1 << 20),
+      Vec::<String>::new()
+    );
+    assert!(critical_overlaps(&table, logo.offset_bytes(), logo.size_bytes()).is_empty());
+    assert!(critical_overlaps(&table, 0, 0).is_empty());
+  }
+}
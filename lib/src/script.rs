@@ -0,0 +1,259 @@
+//! Writing a flash out as the device commands it runs, for reading rather than running.
+
+use std::fmt::Write;
+
+use crate::{
+  Flasher,
+  config::{
+    DataOrFile, FlashStep, MetaFile, ResetMode, RestorePartitionValue, Step, StringOrFile, WaitValue,
+    WriteLargeMemoryValue,
+  },
+};
+
+impl Flasher {
+  /// Write the flash out as a shell script of the equivalent Amlogic `update` tool commands
+  ///
+  /// This is for documentation and auditing: it shows the bulk commands, memory writes and
+  /// partition writes each step makes without reading the flasher's source. Steps the `update`
+  /// tool has no command for, such as BL2 boot handshakes, deltas and assertions, are described in
+  /// comments, as are steps the flasher was built to skip. Variables are left as `${name}`, since
+  /// they only get values while flashing.
+  pub fn export_script(&self) -> String {
+    let staging = format!("{:#x}", self.device().profile().staging_address);
+    let mut script = format!(
+      "#!/bin/sh\n# {} {}: {}\n# generated by flashthing {}\nset -e\n",
+      self.config().name,
+      self.config().version,
+      self.config().description,
+      env!("CARGO_PKG_VERSION")
+    );
+    for (index, step) in self.steps().iter().enumerate() {
+      let _ = writeln!(
+        script,
+        "\n# step {} ({})",
+        index + 1,
+        step.id.as_deref().unwrap_or_default()
+      );
+      let commands = step_commands(step, &staging);
+      if self.skipped()[index] {
+        script.push_str("# skipped:\n");
+        commands.lines().for_each(|line| {
+          let _ = writeln!(script, "# {}", line.trim_start_matches("# "));
+        });
+      } else {
+        script.push_str(&commands);
+      }
+    }
+    script
+  }
+}
+
+/// The commands for one step, each on its own line
+fn step_commands(step: &Step, staging: &str) -> String {
+  let commands = commands(step, staging);
+  match step.step.patches() {
+    Some((_, patches)) => format!(
+      "# the step patches {} places in its data as it is written, which this does not do\n{}",
+      patches.len(),
+      commands
+    ),
+    None => commands,
+  }
+}
+
+/// The commands for one step, without any note about its patches
+fn commands(step: &Step, staging: &str) -> String {
+  let update = |args: &str| format!("update {args}\n");
+  let bulkcmd = |command: &str| update(&format!("bulkcmd {}", quote(command)));
+  match &step.step {
+    FlashStep::Identify { .. } => update("identify"),
+    FlashStep::Bulkcmd { value } | FlashStep::BulkcmdStat { value, .. } => bulkcmd(value),
+    FlashStep::Run { value } => update(&format!("run {:#x}", value.address.get())),
+    FlashStep::WriteSimpleMemory { value } => {
+      update(&format!("write {} {:#x}", data(&value.data), value.address.get()))
+    }
+    FlashStep::ReadSimpleMemory { value, output, .. } | FlashStep::ReadLargeMemory { value, output, .. } => {
+      let file = output
+        .as_ref()
+        .map_or("/dev/stdout", |output| output.file_path.as_str());
+      update(&format!(
+        "mread mem {:#x} normal {:#x} {}",
+        value.address.get(),
+        value.length.get(),
+        quote(file)
+      ))
+    }
+    FlashStep::WriteLargeMemory {
+      value:
+        WriteLargeMemoryValue {
+          partition: Some(partition),
+          offset_in_partition: None,
+          data: contents,
+          ..
+        },
+    } => update(&format!("partition {} {}", partition, data(contents))),
+    FlashStep::WriteLargeMemory { value } => format!(
+      "# write {} to the disk at {}, staged through {} and written with `mmc write`\n",
+      data(&value.data),
+      match (&value.address, &value.partition) {
+        (Some(address), _) => format!("{:#x}", address.get()),
+        (None, partition) => format!(
+          "{:#x} into partition {}",
+          value.offset_in_partition.map_or(0, |offset| offset.get()),
+          partition.as_deref().unwrap_or_default()
+        ),
+      },
+      staging
+    ),
+    FlashStep::GetBootAMLC { .. } => "# read where BL2 wants its next AMLC block (no update command)\n".to_owned(),
+    FlashStep::WriteAMLCData { value } => format!(
+      "# send {} to BL2 as AMLC block {} at offset {:#x} (no update command)\n",
+      data(&value.data),
+      value.seq,
+      value.amlc_offset
+    ),
+    FlashStep::Bl2Boot { value } => format!(
+      "# boot BL2 {} then bootloader {} over USB, as pyamlboot's boot-g12.py does\n",
+      data(&value.bl2),
+      data(&value.bootloader)
+    ),
+    FlashStep::ValidatePartitionSize { value, .. } => format!("# check partition {} exists and its size\n", value.name),
+    FlashStep::RestorePartition {
+      value: RestorePartitionValue {
+        name, data: contents, ..
+      },
+    } => update(&format!("partition {} {}", name, data(contents))),
+    FlashStep::ApplyDelta { value } => format!(
+      "# apply delta {} to partition {}, writing only the regions it changes\n",
+      data(&value.delta),
+      value.name
+    ),
+    FlashStep::WriteBootPartition { value } => [
+      bulkcmd(&format!("mmc dev 1 {}", value.hwpart)),
+      bulkcmd("amlmmc key"),
+      update(&format!("write {} {}", data(&value.data), staging)),
+      format!(
+        "update bulkcmd \"mmc write {} 0 $(printf '%#x' $(( ($(stat -c %s {}) + 511) / 512 )))\"\n",
+        staging,
+        data(&value.data)
+      ),
+      bulkcmd("mmc dev 1 0"),
+    ]
+    .concat(),
+    FlashStep::WriteUserArea { value } => format!(
+      "# write {} to the user area at LBA {:#x}, staged through {} and written with `mmc write`\n",
+      data(&value.data),
+      value.lba,
+      staging
+    ),
+    FlashStep::WriteEnv { value } => {
+      let file = match value {
+        StringOrFile::File(file) => quote(&file.file_path),
+        StringOrFile::String(_) => "env.txt".to_owned(),
+      };
+      let mut commands = match value {
+        StringOrFile::String(env) => format!("printf '%s' {} > env.txt\n", quote(env)),
+        StringOrFile::File(_) => String::new(),
+      };
+      commands.push_str(&bulkcmd("amlmmc env"));
+      commands.push_str(&update(&format!("write {} {}", file, staging)));
+      let _ = writeln!(
+        commands,
+        "update bulkcmd \"env import -t {} $(printf '%#x' $(stat -c %s {}))\"",
+        staging, file
+      );
+      commands
+    }
+    FlashStep::WriteBootScript { value } => format!(
+      "# write {} as a boot.scr image, like `mkimage -T script` makes, to partition {} at {:#x}\n",
+      match &value.script {
+        StringOrFile::File(file) => quote(&file.file_path),
+        StringOrFile::String(_) => "the inline script".to_owned(),
+      },
+      value.partition,
+      value.offset_in_partition.map_or(0, |offset| offset.get())
+    ),
+    FlashStep::Log { value } => format!("echo {}\n", quote(value)),
+    FlashStep::Wait {
+      value: WaitValue::Time { time },
+    } => format!("sleep {}\n", *time as f64 / 1000.0),
+    FlashStep::Wait {
+      value: WaitValue::UserInput { message },
+    } => format!("printf '%s ' {} && read -r _\n", quote(message)),
+    FlashStep::Reset { value } => match value.mode {
+      ResetMode::Soft => bulkcmd("reset"),
+      ResetMode::Burn => bulkcmd("reboot update"),
+    },
+    FlashStep::Reconnect { .. } => "# wait for the device to come back in USB burn mode\n".to_owned(),
+    FlashStep::Repeat { value } => {
+      let mut commands = format!("for _ in $(seq {}); do\n", value.count);
+      for step in &value.steps {
+        step_commands(step, staging).lines().for_each(|line| {
+          let _ = writeln!(commands, "  {line}");
+        });
+      }
+      // a loop body of only comments is a syntax error
+      commands.push_str("  :\ndone\n");
+      commands
+    }
+    FlashStep::Assert { value } => {
+      let checked = match (&value.variable, &value.bulkcmd) {
+        (Some(variable), _) => format!("variable {variable}"),
+        (None, Some(command)) => format!("the response to `{command}`"),
+        (None, None) => "nothing".to_owned(),
+      };
+      format!("# check {checked}, stopping if it is not as expected\n")
+    }
+    FlashStep::Provision { value } => format!(
+      "# write values unique to the device for {}\n",
+      value.variables.join(", ")
+    ),
+    FlashStep::PostBootCheck { value } => {
+      let mut commands =
+        "# boot the device, then check these through adb, stopping if one is not as expected\n".to_owned();
+      for check in &value.checks {
+        let _ = writeln!(commands, "#   adb shell {}", quote(&check.command));
+      }
+      commands
+    }
+  }
+}
+
+/// The file data is read from, or a description of inline data
+fn data(data: &DataOrFile) -> String {
+  match data {
+    DataOrFile::File(file) => source(file),
+    DataOrFile::Data(bytes) => format!("<{} bytes of inline data>", bytes.len()),
+    DataOrFile::Hex { .. } | DataOrFile::Base64 { .. } => "<inline data>".to_owned(),
+    DataOrFile::Concat { concat } => format!("<(cat {})", concat.iter().map(source).collect::<Vec<_>>().join(" ")),
+  }
+}
+
+/// The path of a file, or the part of it `offset` and `length` select cut out of it
+fn source(file: &MetaFile) -> String {
+  if !file.is_sliced() {
+    return quote(&file.file_path);
+  }
+  let offset = file.offset.map_or(0, |offset| offset.get());
+  match file.length {
+    Some(length) => format!(
+      "<(tail -c +{} {} | head -c {})",
+      offset + 1,
+      quote(&file.file_path),
+      length.get()
+    ),
+    None => format!("<(tail -c +{} {})", offset + 1, quote(&file.file_path)),
+  }
+}
+
+/// Quote `text` for the shell if it needs it
+fn quote(text: &str) -> String {
+  if !text.is_empty()
+    && text
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || "-_./:=,+@%".contains(c))
+  {
+    return text.to_owned();
+  }
+  format!("'{}'", text.replace('\'', r"'\''"))
+}
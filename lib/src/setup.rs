@@ -1,25 +1,155 @@
+use serde::Serialize;
+
+use crate::{PRODUCT_ID, PRODUCT_ID_BOOTED, VENDOR_ID, VENDOR_ID_BOOTED};
+
+/// Name of the installed rules file; uaccess only takes effect for rules ordered before 73-seat-late
+#[cfg(any(target_os = "linux", test))]
+const RULES_FILE: &str = "70-superbird.rules";
+
+/// Name older versions installed the rules under, removed when the rules are installed again so
+/// its rules, ordered after the new ones, don't override them
+#[cfg(any(target_os = "linux", test))]
+const OLD_RULES_FILE: &str = "98-superbird.rules";
+
+/// udev rules granting access to the device in USB mode and when booted normally
+///
+/// `TAG+="uaccess"` gives whoever is logged in at the seat access. Headless machines and remote
+/// sessions have no seat, so `group`, e.g. `plugdev`, also gives its members access if set.
+pub fn udev_rules(group: Option<&str>) -> String {
+  let group = group.map(|group| format!("GROUP=\"{}\", ", group)).unwrap_or_default();
+  [(VENDOR_ID, PRODUCT_ID), (VENDOR_ID_BOOTED, PRODUCT_ID_BOOTED)]
+    .iter()
+    .map(|(vendor_id, product_id)| {
+      format!(
+        "SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", MODE=\"0660\", {}TAG+=\"uaccess\"\n",
+        vendor_id, product_id, group
+      )
+    })
+    .collect()
+}
+
+/// Check that `group` is a plain group name, so it can't break out of the rule it is put in
+pub fn check_udev_group(group: &str) -> crate::Result<()> {
+  let valid = group
+    .chars()
+    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+  if group.is_empty() || !valid {
+    return Err(crate::Error::InvalidOperation(format!(
+      "`{}` is not a valid group name",
+      group
+    )));
+  }
+  Ok(())
+}
+
+/// Outcome of [`AmlogicSoC::host_setup`](crate::AmlogicSoC::host_setup)
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct HostSetupStatus {
+  /// Sandbox the process is running in, if any
+  pub confinement: Option<Confinement>,
+  /// Whether the host was changed, e.g. udev rules were installed
+  pub changed: bool,
+  /// Problems that have to be fixed by hand, each saying how
+  pub findings: Vec<String>,
+}
+
+/// App sandbox that limits access to the host
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confinement {
+  /// Running as a Flatpak
+  Flatpak,
+  /// Running as a Snap
+  Snap,
+}
+
+impl std::fmt::Display for Confinement {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Confinement::Flatpak => write!(f, "Flatpak"),
+      Confinement::Snap => write!(f, "Snap"),
+    }
+  }
+}
+
+/// Detect whether the process runs inside Flatpak or Snap confinement
+#[cfg(not(target_os = "macos"))]
+pub fn confinement() -> Option<Confinement> {
+  if std::env::var_os("FLATPAK_ID").is_some() || std::path::Path::new("/.flatpak-info").exists() {
+    Some(Confinement::Flatpak)
+  } else if std::env::var_os("SNAP_NAME").is_some() {
+    Some(Confinement::Snap)
+  } else {
+    None
+  }
+}
+
+/// What a confined app has to ask the user to do, since it cannot write to /etc itself
+///
+/// # Parameters
+/// - `confinement`: The sandbox in use
+/// - `app_id`: Flatpak app ID or snap name, if known
+/// - `has_usb`: Whether the sandbox exposes USB device nodes
+#[cfg(any(target_os = "linux", test))]
+fn confined_findings(confinement: Confinement, app_id: Option<&str>, has_usb: bool) -> Vec<String> {
+  let mut findings = Vec::new();
+  if !has_usb {
+    findings.push(match confinement {
+      Confinement::Flatpak => format!(
+        "the Flatpak has no access to USB devices; run `flatpak override --user --device=all {}` and restart it",
+        app_id.unwrap_or("<app id>")
+      ),
+      Confinement::Snap => format!(
+        "the snap has no access to USB devices; run `sudo snap connect {}:raw-usb` and restart it",
+        app_id.unwrap_or("<snap name>")
+      ),
+    });
+  }
+  findings.push(format!(
+    "udev rules cannot be installed from inside a {confinement}; install the output of `host_setup_rules` to /etc/udev/rules.d/{RULES_FILE} on the host and remove /etc/udev/rules.d/{OLD_RULES_FILE} if it is there"
+  ));
+  findings
+}
+
 #[cfg(target_os = "linux")]
-pub fn setup_host_linux() -> crate::Result<()> {
+pub fn setup_host_linux(group: Option<&str>) -> crate::Result<HostSetupStatus> {
   use std::{fs, path::PathBuf, process::Command};
 
-  use crate::{PRODUCT_ID, PRODUCT_ID_BOOTED, VENDOR_ID, VENDOR_ID_BOOTED};
-
-  let rules_path = PathBuf::from("/etc/udev/rules.d/98-superbird.rules");
+  if let Some(confinement) = confinement() {
+    let app_id = match confinement {
+      Confinement::Flatpak => std::env::var("FLATPAK_ID").ok(),
+      Confinement::Snap => std::env::var("SNAP_NAME").ok(),
+    };
+    let has_usb = std::path::Path::new("/dev/bus/usb").exists();
+    tracing::warn!("running inside a {}, not installing udev rules", confinement);
+    return Ok(HostSetupStatus {
+      confinement: Some(confinement),
+      changed: false,
+      findings: confined_findings(confinement, app_id.as_deref(), has_usb),
+    });
+  }
 
-  let username = whoami::username()?;
-  let rules_content = format!(
-    "SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", OWNER=\"{}\", MODE=\"0666\"\n\
-       SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", OWNER=\"{}\", MODE=\"0666\"\n",
-    VENDOR_ID, PRODUCT_ID, username, VENDOR_ID_BOOTED, PRODUCT_ID_BOOTED, username
-  );
+  let mut setup_status = HostSetupStatus::default();
+  let rules_path = PathBuf::from("/etc/udev/rules.d").join(RULES_FILE);
+  let old_rules_path = PathBuf::from("/etc/udev/rules.d").join(OLD_RULES_FILE);
+  let rules_content = udev_rules(group);
 
   let temp_dir = std::env::temp_dir();
-  let temp_file_path = temp_dir.join("98-superbird.rules");
+  let temp_file_path = temp_dir.join(RULES_FILE);
   fs::write(&temp_file_path, &rules_content)?;
   tracing::debug!("created temporary rules file at: {}", temp_file_path.display());
 
+  // one pkexec call, so the user is only asked once to install the rules and drop the old ones
   let pkexec_result = Command::new("pkexec")
-    .args(["cp", &temp_file_path.to_string_lossy(), &rules_path.to_string_lossy()])
+    .args([
+      "sh",
+      "-c",
+      "cp \"$1\" \"$2\" && rm -f \"$3\"",
+      "sh",
+      &temp_file_path.to_string_lossy(),
+      &rules_path.to_string_lossy(),
+      &old_rules_path.to_string_lossy(),
+    ])
     .status();
 
   if let Ok(status) = pkexec_result {
@@ -36,11 +166,18 @@ pub fn setup_host_linux() -> crate::Result<()> {
 
         tracing::info!("successfully activated udev rules. Device should now be accessible.");
         let _ = fs::remove_file(&temp_file_path);
-        return Ok(());
+        setup_status.changed = true;
+        return Ok(setup_status);
       }
 
       tracing::warn!("installed rules but failed to reload automatically. please run:");
       tracing::warn!("  sudo udevadm control --reload-rules && sudo udevadm trigger");
+      setup_status.changed = true;
+      setup_status.findings.push(
+        "udev rules were installed but not reloaded; run `sudo udevadm control --reload-rules && sudo udevadm trigger`"
+          .into(),
+      );
+      return Ok(setup_status);
     } else {
       tracing::warn!("polkit authentication failed or was canceled");
     }
@@ -50,7 +187,161 @@ pub fn setup_host_linux() -> crate::Result<()> {
 
   tracing::info!("to install the rules manually, run the following commands:");
   tracing::info!("  sudo cp {} /etc/udev/rules.d/", temp_file_path.display());
+  tracing::info!("  sudo rm -f {}", old_rules_path.display());
   tracing::info!("  sudo udevadm control --reload-rules && sudo udevadm trigger");
+  setup_status.findings.push(format!(
+    "udev rules could not be installed; run `sudo cp {} /etc/udev/rules.d/ && sudo rm -f {} && sudo udevadm control --reload-rules && sudo udevadm trigger`",
+    temp_file_path.display(),
+    old_rules_path.display()
+  ));
 
-  Ok(())
+  Ok(setup_status)
+}
+
+/// Entitlement a sandboxed macOS app needs to talk to USB devices
+#[cfg(target_os = "macos")]
+const USB_ENTITLEMENT: &str = "com.apple.security.device.usb";
+
+#[cfg(target_os = "macos")]
+pub fn setup_host_macos(profile: &crate::DeviceProfile) -> crate::Result<HostSetupStatus> {
+  let findings = diagnose_macos(profile);
+  if findings.is_empty() {
+    tracing::info!("no USB access problems found");
+  }
+  for finding in &findings {
+    tracing::warn!("{}", finding);
+  }
+
+  Ok(HostSetupStatus {
+    findings,
+    ..Default::default()
+  })
+}
+
+/// Look for the usual reasons a device cannot be opened on macOS
+///
+/// macOS needs no setup for USB access, so failures come from another process holding the
+/// device or from the app sandbox. Each finding says what is wrong and how to fix it.
+#[cfg(target_os = "macos")]
+fn diagnose_macos(profile: &crate::DeviceProfile) -> Vec<String> {
+  use std::process::Command;
+
+  let mut findings = Vec::new();
+
+  match Command::new("ioreg")
+    .args(["-r", "-c", "IOUSBHostDevice", "-l", "-w0"])
+    .output()
+  {
+    Ok(output) => {
+      let ioreg = String::from_utf8_lossy(&output.stdout);
+      if let Some(owner) = exclusive_owner(&ioreg, profile.vendor_id, profile.product_id) {
+        findings.push(format!(
+          "the device is held open by another process ({owner}); quit it and reconnect the device"
+        ));
+      }
+    }
+    Err(err) => tracing::debug!("could not run ioreg: {}", err),
+  }
+
+  if std::env::var_os("APP_SANDBOX_CONTAINER_ID").is_some() {
+    let entitled = std::env::current_exe()
+      .ok()
+      .and_then(|exe| {
+        Command::new("codesign")
+          .args(["-d", "--entitlements", "-"])
+          .arg(exe)
+          .output()
+          .ok()
+      })
+      .is_some_and(|output| {
+        // codesign prints the entitlements to stdout or stderr depending on the macOS version
+        String::from_utf8_lossy(&output.stdout).contains(USB_ENTITLEMENT)
+          || String::from_utf8_lossy(&output.stderr).contains(USB_ENTITLEMENT)
+      });
+    if !entitled {
+      findings.push(format!(
+        "this app runs in the App Sandbox without the {USB_ENTITLEMENT} entitlement; add it to the app's entitlements and re-sign"
+      ));
+    }
+  }
+
+  findings
+}
+
+/// Find the exclusive owner of a device in `ioreg -l` output, if another process has claimed it
+#[cfg(any(target_os = "macos", test))]
+fn exclusive_owner(ioreg: &str, vendor_id: u16, product_id: u16) -> Option<String> {
+  let vendor = format!("\"idVendor\" = {vendor_id}");
+  let product = format!("\"idProduct\" = {product_id}");
+
+  ioreg
+    .split("+-o ")
+    .filter(|device| {
+      device.lines().any(|line| line.trim() == vendor) && device.lines().any(|line| line.trim() == product)
+    })
+    .find_map(|device| {
+      device.lines().find_map(|line| {
+        let (key, value) = line.trim().split_once(" = ")?;
+        (key == "\"UsbExclusiveOwner\"").then(|| value.trim_matches('"').to_owned())
+      })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_exclusive_owner() {
+    let ioreg = r#"+-o GX-CHIP@01100000  <class IOUSBHostDevice, id 0x100001234, registered, matched, active, busy 0 (3 ms), retain 22>
+    {
+      "idProduct" = 49155
+      "idVendor" = 7054
+      "UsbExclusiveOwner" = "pid 4242, adb"
+    }
++-o Keyboard@01200000  <class IOUSBHostDevice, id 0x100001235, registered, matched, active, busy 0 (3 ms), retain 22>
+    {
+      "idProduct" = 1
+      "idVendor" = 1452
+      "UsbExclusiveOwner" = "pid 1, kernel_task"
+    }
+"#;
+    assert_eq!(exclusive_owner(ioreg, 0x1b8e, 0xc003), Some("pid 4242, adb".to_owned()));
+    assert_eq!(exclusive_owner(ioreg, 0x1b8e, 0xc004), None);
+  }
+
+  #[test]
+  fn test_confined_findings() {
+    let findings = confined_findings(Confinement::Flatpak, Some("app.flashthing"), false);
+    assert_eq!(findings.len(), 2);
+    assert!(findings[0].contains("--device=all app.flashthing"));
+    assert!(findings[1].contains(RULES_FILE));
+
+    let findings = confined_findings(Confinement::Snap, None, true);
+    assert_eq!(findings.len(), 1);
+    assert!(findings[0].contains("inside a Snap"));
+  }
+
+  #[test]
+  fn test_udev_rules() {
+    let rules = udev_rules(None);
+    assert_eq!(rules.lines().count(), 2);
+    assert!(rules.contains("ATTRS{idVendor}==\"1b8e\", ATTRS{idProduct}==\"c003\""));
+    assert!(
+      rules
+        .lines()
+        .all(|rule| rule.ends_with("TAG+=\"uaccess\"") && !rule.contains("OWNER") && !rule.contains("GROUP"))
+    );
+
+    let rules = udev_rules(Some("plugdev"));
+    assert!(
+      rules
+        .lines()
+        .all(|rule| rule.ends_with("GROUP=\"plugdev\", TAG+=\"uaccess\""))
+    );
+
+    assert!(check_udev_group("plugdev").is_ok());
+    assert!(check_udev_group("").is_err());
+    assert!(check_udev_group("plugdev\", OWNER=\"root").is_err());
+  }
 }
@@ -0,0 +1,408 @@
+//! Point-in-time captures of device state, for working out what a flash actually changed.
+
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  fmt,
+  fs::File,
+  io::{Read, Write},
+  path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zip::{ZipArchive, ZipWriter, write::SimpleFileOptions};
+
+use crate::{AmlogicSoC, DeviceInfo, Result, aml::to_hex, config::UBootEnv, flash::FlashProgress, report::unix_now};
+
+/// Bytes captured from the start of each small partition
+const HEAD_SIZE: usize = 1024 * 1024;
+/// Partitions up to this size have their first [`HEAD_SIZE`] bytes captured
+const SMALL_PARTITION_SIZE: usize = 64 * 1024 * 1024;
+/// Partitions hashed in full, since they are too large to capture but are what a flash usually changes
+const DIGEST_PARTITIONS: [&str; 2] = ["system_a", "system_b"];
+
+const SNAPSHOT_JSON: &str = "snapshot.json";
+const HEADS_DIR: &str = "heads";
+
+/// State of a device at one point in time
+///
+/// Snapshots are saved as a zip holding `snapshot.json` and the captured start of each small
+/// partition under `heads/`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+  /// Unix timestamp (seconds) the snapshot was taken at
+  pub taken_at: u64,
+  /// Name of the device profile used
+  pub profile: String,
+  /// USB details of the device
+  pub device: DeviceInfo,
+  /// Response to the identify request
+  pub identify: String,
+  /// U-Boot environment, as stored in the `env` partition
+  pub env: BTreeMap<String, String>,
+  /// Partition table with what was read from each partition
+  pub partitions: Vec<PartitionState>,
+  /// Start of each small partition, keyed by name
+  #[serde(skip)]
+  pub heads: BTreeMap<String, Vec<u8>>,
+}
+
+/// What a snapshot recorded about one partition
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PartitionState {
+  /// Partition name
+  pub name: String,
+  /// Offset from the start of the user area in sectors
+  pub offset: usize,
+  /// Size in bytes the device accepted, `None` if the partition could not be read
+  pub size: Option<usize>,
+  /// SHA-256 of the captured start of the partition
+  pub head_sha256: Option<String>,
+  /// SHA-256 of the whole partition, for the system slots
+  pub sha256: Option<String>,
+}
+
+/// A difference between two snapshots
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotChange {
+  /// The snapshots were taken from devices with different serial numbers
+  Device {
+    /// Serial in the first snapshot
+    before: Option<String>,
+    /// Serial in the second snapshot
+    after: Option<String>,
+  },
+  /// The identify response changed
+  Identify {
+    /// Response in the first snapshot
+    before: String,
+    /// Response in the second snapshot
+    after: String,
+  },
+  /// An environment variable was added, removed or changed
+  Env {
+    /// Variable name
+    key: String,
+    /// Value in the first snapshot
+    before: Option<String>,
+    /// Value in the second snapshot
+    after: Option<String>,
+  },
+  /// A partition appeared, disappeared or changed size
+  PartitionSize {
+    /// Partition name
+    name: String,
+    /// Size in the first snapshot
+    before: Option<usize>,
+    /// Size in the second snapshot
+    after: Option<usize>,
+  },
+  /// The captured start of a partition differs
+  PartitionHead {
+    /// Partition name
+    name: String,
+    /// Offset of the first differing byte
+    first_difference: usize,
+    /// Number of differing bytes in the captured region
+    bytes_changed: usize,
+  },
+  /// The digest of a whole partition differs
+  PartitionDigest {
+    /// Partition name
+    name: String,
+    /// Digest in the first snapshot
+    before: Option<String>,
+    /// Digest in the second snapshot
+    after: Option<String>,
+  },
+}
+
+impl fmt::Display for SnapshotChange {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let show = |value: &Option<String>| value.clone().unwrap_or_else(|| "(none)".into());
+    match self {
+      Self::Device { before, after } => write!(f, "device serial: {} -> {}", show(before), show(after)),
+      Self::Identify { before, after } => write!(f, "identify: {} -> {}", before, after),
+      Self::Env { key, before, after } => write!(f, "env {}: {} -> {}", key, show(before), show(after)),
+      Self::PartitionSize { name, before, after } => write!(
+        f,
+        "{} size: {} -> {}",
+        name,
+        show(&before.map(|size| size.to_string())),
+        show(&after.map(|size| size.to_string()))
+      ),
+      Self::PartitionHead {
+        name,
+        first_difference,
+        bytes_changed,
+      } => write!(
+        f,
+        "{}: {} bytes changed, starting at {:#x}",
+        name, bytes_changed, first_difference
+      ),
+      Self::PartitionDigest { name, before, after } => {
+        write!(f, "{} sha256: {} -> {}", name, show(before), show(after))
+      }
+    }
+  }
+}
+
+impl Snapshot {
+  /// Capture the state of a device in USB burn mode
+  ///
+  /// Reads the start of every small partition and hashes the system slots, which takes a few
+  /// minutes. Partitions that can't be read are recorded without a size.
+  ///
+  /// # Parameters
+  /// - `aml`: The connected device
+  /// - `progress_callback`: Function to call with progress updates while hashing the system slots
+  ///
+  /// # Returns
+  /// - `Result<Self>`: The snapshot or an error
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn capture<F: Fn(FlashProgress)>(aml: &AmlogicSoC, progress_callback: F) -> Result<Self> {
+    let identify = aml.identify()?;
+    let head_limit = HEAD_SIZE.min(aml.profile().max_transfer_size);
+
+    let mut partitions = Vec::new();
+    let mut heads = BTreeMap::new();
+    for part_info in aml.profile().partitions.iter() {
+      let name = part_info.name.to_string();
+      let mut state = PartitionState {
+        name: name.clone(),
+        offset: part_info.offset,
+        size: None,
+        head_sha256: None,
+        sha256: None,
+      };
+
+      // reserved needs allowSpecialPartitions and cache is empty, so neither has anything to read
+      if name == "reserved" || part_info.size == 0 {
+        partitions.push(state);
+        continue;
+      }
+
+      let part_size = match aml.validate_partition_size(&name, part_info, false) {
+        Ok(size) => size,
+        Err(e) => {
+          tracing::warn!("could not read {} for the snapshot: {}", name, e);
+          partitions.push(state);
+          continue;
+        }
+      };
+      state.size = Some(part_size);
+
+      if part_size <= SMALL_PARTITION_SIZE {
+        tracing::info!("capturing the start of {}", name);
+        let head = aml.read_partition_chunk(&name, 0, part_size.min(head_limit))?;
+        state.head_sha256 = Some(to_hex(&Sha256::digest(&head)));
+        heads.insert(name.clone(), head);
+      }
+      if DIGEST_PARTITIONS.contains(&name.as_str()) {
+        tracing::info!("hashing {}", name);
+        state.sha256 = Some(aml.digest_partition::<Sha256, _>(&name, part_size, &progress_callback)?);
+      }
+      partitions.push(state);
+    }
+
+    Ok(Self {
+      taken_at: unix_now(),
+      profile: aml.profile().name.to_string(),
+      device: aml.device_info().clone(),
+      identify,
+      env: heads
+        .get("env")
+        .map(|env| UBootEnv::from_image_unchecked(env).into_iter().collect())
+        .unwrap_or_default(),
+      partitions,
+      heads,
+    })
+  }
+
+  /// Save the snapshot to a zip at `path`
+  pub fn save(&self, path: &Path) -> Result<()> {
+    let mut zip = ZipWriter::new(File::create(path)?);
+    let options = SimpleFileOptions::default();
+
+    zip.start_file(SNAPSHOT_JSON, options)?;
+    zip.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+    for (name, head) in &self.heads {
+      zip.start_file(format!("{HEADS_DIR}/{name}.bin"), options)?;
+      zip.write_all(head)?;
+    }
+
+    zip.finish()?;
+    Ok(())
+  }
+
+  /// Load a snapshot saved with [`save`](Self::save)
+  pub fn load(path: &Path) -> Result<Self> {
+    let mut zip = ZipArchive::new(File::open(path)?)?;
+
+    let mut json = String::new();
+    zip.by_name(SNAPSHOT_JSON)?.read_to_string(&mut json)?;
+    let mut this: Self = serde_json::from_str(&json)?;
+
+    for state in &this.partitions {
+      if state.head_sha256.is_none() {
+        continue;
+      }
+      let mut head = Vec::new();
+      zip
+        .by_name(&format!("{HEADS_DIR}/{}.bin", state.name))?
+        .read_to_end(&mut head)?;
+      this.heads.insert(state.name.clone(), head);
+    }
+    Ok(this)
+  }
+
+  /// List what changed between this snapshot and a later one
+  pub fn diff(&self, after: &Snapshot) -> Vec<SnapshotChange> {
+    let mut changes = Vec::new();
+
+    if self.device.serial != after.device.serial {
+      changes.push(SnapshotChange::Device {
+        before: self.device.serial.clone(),
+        after: after.device.serial.clone(),
+      });
+    }
+    if self.identify != after.identify {
+      changes.push(SnapshotChange::Identify {
+        before: self.identify.clone(),
+        after: after.identify.clone(),
+      });
+    }
+
+    let keys = self.env.keys().chain(after.env.keys()).collect::<BTreeSet<_>>();
+    for key in keys {
+      let (before_value, after_value) = (self.env.get(key), after.env.get(key));
+      if before_value != after_value {
+        changes.push(SnapshotChange::Env {
+          key: key.clone(),
+          before: before_value.cloned(),
+          after: after_value.cloned(),
+        });
+      }
+    }
+
+    let names = self
+      .partitions
+      .iter()
+      .chain(&after.partitions)
+      .map(|state| state.name.as_str())
+      .collect::<BTreeSet<_>>();
+    for name in names {
+      let before_state = self.partitions.iter().find(|state| state.name == name);
+      let after_state = after.partitions.iter().find(|state| state.name == name);
+      let size = |state: Option<&PartitionState>| state.and_then(|state| state.size);
+      if size(before_state) != size(after_state) {
+        changes.push(SnapshotChange::PartitionSize {
+          name: name.to_owned(),
+          before: size(before_state),
+          after: size(after_state),
+        });
+      }
+
+      if let (Some(before_head), Some(after_head)) = (self.heads.get(name), after.heads.get(name))
+        && let Some((first_difference, bytes_changed)) = compare_bytes(before_head, after_head)
+      {
+        changes.push(SnapshotChange::PartitionHead {
+          name: name.to_owned(),
+          first_difference,
+          bytes_changed,
+        });
+      }
+
+      let digest = |state: Option<&PartitionState>| state.and_then(|state| state.sha256.clone());
+      if digest(before_state) != digest(after_state) {
+        changes.push(SnapshotChange::PartitionDigest {
+          name: name.to_owned(),
+          before: digest(before_state),
+          after: digest(after_state),
+        });
+      }
+    }
+
+    changes
+  }
+}
+
+/// Find the first differing offset and the number of differing bytes, counting a length change as changed bytes
+fn compare_bytes(before: &[u8], after: &[u8]) -> Option<(usize, usize)> {
+  let mut first_difference = None;
+  let mut bytes_changed = before.len().abs_diff(after.len());
+  for (offset, (a, b)) in before.iter().zip(after).enumerate() {
+    if a != b {
+      first_difference.get_or_insert(offset);
+      bytes_changed += 1;
+    }
+  }
+
+  match first_difference {
+    Some(offset) => Some((offset, bytes_changed)),
+    None if bytes_changed > 0 => Some((before.len().min(after.len()), bytes_changed)),
+    None => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_diff() {
+    let device = crate::fixtures::device_info(Some("abc"));
+    let partition = |name: &str, sha256: Option<&str>| PartitionState {
+      name: name.into(),
+      offset: 0,
+      size: Some(16),
+      head_sha256: None,
+      sha256: sha256.map(str::to_owned),
+    };
+    let before = Snapshot {
+      taken_at: 0,
+      profile: "superbird".into(),
+      device: device.clone(),
+      identify: "0.0.0".into(),
+      env: BTreeMap::from([("slot".into(), "a".into()), ("old".into(), "1".into())]),
+      partitions: vec![partition("env", None), partition("system_a", Some("aa"))],
+      heads: BTreeMap::from([("env".into(), vec![0; 16])]),
+    };
+    let mut after = before.clone();
+    after.env = BTreeMap::from([("slot".into(), "b".into())]);
+    after.partitions[1].sha256 = Some("bb".into());
+    after
+      .heads
+      .insert("env".into(), [vec![0; 4], vec![1; 2], vec![0; 10]].concat());
+
+    assert!(before.diff(&before).is_empty());
+    assert_eq!(
+      before.diff(&after),
+      [
+        SnapshotChange::Env {
+          key: "old".into(),
+          before: Some("1".into()),
+          after: None,
+        },
+        SnapshotChange::Env {
+          key: "slot".into(),
+          before: Some("a".into()),
+          after: Some("b".into()),
+        },
+        SnapshotChange::PartitionHead {
+          name: "env".into(),
+          first_difference: 4,
+          bytes_changed: 2,
+        },
+        SnapshotChange::PartitionDigest {
+          name: "system_a".into(),
+          before: Some("aa".into()),
+          after: Some("bb".into()),
+        },
+      ]
+    );
+  }
+}
@@ -0,0 +1,91 @@
+//! Temporary files for data too large to hold in memory, such as a partition read back from the
+//! device, removed again once they are dropped.
+
+use std::{
+  fs::{self, File, OpenOptions},
+  io::{self, Read, Seek, SeekFrom, Write},
+  path::{Path, PathBuf},
+  sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::Result;
+
+/// Files spooled so far by this process, to give each a name of its own
+static SPOOLED: AtomicUsize = AtomicUsize::new(0);
+
+/// Temporary file in the system's temp directory, deleted when dropped
+#[derive(Debug)]
+pub(crate) struct Spool {
+  path: PathBuf,
+  file: File,
+}
+
+impl Spool {
+  /// Create an empty spool file, with `name` in its file name to tell what it holds
+  pub(crate) fn create(name: &str) -> Result<Self> {
+    let path = std::env::temp_dir().join(format!(
+      "flashthing-{}-{}-{}",
+      name,
+      std::process::id(),
+      SPOOLED.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    Ok(Self { path, file })
+  }
+
+  /// Path of the file, for opening another handle to it
+  pub(crate) fn path(&self) -> &Path {
+    &self.path
+  }
+}
+
+impl Read for Spool {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.file.read(buf)
+  }
+}
+
+impl Write for Spool {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.file.write(buf)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.file.flush()
+  }
+}
+
+impl Seek for Spool {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    self.file.seek(pos)
+  }
+}
+
+impl Drop for Spool {
+  fn drop(&mut self) {
+    if let Err(err) = fs::remove_file(&self.path) {
+      tracing::debug!("could not remove {}: {}", self.path.display(), err);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_spool() {
+    let mut spool = Spool::create("test").unwrap();
+    let path = spool.path().to_owned();
+    spool.write_all(b"hello world").unwrap();
+    spool.seek(SeekFrom::Start(6)).unwrap();
+    let mut word = String::new();
+    spool.read_to_string(&mut word).unwrap();
+    assert_eq!(word, "world");
+    assert_eq!(fs::read(&path).unwrap(), b"hello world");
+
+    assert_ne!(Spool::create("test").unwrap().path(), path);
+    drop(spool);
+    assert!(!path.exists());
+  }
+}
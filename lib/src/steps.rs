@@ -0,0 +1,144 @@
+//! Running a flash one step at a time through handles, for callers that decide step by step what to do.
+
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+use crate::{
+  Error, FlashOutcome, Flasher, Result,
+  config::{FlashStep, Step},
+};
+
+/// Iterator over the steps of a flash, made by [`Flasher::into_steps`]
+///
+/// Each [`StepExecution`] it yields is the next step to run, and has to be run or skipped before
+/// asking for the next one; a handle dropped without either is skipped. Steps the flasher was
+/// built to skip are left out. Once there are no steps left, the flash is finished and its
+/// report finalized, as when [`Flasher::step`] returns [`FlashOutcome::Complete`].
+pub struct Steps {
+  flasher: Arc<Mutex<Flasher>>,
+  /// Index of the step of the last handle handed out
+  last: Option<usize>,
+}
+
+/// One step of a flash, yielded by [`Steps`]
+pub struct StepExecution {
+  flasher: Weak<Mutex<Flasher>>,
+  index: usize,
+  step: Step,
+}
+
+impl Flasher {
+  /// Turn the flasher into an iterator of its steps, to run or skip one at a time
+  ///
+  /// This is the same as calling [`step`](Self::step) in a loop, but lets callers look at each
+  /// step and leave it out before it runs. [`Steps::into_flasher`] gives the flasher back, e.g.
+  /// for its [`report`](Self::report).
+  pub fn into_steps(self) -> Steps {
+    Steps {
+      flasher: Arc::new(Mutex::new(self)),
+      last: None,
+    }
+  }
+}
+
+impl Steps {
+  /// Get the flasher back, e.g. for the report of the flash
+  ///
+  /// # Returns
+  /// - `Result<Flasher>`: The flasher, or an error if a step is still running on another thread
+  pub fn into_flasher(self) -> Result<Flasher> {
+    let flasher =
+      Arc::try_unwrap(self.flasher).map_err(|_| Error::InvalidOperation("a step is still running".to_string()))?;
+    flasher
+      .into_inner()
+      .map_err(|_| Error::InvalidOperation("a step panicked".to_string()))
+  }
+}
+
+impl Iterator for Steps {
+  type Item = StepExecution;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let mut flasher = lock(&self.flasher).ok()?;
+    let mut index = flasher.next_pending();
+    if let Some(last) = self.last
+      && index == Some(last)
+    {
+      tracing::debug!("step {} was neither run nor skipped, skipping it", last + 1);
+      flasher.skip_step(last);
+      index = flasher.next_pending();
+    }
+
+    let Some(index) = index else {
+      // finalizes the report; a failure was already returned by the step that failed
+      if let Err(e) = flasher.step() {
+        tracing::debug!("flash finished with error: {}", e);
+      }
+      return None;
+    };
+    self.last = Some(index);
+    Some(StepExecution {
+      flasher: Arc::downgrade(&self.flasher),
+      index,
+      step: flasher.steps()[index].clone(),
+    })
+  }
+}
+
+impl StepExecution {
+  /// Position of the step in the package, counting from 1 like [`Event::Step`](crate::Event::Step)
+  pub fn number(&self) -> usize {
+    self.index + 1
+  }
+
+  /// The step's `id`, if it has one
+  pub fn id(&self) -> Option<&str> {
+    self.step.id.as_deref()
+  }
+
+  /// The step as written in the package, before variables are substituted into it
+  pub fn step(&self) -> &FlashStep {
+    &self.step.step
+  }
+
+  /// Run the step, retrying it as its `onError` allows
+  ///
+  /// # Returns
+  /// - `Result<FlashOutcome>`: What the step produced, or the error that ended flashing
+  pub fn run(self) -> Result<FlashOutcome> {
+    let flasher = self.flasher()?;
+    let mut flasher = self.lock_pending(&flasher)?;
+    flasher.step()
+  }
+
+  /// Leave the step out, as if it was given to [`FlasherBuilder::skip`](crate::FlasherBuilder::skip)
+  pub fn skip(self) -> Result<()> {
+    let flasher = self.flasher()?;
+    self.lock_pending(&flasher)?.skip_step(self.index);
+    Ok(())
+  }
+
+  fn flasher(&self) -> Result<Arc<Mutex<Flasher>>> {
+    self
+      .flasher
+      .upgrade()
+      .ok_or_else(|| Error::InvalidOperation("the steps this step came from were dropped".to_string()))
+  }
+
+  /// Lock the flasher, checking this is still the step it runs next
+  fn lock_pending<'a>(&self, flasher: &'a Mutex<Flasher>) -> Result<MutexGuard<'a, Flasher>> {
+    let flasher = lock(flasher)?;
+    if flasher.next_pending() != Some(self.index) {
+      return Err(Error::InvalidOperation(format!(
+        "step {} is not the next step to run",
+        self.number()
+      )));
+    }
+    Ok(flasher)
+  }
+}
+
+fn lock(flasher: &Mutex<Flasher>) -> Result<MutexGuard<'_, Flasher>> {
+  flasher
+    .lock()
+    .map_err(|_| Error::InvalidOperation("a step panicked".to_string()))
+}
@@ -0,0 +1,103 @@
+//! Built-in `meta.json`s for restoring stock dumps, one per layout dumps come in.
+
+use std::str::FromStr;
+
+use crate::{Error, Result};
+
+/// A built-in `meta.json` for flashing a stock dump, which has none of its own
+///
+/// Dumps of different superbird firmware revisions, or made with different tools, name their
+/// files differently. [`StockProfile::SUPERBIRD`] is the default and expects the system
+/// partitions as `system_a.ext2` and `system_b.ext2`, the way `dump` writes them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StockProfile {
+  /// Name the profile is picked by, e.g. `superbird-dump`
+  pub name: &'static str,
+  /// What dumps the profile is for
+  pub description: &'static str,
+  meta: &'static [u8],
+}
+
+impl StockProfile {
+  /// Dumps with the system partitions as `.ext2` images and every other partition as `<name>.dump`
+  pub const SUPERBIRD: Self = Self {
+    name: "superbird",
+    description: "system partitions as .ext2 images, the rest as .dump files",
+    meta: include_bytes!("../resources/stock-meta.json"),
+  };
+
+  /// Dumps with every partition as `<name>.dump`, including the system partitions
+  pub const SUPERBIRD_DUMP: Self = Self {
+    name: "superbird-dump",
+    description: "every partition as a .dump file, including the system partitions",
+    meta: include_bytes!("../resources/stock-meta-dump.json"),
+  };
+
+  /// Every built-in stock profile, the default first
+  pub const ALL: &[Self] = &[Self::SUPERBIRD, Self::SUPERBIRD_DUMP];
+
+  /// Find a built-in stock profile by name
+  pub fn find(name: &str) -> Option<Self> {
+    Self::ALL.iter().find(|profile| profile.name == name).copied()
+  }
+
+  /// The profile's `meta.json`
+  pub fn meta(&self) -> &'static [u8] {
+    self.meta
+  }
+}
+
+impl Default for StockProfile {
+  fn default() -> Self {
+    Self::SUPERBIRD
+  }
+}
+
+impl FromStr for StockProfile {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    Self::find(s).ok_or_else(|| {
+      let names = Self::ALL.iter().map(|profile| profile.name).collect::<Vec<_>>();
+      Error::InvalidOperation(format!(
+        "unknown stock profile {s:?}, expected one of {}",
+        names.join(", ")
+      ))
+    })
+  }
+}
+
+impl std::fmt::Display for StockProfile {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(self.name)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::FlashConfig;
+
+  #[test]
+  fn test_stock_profiles() {
+    assert_eq!(StockProfile::default(), StockProfile::SUPERBIRD);
+    assert_eq!(
+      "superbird-dump".parse::<StockProfile>().unwrap(),
+      StockProfile::SUPERBIRD_DUMP
+    );
+    assert!("superbird-9".parse::<StockProfile>().is_err());
+
+    let files = |profile: StockProfile| {
+      let config = FlashConfig::from_stock_profile(profile).unwrap();
+      config
+        .steps
+        .iter()
+        .flat_map(|step| crate::package::step_files(&step.step))
+        .map(|file| file.file_path.clone())
+        .collect::<Vec<_>>()
+    };
+    assert!(files(StockProfile::SUPERBIRD).contains(&"system_a.ext2".to_owned()));
+    let dump = files(StockProfile::SUPERBIRD_DUMP);
+    assert!(dump.contains(&"system_a.dump".to_owned()) && !dump.contains(&"system_a.ext2".to_owned()));
+  }
+}
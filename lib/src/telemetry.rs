@@ -0,0 +1,134 @@
+//! Counters and histograms recorded through the [`metrics`](https://docs.rs/metrics) facade when
+//! built with the `metrics` feature, so services embedding the flasher can export them to e.g.
+//! Prometheus. Without the feature, recording does nothing.
+#![cfg_attr(not(feature = "metrics"), allow(unused_variables, dead_code))]
+
+use std::time::Duration;
+
+use crate::config::FlashStep;
+
+const BYTES_WRITTEN: &str = "flashthing_bytes_written_total";
+const BYTES_SKIPPED: &str = "flashthing_bytes_skipped_total";
+const TRANSFER_RATE: &str = "flashthing_transfer_bytes_per_second";
+const RETRIES: &str = "flashthing_retries_total";
+const COOLDOWN: &str = "flashthing_cooldown_seconds";
+const BULKCMD_DURATION: &str = "flashthing_bulkcmd_duration_seconds";
+const STEP_DURATION: &str = "flashthing_step_duration_seconds";
+
+/// Describe every metric to the installed recorder, so exporters can show their units and help text
+///
+/// Call it once after installing the recorder. Metrics are recorded whether or not they were described.
+#[cfg(feature = "metrics")]
+pub fn describe_metrics() {
+  use metrics::{Unit, describe_counter, describe_histogram};
+
+  describe_counter!(BYTES_WRITTEN, Unit::Bytes, "Bytes written to the eMMC");
+  describe_counter!(
+    BYTES_SKIPPED,
+    Unit::Bytes,
+    "Bytes compare-before-write found already on the eMMC"
+  );
+  describe_histogram!(
+    TRANSFER_RATE,
+    "Rate of each bulk transfer of data to the device's memory"
+  );
+  describe_counter!(RETRIES, "Retried eMMC writes (kind=mmc_write) and steps (kind=step)");
+  describe_histogram!(
+    COOLDOWN,
+    Unit::Seconds,
+    "Time spent on each pause to let the device cool down"
+  );
+  describe_histogram!(BULKCMD_DURATION, Unit::Seconds, "Time each bulk command took to answer");
+  describe_histogram!(
+    STEP_DURATION,
+    Unit::Seconds,
+    "Time each step took including retries, by step type and outcome"
+  );
+}
+
+pub(crate) fn bytes_written(bytes: usize) {
+  #[cfg(feature = "metrics")]
+  metrics::counter!(BYTES_WRITTEN).increment(bytes as u64);
+}
+
+pub(crate) fn bytes_skipped(bytes: usize) {
+  #[cfg(feature = "metrics")]
+  metrics::counter!(BYTES_SKIPPED).increment(bytes as u64);
+}
+
+pub(crate) fn transfer(bytes: usize, elapsed: Duration) {
+  #[cfg(feature = "metrics")]
+  if !elapsed.is_zero() {
+    metrics::histogram!(TRANSFER_RATE).record(bytes as f64 / elapsed.as_secs_f64());
+  }
+}
+
+/// Count a retry of `kind`, `mmc_write` or `step`
+pub(crate) fn retry(kind: &'static str) {
+  #[cfg(feature = "metrics")]
+  metrics::counter!(RETRIES, "kind" => kind).increment(1);
+}
+
+pub(crate) fn cooldown(paused: Duration) {
+  #[cfg(feature = "metrics")]
+  metrics::histogram!(COOLDOWN).record(paused.as_secs_f64());
+}
+
+pub(crate) fn bulkcmd(elapsed: Duration) {
+  #[cfg(feature = "metrics")]
+  metrics::histogram!(BULKCMD_DURATION).record(elapsed.as_secs_f64());
+}
+
+pub(crate) fn step(step: &FlashStep, elapsed: Duration, completed: bool) {
+  #[cfg(feature = "metrics")]
+  metrics::histogram!(
+    STEP_DURATION,
+    "type" => step.kind(),
+    "outcome" => if completed { "completed" } else { "failed" }
+  )
+  .record(elapsed.as_secs_f64());
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+  use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+  use super::*;
+
+  #[test]
+  fn test_recording() {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    metrics::with_local_recorder(&recorder, || {
+      bytes_written(4096);
+      bytes_written(512);
+      retry("mmc_write");
+      step(
+        &FlashStep::Bulkcmd {
+          value: "saveenv".into(),
+        },
+        Duration::from_millis(20),
+        true,
+      );
+    });
+
+    let metrics = snapshotter.snapshot().into_vec();
+    let value = |name: &str| {
+      metrics
+        .iter()
+        .find(|(key, ..)| key.key().name() == name)
+        .map(|(key, _, _, value)| {
+          (
+            key.key().labels().map(|l| l.value().to_owned()).collect::<Vec<_>>(),
+            value,
+          )
+        })
+        .unwrap()
+    };
+    assert!(matches!(value(BYTES_WRITTEN).1, DebugValue::Counter(4608)));
+    assert_eq!(value(RETRIES).0, ["mmc_write"]);
+    let (labels, duration) = value(STEP_DURATION);
+    assert_eq!(labels, ["bulkcmd", "completed"]);
+    assert!(matches!(duration, DebugValue::Histogram(values) if values.len() == 1));
+  }
+}
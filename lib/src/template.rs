@@ -0,0 +1,197 @@
+//! Rendering text files marked `template`, so one env file or boot script can cover several variants
+//! of a package instead of one nearly identical file for each.
+//!
+//! A template is the text as written, with lines choosing what is kept:
+//!
+//! ```text
+//! @if slot == b
+//! boot_slot=_b
+//! @elif slot
+//! boot_slot=_${slot}
+//! @else
+//! boot_slot=_a
+//! @end
+//! ```
+//!
+//! `@if NAME` holds when the variable is set and not empty, `@if NAME == VALUE` and
+//! `@if NAME != VALUE` compare it, and `VALUE` is the rest of the line. Variables are then
+//! substituted into the lines that are kept.
+
+use std::collections::HashMap;
+
+use crate::{Error, Result, variables};
+
+/// Condition of an `@if` or `@elif` line
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Condition {
+  Set(String),
+  Equals(String, String),
+  NotEquals(String, String),
+}
+
+/// Line of a template
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Line<'a> {
+  Text(&'a str),
+  If(Condition),
+  Elif(Condition),
+  Else,
+  End,
+}
+
+/// Parse `text`, checking its `@` lines are known and that every `@if` has an `@end`
+///
+/// `name` is the file the text came from, for errors.
+pub(crate) fn check(name: &str, text: &str) -> Result<()> {
+  parse(name, text).map(drop)
+}
+
+/// Render `text`, keeping the lines whose conditions hold and substituting `variables` into them
+///
+/// # Returns
+/// - `Result<String>`: The rendered text, or an error for a malformed template or a comparison with
+///   a variable that is not set
+pub(crate) fn render(name: &str, text: &str, variables: &HashMap<String, String>) -> Result<String> {
+  let holds = |condition: &Condition| -> Result<bool> {
+    let value = |variable: &str| {
+      variables
+        .get(variable)
+        .ok_or_else(|| Error::InvalidOperation(format!("{}: variable `{}` is not set", name, variable)))
+    };
+    Ok(match condition {
+      Condition::Set(variable) => variables.get(variable).is_some_and(|value| !value.is_empty()),
+      Condition::Equals(variable, expected) => value(variable)? == expected,
+      Condition::NotEquals(variable, expected) => value(variable)? != expected,
+    })
+  };
+
+  let mut rendered = String::with_capacity(text.len());
+  // for each open `@if`: whether the lines of the branch being read are kept, whether an earlier
+  // branch was, and whether the lines around the `@if` are
+  let mut open: Vec<(bool, bool, bool)> = Vec::new();
+  for line in parse(name, text)? {
+    let kept = open.last().is_none_or(|(keep, _, _)| *keep);
+    match line {
+      Line::Text(text) if kept => rendered.push_str(&variables::substitute_text(text, variables)),
+      Line::Text(_) => {}
+      Line::If(condition) => {
+        let keep = kept && holds(&condition)?;
+        open.push((keep, keep, kept));
+      }
+      Line::Elif(condition) => {
+        let (keep, taken, outer) = open.last_mut().expect("parse checks @elif is in an @if");
+        *keep = *outer && !*taken && holds(&condition)?;
+        *taken |= *keep;
+      }
+      Line::Else => {
+        let (keep, taken, outer) = open.last_mut().expect("parse checks @else is in an @if");
+        *keep = *outer && !*taken;
+        *taken = true;
+      }
+      Line::End => {
+        open.pop();
+      }
+    }
+  }
+  Ok(rendered)
+}
+
+/// Split `text` into lines, each keeping its line ending, and parse the `@` ones
+fn parse<'a>(name: &str, text: &'a str) -> Result<Vec<Line<'a>>> {
+  let invalid = |number: usize, problem: &str| Error::InvalidOperation(format!("{}:{}: {}", name, number, problem));
+  let mut lines = Vec::new();
+  // whether each open `@if` has had its `@else`
+  let mut open: Vec<bool> = Vec::new();
+  for (index, text) in text.split_inclusive('\n').enumerate() {
+    let number = index + 1;
+    let Some(directive) = text.trim().strip_prefix('@') else {
+      lines.push(Line::Text(text));
+      continue;
+    };
+    let (keyword, rest) = directive.split_once(char::is_whitespace).unwrap_or((directive, ""));
+    let line = match keyword {
+      "if" => {
+        open.push(false);
+        Line::If(condition(rest).ok_or_else(|| invalid(number, "@if needs a variable to check"))?)
+      }
+      "elif" => {
+        if open.last() != Some(&false) {
+          return Err(invalid(number, "@elif is not in an @if, or comes after its @else"));
+        }
+        Line::Elif(condition(rest).ok_or_else(|| invalid(number, "@elif needs a variable to check"))?)
+      }
+      "else" => match open.last_mut() {
+        Some(had_else @ false) => {
+          *had_else = true;
+          Line::Else
+        }
+        _ => return Err(invalid(number, "@else is not in an @if, or the @if already had one")),
+      },
+      "end" => {
+        open.pop().ok_or_else(|| invalid(number, "@end has no @if"))?;
+        Line::End
+      }
+      _ => return Err(invalid(number, &format!("unknown directive @{}", keyword))),
+    };
+    lines.push(line);
+  }
+  if !open.is_empty() {
+    return Err(Error::InvalidOperation(format!(
+      "{}: {} @if without an @end",
+      name,
+      open.len()
+    )));
+  }
+  Ok(lines)
+}
+
+/// Parse the condition of an `@if` or `@elif`
+fn condition(text: &str) -> Option<Condition> {
+  let text = text.trim();
+  if let Some((variable, value)) = text.split_once("!=") {
+    return Some(Condition::NotEquals(
+      variable.trim().to_owned(),
+      value.trim().to_owned(),
+    ));
+  }
+  if let Some((variable, value)) = text.split_once("==") {
+    return Some(Condition::Equals(variable.trim().to_owned(), value.trim().to_owned()));
+  }
+  (!text.is_empty() && !text.contains(char::is_whitespace)).then(|| Condition::Set(text.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render() {
+    let template = "a=1\n@if slot == b\nslot=b\n@elif model\nslot=${model}\n@else\nslot=a\n@end\nb=${board}\n";
+    let variables = |pairs: &[(&str, &str)]| {
+      pairs
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect::<HashMap<_, _>>()
+    };
+
+    let rendered = render("env.txt", template, &variables(&[("slot", "b"), ("board", "sb")])).unwrap();
+    assert_eq!(rendered, "a=1\nslot=b\nb=sb\n");
+    let rendered = render("env.txt", template, &variables(&[("slot", "a"), ("model", "x")])).unwrap();
+    assert_eq!(rendered, "a=1\nslot=x\nb=${board}\n");
+    let rendered = render("env.txt", template, &variables(&[("slot", "a")])).unwrap();
+    assert_eq!(rendered, "a=1\nslot=a\nb=${board}\n");
+
+    // nested sections inside one that is dropped are dropped with it
+    let nested = "@if a\n@if b\nboth\n@else\nonly a\n@end\n@end\n";
+    assert_eq!(render("t", nested, &variables(&[("b", "1")])).unwrap(), "");
+    assert_eq!(render("t", nested, &variables(&[("a", "1")])).unwrap(), "only a\n");
+
+    let err = render("env.txt", template, &variables(&[])).unwrap_err();
+    assert!(err.to_string().contains("variable `slot` is not set"), "{err}");
+    assert!(check("t", "@if a\n").is_err());
+    assert!(check("t", "@end\n").is_err());
+    assert!(check("t", "@if a\n@else\n@else\n@end\n").is_err());
+    assert!(check("t", "@endif\n").is_err());
+    assert!(check("t", "@if a b\n@end\n").is_err());
+  }
+}
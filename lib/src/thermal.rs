@@ -0,0 +1,77 @@
+//! Cooldown policy for long eMMC writes.
+
+use std::{borrow::Cow, time::Duration};
+
+/// When to pause between eMMC write chunks to let the device cool down
+///
+/// By default a chunk whose `mmc write` takes longer than three seconds is followed by a five
+/// second pause, since slow writes are the first sign of an overheating eMMC. If the bootloader
+/// can report the SoC temperature, set `temperature_command` to pause on the reading instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThermalPolicy {
+  /// `mmc write` duration after which to pause, `None` to never pause for slow writes
+  pub slow_write_threshold: Option<Duration>,
+  /// How long to pause after a slow write
+  pub slow_write_cooldown: Duration,
+  /// How long to wait before retrying a failed write
+  pub error_cooldown: Duration,
+  /// bulkcmd that prints the SoC temperature in °C; polled after every chunk when set
+  pub temperature_command: Option<Cow<'static, str>>,
+  /// Temperature in °C above which writing pauses
+  pub pause_above: f64,
+  /// Temperature in °C below which writing resumes
+  pub resume_below: f64,
+  /// How often to read the temperature while paused
+  pub poll_interval: Duration,
+  /// Longest a single pause may last before writing resumes regardless
+  pub max_pause: Duration,
+}
+
+impl ThermalPolicy {
+  /// The default policy: pause five seconds after any write slower than three seconds
+  pub const fn new() -> Self {
+    Self {
+      slow_write_threshold: Some(Duration::from_secs(3)),
+      slow_write_cooldown: Duration::from_secs(5),
+      error_cooldown: Duration::from_secs(5),
+      temperature_command: None,
+      pause_above: 85.0,
+      resume_below: 70.0,
+      poll_interval: Duration::from_secs(5),
+      max_pause: Duration::from_secs(300),
+    }
+  }
+
+  /// A policy that never pauses, only waiting briefly before retrying failed writes
+  pub const fn disabled() -> Self {
+    let mut policy = Self::new();
+    policy.slow_write_threshold = None;
+    policy
+  }
+}
+
+impl Default for ThermalPolicy {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Pull a temperature out of a bulkcmd response, e.g. `success temp: 48.5C`
+pub(crate) fn parse_temperature(response: &str) -> Option<f64> {
+  response
+    .split(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+    .filter(|token| token.chars().any(|c| c.is_ascii_digit()))
+    .find_map(|token| token.parse::<f64>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_temperature() {
+    assert_eq!(parse_temperature("success temp: 48.5C"), Some(48.5));
+    assert_eq!(parse_temperature("cpu temp = 61"), Some(61.0));
+    assert_eq!(parse_temperature("success"), None);
+  }
+}
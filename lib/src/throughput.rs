@@ -0,0 +1,88 @@
+//! Measuring how fast data moves over USB between the host and a device's memory, without
+//! touching the eMMC.
+
+use std::{fmt, time::Instant};
+
+use crate::{AmlogicSoC, Error, Result};
+
+/// How long moving the same data to and from the device's memory took
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransportBench {
+  /// Bytes written and read back
+  pub bytes: usize,
+  /// Size of each bulk transfer block
+  pub block_length: usize,
+  /// Seconds the write took
+  pub write_secs: f64,
+  /// Seconds the read took
+  pub read_secs: f64,
+}
+
+impl TransportBench {
+  /// Bytes per second written to the device
+  pub fn write_rate(&self) -> f64 {
+    self.bytes as f64 / self.write_secs
+  }
+
+  /// Bytes per second read from the device
+  pub fn read_rate(&self) -> f64 {
+    self.bytes as f64 / self.read_secs
+  }
+}
+
+impl fmt::Display for TransportBench {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{} KiB in {} byte blocks: write {:.2} KB/s, read {:.2} KB/s",
+      self.bytes / 1024,
+      self.block_length,
+      self.write_rate() / 1024.0,
+      self.read_rate() / 1024.0
+    )
+  }
+}
+
+impl AmlogicSoC {
+  /// Time writing `length` bytes to the staging area in DDR and reading them back
+  ///
+  /// Only the device's memory is used, so this is safe to run on a device holding a working
+  /// install. The data read back is checked against what was written.
+  ///
+  /// # Parameters
+  /// - `length`: Bytes to move, at most the profile's `max_transfer_size`
+  /// - `block_length`: Size of each bulk transfer block, which `length` must be a multiple of
+  ///
+  /// # Returns
+  /// - `Result<TransportBench>`: The timings or an error
+  pub fn bench_transport(&self, length: usize, block_length: usize) -> Result<TransportBench> {
+    let profile = self.profile();
+    if length > profile.max_transfer_size {
+      return Err(Error::InvalidOperation(format!(
+        "can't move {} bytes at once, the most is {}",
+        length, profile.max_transfer_size
+      )));
+    }
+
+    let data = (0..length).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let start = Instant::now();
+    self.write_large_memory(profile.staging_address, &data, block_length, false)?;
+    let write_secs = start.elapsed().as_secs_f64();
+
+    let start = Instant::now();
+    let read = self.read_large_memory(profile.staging_address, length, block_length)?;
+    let read_secs = start.elapsed().as_secs_f64();
+    if read != data {
+      return Err(Error::InvalidOperation(
+        "data read back from memory doesn't match what was written".into(),
+      ));
+    }
+
+    Ok(TransportBench {
+      bytes: length,
+      block_length,
+      write_secs,
+      read_secs,
+    })
+  }
+}
@@ -0,0 +1,441 @@
+//! Simulation target: answers the burn mode protocol on behalf of a device whose eMMC is a local
+//! disk image, so configs can be flashed and inspected without hardware.
+//!
+//! The user area is the image file itself, written sparsely. The boot hwpartitions are kept next to
+//! it as `<image>.boot0` and `<image>.boot1`. DDR is simulated so staged transfers work as they do
+//! on a device, but it starts out empty on every connection. U-Boot commands other than the `mmc`
+//! and `amlmmc` reads and writes the flasher uses are accepted without doing anything.
+
+use std::{
+  collections::BTreeMap,
+  fs::{File, OpenOptions},
+  io::{Read, Seek, SeekFrom, Write},
+  path::{Path, PathBuf},
+  sync::Mutex,
+  time::Duration,
+};
+
+use flashthing_core::{
+  AMLC_AMLS_BLOCK_LENGTH, AO_SEC_SD_CFG10, REQ_BULKCMD, REQ_GET_AMLC, REQ_IDENTIFY_HOST, REQ_RD_LARGE_MEM,
+  REQ_READ_MEM, REQ_RUN_IN_ADDR, REQ_WR_LARGE_MEM, REQ_WRITE_AMLC, REQ_WRITE_MEM, SECURE_BOOT_BIT, Transport,
+};
+
+use crate::{DeviceInfo, Error, PART_SECTOR_SIZE, Result, partitions::PartitionTable, profile::DeviceProfile};
+
+const ENDPOINT_IN: u8 = 0x81;
+const ENDPOINT_OUT: u8 = 0x01;
+/// Identify response of a device running the burn mode bootloader
+const IDENTIFY: [u8; 8] = [0, 7, 0, 16, 0, 0, 0, 0];
+/// Size of the simulated DDR pages, which are allocated as they are written
+const PAGE_SIZE: usize = 64 * 1024;
+/// Amount of bootloader the simulated BL2 asks for over AMLC
+const AMLC_REQUEST_LENGTH: u32 = 0x10000;
+
+/// What the simulated device expects next on the bulk endpoints
+#[derive(Debug)]
+enum Pending {
+  Idle,
+  /// `remaining` bytes of a large memory write, to be stored at `address`
+  Write {
+    address: u32,
+    remaining: usize,
+  },
+  /// `remaining` bytes of a large memory read, starting at `address`
+  Read {
+    address: u32,
+    remaining: usize,
+  },
+  /// A response to return on the next bulk read
+  Response(Vec<u8>),
+  /// `remaining` bytes of AMLC data, acknowledged once they have all arrived
+  Amlc {
+    remaining: usize,
+  },
+}
+
+struct ImageDevice {
+  path: PathBuf,
+  partitions: PartitionTable,
+  bl2_address: u32,
+  /// Open images for the user area and the two boot hwpartitions
+  hwparts: [Option<File>; 3],
+  /// Selected hwpartition: 0 is the user area, 1 and 2 are boot0 and boot1
+  hwpart: usize,
+  ram: BTreeMap<u32, Box<[u8]>>,
+  pending: Pending,
+  /// Set once BL2 has been started, so AMLC requests are answered
+  bl2_running: bool,
+}
+
+/// A [`Transport`] backed by a disk image instead of a USB device
+pub(crate) struct ImageTransport {
+  device: Mutex<ImageDevice>,
+}
+
+impl std::fmt::Debug for ImageTransport {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let path = self.device.lock().map(|device| device.path.clone()).unwrap_or_default();
+    f.debug_struct("ImageTransport").field("path", &path).finish()
+  }
+}
+
+/// Open the disk image at `path` as a device in USB burn mode, creating it if it doesn't exist
+pub(crate) fn open(path: &Path, profile: &DeviceProfile) -> Result<(Box<dyn Transport>, DeviceInfo)> {
+  let user = OpenOptions::new()
+    .read(true)
+    .write(true)
+    .create(true)
+    .truncate(false)
+    .open(path)?;
+  tracing::info!("simulating a device with the disk image at {}", path.display());
+
+  let mut device = ImageDevice {
+    path: path.to_owned(),
+    partitions: profile.partitions.clone(),
+    bl2_address: profile.bl2_address,
+    hwparts: [Some(user), None, None],
+    hwpart: 0,
+    ram: BTreeMap::new(),
+    pending: Pending::Idle,
+    bl2_running: false,
+  };
+  // report secure boot as enforced, like retail devices
+  device.write_ram(AO_SEC_SD_CFG10, &SECURE_BOOT_BIT.to_le_bytes());
+  let info = DeviceInfo {
+    vendor_id: profile.vendor_id,
+    product_id: profile.product_id,
+    bus_number: 0,
+    address: 0,
+    serial: None,
+    interface_count: 1,
+    interface_number: 0,
+    alt_setting: 0,
+    endpoint_in: ENDPOINT_IN,
+    endpoint_out: ENDPOINT_OUT,
+    bulk: true,
+  };
+  Ok((
+    Box::new(ImageTransport {
+      device: Mutex::new(device),
+    }),
+    info,
+  ))
+}
+
+impl ImageTransport {
+  fn device(&self) -> Result<std::sync::MutexGuard<'_, ImageDevice>> {
+    self
+      .device
+      .lock()
+      .map_err(|_| Error::InvalidOperation("image target state is poisoned".into()))
+  }
+}
+
+impl Transport for ImageTransport {
+  fn write_control(
+    &self,
+    _request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    data: &[u8],
+    _timeout: Duration,
+  ) -> flashthing_core::Result<usize> {
+    let mut device = self.device()?;
+    let address = (value as u32) << 16 | index as u32;
+    match request {
+      REQ_WRITE_MEM => device.write_ram(address, data),
+      REQ_WR_LARGE_MEM | REQ_RD_LARGE_MEM => {
+        let header = |range: std::ops::Range<usize>| -> Result<u32> { Ok(u32::from_le_bytes(data[range].try_into()?)) };
+        let (address, length) = (header(0..4)?, header(4..8)? as usize);
+        device.pending = match request {
+          REQ_WR_LARGE_MEM => Pending::Write {
+            address,
+            remaining: length,
+          },
+          _ => Pending::Read {
+            address,
+            remaining: length,
+          },
+        };
+      }
+      REQ_RUN_IN_ADDR => {
+        tracing::debug!("image target: running code at {:#X}", address);
+        device.bl2_running = address == device.bl2_address;
+      }
+      REQ_BULKCMD => {
+        let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+        let command = String::from_utf8_lossy(&data[..end]).into_owned();
+        let response = match device.bulkcmd(&command) {
+          Ok(()) => "success".to_owned(),
+          Err(e) => {
+            tracing::debug!("image target: {:?} failed: {}", command, e);
+            format!("failed: {e}")
+          }
+        };
+        device.pending = Pending::Response(response.into_bytes());
+      }
+      REQ_GET_AMLC if device.bl2_running => {
+        let mut request = vec![0u8; AMLC_AMLS_BLOCK_LENGTH];
+        request[0..4].copy_from_slice(b"AMLC");
+        request[8..12].copy_from_slice(&AMLC_REQUEST_LENGTH.to_le_bytes());
+        device.pending = Pending::Response(request);
+      }
+      REQ_WRITE_AMLC if device.bl2_running => {
+        device.pending = Pending::Amlc {
+          remaining: index as usize + 1,
+        };
+      }
+      request => {
+        return Err(
+          Error::InvalidOperation(format!(
+            "image target does not support control request {request:#04x} here"
+          ))
+          .into(),
+        );
+      }
+    }
+    Ok(data.len())
+  }
+
+  fn read_control(
+    &self,
+    _request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    buf: &mut [u8],
+    _timeout: Duration,
+  ) -> flashthing_core::Result<usize> {
+    let device = self.device()?;
+    match request {
+      REQ_IDENTIFY_HOST => {
+        let length = buf.len().min(IDENTIFY.len());
+        buf[..length].copy_from_slice(&IDENTIFY[..length]);
+        Ok(length)
+      }
+      REQ_READ_MEM => {
+        device.read_ram((value as u32) << 16 | index as u32, buf);
+        Ok(buf.len())
+      }
+      request => {
+        Err(Error::InvalidOperation(format!("image target does not support control request {request:#04x}")).into())
+      }
+    }
+  }
+
+  fn write_bulk(&self, _endpoint: u8, data: &[u8], _timeout: Duration) -> flashthing_core::Result<usize> {
+    let mut device = self.device()?;
+    match device.pending {
+      Pending::Write { address, remaining } => {
+        let length = data.len().min(remaining);
+        device.write_ram(address, &data[..length]);
+        device.pending = match remaining - length {
+          0 => Pending::Idle,
+          remaining => Pending::Write {
+            address: address + length as u32,
+            remaining,
+          },
+        };
+      }
+      Pending::Amlc { remaining } => {
+        device.pending = match remaining.saturating_sub(data.len()) {
+          0 => Pending::Response(b"OKAY".to_vec()),
+          remaining => Pending::Amlc { remaining },
+        };
+      }
+      // acknowledgements of AMLC requests need no answer
+      _ => {}
+    }
+    Ok(data.len())
+  }
+
+  fn read_bulk(&self, _endpoint: u8, buf: &mut [u8], _timeout: Duration) -> flashthing_core::Result<usize> {
+    let mut device = self.device()?;
+    match std::mem::replace(&mut device.pending, Pending::Idle) {
+      Pending::Read { address, remaining } => {
+        let length = buf.len().min(remaining);
+        device.read_ram(address, &mut buf[..length]);
+        if remaining > length {
+          device.pending = Pending::Read {
+            address: address + length as u32,
+            remaining: remaining - length,
+          };
+        }
+        Ok(length)
+      }
+      Pending::Response(response) => {
+        let length = buf.len().min(response.len());
+        buf[..length].copy_from_slice(&response[..length]);
+        Ok(length)
+      }
+      pending => {
+        device.pending = pending;
+        Err(Error::InvalidOperation("image target has nothing to send".into()).into())
+      }
+    }
+  }
+}
+
+impl ImageDevice {
+  fn write_ram(&mut self, address: u32, data: &[u8]) {
+    let mut written = 0;
+    while written < data.len() {
+      let at = address as usize + written;
+      let (page, offset) = ((at / PAGE_SIZE * PAGE_SIZE) as u32, at % PAGE_SIZE);
+      let length = (PAGE_SIZE - offset).min(data.len() - written);
+      let page = self
+        .ram
+        .entry(page)
+        .or_insert_with(|| vec![0; PAGE_SIZE].into_boxed_slice());
+      page[offset..offset + length].copy_from_slice(&data[written..written + length]);
+      written += length;
+    }
+  }
+
+  fn read_ram(&self, address: u32, buf: &mut [u8]) {
+    let mut read = 0;
+    while read < buf.len() {
+      let at = address as usize + read;
+      let (page, offset) = ((at / PAGE_SIZE * PAGE_SIZE) as u32, at % PAGE_SIZE);
+      let length = (PAGE_SIZE - offset).min(buf.len() - read);
+      match self.ram.get(&page) {
+        Some(page) => buf[read..read + length].copy_from_slice(&page[offset..offset + length]),
+        None => buf[read..read + length].fill(0),
+      }
+      read += length;
+    }
+  }
+
+  /// Run a U-Boot command, as far as it affects the eMMC
+  fn bulkcmd(&mut self, command: &str) -> Result<()> {
+    let words = command.split_whitespace().collect::<Vec<_>>();
+    match words.as_slice() {
+      ["mmc", "dev", _] => self.hwpart = 0,
+      ["mmc", "dev", _, hwpart] => {
+        self.hwpart = match parse_hex(hwpart)? {
+          hwpart @ 0..=2 => hwpart as usize,
+          hwpart => return Err(Error::InvalidOperation(format!("no hwpartition {hwpart}"))),
+        }
+      }
+      ["amlmmc", "switch", _, target] => {
+        self.hwpart = match *target {
+          "user" => 0,
+          "boot0" => 1,
+          "boot1" => 2,
+          target => return Err(Error::InvalidOperation(format!("no hwpartition {target}"))),
+        }
+      }
+      ["mmc", direction @ ("read" | "write"), address, lba, count] => {
+        let offset = parse_hex(lba)? * PART_SECTOR_SIZE as u64;
+        let length = parse_hex(count)? as usize * PART_SECTOR_SIZE;
+        self.transfer(direction == &"write", parse_hex(address)? as u32, offset, length)?;
+      }
+      ["amlmmc", direction @ ("read" | "write"), name, address, offset, size] => {
+        let part = self.partitions.resolve(name)?.clone();
+        if part.name == "reserved" {
+          return Err(Error::InvalidOperation("amlmmc refuses the reserved partition".into()));
+        }
+        let (offset, length) = (parse_hex(offset)?, parse_hex(size)? as usize);
+        let part_size = (part.end() - part.offset) * PART_SECTOR_SIZE;
+        if offset as usize + length > part_size {
+          return Err(Error::InvalidOperation(format!(
+            "{} is only {} bytes",
+            part.name, part_size
+          )));
+        }
+        let disk_offset = part.offset_bytes() as u64 + offset;
+        self.transfer(direction == &"write", parse_hex(address)? as u32, disk_offset, length)?;
+      }
+      _ => tracing::debug!("image target: accepting {:?} without simulating it", command),
+    }
+    Ok(())
+  }
+
+  /// Copy `length` bytes between DDR at `address` and the selected hwpartition at `offset`
+  fn transfer(&mut self, write: bool, address: u32, offset: u64, length: usize) -> Result<()> {
+    let mut buf = vec![0; length];
+    if write {
+      self.read_ram(address, &mut buf);
+      let file = self.hwpart_file()?;
+      file.seek(SeekFrom::Start(offset))?;
+      file.write_all(&buf)?;
+      return Ok(());
+    }
+
+    // past the end of the image reads back as zeros, like an erased eMMC
+    let file = self.hwpart_file()?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut read = 0;
+    while read < length {
+      match file.read(&mut buf[read..])? {
+        0 => break,
+        n => read += n,
+      }
+    }
+    self.write_ram(address, &buf);
+    Ok(())
+  }
+
+  fn hwpart_file(&mut self) -> Result<&mut File> {
+    if self.hwparts[self.hwpart].is_none() {
+      let mut path = self.path.clone().into_os_string();
+      path.push(format!(".boot{}", self.hwpart - 1));
+      let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+      self.hwparts[self.hwpart] = Some(file);
+    }
+    Ok(self.hwparts[self.hwpart].as_mut().expect("hwpartition was just opened"))
+  }
+}
+
+/// Parse a number the way U-Boot does, as hex with or without a `0x` prefix
+fn parse_hex(text: &str) -> Result<u64> {
+  let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+  u64::from_str_radix(digits, 16).map_err(|_| Error::InvalidOperation(format!("{text:?} is not a hex number")))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{AmlogicSoC, DeviceTarget};
+
+  #[test]
+  fn test_image_target() {
+    let dir = std::env::temp_dir().join(format!("flashthing-image-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let image = dir.join("disk.img");
+    let target = DeviceTarget::Image(image.clone());
+    let aml = AmlogicSoC::init_with_target(None, DeviceProfile::default(), target.clone()).unwrap();
+
+    let data = (0..64 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let logo = aml.profile().partitions.get("logo").unwrap().clone();
+    aml
+      .write_large_memory_to_disk(
+        logo.offset_bytes() as u32,
+        &mut data.as_slice(),
+        data.len(),
+        4096,
+        true,
+        false,
+        |_| {},
+      )
+      .unwrap();
+    aml.reset(crate::config::ResetMode::Soft).unwrap();
+
+    // the image outlives the connection, and partition reads see the raw write
+    let aml = AmlogicSoC::init_with_target(None, DeviceProfile::default(), target).unwrap();
+    assert_eq!(aml.read_partition_chunk("logo", 0, data.len()).unwrap(), data);
+    assert!(aml.bulkcmd("amlmmc read reserved 0x1080000 0 0x200").is_err());
+    let on_disk = std::fs::read(&image).unwrap();
+    assert_eq!(&on_disk[logo.offset_bytes()..logo.offset_bytes() + data.len()], data);
+
+    aml.write_boot_partition(1, &data).unwrap();
+    assert_eq!(std::fs::read(dir.join("disk.img.boot0")).unwrap(), data);
+    let _ = std::fs::remove_dir_all(&dir);
+  }
+}
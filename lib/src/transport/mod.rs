@@ -0,0 +1,114 @@
+//! What a connection talks to: a USB device, through the backends in `flashthing-core`, a disk
+//! image standing in for one (see [`DeviceTarget::Image`]), or a [`Connector`] of your own.
+
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+
+use flashthing_core::{Transport, UsbLogLevel};
+
+use crate::{DeviceInfo, DeviceMode, Error, Result, profile::DeviceProfile};
+
+mod image;
+
+/// Finds and opens devices for a [`DeviceTarget::Custom`] target
+pub trait Connector: Send + Sync + std::fmt::Debug {
+  /// Look for the device described by `profile` and report the mode it is in
+  fn find_device(&self, profile: &DeviceProfile) -> DeviceMode;
+
+  /// Open the device described by `profile`, returning its transport and details
+  fn open(&self, profile: &DeviceProfile) -> Result<(Box<dyn Transport>, DeviceInfo)>;
+}
+
+/// What a connection talks to
+#[derive(Debug, Clone, Default)]
+pub enum DeviceTarget {
+  /// A device attached over USB
+  #[default]
+  Usb,
+  /// A simulated device whose eMMC user area is the disk image at this path
+  ///
+  /// The image is created if it doesn't exist and grows as it is written. The boot hwpartitions
+  /// are kept next to it as `<path>.boot0` and `<path>.boot1`. U-Boot commands other than eMMC
+  /// reads and writes (environment changes, for one) are accepted without being applied.
+  Image(PathBuf),
+  /// A device reached through a [`Connector`] of your own
+  Custom(Arc<dyn Connector>),
+}
+
+impl PartialEq for DeviceTarget {
+  fn eq(&self, other: &Self) -> bool {
+    match (self, other) {
+      (Self::Usb, Self::Usb) => true,
+      (Self::Image(a), Self::Image(b)) => a == b,
+      (Self::Custom(a), Self::Custom(b)) => Arc::ptr_eq(a, b),
+      _ => false,
+    }
+  }
+}
+
+impl Eq for DeviceTarget {}
+
+impl FromStr for DeviceTarget {
+  type Err = Error;
+
+  /// Parse `usb` or `image:<path>`
+  fn from_str(s: &str) -> Result<Self> {
+    match s.split_once(':') {
+      None if s == "usb" => Ok(Self::Usb),
+      Some(("image", path)) if !path.is_empty() => Ok(Self::Image(path.into())),
+      _ => Err(Error::InvalidOperation(format!(
+        "unknown target {s:?}, expected \"usb\" or \"image:<path>\""
+      ))),
+    }
+  }
+}
+
+impl std::fmt::Display for DeviceTarget {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Usb => write!(f, "usb"),
+      Self::Image(path) => write!(f, "image:{}", path.display()),
+      Self::Custom(connector) => write!(f, "{connector:?}"),
+    }
+  }
+}
+
+/// Look for the device described by `profile` and report the mode it is in
+///
+/// A disk image is always ready, so it reports USB burn mode.
+pub(crate) fn find_device(target: &DeviceTarget, profile: &DeviceProfile) -> DeviceMode {
+  match target {
+    DeviceTarget::Usb => flashthing_core::find_usb_device(&profile.usb_ids()),
+    DeviceTarget::Image(_) => DeviceMode::UsbBurn,
+    DeviceTarget::Custom(connector) => connector.find_device(profile),
+  }
+}
+
+/// Open the device described by `profile` and claim the interface used for burning
+pub(crate) fn open(target: &DeviceTarget, profile: &DeviceProfile) -> Result<(Box<dyn Transport>, DeviceInfo)> {
+  match target {
+    DeviceTarget::Usb => Ok(flashthing_core::open_usb(&profile.usb_ids())?),
+    DeviceTarget::Image(path) => image::open(path, profile),
+    DeviceTarget::Custom(connector) => connector.open(profile),
+  }
+}
+
+/// Set the log level of the USB backend, where it has one
+pub(crate) fn set_log_level(level: UsbLogLevel) -> Result<()> {
+  Ok(flashthing_core::set_usb_log_level(level)?)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_device_target() {
+    assert_eq!("usb".parse::<DeviceTarget>().unwrap(), DeviceTarget::Usb);
+    let target = "image:out/disk.img".parse::<DeviceTarget>().unwrap();
+    assert_eq!(target, DeviceTarget::Image("out/disk.img".into()));
+    assert_eq!(target.to_string(), "image:out/disk.img");
+    for bad in ["image:", "image", "disk.img", "usb:1"] {
+      assert!(bad.parse::<DeviceTarget>().is_err(), "{bad}");
+    }
+  }
+}
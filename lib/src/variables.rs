@@ -0,0 +1,466 @@
+//! Variables set while flashing, like the response of a `bulkcmdStat`, and substituting them into
+//! the steps that refer to them as `${name}`.
+
+use std::collections::{HashMap, HashSet};
+
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{
+  Error, Result,
+  config::{FlashStep, parse_number},
+};
+
+/// Variables describing the host and the flash, set when flashing starts
+pub const HOST_FACTS: [&str; 5] = ["host_os", "host_arch", "flashthing_version", "flash_date", "flash_time"];
+
+/// Prefix of the variables holding environment variables allowed with [`crate::FlasherBuilder::env_variables`]
+pub const ENV_PREFIX: &str = "env_";
+
+/// Stands in for strings with variables in a step that does not load with its references left in place
+const PLACEHOLDER: &str = "0";
+
+/// A step that refers to variables, kept as written so it can be loaded again once they are set
+#[derive(Debug, Clone)]
+pub(crate) struct DeferredStep {
+  step: Value,
+  variables: Vec<String>,
+}
+
+impl DeferredStep {
+  /// Names of the variables the step refers to
+  pub(crate) fn variables(&self) -> &[String] {
+    &self.variables
+  }
+
+  /// Load the step with the current value of every variable it refers to
+  ///
+  /// # Returns
+  /// - `Result<FlashStep>`: The step, or an error if a variable is not set or its value does not fit
+  pub(crate) fn resolve(&self, variables: &HashMap<String, String>) -> Result<FlashStep> {
+    let mut replacements = Vec::new();
+    for name in &self.variables {
+      let value = variables.get(name).ok_or_else(|| match name.strip_prefix(ENV_PREFIX) {
+        Some(env) => Error::InvalidOperation(format!(
+          "variable `{}` is not set, {} has to be set and allowed with `env_variables`",
+          name, env
+        )),
+        None => Error::InvalidOperation(format!("variable `{}` is not set", name)),
+      })?;
+      replacements.push((reference(name), value.clone()));
+    }
+
+    let mut step = self.step.clone();
+    substitute(&mut step, &replacements);
+    Ok(serde_json::from_value(step)?)
+  }
+}
+
+/// The value of every variable in [`HOST_FACTS`], as of now
+pub(crate) fn host_facts() -> Vec<(String, String)> {
+  let now = chrono::Utc::now();
+  let values = [
+    std::env::consts::OS.to_owned(),
+    std::env::consts::ARCH.to_owned(),
+    env!("CARGO_PKG_VERSION").to_owned(),
+    now.format("%Y-%m-%d").to_string(),
+    now.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+  ];
+  HOST_FACTS.iter().map(|name| name.to_string()).zip(values).collect()
+}
+
+/// The allowed environment variables that are set, as `env_` variables
+pub(crate) fn environment(allowed: &[String]) -> Vec<(String, String)> {
+  allowed
+    .iter()
+    .filter_map(|name| Some((format!("{}{}", ENV_PREFIX, name), std::env::var(name).ok()?)))
+    .collect()
+}
+
+/// Compile the `capture` pattern of a `bulkcmdStat` step, which has to name its groups
+pub(crate) fn capture_regex(pattern: &str) -> Result<Regex> {
+  let regex =
+    Regex::new(pattern).map_err(|e| Error::InvalidOperation(format!("capture `{}` is invalid: {}", pattern, e)))?;
+  if regex.capture_names().flatten().next().is_none() {
+    return Err(Error::InvalidOperation(format!(
+      "capture `{}` has no named groups, name them like `(?P<offset>0x[0-9a-f]+)`",
+      pattern
+    )));
+  }
+  Ok(regex)
+}
+
+/// Replace `${name}` in `text` with the value of every variable that is set, leaving other names alone
+pub(crate) fn substitute_text(text: &str, variables: &HashMap<String, String>) -> String {
+  let mut text = text.to_owned();
+  for (name, value) in variables {
+    let reference = reference(name);
+    if text.contains(reference.as_str()) {
+      text = text.replace(reference.as_str(), value);
+    }
+  }
+  text
+}
+
+/// Replace every pattern in every string of `value` with its replacement
+pub(crate) fn substitute(value: &mut Value, replacements: &[(String, String)]) {
+  match value {
+    Value::String(text) => {
+      for (pattern, replacement) in replacements {
+        if text.contains(pattern.as_str()) {
+          *text = text.replace(pattern.as_str(), replacement);
+        }
+      }
+    }
+    Value::Array(items) => items.iter_mut().for_each(|item| substitute(item, replacements)),
+    Value::Object(fields) => fields.values_mut().for_each(|field| substitute(field, replacements)),
+    _ => {}
+  }
+}
+
+/// Evaluate a number written in decimal or `0x` hex, or arithmetic on such numbers
+///
+/// Supports `+`, `-`, `*`, `/` and `%` with the usual precedence, and parentheses. Results that
+/// would be negative or overflow are errors rather than wrapping around.
+///
+/// # Returns
+/// - `Result<u64, String>`: The value, or why the text isn't a number or valid expression
+pub(crate) fn evaluate(text: &str) -> std::result::Result<u64, String> {
+  if let Some(number) = parse_number(text) {
+    return Ok(number);
+  }
+
+  let mut tokens = Vec::new();
+  let mut chars = text.char_indices().peekable();
+  while let Some((start, c)) = chars.next() {
+    match c {
+      c if c.is_whitespace() => {}
+      '+' | '-' | '*' | '/' | '%' | '(' | ')' => tokens.push(Token::Op(c)),
+      c if c.is_ascii_alphanumeric() => {
+        let mut end = start + c.len_utf8();
+        while let Some(&(at, next)) = chars.peek()
+          && (next.is_ascii_alphanumeric() || next == '_')
+        {
+          end = at + next.len_utf8();
+          chars.next();
+        }
+        let number = &text[start..end];
+        tokens.push(Token::Number(parse_number(number).ok_or_else(|| not_a_number(text))?));
+      }
+      _ => return Err(not_a_number(text)),
+    }
+  }
+  if !tokens.iter().any(|token| matches!(token, Token::Op(_))) {
+    return Err(not_a_number(text));
+  }
+
+  let mut parser = Parser {
+    text,
+    tokens: &tokens,
+    at: 0,
+  };
+  let value = parser.sum()?;
+  match parser.tokens.get(parser.at) {
+    None => Ok(value),
+    Some(_) => Err(format!("\"{}\" is not a valid expression", text)),
+  }
+}
+
+fn not_a_number(text: &str) -> String {
+  format!("\"{}\" is not a decimal or 0x hex number, or arithmetic on them", text)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Token {
+  Number(u64),
+  Op(char),
+}
+
+/// Recursive descent over the tokens of an expression
+struct Parser<'a> {
+  text: &'a str,
+  tokens: &'a [Token],
+  at: usize,
+}
+
+impl Parser<'_> {
+  fn next_op(&mut self, ops: &[char]) -> Option<char> {
+    match self.tokens.get(self.at) {
+      Some(Token::Op(op)) if ops.contains(op) => {
+        self.at += 1;
+        Some(*op)
+      }
+      _ => None,
+    }
+  }
+
+  fn sum(&mut self) -> std::result::Result<u64, String> {
+    let mut value = self.product()?;
+    while let Some(op) = self.next_op(&['+', '-']) {
+      let rhs = self.product()?;
+      value = match op {
+        '+' => value.checked_add(rhs),
+        _ => value.checked_sub(rhs),
+      }
+      .ok_or_else(|| format!("\"{}\" is out of range", self.text))?;
+    }
+    Ok(value)
+  }
+
+  fn product(&mut self) -> std::result::Result<u64, String> {
+    let mut value = self.operand()?;
+    while let Some(op) = self.next_op(&['*', '/', '%']) {
+      let rhs = self.operand()?;
+      if op != '*' && rhs == 0 {
+        return Err(format!("\"{}\" divides by zero", self.text));
+      }
+      value = match op {
+        '*' => value.checked_mul(rhs),
+        '/' => Some(value / rhs),
+        _ => Some(value % rhs),
+      }
+      .ok_or_else(|| format!("\"{}\" is out of range", self.text))?;
+    }
+    Ok(value)
+  }
+
+  fn operand(&mut self) -> std::result::Result<u64, String> {
+    let token = self.tokens.get(self.at).copied();
+    self.at += 1;
+    match token {
+      Some(Token::Number(number)) => Ok(number),
+      Some(Token::Op('(')) => {
+        let value = self.sum()?;
+        match self.next_op(&[')']) {
+          Some(_) => Ok(value),
+          None => Err(format!("\"{}\" has an unclosed parenthesis", self.text)),
+        }
+      }
+      _ => Err(format!("\"{}\" is not a valid expression", self.text)),
+    }
+  }
+}
+
+/// Set aside every step of a meta.json that refers to a variable the config sets
+///
+/// Steps whose references would not load, like a number written as `"${offset}"`, get a placeholder
+/// in their place until they run.
+///
+/// # Returns
+/// - `Result<HashMap<usize, DeferredStep>>`: The steps set aside, by their position counting nested
+///   steps in the order they appear
+pub(crate) fn defer_steps(config: &mut Value) -> Result<HashMap<usize, DeferredStep>> {
+  let mut declared = HOST_FACTS.iter().map(|name| name.to_string()).collect::<HashSet<_>>();
+  if let Some(Value::Object(variables)) = config.get("variables") {
+    declared.extend(variables.keys().cloned());
+  }
+  if let Some(Value::Array(steps)) = config.get("steps") {
+    declare(steps, &mut declared)?;
+  }
+
+  let mut deferred = HashMap::new();
+  if let Some(Value::Array(steps)) = config.get_mut("steps")
+    && !declared.is_empty()
+  {
+    defer(steps, &declared, &mut 0, &mut deferred);
+  }
+  Ok(deferred)
+}
+
+fn declare(steps: &[Value], declared: &mut HashSet<String>) -> Result<()> {
+  for step in steps {
+    if let Some(variable) = step.get("variable").and_then(Value::as_str) {
+      declared.insert(variable.to_owned());
+    }
+    if let Some(pattern) = step.get("capture").and_then(Value::as_str) {
+      declared.extend(capture_regex(pattern)?.capture_names().flatten().map(str::to_owned));
+    }
+    if step.get("type").and_then(Value::as_str) == Some("provision")
+      && let Some(Value::Array(variables)) = step.pointer("/value/variables")
+    {
+      declared.extend(variables.iter().filter_map(Value::as_str).map(str::to_owned));
+    }
+    // which environment variables are allowed is up to the caller, so any of them may be set
+    env_references(step, declared);
+    if let Some(Value::Array(nested)) = step.pointer("/value/steps") {
+      declare(nested, declared)?;
+    }
+  }
+  Ok(())
+}
+
+fn defer(
+  steps: &mut [Value],
+  declared: &HashSet<String>,
+  index: &mut usize,
+  deferred: &mut HashMap<usize, DeferredStep>,
+) {
+  for step in steps {
+    let position = *index;
+    *index += 1;
+    // a repeat's nested steps are set aside on their own
+    if step.get("type").and_then(Value::as_str) == Some("repeat") {
+      if let Some(Value::Array(nested)) = step.pointer_mut("/value/steps") {
+        defer(nested, declared, index, deferred);
+      }
+      continue;
+    }
+    // a provision step substitutes variables itself, once it has the ones it provisions
+    if step.get("type").and_then(Value::as_str) == Some("provision") {
+      continue;
+    }
+
+    let mut variables = declared
+      .iter()
+      .filter(|name| refers_to(step, &reference(name)))
+      .cloned()
+      .collect::<Vec<_>>();
+    if variables.is_empty() {
+      continue;
+    }
+    variables.sort();
+
+    let written = step.clone();
+    if serde_json::from_value::<FlashStep>(written.clone()).is_err() {
+      let references = variables.iter().map(|name| reference(name)).collect::<Vec<_>>();
+      stand_in(step, &references);
+    }
+    deferred.insert(
+      position,
+      DeferredStep {
+        step: written,
+        variables,
+      },
+    );
+  }
+}
+
+/// Replace every string that holds one of `references` with the placeholder, so an expression
+/// like `"${offset} - 0x200"` can't fail to load for the placeholder's sake
+fn stand_in(value: &mut Value, references: &[String]) {
+  match value {
+    Value::String(text) if references.iter().any(|reference| text.contains(reference.as_str())) => {
+      *text = PLACEHOLDER.to_owned();
+    }
+    Value::Array(items) => items.iter_mut().for_each(|item| stand_in(item, references)),
+    Value::Object(fields) => (fields.iter_mut())
+      .filter(|(key, _)| *key != "id")
+      .for_each(|(_, field)| stand_in(field, references)),
+    _ => {}
+  }
+}
+
+fn env_references(value: &Value, declared: &mut HashSet<String>) {
+  match value {
+    Value::String(text) => {
+      for (_, after) in text.match_indices("${").map(|(at, _)| text.split_at(at + 2)) {
+        if let Some((name, _)) = after.split_once('}')
+          && name.starts_with(ENV_PREFIX)
+          && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+          declared.insert(name.to_owned());
+        }
+      }
+    }
+    Value::Array(items) => items.iter().for_each(|item| env_references(item, declared)),
+    Value::Object(fields) => fields.values().for_each(|field| env_references(field, declared)),
+    _ => {}
+  }
+}
+
+fn reference(name: &str) -> String {
+  format!("${{{name}}}")
+}
+
+fn refers_to(value: &Value, reference: &str) -> bool {
+  match value {
+    Value::String(text) => text.contains(reference),
+    Value::Array(items) => items.iter().any(|item| refers_to(item, reference)),
+    Value::Object(fields) => fields
+      .iter()
+      .any(|(key, field)| key != "id" && refers_to(field, reference)),
+    _ => false,
+  }
+}
+
+impl FlashStep {
+  /// Whether running this step sets the variable `name`
+  pub(crate) fn sets_variable(&self, name: &str) -> bool {
+    match self {
+      FlashStep::Identify { variable } => variable.as_deref() == Some(name),
+      FlashStep::BulkcmdStat { variable, capture, .. } => {
+        variable.as_deref() == Some(name)
+          || capture
+            .as_deref()
+            .and_then(|pattern| capture_regex(pattern).ok())
+            .is_some_and(|regex| regex.capture_names().flatten().any(|group| group == name))
+      }
+      FlashStep::Provision { value } => value.variables.iter().any(|variable| variable == name),
+      _ => false,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_evaluate() {
+    assert_eq!(evaluate("0x9C00000"), Ok(0x9c00000));
+    assert_eq!(evaluate("0x9C00000 + 0x200"), Ok(0x9c00200));
+    assert_eq!(evaluate("2 + 3 * 4"), Ok(14));
+    assert_eq!(evaluate("(2 + 3) * 4"), Ok(20));
+    assert_eq!(evaluate("0x1_0000 / 512 - 1"), Ok(127));
+    assert_eq!(evaluate(" 10 % 4 "), Ok(2));
+
+    assert!(evaluate("1 - 2").is_err());
+    assert!(evaluate("0xffffffffffffffff + 1").is_err());
+    assert!(evaluate("1 / 0").is_err());
+    assert!(evaluate("(1 + 2").is_err());
+    assert!(evaluate("1 +").is_err());
+    assert!(evaluate("1 2").is_err());
+    assert!(evaluate("sectors").is_err());
+    assert!(evaluate("${offset} + 1").is_err());
+  }
+
+  #[test]
+  fn test_defer_steps() {
+    let mut config = serde_json::json!({
+      "variables": { "count": 1 },
+      "steps": [
+        { "type": "bulkcmdStat", "value": "printenv part", "capture": "part=(?P<offset>0x[0-9a-f]+) (?P<size>\\d+)" },
+        { "type": "bulkcmd", "value": "mmc read 0x1080000 ${offset} ${filesize}" },
+        { "type": "repeat", "value": { "count": 2, "steps": [
+          { "type": "writeLargeMemory", "value": { "address": "${offset} - 0x200", "data": [0], "blockLength": 4096 } },
+          { "type": "log", "value": "done" }
+        ] } }
+      ]
+    });
+    let deferred = defer_steps(&mut config).unwrap();
+    assert_eq!(deferred.keys().copied().collect::<HashSet<_>>(), HashSet::from([1, 3]));
+
+    // references that load as they are stay visible, the rest get a placeholder
+    assert_eq!(config["steps"][1]["value"], "mmc read 0x1080000 ${offset} ${filesize}");
+    assert_eq!(config["steps"][2]["value"]["steps"][0]["value"]["address"], PLACEHOLDER);
+
+    let variables = HashMap::from([("offset".to_owned(), "0x2700000".to_owned())]);
+    let FlashStep::Bulkcmd { value } = deferred[&1].resolve(&variables).unwrap() else {
+      panic!("not a bulkcmd step");
+    };
+    // `${filesize}` isn't a variable of the config, so it is left for U-Boot
+    assert_eq!(value, "mmc read 0x1080000 0x2700000 ${filesize}");
+    let FlashStep::WriteLargeMemory { value } = deferred[&3].resolve(&variables).unwrap() else {
+      panic!("not a writeLargeMemory step");
+    };
+    assert_eq!(value.address.unwrap().get(), 0x26ffe00);
+
+    assert!(deferred[&3].resolve(&HashMap::new()).is_err());
+    let bad = HashMap::from([("offset".to_owned(), "somewhere".to_owned())]);
+    assert!(deferred[&3].resolve(&bad).is_err());
+
+    let mut config =
+      serde_json::json!({ "steps": [{ "type": "bulkcmdStat", "value": "version", "capture": "v(\\d+)" }] });
+    assert!(defer_steps(&mut config).is_err());
+  }
+}
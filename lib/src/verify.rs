@@ -0,0 +1,169 @@
+//! Checking partitions on a device against the hashes a package expects them to have.
+
+use std::{fmt, fs, io::Read, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  AmlogicSoC, Error, Result,
+  archive::{open_archive, package_root},
+  flash::FlashProgress,
+  paths::{PathPolicy, resolve_in_archive},
+};
+
+/// Name of the hash manifest at the root of a package
+pub const HASH_MANIFEST: &str = "hashes.json";
+
+/// Hash function used for partition digests
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum HashAlgorithm {
+  /// SHA-256
+  #[default]
+  Sha256,
+  /// SHA-512
+  Sha512,
+}
+
+impl fmt::Display for HashAlgorithm {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Sha256 => write!(f, "sha256"),
+      Self::Sha512 => write!(f, "sha512"),
+    }
+  }
+}
+
+/// Expected partition hashes, shipped in a package as `hashes.json`
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HashManifest {
+  /// Hash function the digests were made with (defaults to sha256)
+  #[serde(default)]
+  pub algorithm: HashAlgorithm,
+  /// Partitions to check
+  pub partitions: Vec<ExpectedHash>,
+}
+
+/// Expected digest of one partition
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpectedHash {
+  /// Partition name
+  pub name: String,
+  /// Lowercase hex digest
+  pub digest: String,
+  /// Bytes hashed from the start of the partition, for images smaller than their partition
+  pub length: Option<usize>,
+}
+
+/// Outcome of checking one partition
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PartitionCheck {
+  /// Partition name
+  pub name: String,
+  /// Digest the manifest expects
+  pub expected: String,
+  /// Digest read from the device, `None` if the partition could not be read
+  pub actual: Option<String>,
+  /// Why the partition could not be read
+  pub error: Option<String>,
+}
+
+impl PartitionCheck {
+  /// Whether the partition holds what the manifest expects
+  pub fn ok(&self) -> bool {
+    self.actual.as_deref() == Some(self.expected.as_str())
+  }
+}
+
+impl HashManifest {
+  /// Load the manifest from a package directory, a package zip, or a manifest file
+  pub fn load(path: &Path) -> Result<Self> {
+    let json = if path.is_dir() {
+      let manifest = path.join(HASH_MANIFEST);
+      if !manifest.is_file() {
+        return Err(Error::FileMissing(manifest));
+      }
+      fs::read_to_string(manifest)?
+    } else if path.extension().is_some_and(|extension| extension == "zip") {
+      let mut zip = open_archive(path)?;
+      let root = package_root(&zip, HASH_MANIFEST)?.ok_or(zip::result::ZipError::FileNotFound)?;
+      let mut json = String::new();
+      zip
+        .by_index(resolve_in_archive(&zip, &root, HASH_MANIFEST, PathPolicy::Strict)?)?
+        .read_to_string(&mut json)?;
+      json
+    } else {
+      fs::read_to_string(path)?
+    };
+
+    Ok(serde_json::from_str(&json)?)
+  }
+
+  /// Hash every listed partition on the device and compare it with the manifest
+  ///
+  /// A partition that can't be read is reported as a failed check rather than an error, so one
+  /// bad partition doesn't hide the state of the others.
+  ///
+  /// # Parameters
+  /// - `aml`: The connected device
+  /// - `progress_callback`: Function to call with progress updates while hashing
+  ///
+  /// # Returns
+  /// - `Result<Vec<PartitionCheck>>`: One check per listed partition
+  #[cfg_attr(feature = "instrument", tracing::instrument(level = "trace", skip_all))]
+  pub fn verify<F: Fn(FlashProgress)>(&self, aml: &AmlogicSoC, progress_callback: F) -> Result<Vec<PartitionCheck>> {
+    let mut checks = Vec::with_capacity(self.partitions.len());
+    for expected in &self.partitions {
+      tracing::info!("hashing {}", expected.name);
+      let result = aml.hash_partition(&expected.name, self.algorithm, expected.length, &progress_callback);
+      if let Err(Error::SessionLost(reason)) = &result {
+        return Err(Error::SessionLost(reason.clone()));
+      }
+
+      let (actual, error) = match result {
+        Ok(digest) => (Some(digest), None),
+        Err(e) => (None, Some(e.to_string())),
+      };
+      checks.push(PartitionCheck {
+        name: expected.name.clone(),
+        expected: expected.digest.to_lowercase(),
+        actual,
+        error,
+      });
+    }
+    Ok(checks)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_manifest() {
+    let json = r#"
+      {
+        "partitions": [
+          { "name": "boot_a", "digest": "ABCD" },
+          { "name": "system_a", "digest": "ef01", "length": 4096 }
+        ]
+      }
+    "#;
+    let manifest: HashManifest = serde_json::from_str(json).unwrap();
+    assert_eq!(manifest.algorithm, HashAlgorithm::Sha256);
+    assert_eq!(manifest.partitions[1].length, Some(4096));
+
+    let check = PartitionCheck {
+      name: "boot_a".into(),
+      expected: manifest.partitions[0].digest.to_lowercase(),
+      actual: Some("abcd".into()),
+      error: None,
+    };
+    assert!(check.ok());
+  }
+}
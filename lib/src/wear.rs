@@ -0,0 +1,178 @@
+//! Host-side record of the devices flashed from this machine and the eMMC wear each has taken,
+//! persisted across sessions and keyed by device serial.
+
+use std::{
+  collections::HashMap,
+  fs,
+  path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{FlashReport, Result, report::unix_now};
+
+/// What is known about a device that was flashed from this machine, and its cumulative writes
+#[serde_with::skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceWear {
+  /// Total bytes written to the eMMC across all recorded sessions
+  pub bytes_written: u64,
+  /// Total bytes that compare-before-write avoided rewriting
+  pub bytes_skipped: u64,
+  /// Number of recorded flash sessions
+  pub sessions: u64,
+  /// `name@version` of the last package flashed to the device
+  pub last_package: Option<String>,
+  /// Unix timestamp (seconds) of the last recorded session
+  pub last_flashed_at: Option<u64>,
+  /// Unix timestamp (seconds) of the first recorded session
+  pub first_flashed_at: Option<u64>,
+  /// Whether the last recorded session succeeded
+  #[serde(default)]
+  pub last_success: bool,
+  /// [`Error::code`](crate::Error::code) of the last session's error, if it failed
+  pub last_error_code: Option<String>,
+  /// ROM version the last `identify` step read from the device, e.g. `0-7-0-16-0-0-0-0`
+  pub identity: Option<String>,
+}
+
+/// Persistent ledger of every device flashed from this machine
+///
+/// Refurbishers re-flash the same units many times; the ledger keeps a running total of what was
+/// written so heavily worn devices can be spotted, and what each device last had flashed and
+/// whether that worked, so it can be looked up by serial without plugging it in. The
+/// [`Flasher`](crate::Flasher) records every session with a device that reports a serial.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct WearLedger {
+  /// Wear records keyed by device serial
+  pub devices: HashMap<String, DeviceWear>,
+}
+
+impl WearLedger {
+  /// Default location of the ledger in the user's local data directory
+  pub fn default_path() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| dir.join("flashthing").join("wear.json"))
+  }
+
+  /// Load a ledger from `path`, returning an empty ledger if it does not exist yet
+  pub fn load(path: &Path) -> Result<Self> {
+    if !path.exists() {
+      return Ok(Self::default());
+    }
+
+    let json = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&json)?)
+  }
+
+  /// Save the ledger to `path`, creating parent directories as needed
+  pub fn save(&self, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, serde_json::to_string_pretty(self)?)?;
+    Ok(())
+  }
+
+  /// Get the wear record for a device, if one exists
+  pub fn device(&self, serial: &str) -> Option<&DeviceWear> {
+    self.devices.get(serial)
+  }
+
+  /// Every recorded device and its serial, most recently flashed first
+  pub fn list(&self) -> Vec<(&str, &DeviceWear)> {
+    let mut devices = self
+      .devices
+      .iter()
+      .map(|(serial, wear)| (serial.as_str(), wear))
+      .collect::<Vec<_>>();
+    devices.sort_by(|(a_serial, a), (b_serial, b)| {
+      b.last_flashed_at
+        .cmp(&a.last_flashed_at)
+        .then_with(|| a_serial.cmp(b_serial))
+    });
+    devices
+  }
+
+  /// Record a finished session against the device it flashed and return its updated record
+  ///
+  /// # Returns
+  /// - `Option<&DeviceWear>`: The record, or `None` if the device did not report a serial
+  pub fn record(&mut self, report: &FlashReport) -> Option<&DeviceWear> {
+    let serial = report.device_serial.as_ref()?;
+    let now = report.finished_at.unwrap_or_else(unix_now);
+    let wear = self.devices.entry(serial.clone()).or_default();
+    wear.bytes_written += report.bytes_written;
+    wear.bytes_skipped += report.bytes_skipped;
+    wear.sessions += 1;
+    wear.last_package = Some(format!("{}@{}", report.package, report.version));
+    wear.first_flashed_at.get_or_insert(report.started_at.unwrap_or(now));
+    wear.last_flashed_at = Some(now);
+    wear.last_success = report.success;
+    wear.last_error_code = report.error_code.clone();
+    if report.device_identity.is_some() {
+      wear.identity = report.device_identity.clone();
+    }
+    Some(wear)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn report(serial: &str, version: &str, finished_at: u64, error_code: Option<&str>) -> FlashReport {
+    FlashReport {
+      package: "thingos".into(),
+      version: version.into(),
+      device_serial: Some(serial.into()),
+      started_at: Some(finished_at - 60),
+      finished_at: Some(finished_at),
+      success: error_code.is_none(),
+      error_code: error_code.map(str::to_owned),
+      bytes_written: 100,
+      bytes_skipped: 10,
+      device_identity: Some("0-7-0-16-0-0-0-0".into()),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn test_record_accumulates() {
+    let mut ledger = WearLedger::default();
+    ledger.record(&report("abc", "1.0.0", 1000, None));
+    ledger.record(&report("def", "1.0.0", 3000, None));
+    let wear = ledger
+      .record(&report("abc", "1.1.0", 2000, Some("WRONG_MODE")))
+      .unwrap();
+
+    assert_eq!((wear.bytes_written, wear.bytes_skipped, wear.sessions), (200, 20, 2));
+    assert_eq!((wear.first_flashed_at, wear.last_flashed_at), (Some(940), Some(2000)));
+    assert_eq!(wear.last_package.as_deref(), Some("thingos@1.1.0"));
+    assert!(!wear.last_success && wear.last_error_code.as_deref() == Some("WRONG_MODE"));
+    assert!(ledger.device("other").is_none());
+    assert!(ledger.record(&FlashReport::default()).is_none());
+
+    let serials = ledger.list().iter().map(|(serial, _)| *serial).collect::<Vec<_>>();
+    assert_eq!(serials, ["def", "abc"]);
+  }
+
+  #[test]
+  fn test_roundtrip() {
+    let path = std::env::temp_dir().join(format!("flashthing-wear-{}.json", std::process::id()));
+    let mut ledger = WearLedger::default();
+    ledger.record(&report("abc", "1.0.0", 1000, None));
+    ledger.save(&path).unwrap();
+
+    let loaded = WearLedger::load(&path).unwrap();
+    assert_eq!(loaded.device("abc"), ledger.device("abc"));
+    let _ = fs::remove_file(&path);
+
+    // ledgers saved before devices were recorded still load
+    let old = r#"{ "devices": { "abc": { "bytesWritten": 1, "bytesSkipped": 2, "sessions": 1 } } }"#;
+    let old: WearLedger = serde_json::from_str(old).unwrap();
+    assert_eq!(old.device("abc").map(|wear| wear.bytes_written), Some(1));
+  }
+}
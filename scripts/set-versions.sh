@@ -17,9 +17,11 @@ update_package_json() {
 
 echo "Setting versions to v$VERSION_WITHOUT_V..."
 
+update_cargo_toml "core/Cargo.toml"
 update_cargo_toml "lib/Cargo.toml"
 update_cargo_toml "cli/Cargo.toml"
 update_cargo_toml "bindings/Cargo.toml"
+update_cargo_toml "emulator/Cargo.toml"
 update_package_json "bindings/package.json"
 
 echo "Version updated successfully to v$VERSION_WITHOUT_V"